@@ -0,0 +1,201 @@
+// Write admission control
+//
+// When commits can't keep up with incoming writes (a slow disk, say), callers
+// keep beginning transactions and staging operations while earlier ones wait
+// for the writer, and the staged data grows until the process runs out of
+// memory. `AdmissionControl` keeps a moving average of how long the writer
+// takes per commit and counts the work waiting for it. Once the open
+// transactions hold more than `max_staged_bytes`, or the pending work would
+// take longer than `max_backlog` to commit at the recent rate, new
+// transactions and staged operations fail with `StatehouseError::Overloaded`
+// and a hint of when to retry. Commits, aborts and reads are always
+// admitted, since they drain the backlog.
+//
+// Refusing everything punishes well-behaved callers for one client that
+// opened a large transaction and went quiet. With `IdleAbort` set, once the
+// open transactions hold more than its high-water mark, the largest one that
+// has staged nothing for `min_idle` is aborted first, then the next largest,
+// until usage is back under the mark; only then are the limits checked.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::error::StatehouseError;
+
+/// Shortest retry-after hint given to a refused caller
+pub const MIN_RETRY_AFTER: Duration = Duration::from_millis(10);
+
+/// Weight of the newest commit in the moving average, as 1/N
+const AVERAGE_WEIGHT: u64 = 8;
+
+/// When new work is refused; both `None` (the default) admits everything
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdmissionLimits {
+    /// Bytes staged across all open transactions
+    pub max_staged_bytes: Option<u64>,
+    /// Estimated time to commit the pending work (`Backlog::estimated_drain`)
+    pub max_backlog: Option<Duration>,
+    /// Abort idle transactions to free memory before refusing anything
+    pub abort_idle: Option<IdleAbort>,
+}
+
+impl AdmissionLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.max_staged_bytes.is_some() || self.max_backlog.is_some() || self.abort_idle.is_some()
+    }
+}
+
+/// When open transactions hold more than `high_water_bytes`, abort the
+/// largest that has been idle for `min_idle`, until they no longer do.
+/// Prepared transactions are never aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleAbort {
+    pub high_water_bytes: u64,
+    /// How long a transaction must have gone without staging anything
+    pub min_idle: Duration,
+}
+
+/// Work waiting for the writer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Backlog {
+    pub open_transactions: usize,
+    /// Approximate memory held by the open transactions' staged operations
+    pub staged_bytes: u64,
+    /// Commits sent to the writer and not yet finished
+    pub pending_commits: usize,
+    /// Moving average of how long the writer takes per commit; zero until
+    /// the first commit
+    pub avg_commit_time: Duration,
+    /// How long the pending commits and open transactions would take to
+    /// commit at `avg_commit_time` each
+    pub estimated_drain: Duration,
+    /// Transactions and operations refused since startup
+    pub rejected: u64,
+    /// Idle transactions aborted to free memory since startup
+    pub idle_aborted: u64,
+}
+
+pub(crate) struct AdmissionControl {
+    limits: AdmissionLimits,
+    pending_commits: AtomicUsize,
+    avg_commit_us: AtomicU64,
+    rejected: AtomicU64,
+    idle_aborted: AtomicU64,
+}
+
+/// A commit waiting for or held by the writer; dropping it takes it off
+/// the backlog
+pub(crate) struct PendingCommit<'a> {
+    admission: &'a AdmissionControl,
+}
+
+impl AdmissionControl {
+    pub(crate) fn new(limits: AdmissionLimits) -> Self {
+        Self {
+            limits,
+            pending_commits: AtomicUsize::new(0),
+            avg_commit_us: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            idle_aborted: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn limits(&self) -> AdmissionLimits {
+        self.limits
+    }
+
+    /// Count a commit as pending until the returned guard is dropped
+    pub(crate) fn pending_commit(&self) -> PendingCommit<'_> {
+        self.pending_commits.fetch_add(1, Ordering::SeqCst);
+        PendingCommit { admission: self }
+    }
+
+    /// Fold how long the writer spent on one commit into the average
+    pub(crate) fn record_commit(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let _ = self.avg_commit_us.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |avg| {
+            Some(match avg {
+                0 => sample.max(1),
+                avg => (avg - avg / AVERAGE_WEIGHT + sample / AVERAGE_WEIGHT).max(1),
+            })
+        });
+    }
+
+    pub(crate) fn record_idle_abort(&self) {
+        self.idle_aborted.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The backlog, given the open transactions and the bytes they've staged
+    pub(crate) fn backlog(&self, open_transactions: usize, staged_bytes: u64) -> Backlog {
+        let pending_commits = self.pending_commits.load(Ordering::SeqCst);
+        let avg_commit_time = Duration::from_micros(self.avg_commit_us.load(Ordering::SeqCst));
+        let pending = (pending_commits + open_transactions).min(u32::MAX as usize) as u32;
+        Backlog {
+            open_transactions,
+            staged_bytes,
+            pending_commits,
+            avg_commit_time,
+            estimated_drain: avg_commit_time.saturating_mul(pending),
+            rejected: self.rejected.load(Ordering::SeqCst),
+            idle_aborted: self.idle_aborted.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Refuse new work with `StatehouseError::Overloaded` if `backlog` is
+    /// over a limit
+    pub(crate) fn admit(&self, backlog: &Backlog) -> Result<()> {
+        let (reason, retry_after) = match (self.limits.max_staged_bytes, self.limits.max_backlog) {
+            (Some(max), _) if backlog.staged_bytes > max => (
+                format!("{} bytes staged by open transactions, limit {}", backlog.staged_bytes, max),
+                // Staged bytes leave as transactions commit; allow for one
+                backlog.avg_commit_time,
+            ),
+            (_, Some(max)) if backlog.estimated_drain > max => (
+                format!("commit backlog of {:?}, limit {:?}", backlog.estimated_drain, max),
+                backlog.estimated_drain - max,
+            ),
+            _ => return Ok(()),
+        };
+        self.rejected.fetch_add(1, Ordering::SeqCst);
+        Err(StatehouseError::Overloaded { reason, retry_after: retry_after.max(MIN_RETRY_AFTER) }.into())
+    }
+}
+
+impl Drop for PendingCommit<'_> {
+    fn drop(&mut self) {
+        self.admission.pending_commits.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let limits = AdmissionLimits { max_staged_bytes: Some(1000), max_backlog: Some(Duration::from_millis(100)), abort_idle: None };
+        let admission = AdmissionControl::new(limits);
+        assert!(admission.admit(&admission.backlog(50, 1000)).is_ok());
+
+        let err = admission.admit(&admission.backlog(1, 1001)).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StatehouseError::Overloaded { retry_after, .. }) if *retry_after == MIN_RETRY_AFTER));
+
+        // 10ms commits: 20 pending take 200ms to drain, 100ms over the limit
+        admission.record_commit(Duration::from_millis(10));
+        let pending: Vec<_> = (0..5).map(|_| admission.pending_commit()).collect();
+        let backlog = admission.backlog(15, 0);
+        assert_eq!((backlog.pending_commits, backlog.estimated_drain), (5, Duration::from_millis(200)));
+        let err = admission.admit(&backlog).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StatehouseError::Overloaded { retry_after, .. }) if *retry_after == Duration::from_millis(100)));
+        assert_eq!(admission.backlog(0, 0).rejected, 2);
+
+        drop(pending);
+        assert!(admission.admit(&admission.backlog(10, 0)).is_ok());
+
+        // Faster commits pull the average down
+        admission.record_commit(Duration::from_millis(2));
+        assert_eq!(admission.backlog(0, 0).avg_commit_time, Duration::from_millis(9));
+        assert!(AdmissionControl::new(AdmissionLimits::default()).admit(&admission.backlog(1_000_000, u64::MAX)).is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+// Bounded least-recently-used cache
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Fixed-capacity map that evicts the least recently used entry when full.
+/// Recency is a monotonically increasing tick, so `get` and `put` are O(log n).
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    /// Last-use tick -> key, oldest first
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::with_capacity(capacity),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Look up `key`, marking it most recently used
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        self.tick += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(self.tick, key.clone());
+        *last_used = self.tick;
+        Some(value)
+    }
+
+    /// Insert or replace `key`, evicting the least recently used entry if full
+    pub(crate) fn put(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        if let Some((_, last_used)) = self.entries.insert(key, (value, self.tick)) {
+            self.order.remove(&last_used);
+        }
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.put("a", 1);
+        cache.put("b", 2);
+
+        // Touch "a" so "b" becomes the eviction candidate
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.put("c", 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+
+        // Replacing an entry doesn't grow the cache
+        cache.put("a", 10);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"a"), Some(&10));
+
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.get(&"a"), None);
+    }
+}
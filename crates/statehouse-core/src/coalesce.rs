@@ -0,0 +1,85 @@
+// Write coalescing for high-frequency keys
+//
+// Keys like heartbeats and progress counters can be rewritten hundreds of times
+// a second, and each write is normally its own version and event. Keys matching
+// a coalescing rule instead have their writes buffered: a commit made up only of
+// plain writes to such keys, that doesn't ask for a durability, is acknowledged
+// without touching storage, later writes replace the buffered value, and when
+// the key's window closes the last value is committed as a single version in a
+// single event.
+//
+// Coalescing trades history for volume, so it is opt-in per key prefix:
+// - Replay, version history and as-of reads only see the flushed value; the
+//   intermediate writes never existed as far as the log is concerned.
+// - Reads return the last flushed value until the window closes.
+// - Buffered writes live in memory and are lost if the process dies before
+//   they are flushed.
+// Any other operation on a key with a buffered write (a delete, a conditional
+// write, a transaction that mixes in other keys, ...) commits the buffered
+// value first, so it applies on top of the latest write as usual. If committing
+// buffered values fails (on a quota, say), they stay buffered and the flush, or
+// the operation that needed them first, fails with the error.
+
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// Which keys are coalesced, and over what window
+#[derive(Debug, Clone, Default)]
+pub struct CoalesceRules {
+    /// (key prefix, window)
+    rules: Vec<(String, Duration)>,
+}
+
+impl CoalesceRules {
+    /// Parse `prefix=millis;...`, e.g. `heartbeat=1000;progress/=250`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut rules = Self::default();
+        for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (prefix, window) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid coalescing rule {:?}: expected prefix=millis", entry))?;
+            let window = window.trim().parse().map_err(|_| anyhow!("Invalid coalescing rule {:?}: window is not a number of milliseconds", entry))?;
+            rules = rules.with_prefix(prefix.trim(), Duration::from_millis(window));
+        }
+        Ok(rules)
+    }
+
+    /// Coalesce writes to keys starting with `prefix` over `window`
+    pub fn with_prefix(mut self, prefix: &str, window: Duration) -> Self {
+        self.rules.push((prefix.to_string(), window));
+        self
+    }
+
+    /// Window for `key`, from the longest matching prefix
+    pub fn window(&self, key: &str) -> Option<Duration> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, window)| *window)
+            .filter(|window| !window.is_zero())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let rules = CoalesceRules::parse("heartbeat=1000; heartbeat/fast=50;progress/=0").unwrap();
+        assert_eq!(rules.window("heartbeat"), Some(Duration::from_secs(1)));
+        assert_eq!(rules.window("heartbeat/fast/1"), Some(Duration::from_millis(50)));
+        assert_eq!(rules.window("progress/1"), None);
+        assert_eq!(rules.window("status"), None);
+        assert!(CoalesceRules::parse("").unwrap().is_empty());
+
+        for bad in ["heartbeat", "heartbeat=soon"] {
+            assert!(CoalesceRules::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
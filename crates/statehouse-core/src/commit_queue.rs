@@ -0,0 +1,166 @@
+// Fair ordering of commits
+//
+// The state machine's writer runs commits in the order they reach its command
+// channel, so a large commit only waits for those queued ahead of it. With a
+// `CommitOrdering` other than `Unordered`, each commit first takes a ticket
+// and waits its turn before it is sent, and the queue lets one waiter through
+// at a time: in arrival order (`Fifo`), or rotating between namespaces with
+// waiters so no namespace goes more than one commit per other busy namespace
+// without being served (`NamespaceRoundRobin`).
+
+use anyhow::{bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+
+/// How commits waiting for the writer are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommitOrdering {
+    /// In the order commits reach the writer; no queue
+    #[default]
+    Unordered,
+    /// Strictly in the order commits arrive
+    Fifo,
+    /// Round-robin between the namespaces of waiting commits, FIFO within one
+    NamespaceRoundRobin,
+}
+
+impl CommitOrdering {
+    /// Parse `unordered`, `fifo` or `round-robin`
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.trim() {
+            "unordered" => Ok(CommitOrdering::Unordered),
+            "fifo" => Ok(CommitOrdering::Fifo),
+            "round-robin" => Ok(CommitOrdering::NamespaceRoundRobin),
+            other => bail!("Unknown commit ordering {:?}: expected unordered, fifo or round-robin", other),
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_ticket: u64,
+    /// Waiting tickets per lane, oldest first
+    lanes: HashMap<String, VecDeque<u64>>,
+    /// Lanes with waiters, in the order they will be served
+    rotation: VecDeque<String>,
+    /// Ticket allowed to commit, `None` while the writer is idle
+    serving: Option<u64>,
+}
+
+impl QueueState {
+    /// Hand the writer to the next waiter, if any
+    fn advance(&mut self) {
+        self.serving = None;
+        let Some(lane) = self.rotation.pop_front() else { return };
+        let Some(waiting) = self.lanes.get_mut(&lane) else { return };
+        self.serving = waiting.pop_front();
+        if waiting.is_empty() {
+            self.lanes.remove(&lane);
+        } else {
+            self.rotation.push_back(lane);
+        }
+    }
+}
+
+pub(crate) struct CommitQueue {
+    ordering: CommitOrdering,
+    state: Mutex<QueueState>,
+    turn: Condvar,
+}
+
+/// The writer, held by one commit at a time; dropping it serves the next
+pub(crate) struct CommitTurn<'a> {
+    queue: &'a CommitQueue,
+}
+
+impl CommitQueue {
+    pub(crate) fn new(ordering: CommitOrdering) -> Self {
+        Self {
+            ordering,
+            state: Mutex::new(QueueState::default()),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Wait until it's the turn of a commit; `namespace` is only asked for
+    /// when commits are ordered by namespace
+    pub(crate) fn enter(&self, namespace: impl FnOnce() -> String) -> CommitTurn<'_> {
+        let lane = match self.ordering {
+            CommitOrdering::NamespaceRoundRobin => namespace(),
+            _ => String::new(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        let waiting = state.lanes.entry(lane.clone()).or_default();
+        waiting.push_back(ticket);
+        if waiting.len() == 1 {
+            state.rotation.push_back(lane);
+        }
+        if state.serving.is_none() {
+            state.advance();
+        }
+
+        let _state = self.turn.wait_while(state, |state| state.serving != Some(ticket)).unwrap();
+        CommitTurn { queue: self }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn waiting(&self) -> usize {
+        self.state.lock().unwrap().lanes.values().map(VecDeque::len).sum()
+    }
+}
+
+impl Drop for CommitTurn<'_> {
+    fn drop(&mut self) {
+        // Runs on unwind too, so a panicking commit doesn't stall the queue
+        let mut state = self.queue.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.advance();
+        self.queue.turn.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Queue commits to `namespaces` in order behind a held turn, then
+    /// release it and return the order they were served in
+    fn served_order(ordering: CommitOrdering, namespaces: &[&str]) -> Vec<usize> {
+        let queue = Arc::new(CommitQueue::new(ordering));
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let held = queue.enter(|| "held".to_string());
+
+        let mut waiters = Vec::new();
+        for (i, namespace) in namespaces.iter().enumerate() {
+            let (waiter_queue, served, namespace) = (queue.clone(), served.clone(), namespace.to_string());
+            waiters.push(std::thread::spawn(move || {
+                let _turn = waiter_queue.enter(|| namespace);
+                served.lock().unwrap().push(i);
+            }));
+            while queue.waiting() < i + 1 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        drop(held);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        Arc::try_unwrap(served).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_served_order() {
+        let namespaces = ["busy", "busy", "busy", "quiet", "busy"];
+        assert_eq!(served_order(CommitOrdering::Fifo, &namespaces), vec![0, 1, 2, 3, 4]);
+        // The quiet namespace is served after one busy commit, not behind all of them
+        assert_eq!(served_order(CommitOrdering::NamespaceRoundRobin, &namespaces), vec![0, 3, 1, 2, 4]);
+
+        assert_eq!(CommitOrdering::parse("round-robin").unwrap(), CommitOrdering::NamespaceRoundRobin);
+        assert!(CommitOrdering::parse("lifo").is_err());
+    }
+}
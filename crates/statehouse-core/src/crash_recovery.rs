@@ -0,0 +1,325 @@
+// Crash recovery chaos test
+//
+// Drives RocksDB-backed state machines through seeded random commits, crashing
+// each at a random storage call and reopening it, over and over. After every
+// reopen, every acknowledged commit must be present, and the commit the crash
+// interrupted, whose caller saw an error, must be either wholly present or
+// wholly absent. Commit timestamps must keep increasing across crashes. The
+// seed is printed with every failure, and a failing seed reproduces exactly.
+//
+// RocksStorage writes a commit's records one at a time, so on its own it is
+// only crashed where a commit is whole or not yet begun: as its timestamp is
+// issued, or once its records and event are written but before it is
+// acknowledged, which with `fsync_on_commit` off is the window in which a
+// commit sits unflushed. A crash there may also lose the `__commit_ts__`
+// counter's latest writes, which reopening must repair from the event log.
+// Crashes inside a commit run behind WalStorage, which makes a commit atomic
+// by writing it as a single frame.
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+use crate::deadline::Deadline;
+use crate::state_machine::StateMachine;
+use crate::storage::{
+    CompactionStats, EventLogEntry, NamespaceUsage, PurgeStats, RenameStats, RocksStorage, Snapshot, StateMeta, StateRecord, Storage,
+    StorageConfig,
+};
+use crate::storage_conformance::Rng;
+use crate::types::*;
+use crate::wal::{WalConfig, WalStorage};
+
+const AGENTS: &[&str] = &["a", "b"];
+const KEYS: &[&str] = &["k1", "k2", "k3"];
+
+/// Crash-and-reopen cycles per seed
+const CYCLES: usize = 25;
+
+/// Storage calls that write, and where a crash can interrupt a commit
+const COMMIT_CALLS: &[&str] = &["next_commit_ts", "write_state", "append_event", "sync_commit", "flush", "save_snapshot"];
+
+/// Where RocksStorage alone can crash without tearing a commit
+const WHOLE_COMMIT_CALLS: &[&str] = &["next_commit_ts", "sync_commit", "save_snapshot"];
+
+/// Storage that fails every write once a budget of calls runs out, as if
+/// the process died there. Nothing reaches the wrapped storage after that.
+struct CrashingStorage {
+    inner: Arc<dyn Storage>,
+    /// The calls that count against the budget and can crash
+    crash_points: &'static [&'static str],
+    /// Counted calls left before the crash; `None` once it has happened
+    calls_left: Mutex<Option<usize>>,
+}
+
+impl CrashingStorage {
+    fn new(inner: Arc<dyn Storage>, crash_points: &'static [&'static str], budget: usize) -> Self {
+        Self { inner, crash_points, calls_left: Mutex::new(Some(budget)) }
+    }
+
+    /// Fail if the process has crashed, or crash now if `call` spends the
+    /// last of the budget
+    fn reach(&self, call: &str) -> Result<()> {
+        let mut calls_left = self.calls_left.lock().unwrap();
+        match calls_left.as_mut() {
+            None => return Err(anyhow!("crashed")),
+            Some(left) if self.crash_points.contains(&call) => {
+                if *left == 0 {
+                    *calls_left = None;
+                    return Err(anyhow!("crashed at {}", call));
+                }
+                *left -= 1;
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    fn crashed(&self) -> bool {
+        self.calls_left.lock().unwrap().is_none()
+    }
+}
+
+impl Storage for CrashingStorage {
+    fn health_check(&self) -> Result<()> { self.inner.health_check() }
+    fn write_state(&self, record: StateRecord) -> Result<()> {
+        self.reach("write_state")?;
+        self.inner.write_state(record)
+    }
+    fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> { self.inner.read_state(record_id) }
+    fn exists(&self, record_id: &RecordId) -> Result<bool> { self.inner.exists(record_id) }
+    fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> { self.inner.read_state_meta(record_id) }
+    fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> { self.inner.read_states(record_ids) }
+    fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> { self.inner.read_state_at_version(record_id, version) }
+    fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> { self.inner.read_version_history(record_id, limit) }
+    fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> { self.inner.read_state_as_of(record_id, as_of) }
+    fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> { self.inner.list_keys(namespace, agent_id, include_deleted, deadline) }
+    fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> { self.inner.count_keys(namespace, agent_id, prefix) }
+    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> { self.inner.scan_prefix(namespace, agent_id, prefix, include_deleted, deadline) }
+    fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> { self.inner.scan_prefix_consistent(namespace, agent_id, prefix, include_deleted, deadline) }
+    fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
+        self.inner.scan_prefix_page(namespace, agent_id, prefix, include_deleted, start_after, limit)
+    }
+    fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> { self.inner.scan_namespace_prefix(namespace, prefix, limit) }
+    fn append_event(&self, event: EventLogEntry) -> Result<()> {
+        self.reach("append_event")?;
+        self.inner.append_event(event)
+    }
+    fn last_event_ts(&self) -> Result<Option<CommitTs>> { self.inner.last_event_ts() }
+    fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> { self.inner.read_event(commit_ts) }
+    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> { self.inner.replay_events(namespace, agent_id, start_ts, end_ts, deadline) }
+    fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> { self.inner.read_events(from_ts, limit) }
+    fn count_events(&self, from_ts: CommitTs) -> Result<u64> { self.inner.count_events(from_ts) }
+    fn next_commit_ts(&self) -> Result<CommitTs> {
+        self.reach("next_commit_ts")?;
+        self.inner.next_commit_ts()
+    }
+    fn current_commit_ts(&self) -> Result<CommitTs> { self.inner.current_commit_ts() }
+    fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> { self.inner.advance_commit_ts(commit_ts) }
+    fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> { self.inner.reserve_commit_ts_block(n) }
+    fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.inner.next_namespace_ts(namespace) }
+    fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> { self.inner.advance_namespace_ts(namespace, namespace_ts) }
+    fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.inner.current_namespace_ts(namespace) }
+    fn flush(&self) -> Result<()> {
+        self.reach("flush")?;
+        self.inner.flush()
+    }
+    fn supports_durability(&self, durability: Durability) -> bool { self.inner.supports_durability(durability) }
+    fn sync_commit(&self, durability: Option<Durability>) -> Result<()> {
+        self.reach("sync_commit")?;
+        self.inner.sync_commit(durability)
+    }
+    fn flush_deferred(&self) -> Result<()> {
+        self.reach("flush")?;
+        self.inner.flush_deferred()
+    }
+    fn create_snapshot(&self) -> Result<Snapshot> { self.inner.create_snapshot() }
+    fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> { self.inner.create_snapshot_at(snapshot_ts) }
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        self.reach("save_snapshot")?;
+        self.inner.save_snapshot(snapshot)
+    }
+    fn load_snapshot(&self) -> Result<Option<Snapshot>> { self.inner.load_snapshot() }
+    fn get_all_state(&self) -> Result<Vec<StateRecord>> { self.inner.get_all_state() }
+    fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> { self.inner.create_snapshot_for_namespace(namespace) }
+    fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()> { self.inner.save_snapshot_for_namespace(snapshot) }
+    fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>> { self.inner.load_snapshot_for_namespace(namespace) }
+    fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> { self.inner.restore_namespace_snapshot(snapshot) }
+    fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> { self.inner.compact_history(up_to_ts, keep_versions) }
+    fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> { self.inner.trim_events(up_to_ts) }
+    fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> { self.inner.list_tombstones(up_to_ts) }
+    fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> { self.inner.purge_tombstones(tombstones) }
+    fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> { self.inner.list_expired(now_ms) }
+    fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> { self.inner.purge_key(record_id) }
+    fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> { self.inner.rename_namespace(from, to, merge) }
+    fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> { self.inner.version_bounds(record_id) }
+    fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> { self.inner.namespace_usage(namespace) }
+    fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> { self.inner.least_recent_keys(namespace, limit) }
+    fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>> { self.inner.version_counts(namespace, deadline) }
+    fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> { self.inner.agent_last_commit_ts(namespace, agent_id) }
+}
+
+/// Latest value (`None` once deleted) and version of every key written
+type Model = HashMap<RecordId, (Option<Value>, Version)>;
+
+/// A transaction's operations: key and value, `None` for a delete
+type Operations = Vec<(RecordId, Option<Value>)>;
+
+fn apply(model: &Model, operations: &Operations) -> Model {
+    let mut model = model.clone();
+    for (record_id, value) in operations {
+        let entry = model.entry(record_id.clone()).or_insert((None, 0));
+        *entry = (value.clone(), entry.1 + 1);
+    }
+    model
+}
+
+fn random_operations(rng: &mut Rng) -> Operations {
+    (0..1 + rng.below(4))
+        .map(|_| {
+            let record_id = RecordId::new("ns".to_string(), rng.pick(AGENTS).to_string(), rng.pick(KEYS).to_string());
+            let value = match rng.below(4) {
+                0 => None,
+                _ => Some(json!({"n": rng.below(1000)})),
+            };
+            (record_id, value)
+        })
+        .collect()
+}
+
+/// A storage stack under test
+#[derive(Clone, Copy, Debug)]
+enum Stack {
+    /// RocksStorage with `fsync_on_commit` off
+    Rocks,
+    /// The same behind WalStorage
+    RocksWal,
+}
+
+impl Stack {
+    fn crash_points(self) -> &'static [&'static str] {
+        match self {
+            Stack::Rocks => WHOLE_COMMIT_CALLS,
+            Stack::RocksWal => COMMIT_CALLS,
+        }
+    }
+
+    fn open(self, dir: &Path, rng: &mut Rng) -> Result<(StateMachine, Arc<CrashingStorage>)> {
+        let config = StorageConfig { data_dir: dir.to_path_buf(), fsync_on_commit: false, ..StorageConfig::default() };
+        let rocks: Arc<dyn Storage> = Arc::new(RocksStorage::new(config)?);
+        let inner: Arc<dyn Storage> = match self {
+            Stack::Rocks => rocks,
+            Stack::RocksWal => Arc::new(WalStorage::open(rocks, WalConfig { dir: dir.join("wal"), segment_bytes: 4096, ..Default::default() })?),
+        };
+        let storage = Arc::new(CrashingStorage::new(inner, self.crash_points(), rng.below(30)));
+        let sm = StateMachine::new(storage.clone());
+        sm.recover()?;
+        Ok((sm, storage))
+    }
+}
+
+/// Put `__commit_ts__` back to `commit_ts`, as if the writes moving it past
+/// that were lost in the crash
+fn lose_counter_writes(dir: &Path, commit_ts: CommitTs) {
+    let db = rocksdb::DB::open_default(dir.join("rocksdb")).unwrap();
+    db.put(b"__commit_ts__", commit_ts.to_be_bytes()).unwrap();
+}
+
+/// Every key's latest value and version as the state machine reports them,
+/// and the newest commit_ts among them
+fn observe(sm: &StateMachine) -> (Model, CommitTs) {
+    let mut observed = Model::new();
+    let mut newest = 0;
+    for agent_id in AGENTS {
+        for key in KEYS {
+            if let Some(record) = sm.get_state("ns", agent_id, key).unwrap() {
+                let record_id = RecordId::new("ns".to_string(), agent_id.to_string(), key.to_string());
+                observed.insert(record_id, (record.value, record.version));
+                newest = newest.max(record.commit_ts);
+            }
+        }
+    }
+    (observed, newest)
+}
+
+fn run(stack: Stack, seed: u64) {
+    let dir = TempDir::new().unwrap();
+    let data_dir: PathBuf = dir.path().to_path_buf();
+    let mut rng = Rng(seed);
+    let mut model = Model::new();
+    // Operations of the commit a crash interrupted, which may or may not have landed
+    let mut interrupted: Option<Operations> = None;
+    let mut last_commit_ts = 0;
+
+    for cycle in 0..CYCLES {
+        let context = format!("{:?} seed {} cycle {}", stack, seed, cycle);
+        let (sm, storage) = stack.open(&data_dir, &mut rng).unwrap_or_else(|e| panic!("{}: reopen failed: {}", context, e));
+
+        // Acknowledged commits are all there; the interrupted one is all or nothing
+        let (observed, newest) = observe(&sm);
+        let landed = interrupted.take().map(|operations| apply(&model, &operations));
+        if observed != model {
+            match landed {
+                Some(landed) if observed == landed => model = landed,
+                _ => panic!("{}: recovered state {:?} doesn't match the acknowledged commits {:?}", context, observed, model),
+            }
+        }
+        for agent_id in AGENTS {
+            let mut keys: Vec<String> = model
+                .iter()
+                .filter(|(record_id, (value, _))| record_id.agent_id == *agent_id && value.is_some())
+                .map(|(record_id, _)| record_id.key.clone())
+                .collect();
+            keys.sort();
+            assert_eq!(sm.list_keys("ns", agent_id, false, Deadline::NONE).unwrap(), keys, "{}: list_keys {}", context, agent_id);
+        }
+        last_commit_ts = last_commit_ts.max(newest);
+
+        // Commit until the crash; a cycle that never reaches it ends in a clean shutdown
+        for _ in 0..20 {
+            if rng.below(8) == 0 {
+                if sm.create_snapshot().is_err() {
+                    break;
+                }
+                continue;
+            }
+            let operations = random_operations(&mut rng);
+            let txn_id = sm.begin_transaction(None).unwrap();
+            for (RecordId { namespace, agent_id, key }, value) in operations.clone() {
+                match value {
+                    Some(value) => sm.write(&txn_id, namespace, agent_id, key, value).unwrap(),
+                    None => sm.delete(&txn_id, namespace, agent_id, key).unwrap(),
+                }
+            }
+            match sm.commit(&txn_id) {
+                Ok(result) => {
+                    assert!(result.commit_ts > last_commit_ts, "{}: commit_ts {} reissued after {}", context, result.commit_ts, last_commit_ts);
+                    last_commit_ts = result.commit_ts;
+                    model = apply(&model, &operations);
+                }
+                Err(_) => {
+                    interrupted = Some(operations);
+                    break;
+                }
+            }
+        }
+
+        let crashed = storage.crashed();
+        drop(sm);
+        drop(storage);
+        if crashed && matches!(stack, Stack::Rocks) && rng.below(2) == 0 {
+            lose_counter_writes(&data_dir, last_commit_ts.saturating_sub(rng.below(3) as u64));
+        }
+    }
+}
+
+#[test]
+fn test_crash_recovery_chaos() {
+    for seed in 0..6 {
+        run(Stack::Rocks, seed);
+        run(Stack::RocksWal, seed);
+    }
+}
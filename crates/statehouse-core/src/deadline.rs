@@ -0,0 +1,71 @@
+// Request deadlines
+//
+// A client that sets a deadline stops waiting once it passes, so a scan still
+// running then is wasted work. Long-running storage reads (scan, list,
+// replay) take a `Deadline` and check it every `CHECK_INTERVAL` records,
+// failing with `StatehouseError::DeadlineExceeded` once it has passed.
+
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+use crate::error::StatehouseError;
+
+/// Records a storage read processes between deadline checks
+pub const CHECK_INTERVAL: usize = 256;
+
+/// When the caller stops waiting for a result; `NONE` never expires
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub const NONE: Deadline = Deadline(None);
+
+    pub fn at(instant: Instant) -> Self {
+        Self(Some(instant))
+    }
+
+    /// `timeout` from now; `NONE` if that is too far off to represent
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now().checked_add(timeout))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.is_some_and(|instant| Instant::now() >= instant)
+    }
+
+    /// Fail with `DeadlineExceeded` if the deadline has passed
+    pub fn check(&self) -> Result<()> {
+        if self.is_expired() {
+            return Err(StatehouseError::DeadlineExceeded.into());
+        }
+        Ok(())
+    }
+
+    /// `check`, but only on every `CHECK_INTERVAL`th record (the first
+    /// included), given how many a loop has processed so far
+    pub fn check_every(&self, processed: usize) -> Result<()> {
+        if processed.is_multiple_of(CHECK_INTERVAL) {
+            self.check()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(Deadline::NONE.check().is_ok());
+        assert!(Deadline::after(Duration::from_secs(60)).check().is_ok());
+        assert_eq!(Deadline::after(Duration::MAX), Deadline::NONE);
+
+        let expired = Deadline::at(Instant::now());
+        let err = expired.check().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StatehouseError::DeadlineExceeded)));
+        assert!(expired.check_every(0).is_err());
+        assert!(expired.check_every(1).is_ok());
+        assert!(expired.check_every(CHECK_INTERVAL).is_err());
+    }
+}
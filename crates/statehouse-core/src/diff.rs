@@ -0,0 +1,106 @@
+// Structural diff between two stored values
+//
+// Objects are compared field by field and arrays index by index, recursing
+// into children of the same kind; anything else that differs (a scalar, or a
+// value whose type changed) is reported whole at its path. Paths are JSON
+// pointers (RFC 6901), "" for the whole value.
+
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Changed => "changed",
+        }
+    }
+}
+
+/// One difference between two values
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    /// The older value at `path`; `None` when added
+    pub before: Option<Value>,
+    /// The newer value at `path`; `None` when removed
+    pub after: Option<Value>,
+}
+
+/// Differences from `before` to `after`, ordered by object key and array
+/// index. `None` stands for no value (e.g. a deleted version), so diffing
+/// against it reports the other side whole as added or removed.
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Vec<ValueChange> {
+    let mut changes = Vec::new();
+    diff_at(&mut String::new(), before, after, &mut changes);
+    changes
+}
+
+fn diff_at(path: &mut String, before: Option<&Value>, after: Option<&Value>, changes: &mut Vec<ValueChange>) {
+    match (before, after) {
+        (None, None) => {}
+        (None, Some(after)) => changes.push(ValueChange { path: path.clone(), kind: ChangeKind::Added, before: None, after: Some(after.clone()) }),
+        (Some(before), None) => changes.push(ValueChange { path: path.clone(), kind: ChangeKind::Removed, before: Some(before.clone()), after: None }),
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+            for key in keys {
+                let len = path.len();
+                path.push('/');
+                path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                diff_at(path, before.get(key), after.get(key), changes);
+                path.truncate(len);
+            }
+        }
+        (Some(Value::Array(before)), Some(Value::Array(after))) => {
+            for i in 0..before.len().max(after.len()) {
+                let len = path.len();
+                path.push('/');
+                path.push_str(&i.to_string());
+                diff_at(path, before.get(i), after.get(i), changes);
+                path.truncate(len);
+            }
+        }
+        (Some(before), Some(after)) if before != after => changes.push(ValueChange {
+            path: path.clone(),
+            kind: ChangeKind::Changed,
+            before: Some(before.clone()),
+            after: Some(after.clone()),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff() {
+        let paths = |changes: Vec<ValueChange>| -> Vec<(String, ChangeKind)> { changes.into_iter().map(|c| (c.path, c.kind)).collect() };
+
+        let before = json!({"items": [1, 2, 3], "a/b": {"~c": 1}, "kind": {"x": 1}});
+        let after = json!({"items": [1, 5], "a/b": {"~c": 2}, "kind": [1]});
+        assert_eq!(
+            paths(diff(Some(&before), Some(&after))),
+            vec![
+                ("/a~1b/~0c".to_string(), ChangeKind::Changed),
+                ("/items/1".to_string(), ChangeKind::Changed),
+                ("/items/2".to_string(), ChangeKind::Removed),
+                ("/kind".to_string(), ChangeKind::Changed),
+            ]
+        );
+
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+        assert_eq!(diff(None, Some(&after)), vec![ValueChange { path: String::new(), kind: ChangeKind::Added, before: None, after: Some(after.clone()) }]);
+    }
+}
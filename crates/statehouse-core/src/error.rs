@@ -0,0 +1,237 @@
+// Error types for Statehouse
+
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::types::*;
+
+/// Errors that clients can act on, carried inside `anyhow::Error`.
+/// Callers that need to distinguish them use `downcast_ref::<StatehouseError>()`.
+#[derive(Debug, Error)]
+pub enum StatehouseError {
+    /// A conditional operation found a different version than expected
+    #[error("Version conflict on {namespace}/{agent_id}/{key}: expected version {expected}, found {actual}")]
+    Conflict {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        expected: Version,
+        actual: Version,
+    },
+
+    /// The operation requires a key that doesn't exist or is deleted
+    #[error("Key not found: {namespace}/{agent_id}/{key}")]
+    KeyNotFound {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+    },
+
+    /// The operation requires a key to be absent, but it exists
+    #[error("Key already exists: {namespace}/{agent_id}/{key}")]
+    KeyExists {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+    },
+
+    /// A snapshot restore would move the commit timestamp backward
+    #[error("Snapshot at commit_ts {snapshot_ts} is older than the data directory (commit_ts {current_ts}); restoring it would reissue commit timestamps. Set allow_regression to override")]
+    SnapshotRegression {
+        snapshot_ts: CommitTs,
+        current_ts: CommitTs,
+    },
+
+    /// A client-supplied transaction id is already in use by an open transaction
+    #[error("Transaction already exists: {txn_id}")]
+    TransactionExists {
+        txn_id: TxnId,
+    },
+
+    /// Staging another operation would exceed a `TransactionLimits` cap.
+    /// The transaction is left open with its earlier operations intact.
+    #[error("Transaction {txn_id} exceeds {limit}")]
+    TransactionTooLarge {
+        txn_id: TxnId,
+        limit: String,
+    },
+
+    /// A written value nests deeper than `TransactionLimits::max_json_depth`
+    #[error("Value nests deeper than max_json_depth ({limit})")]
+    ValueTooDeep {
+        limit: usize,
+    },
+
+    /// A replay range covers more timestamps than `ReplayLimits::max_span`
+    #[error("Replay range spans {requested} timestamps, more than max_replay_span ({limit}); set start_ts and end_ts to cover at most {limit}")]
+    ReplaySpanTooLarge {
+        requested: CommitTs,
+        limit: CommitTs,
+    },
+
+    /// The storage backend can't acknowledge a commit at the requested durability
+    #[error("Durability {durability:?} is not supported by this storage backend")]
+    UnsupportedDurability {
+        durability: Durability,
+    },
+
+    /// A conditional write's value predicate didn't hold at commit
+    #[error("Condition on {namespace}/{agent_id}/{key} failed: {reason}")]
+    PredicateFailed {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        reason: String,
+    },
+
+    /// A write or delete made conditional on an ETag found the key changed
+    #[error("{namespace}/{agent_id}/{key} no longer matches ETag {expected}; its current ETag is {actual}")]
+    EtagMismatch {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        expected: String,
+        actual: String,
+    },
+
+    /// Committing would take a namespace past its `NamespaceQuota`. The
+    /// transaction is dropped without writing anything.
+    #[error("Namespace {namespace} would exceed {limit}: {usage} in use, transaction adds {added}")]
+    QuotaExceeded {
+        namespace: Namespace,
+        limit: String,
+        usage: u64,
+        added: u64,
+    },
+
+    /// A version read asked for a version older than any still stored
+    #[error("Version {version} of {namespace}/{agent_id}/{key} was compacted; the oldest retained version is {min_version}")]
+    VersionCompacted {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        version: Version,
+        min_version: Version,
+    },
+
+    /// A diff named a version the key never reached
+    #[error("Version {version} of {namespace}/{agent_id}/{key} does not exist")]
+    VersionNotFound {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        version: Version,
+    },
+
+    /// An undelete found a tombstone past its delete grace period, or one
+    /// written without a grace period
+    #[error("Deleted key {namespace}/{agent_id}/{key} is past its grace period and can no longer be restored")]
+    GracePeriodExpired {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+    },
+
+    /// An undelete of a key the same transaction already changes, so there
+    /// is no committed tombstone for it to restore from
+    #[error("Cannot undelete {namespace}/{agent_id}/{key}: the transaction already changes it")]
+    UndeleteAfterChange {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+    },
+
+    /// Another transaction has prepared a commit touching the key (see
+    /// `StateMachine::prepare_commit`). The rejected transaction is dropped.
+    #[error("Key {namespace}/{agent_id}/{key} is held by prepared transaction {txn_id}")]
+    KeyPrepared {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        txn_id: TxnId,
+    },
+
+    /// The transaction is prepared, so it can only be finalized or cancelled
+    #[error("Transaction {txn_id} is prepared; finalize or cancel it")]
+    TransactionPrepared {
+        txn_id: TxnId,
+    },
+
+    /// Finalize or cancel of a transaction that isn't prepared: still open,
+    /// or already cancelled
+    #[error("Transaction {txn_id} is not prepared")]
+    TransactionNotPrepared {
+        txn_id: TxnId,
+    },
+
+    /// A rollback or release named a savepoint the transaction doesn't
+    /// have: never taken, released, or rolled past
+    #[error("Savepoint {savepoint_id} not found in transaction {txn_id}")]
+    SavepointNotFound {
+        txn_id: TxnId,
+        savepoint_id: SavepointId,
+    },
+
+    /// Admission control is shedding load: commits aren't keeping up with
+    /// new work. Nothing was changed; retry after `retry_after`.
+    #[error("Server overloaded ({reason}); retry after {retry_after:?}")]
+    Overloaded {
+        reason: String,
+        retry_after: Duration,
+    },
+
+    /// A read passed the caller's deadline and stopped early
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+
+    /// RocksDB found its files damaged on open. Nothing was changed; the
+    /// directory needs a backup and a repair before it can be opened.
+    #[error("RocksDB data in {path} is corrupt ({reason}). Manual intervention needed: back up the directory, then run `statehoused repair <data_dir>` or restart with STATEHOUSE_AUTO_REPAIR=1")]
+    StorageCorrupt {
+        path: String,
+        reason: String,
+    },
+
+    /// RocksDB couldn't be opened for a reason other than corruption:
+    /// missing files, permissions, or another process holding the lock
+    #[error("Cannot open RocksDB data in {path} ({reason}). Check that it exists, is readable and writable by this user, and isn't in use by another statehoused")]
+    StorageUnavailable {
+        path: String,
+        reason: String,
+    },
+
+    /// A `StorageConfig` setting is out of range, or the data directory
+    /// can't be created or written; found by `StorageConfig::validate`
+    #[error("Invalid storage config: {field} {reason}")]
+    InvalidConfig {
+        field: &'static str,
+        reason: String,
+    },
+
+    /// A client-supplied transaction id is malformed
+    #[error("Invalid transaction id {txn_id:?}: {reason}")]
+    InvalidTxnId {
+        txn_id: TxnId,
+        reason: String,
+    },
+
+    /// An imported record is not newer, in both version and commit_ts, than
+    /// the key's current record. Records of one key must be imported oldest first.
+    #[error("Imported version {version} (commit_ts {commit_ts}) of {namespace}/{agent_id}/{key} is not newer than its current version {current_version} (commit_ts {current_commit_ts})")]
+    ImportOutOfOrder {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        version: Version,
+        commit_ts: CommitTs,
+        current_version: Version,
+        current_commit_ts: CommitTs,
+    },
+
+    /// A namespace rename targets a namespace that already has records,
+    /// without asking to merge into it
+    #[error("Namespace {namespace} already has records; rename into it with merge set")]
+    NamespaceNotEmpty {
+        namespace: Namespace,
+    },
+}
@@ -0,0 +1,94 @@
+// Per-namespace eviction policies
+//
+// A namespace used as a bounded cache can name a policy that makes room
+// instead of failing like a quota. Under `Lru { max_keys }`, a commit that
+// would leave more than `max_keys` live records in the namespace also
+// tombstones the records whose latest commit is oldest, as part of the same
+// event, so replay and replicas see the evictions like any other delete.
+// Storage keeps a per-namespace recency index so finding them doesn't scan
+// the namespace.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+use crate::types::Namespace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Keep at most `max_keys` live records, evicting the least recently
+    /// written first
+    Lru { max_keys: u64 },
+}
+
+/// The eviction policy of every namespace: a per-namespace one if set, else
+/// the default
+#[derive(Debug, Clone, Default)]
+pub struct EvictionPolicies {
+    default: Option<EvictionPolicy>,
+    namespaces: HashMap<Namespace, EvictionPolicy>,
+}
+
+impl EvictionPolicies {
+    /// Parse `namespace=policy;...`, where a policy is `lru:N` and the
+    /// namespace `*` sets the default, e.g. `*=lru:100000;sessions=lru:1000`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut policies = Self::default();
+        for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (namespace, policy) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid eviction policy {:?}: expected namespace=policy", entry))?;
+            let (name, value) = policy
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid eviction policy {:?}: expected lru:N", policy))?;
+            let value = value.trim().parse().map_err(|_| anyhow!("Invalid eviction policy {:?}: not a number", policy))?;
+            let policy = match name.trim() {
+                "lru" => EvictionPolicy::Lru { max_keys: value },
+                other => bail!("Unknown eviction policy {:?}: expected lru", other),
+            };
+            policies = match namespace.trim() {
+                "*" => policies.with_default(policy),
+                namespace => policies.with_policy(namespace, policy),
+            };
+        }
+        Ok(policies)
+    }
+
+    /// Policy for namespaces without their own
+    pub fn with_default(mut self, policy: EvictionPolicy) -> Self {
+        self.default = Some(policy);
+        self
+    }
+
+    pub fn with_policy(mut self, namespace: &str, policy: EvictionPolicy) -> Self {
+        self.namespaces.insert(namespace.to_string(), policy);
+        self
+    }
+
+    pub fn get(&self, namespace: &str) -> Option<EvictionPolicy> {
+        self.namespaces.get(namespace).copied().or(self.default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.namespaces.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let policies = EvictionPolicies::parse("*=lru:100; sessions=lru:10").unwrap();
+        assert_eq!(policies.get("sessions"), Some(EvictionPolicy::Lru { max_keys: 10 }));
+        assert_eq!(policies.get("other"), Some(EvictionPolicy::Lru { max_keys: 100 }));
+
+        let policies = EvictionPolicies::parse("sessions=lru:10").unwrap();
+        assert_eq!(policies.get("other"), None);
+        assert!(EvictionPolicies::parse("").unwrap().is_empty());
+
+        for bad in ["sessions", "sessions=lru", "sessions=lru:many", "sessions=fifo:1"] {
+            assert!(EvictionPolicies::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+// RocksDB key encoding
+//
+// Every key RocksStorage writes, apart from a few fixed `__name__` bookkeeping
+// keys, is a kind tag followed by that kind's components:
+//
+//   state\0   <namespace> <agent_id> <key>             latest record
+//   head\0    <namespace> <agent_id> <key>             17-byte record header
+//   version\0 <namespace> <agent_id> <key> <version>   one entry per version
+//   recent\0  <namespace> <commit_ts> <agent_id> <key> live records by latest commit
+//   event\0   <commit_ts>                              event log
+//   agentevent\0 <namespace> <agent_id> <commit_ts>    events touching an agent
+//   counter\0 <name> <namespace>                       per-namespace counters
+//   watermark\0 <namespace> <agent_id>                 agent's latest commit_ts
+//   blob\0    <hash>                                   value shared by records
+//   blobref\0 <hash>                                   entries referring to a blob
+//
+// Strings are escaped (0x00 -> 0x00 0xFF) and terminated by 0x00 0x01, so they
+// may contain any character, ':' and NUL included, and always decode back to
+// the same components. Integers are 8-byte big-endian. Both encodings preserve
+// order: keys of one kind sort like their component tuples, and the keys under
+// a namespace, an agent or a record form one contiguous range. (A length
+// prefix would delimit strings too, but keys would then sort by length first.)
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::types::{AgentId, CommitTs, Namespace, RecordId, Version};
+
+pub const STATE_TAG: &[u8] = b"state\0";
+pub const HEAD_TAG: &[u8] = b"head\0";
+pub const VERSION_TAG: &[u8] = b"version\0";
+pub const RECENT_TAG: &[u8] = b"recent\0";
+pub const EVENT_TAG: &[u8] = b"event\0";
+pub const AGENT_EVENT_TAG: &[u8] = b"agentevent\0";
+pub const COUNTER_TAG: &[u8] = b"counter\0";
+pub const WATERMARK_TAG: &[u8] = b"watermark\0";
+pub const BLOB_TAG: &[u8] = b"blob\0";
+pub const BLOB_REF_TAG: &[u8] = b"blobref\0";
+
+/// Counter holding a namespace's commit sequence
+pub const NAMESPACE_TS_COUNTER: &str = "namespace_ts";
+
+/// Counter holding a namespace's live record count and value bytes
+pub const NAMESPACE_USAGE_COUNTER: &str = "namespace_usage";
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_NUL: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+/// A key decoded by `decode_key`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedKey {
+    State(RecordId),
+    Head(RecordId),
+    Version(RecordId, Version),
+    Recent(CommitTs, RecordId),
+    Event(CommitTs),
+    AgentEvent { namespace: Namespace, agent_id: AgentId, commit_ts: CommitTs },
+    Counter { name: String, namespace: Namespace },
+    Watermark { namespace: Namespace, agent_id: AgentId },
+    Blob(String),
+    BlobRef(String),
+    /// Bookkeeping key outside the tagged scheme, e.g. `__commit_ts__`
+    Meta(String),
+}
+
+pub fn state_key(record_id: &RecordId) -> Vec<u8> {
+    record_key(STATE_TAG, record_id)
+}
+
+pub fn head_key(record_id: &RecordId) -> Vec<u8> {
+    record_key(HEAD_TAG, record_id)
+}
+
+pub fn version_key(record_id: &RecordId, version: Version) -> Vec<u8> {
+    let mut key = version_prefix(record_id);
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// Entry in `record_id`'s namespace's recency index for a record last
+/// written at `commit_ts`
+pub fn recent_key(record_id: &RecordId, commit_ts: CommitTs) -> Vec<u8> {
+    let mut key = namespace_recent_prefix(&record_id.namespace);
+    key.extend_from_slice(&commit_ts.to_be_bytes());
+    push_str(&mut key, &record_id.agent_id);
+    push_str(&mut key, &record_id.key);
+    key
+}
+
+pub fn event_key(commit_ts: CommitTs) -> Vec<u8> {
+    let mut key = EVENT_TAG.to_vec();
+    key.extend_from_slice(&commit_ts.to_be_bytes());
+    key
+}
+
+/// Entry in an agent's event index for the event at `commit_ts`
+pub fn agent_event_key(namespace: &str, agent_id: &str, commit_ts: CommitTs) -> Vec<u8> {
+    let mut key = agent_event_prefix(namespace, agent_id);
+    key.extend_from_slice(&commit_ts.to_be_bytes());
+    key
+}
+
+pub fn counter_key(name: &str, namespace: &str) -> Vec<u8> {
+    let mut key = COUNTER_TAG.to_vec();
+    push_str(&mut key, name);
+    push_str(&mut key, namespace);
+    key
+}
+
+pub fn watermark_key(namespace: &str, agent_id: &str) -> Vec<u8> {
+    let mut key = namespace_watermark_prefix(namespace);
+    push_str(&mut key, agent_id);
+    key
+}
+
+/// A deduplicated value, named by its `storage::value_hash`
+pub fn blob_key(hash: &str) -> Vec<u8> {
+    let mut key = BLOB_TAG.to_vec();
+    push_str(&mut key, hash);
+    key
+}
+
+/// How many `state` and `version` entries refer to the blob named `hash`
+pub fn blob_ref_key(hash: &str) -> Vec<u8> {
+    let mut key = BLOB_REF_TAG.to_vec();
+    push_str(&mut key, hash);
+    key
+}
+
+/// Prefix of the state keys of every record in `namespace`
+pub fn namespace_state_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = STATE_TAG.to_vec();
+    push_str(&mut prefix, namespace);
+    prefix
+}
+
+/// Prefix of the version keys of every record in `namespace`
+pub fn namespace_version_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = VERSION_TAG.to_vec();
+    push_str(&mut prefix, namespace);
+    prefix
+}
+
+/// Prefix of the head keys of every record in `namespace`
+pub fn namespace_head_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = HEAD_TAG.to_vec();
+    push_str(&mut prefix, namespace);
+    prefix
+}
+
+/// Prefix of the recency index of `namespace`, which iterates oldest first
+pub fn namespace_recent_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = RECENT_TAG.to_vec();
+    push_str(&mut prefix, namespace);
+    prefix
+}
+
+/// Prefix of the state keys of every record of one agent
+pub fn agent_state_prefix(namespace: &str, agent_id: &str) -> Vec<u8> {
+    let mut prefix = namespace_state_prefix(namespace);
+    push_str(&mut prefix, agent_id);
+    prefix
+}
+
+/// Prefix of the state keys of one agent's records whose key starts with `key_prefix`
+pub fn key_prefix_state_prefix(namespace: &str, agent_id: &str, key_prefix: &str) -> Vec<u8> {
+    let mut prefix = agent_state_prefix(namespace, agent_id);
+    push_escaped(&mut prefix, key_prefix);
+    prefix
+}
+
+/// Prefix of the head keys of one agent's records whose key starts with `key_prefix`
+pub fn key_prefix_head_prefix(namespace: &str, agent_id: &str, key_prefix: &str) -> Vec<u8> {
+    let mut prefix = namespace_head_prefix(namespace);
+    push_str(&mut prefix, agent_id);
+    push_escaped(&mut prefix, key_prefix);
+    prefix
+}
+
+/// Prefix of the event index of every agent in `namespace`
+pub fn namespace_agent_event_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = AGENT_EVENT_TAG.to_vec();
+    push_str(&mut prefix, namespace);
+    prefix
+}
+
+/// Prefix of one agent's event index, which iterates oldest first
+pub fn agent_event_prefix(namespace: &str, agent_id: &str) -> Vec<u8> {
+    let mut prefix = namespace_agent_event_prefix(namespace);
+    push_str(&mut prefix, agent_id);
+    prefix
+}
+
+/// Prefix of the watermarks of every agent in `namespace`
+pub fn namespace_watermark_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = WATERMARK_TAG.to_vec();
+    push_str(&mut prefix, namespace);
+    prefix
+}
+
+/// Prefix of every version key of exactly this record
+pub fn version_prefix(record_id: &RecordId) -> Vec<u8> {
+    record_key(VERSION_TAG, record_id)
+}
+
+/// Smallest key sorting after every key that starts with `prefix`, for use as
+/// an exclusive upper bound. Every prefix built here starts with a tag, so
+/// there is always a byte to increment.
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xFF) {
+        end.pop();
+    }
+    if let Some(last) = end.last_mut() {
+        *last += 1;
+    }
+    end
+}
+
+pub fn decode_state_key(key: &[u8]) -> Result<RecordId> {
+    let mut rest = strip_tag(key, STATE_TAG)?;
+    let record_id = take_record_id(&mut rest)?;
+    finish(rest)?;
+    Ok(record_id)
+}
+
+pub fn decode_head_key(key: &[u8]) -> Result<RecordId> {
+    let mut rest = strip_tag(key, HEAD_TAG)?;
+    let record_id = take_record_id(&mut rest)?;
+    finish(rest)?;
+    Ok(record_id)
+}
+
+pub fn decode_version_key(key: &[u8]) -> Result<(RecordId, Version)> {
+    let mut rest = strip_tag(key, VERSION_TAG)?;
+    let record_id = take_record_id(&mut rest)?;
+    let version = take_u64(&mut rest)?;
+    finish(rest)?;
+    Ok((record_id, version))
+}
+
+/// Latest commit and record of a recency index entry
+pub fn decode_recent_key(key: &[u8]) -> Result<(CommitTs, RecordId)> {
+    let mut rest = strip_tag(key, RECENT_TAG)?;
+    let namespace = take_str(&mut rest)?;
+    let commit_ts = take_u64(&mut rest)?;
+    let agent_id = take_str(&mut rest)?;
+    let key = take_str(&mut rest)?;
+    finish(rest)?;
+    Ok((commit_ts, RecordId::new(namespace, agent_id, key)))
+}
+
+pub fn decode_event_key(key: &[u8]) -> Result<CommitTs> {
+    let mut rest = strip_tag(key, EVENT_TAG)?;
+    let commit_ts = take_u64(&mut rest)?;
+    finish(rest)?;
+    Ok(commit_ts)
+}
+
+/// Namespace, agent and commit_ts of an agent event index entry
+pub fn decode_agent_event_key(key: &[u8]) -> Result<(Namespace, AgentId, CommitTs)> {
+    let mut rest = strip_tag(key, AGENT_EVENT_TAG)?;
+    let namespace = take_str(&mut rest)?;
+    let agent_id = take_str(&mut rest)?;
+    let commit_ts = take_u64(&mut rest)?;
+    finish(rest)?;
+    Ok((namespace, agent_id, commit_ts))
+}
+
+/// Counter name and namespace
+pub fn decode_counter_key(key: &[u8]) -> Result<(String, Namespace)> {
+    let mut rest = strip_tag(key, COUNTER_TAG)?;
+    let name = take_str(&mut rest)?;
+    let namespace = take_str(&mut rest)?;
+    finish(rest)?;
+    Ok((name, namespace))
+}
+
+/// Decode any key RocksStorage writes, e.g. to print a dump of the database
+/// Namespace and agent
+pub fn decode_watermark_key(key: &[u8]) -> Result<(Namespace, AgentId)> {
+    let mut rest = strip_tag(key, WATERMARK_TAG)?;
+    let namespace = take_str(&mut rest)?;
+    let agent_id = take_str(&mut rest)?;
+    finish(rest)?;
+    Ok((namespace, agent_id))
+}
+
+pub fn decode_blob_key(key: &[u8]) -> Result<String> {
+    let mut rest = strip_tag(key, BLOB_TAG)?;
+    let hash = take_str(&mut rest)?;
+    finish(rest)?;
+    Ok(hash)
+}
+
+pub fn decode_blob_ref_key(key: &[u8]) -> Result<String> {
+    let mut rest = strip_tag(key, BLOB_REF_TAG)?;
+    let hash = take_str(&mut rest)?;
+    finish(rest)?;
+    Ok(hash)
+}
+
+pub fn decode_key(key: &[u8]) -> Result<DecodedKey> {
+    if key.starts_with(STATE_TAG) {
+        decode_state_key(key).map(DecodedKey::State)
+    } else if key.starts_with(HEAD_TAG) {
+        decode_head_key(key).map(DecodedKey::Head)
+    } else if key.starts_with(VERSION_TAG) {
+        decode_version_key(key).map(|(record_id, version)| DecodedKey::Version(record_id, version))
+    } else if key.starts_with(RECENT_TAG) {
+        decode_recent_key(key).map(|(commit_ts, record_id)| DecodedKey::Recent(commit_ts, record_id))
+    } else if key.starts_with(EVENT_TAG) {
+        decode_event_key(key).map(DecodedKey::Event)
+    } else if key.starts_with(AGENT_EVENT_TAG) {
+        decode_agent_event_key(key).map(|(namespace, agent_id, commit_ts)| DecodedKey::AgentEvent { namespace, agent_id, commit_ts })
+    } else if key.starts_with(COUNTER_TAG) {
+        decode_counter_key(key).map(|(name, namespace)| DecodedKey::Counter { name, namespace })
+    } else if key.starts_with(WATERMARK_TAG) {
+        decode_watermark_key(key).map(|(namespace, agent_id)| DecodedKey::Watermark { namespace, agent_id })
+    } else if key.starts_with(BLOB_TAG) {
+        decode_blob_key(key).map(DecodedKey::Blob)
+    } else if key.starts_with(BLOB_REF_TAG) {
+        decode_blob_ref_key(key).map(DecodedKey::BlobRef)
+    } else if key.starts_with(b"__") {
+        Ok(DecodedKey::Meta(String::from_utf8(key.to_vec())?))
+    } else {
+        Err(anyhow!("Unrecognized key: {:?}", String::from_utf8_lossy(key)))
+    }
+}
+
+fn record_key(tag: &[u8], record_id: &RecordId) -> Vec<u8> {
+    let mut key = tag.to_vec();
+    push_str(&mut key, &record_id.namespace);
+    push_str(&mut key, &record_id.agent_id);
+    push_str(&mut key, &record_id.key);
+    key
+}
+
+/// Append `s` escaped but unterminated, which is a prefix of the encoding of
+/// every string starting with `s`
+fn push_escaped(out: &mut Vec<u8>, s: &str) {
+    for &byte in s.as_bytes() {
+        out.push(byte);
+        if byte == ESCAPE {
+            out.push(ESCAPED_NUL);
+        }
+    }
+}
+
+fn push_str(out: &mut Vec<u8>, s: &str) {
+    push_escaped(out, s);
+    out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+fn strip_tag<'a>(key: &'a [u8], tag: &[u8]) -> Result<&'a [u8]> {
+    key.strip_prefix(tag)
+        .ok_or_else(|| anyhow!("Expected {:?} key", String::from_utf8_lossy(&tag[..tag.len() - 1])))
+}
+
+fn take_record_id(rest: &mut &[u8]) -> Result<RecordId> {
+    let namespace = take_str(rest)?;
+    let agent_id = take_str(rest)?;
+    let key = take_str(rest)?;
+    Ok(RecordId::new(namespace, agent_id, key))
+}
+
+fn take_str(rest: &mut &[u8]) -> Result<String> {
+    let mut bytes = Vec::new();
+    let mut pos = 0;
+    loop {
+        match rest.get(pos) {
+            None => bail!("Unterminated string in key"),
+            Some(&ESCAPE) => match rest.get(pos + 1) {
+                Some(&TERMINATOR) => break,
+                Some(&ESCAPED_NUL) => {
+                    bytes.push(0);
+                    pos += 2;
+                }
+                _ => bail!("Invalid escape in key"),
+            },
+            Some(&byte) => {
+                bytes.push(byte);
+                pos += 1;
+            }
+        }
+    }
+    *rest = &rest[pos + 2..];
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn take_u64(rest: &mut &[u8]) -> Result<u64> {
+    if rest.len() < 8 {
+        bail!("Truncated integer in key");
+    }
+    let (bytes, tail) = rest.split_at(8);
+    *rest = tail;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn finish(rest: &[u8]) -> Result<()> {
+    if !rest.is_empty() {
+        bail!("{} trailing bytes in key", rest.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(namespace: &str, agent_id: &str, key: &str) -> RecordId {
+        RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string())
+    }
+
+    const AWKWARD: &[&str] = &["", "a", "a:b", ":", "a\0", "\0", "\0\u{1}", "a\u{1}", "a\u{ff}", "ключ", "鍵:値", "🦀"];
+
+    #[test]
+    fn test_round_trip() {
+        for namespace in AWKWARD {
+            for agent_id in AWKWARD {
+                for key in AWKWARD {
+                    let record_id = record(namespace, agent_id, key);
+                    assert_eq!(decode_state_key(&state_key(&record_id)).unwrap(), record_id);
+                    assert_eq!(decode_head_key(&head_key(&record_id)).unwrap(), record_id);
+                    assert_eq!(
+                        decode_version_key(&version_key(&record_id, 42)).unwrap(),
+                        (record_id.clone(), 42)
+                    );
+                    assert_eq!(decode_recent_key(&recent_key(&record_id, 42)).unwrap(), (42, record_id.clone()));
+                    assert_eq!(decode_key(&state_key(&record_id)).unwrap(), DecodedKey::State(record_id));
+                }
+                assert_eq!(
+                    decode_watermark_key(&watermark_key(namespace, agent_id)).unwrap(),
+                    (namespace.to_string(), agent_id.to_string())
+                );
+                assert_eq!(
+                    decode_agent_event_key(&agent_event_key(namespace, agent_id, 42)).unwrap(),
+                    (namespace.to_string(), agent_id.to_string(), 42)
+                );
+            }
+            assert_eq!(
+                decode_counter_key(&counter_key(NAMESPACE_TS_COUNTER, namespace)).unwrap(),
+                (NAMESPACE_TS_COUNTER.to_string(), namespace.to_string())
+            );
+        }
+
+        for commit_ts in [0, 1, 255, 256, CommitTs::MAX] {
+            assert_eq!(decode_event_key(&event_key(commit_ts)).unwrap(), commit_ts);
+        }
+        assert_eq!(decode_key(b"__commit_ts__").unwrap(), DecodedKey::Meta("__commit_ts__".to_string()));
+        assert_eq!(decode_key(&blob_key("af13")).unwrap(), DecodedKey::Blob("af13".to_string()));
+        assert_eq!(decode_key(&blob_ref_key("af13")).unwrap(), DecodedKey::BlobRef("af13".to_string()));
+
+        // Kinds don't decode as each other, and damaged keys are rejected
+        let record_id = record("ns", "agent", "key");
+        assert!(decode_state_key(&head_key(&record_id)).is_err());
+        let key = state_key(&record_id);
+        assert!(decode_state_key(&key[..key.len() - 1]).is_err());
+        assert!(decode_state_key(&[key.as_slice(), b"x"].concat()).is_err());
+        assert!(decode_event_key(&event_key(7)[..10]).is_err());
+        assert!(decode_key(b"state:ns:agent:key").is_err());
+    }
+
+    #[test]
+    fn test_order_preserving() {
+        let mut tuples = Vec::new();
+        for namespace in AWKWARD {
+            for agent_id in AWKWARD {
+                for key in AWKWARD {
+                    tuples.push(record(namespace, agent_id, key));
+                }
+            }
+        }
+        let tuple_order = |a: &RecordId, b: &RecordId| {
+            (a.namespace.as_bytes(), a.agent_id.as_bytes(), a.key.as_bytes())
+                .cmp(&(b.namespace.as_bytes(), b.agent_id.as_bytes(), b.key.as_bytes()))
+        };
+
+        let mut by_tuple = tuples.clone();
+        by_tuple.sort_by(tuple_order);
+        let mut by_key = tuples.clone();
+        by_key.sort_by_key(state_key);
+        assert_eq!(by_key, by_tuple);
+
+        // Versions sort numerically within a record and never interleave with another record's
+        let mut versions: Vec<(RecordId, Version)> = Vec::new();
+        for record_id in &tuples {
+            for version in [1, 2, 10, 256] {
+                versions.push((record_id.clone(), version));
+            }
+        }
+        let mut by_tuple = versions.clone();
+        by_tuple.sort_by(|a, b| tuple_order(&a.0, &b.0).then(a.1.cmp(&b.1)));
+        versions.sort_by_key(|(record_id, version)| version_key(record_id, *version));
+        assert_eq!(versions, by_tuple);
+
+        let mut timestamps = vec![300, 2, CommitTs::MAX, 0, 256, 255];
+        let mut sorted = timestamps.clone();
+        sorted.sort();
+        timestamps.sort_by_key(|ts| event_key(*ts));
+        assert_eq!(timestamps, sorted);
+    }
+
+    #[test]
+    fn test_prefixes_select_exact_ranges() {
+        for namespace in AWKWARD {
+            for agent_id in AWKWARD {
+                for key in AWKWARD {
+                    let record_id = record(namespace, agent_id, key);
+                    let state = state_key(&record_id);
+                    let version = version_key(&record_id, 3);
+
+                    for other in AWKWARD {
+                        // Namespace and agent prefixes match their own records only
+                        let in_namespace = state.starts_with(&namespace_state_prefix(other));
+                        assert_eq!(in_namespace, namespace == other);
+                        let of_agent = state.starts_with(&agent_state_prefix(namespace, other));
+                        assert_eq!(of_agent, agent_id == other);
+
+                        // Key prefixes match like str::starts_with
+                        let matches_prefix = state.starts_with(&key_prefix_state_prefix(namespace, agent_id, other));
+                        assert_eq!(matches_prefix, key.starts_with(other), "{:?} {:?}", key, other);
+
+                        // A record's version prefix never covers another key's versions
+                        let covers = version.starts_with(&version_prefix(&record(namespace, agent_id, other)));
+                        assert_eq!(covers, key == other);
+                    }
+
+                    let prefix = namespace_state_prefix(namespace);
+                    assert!(prefix.as_slice() <= state.as_slice() && state < prefix_end(&prefix));
+                    let prefix = key_prefix_state_prefix(namespace, agent_id, key);
+                    assert!(prefix.as_slice() <= state.as_slice() && state < prefix_end(&prefix));
+                }
+            }
+        }
+    }
+}
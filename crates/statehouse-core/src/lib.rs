@@ -1,8 +1,30 @@
 // Statehouse Core
 // Core state machine, storage, and business logic
 
+pub mod admission;
+mod cache;
+pub mod coalesce;
+pub mod commit_queue;
+#[cfg(test)]
+mod crash_recovery;
+pub mod deadline;
+pub mod diff;
+pub mod error;
+pub mod eviction;
+pub mod key_codec;
+pub mod predicate;
+pub mod projection;
+pub mod quota;
+pub mod replication;
+pub mod schema;
+pub mod session;
 pub mod storage;
+#[cfg(test)]
+mod storage_conformance;
 pub mod state_machine;
+pub mod ttl;
 pub mod types;
+pub mod wal;
 
+pub use error::StatehouseError;
 pub use types::*;
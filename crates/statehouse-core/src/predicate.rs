@@ -0,0 +1,148 @@
+// Value predicates for conditional writes
+//
+// A predicate compares one field of a key's current value, selected by a JSON
+// pointer, against a literal. It is evaluated at commit time, under the commit
+// lock, so "write only if status is pending" can't race another writer.
+// A missing key or a missing field never satisfies a predicate, whatever the
+// operator: `Ne` means "present and different", not "anything but".
+
+use anyhow::Result;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::projection;
+
+/// Comparison a `ValuePredicate` applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    /// Ordering operators compare two numbers or two strings; a field of any
+    /// other type fails them
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            CompareOp::Eq => "==",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+}
+
+/// `<field at pointer> <op> <operand>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValuePredicate {
+    pointer: String,
+    tokens: Vec<String>,
+    op: CompareOp,
+    operand: Value,
+}
+
+impl ValuePredicate {
+    /// Fails if `pointer` is not a valid JSON pointer. The empty pointer
+    /// compares the whole value.
+    pub fn new(pointer: &str, op: CompareOp, operand: Value) -> Result<Self> {
+        Ok(Self {
+            pointer: pointer.to_string(),
+            tokens: projection::parse_pointer(pointer)?,
+            op,
+            operand,
+        })
+    }
+
+    pub fn pointer(&self) -> &str {
+        &self.pointer
+    }
+
+    pub fn operand(&self) -> &Value {
+        &self.operand
+    }
+
+    /// Check the predicate against a key's live value (`None` if the key is
+    /// absent or deleted), explaining why it doesn't hold
+    pub fn check(&self, current: Option<&Value>) -> std::result::Result<(), String> {
+        let Some(current) = current else {
+            return Err("key does not exist".to_string());
+        };
+        let Some(field) = projection::select(current, &self.tokens) else {
+            return Err(format!("field {:?} does not exist", self.pointer));
+        };
+
+        let holds = match self.op {
+            CompareOp::Eq => values_equal(field, &self.operand),
+            CompareOp::Ne => !values_equal(field, &self.operand),
+            op => compare(field, &self.operand).is_some_and(|ordering| match op {
+                CompareOp::Lt => ordering == Ordering::Less,
+                CompareOp::Le => ordering != Ordering::Greater,
+                CompareOp::Gt => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }),
+        };
+        if holds {
+            Ok(())
+        } else {
+            Err(format!("{:?} {} {} does not hold: the field is {}", self.pointer, self.op.symbol(), self.operand, field))
+        }
+    }
+}
+
+/// JSON equality, except that numbers compare by value (1 equals 1.0)
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare(a, b) == Some(Ordering::Equal),
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check() {
+        let value = json!({"status": "pending", "attempts": 2, "meta": {"owner": null}});
+        let check = |pointer: &str, op: CompareOp, operand: Value, current: Option<&Value>| {
+            ValuePredicate::new(pointer, op, operand).unwrap().check(current)
+        };
+
+        assert!(check("/status", CompareOp::Eq, json!("pending"), Some(&value)).is_ok());
+        assert!(check("/status", CompareOp::Eq, json!("done"), Some(&value)).is_err());
+        assert!(check("/status", CompareOp::Ne, json!("done"), Some(&value)).is_ok());
+        assert!(check("/attempts", CompareOp::Eq, json!(2.0), Some(&value)).is_ok());
+        assert!(check("/attempts", CompareOp::Lt, json!(3), Some(&value)).is_ok());
+        assert!(check("/attempts", CompareOp::Ge, json!(3), Some(&value)).is_err());
+        assert!(check("/status", CompareOp::Gt, json!("a"), Some(&value)).is_ok());
+        assert!(check("/status", CompareOp::Gt, json!(1), Some(&value)).is_err());
+        assert!(check("/meta/owner", CompareOp::Eq, Value::Null, Some(&value)).is_ok());
+        assert!(check("", CompareOp::Eq, value.clone(), Some(&value)).is_ok());
+
+        // Missing keys and fields fail every operator
+        let err = check("/status", CompareOp::Ne, json!("done"), None).unwrap_err();
+        assert_eq!(err, "key does not exist");
+        let err = check("/owner", CompareOp::Ne, json!("bob"), Some(&value)).unwrap_err();
+        assert!(err.contains("does not exist"), "{}", err);
+
+        assert!(ValuePredicate::new("status", CompareOp::Eq, Value::Null).is_err());
+    }
+}
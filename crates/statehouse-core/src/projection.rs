@@ -0,0 +1,102 @@
+// Read-time projection of stored values
+//
+// A projection is a list of JSON pointers (RFC 6901). The projected value keeps
+// only the selected subtrees, nested under the same path they have in the
+// stored value, so callers index into it the same way. Array indices along a
+// path become object keys ("/items/0/id" projects to {"items": {"0": {"id": ..}}}),
+// since the result must stay an object.
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+/// Split a JSON pointer into unescaped reference tokens. The empty pointer
+/// selects the whole value.
+pub fn parse_pointer(pointer: &str) -> Result<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        bail!("Invalid JSON pointer {:?}: must be empty or start with '/'", pointer);
+    };
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+/// Keep only the subtrees of `value` selected by `pointers`. Pointers that
+/// match nothing are skipped; if none match, the result is an empty object.
+/// Overlapping pointers are fine: the widest selection wins.
+pub fn project(value: &Value, pointers: &[String]) -> Result<Value> {
+    let mut projected = Value::Object(Map::new());
+    for pointer in pointers {
+        let tokens = parse_pointer(pointer)?;
+        if let Some(selected) = select(value, &tokens) {
+            insert(&mut projected, &tokens, selected.clone());
+        }
+    }
+    Ok(projected)
+}
+
+/// The subtree at a parsed pointer, if there is one
+pub(crate) fn select<'a>(value: &'a Value, tokens: &[String]) -> Option<&'a Value> {
+    tokens.iter().try_fold(value, |node, token| match node {
+        Value::Object(fields) => fields.get(token),
+        Value::Array(items) => token.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+fn insert(projected: &mut Value, tokens: &[String], selected: Value) {
+    let Some((last, parents)) = tokens.split_last() else {
+        // The whole value, which is always an object when stored
+        *projected = selected;
+        return;
+    };
+    let mut node = projected;
+    for token in parents {
+        let Value::Object(fields) = node else {
+            // An earlier pointer already selected this whole subtree
+            return;
+        };
+        node = fields.entry(token.clone()).or_insert_with(|| Value::Object(Map::new()));
+    }
+    if let Value::Object(fields) = node {
+        fields.insert(last.clone(), selected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_project() {
+        let value = json!({
+            "profile": {"name": "ada", "address": {"city": "London", "zip": "N1"}},
+            "items": [{"id": 1}, {"id": 2}],
+            "a/b": {"~c": true},
+        });
+        let project_paths = |paths: &[&str]| {
+            let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+            project(&value, &paths).unwrap()
+        };
+
+        assert_eq!(project_paths(&["/profile/address/city"]), json!({"profile": {"address": {"city": "London"}}}));
+        assert_eq!(
+            project_paths(&["/profile/name", "/items/1/id"]),
+            json!({"profile": {"name": "ada"}, "items": {"1": {"id": 2}}})
+        );
+        assert_eq!(project_paths(&["/a~1b/~0c"]), json!({"a/b": {"~c": true}}));
+
+        // The widest selection wins, in either order
+        let expected = json!({"profile": value["profile"].clone()});
+        assert_eq!(project_paths(&["/profile", "/profile/name"]), expected);
+        assert_eq!(project_paths(&["/profile/name", "/profile"]), expected);
+        assert_eq!(project_paths(&[""]), value);
+
+        // Unmatched pointers select nothing
+        assert_eq!(project_paths(&["/missing", "/items/9", "/profile/name/first"]), json!({}));
+        assert_eq!(project_paths(&[]), json!({}));
+
+        assert!(project(&value, &["profile".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+// Per-namespace storage quotas
+//
+// A quota caps how many live records a namespace holds and/or the bytes of
+// their values (serialized as JSON). Storage keeps each namespace's usage as
+// records are written; `StateMachine::commit` adds up what a transaction would
+// change and rejects it before anything is written if that would take the
+// namespace past its quota. A transaction that doesn't grow a dimension is
+// never rejected for it, so a namespace over quota (after the quota was
+// lowered, say) can always delete its way back under.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+use crate::types::Namespace;
+
+/// Limits for one namespace; `None` leaves a dimension uncapped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceQuota {
+    pub max_records: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// The quota of every namespace: a per-namespace one if set, else the default
+#[derive(Debug, Clone, Default)]
+pub struct NamespaceQuotas {
+    default: Option<NamespaceQuota>,
+    namespaces: HashMap<Namespace, NamespaceQuota>,
+}
+
+impl NamespaceQuotas {
+    /// Parse `namespace=limit,limit;...`, where a limit is `records:N` or
+    /// `bytes:N` and the namespace `*` sets the default, e.g.
+    /// `*=records:100000;tenant-a=records:1000,bytes:10485760`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut quotas = Self::default();
+        for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (namespace, limits) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid namespace quota {:?}: expected namespace=limits", entry))?;
+            let mut quota = NamespaceQuota::default();
+            for limit in limits.split(',').map(str::trim) {
+                let (name, value) = limit
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Invalid quota limit {:?}: expected records:N or bytes:N", limit))?;
+                let value = value.trim().parse().map_err(|_| anyhow!("Invalid quota limit {:?}: not a number", limit))?;
+                match name.trim() {
+                    "records" => quota.max_records = Some(value),
+                    "bytes" => quota.max_bytes = Some(value),
+                    other => bail!("Unknown quota limit {:?}: expected records or bytes", other),
+                }
+            }
+            quotas = match namespace.trim() {
+                "*" => quotas.with_default(quota),
+                namespace => quotas.with_quota(namespace, quota),
+            };
+        }
+        Ok(quotas)
+    }
+
+    /// Quota for namespaces without their own
+    pub fn with_default(mut self, quota: NamespaceQuota) -> Self {
+        self.default = Some(quota);
+        self
+    }
+
+    pub fn with_quota(mut self, namespace: &str, quota: NamespaceQuota) -> Self {
+        self.namespaces.insert(namespace.to_string(), quota);
+        self
+    }
+
+    pub fn get(&self, namespace: &str) -> Option<NamespaceQuota> {
+        self.namespaces.get(namespace).copied().or(self.default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.namespaces.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let quotas = NamespaceQuotas::parse("*=records:100; tenant-a=records:10,bytes:2048;tenant-b=bytes:1").unwrap();
+        assert_eq!(quotas.get("tenant-a"), Some(NamespaceQuota { max_records: Some(10), max_bytes: Some(2048) }));
+        assert_eq!(quotas.get("tenant-b"), Some(NamespaceQuota { max_records: None, max_bytes: Some(1) }));
+        assert_eq!(quotas.get("other"), Some(NamespaceQuota { max_records: Some(100), max_bytes: None }));
+
+        let quotas = NamespaceQuotas::parse("tenant-a=records:10").unwrap();
+        assert_eq!(quotas.get("other"), None);
+        assert!(NamespaceQuotas::parse("").unwrap().is_empty());
+
+        for bad in ["tenant-a", "tenant-a=records", "tenant-a=records:many", "tenant-a=keys:1"] {
+            assert!(NamespaceQuotas::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// Replication hooks
+//
+// Asynchronous, best-effort log shipping to a warm standby. This is not
+// consensus: a standby may lag the primary, and events dropped under
+// backpressure leave it behind until it is reseeded from a snapshot.
+
+use crate::storage::EventLogEntry;
+
+/// Receives every committed event on the primary, in commit order
+pub trait ReplicationSink: Send + Sync {
+    /// Queue `event` for shipping. Called from the writer, after the
+    /// event is durable locally, so it must not block.
+    fn ship(&self, event: &EventLogEntry);
+}
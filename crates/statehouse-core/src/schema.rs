@@ -0,0 +1,142 @@
+// Schema inference from sampled values
+//
+// Describes what an agent's values look like without reading them all.
+// Sampled values are merged into one tree of `SchemaNode`s: objects field by
+// field and, since arrays are usually lists of like things, every element of
+// every array into a single `items` node. Each node records the JSON types
+// seen there, so one seen with several is a union, and each object field how
+// many of the objects seen there had it, so one missing from some is
+// optional.
+
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JsonType {
+    Null,
+    Boolean,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Boolean,
+            Value::Number(_) => JsonType::Number,
+            Value::String(_) => JsonType::String,
+            Value::Array(_) => JsonType::Array,
+            Value::Object(_) => JsonType::Object,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JsonType::Null => "null",
+            JsonType::Boolean => "boolean",
+            JsonType::Number => "number",
+            JsonType::String => "string",
+            JsonType::Array => "array",
+            JsonType::Object => "object",
+        }
+    }
+}
+
+/// The values seen at one place in the sampled values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaNode {
+    /// Values seen here
+    pub count: u64,
+    /// Their types; more than one makes this a union
+    pub types: BTreeSet<JsonType>,
+    /// Of those values, how many were objects
+    pub objects: u64,
+    /// Fields of those objects; a field's `count` is how many had it
+    pub fields: BTreeMap<String, SchemaNode>,
+    /// Every element of the arrays seen here, merged
+    pub items: Option<Box<SchemaNode>>,
+}
+
+impl SchemaNode {
+    /// Fold `value` into what has been seen here
+    pub fn merge(&mut self, value: &Value) {
+        self.count += 1;
+        self.types.insert(JsonType::of(value));
+        match value {
+            Value::Object(object) => {
+                self.objects += 1;
+                for (name, field) in object {
+                    self.fields.entry(name.clone()).or_default().merge(field);
+                }
+            }
+            Value::Array(elements) => {
+                let items = self.items.get_or_insert_with(Default::default);
+                for element in elements {
+                    items.merge(element);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_union(&self) -> bool {
+        self.types.len() > 1
+    }
+
+    /// Whether some of the objects seen here lacked `field`
+    pub fn is_optional(&self, field: &str) -> bool {
+        self.fields.get(field).is_some_and(|node| node.count < self.objects)
+    }
+}
+
+/// Schema inferred from up to `sample_size` of an agent's values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InferredSchema {
+    /// Values merged into `root`
+    pub sampled: u64,
+    pub root: SchemaNode,
+}
+
+impl InferredSchema {
+    pub fn infer<'a>(values: impl IntoIterator<Item = &'a Value>) -> Self {
+        let mut root = SchemaNode::default();
+        for value in values {
+            root.merge(value);
+        }
+        Self { sampled: root.count, root }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_infer() {
+        let values = [
+            json!({"id": 1, "name": "ada", "tags": ["a", "b"], "address": {"city": "London"}}),
+            json!({"id": 2, "name": null, "tags": [], "address": {"city": "Paris", "zip": "75001"}}),
+            json!({"id": "3", "tags": [1]}),
+        ];
+        let schema = InferredSchema::infer(&values);
+        assert_eq!(schema.sampled, 3);
+
+        let root = &schema.root;
+        assert_eq!(root.types, BTreeSet::from([JsonType::Object]));
+        assert_eq!(root.fields.keys().collect::<Vec<_>>(), ["address", "id", "name", "tags"]);
+        assert!(!root.is_optional("id") && root.is_optional("name") && root.is_optional("address"));
+        assert_eq!(root.fields["id"].types, BTreeSet::from([JsonType::Number, JsonType::String]));
+        assert!(root.fields["id"].is_union());
+        assert_eq!(root.fields["name"].types, BTreeSet::from([JsonType::Null, JsonType::String]));
+
+        let items = root.fields["tags"].items.as_deref().unwrap();
+        assert_eq!((items.count, items.types.clone()), (3, BTreeSet::from([JsonType::Number, JsonType::String])));
+        let address = &root.fields["address"];
+        assert!(!address.is_optional("city") && address.is_optional("zip"));
+        assert!(!root.is_optional("missing"));
+    }
+}
@@ -0,0 +1,150 @@
+// Sessions
+//
+// A `Session` bundles a read transaction and a write transaction so a caller
+// doing several operations needn't thread transaction ids through them.
+// Reads see the state as of when the session was opened (the read
+// transaction's timestamp) overlaid with the session's own uncommitted
+// writes, so the session reads its writes but nobody else's commits made
+// since it opened. Writes are staged in a write transaction, begun with the
+// first one, and land together on `commit`; commits are last-writer-wins, as
+// for any transaction.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::state_machine::{CommitResult, StateMachine};
+use crate::types::*;
+
+/// Handle from `StateMachine::open_session`. Dropping it without committing
+/// aborts its writes.
+pub struct Session<'a> {
+    state_machine: &'a StateMachine,
+    read_txn_id: TxnId,
+    read_ts: CommitTs,
+    write_txn_id: Option<TxnId>,
+    /// Latest value the session wrote per key; `None` for a delete
+    writes: HashMap<RecordId, Option<serde_json::Value>>,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn open(state_machine: &'a StateMachine) -> Result<Self> {
+        let (read_txn_id, read_ts) = state_machine.begin_read_transaction(None)?;
+        Ok(Self { state_machine, read_txn_id, read_ts, write_txn_id: None, writes: HashMap::new() })
+    }
+
+    /// Commit timestamp the session reads as of
+    pub fn read_ts(&self) -> CommitTs {
+        self.read_ts
+    }
+
+    /// The key's value as this session sees it: its own latest write if it
+    /// made one, otherwise the value as of `read_ts`. `None` if absent or
+    /// deleted.
+    pub fn get(&self, namespace: &str, agent_id: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        if let Some(value) = self.writes.get(&record_id) {
+            return Ok(value.clone());
+        }
+        let record = self.state_machine.get_state_batch_in_snapshot(&self.read_txn_id, &[record_id])?.pop().flatten();
+        Ok(record.filter(|r| !r.deleted).and_then(|r| r.value))
+    }
+
+    /// Stage a write, visible to this session's reads at once and to
+    /// everyone else after `commit`
+    pub fn put(&mut self, namespace: &str, agent_id: &str, key: &str, value: serde_json::Value) -> Result<()> {
+        let txn_id = self.write_txn()?;
+        self.state_machine.write(&txn_id, namespace.to_string(), agent_id.to_string(), key.to_string(), value.clone())?;
+        self.writes.insert(RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string()), Some(value));
+        Ok(())
+    }
+
+    /// Stage a delete, like `put`
+    pub fn delete(&mut self, namespace: &str, agent_id: &str, key: &str) -> Result<()> {
+        let txn_id = self.write_txn()?;
+        self.state_machine.delete(&txn_id, namespace.to_string(), agent_id.to_string(), key.to_string())?;
+        self.writes.insert(RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string()), None);
+        Ok(())
+    }
+
+    /// Commit the session's writes and close it. A session that wrote
+    /// nothing commits nothing and returns an empty result.
+    pub fn commit(mut self) -> Result<CommitResult> {
+        match self.write_txn_id.take() {
+            Some(txn_id) => self.state_machine.commit(&txn_id),
+            None => Ok(CommitResult::default()),
+        }
+    }
+
+    fn write_txn(&mut self) -> Result<TxnId> {
+        if let Some(txn_id) = &self.write_txn_id {
+            return Ok(txn_id.clone());
+        }
+        let txn_id = self.state_machine.begin_transaction(None)?;
+        self.write_txn_id = Some(txn_id.clone());
+        Ok(txn_id)
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        // Both fail only if the transaction already expired, which leaves
+        // nothing to clean up
+        if let Some(txn_id) = self.write_txn_id.take() {
+            let _ = self.state_machine.abort(&txn_id);
+        }
+        let _ = self.state_machine.abort(&self.read_txn_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{RocksStorage, StorageConfig};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_session_reads_own_writes_in_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig { data_dir: temp_dir.path().to_path_buf(), ..StorageConfig::default() };
+        let sm = StateMachine::new(Arc::new(RocksStorage::new(config.clone()).unwrap()));
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "plan".to_string(), serde_json::json!("draft")).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let earlier = sm.open_session().unwrap();
+        let mut session = sm.open_session().unwrap();
+        session.put("default", "agent-1", "plan", serde_json::json!("final")).unwrap();
+        session.put("default", "agent-1", "note", serde_json::json!(1)).unwrap();
+        session.delete("default", "agent-1", "note").unwrap();
+
+        // Only the writing session sees its writes before commit
+        assert_eq!(session.get("default", "agent-1", "plan").unwrap(), Some(serde_json::json!("final")));
+        assert_eq!(session.get("default", "agent-1", "note").unwrap(), None);
+        assert_eq!(earlier.get("default", "agent-1", "plan").unwrap(), Some(serde_json::json!("draft")));
+        assert_eq!(sm.get_state("default", "agent-1", "plan").unwrap().unwrap().value, Some(serde_json::json!("draft")));
+
+        let result = session.commit().unwrap();
+        assert!(result.commit_ts > earlier.read_ts());
+        assert!(sm.list_open_transactions().is_empty());
+
+        // The earlier session keeps its snapshot; a new one sees the commit
+        assert_eq!(earlier.get("default", "agent-1", "plan").unwrap(), Some(serde_json::json!("draft")));
+        assert_eq!(sm.open_session().unwrap().get("default", "agent-1", "plan").unwrap(), Some(serde_json::json!("final")));
+
+        // Dropping a session discards what it staged
+        let mut abandoned = sm.open_session().unwrap();
+        abandoned.put("default", "agent-1", "plan", serde_json::json!("discarded")).unwrap();
+        assert_eq!(sm.list_open_transactions().len(), 1);
+        drop(abandoned);
+        assert!(sm.list_open_transactions().is_empty());
+
+        // The commit is durable across a restart
+        drop(earlier);
+        drop(sm);
+        let sm = StateMachine::new(Arc::new(RocksStorage::new(config).unwrap()));
+        assert_eq!(sm.get_state("default", "agent-1", "plan").unwrap().unwrap().value, Some(serde_json::json!("final")));
+        assert!(sm.get_state("default", "agent-1", "note").unwrap().is_none_or(|r| r.deleted));
+    }
+}
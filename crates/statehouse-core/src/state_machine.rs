@@ -1,12 +1,26 @@
 // State machine implementation
 
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
-use tracing::{info, debug};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, debug, warn};
 
-use crate::storage::{EventLogEntry, OperationRecord, StateRecord, Storage};
+use crate::admission::{AdmissionControl, AdmissionLimits, Backlog, IdleAbort};
+use crate::coalesce::CoalesceRules;
+use crate::commit_queue::{CommitOrdering, CommitQueue, CommitTurn};
+use crate::deadline::Deadline;
+use crate::diff::{self, ValueChange};
+use crate::error::StatehouseError;
+use crate::eviction::{EvictionPolicies, EvictionPolicy};
+use crate::predicate::ValuePredicate;
+use crate::replication::ReplicationSink;
+use crate::quota::{NamespaceQuota, NamespaceQuotas};
+use crate::schema::InferredSchema;
+use crate::session::Session;
+use crate::storage::{json_size, value_hash, CompactionStats, EventLogEntry, NamespaceUsage, OperationRecord, PurgeStats, RenameStats, SnapshotMetadata, StateMeta, StateRecord, Storage};
+use crate::ttl::DefaultTtls;
 use crate::types::*;
 
 /// Transaction state
@@ -14,8 +28,79 @@ use crate::types::*;
 struct Transaction {
     txn_id: TxnId,
     created_at: Instant,
+    /// When an operation was last staged, or the transaction began
+    last_active: Instant,
     timeout: Duration,
     operations: Vec<StagedOperation>,
+    /// Approximate memory held by `operations` (see `StagedOperation::size`)
+    staged_bytes: usize,
+    /// Set once `prepare_commit` has accepted the transaction
+    prepared: Option<PreparedCommit>,
+    /// Live savepoints, oldest first
+    savepoints: Vec<Savepoint>,
+    next_savepoint: SavepointId,
+    /// Highest `client_seq` staged; a write carrying one no higher is a
+    /// replay and is ignored
+    client_seq: Option<u64>,
+}
+
+impl Transaction {
+    /// An open transaction expires `timeout` after it began, a prepared one
+    /// `timeout` after it was prepared
+    fn expired(&self) -> bool {
+        let since = self.prepared.as_ref().map_or(self.created_at, |prepared| prepared.at);
+        since.elapsed() > self.timeout
+    }
+}
+
+/// How much of a transaction was staged when a savepoint was taken
+#[derive(Debug, Clone, Copy)]
+struct Savepoint {
+    id: SavepointId,
+    operations: usize,
+    staged_bytes: usize,
+}
+
+/// A transaction waiting for `finalize_commit` or `cancel_commit`
+#[derive(Debug, Clone)]
+struct PreparedCommit {
+    at: Instant,
+    durability: Option<Durability>,
+}
+
+/// How a prepared transaction ended, kept for `FINISHED_COMMIT_RETENTION`
+#[derive(Debug)]
+struct FinishedCommit {
+    at: Instant,
+    /// `None` if it was cancelled, expired or failed to apply
+    result: Option<CommitResult>,
+}
+
+/// How long the outcome of a prepared transaction is remembered, so a client
+/// retrying `finalize_commit` or asking `commit_status` gets the same answer
+const FINISHED_COMMIT_RETENTION: Duration = Duration::from_secs(600);
+
+/// Where a transaction is in the two-phase commit protocol
+#[derive(Debug, Clone)]
+pub enum CommitStatus {
+    /// Never begun, already ended outside the two-phase protocol, or finished
+    /// longer ago than the server remembers
+    Unknown,
+    Open,
+    Prepared,
+    Committed(CommitResult),
+    /// Cancelled, expired while prepared, or failed when finalized
+    Cancelled,
+}
+
+/// Read-only transaction pinned to a commit timestamp
+#[derive(Debug, Clone)]
+struct ReadTransaction {
+    read_ts: CommitTs,
+    created_at: Instant,
+    timeout: Duration,
+    /// Records already resolved at `read_ts`, to avoid repeated version scans
+    cache: HashMap<RecordId, Option<StateRecord>>,
 }
 
 #[derive(Debug, Clone)]
@@ -25,18 +110,205 @@ enum StagedOperation {
         agent_id: AgentId,
         key: Key,
         value: serde_json::Value,
+        labels: Labels,
+        /// The write's own TTL, zero for none; `None` takes the namespace's default
+        ttl: Option<Duration>,
     },
     Delete {
         namespace: Namespace,
         agent_id: AgentId,
         key: Key,
     },
+    ConditionalDelete {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        expected_version: Version,
+    },
+    ConditionalWriteIf {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        value: serde_json::Value,
+        predicate: ValuePredicate,
+    },
+    Touch {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+    },
+    Rename {
+        namespace: Namespace,
+        agent_id: AgentId,
+        from_key: Key,
+        to_key: Key,
+        overwrite: bool,
+    },
+    Swap {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key_a: Key,
+        key_b: Key,
+        missing_as_null: bool,
+    },
+    GetOrCreate {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        default: serde_json::Value,
+    },
+    Undelete {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+    },
+    /// Precondition only: the key must be at `expected_version`, counting
+    /// the transaction's earlier changes to it
+    ExpectVersion {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        expected_version: Version,
+    },
+    /// Precondition only: the key's ETag must be `etag`, counting the
+    /// transaction's earlier changes to it
+    MatchEtag {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        etag: String,
+    },
+    Increment {
+        namespace: Namespace,
+        agent_id: AgentId,
+        key: Key,
+        pointer: String,
+        delta: i64,
+    },
+}
+
+impl StagedOperation {
+    /// Namespace and agent the operation targets
+    fn scope(&self) -> (&Namespace, &AgentId) {
+        match self {
+            StagedOperation::Write { namespace, agent_id, .. }
+            | StagedOperation::Delete { namespace, agent_id, .. }
+            | StagedOperation::ConditionalDelete { namespace, agent_id, .. }
+            | StagedOperation::ConditionalWriteIf { namespace, agent_id, .. }
+            | StagedOperation::Touch { namespace, agent_id, .. }
+            | StagedOperation::Rename { namespace, agent_id, .. }
+            | StagedOperation::Swap { namespace, agent_id, .. }
+            | StagedOperation::GetOrCreate { namespace, agent_id, .. }
+            | StagedOperation::Undelete { namespace, agent_id, .. }
+            | StagedOperation::ExpectVersion { namespace, agent_id, .. }
+            | StagedOperation::MatchEtag { namespace, agent_id, .. }
+            | StagedOperation::Increment { namespace, agent_id, .. } => (namespace, agent_id),
+        }
+    }
+
+    /// Approximate bytes held while staged: identifiers plus the serialized value
+    fn size(&self) -> usize {
+        let (namespace, agent_id) = self.scope();
+        let keys = match self {
+            StagedOperation::Write { key, labels, .. } => key.len() + labels.iter().map(|(name, value)| name.len() + value.len()).sum::<usize>(),
+            StagedOperation::Delete { key, .. }
+            | StagedOperation::ConditionalDelete { key, .. }
+            | StagedOperation::Touch { key, .. }
+            | StagedOperation::GetOrCreate { key, .. }
+            | StagedOperation::Undelete { key, .. }
+            | StagedOperation::ExpectVersion { key, .. } => key.len(),
+            StagedOperation::MatchEtag { key, etag, .. } => key.len() + etag.len(),
+            StagedOperation::Increment { key, pointer, .. } => key.len() + pointer.len() + std::mem::size_of::<i64>(),
+            StagedOperation::ConditionalWriteIf { key, predicate, .. } => {
+                key.len() + predicate.pointer().len() + json_size(predicate.operand())
+            }
+            StagedOperation::Rename { from_key, to_key, .. } => from_key.len() + to_key.len(),
+            StagedOperation::Swap { key_a, key_b, .. } => key_a.len() + key_b.len(),
+        };
+        namespace.len() + agent_id.len() + keys + self.value().map_or(0, json_size)
+    }
+
+    /// Records the operation reads or changes
+    fn record_ids(&self) -> Vec<RecordId> {
+        let (namespace, agent_id) = self.scope();
+        let keys = match self {
+            StagedOperation::Rename { from_key, to_key, .. } => vec![from_key, to_key],
+            StagedOperation::Swap { key_a, key_b, .. } => vec![key_a, key_b],
+            StagedOperation::Write { key, .. }
+            | StagedOperation::Delete { key, .. }
+            | StagedOperation::ConditionalDelete { key, .. }
+            | StagedOperation::ConditionalWriteIf { key, .. }
+            | StagedOperation::Touch { key, .. }
+            | StagedOperation::GetOrCreate { key, .. }
+            | StagedOperation::Undelete { key, .. }
+            | StagedOperation::ExpectVersion { key, .. }
+            | StagedOperation::MatchEtag { key, .. }
+            | StagedOperation::Increment { key, .. } => vec![key],
+        };
+        keys.into_iter().map(|key| RecordId::new(namespace.clone(), agent_id.clone(), key.clone())).collect()
+    }
+
+    /// The value the operation would store, if it carries one
+    fn value(&self) -> Option<&serde_json::Value> {
+        match self {
+            StagedOperation::Write { value, .. } | StagedOperation::ConditionalWriteIf { value, .. } => Some(value),
+            StagedOperation::GetOrCreate { default, .. } => Some(default),
+            _ => None,
+        }
+    }
+}
+
+/// A record change produced by resolving a staged operation at commit time
+#[derive(Debug)]
+struct Mutation {
+    record_id: RecordId,
+    /// New value, or `None` for a tombstone
+    value: Option<serde_json::Value>,
+    labels: Labels,
+    /// As for `StagedOperation::Write`
+    ttl: Option<Duration>,
+}
+
+impl Mutation {
+    fn new(record_id: RecordId, value: Option<serde_json::Value>) -> Self {
+        Self { record_id, value, labels: Labels::new(), ttl: None }
+    }
+}
+
+/// A key's value and labels as a transaction sees them, `None` if it isn't live
+type LiveValue = Option<(serde_json::Value, Labels)>;
+
+/// Per group resolved by `resolve_groups`, the error that left it out
+type GroupFailures = Vec<Option<anyhow::Error>>;
+
+/// Operations resolved so far in a commit
+#[derive(Default)]
+struct Resolution {
+    /// Each record the mutations touch, as of the last of them
+    pending: HashMap<RecordId, LiveValue>,
+    mutations: Vec<Mutation>,
+    get_or_create: Vec<GetOrCreateResult>,
+}
+
+impl Resolution {
+    /// Drop the mutations and get-or-create outcomes past the given counts,
+    /// winding `pending` back to match
+    fn truncate(&mut self, mutations: usize, get_or_create: usize) {
+        self.get_or_create.truncate(get_or_create);
+        for Mutation { record_id, .. } in self.mutations.drain(mutations..).collect::<Vec<_>>() {
+            match self.mutations.iter().rev().find(|m| m.record_id == record_id) {
+                Some(m) => self.pending.insert(record_id, m.value.clone().map(|value| (value, m.labels.clone()))),
+                None => self.pending.remove(&record_id),
+            };
+        }
+    }
 }
 
-/// Command to the state machine
+/// Command to the state machine's writer
 #[derive(Debug)]
-pub enum Command {
+enum Command {
     BeginTransaction {
+        txn_id: Option<TxnId>,
         timeout_ms: Option<u64>,
     },
     Write {
@@ -45,6 +317,10 @@ pub enum Command {
         agent_id: AgentId,
         key: Key,
         value: serde_json::Value,
+        labels: Labels,
+        ttl: Option<Duration>,
+        /// Per-transaction sequence number the client gave the write
+        client_seq: Option<u64>,
     },
     Delete {
         txn_id: TxnId,
@@ -52,559 +328,6925 @@ pub enum Command {
         agent_id: AgentId,
         key: Key,
     },
+    /// Stage several operations as a unit, for the staging calls other
+    /// than `write` and `delete`
+    Stage {
+        txn_id: TxnId,
+        ops: Vec<StagedOperation>,
+    },
+    Savepoint {
+        txn_id: TxnId,
+    },
+    RollbackTo {
+        txn_id: TxnId,
+        savepoint_id: SavepointId,
+    },
+    ReleaseSavepoint {
+        txn_id: TxnId,
+        savepoint_id: SavepointId,
+    },
     Commit {
         txn_id: TxnId,
+        durability: Option<Durability>,
+    },
+    /// Commit with each group of `groups` (counts of consecutive staged
+    /// operations) resolved on its own, as `batch_apply` does
+    CommitEach {
+        txn_id: TxnId,
+        durability: Option<Durability>,
+        groups: Vec<usize>,
     },
     Abort {
         txn_id: TxnId,
     },
+    PrepareCommit {
+        txn_id: TxnId,
+        durability: Option<Durability>,
+    },
+    FinalizeCommit {
+        txn_id: TxnId,
+    },
+    CancelCommit {
+        txn_id: TxnId,
+    },
+    /// Commit buffered coalesced writes whose window has closed, or all with `force`
+    FlushCoalesced {
+        force: bool,
+    },
+    /// Clear expired transactions and the commit outcomes kept for retries
+    CleanupExpired,
+    /// Delete keys whose TTL has run out
+    ExpireKeys,
+    ApplyReplicated {
+        event: EventLogEntry,
+    },
+    Import {
+        records: Vec<StateRecord>,
+    },
+    /// Purge tombstones at or before `up_to_ts` that are past their grace period
+    PurgeTombstones {
+        up_to_ts: CommitTs,
+    },
+    PurgeKey {
+        record_id: RecordId,
+    },
+    RenameNamespace {
+        from: Namespace,
+        to: Namespace,
+        merge: bool,
+    },
+    RestoreNamespace {
+        namespace: Namespace,
+        snapshot: Box<crate::storage::Snapshot>,
+    },
+    /// Drop versions at or before `up_to_ts` beyond the newest `keep_versions` per key
+    CompactHistory {
+        up_to_ts: CommitTs,
+        keep_versions: usize,
+    },
+    TrimEvents {
+        up_to_ts: CommitTs,
+    },
+    /// Rebuild the version counters from `snapshot` and the events after it
+    Recover {
+        snapshot: Option<Box<crate::storage::Snapshot>>,
+    },
+    /// Reseed the version counters from `snapshot` alone
+    SeedVersions {
+        snapshot: Box<crate::storage::Snapshot>,
+    },
+    /// The newest commit_ts with no commit half-applied at it
+    CommittedTs,
+    /// The newest event's commit_ts, 0 if there is none
+    LastEventTs,
+    Backlog,
+    CommitStatus {
+        txn_id: TxnId,
+    },
+    /// The namespace of a transaction's first staged operation
+    TransactionNamespace {
+        txn_id: TxnId,
+    },
+    ListTransactions,
+    MonitorTransactions {
+        warn_fraction: f64,
+    },
+    ConsistencyCheck,
 }
 
-/// State machine for Statehouse
-/// Single-writer design: all mutations go through one logical thread
-pub struct StateMachine {
-    storage: Arc<dyn Storage>,
-    transactions: Arc<RwLock<HashMap<TxnId, Transaction>>>,
-    version_counters: Arc<RwLock<HashMap<RecordId, Version>>>,
-    commits_since_snapshot: Arc<RwLock<u64>>,
+/// The writer's answer to a `Command`
+#[derive(Debug)]
+enum Reply {
+    Begun(TxnId),
+    Done,
+    /// A write ignored as a replay of one already staged
+    Duplicate,
+    Committed(CommitResult),
+    /// The commit, and per group the error that left it out
+    CommittedEach(CommitResult, GroupFailures),
+    Flushed(usize),
+    Expired(usize),
+    Savepoint(SavepointId),
+    RolledBack(usize),
+    /// Whether a replicated event was new
+    Applied(bool),
+    Imported(ImportStats),
+    Compacted(CompactionStats),
+    Purged(PurgeStats),
+    Renamed(RenameStats),
+    Recovered(RecoveryStats),
+    CommitTs(CommitTs),
+    Backlog(Backlog),
+    CommitStatus(CommitStatus),
+    Namespace(Namespace),
+    Transactions(Vec<TxnSummary>),
+    Ages(TransactionAgeReport),
+    Consistency(ConsistencyReport),
 }
 
-impl StateMachine {
-    pub fn new(storage: Arc<dyn Storage>) -> Self {
-        Self {
-            storage,
-            transactions: Arc::new(RwLock::new(HashMap::new())),
-            version_counters: Arc::new(RwLock::new(HashMap::new())),
-            commits_since_snapshot: Arc::new(RwLock::new(0)),
-        }
-    }
+type QueuedCommand = (Command, mpsc::Sender<Result<Reply>>);
 
-    /// Begin a new transaction
-    pub fn begin_transaction(&self, timeout_ms: Option<u64>) -> Result<TxnId> {
-        let txn_id = uuid::Uuid::new_v4().to_string();
-        let timeout = Duration::from_millis(timeout_ms.unwrap_or(30000));
+/// What only the writer touches: the open transactions and the version
+/// counters. Everything else reaches them through a `Command`.
+#[derive(Default)]
+struct WriterState {
+    transactions: HashMap<TxnId, Transaction>,
+    version_counters: HashMap<RecordId, Version>,
+}
 
-        let txn = Transaction {
-            txn_id: txn_id.clone(),
-            created_at: Instant::now(),
-            timeout,
-            operations: Vec::new(),
-        };
+/// The receiving end of the command queue and the state the commands act
+/// on, together, so only whoever is draining the queue can reach the state
+struct Writer {
+    receiver: mpsc::Receiver<QueuedCommand>,
+    state: WriterState,
+}
 
-        let mut transactions = self.transactions.write().unwrap();
-        transactions.insert(txn_id.clone(), txn);
+/// Commands waiting for the writer, in the order they were sent
+struct CommandChannel {
+    sender: mpsc::Sender<QueuedCommand>,
+    /// Held by whichever caller is acting as the writer
+    writer: Mutex<Writer>,
+}
 
-        debug!("Transaction started: txn_id={}", txn_id);
-        Ok(txn_id)
+impl CommandChannel {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self { sender, writer: Mutex::new(Writer { receiver, state: WriterState::default() }) }
     }
+}
 
-    /// Stage a write operation
-    pub fn write(&self, txn_id: &str, namespace: String, agent_id: String, key: String, value: serde_json::Value) -> Result<()> {
-        let mut transactions = self.transactions.write().unwrap();
-        let txn = transactions.get_mut(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?;
-
-        // Check timeout
-        if txn.created_at.elapsed() > txn.timeout {
-            transactions.remove(txn_id);
-            return Err(anyhow!("Transaction expired"));
-        }
+/// Upper bounds (in milliseconds) of the transaction-age histogram buckets.
+/// Transactions older than the last bound fall into a final overflow bucket.
+pub const TXN_AGE_BUCKETS_MS: [u64; 5] = [1_000, 5_000, 10_000, 30_000, 60_000];
 
-        txn.operations.push(StagedOperation::Write {
-            namespace,
-            agent_id,
-            key,
-            value,
-        });
+/// Upper bounds (inclusive) of the version-depth histogram buckets. Keys with
+/// more versions than the last bound fall into a final overflow bucket.
+pub const VERSION_DEPTH_BUCKETS: [u64; 4] = [1, 5, 20, 100];
 
-        Ok(())
-    }
+/// Most keys `version_depth_histogram` lists as deepest
+pub const MAX_DEEPEST_KEYS: usize = 1000;
 
-    /// Stage a delete operation
-    pub fn delete(&self, txn_id: &str, namespace: String, agent_id: String, key: String) -> Result<()> {
-        let mut transactions = self.transactions.write().unwrap();
-        let txn = transactions.get_mut(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?;
+/// How deep a namespace's version chains are, to size version compaction
+#[derive(Debug, Clone, Default)]
+pub struct VersionDepthHistogram {
+    /// Keys with at least one stored version
+    pub keys: u64,
+    /// Versions stored across them
+    pub versions: u64,
+    /// Keys per `VERSION_DEPTH_BUCKETS` bucket, plus the overflow bucket
+    pub buckets: [u64; VERSION_DEPTH_BUCKETS.len() + 1],
+    /// The keys with the most versions and how many, deepest first (ties in
+    /// key order)
+    pub deepest: Vec<(RecordId, u64)>,
+}
 
-        // Check timeout
-        if txn.created_at.elapsed() > txn.timeout {
-            transactions.remove(txn_id);
-            return Err(anyhow!("Transaction expired"));
-        }
+/// An open transaction that has used up most of its timeout
+#[derive(Debug, Clone)]
+pub struct StuckTransaction {
+    pub txn_id: TxnId,
+    pub age: Duration,
+    pub timeout: Duration,
+    pub staged_ops: usize,
+}
 
-        txn.operations.push(StagedOperation::Delete {
-            namespace,
-            agent_id,
-            key,
-        });
+/// Point-in-time age distribution of open transactions
+#[derive(Debug, Clone, Default)]
+pub struct TransactionAgeReport {
+    /// Number of open transactions
+    pub open: usize,
+    /// Counts per `TXN_AGE_BUCKETS_MS` bucket, plus the overflow bucket
+    pub buckets: [usize; TXN_AGE_BUCKETS_MS.len() + 1],
+    /// Age of the oldest open transaction
+    pub oldest: Option<Duration>,
+    /// Transactions older than the warning fraction of their timeout
+    pub stuck: Vec<StuckTransaction>,
+    /// How many of `stuck` this call warned about: those that weren't stuck
+    /// at the previous call
+    pub warned: usize,
+}
 
-        Ok(())
-    }
+/// Outcome of a successful commit
+#[derive(Debug, Clone, Default)]
+pub struct CommitResult {
+    /// 0 if the commit's writes were coalesced
+    pub commit_ts: CommitTs,
+    /// Timestamp in each touched namespace's own sequence; empty unless
+    /// per-namespace sequences are enabled
+    pub namespace_ts: BTreeMap<Namespace, CommitTs>,
+    /// Records the commit mutated, each listed once in first-staged order
+    pub changed: Vec<RecordId>,
+    /// Version of each `changed` record before and after the commit (0 = absent)
+    pub versions: Vec<(Version, Version)>,
+    /// Outcome of each staged `get_or_create`, in staged order
+    pub get_or_create: Vec<GetOrCreateResult>,
+    /// The commit only buffered writes to coalescing keys: nothing is written
+    /// until their window closes (see `crate::coalesce`)
+    pub coalesced: bool,
+}
 
-    /// Commit a transaction atomically
-    pub fn commit(&self, txn_id: &str) -> Result<CommitTs> {
-        use tracing::{info, debug};
-        
-        debug!(txn_id = %txn_id, "Committing transaction");
-        
-        // Remove transaction from staging
-        let txn = {
-            let mut transactions = self.transactions.write().unwrap();
-            transactions.remove(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?
-        };
+/// A coalesced write waiting for its key's window to close
+#[derive(Debug, Clone)]
+struct CoalescedWrite {
+    value: serde_json::Value,
+    labels: Labels,
+    closes_at: Instant,
+}
 
-        // Check timeout
-        if txn.created_at.elapsed() > txn.timeout {
-            debug!(txn_id = %txn_id, "Transaction expired");
-            return Err(anyhow!("Transaction expired"));
-        }
+/// How a staged `get_or_create` resolved at commit
+#[derive(Debug, Clone)]
+pub struct GetOrCreateResult {
+    pub record_id: RecordId,
+    /// Whether the default was written because the key was absent or deleted
+    pub created: bool,
+    /// The key's value and version at that point in the transaction
+    pub value: serde_json::Value,
+    pub version: Version,
+}
 
-        // Get commit timestamp
-        let commit_ts = self.storage.next_commit_ts()?;
+/// Caps on staged operations, so an uncommitted transaction can't hold
+/// unbounded server memory and a single value can't nest without bound
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionLimits {
+    /// Maximum total `StagedOperation::size` of a transaction's staged operations
+    pub max_transaction_bytes: usize,
+    /// Maximum number of staged operations per transaction
+    pub max_ops_per_transaction: usize,
+    /// Maximum nesting depth of a written value (1 for a flat object)
+    pub max_json_depth: usize,
+}
 
-        // Apply operations
-        let mut operation_records = Vec::new();
-        let mut version_counters = self.version_counters.write().unwrap();
-
-        for op in txn.operations {
-            match op {
-                StagedOperation::Write { namespace, agent_id, key, value } => {
-                    let record_id = RecordId::new(namespace.clone(), agent_id.clone(), key.clone());
-
-                    // Get next version for this key
-                    let version = version_counters.entry(record_id.clone()).or_insert(0);
-                    *version += 1;
-                    let current_version = *version;
-
-                    // Write to storage
-                    let record = StateRecord {
-                        namespace: namespace.clone(),
-                        agent_id: agent_id.clone(),
-                        key: key.clone(),
-                        value: Some(value.clone()),
-                        version: current_version,
-                        commit_ts,
-                        deleted: false,
-                    };
-                    self.storage.write_state(record)?;
-
-                    // Record operation
-                    operation_records.push(OperationRecord {
-                        namespace,
-                        agent_id,
-                        key,
-                        value: Some(value),
-                        version: current_version,
-                    });
-                }
-                StagedOperation::Delete { namespace, agent_id, key } => {
-                    let record_id = RecordId::new(namespace.clone(), agent_id.clone(), key.clone());
-
-                    // Get next version for this key
-                    let version = version_counters.entry(record_id.clone()).or_insert(0);
-                    *version += 1;
-                    let current_version = *version;
-
-                    // Write tombstone to storage
-                    let record = StateRecord {
-                        namespace: namespace.clone(),
-                        agent_id: agent_id.clone(),
-                        key: key.clone(),
-                        value: None,
-                        version: current_version,
-                        commit_ts,
-                        deleted: true,
-                    };
-                    self.storage.write_state(record)?;
-
-                    // Record operation
-                    operation_records.push(OperationRecord {
-                        namespace,
-                        agent_id,
-                        key,
-                        value: None,
-                        version: current_version,
-                    });
-                }
-            }
+impl Default for TransactionLimits {
+    fn default() -> Self {
+        Self {
+            max_transaction_bytes: 64 * 1024 * 1024, // 64MB
+            max_ops_per_transaction: 100_000,
+            max_json_depth: 64,
         }
+    }
+}
 
-        // Append event to log
-        let event = EventLogEntry {
-            txn_id: txn.txn_id.clone(),
-            commit_ts,
-            operations: operation_records.clone(),
-        };
-        self.storage.append_event(event)?;
-
-        // Flush if needed
-        self.storage.flush()?;
-
-        // Log successful commit
-        info!(
-            txn_id = %txn_id,
-            commit_ts = commit_ts,
-            operations = operation_records.len(),
-            "Transaction committed"
-        );
+/// Bound on how much of the event log one `replay` may cover, so a single
+/// unbounded request can't scan a huge log
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayLimits {
+    /// Widest `[start_ts, end_ts]` range, in timestamps; `None` for no limit
+    pub max_span: Option<CommitTs>,
+    /// Clamp a replay without `start_ts` to the last `max_span` timestamps
+    /// instead of rejecting it
+    pub clamp_unbounded: bool,
+}
 
-        Ok(commit_ts)
-    }
+/// Which operations `replay_filtered` returns. The default matches
+/// everything and returns events untouched.
+#[derive(Debug, Clone)]
+pub struct ReplayFilter {
+    pub include_writes: bool,
+    pub include_deletes: bool,
+    /// Only operations on keys starting with this; empty for all keys
+    pub key_prefix: String,
+    /// Only events committed strictly after this commit timestamp, to resume
+    /// a replay after the last event received
+    pub resume_after: Option<CommitTs>,
+}
 
-    /// Abort a transaction
-    pub fn abort(&self, txn_id: &str) -> Result<()> {
-        use tracing::debug;
-        
-        let mut transactions = self.transactions.write().unwrap();
-        if transactions.remove(txn_id).is_some() {
-            debug!(txn_id = %txn_id, "Transaction aborted");
+impl Default for ReplayFilter {
+    fn default() -> Self {
+        Self {
+            include_writes: true,
+            include_deletes: true,
+            key_prefix: String::new(),
+            resume_after: None,
         }
-        Ok(())
     }
+}
 
-    /// Read latest state
-    pub fn get_state(&self, namespace: &str, agent_id: &str, key: &str) -> Result<Option<StateRecord>> {
-        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
-        self.storage.read_state(&record_id)
+impl ReplayFilter {
+    fn matches_all(&self) -> bool {
+        self.include_writes && self.include_deletes && self.key_prefix.is_empty()
     }
 
-    /// Read state at specific version
-    pub fn get_state_at_version(&self, namespace: &str, agent_id: &str, key: &str, version: Version) -> Result<Option<StateRecord>> {
-        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
-        self.storage.read_state_at_version(&record_id, version)
+    fn matches(&self, op: &OperationRecord) -> bool {
+        let included = if op.value.is_some() { self.include_writes } else { self.include_deletes };
+        included && op.key.starts_with(&self.key_prefix)
     }
+}
 
-    /// List keys for an agent
-    pub fn list_keys(&self, namespace: &str, agent_id: &str) -> Result<Vec<String>> {
-        self.storage.list_keys(namespace, agent_id)
-    }
+/// Maximum length of a client-supplied transaction id
+pub const MAX_TXN_ID_LEN: usize = 128;
 
-    /// Scan keys with prefix
-    pub fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<Vec<StateRecord>> {
-        self.storage.scan_prefix(namespace, agent_id, prefix)
-    }
+/// Maximum number of transactions returned by `list_open_transactions`
+pub const MAX_LISTED_TRANSACTIONS: usize = 1000;
 
-    /// Replay events for an agent
-    pub fn replay(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>) -> Result<Vec<EventLogEntry>> {
-        info!(
-            namespace = %namespace,
-            agent_id = %agent_id,
-            start_ts = ?start_ts,
-            end_ts = ?end_ts,
-            "Replay started"
-        );
+/// Maximum number of events in one `transaction_log` page
+pub const MAX_TRANSACTION_LOG_PAGE: usize = 1000;
 
-        let events = self.storage.replay_events(namespace, agent_id, start_ts, end_ts)?;
-        let event_count = events.len();
+/// Wait before the first `with_retry` retry; doubles on each further conflict
+const RETRY_BASE_BACKOFF: Duration = Duration::from_millis(10);
 
-        info!(
-            namespace = %namespace,
-            agent_id = %agent_id,
-            event_count = event_count,
-            "Replay completed"
-        );
+/// Longest wait between `with_retry` attempts
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(1);
 
-        Ok(events)
+/// Records fetched from storage at a time by `ScanPrefixIter`
+pub const SCAN_PAGE_SIZE: usize = 256;
+
+/// Most values `infer_schema` samples
+pub const MAX_SCHEMA_SAMPLE: usize = 10_000;
+
+/// Lazy prefix scan returned by `StateMachine::scan_prefix_iter`. Records are
+/// read a page at a time, so at most `SCAN_PAGE_SIZE` are held in memory.
+/// Each page reflects the latest state when it is read: the scan as a whole
+/// is not a point-in-time view.
+pub struct ScanPrefixIter {
+    storage: Arc<dyn Storage>,
+    namespace: Namespace,
+    agent_id: AgentId,
+    prefix: String,
+    /// Key of the last record yielded, where the next page starts
+    cursor: Option<Key>,
+    page: VecDeque<StateRecord>,
+    done: bool,
+}
+
+impl Iterator for ScanPrefixIter {
+    type Item = Result<StateRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            let page = self.storage.scan_prefix_page(
+                &self.namespace,
+                &self.agent_id,
+                &self.prefix,
+                false,
+                self.cursor.as_deref(),
+                SCAN_PAGE_SIZE,
+            );
+            match page {
+                Ok(page) => {
+                    self.done = page.len() < SCAN_PAGE_SIZE;
+                    self.page = page.into();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let record = self.page.pop_front()?;
+        self.cursor = Some(record.key.clone());
+        Some(Ok(record))
     }
+}
 
-    /// Cleanup expired transactions (should be called periodically)
-    pub fn cleanup_expired_transactions(&self) {
-        let mut transactions = self.transactions.write().unwrap();
-        transactions.retain(|_, txn| txn.created_at.elapsed() <= txn.timeout);
+/// An invariant `StateMachine::consistency_check` found broken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsistencyViolation {
+    /// A record's latest state isn't its newest stored version
+    /// (`None` if no versions are stored at all)
+    StateVersionMismatch {
+        record_id: RecordId,
+        state_version: Version,
+        max_stored_version: Option<Version>,
+    },
+    /// The log holds an event newer than the commit timestamp counter, so
+    /// that timestamp would be issued again
+    CommitTsBehindEvents {
+        commit_ts: CommitTs,
+        last_event_ts: CommitTs,
+    },
+    /// The in-memory version counter of a record disagrees with its stored
+    /// version (0 if it isn't stored), so its next commit would be misnumbered
+    VersionCounterMismatch {
+        record_id: RecordId,
+        counter: Version,
+        stored_version: Version,
+    },
+}
+
+impl ConsistencyViolation {
+    /// The record at fault, for violations that concern one
+    pub fn record_id(&self) -> Option<&RecordId> {
+        match self {
+            ConsistencyViolation::StateVersionMismatch { record_id, .. }
+            | ConsistencyViolation::VersionCounterMismatch { record_id, .. } => Some(record_id),
+            ConsistencyViolation::CommitTsBehindEvents { .. } => None,
+        }
     }
+}
 
-    /// Create a snapshot of current state
-    pub fn create_snapshot(&self) -> Result<()> {
-        let snapshot = self.storage.create_snapshot()?;
-        self.storage.save_snapshot(&snapshot)?;
-        
-        // Reset counter after successful snapshot
-        let mut counter = self.commits_since_snapshot.write().unwrap();
-        *counter = 0;
-        
+impl std::fmt::Display for ConsistencyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsistencyViolation::StateVersionMismatch { record_id, state_version, max_stored_version } => write!(
+                f,
+                "{}/{}/{}: state is at version {} but the newest stored version is {:?}",
+                record_id.namespace, record_id.agent_id, record_id.key, state_version, max_stored_version
+            ),
+            ConsistencyViolation::CommitTsBehindEvents { commit_ts, last_event_ts } => write!(
+                f,
+                "commit_ts counter is at {} but the event log reaches {}",
+                commit_ts, last_event_ts
+            ),
+            ConsistencyViolation::VersionCounterMismatch { record_id, counter, stored_version } => write!(
+                f,
+                "{}/{}/{}: version counter is {} but the stored version is {}",
+                record_id.namespace, record_id.agent_id, record_id.key, counter, stored_version
+            ),
+        }
+    }
+}
+
+/// Outcome of `StateMachine::consistency_check`
+#[derive(Debug, Clone, Default)]
+pub struct ConsistencyReport {
+    pub records_checked: u64,
+    pub counters_checked: u64,
+    pub violations: Vec<ConsistencyViolation>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Which steps `StateMachine::run_maintenance` runs; all by default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceOpts {
+    pub snapshot: bool,
+    pub version_gc: bool,
+    /// Versions kept per key at or before the snapshot (0 is treated as 1)
+    pub keep_versions: usize,
+    pub tombstone_gc: bool,
+    /// Needs `snapshot`
+    pub trim_log: bool,
+    pub consistency_check: bool,
+}
+
+impl Default for MaintenanceOpts {
+    fn default() -> Self {
+        Self {
+            snapshot: true,
+            version_gc: true,
+            keep_versions: 1,
+            tombstone_gc: true,
+            trim_log: true,
+            consistency_check: true,
+        }
+    }
+}
+
+impl MaintenanceOpts {
+    /// Enabled steps, in the order they run
+    pub fn steps(&self) -> Vec<MaintenanceStep> {
+        [
+            (self.snapshot, MaintenanceStep::Snapshot),
+            (self.version_gc, MaintenanceStep::VersionGc),
+            (self.tombstone_gc, MaintenanceStep::TombstoneGc),
+            (self.trim_log, MaintenanceStep::TrimLog),
+            (self.consistency_check, MaintenanceStep::ConsistencyCheck),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, step)| enabled.then_some(step))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceStep {
+    Snapshot,
+    VersionGc,
+    TombstoneGc,
+    TrimLog,
+    ConsistencyCheck,
+}
+
+impl MaintenanceStep {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaintenanceStep::Snapshot => "snapshot",
+            MaintenanceStep::VersionGc => "version_gc",
+            MaintenanceStep::TombstoneGc => "tombstone_gc",
+            MaintenanceStep::TrimLog => "trim_log",
+            MaintenanceStep::ConsistencyCheck => "consistency_check",
+        }
+    }
+}
+
+/// What each step of `StateMachine::run_maintenance` did; `None` for a
+/// step that didn't run
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceReport {
+    pub snapshot: Option<SnapshotMetadata>,
+    pub version_gc: Option<CompactionStats>,
+    pub tombstone_gc: Option<CompactionStats>,
+    pub trim_log: Option<CompactionStats>,
+    pub consistency: Option<ConsistencyReport>,
+}
+
+impl MaintenanceReport {
+    /// The GC and trim steps' counts added up
+    pub fn totals(&self) -> CompactionStats {
+        let mut totals = CompactionStats::default();
+        for stats in [&self.version_gc, &self.tombstone_gc, &self.trim_log].into_iter().flatten() {
+            totals.snapshot_ts = stats.snapshot_ts;
+            totals.merge(stats);
+        }
+        totals
+    }
+}
+
+/// What `StateMachine::import_records` wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub records_imported: u64,
+    /// Records already stored with the same version and commit_ts
+    pub records_skipped: u64,
+    /// Events written or extended with imported operations
+    pub events_written: u64,
+    /// Highest commit_ts imported (0 if none were)
+    pub max_commit_ts: CommitTs,
+}
+
+/// How often `recover` logs progress while replaying events
+const RECOVERY_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Events `recover` reads from storage at a time
+const RECOVERY_PAGE_SIZE: usize = 1000;
+
+/// Where startup recovery is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryStatus {
+    LoadingSnapshot,
+    /// `processed` of the `total` events after the snapshot
+    ReplayingEvents { processed: u64, total: u64 },
+    Ready,
+}
+
+impl RecoveryStatus {
+    pub fn is_ready(&self) -> bool {
+        *self == RecoveryStatus::Ready
+    }
+}
+
+impl std::fmt::Display for RecoveryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecoveryStatus::LoadingSnapshot => write!(f, "loading snapshot"),
+            RecoveryStatus::ReplayingEvents { processed, total } => write!(f, "replaying events ({}/{})", processed, total),
+            RecoveryStatus::Ready => write!(f, "ready"),
+        }
+    }
+}
+
+/// Outcome of `StateMachine::recover`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Commit timestamp of the snapshot recovery started from, if there was one
+    pub snapshot_ts: Option<CommitTs>,
+    /// Events found after the snapshot and replayed into the version counters
+    pub events_replayed: u64,
+}
+
+/// Commit timestamps reserved with `Storage::reserve_commit_ts_block`
+#[derive(Debug, Clone, Copy)]
+struct CommitTsBlock {
+    /// Most recently handed out
+    last: CommitTs,
+    end: CommitTs,
+}
+
+/// What `StateMachine::get_existing_batch` found
+#[derive(Debug, Clone, Default)]
+pub struct ExistingBatch {
+    /// Live records, in request order
+    pub found: Vec<StateRecord>,
+    /// Requested keys that were never written or are deleted, in request order
+    pub missing: Vec<RecordId>,
+}
+
+/// One page of a prefix scan, from `StateMachine::scan_prefix_page`
+#[derive(Debug, Clone, Default)]
+pub struct ScanPage {
+    /// Matching records, in key order
+    pub records: Vec<StateRecord>,
+    /// Pass as `start_after` to continue; `None` once nothing matches after
+    /// the last record
+    pub next_cursor: Option<Key>,
+}
+
+/// One page of the transaction log, from `StateMachine::transaction_log`
+#[derive(Debug, Clone, Default)]
+pub struct TransactionLogPage {
+    /// Committed events of every namespace, in commit_ts order
+    pub events: Vec<EventLogEntry>,
+    /// Offset to read the next page from: one past the last event's
+    /// commit_ts, or the requested offset if the page is empty
+    pub next_offset: CommitTs,
+}
+
+/// One operation of `StateMachine::batch_apply`
+#[derive(Debug, Clone)]
+pub struct BatchOp {
+    pub record_id: RecordId,
+    pub kind: BatchOpKind,
+    /// Apply only if the key is at this version when the batch commits
+    /// (0 = never written)
+    pub expected_version: Option<Version>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchOpKind {
+    Write { value: serde_json::Value, labels: Labels },
+    Delete,
+    /// Add `delta` to the integer at `pointer` (a JSON pointer; "" is the
+    /// whole value). A missing key or field counts as 0, though the field's
+    /// parent must be an object.
+    Increment { pointer: String, delta: i64 },
+}
+
+/// Outcome of `StateMachine::batch_apply`
+#[derive(Debug, Default)]
+pub struct BatchApplyResult {
+    /// Transaction the batch ran as
+    pub txn_id: TxnId,
+    /// The batch's commit
+    pub commit: CommitResult,
+    /// Per operation, in order: the version its key is at after the batch,
+    /// or why it was left out (only in a non-atomic batch)
+    pub outcomes: Vec<Result<Version>>,
+}
+
+/// Summary of an open transaction, for debugging
+#[derive(Debug, Clone)]
+pub struct TxnSummary {
+    pub txn_id: TxnId,
+    pub age: Duration,
+    pub timeout: Duration,
+    pub staged_ops: usize,
+    /// (namespace, agent_id) pairs touched by the staged operations
+    pub agents: BTreeSet<(Namespace, AgentId)>,
+}
+
+/// State machine for Statehouse
+///
+/// Single-writer design: the open transactions and the version counters
+/// belong to the writer (`WriterState`), and every call that changes them or
+/// storage sends a `Command` over a channel and waits for the reply. That
+/// covers beginning, staging, savepoints, commit and abort, and also
+/// replication, import, recovery, purges, history GC, log trimming,
+/// namespace rename and restore, and the expiry sweep. One caller at a time
+/// acts as the writer, draining the channel and running every queued
+/// command in the order it arrived, so mutations are strictly serialized by
+/// arrival rather than by who wins a lock.
+///
+/// Reads of the writer's state (listing transactions, the backlog, commit
+/// status) are commands too. So are the points that must not fall inside a
+/// commit, such as the timestamp a read transaction or snapshot is pinned
+/// to: the writer only runs a command between commits.
+pub struct StateMachine {
+    storage: Arc<dyn Storage>,
+    commands: CommandChannel,
+    read_transactions: Arc<RwLock<HashMap<TxnId, ReadTransaction>>>,
+    commits_since_snapshot: Arc<RwLock<u64>>,
+    /// Held while a snapshot is taken, so only one runs at a time
+    snapshotting: Mutex<()>,
+    /// When the last snapshot was saved, or the state machine created
+    last_snapshot: Mutex<Instant>,
+    limits: TransactionLimits,
+    replay_limits: ReplayLimits,
+    /// Where committed events are shipped, if this node is a replication primary
+    replication: Option<Arc<dyn ReplicationSink>>,
+    /// Stamp commits with a gap-free per-namespace timestamp as well as the global one
+    namespace_sequences: bool,
+    quotas: NamespaceQuotas,
+    eviction: EvictionPolicies,
+    default_ttls: DefaultTtls,
+    /// How long a deleted key stays recoverable with `undelete`
+    delete_grace: Duration,
+    /// Orders commits waiting for the writer, unless `CommitOrdering::Unordered`
+    commit_queue: Option<CommitQueue>,
+    coalesce: CoalesceRules,
+    /// Buffered coalesced writes, latest value per key
+    coalesced: Mutex<HashMap<RecordId, CoalescedWrite>>,
+    recovery: Mutex<RecoveryStatus>,
+    /// Recently finished prepared transactions
+    finished: Mutex<HashMap<TxnId, FinishedCommit>>,
+    /// Stuck transactions `monitor_transactions` has already warned about
+    warned_stuck: Mutex<HashSet<TxnId>>,
+    admission: AdmissionControl,
+    /// Commit timestamps reserved from storage per block; 0 takes each from storage
+    commit_ts_block_size: u64,
+    commit_ts_block: Mutex<Option<CommitTsBlock>>,
+}
+
+impl StateMachine {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            commands: CommandChannel::new(),
+            read_transactions: Arc::new(RwLock::new(HashMap::new())),
+            commits_since_snapshot: Arc::new(RwLock::new(0)),
+            snapshotting: Mutex::new(()),
+            last_snapshot: Mutex::new(Instant::now()),
+            limits: TransactionLimits::default(),
+            replay_limits: ReplayLimits::default(),
+            replication: None,
+            namespace_sequences: false,
+            quotas: NamespaceQuotas::default(),
+            eviction: EvictionPolicies::default(),
+            default_ttls: DefaultTtls::default(),
+            delete_grace: Duration::ZERO,
+            commit_queue: None,
+            coalesce: CoalesceRules::default(),
+            coalesced: Mutex::new(HashMap::new()),
+            recovery: Mutex::new(RecoveryStatus::Ready),
+            finished: Mutex::new(HashMap::new()),
+            warned_stuck: Mutex::new(HashSet::new()),
+            admission: AdmissionControl::new(AdmissionLimits::default()),
+            commit_ts_block_size: 0,
+            commit_ts_block: Mutex::new(None),
+        }
+    }
+
+    pub fn transaction_limits(&self) -> TransactionLimits {
+        self.limits
+    }
+
+    pub fn with_transaction_limits(mut self, limits: TransactionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_replay_limits(mut self, replay_limits: ReplayLimits) -> Self {
+        self.replay_limits = replay_limits;
+        self
+    }
+
+    pub fn with_replication_sink(mut self, sink: Arc<dyn ReplicationSink>) -> Self {
+        self.replication = Some(sink);
+        self
+    }
+
+    /// Give each namespace its own persisted commit sequence. Records and
+    /// events keep the global `commit_ts` for cross-namespace ordering and
+    /// also carry a `namespace_ts` with no gaps from other namespaces'
+    /// commits; `replay` bounds are then read in that sequence.
+    pub fn with_namespace_sequences(mut self, enabled: bool) -> Self {
+        self.namespace_sequences = enabled;
+        self
+    }
+
+    /// Cap namespaces' live records and value bytes; commits that would
+    /// exceed a quota fail with `StatehouseError::QuotaExceeded`
+    pub fn with_namespace_quotas(mut self, quotas: NamespaceQuotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Make room in bounded namespaces by evicting records at commit rather
+    /// than failing; see `crate::eviction`
+    pub fn with_eviction_policies(mut self, eviction: EvictionPolicies) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Give writes that don't set a TTL their namespace's default; see
+    /// `crate::ttl`
+    pub fn with_default_ttls(mut self, default_ttls: DefaultTtls) -> Self {
+        self.default_ttls = default_ttls;
+        self
+    }
+
+    /// Keep deleted keys recoverable for `grace`: tombstones are stamped with
+    /// a `purge_after` deadline, `undelete` can restore the pre-delete value
+    /// until then, and `compact` only purges them once it has passed. Zero
+    /// (the default) makes tombstones purgeable at once.
+    pub fn with_delete_grace(mut self, grace: Duration) -> Self {
+        self.delete_grace = grace;
+        self
+    }
+
+    /// Queue commits for the writer so a stream of small commits can't
+    /// starve a large one; see `CommitOrdering`
+    pub fn with_commit_ordering(mut self, ordering: CommitOrdering) -> Self {
+        self.commit_queue = match ordering {
+            CommitOrdering::Unordered => None,
+            ordering => Some(CommitQueue::new(ordering)),
+        };
+        self
+    }
+
+    /// Coalesce writes to keys matching `rules`: see `crate::coalesce`. The
+    /// caller should call `flush_coalesced` periodically, and with `force`
+    /// before shutting down.
+    pub fn with_coalescing(mut self, rules: CoalesceRules) -> Self {
+        self.coalesce = rules;
+        self
+    }
+
+    /// Hand out commit timestamps from blocks of `size` reserved from
+    /// storage, rather than persisting the counter on every commit.
+    /// Timestamps left in a block when the process stops are skipped. 0
+    /// (the default) takes each timestamp from storage.
+    pub fn with_commit_ts_blocks(mut self, size: u64) -> Self {
+        self.commit_ts_block_size = size;
+        self
+    }
+
+    /// Refuse new transactions and staged operations with
+    /// `StatehouseError::Overloaded` while commits can't keep up; see
+    /// `crate::admission`
+    pub fn with_admission_limits(mut self, limits: AdmissionLimits) -> Self {
+        self.admission = AdmissionControl::new(limits);
+        self
+    }
+
+    pub fn admission_limits(&self) -> AdmissionLimits {
+        self.admission.limits()
+    }
+
+    /// Work waiting for the writer, as admission control sees it
+    pub fn backlog(&self) -> Backlog {
+        match self.send(Command::Backlog) {
+            Ok(Reply::Backlog(backlog)) => backlog,
+            reply => unreachable!("Unexpected reply to Backlog: {:?}", reply),
+        }
+    }
+
+    fn execute_backlog(&self, state: &WriterState) -> Backlog {
+        let staged_bytes = state.transactions.values().map(|txn| txn.staged_bytes as u64).sum();
+        self.admission.backlog(state.transactions.len(), staged_bytes)
+    }
+
+    /// Fail with `StatehouseError::Overloaded` if admission control is
+    /// shedding load, after aborting idle transactions if it's set to.
+    /// `staging` is the transaction asking to stage, which isn't idle.
+    fn admit(&self, state: &mut WriterState, staging: Option<&str>) -> Result<()> {
+        let limits = self.admission.limits();
+        if !limits.is_enabled() {
+            return Ok(());
+        }
+        if let Some(abort_idle) = limits.abort_idle {
+            self.abort_idle_transactions(state, abort_idle, staging);
+        }
+        self.admission.admit(&self.execute_backlog(state))
+    }
+
+    /// Abort the largest idle transactions until the open ones hold no more
+    /// than `abort_idle.high_water_bytes`, or none left is idle long enough
+    fn abort_idle_transactions(&self, state: &mut WriterState, abort_idle: IdleAbort, staging: Option<&str>) {
+        let transactions = &mut state.transactions;
+        let mut staged_bytes: u64 = transactions.values().map(|txn| txn.staged_bytes as u64).sum();
+        while staged_bytes > abort_idle.high_water_bytes {
+            let largest = transactions
+                .values()
+                .filter(|txn| {
+                    txn.prepared.is_none()
+                        && txn.staged_bytes > 0
+                        && staging != Some(txn.txn_id.as_str())
+                        && txn.last_active.elapsed() >= abort_idle.min_idle
+                })
+                .max_by_key(|txn| txn.staged_bytes)
+                .map(|txn| txn.txn_id.clone());
+            let Some(txn_id) = largest else {
+                break;
+            };
+            let txn = transactions.remove(&txn_id).expect("transaction found above");
+            staged_bytes -= txn.staged_bytes as u64;
+            self.admission.record_idle_abort();
+            warn!(
+                txn_id = %txn_id,
+                freed_bytes = txn.staged_bytes,
+                idle_ms = txn.last_active.elapsed().as_millis() as u64,
+                "Aborted idle transaction under memory pressure"
+            );
+        }
+    }
+
+    /// A namespace's current usage and the quota it is held to, if any
+    pub fn namespace_usage(&self, namespace: &str) -> Result<(NamespaceUsage, Option<NamespaceQuota>)> {
+        Ok((self.storage.namespace_usage(namespace)?, self.quotas.get(namespace)))
+    }
+
+    /// Count the stored versions of every key in `namespace` into
+    /// `VERSION_DEPTH_BUCKETS`, along with the `top_n` (at most
+    /// `MAX_DEEPEST_KEYS`) deepest keys. Scans the
+    /// namespace's version keys without decoding any record.
+    pub fn version_depth_histogram(&self, namespace: &str, top_n: usize, deadline: Deadline) -> Result<VersionDepthHistogram> {
+        let mut counts = self.storage.version_counts(namespace, deadline)?;
+        let mut histogram = VersionDepthHistogram::default();
+        for (_, count) in &counts {
+            let bucket = VERSION_DEPTH_BUCKETS
+                .iter()
+                .position(|bound| count <= bound)
+                .unwrap_or(VERSION_DEPTH_BUCKETS.len());
+            histogram.buckets[bucket] += 1;
+            histogram.keys += 1;
+            histogram.versions += count;
+        }
+
+        // Stable, so ties stay in key order
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts.truncate(top_n.min(MAX_DEEPEST_KEYS));
+        histogram.deepest = counts;
+        Ok(histogram)
+    }
+
+    /// Begin a new transaction
+    pub fn begin_transaction(&self, timeout_ms: Option<u64>) -> Result<TxnId> {
+        self.begin_transaction_with_id(None, timeout_ms)
+    }
+
+    /// Begin a new transaction under a client-supplied `txn_id`, or a fresh
+    /// v4 UUID when `None`. Fails if the id is malformed or already open.
+    pub fn begin_transaction_with_id(&self, txn_id: Option<TxnId>, timeout_ms: Option<u64>) -> Result<TxnId> {
+        match self.send(Command::BeginTransaction { txn_id, timeout_ms })? {
+            Reply::Begun(txn_id) => Ok(txn_id),
+            reply => unreachable!("Unexpected reply to BeginTransaction: {:?}", reply),
+        }
+    }
+
+    /// Hand `command` to the writer and wait for its reply. Whoever takes the
+    /// writer runs everything queued, its own command included if nobody
+    /// got to it first, so a command never waits on a writer that has left.
+    /// Never called while running a command: the writer isn't reentrant.
+    fn send(&self, command: Command) -> Result<Reply> {
+        let (reply, replied) = mpsc::channel();
+        self.commands.sender.send((command, reply)).map_err(|_| anyhow!("Command channel closed"))?;
+        {
+            // If a writer panicked mid-drain, the commands after its own are
+            // still queued; carry on with them
+            let mut writer = self.commands.writer.lock().unwrap_or_else(|e| e.into_inner());
+            let Writer { receiver, state } = &mut *writer;
+            while let Ok((command, reply)) = receiver.try_recv() {
+                let _ = reply.send(self.execute(state, command));
+            }
+        }
+        replied.recv().map_err(|_| anyhow!("Command dropped by the writer"))?
+    }
+
+    /// Run one command; only ever called by the writer
+    fn execute(&self, state: &mut WriterState, command: Command) -> Result<Reply> {
+        match command {
+            Command::BeginTransaction { txn_id, timeout_ms } => self.execute_begin(state, txn_id, timeout_ms).map(Reply::Begun),
+            Command::Write { txn_id, namespace, agent_id, key, value, labels, ttl, client_seq } => {
+                self.execute_write(state, &txn_id, StagedOperation::Write { namespace, agent_id, key, value, labels, ttl }, client_seq)
+            }
+            Command::Delete { txn_id, namespace, agent_id, key } => self
+                .stage(state, &txn_id, StagedOperation::Delete { namespace, agent_id, key })
+                .map(|()| Reply::Done),
+            Command::Stage { txn_id, ops } => self.stage_all(state, &txn_id, ops).map(|()| Reply::Done),
+            Command::Savepoint { txn_id } => self.execute_savepoint(state, &txn_id).map(Reply::Savepoint),
+            Command::RollbackTo { txn_id, savepoint_id } => self.execute_rollback_to(state, &txn_id, savepoint_id).map(Reply::RolledBack),
+            Command::ReleaseSavepoint { txn_id, savepoint_id } => {
+                self.execute_release_savepoint(state, &txn_id, savepoint_id).map(|()| Reply::Done)
+            }
+            Command::Commit { txn_id, durability } => {
+                let started = Instant::now();
+                let result = self.execute_commit(state, &txn_id, durability).map(Reply::Committed);
+                self.admission.record_commit(started.elapsed());
+                result
+            }
+            Command::CommitEach { txn_id, durability, groups } => {
+                let started = Instant::now();
+                let result = self
+                    .execute_commit_groups(state, &txn_id, durability, Some(&groups))
+                    .map(|(result, failures)| Reply::CommittedEach(result, failures));
+                self.admission.record_commit(started.elapsed());
+                result
+            }
+            Command::Abort { txn_id } => self.execute_abort(state, &txn_id).map(|()| Reply::Done),
+            Command::PrepareCommit { txn_id, durability } => self.execute_prepare(state, &txn_id, durability).map(|()| Reply::Done),
+            Command::FinalizeCommit { txn_id } => {
+                let started = Instant::now();
+                let result = self.execute_finalize(state, &txn_id).map(Reply::Committed);
+                self.admission.record_commit(started.elapsed());
+                result
+            }
+            Command::CancelCommit { txn_id } => self.execute_cancel(state, &txn_id).map(|()| Reply::Done),
+            Command::FlushCoalesced { force } => {
+                let now = Instant::now();
+                self.commit_coalesced(&mut state.version_counters, |_, write| force || write.closes_at <= now).map(Reply::Flushed)
+            }
+            Command::CleanupExpired => {
+                self.execute_cleanup(state);
+                Ok(Reply::Done)
+            }
+            Command::ExpireKeys => self.execute_expire_keys(state).map(Reply::Expired),
+            Command::ApplyReplicated { event } => self.execute_apply_replicated(state, event).map(Reply::Applied),
+            Command::Import { records } => self.execute_import(state, records).map(Reply::Imported),
+            Command::PurgeTombstones { up_to_ts } => self.execute_gc_tombstones(state, up_to_ts).map(Reply::Compacted),
+            Command::PurgeKey { record_id } => self.execute_purge_key(state, &record_id).map(Reply::Purged),
+            Command::RenameNamespace { from, to, merge } => self.execute_rename_namespace(state, &from, &to, merge).map(Reply::Renamed),
+            Command::RestoreNamespace { namespace, snapshot } => {
+                self.execute_restore_namespace(state, &namespace, &snapshot).map(|()| Reply::Done)
+            }
+            Command::CompactHistory { up_to_ts, keep_versions } => self.storage.compact_history(up_to_ts, keep_versions).map(Reply::Compacted),
+            Command::TrimEvents { up_to_ts } => self.storage.trim_events(up_to_ts).map(Reply::Compacted),
+            Command::Recover { snapshot } => self.execute_recover(state, snapshot.as_deref()).map(Reply::Recovered),
+            Command::SeedVersions { snapshot } => self.seed_version_counters(&mut state.version_counters, &snapshot).map(|()| Reply::Done),
+            Command::CommittedTs => self.current_commit_ts().map(Reply::CommitTs),
+            Command::LastEventTs => Ok(Reply::CommitTs(self.storage.last_event_ts()?.unwrap_or(0))),
+            Command::Backlog => Ok(Reply::Backlog(self.execute_backlog(state))),
+            Command::CommitStatus { txn_id } => Ok(Reply::CommitStatus(self.execute_commit_status(state, &txn_id))),
+            Command::TransactionNamespace { txn_id } => {
+                let first = state.transactions.get(&txn_id).and_then(|txn| txn.operations.first());
+                Ok(Reply::Namespace(first.map(|op| op.scope().0.clone()).unwrap_or_default()))
+            }
+            Command::ListTransactions => Ok(Reply::Transactions(self.execute_list_transactions(state))),
+            Command::MonitorTransactions { warn_fraction } => Ok(Reply::Ages(self.execute_monitor(state, warn_fraction))),
+            Command::ConsistencyCheck => self.execute_consistency_check(state).map(Reply::Consistency),
+        }
+    }
+
+    /// The newest commit timestamp no commit is half-applied at
+    fn committed_ts(&self) -> Result<CommitTs> {
+        match self.send(Command::CommittedTs)? {
+            Reply::CommitTs(commit_ts) => Ok(commit_ts),
+            reply => unreachable!("Unexpected reply to CommittedTs: {:?}", reply),
+        }
+    }
+
+    fn execute_begin(&self, state: &mut WriterState, txn_id: Option<TxnId>, timeout_ms: Option<u64>) -> Result<TxnId> {
+        self.admit(state, None)?;
+        let txn_id = match txn_id {
+            Some(txn_id) => {
+                validate_txn_id(&txn_id)?;
+                txn_id
+            }
+            None => uuid::Uuid::new_v4().to_string(),
+        };
+        let timeout = Duration::from_millis(timeout_ms.unwrap_or(30000));
+
+        let now = Instant::now();
+        let txn = Transaction {
+            txn_id: txn_id.clone(),
+            created_at: now,
+            last_active: now,
+            timeout,
+            operations: Vec::new(),
+            staged_bytes: 0,
+            prepared: None,
+            savepoints: Vec::new(),
+            next_savepoint: 1,
+            client_seq: None,
+        };
+
+        let transactions = &mut state.transactions;
+        if transactions.contains_key(&txn_id)
+            || self.read_transactions.read().unwrap().contains_key(&txn_id)
+            || self.finished.lock().unwrap().contains_key(&txn_id)
+        {
+            return Err(StatehouseError::TransactionExists { txn_id }.into());
+        }
+        transactions.insert(txn_id.clone(), txn);
+
+        debug!("Transaction started: txn_id={}", txn_id);
+        Ok(txn_id)
+    }
+
+    /// Have the writer stage `ops` as a unit, as `stage_all` does
+    fn send_stage(&self, txn_id: &str, ops: Vec<StagedOperation>) -> Result<()> {
+        self.send(Command::Stage { txn_id: txn_id.to_string(), ops })?;
         Ok(())
     }
 
-    /// Check if snapshot should be created and do it if needed
-    pub fn maybe_snapshot(&self, snapshot_interval: u64) -> Result<()> {
-        let mut counter = self.commits_since_snapshot.write().unwrap();
-        *counter += 1;
-        
-        if *counter >= snapshot_interval {
-            drop(counter); // Release lock before creating snapshot
-            self.create_snapshot()?;
+    /// Append an operation to an open transaction, enforcing its timeout and
+    /// `TransactionLimits`. A rejected operation leaves the transaction as it was.
+    fn stage(&self, state: &mut WriterState, txn_id: &str, op: StagedOperation) -> Result<()> {
+        self.stage_all(state, txn_id, vec![op])
+    }
+
+    /// Append several operations as a unit: either all are staged or, if any
+    /// would break a limit, none are
+    fn stage_all(&self, state: &mut WriterState, txn_id: &str, ops: Vec<StagedOperation>) -> Result<()> {
+        self.admit(state, Some(txn_id))?;
+
+        // Before anything walks the value recursively (`size` serializes it)
+        for op in &ops {
+            if let Some(value) = op.value() {
+                if json_depth(value) > self.limits.max_json_depth {
+                    return Err(StatehouseError::ValueTooDeep { limit: self.limits.max_json_depth }.into());
+                }
+            }
         }
-        
+
+        let txn = Self::staging_transaction(&mut state.transactions, txn_id)?;
+
+        if txn.operations.len() + ops.len() > self.limits.max_ops_per_transaction {
+            return Err(StatehouseError::TransactionTooLarge {
+                txn_id: txn_id.to_string(),
+                limit: format!("max_ops_per_transaction ({})", self.limits.max_ops_per_transaction),
+            }.into());
+        }
+        let staged_bytes = txn.staged_bytes + ops.iter().map(StagedOperation::size).sum::<usize>();
+        if staged_bytes > self.limits.max_transaction_bytes {
+            return Err(StatehouseError::TransactionTooLarge {
+                txn_id: txn_id.to_string(),
+                limit: format!("max_transaction_bytes ({})", self.limits.max_transaction_bytes),
+            }.into());
+        }
+
+        txn.operations.extend(ops);
+        txn.staged_bytes = staged_bytes;
+        txn.last_active = Instant::now();
         Ok(())
     }
 
-    /// Load snapshot and replay events after snapshot timestamp
-    pub fn recover_from_snapshot(&self, snapshot: &crate::storage::Snapshot) -> Result<()> {
-        // Restore version counters from snapshot
-        let mut version_counters = self.version_counters.write().unwrap();
-        version_counters.clear();
-        
-        for record in &snapshot.records {
-            let record_id = RecordId::new(
-                record.namespace.clone(),
-                record.agent_id.clone(),
-                record.key.clone(),
-            );
-            version_counters.insert(record_id, record.version);
+    /// Stage a write unless `client_seq` shows it's a replay. Only the
+    /// writer stages, so nothing can stage between the check and recording
+    /// the new high-water mark.
+    fn execute_write(&self, state: &mut WriterState, txn_id: &str, op: StagedOperation, client_seq: Option<u64>) -> Result<Reply> {
+        let Some(seq) = client_seq else {
+            return self.stage(state, txn_id, op).map(|()| Reply::Done);
+        };
+        let applied = state.transactions.get(txn_id).and_then(|txn| txn.client_seq);
+        if applied.is_some_and(|applied| seq <= applied) {
+            return Ok(Reply::Duplicate);
+        }
+        self.stage(state, txn_id, op)?;
+        if let Some(txn) = state.transactions.get_mut(txn_id) {
+            txn.client_seq = Some(seq);
+        }
+        Ok(Reply::Done)
+    }
+
+    /// An open transaction that can still stage operations. An expired one
+    /// is dropped.
+    fn staging_transaction<'a>(transactions: &'a mut HashMap<TxnId, Transaction>, txn_id: &str) -> Result<&'a mut Transaction> {
+        let expired = transactions.get(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?.expired();
+        if expired {
+            transactions.remove(txn_id);
+            return Err(anyhow!("Transaction expired"));
+        }
+        let txn = transactions.get_mut(txn_id).expect("transaction checked above");
+        if txn.prepared.is_some() {
+            return Err(StatehouseError::TransactionPrepared { txn_id: txn_id.to_string() }.into());
+        }
+        Ok(txn)
+    }
+
+    /// Mark the transaction's current point, to discard what is staged after
+    /// it with `rollback_to`. Savepoints live in the staging buffer only.
+    pub fn savepoint(&self, txn_id: &str) -> Result<SavepointId> {
+        match self.send(Command::Savepoint { txn_id: txn_id.to_string() })? {
+            Reply::Savepoint(id) => Ok(id),
+            reply => unreachable!("Unexpected reply to Savepoint: {:?}", reply),
+        }
+    }
+
+    fn execute_savepoint(&self, state: &mut WriterState, txn_id: &str) -> Result<SavepointId> {
+        let txn = Self::staging_transaction(&mut state.transactions, txn_id)?;
+        let id = txn.next_savepoint;
+        txn.next_savepoint += 1;
+        txn.savepoints.push(Savepoint { id, operations: txn.operations.len(), staged_bytes: txn.staged_bytes });
+        debug!(txn_id = %txn_id, savepoint = id, "Savepoint taken");
+        Ok(id)
+    }
+
+    /// Discard the operations staged after `savepoint_id`, returning how
+    /// many. Later savepoints are released; this one stays, so the
+    /// transaction can roll back to it again.
+    pub fn rollback_to(&self, txn_id: &str, savepoint_id: SavepointId) -> Result<usize> {
+        match self.send(Command::RollbackTo { txn_id: txn_id.to_string(), savepoint_id })? {
+            Reply::RolledBack(discarded) => Ok(discarded),
+            reply => unreachable!("Unexpected reply to RollbackTo: {:?}", reply),
+        }
+    }
+
+    fn execute_rollback_to(&self, state: &mut WriterState, txn_id: &str, savepoint_id: SavepointId) -> Result<usize> {
+        let txn = Self::staging_transaction(&mut state.transactions, txn_id)?;
+        let index = Self::savepoint_index(txn, savepoint_id)?;
+        let savepoint = txn.savepoints[index];
+        txn.savepoints.truncate(index + 1);
+
+        let discarded = txn.operations.len() - savepoint.operations;
+        txn.operations.truncate(savepoint.operations);
+        txn.staged_bytes = savepoint.staged_bytes;
+        debug!(txn_id = %txn_id, savepoint = savepoint_id, discarded, "Rolled back to savepoint");
+        Ok(discarded)
+    }
+
+    /// Forget `savepoint_id` and every savepoint taken after it, keeping
+    /// the staged operations
+    pub fn release_savepoint(&self, txn_id: &str, savepoint_id: SavepointId) -> Result<()> {
+        self.send(Command::ReleaseSavepoint { txn_id: txn_id.to_string(), savepoint_id })?;
+        Ok(())
+    }
+
+    fn execute_release_savepoint(&self, state: &mut WriterState, txn_id: &str, savepoint_id: SavepointId) -> Result<()> {
+        let txn = Self::staging_transaction(&mut state.transactions, txn_id)?;
+        let index = Self::savepoint_index(txn, savepoint_id)?;
+        txn.savepoints.truncate(index);
+        Ok(())
+    }
+
+    fn savepoint_index(txn: &Transaction, savepoint_id: SavepointId) -> Result<usize> {
+        txn.savepoints.iter().position(|savepoint| savepoint.id == savepoint_id).ok_or_else(|| {
+            StatehouseError::SavepointNotFound { txn_id: txn.txn_id.clone(), savepoint_id }.into()
+        })
+    }
+
+    /// Stage a write operation
+    pub fn write(&self, txn_id: &str, namespace: String, agent_id: String, key: String, value: serde_json::Value) -> Result<()> {
+        self.write_with_labels(txn_id, namespace, agent_id, key, value, Labels::new())
+    }
+
+    /// Stage a write whose value carries `labels`. Labels belong to the
+    /// version written, like the value, and replace any the key had.
+    pub fn write_with_labels(&self, txn_id: &str, namespace: String, agent_id: String, key: String, value: serde_json::Value, labels: Labels) -> Result<()> {
+        self.send(Command::Write {
+            txn_id: txn_id.to_string(),
+            namespace,
+            agent_id,
+            key,
+            value,
+            labels,
+            ttl: None,
+            client_seq: None,
+        })?;
+        Ok(())
+    }
+
+    /// Stage a write that expires `ttl` after it commits: the first expiry
+    /// sweep from then on deletes the key, unless it has been written again.
+    /// A zero `ttl` never expires, even in a namespace with a default TTL
+    /// (see `with_default_ttls`), which writes without a TTL of their own
+    /// take.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_with_ttl(
+        &self,
+        txn_id: &str,
+        namespace: String,
+        agent_id: String,
+        key: String,
+        value: serde_json::Value,
+        labels: Labels,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.send(Command::Write {
+            txn_id: txn_id.to_string(),
+            namespace,
+            agent_id,
+            key,
+            value,
+            labels,
+            ttl: Some(ttl),
+            client_seq: None,
+        })?;
+        Ok(())
+    }
+
+    /// Stage a write tagged with the client's sequence number for it, which
+    /// increases with each write the client sends in the transaction. A
+    /// retried write whose seq is no higher than one already staged is
+    /// ignored, so it applies once however often it's resent. Returns false
+    /// for such a duplicate.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_with_seq(
+        &self,
+        txn_id: &str,
+        namespace: String,
+        agent_id: String,
+        key: String,
+        value: serde_json::Value,
+        labels: Labels,
+        client_seq: u64,
+    ) -> Result<bool> {
+        let reply = self.send(Command::Write {
+            txn_id: txn_id.to_string(),
+            namespace,
+            agent_id,
+            key,
+            value,
+            labels,
+            ttl: None,
+            client_seq: Some(client_seq),
+        })?;
+        Ok(!matches!(reply, Reply::Duplicate))
+    }
+
+    /// Stage a delete operation
+    pub fn delete(&self, txn_id: &str, namespace: String, agent_id: String, key: String) -> Result<()> {
+        self.send(Command::Delete {
+            txn_id: txn_id.to_string(),
+            namespace,
+            agent_id,
+            key,
+        })?;
+        Ok(())
+    }
+
+    /// Stage a delete that only applies if the key is still at `expected_version`
+    /// when the transaction commits
+    pub fn delete_if_version(&self, txn_id: &str, namespace: String, agent_id: String, key: String, expected_version: Version) -> Result<()> {
+        self.send_stage(txn_id, vec![StagedOperation::ConditionalDelete {
+            namespace,
+            agent_id,
+            key,
+            expected_version,
+        }])
+    }
+
+    /// Stage a write that only applies if the key's ETag (see `types::etag`)
+    /// is still `etag` when the transaction commits; otherwise the commit
+    /// fails with `StatehouseError::EtagMismatch`
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_if_match(
+        &self,
+        txn_id: &str,
+        namespace: String,
+        agent_id: String,
+        key: String,
+        value: serde_json::Value,
+        labels: Labels,
+        etag: String,
+    ) -> Result<()> {
+        self.send_stage(txn_id, vec![
+            StagedOperation::MatchEtag { namespace: namespace.clone(), agent_id: agent_id.clone(), key: key.clone(), etag },
+            StagedOperation::Write { namespace, agent_id, key, value, labels, ttl: None },
+        ])
+    }
+
+    /// Stage a delete that only applies if the key's ETag is still `etag`
+    /// when the transaction commits, as for `write_if_match`
+    pub fn delete_if_match(&self, txn_id: &str, namespace: String, agent_id: String, key: String, etag: String) -> Result<()> {
+        self.send_stage(txn_id, vec![
+            StagedOperation::MatchEtag { namespace: namespace.clone(), agent_id: agent_id.clone(), key: key.clone(), etag },
+            StagedOperation::Delete { namespace, agent_id, key },
+        ])
+    }
+
+    /// Stage a write that only applies if `predicate` holds against the key's
+    /// live value when the transaction commits; otherwise the commit fails
+    /// with `StatehouseError::PredicateFailed`
+    pub fn write_if(&self, txn_id: &str, namespace: String, agent_id: String, key: String, value: serde_json::Value, predicate: ValuePredicate) -> Result<()> {
+        self.send_stage(txn_id, vec![StagedOperation::ConditionalWriteIf {
+            namespace,
+            agent_id,
+            key,
+            value,
+            predicate,
+        }])
+    }
+
+    /// Stage a touch: rewrite the key's current value unchanged with a new
+    /// version and commit_ts. The commit fails if the key doesn't exist.
+    pub fn touch(&self, txn_id: &str, namespace: String, agent_id: String, key: String) -> Result<()> {
+        self.send_stage(txn_id, vec![StagedOperation::Touch {
+            namespace,
+            agent_id,
+            key,
+        }])
+    }
+
+    /// Stage an undelete: at commit, write the value the key had before it was
+    /// deleted back as a new version. The commit fails if the key is live or
+    /// missing, or if its tombstone is past its grace period.
+    pub fn undelete(&self, txn_id: &str, namespace: String, agent_id: String, key: String) -> Result<()> {
+        self.send_stage(txn_id, vec![StagedOperation::Undelete {
+            namespace,
+            agent_id,
+            key,
+        }])
+    }
+
+    /// Stage a rename: at commit, move `from_key`'s value to `to_key` and
+    /// tombstone `from_key`. The commit fails if `from_key` doesn't exist, or
+    /// if `to_key` exists and `overwrite` is false.
+    pub fn rename(&self, txn_id: &str, namespace: String, agent_id: String, from_key: String, to_key: String, overwrite: bool) -> Result<()> {
+        if from_key == to_key {
+            return Err(anyhow!("Cannot rename a key to itself"));
+        }
+
+        self.send_stage(txn_id, vec![StagedOperation::Rename {
+            namespace,
+            agent_id,
+            from_key,
+            to_key,
+            overwrite,
+        }])
+    }
+
+    /// Stage a swap: at commit, write `key_a`'s current value (and labels)
+    /// to `key_b` and `key_b`'s to `key_a`, giving both a new version. The
+    /// commit fails if either key doesn't exist, unless `missing_as_null`, in
+    /// which case a missing key's value counts as JSON null.
+    pub fn swap(&self, txn_id: &str, namespace: String, agent_id: String, key_a: String, key_b: String, missing_as_null: bool) -> Result<()> {
+        if key_a == key_b {
+            return Err(anyhow!("Cannot swap a key with itself"));
+        }
+
+        self.send_stage(txn_id, vec![StagedOperation::Swap {
+            namespace,
+            agent_id,
+            key_a,
+            key_b,
+            missing_as_null,
+        }])
+    }
+
+    /// Stage a delete of every live key the agent has now, returning those
+    /// keys. Committed, the deletes land in one event; unlike purging, the
+    /// agent's versions and events are kept, so replay and as-of reads still
+    /// see its state before the reset. Keys written after this call are not
+    /// cleared.
+    pub fn reset_agent(&self, txn_id: &str, namespace: String, agent_id: String) -> Result<Vec<Key>> {
+        let keys = self.storage.list_keys(&namespace, &agent_id, false, Deadline::NONE)?;
+        let ops = keys
+            .iter()
+            .map(|key| StagedOperation::Delete {
+                namespace: namespace.clone(),
+                agent_id: agent_id.clone(),
+                key: key.clone(),
+            })
+            .collect();
+        self.send_stage(txn_id, ops)?;
+        Ok(keys)
+    }
+
+    /// Stage a delete of each of `refs`, all or none, returning how many were
+    /// staged. Like `delete`, a key that doesn't exist gets a tombstone.
+    pub fn delete_batch(&self, txn_id: &str, refs: Vec<RecordId>) -> Result<usize> {
+        let ops: Vec<StagedOperation> = refs
+            .into_iter()
+            .map(|RecordId { namespace, agent_id, key }| StagedOperation::Delete { namespace, agent_id, key })
+            .collect();
+        let staged = ops.len();
+        self.send_stage(txn_id, ops)?;
+        Ok(staged)
+    }
+
+    /// Stage a get-or-create: at commit, keep the key's live value if it has
+    /// one, otherwise write `default`. The writer makes the check and the
+    /// write atomic, so of two racing calls only the first to commit creates;
+    /// the other sees its value. The outcome is in `CommitResult::get_or_create`.
+    pub fn get_or_create(&self, txn_id: &str, namespace: String, agent_id: String, key: String, default: serde_json::Value) -> Result<()> {
+        self.send_stage(txn_id, vec![StagedOperation::GetOrCreate {
+            namespace,
+            agent_id,
+            key,
+            default,
+        }])
+    }
+
+    /// Apply `ops` in a transaction of their own. With `atomic`, the batch
+    /// commits whole or not at all: a failed condition fails it like
+    /// `commit`. Otherwise each operation is resolved on its own, in order,
+    /// seeing those before it; one whose condition fails, or that can't
+    /// apply (an increment of a non-integer, say), is left out with its error
+    /// in `outcomes` and the rest commit together. Either way, a batch that
+    /// breaks a `TransactionLimits` limit fails before anything is resolved.
+    pub fn batch_apply(&self, ops: Vec<BatchOp>, atomic: bool, durability: Option<Durability>) -> Result<BatchApplyResult> {
+        let mut operations = Vec::with_capacity(ops.len());
+        let mut groups = Vec::with_capacity(ops.len());
+        let mut record_ids = Vec::with_capacity(ops.len());
+        for BatchOp { record_id, kind, expected_version } in ops {
+            let RecordId { namespace, agent_id, key } = record_id.clone();
+            if let Some(expected_version) = expected_version {
+                operations.push(StagedOperation::ExpectVersion {
+                    namespace: namespace.clone(),
+                    agent_id: agent_id.clone(),
+                    key: key.clone(),
+                    expected_version,
+                });
+            }
+            operations.push(match kind {
+                BatchOpKind::Write { value, labels } => StagedOperation::Write { namespace, agent_id, key, value, labels, ttl: None },
+                BatchOpKind::Delete => StagedOperation::Delete { namespace, agent_id, key },
+                BatchOpKind::Increment { pointer, delta } => {
+                    if !pointer.is_empty() && !pointer.starts_with('/') {
+                        return Err(anyhow!("Invalid JSON pointer {:?}: must be empty or start with '/'", pointer));
+                    }
+                    StagedOperation::Increment { namespace, agent_id, key, pointer, delta }
+                }
+            });
+            groups.push(1 + expected_version.is_some() as usize);
+            record_ids.push(record_id);
+        }
+
+        let txn_id = self.begin_transaction(None)?;
+        let committed = self.send_stage(&txn_id, operations).and_then(|()| {
+            if atomic {
+                let result = self.commit_with_durability(&txn_id, durability)?;
+                return Ok((result, record_ids.iter().map(|_| None).collect()));
+            }
+            let _pending = self.admission.pending_commit();
+            let _turn = self.commit_turn(&txn_id);
+            match self.send(Command::CommitEach { txn_id: txn_id.clone(), durability, groups })? {
+                Reply::CommittedEach(result, failures) => Ok((result, failures)),
+                reply => unreachable!("Unexpected reply to CommitEach: {:?}", reply),
+            }
+        });
+        let (commit, failures) = match committed {
+            Ok(committed) => committed,
+            Err(e) => {
+                // Whatever failed may have left the transaction open
+                let _ = self.abort(&txn_id);
+                return Err(e);
+            }
+        };
+
+        let version_of = |record_id: &RecordId| {
+            commit.changed.iter().position(|id| id == record_id).map_or(0, |index| commit.versions[index].1)
+        };
+        let outcomes = record_ids
+            .iter()
+            .zip(failures)
+            .map(|(record_id, failure)| match failure {
+                Some(e) => Err(e),
+                None => Ok(version_of(record_id)),
+            })
+            .collect();
+        Ok(BatchApplyResult { txn_id, commit, outcomes })
+    }
+
+    /// Current version of a record, seeding the counter from storage on first use
+    fn current_version(&self, version_counters: &mut HashMap<RecordId, Version>, record_id: &RecordId) -> Result<Version> {
+        if let Some(version) = version_counters.get(record_id) {
+            return Ok(*version);
+        }
+        let version = self.storage.read_state(record_id)?.map(|r| r.version).unwrap_or(0);
+        version_counters.insert(record_id.clone(), version);
+        Ok(version)
+    }
+
+    /// The next commit timestamp, from the current block if blocks are on.
+    /// Called by `apply`, in the writer.
+    fn next_commit_ts(&self) -> Result<CommitTs> {
+        if self.commit_ts_block_size == 0 {
+            return self.storage.next_commit_ts();
+        }
+        let mut block = self.commit_ts_block.lock().unwrap();
+        match block.as_mut() {
+            Some(block) if block.last < block.end => {
+                block.last += 1;
+                Ok(block.last)
+            }
+            _ => {
+                let (start, end) = self.storage.reserve_commit_ts_block(self.commit_ts_block_size)?;
+                *block = Some(CommitTsBlock { last: start, end });
+                Ok(start)
+            }
+        }
+    }
+
+    /// The most recently issued commit timestamp. With blocks on, storage's
+    /// counter is the end of the current block, past anything issued.
+    fn current_commit_ts(&self) -> Result<CommitTs> {
+        match *self.commit_ts_block.lock().unwrap() {
+            Some(block) => Ok(block.last),
+            None => self.storage.current_commit_ts(),
+        }
+    }
+
+    /// Turn staged operations into the record changes they produce, checking
+    /// preconditions against committed state plus the transaction's own earlier operations
+    fn resolve_operations(&self, version_counters: &mut HashMap<RecordId, Version>, operations: Vec<StagedOperation>) -> Result<(Vec<Mutation>, Vec<GetOrCreateResult>)> {
+        let mut resolution = Resolution::default();
+        for op in operations {
+            self.resolve_operation(version_counters, &mut resolution, op)?;
+        }
+        Ok((resolution.mutations, resolution.get_or_create))
+    }
+
+    /// Like `resolve_operations`, but each of `groups` (counts of consecutive
+    /// operations) is resolved on its own: a group failing with a
+    /// `StatehouseError` is left out, its error returned in its place, and
+    /// later groups see only the groups before them that succeeded. Any
+    /// other error fails the whole commit.
+    fn resolve_groups(&self, version_counters: &mut HashMap<RecordId, Version>, operations: Vec<StagedOperation>, groups: &[usize]) -> Result<(Vec<Mutation>, Vec<GetOrCreateResult>, GroupFailures)> {
+        let mut resolution = Resolution::default();
+        let mut failures = Vec::with_capacity(groups.len());
+        let mut operations = operations.into_iter();
+
+        for &len in groups {
+            let group: Vec<StagedOperation> = operations.by_ref().take(len).collect();
+            let (mutations, get_or_create) = (resolution.mutations.len(), resolution.get_or_create.len());
+            match group.into_iter().try_for_each(|op| self.resolve_operation(version_counters, &mut resolution, op)) {
+                Ok(()) => failures.push(None),
+                Err(e) if e.downcast_ref::<StatehouseError>().is_some() => {
+                    resolution.truncate(mutations, get_or_create);
+                    failures.push(Some(e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok((resolution.mutations, resolution.get_or_create, failures))
+    }
+
+    /// Resolve one operation onto `resolution`, after those already in it
+    fn resolve_operation(&self, version_counters: &mut HashMap<RecordId, Version>, resolution: &mut Resolution, op: StagedOperation) -> Result<()> {
+        let Resolution { pending, mutations, get_or_create } = resolution;
+        let mutation = match op {
+            StagedOperation::Write { namespace, agent_id, key, value, labels, ttl } => Mutation {
+                record_id: RecordId::new(namespace, agent_id, key),
+                value: Some(value),
+                labels,
+                ttl,
+            },
+            StagedOperation::Delete { namespace, agent_id, key } => Mutation::new(RecordId::new(namespace, agent_id, key), None),
+            StagedOperation::ConditionalDelete { namespace, agent_id, key, expected_version } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let actual = self.current_version(version_counters, &record_id)?
+                    + mutations.iter().filter(|m| m.record_id == record_id).count() as Version;
+                if actual != expected_version {
+                    debug!(key = %record_id.key, "Conditional delete conflict");
+                    return Err(StatehouseError::Conflict {
+                        namespace: record_id.namespace,
+                        agent_id: record_id.agent_id,
+                        key: record_id.key,
+                        expected: expected_version,
+                        actual,
+                    }.into());
+                }
+                Mutation::new(record_id, None)
+            }
+            StagedOperation::ConditionalWriteIf { namespace, agent_id, key, value, predicate } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let current = self.live_value(pending, &record_id)?.map(|(value, _)| value);
+                if let Err(reason) = predicate.check(current.as_ref()) {
+                    debug!(key = %record_id.key, reason = %reason, "Conditional write rejected");
+                    return Err(StatehouseError::PredicateFailed {
+                        namespace: record_id.namespace,
+                        agent_id: record_id.agent_id,
+                        key: record_id.key,
+                        reason,
+                    }.into());
+                }
+                Mutation::new(record_id, Some(value))
+            }
+            StagedOperation::Touch { namespace, agent_id, key } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let Some((value, labels)) = self.live_value(pending, &record_id)? else {
+                    return Err(StatehouseError::KeyNotFound {
+                        namespace: record_id.namespace,
+                        agent_id: record_id.agent_id,
+                        key: record_id.key,
+                    }.into());
+                };
+                Mutation { record_id, value: Some(value), labels, ttl: None }
+            }
+            StagedOperation::Rename { namespace, agent_id, from_key, to_key, overwrite } => {
+                let from_id = RecordId::new(namespace.clone(), agent_id.clone(), from_key);
+                let to_id = RecordId::new(namespace, agent_id, to_key);
+                let Some((value, labels)) = self.live_value(pending, &from_id)? else {
+                    return Err(StatehouseError::KeyNotFound {
+                        namespace: from_id.namespace,
+                        agent_id: from_id.agent_id,
+                        key: from_id.key,
+                    }.into());
+                };
+                if !overwrite && self.live_value(pending, &to_id)?.is_some() {
+                    return Err(StatehouseError::KeyExists {
+                        namespace: to_id.namespace,
+                        agent_id: to_id.agent_id,
+                        key: to_id.key,
+                    }.into());
+                }
+
+                // Stage the destination write here; the source tombstone follows it
+                pending.insert(to_id.clone(), Some((value.clone(), labels.clone())));
+                mutations.push(Mutation { record_id: to_id, value: Some(value), labels, ttl: None });
+                Mutation::new(from_id, None)
+            }
+            StagedOperation::Swap { namespace, agent_id, key_a, key_b, missing_as_null } => {
+                let a_id = RecordId::new(namespace.clone(), agent_id.clone(), key_a);
+                let b_id = RecordId::new(namespace, agent_id, key_b);
+                let current = |record_id: &RecordId| -> Result<(serde_json::Value, Labels)> {
+                    match self.live_value(pending, record_id)? {
+                        Some(live) => Ok(live),
+                        None if missing_as_null => Ok((serde_json::Value::Null, Labels::new())),
+                        None => Err(StatehouseError::KeyNotFound {
+                            namespace: record_id.namespace.clone(),
+                            agent_id: record_id.agent_id.clone(),
+                            key: record_id.key.clone(),
+                        }.into()),
+                    }
+                };
+                let (a_value, a_labels) = current(&a_id)?;
+                let (b_value, b_labels) = current(&b_id)?;
+
+                // Stage the write to `key_b` here; the one to `key_a` follows it
+                pending.insert(b_id.clone(), Some((a_value.clone(), a_labels.clone())));
+                mutations.push(Mutation { record_id: b_id, value: Some(a_value), labels: a_labels, ttl: None });
+                Mutation { record_id: a_id, value: Some(b_value), labels: b_labels, ttl: None }
+            }
+            StagedOperation::GetOrCreate { namespace, agent_id, key, default } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let existing = self.live_value(pending, &record_id)?.map(|(value, _)| value);
+                // Each earlier mutation of the record in this transaction takes a version
+                let version = self.current_version(version_counters, &record_id)?
+                    + mutations.iter().filter(|m| m.record_id == record_id).count() as Version;
+
+                match existing {
+                    Some(value) => {
+                        get_or_create.push(GetOrCreateResult { record_id, created: false, value, version });
+                        return Ok(());
+                    }
+                    None => {
+                        get_or_create.push(GetOrCreateResult {
+                            record_id: record_id.clone(),
+                            created: true,
+                            value: default.clone(),
+                            version: version + 1,
+                        });
+                        Mutation::new(record_id, Some(default))
+                    }
+                }
+            }
+            StagedOperation::Undelete { namespace, agent_id, key } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                if pending.contains_key(&record_id) {
+                    return Err(StatehouseError::UndeleteAfterChange {
+                        namespace: record_id.namespace,
+                        agent_id: record_id.agent_id,
+                        key: record_id.key,
+                    }.into());
+                }
+                let (value, labels) = self.deleted_value(record_id.clone())?;
+                Mutation { record_id, value: Some(value), labels, ttl: None }
+            }
+            StagedOperation::ExpectVersion { namespace, agent_id, key, expected_version } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let actual = self.current_version(version_counters, &record_id)?
+                    + mutations.iter().filter(|m| m.record_id == record_id).count() as Version;
+                if actual != expected_version {
+                    debug!(key = %record_id.key, "Version precondition conflict");
+                    return Err(StatehouseError::Conflict {
+                        namespace: record_id.namespace,
+                        agent_id: record_id.agent_id,
+                        key: record_id.key,
+                        expected: expected_version,
+                        actual,
+                    }.into());
+                }
+                return Ok(());
+            }
+            StagedOperation::MatchEtag { namespace, agent_id, key, etag } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let actual = self.current_version(version_counters, &record_id)?
+                    + mutations.iter().filter(|m| m.record_id == record_id).count() as Version;
+                let content = self.live_value(pending, &record_id)?;
+                let actual = crate::types::etag(actual, content.as_ref().map(|(value, labels)| (value, labels)));
+                if actual != etag {
+                    debug!(key = %record_id.key, "ETag precondition failed");
+                    return Err(StatehouseError::EtagMismatch {
+                        namespace: record_id.namespace,
+                        agent_id: record_id.agent_id,
+                        key: record_id.key,
+                        expected: etag,
+                        actual,
+                    }.into());
+                }
+                return Ok(());
+            }
+            StagedOperation::Increment { namespace, agent_id, key, pointer, delta } => {
+                let record_id = RecordId::new(namespace, agent_id, key);
+                let (current, labels) = self.live_value(pending, &record_id)?.unzip();
+                match incremented(current, &pointer, delta) {
+                    Ok(value) => Mutation { record_id, value: Some(value), labels: labels.unwrap_or_default(), ttl: None },
+                    Err(reason) => {
+                        return Err(StatehouseError::PredicateFailed {
+                            namespace: record_id.namespace,
+                            agent_id: record_id.agent_id,
+                            key: record_id.key,
+                            reason,
+                        }.into());
+                    }
+                }
+            }
+        };
+        pending.insert(mutation.record_id.clone(), mutation.value.clone().map(|value| (value, mutation.labels.clone())));
+        mutations.push(mutation);
+        Ok(())
+    }
+
+    /// The value and labels a deleted record had before its tombstone,
+    /// provided the tombstone is still within its grace period
+    fn deleted_value(&self, record_id: RecordId) -> Result<(serde_json::Value, Labels)> {
+        let tombstone = match self.storage.read_state(&record_id)? {
+            Some(record) if record.deleted => record,
+            Some(_) => {
+                return Err(StatehouseError::KeyExists {
+                    namespace: record_id.namespace,
+                    agent_id: record_id.agent_id,
+                    key: record_id.key,
+                }.into());
+            }
+            None => {
+                return Err(StatehouseError::KeyNotFound {
+                    namespace: record_id.namespace,
+                    agent_id: record_id.agent_id,
+                    key: record_id.key,
+                }.into());
+            }
+        };
+        if tombstone.purge_after.is_none_or(|purge_after| purge_after <= unix_now_ms()) {
+            return Err(StatehouseError::GracePeriodExpired {
+                namespace: record_id.namespace,
+                agent_id: record_id.agent_id,
+                key: record_id.key,
+            }.into());
+        }
+
+        // Walk back past repeated deletes to the last live version
+        for version in (1..tombstone.version).rev() {
+            match self.storage.read_state_at_version(&record_id, version)? {
+                Some(record) if !record.deleted => {
+                    if let Some(value) = record.value {
+                        return Ok((value, record.labels));
+                    }
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Err(StatehouseError::KeyNotFound {
+            namespace: record_id.namespace,
+            agent_id: record_id.agent_id,
+            key: record_id.key,
+        }.into())
+    }
+
+    /// Deadline for a tombstone written now, if deletes have a grace period
+    fn purge_after(&self) -> Option<u64> {
+        (!self.delete_grace.is_zero()).then(|| unix_now_ms() + self.delete_grace.as_millis() as u64)
+    }
+
+    /// Value of a live record as a committing transaction sees it
+    fn live_value(&self, pending: &HashMap<RecordId, LiveValue>, record_id: &RecordId) -> Result<LiveValue> {
+        if let Some(value) = pending.get(record_id) {
+            return Ok(value.clone());
+        }
+        Ok(self.storage.read_state(record_id)?.filter(|r| !r.deleted).and_then(|r| Some((r.value?, r.labels))))
+    }
+
+    /// Commit a transaction atomically, returning its commit timestamp and
+    /// the records it changed (for in-process cache invalidation)
+    pub fn commit(&self, txn_id: &str) -> Result<CommitResult> {
+        self.commit_with_durability(txn_id, None)
+    }
+
+    /// Commit a transaction, acknowledging it once it is as durable as
+    /// `durability` asks (`None` for the storage's configured default). An
+    /// unsupported durability fails before anything is written and leaves
+    /// the transaction open.
+    pub fn commit_with_durability(&self, txn_id: &str, durability: Option<Durability>) -> Result<CommitResult> {
+        let _pending = self.admission.pending_commit();
+        let _turn = self.commit_turn(txn_id);
+        match self.send(Command::Commit { txn_id: txn_id.to_string(), durability })? {
+            Reply::Committed(result) => Ok(result),
+            reply => unreachable!("Unexpected reply to Commit: {:?}", reply),
+        }
+    }
+
+    /// Wait for a commit's turn before queueing it, so the writer sees
+    /// commits in the configured order rather than strictly as they arrive
+    fn commit_turn(&self, txn_id: &str) -> Option<CommitTurn<'_>> {
+        self.commit_queue.as_ref().map(|queue| {
+            queue.enter(|| match self.send(Command::TransactionNamespace { txn_id: txn_id.to_string() }) {
+                Ok(Reply::Namespace(namespace)) => namespace,
+                reply => unreachable!("Unexpected reply to TransactionNamespace: {:?}", reply),
+            })
+        })
+    }
+
+    /// First phase of a two-phase commit: check the transaction would commit
+    /// against current state, then close it to further staging and hold its
+    /// keys, so other transactions touching them fail with `KeyPrepared`
+    /// until it is finalized or cancelled. A prepared transaction expires
+    /// its timeout after being prepared, so a client has that long to
+    /// reconnect and finish it. The commit timestamp is assigned when it is
+    /// finalized, keeping timestamps in the order commits are applied.
+    /// Preparing an already prepared transaction does nothing.
+    pub fn prepare_commit(&self, txn_id: &str, durability: Option<Durability>) -> Result<()> {
+        self.send(Command::PrepareCommit { txn_id: txn_id.to_string(), durability })?;
+        Ok(())
+    }
+
+    /// Commit a prepared transaction. Once it has committed, calling this
+    /// again returns the same result for `FINISHED_COMMIT_RETENTION`, so a
+    /// client can retry it safely after losing the reply.
+    pub fn finalize_commit(&self, txn_id: &str) -> Result<CommitResult> {
+        let _pending = self.admission.pending_commit();
+        let _turn = self.commit_turn(txn_id);
+        match self.send(Command::FinalizeCommit { txn_id: txn_id.to_string() })? {
+            Reply::Committed(result) => Ok(result),
+            reply => unreachable!("Unexpected reply to FinalizeCommit: {:?}", reply),
+        }
+    }
+
+    /// Drop a prepared transaction, releasing its keys. Cancelling one that
+    /// is already cancelled does nothing.
+    pub fn cancel_commit(&self, txn_id: &str) -> Result<()> {
+        self.send(Command::CancelCommit { txn_id: txn_id.to_string() })?;
+        Ok(())
+    }
+
+    /// Where `txn_id` is in the two-phase commit protocol
+    pub fn commit_status(&self, txn_id: &str) -> CommitStatus {
+        match self.send(Command::CommitStatus { txn_id: txn_id.to_string() }) {
+            Ok(Reply::CommitStatus(status)) => status,
+            reply => unreachable!("Unexpected reply to CommitStatus: {:?}", reply),
+        }
+    }
+
+    fn execute_commit_status(&self, state: &WriterState, txn_id: &str) -> CommitStatus {
+        if let Some(txn) = state.transactions.get(txn_id).filter(|txn| !txn.expired()) {
+            return if txn.prepared.is_some() { CommitStatus::Prepared } else { CommitStatus::Open };
+        }
+        match self.finished.lock().unwrap().get(txn_id) {
+            Some(FinishedCommit { result: Some(result), .. }) => CommitStatus::Committed(result.clone()),
+            Some(FinishedCommit { result: None, .. }) => CommitStatus::Cancelled,
+            None => CommitStatus::Unknown,
+        }
+    }
+
+    fn execute_prepare(&self, state: &mut WriterState, txn_id: &str, durability: Option<Durability>) -> Result<()> {
+        if let Some(durability) = durability.filter(|d| !self.storage.supports_durability(*d)) {
+            return Err(StatehouseError::UnsupportedDurability { durability }.into());
+        }
+
+        let WriterState { transactions, version_counters } = state;
+        let txn = transactions.get(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?;
+        if txn.expired() {
+            transactions.remove(txn_id);
+            return Err(anyhow!("Transaction expired"));
+        }
+        if txn.prepared.is_some() {
+            return Ok(());
+        }
+        self.check_prepared_keys(transactions, txn_id, &txn.operations)?;
+
+        // A failed check leaves the transaction open
+        let (mut mutations, _) = self.resolve_operations(version_counters, txn.operations.clone())?;
+        self.add_evictions(&mut mutations)?;
+        self.check_quotas(&mutations)?;
+
+        if let Some(txn) = transactions.get_mut(txn_id) {
+            txn.prepared = Some(PreparedCommit { at: Instant::now(), durability });
+        }
+        debug!(txn_id = %txn_id, "Transaction prepared");
+        Ok(())
+    }
+
+    fn execute_finalize(&self, state: &mut WriterState, txn_id: &str) -> Result<CommitResult> {
+        if let Some(finished) = self.finished.lock().unwrap().get(txn_id) {
+            return finished.result.clone().ok_or_else(|| StatehouseError::TransactionNotPrepared { txn_id: txn_id.to_string() }.into());
+        }
+
+        let (operations, durability) = {
+            let transactions = &mut state.transactions;
+            let prepared = transactions.get(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?.prepared.clone();
+            let Some(prepared) = prepared else {
+                return Err(StatehouseError::TransactionNotPrepared { txn_id: txn_id.to_string() }.into());
+            };
+            let txn = transactions.remove(txn_id).expect("transaction checked above");
+            if txn.expired() {
+                self.finish(txn_id, None);
+                return Err(anyhow!("Transaction expired"));
+            }
+            (txn.operations, prepared.durability)
+        };
+
+        if !self.coalesce.is_empty() {
+            let used: HashSet<RecordId> = operations.iter().flat_map(StagedOperation::record_ids).collect();
+            self.commit_coalesced(&mut state.version_counters, |record_id, _| used.contains(record_id))?;
+        }
+        let result = self.apply(&mut state.version_counters, txn_id, operations, durability);
+        self.finish(txn_id, result.as_ref().ok().cloned());
+        result
+    }
+
+    fn execute_cancel(&self, state: &mut WriterState, txn_id: &str) -> Result<()> {
+        let transactions = &mut state.transactions;
+        match transactions.get(txn_id) {
+            Some(txn) if txn.prepared.is_some() => {
+                transactions.remove(txn_id);
+                self.finish(txn_id, None);
+                debug!(txn_id = %txn_id, "Prepared transaction cancelled");
+                Ok(())
+            }
+            Some(_) => Err(StatehouseError::TransactionNotPrepared { txn_id: txn_id.to_string() }.into()),
+            None => match self.finished.lock().unwrap().get(txn_id) {
+                Some(FinishedCommit { result: None, .. }) => Ok(()),
+                Some(FinishedCommit { result: Some(result), .. }) => Err(anyhow!("Transaction {} already committed at commit_ts {}", txn_id, result.commit_ts)),
+                None => Err(anyhow!("Transaction not found")),
+            },
+        }
+    }
+
+    /// Remember how a prepared transaction ended
+    fn finish(&self, txn_id: &str, result: Option<CommitResult>) {
+        self.finished.lock().unwrap().insert(txn_id.to_string(), FinishedCommit { at: Instant::now(), result });
+    }
+
+    /// Fail if another prepared transaction holds a key `operations` touch
+    fn check_prepared_keys(&self, transactions: &HashMap<TxnId, Transaction>, txn_id: &str, operations: &[StagedOperation]) -> Result<()> {
+        let used: HashSet<RecordId> = operations.iter().flat_map(StagedOperation::record_ids).collect();
+        for other in transactions.values().filter(|other| other.prepared.is_some() && other.txn_id != txn_id && !other.expired()) {
+            if let Some(record_id) = other.operations.iter().flat_map(StagedOperation::record_ids).find(|id| used.contains(id)) {
+                return Err(StatehouseError::KeyPrepared {
+                    namespace: record_id.namespace,
+                    agent_id: record_id.agent_id,
+                    key: record_id.key,
+                    txn_id: other.txn_id.clone(),
+                }.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_commit(&self, state: &mut WriterState, txn_id: &str, durability: Option<Durability>) -> Result<CommitResult> {
+        self.execute_commit_groups(state, txn_id, durability, None).map(|(result, _)| result)
+    }
+
+    /// Commit a transaction, resolving each of `groups` on its own if given
+    /// (see `resolve_groups`). Returns per group the error that left it out.
+    fn execute_commit_groups(&self, state: &mut WriterState, txn_id: &str, durability: Option<Durability>, groups: Option<&[usize]>) -> Result<(CommitResult, GroupFailures)> {
+        use tracing::debug;
+        
+        debug!(txn_id = %txn_id, "Committing transaction");
+
+        if let Some(durability) = durability.filter(|d| !self.storage.supports_durability(*d)) {
+            return Err(StatehouseError::UnsupportedDurability { durability }.into());
+        }
+        
+        // Remove transaction from staging
+        let txn = {
+            let transactions = &mut state.transactions;
+            let txn = transactions.get(txn_id).ok_or_else(|| anyhow!("Transaction not found"))?;
+            if txn.prepared.is_some() {
+                return Err(StatehouseError::TransactionPrepared { txn_id: txn_id.to_string() }.into());
+            }
+            let txn = transactions.remove(txn_id).expect("transaction checked above");
+            if !txn.expired() {
+                self.check_prepared_keys(transactions, txn_id, &txn.operations)?;
+            }
+            txn
+        };
+
+        // Check timeout
+        if txn.expired() {
+            debug!(txn_id = %txn_id, "Transaction expired");
+            return Err(anyhow!("Transaction expired"));
+        }
+
+        // Buffered writes whose window closed are left to `flush_coalesced`,
+        // so that failing to write them fails the flush, not this commit. A
+        // commit that asks for a durability isn't buffered: it must be that
+        // durable when acknowledged, and a buffered write is only in memory.
+        if !self.coalesce.is_empty() {
+            if groups.is_none() && durability.is_none() && self.coalescable(&txn.operations) {
+                self.buffer_coalesced(txn.operations, Instant::now());
+                debug!(txn_id = %txn_id, "Writes coalesced");
+                return Ok((CommitResult { coalesced: true, ..Default::default() }, Vec::new()));
+            }
+
+            // Buffered writes to keys this transaction uses land first
+            let used: HashSet<RecordId> = txn.operations.iter().flat_map(StagedOperation::record_ids).collect();
+            self.commit_coalesced(&mut state.version_counters, |record_id, _| used.contains(record_id))?;
+        }
+
+        let Some(groups) = groups else {
+            return self.apply(&mut state.version_counters, txn_id, txn.operations, durability).map(|result| (result, Vec::new()));
+        };
+        let mut failures = Vec::new();
+        let result = self.apply_resolved(&mut state.version_counters, txn_id, durability, |version_counters| {
+            let (mutations, get_or_create, group_failures) = self.resolve_groups(version_counters, txn.operations, groups)?;
+            failures = group_failures;
+            Ok((mutations, get_or_create))
+        })?;
+        Ok((result, failures))
+    }
+
+    /// Whether a transaction is only plain writes to coalescing keys
+    fn coalescable(&self, operations: &[StagedOperation]) -> bool {
+        !operations.is_empty()
+            && operations.iter().all(|op| matches!(op, StagedOperation::Write { key, ttl: None, .. } if self.coalesce.window(key).is_some()))
+    }
+
+    fn buffer_coalesced(&self, operations: Vec<StagedOperation>, now: Instant) {
+        let mut coalesced = self.coalesced.lock().unwrap();
+        for op in operations {
+            let StagedOperation::Write { namespace, agent_id, key, value, labels, .. } = op else { continue };
+            let Some(window) = self.coalesce.window(&key) else { continue };
+            // The window opens with the first buffered write and isn't extended
+            match coalesced.entry(RecordId::new(namespace, agent_id, key)) {
+                Entry::Occupied(mut entry) => {
+                    let write = entry.get_mut();
+                    write.value = value;
+                    write.labels = labels;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(CoalescedWrite { value, labels, closes_at: now + window });
+                }
+            }
+        }
+    }
+
+    /// Commit the buffered writes `select` picks as one transaction,
+    /// returning how many keys were written. Their commits were already
+    /// acknowledged, so if that fails they stay buffered for the next try.
+    fn commit_coalesced(&self, version_counters: &mut HashMap<RecordId, Version>, select: impl Fn(&RecordId, &CoalescedWrite) -> bool) -> Result<usize> {
+        let mut due: Vec<(RecordId, CoalescedWrite)> = {
+            let mut coalesced = self.coalesced.lock().unwrap();
+            let selected: Vec<RecordId> = coalesced.iter().filter(|(id, write)| select(id, write)).map(|(id, _)| id.clone()).collect();
+            selected.iter().filter_map(|id| coalesced.remove_entry(id)).collect()
+        };
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        due.sort_by(|(a, _), (b, _)| (&a.namespace, &a.agent_id, &a.key).cmp(&(&b.namespace, &b.agent_id, &b.key)));
+        let keys = due.len();
+        let operations = due
+            .iter()
+            .map(|(RecordId { namespace, agent_id, key }, write)| StagedOperation::Write {
+                namespace: namespace.clone(),
+                agent_id: agent_id.clone(),
+                key: key.clone(),
+                value: write.value.clone(),
+                labels: write.labels.clone(),
+                ttl: None,
+            })
+            .collect();
+        if let Err(e) = self.apply(version_counters, &uuid::Uuid::new_v4().to_string(), operations, None) {
+            // A write buffered for the key since is newer and wins
+            let mut coalesced = self.coalesced.lock().unwrap();
+            for (record_id, write) in due {
+                coalesced.entry(record_id).or_insert(write);
+            }
+            return Err(e);
+        }
+        Ok(keys)
+    }
+
+    /// Commit buffered coalesced writes whose window has closed, or every
+    /// one with `force`. Returns how many keys were written.
+    pub fn flush_coalesced(&self, force: bool) -> Result<usize> {
+        match self.send(Command::FlushCoalesced { force })? {
+            Reply::Flushed(keys) => Ok(keys),
+            reply => unreachable!("Unexpected reply to FlushCoalesced: {:?}", reply),
+        }
+    }
+
+    /// Apply a transaction's operations: resolve them, write the records and
+    /// event, and ship the event
+    fn apply(&self, version_counters: &mut HashMap<RecordId, Version>, txn_id: &str, operations: Vec<StagedOperation>, durability: Option<Durability>) -> Result<CommitResult> {
+        self.apply_resolved(version_counters, txn_id, durability, |version_counters| self.resolve_operations(version_counters, operations))
+    }
+
+    /// `apply`, with the operations resolved by `resolve`
+    fn apply_resolved(
+        &self,
+        version_counters: &mut HashMap<RecordId, Version>,
+        txn_id: &str,
+        durability: Option<Durability>,
+        resolve: impl FnOnce(&mut HashMap<RecordId, Version>) -> Result<(Vec<Mutation>, Vec<GetOrCreateResult>)>,
+    ) -> Result<CommitResult> {
+        // Resolve every operation before anything is written, so a failed
+        // precondition leaves storage untouched
+        let (mut mutations, get_or_create) = resolve(version_counters)?;
+        self.add_evictions(&mut mutations)?;
+        self.check_quotas(&mutations)?;
+
+        // Get commit timestamp
+        let commit_ts = self.next_commit_ts()?;
+        let mut namespace_ts = BTreeMap::new();
+        if self.namespace_sequences {
+            for Mutation { record_id, .. } in &mutations {
+                if !namespace_ts.contains_key(&record_id.namespace) {
+                    let ts = self.storage.next_namespace_ts(&record_id.namespace)?;
+                    namespace_ts.insert(record_id.namespace.clone(), ts);
+                }
+            }
+        }
+
+        // Apply mutations
+        let now = unix_now_ms();
+        let purge_after = self.purge_after();
+        let mut operation_records = Vec::new();
+        let mut changed = Vec::new();
+        let mut versions: Vec<(Version, Version)> = Vec::new();
+        let mut seen: HashMap<RecordId, usize> = HashMap::new();
+
+        for Mutation { record_id, value, labels, ttl } in mutations {
+            // Get next version for this key
+            let previous_version = self.current_version(version_counters, &record_id)?;
+            let current_version = previous_version + 1;
+            version_counters.insert(record_id.clone(), current_version);
+
+            match seen.get(&record_id) {
+                Some(&index) => versions[index].1 = current_version,
+                None => {
+                    seen.insert(record_id.clone(), changed.len());
+                    changed.push(record_id.clone());
+                    versions.push((previous_version, current_version));
+                }
+            }
+
+            // A write without a TTL of its own takes its namespace's default
+            let ttl = ttl.or_else(|| self.default_ttls.get(&record_id.namespace)).filter(|ttl| !ttl.is_zero());
+            let expires_at = ttl.filter(|_| value.is_some()).map(|ttl| now + ttl.as_millis() as u64);
+
+            // Write to storage (a missing value is a tombstone)
+            let RecordId { namespace, agent_id, key } = record_id;
+            let record = StateRecord {
+                namespace: namespace.clone(),
+                agent_id: agent_id.clone(),
+                key: key.clone(),
+                value: value.clone(),
+                version: current_version,
+                commit_ts,
+                deleted: value.is_none(),
+                namespace_ts: namespace_ts.get(&namespace).copied(),
+                purge_after: purge_after.filter(|_| value.is_none()),
+                labels: labels.clone(),
+                value_hash: value.as_ref().map(value_hash),
+                expires_at,
+            };
+            self.storage.write_state(record)?;
+
+            // Record operation
+            operation_records.push(OperationRecord {
+                namespace,
+                agent_id,
+                key,
+                value,
+                version: current_version,
+                labels,
+                expires_at,
+            });
+        }
+
+        // Append event to log
+        let event = EventLogEntry {
+            txn_id: txn_id.to_string(),
+            commit_ts,
+            operations: operation_records.clone(),
+            namespace_ts: namespace_ts.clone(),
+        };
+        self.storage.append_event(event.clone())?;
+
+        // Flush if needed
+        self.storage.sync_commit(durability)?;
+
+        // Still in the writer, so events are shipped in commit order
+        if let Some(sink) = &self.replication {
+            sink.ship(&event);
+        }
+
+        // Log successful commit
+        info!(
+            txn_id = %txn_id,
+            commit_ts = commit_ts,
+            operations = operation_records.len(),
+            "Transaction committed"
+        );
+
+        Ok(CommitResult { commit_ts, namespace_ts, changed, versions, get_or_create, coalesced: false })
+    }
+
+    /// Append tombstones for the records an eviction policy evicts to make
+    /// room for `mutations`: the least recently written ones the transaction
+    /// doesn't touch. Fails if the transaction alone adds more records than
+    /// the policy allows.
+    fn add_evictions(&self, mutations: &mut Vec<Mutation>) -> Result<()> {
+        if self.eviction.is_empty() {
+            return Ok(());
+        }
+
+        // Whether each record is live as of the mutations so far
+        let mut latest: HashMap<&RecordId, bool> = HashMap::new();
+        // Net change in live records per namespace
+        let mut added: BTreeMap<&str, i64> = BTreeMap::new();
+        for Mutation { record_id, value, .. } in mutations.iter() {
+            if self.eviction.get(&record_id.namespace).is_none() {
+                continue;
+            }
+            let previous = match latest.get(record_id) {
+                Some(previous) => *previous,
+                None => self.storage.read_state_meta(record_id)?.is_some_and(|meta| !meta.deleted),
+            };
+            latest.insert(record_id, value.is_some());
+            *added.entry(&record_id.namespace).or_default() += value.is_some() as i64 - previous as i64;
+        }
+
+        let mut evicted = Vec::new();
+        for (namespace, added) in added {
+            let Some(EvictionPolicy::Lru { max_keys }) = self.eviction.get(namespace) else { continue };
+            let records = self.storage.namespace_usage(namespace)?.records;
+            let excess = (records as i64 + added).saturating_sub(max_keys as i64);
+            if added <= 0 || excess <= 0 {
+                continue;
+            }
+
+            let touched = latest.keys().filter(|id| id.namespace == namespace).count();
+            let candidates = self.storage.least_recent_keys(namespace, excess as usize + touched)?;
+            let before = evicted.len();
+            evicted.extend(candidates.into_iter().filter(|id| !latest.contains_key(id)).take(excess as usize));
+            if ((evicted.len() - before) as i64) < excess {
+                return Err(StatehouseError::QuotaExceeded {
+                    namespace: namespace.to_string(),
+                    limit: format!("lru max_keys ({})", max_keys),
+                    usage: records,
+                    added: added as u64,
+                }.into());
+            }
+            debug!(namespace = %namespace, evicted = evicted.len() - before, "Evicting least recently written keys");
+        }
+
+        mutations.extend(evicted.into_iter().map(|record_id| Mutation::new(record_id, None)));
+        Ok(())
+    }
+
+    /// Fail if applying `mutations` would take a namespace past its quota.
+    /// Only dimensions a transaction grows are checked, so deletes and
+    /// shrinking writes always go through.
+    fn check_quotas(&self, mutations: &[Mutation]) -> Result<()> {
+        if self.quotas.is_empty() {
+            return Ok(());
+        }
+
+        // (live, value bytes) of each record as of the mutations so far
+        let mut latest: HashMap<&RecordId, (bool, u64)> = HashMap::new();
+        // Net (records, bytes) change per namespace
+        let mut deltas: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+        for Mutation { record_id, value, .. } in mutations {
+            if self.quotas.get(&record_id.namespace).is_none() {
+                continue;
+            }
+            let previous = match latest.get(record_id) {
+                Some(previous) => *previous,
+                None => self
+                    .storage
+                    .read_state_meta(record_id)?
+                    .filter(|meta| !meta.deleted)
+                    .map_or((false, 0), |meta| (true, meta.value_bytes)),
+            };
+            let current = value.as_ref().map_or((false, 0), |value| (true, json_size(value) as u64));
+            latest.insert(record_id, current);
+
+            let delta = deltas.entry(&record_id.namespace).or_default();
+            delta.0 += current.0 as i64 - previous.0 as i64;
+            delta.1 += current.1 as i64 - previous.1 as i64;
+        }
+
+        for (namespace, (records, bytes)) in deltas {
+            let Some(quota) = self.quotas.get(namespace) else { continue };
+            let usage = self.storage.namespace_usage(namespace)?;
+            let checks = [
+                ("max_records", quota.max_records, usage.records, records),
+                ("max_bytes", quota.max_bytes, usage.bytes, bytes),
+            ];
+            for (name, max, used, added) in checks {
+                let Some(max) = max else { continue };
+                if added > 0 && used + added as u64 > max {
+                    debug!(namespace = %namespace, limit = name, "Namespace quota exceeded");
+                    return Err(StatehouseError::QuotaExceeded {
+                        namespace: namespace.to_string(),
+                        limit: format!("{} ({})", name, max),
+                        usage: used,
+                        added: added as u64,
+                    }.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `stage` in a fresh transaction and commit it, retrying with
+    /// exponential backoff while the commit fails with a conflict. `stage`
+    /// receives the transaction id and should read whatever it depends on
+    /// each time, since a retry means that state changed. Any other error
+    /// aborts and is returned at once, as is the last conflict after
+    /// `max_attempts` tries.
+    pub fn with_retry<F>(&self, max_attempts: u32, mut stage: F) -> Result<CommitTs>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let mut backoff = RETRY_BASE_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let txn_id = self.begin_transaction(None)?;
+            let result = stage(&txn_id).and_then(|()| self.commit(&txn_id));
+            let e = match result {
+                Ok(result) => return Ok(result.commit_ts),
+                Err(e) => e,
+            };
+
+            // A failed commit already dropped the transaction; this covers staging errors
+            self.abort(&txn_id)?;
+            let conflict = matches!(e.downcast_ref::<StatehouseError>(), Some(StatehouseError::Conflict { .. }));
+            if !conflict || attempt >= max_attempts {
+                return Err(e);
+            }
+
+            debug!(attempt = attempt, backoff_ms = backoff.as_millis() as u64, "Commit conflict; retrying");
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(RETRY_MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    /// Abort a transaction (also ends a read transaction)
+    pub fn abort(&self, txn_id: &str) -> Result<()> {
+        self.send(Command::Abort { txn_id: txn_id.to_string() })?;
+        Ok(())
+    }
+
+    fn execute_abort(&self, state: &mut WriterState, txn_id: &str) -> Result<()> {
+        use tracing::debug;
+        
+        if let Some(txn) = state.transactions.remove(txn_id) {
+            if txn.prepared.is_some() {
+                self.finish(txn_id, None);
+            }
+            debug!(txn_id = %txn_id, "Transaction aborted");
+        }
+
+        let mut read_transactions = self.read_transactions.write().unwrap();
+        if read_transactions.remove(txn_id).is_some() {
+            debug!(txn_id = %txn_id, "Read transaction ended");
+        }
+        Ok(())
+    }
+
+    /// Open a `Session`: reads as of now plus the session's own writes,
+    /// which commit together
+    pub fn open_session(&self) -> Result<Session<'_>> {
+        Session::open(self)
+    }
+
+    /// Begin a read-only transaction pinned to the latest committed timestamp.
+    /// Returns the transaction ID and its read timestamp.
+    pub fn begin_read_transaction(&self, timeout_ms: Option<u64>) -> Result<(TxnId, CommitTs)> {
+        let txn_id = uuid::Uuid::new_v4().to_string();
+        let timeout = Duration::from_millis(timeout_ms.unwrap_or(30000));
+
+        // Asking the writer guarantees no commit is half-applied at read_ts
+        let read_ts = self.committed_ts()?;
+
+        let txn = ReadTransaction {
+            read_ts,
+            created_at: Instant::now(),
+            timeout,
+            cache: HashMap::new(),
+        };
+
+        let mut read_transactions = self.read_transactions.write().unwrap();
+        read_transactions.insert(txn_id.clone(), txn);
+
+        debug!(txn_id = %txn_id, read_ts = read_ts, "Read transaction started");
+        Ok((txn_id, read_ts))
+    }
+
+    /// Read several records as of a read transaction's timestamp.
+    /// Each result is the record at the greatest version with `commit_ts <= read_ts`,
+    /// or `None` if the key did not exist yet.
+    pub fn get_state_batch_in_snapshot(&self, read_txn_id: &str, refs: &[RecordId]) -> Result<Vec<Option<StateRecord>>> {
+        let (read_ts, mut results) = {
+            let mut read_transactions = self.read_transactions.write().unwrap();
+            let txn = read_transactions.get(read_txn_id).ok_or_else(|| anyhow!("Read transaction not found"))?;
+
+            if txn.created_at.elapsed() > txn.timeout {
+                read_transactions.remove(read_txn_id);
+                return Err(anyhow!("Transaction expired"));
+            }
+
+            let cached: Vec<Option<Option<StateRecord>>> = refs.iter().map(|r| txn.cache.get(r).cloned()).collect();
+            (txn.read_ts, cached)
+        };
+
+        let mut fetched = Vec::new();
+        for (record_id, slot) in refs.iter().zip(results.iter_mut()) {
+            if slot.is_none() {
+                let record = self.storage.read_state_as_of(record_id, read_ts)?;
+                fetched.push((record_id.clone(), record.clone()));
+                *slot = Some(record);
+            }
+        }
+
+        if !fetched.is_empty() {
+            let mut read_transactions = self.read_transactions.write().unwrap();
+            if let Some(txn) = read_transactions.get_mut(read_txn_id) {
+                txn.cache.extend(fetched);
+            }
+        }
+
+        Ok(results.into_iter().map(Option::flatten).collect())
+    }
+
+    /// Apply an event shipped from a replication primary, writing its records
+    /// with the primary's versions and commit_ts. Events at or below the local
+    /// commit timestamp were already applied and are skipped, so redelivery is
+    /// harmless. Returns whether the event was applied.
+    ///
+    /// A standby must not also take local commits; they would reuse commit
+    /// timestamps the primary hands out.
+    pub fn apply_replicated_event(&self, event: EventLogEntry) -> Result<bool> {
+        match self.send(Command::ApplyReplicated { event })? {
+            Reply::Applied(applied) => Ok(applied),
+            reply => unreachable!("Unexpected reply to ApplyReplicated: {:?}", reply),
+        }
+    }
+
+    fn execute_apply_replicated(&self, state: &mut WriterState, event: EventLogEntry) -> Result<bool> {
+        let version_counters = &mut state.version_counters;
+        if event.commit_ts <= self.storage.current_commit_ts()? {
+            return Ok(false);
+        }
+
+        // The standby keeps tombstones for its own grace period
+        let purge_after = self.purge_after();
+        for op in &event.operations {
+            let record = StateRecord {
+                namespace: op.namespace.clone(),
+                agent_id: op.agent_id.clone(),
+                key: op.key.clone(),
+                value: op.value.clone(),
+                version: op.version,
+                commit_ts: event.commit_ts,
+                deleted: op.value.is_none(),
+                namespace_ts: event.namespace_ts.get(&op.namespace).copied(),
+                purge_after: purge_after.filter(|_| op.value.is_none()),
+                labels: op.labels.clone(),
+                value_hash: op.value.as_ref().map(value_hash),
+                expires_at: op.expires_at,
+            };
+            version_counters.insert(
+                RecordId::new(op.namespace.clone(), op.agent_id.clone(), op.key.clone()),
+                op.version,
+            );
+            self.storage.write_state(record)?;
+        }
+
+        for (namespace, namespace_ts) in &event.namespace_ts {
+            self.storage.advance_namespace_ts(namespace, *namespace_ts)?;
+        }
+        let commit_ts = event.commit_ts;
+        self.storage.append_event(event)?;
+        self.storage.advance_commit_ts(commit_ts)?;
+        self.storage.flush()?;
+
+        debug!(commit_ts = commit_ts, "Applied replicated event");
+        Ok(true)
+    }
+
+    /// Import records with their own `commit_ts` and `version` rather than
+    /// server-assigned ones, for loading data from another system or
+    /// building deterministic fixtures. Commits never take a client-supplied
+    /// timestamp; this is the only way to write one.
+    ///
+    /// Records may arrive in any commit_ts order, but each must be newer in
+    /// both version and commit_ts than what its key already has
+    /// (`StatehouseError::ImportOutOfOrder` otherwise, before anything is
+    /// written). Records already stored with the same version and
+    /// commit_ts are skipped, so an interrupted import can be rerun. Each
+    /// record's operation is added to the event at its commit_ts, so replay
+    /// sees it in order. Afterwards the commit timestamp counter and the
+    /// version counters are raised to the highest values imported.
+    pub fn import_records(&self, records: Vec<StateRecord>) -> Result<ImportStats> {
+        match self.send(Command::Import { records })? {
+            Reply::Imported(stats) => Ok(stats),
+            reply => unreachable!("Unexpected reply to Import: {:?}", reply),
+        }
+    }
+
+    fn execute_import(&self, state: &mut WriterState, mut records: Vec<StateRecord>) -> Result<ImportStats> {
+        let version_counters = &mut state.version_counters;
+        records.sort_by_key(|r| (r.commit_ts, r.version));
+
+        let mut stats = ImportStats::default();
+        let mut latest: HashMap<RecordId, (Version, CommitTs)> = HashMap::new();
+        let mut to_write = Vec::with_capacity(records.len());
+        for mut record in records {
+            if record.version == 0 || record.commit_ts == 0 {
+                return Err(anyhow!("Imported records need a version and commit_ts of at least 1"));
+            }
+            let record_id = RecordId::new(record.namespace.clone(), record.agent_id.clone(), record.key.clone());
+            if self.storage.read_state_at_version(&record_id, record.version)?.is_some_and(|r| r.commit_ts == record.commit_ts) {
+                stats.records_skipped += 1;
+                continue;
+            }
+            let (current_version, current_commit_ts) = match latest.get(&record_id) {
+                Some(current) => *current,
+                None => self.storage.read_state_meta(&record_id)?.map_or((0, 0), |meta| (meta.version, meta.commit_ts)),
+            };
+            if record.version <= current_version || record.commit_ts <= current_commit_ts {
+                return Err(StatehouseError::ImportOutOfOrder {
+                    namespace: record_id.namespace,
+                    agent_id: record_id.agent_id,
+                    key: record_id.key,
+                    version: record.version,
+                    commit_ts: record.commit_ts,
+                    current_version,
+                    current_commit_ts,
+                }.into());
+            }
+            latest.insert(record_id, (record.version, record.commit_ts));
+            // Hashed here whatever the source sent, like any other write
+            record.value_hash = record.value.as_ref().map(value_hash);
+            to_write.push(record);
+        }
+
+        // Records are sorted, so each commit_ts's records are contiguous
+        for group in to_write.chunk_by(|a, b| a.commit_ts == b.commit_ts) {
+            let commit_ts = group[0].commit_ts;
+            let mut event = self.storage.read_event(commit_ts)?.unwrap_or_else(|| EventLogEntry {
+                txn_id: uuid::Uuid::new_v4().to_string(),
+                commit_ts,
+                operations: Vec::new(),
+                namespace_ts: BTreeMap::new(),
+            });
+            for record in group {
+                event.operations.push(OperationRecord {
+                    namespace: record.namespace.clone(),
+                    agent_id: record.agent_id.clone(),
+                    key: record.key.clone(),
+                    value: if record.deleted { None } else { record.value.clone() },
+                    version: record.version,
+                    labels: record.labels.clone(),
+                    expires_at: record.expires_at,
+                });
+                self.storage.write_state(record.clone())?;
+            }
+            // Written after its records, as a commit does
+            self.storage.append_event(event)?;
+            stats.records_imported += group.len() as u64;
+            stats.events_written += 1;
+            stats.max_commit_ts = commit_ts;
+        }
+
+        for (record_id, (version, _)) in latest {
+            let counter = version_counters.entry(record_id).or_insert(0);
+            *counter = (*counter).max(version);
+        }
+        self.storage.advance_commit_ts(stats.max_commit_ts)?;
+        // Imported events may sit in the unused part of the current block
+        *self.commit_ts_block.lock().unwrap() = None;
+        self.storage.flush()?;
+
+        info!(
+            imported = stats.records_imported,
+            skipped = stats.records_skipped,
+            max_commit_ts = stats.max_commit_ts,
+            "Imported records with their own commit timestamps"
+        );
+        Ok(stats)
+    }
+
+    /// Read latest state. A deleted key returns its tombstone record
+    /// (`deleted` set); a key that was never written returns `None`.
+    pub fn get_state(&self, namespace: &str, agent_id: &str, key: &str) -> Result<Option<StateRecord>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        self.storage.read_state(&record_id)
+    }
+
+    /// Whether a key exists and is not deleted. Cheaper than `get_state`
+    /// because the value is never read.
+    pub fn exists(&self, namespace: &str, agent_id: &str, key: &str) -> Result<bool> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        self.storage.exists(&record_id)
+    }
+
+    /// Version, commit timestamp, deleted flag and value size of a key's
+    /// latest state, without reading the value where storage allows
+    pub fn get_state_meta(&self, namespace: &str, agent_id: &str, key: &str) -> Result<Option<StateMeta>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        self.storage.read_state_meta(&record_id)
+    }
+
+    /// Read many keys in one storage round trip, split into the live records
+    /// and the keys that are absent or deleted, so a caller warming a cache
+    /// needn't sift through placeholders
+    pub fn get_existing_batch(&self, refs: &[RecordId]) -> Result<ExistingBatch> {
+        let mut batch = ExistingBatch::default();
+        for (record_id, record) in refs.iter().zip(self.storage.read_states(refs)?) {
+            match record.filter(|r| !r.deleted) {
+                Some(record) => batch.found.push(record),
+                None => batch.missing.push(record_id.clone()),
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Read state at specific version. `None` if the key never reached that
+    /// version; `StatehouseError::VersionCompacted` if compaction removed it.
+    pub fn get_state_at_version(&self, namespace: &str, agent_id: &str, key: &str, version: Version) -> Result<Option<StateRecord>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        if let Some(record) = self.storage.read_state_at_version(&record_id, version)? {
+            return Ok(Some(record));
+        }
+        match self.storage.version_bounds(&record_id)? {
+            Some((min_version, _)) if version < min_version => Err(StatehouseError::VersionCompacted {
+                namespace: record_id.namespace,
+                agent_id: record_id.agent_id,
+                key: record_id.key,
+                version,
+                min_version,
+            }.into()),
+            _ => Ok(None),
+        }
+    }
+
+    /// What changed in a key's value from `from_version` to `to_version`
+    /// (see `diff::diff`). A deleted version, or version 0 for before the
+    /// key was first written, counts as no value, so the other side is
+    /// reported whole as added or removed. Fails with `VersionNotFound` for
+    /// a version the key never reached and `VersionCompacted` for one that
+    /// was compacted.
+    pub fn diff_versions(&self, namespace: &str, agent_id: &str, key: &str, from_version: Version, to_version: Version) -> Result<Vec<ValueChange>> {
+        let value_at = |version: Version| -> Result<Option<serde_json::Value>> {
+            if version == 0 {
+                return Ok(None);
+            }
+            let record = self.get_state_at_version(namespace, agent_id, key, version)?.ok_or_else(|| StatehouseError::VersionNotFound {
+                namespace: namespace.to_string(),
+                agent_id: agent_id.to_string(),
+                key: key.to_string(),
+                version,
+            })?;
+            Ok(record.value)
+        };
+        let before = value_at(from_version)?;
+        let after = value_at(to_version)?;
+        Ok(diff::diff(before.as_ref(), after.as_ref()))
+    }
+
+    /// Infer the shape of an agent's values (see `crate::schema`) from its
+    /// first `sample_size` live keys in key order, at most
+    /// `MAX_SCHEMA_SAMPLE`. Only the sampled records are read.
+    pub fn infer_schema(&self, namespace: &str, agent_id: &str, sample_size: usize) -> Result<InferredSchema> {
+        let records = self.storage.scan_prefix_page(namespace, agent_id, "", false, None, sample_size.min(MAX_SCHEMA_SAMPLE))?;
+        Ok(InferredSchema::infer(records.iter().filter_map(|record| record.value.as_ref())))
+    }
+
+    /// Oldest and newest version still stored for a key, `None` if it has
+    /// none. Versions between them can be read with `get_state_at_version`.
+    pub fn version_bounds(&self, namespace: &str, agent_id: &str, key: &str) -> Result<Option<(Version, Version)>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        self.storage.version_bounds(&record_id)
+    }
+
+    /// Read the latest `limit` versions of a key, newest first.
+    /// Empty if the key was never written.
+    pub fn get_version_history(&self, namespace: &str, agent_id: &str, key: &str, limit: usize) -> Result<Vec<StateRecord>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        self.storage.read_version_history(&record_id, limit)
+    }
+
+    /// Read state as of a commit timestamp
+    pub fn get_state_as_of(&self, namespace: &str, agent_id: &str, key: &str, as_of: CommitTs) -> Result<Option<StateRecord>> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        self.storage.read_state_as_of(&record_id, as_of)
+    }
+
+    /// List keys for an agent, including deleted ones if `include_deleted`,
+    /// giving up with `DeadlineExceeded` once `deadline` passes
+    pub fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> {
+        self.storage.list_keys(namespace, agent_id, include_deleted, deadline)
+    }
+
+    /// Number of an agent's live keys, optionally only those starting with
+    /// `prefix`. Deleted keys aren't counted.
+    pub fn count_keys(&self, namespace: &str, agent_id: &str, prefix: Option<&str>) -> Result<u64> {
+        self.storage.count_keys(namespace, agent_id, prefix.unwrap_or(""))
+    }
+
+    /// commit_ts of the latest commit that wrote or deleted one of an
+    /// agent's keys, `None` if none has. A client that saw this value last
+    /// time has nothing new to sync for the agent; it is kept per agent at
+    /// commit, so asking doesn't scan.
+    pub fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> {
+        self.storage.agent_last_commit_ts(namespace, agent_id)
+    }
+
+    /// List keys that were live for an agent as of `as_of_ts`, sorted.
+    /// Reconstructed by folding the agent's events up to `as_of_ts`, so
+    /// records restored from a snapshot without events are not included.
+    pub fn list_keys_at(&self, namespace: &str, agent_id: &str, as_of_ts: CommitTs, deadline: Deadline) -> Result<Vec<String>> {
+        let mut live: BTreeMap<Key, bool> = BTreeMap::new();
+
+        for event in self.storage.replay_events(namespace, agent_id, None, Some(as_of_ts), deadline)? {
+            for op in event.operations {
+                if op.namespace == namespace && op.agent_id == agent_id {
+                    live.insert(op.key, op.value.is_some());
+                }
+            }
+        }
+
+        Ok(live.into_iter().filter(|(_, is_live)| *is_live).map(|(key, _)| key).collect())
+    }
+
+    /// Scan keys with prefix
+    pub fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<Vec<StateRecord>> {
+        self.storage.scan_prefix(namespace, agent_id, prefix, false, Deadline::NONE)
+    }
+
+    /// Scan keys with prefix whose latest version has every label in
+    /// `labels` (an empty filter matches everything), giving up with
+    /// `DeadlineExceeded` once `deadline` passes. Tombstones are returned,
+    /// with `deleted` set, only if `include_deleted`.
+    pub fn scan_prefix_with_labels(&self, namespace: &str, agent_id: &str, prefix: &str, labels: &Labels, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        let mut records = self.storage.scan_prefix(namespace, agent_id, prefix, include_deleted, deadline)?;
+        records.retain(|record| record.has_labels(labels));
+        Ok(records)
+    }
+
+    /// `scan_prefix_with_labels` with every record read as of one point in
+    /// time, so the records a commit wrote together are all in their new
+    /// state or all in their old one. The plain scan reads each record as it
+    /// reaches it, so a commit landing mid-scan may show in only some.
+    pub fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, labels: &Labels, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        let mut records = self.storage.scan_prefix_consistent(namespace, agent_id, prefix, include_deleted, deadline)?;
+        records.retain(|record| record.has_labels(labels));
+        Ok(records)
+    }
+
+    /// `scan_prefix_with_labels` a page at a time: at most `limit` (at least
+    /// one) matching records whose key sorts after `start_after`, in key
+    /// order. Storage is
+    /// read `SCAN_PAGE_SIZE` records at a time, so a small page doesn't
+    /// iterate the whole prefix. Each page reflects the latest state when it
+    /// is read.
+    #[allow(clippy::too_many_arguments)]
+    pub fn scan_prefix_page(
+        &self,
+        namespace: &str,
+        agent_id: &str,
+        prefix: &str,
+        labels: &Labels,
+        include_deleted: bool,
+        start_after: Option<&str>,
+        limit: usize,
+        deadline: Deadline,
+    ) -> Result<ScanPage> {
+        let limit = limit.max(1);
+        let mut records: Vec<StateRecord> = Vec::new();
+        let mut cursor = start_after.map(str::to_string);
+        loop {
+            deadline.check()?;
+            let page = self.storage.scan_prefix_page(namespace, agent_id, prefix, include_deleted, cursor.as_deref(), SCAN_PAGE_SIZE)?;
+            let exhausted = page.len() < SCAN_PAGE_SIZE;
+            for record in page {
+                cursor = Some(record.key.clone());
+                if !record.has_labels(labels) {
+                    continue;
+                }
+                // Only continue from here if something else matches
+                if records.len() == limit {
+                    let next_cursor = records.last().map(|last| last.key.clone());
+                    return Ok(ScanPage { records, next_cursor });
+                }
+                records.push(record);
+            }
+            if exhausted {
+                return Ok(ScanPage { records, next_cursor: None });
+            }
+        }
+    }
+
+    /// Scan keys with prefix lazily, in key order, starting after the key
+    /// `start_after` if given so an interrupted scan can resume
+    pub fn scan_prefix_iter(&self, namespace: &str, agent_id: &str, prefix: &str, start_after: Option<Key>) -> ScanPrefixIter {
+        ScanPrefixIter {
+            storage: self.storage.clone(),
+            namespace: namespace.to_string(),
+            agent_id: agent_id.to_string(),
+            prefix: prefix.to_string(),
+            cursor: start_after,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Scan keys with prefix across every agent in a namespace, ordered by
+    /// (agent_id, key) and capped at `limit` records
+    pub fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> {
+        self.storage.scan_namespace_prefix(namespace, prefix, limit)
+    }
+
+    /// The whole event committed at `commit_ts`, every agent's operations
+    /// included; `None` if nothing committed then or compaction removed it
+    pub fn get_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> {
+        self.storage.read_event(commit_ts)
+    }
+
+    /// Page through the log of committed transactions across every
+    /// namespace and agent, for consumers building their own read models.
+    /// An event's commit_ts is its offset: offsets only grow, and a consumer
+    /// that stores `next_offset` and reads from it after a restart resumes
+    /// with the first transaction it hasn't seen. At most `limit` events
+    /// (capped at `MAX_TRANSACTION_LOG_PAGE`, which 0 also means) are
+    /// returned. Events trimmed by compaction are skipped.
+    pub fn transaction_log(&self, from_offset: CommitTs, limit: usize) -> Result<TransactionLogPage> {
+        let limit = if limit == 0 { MAX_TRANSACTION_LOG_PAGE } else { limit.min(MAX_TRANSACTION_LOG_PAGE) };
+        let events = self.storage.read_events(from_offset, limit)?;
+        let next_offset = events.last().map_or(from_offset, |event| event.commit_ts + 1);
+        Ok(TransactionLogPage { events, next_offset })
+    }
+
+    /// Replay events for an agent. With per-namespace sequences the bounds
+    /// are namespace timestamps, and events from before the sequence was
+    /// enabled count as 0.
+    pub fn replay(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>) -> Result<Vec<EventLogEntry>> {
+        self.replay_filtered(namespace, agent_id, start_ts, end_ts, &ReplayFilter::default(), Deadline::NONE)
+    }
+
+    /// `replay`, keeping only the agent's operations that match `filter`.
+    /// Events left with no operations are dropped. An event written by a
+    /// transaction spanning several agents loses the other agents'
+    /// operations unless the filter matches everything. Gives up with
+    /// `DeadlineExceeded` once `deadline` passes.
+    pub fn replay_filtered(
+        &self,
+        namespace: &str,
+        agent_id: &str,
+        start_ts: Option<CommitTs>,
+        end_ts: Option<CommitTs>,
+        filter: &ReplayFilter,
+        deadline: Deadline,
+    ) -> Result<Vec<EventLogEntry>> {
+        // A resumed replay starts just past the last event received. With
+        // per-namespace sequences the bounds are namespace timestamps, so the
+        // token's event is looked up for its own.
+        let resume_start = match filter.resume_after {
+            None => None,
+            Some(token) if self.namespace_sequences => self
+                .storage
+                .read_event(token)?
+                .and_then(|event| event.namespace_ts.get(namespace).copied())
+                .map(|namespace_ts| namespace_ts + 1),
+            Some(token) => Some(token.saturating_add(1)),
+        };
+        let start_ts = self.bound_replay(namespace, start_ts.max(resume_start), end_ts)?;
+
+        info!(
+            namespace = %namespace,
+            agent_id = %agent_id,
+            start_ts = ?start_ts,
+            end_ts = ?end_ts,
+            "Replay started"
+        );
+
+        let mut events: Vec<EventLogEntry> = if self.namespace_sequences {
+            let in_range = |ts: CommitTs| start_ts.is_none_or(|start| ts >= start) && end_ts.is_none_or(|end| ts <= end);
+            self.storage
+                .replay_events(namespace, agent_id, None, None, deadline)?
+                .into_iter()
+                .filter(|event| in_range(event.namespace_ts.get(namespace).copied().unwrap_or(0)))
+                .collect()
+        } else {
+            self.storage.replay_events(namespace, agent_id, start_ts, end_ts, deadline)?
+        };
+        if let Some(token) = filter.resume_after {
+            events.retain(|event| event.commit_ts > token);
+        }
+        let events: Vec<EventLogEntry> = if filter.matches_all() {
+            events
+        } else {
+            events
+                .into_iter()
+                .filter_map(|mut event| {
+                    event.operations.retain(|op| op.namespace == namespace && op.agent_id == agent_id && filter.matches(op));
+                    (!event.operations.is_empty()).then_some(event)
+                })
+                .collect()
+        };
+        let event_count = events.len();
+
+        info!(
+            namespace = %namespace,
+            agent_id = %agent_id,
+            event_count = event_count,
+            "Replay completed"
+        );
+
+        Ok(events)
+    }
+
+    /// Check a replay range against `ReplayLimits`, returning the start to
+    /// use: the requested one, or for a clamped unbounded request the first
+    /// of the last `max_span` timestamps. An open end counts up to the
+    /// newest timestamp issued.
+    fn bound_replay(&self, namespace: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>) -> Result<Option<CommitTs>> {
+        let Some(max_span) = self.replay_limits.max_span else {
+            return Ok(start_ts);
+        };
+
+        let latest = if self.namespace_sequences {
+            self.storage.current_namespace_ts(namespace)?
+        } else {
+            self.current_commit_ts()?
+        };
+        let end = end_ts.map_or(latest, |end| end.min(latest));
+        let span = (end + 1).saturating_sub(start_ts.unwrap_or(0));
+        if span <= max_span {
+            return Ok(start_ts);
+        }
+
+        if start_ts.is_none() && self.replay_limits.clamp_unbounded {
+            let clamped = end + 1 - max_span;
+            debug!(namespace = %namespace, start_ts = clamped, "Unbounded replay clamped to max_replay_span");
+            return Ok(Some(clamped));
+        }
+        Err(StatehouseError::ReplaySpanTooLarge { requested: span, limit: max_span }.into())
+    }
+
+    /// Flush commits acknowledged with `Durability::Async` (should be called
+    /// periodically)
+    pub fn flush_deferred(&self) -> Result<()> {
+        self.storage.flush_deferred()
+    }
+
+    /// Durability barrier: flush storage now, so every commit acknowledged
+    /// before the call survives a crash, `Durability::Async` ones included.
+    /// Returns the newest commit the flush covers, 0 if there is none.
+    pub fn sync(&self) -> Result<CommitTs> {
+        // Asking the writer guarantees no commit is half-written, so every
+        // commit up to the newest event is whole when the flush starts
+        let durable_ts = match self.send(Command::LastEventTs)? {
+            Reply::CommitTs(commit_ts) => commit_ts,
+            reply => unreachable!("Unexpected reply to LastEventTs: {:?}", reply),
+        };
+        self.storage.flush()?;
+        debug!(commit_ts = durable_ts, "Synced storage");
+        Ok(durable_ts)
+    }
+
+    /// Cleanup expired transactions (should be called periodically)
+    pub fn cleanup_expired_transactions(&self) {
+        if let Err(e) = self.send(Command::CleanupExpired) {
+            warn!(error = %e, "Failed to clean up expired transactions");
+        }
+    }
+
+    fn execute_cleanup(&self, state: &mut WriterState) {
+        state.transactions.retain(|txn_id, txn| {
+            let expired = txn.expired();
+            if expired && txn.prepared.is_some() {
+                self.finish(txn_id, None);
+            }
+            !expired
+        });
+        self.finished.lock().unwrap().retain(|_, finished| finished.at.elapsed() <= FINISHED_COMMIT_RETENTION);
+
+        let mut read_transactions = self.read_transactions.write().unwrap();
+        read_transactions.retain(|_, txn| txn.created_at.elapsed() <= txn.timeout);
+    }
+
+    /// Expiry sweep: delete every key whose TTL has run out, in one commit,
+    /// returning how many. Should be called periodically; a key expires at
+    /// the first sweep after its time, not at the moment it passes.
+    pub fn expire_keys(&self) -> Result<usize> {
+        match self.send(Command::ExpireKeys)? {
+            Reply::Expired(keys) => Ok(keys),
+            reply => unreachable!("Unexpected reply to ExpireKeys: {:?}", reply),
+        }
+    }
+
+    fn execute_expire_keys(&self, state: &mut WriterState) -> Result<usize> {
+        let now = unix_now_ms();
+        let mut expired = self.storage.list_expired(now)?;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        // A buffered coalesced write was acknowledged after the value that
+        // expired, so it lands first and the key is only deleted if its
+        // value still expires
+        if !self.coalesce.is_empty() {
+            let candidates: HashSet<RecordId> = expired.iter().cloned().collect();
+            self.commit_coalesced(&mut state.version_counters, |record_id, _| candidates.contains(record_id))?;
+            let mut still_expired = Vec::with_capacity(expired.len());
+            for record_id in expired {
+                if self.storage.read_state_meta(&record_id)?.is_some_and(|meta| meta.expired(now)) {
+                    still_expired.push(record_id);
+                }
+            }
+            expired = still_expired;
+        }
+
+        let keys = expired.len();
+        if keys == 0 {
+            return Ok(0);
+        }
+        let operations = expired
+            .into_iter()
+            .map(|RecordId { namespace, agent_id, key }| StagedOperation::Delete { namespace, agent_id, key })
+            .collect();
+        let result = self.apply(&mut state.version_counters, &uuid::Uuid::new_v4().to_string(), operations, None)?;
+        info!(keys, commit_ts = result.commit_ts, "Expired keys deleted");
+        Ok(keys)
+    }
+
+    /// Check the invariants recovery relies on: every record's latest state
+    /// is its newest stored version, the commit timestamp counter is at or
+    /// past every event, and each version counter matches its record's
+    /// stored version. Reads all state in the writer, so commits wait until
+    /// it finishes; meant for startup and maintenance.
+    pub fn consistency_check(&self) -> Result<ConsistencyReport> {
+        match self.send(Command::ConsistencyCheck)? {
+            Reply::Consistency(report) => Ok(report),
+            reply => unreachable!("Unexpected reply to ConsistencyCheck: {:?}", reply),
+        }
+    }
+
+    fn execute_consistency_check(&self, state: &WriterState) -> Result<ConsistencyReport> {
+        let mut report = ConsistencyReport::default();
+
+        for record in self.storage.get_all_state()? {
+            let record_id = RecordId::new(record.namespace, record.agent_id, record.key);
+            let max_stored_version = self.storage.version_bounds(&record_id)?.map(|(_, max)| max);
+            if max_stored_version != Some(record.version) {
+                report.violations.push(ConsistencyViolation::StateVersionMismatch {
+                    record_id,
+                    state_version: record.version,
+                    max_stored_version,
+                });
+            }
+            report.records_checked += 1;
+        }
+
+        let commit_ts = self.storage.current_commit_ts()?;
+        if let Some(last_event_ts) = self.storage.last_event_ts()?.filter(|&ts| ts > commit_ts) {
+            report.violations.push(ConsistencyViolation::CommitTsBehindEvents { commit_ts, last_event_ts });
+        }
+
+        for (record_id, &counter) in state.version_counters.iter() {
+            let stored_version = self.storage.read_state_meta(record_id)?.map_or(0, |meta| meta.version);
+            if counter != stored_version {
+                report.violations.push(ConsistencyViolation::VersionCounterMismatch {
+                    record_id: record_id.clone(),
+                    counter,
+                    stored_version,
+                });
+            }
+            report.counters_checked += 1;
+        }
+
+        if report.is_consistent() {
+            info!(records = report.records_checked, counters = report.counters_checked, "Consistency check passed");
+        } else {
+            warn!(violations = report.violations.len(), "Consistency check failed");
+        }
+        Ok(report)
+    }
+
+    /// List open transactions, oldest first, capped at `MAX_LISTED_TRANSACTIONS`.
+    /// This is a point-in-time snapshot: transactions may commit or expire
+    /// as soon as the writer moves on.
+    pub fn list_open_transactions(&self) -> Vec<TxnSummary> {
+        match self.send(Command::ListTransactions) {
+            Ok(Reply::Transactions(summaries)) => summaries,
+            reply => unreachable!("Unexpected reply to ListTransactions: {:?}", reply),
+        }
+    }
+
+    fn execute_list_transactions(&self, state: &WriterState) -> Vec<TxnSummary> {
+        let mut summaries: Vec<TxnSummary> = state
+            .transactions
+            .values()
+            .map(|txn| TxnSummary {
+                txn_id: txn.txn_id.clone(),
+                age: txn.created_at.elapsed(),
+                timeout: txn.timeout,
+                staged_ops: txn.operations.len(),
+                agents: txn
+                    .operations
+                    .iter()
+                    .map(|op| {
+                        let (namespace, agent_id) = op.scope();
+                        (namespace.clone(), agent_id.clone())
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        summaries.sort_by_key(|t| std::cmp::Reverse(t.age));
+        summaries.truncate(MAX_LISTED_TRANSACTIONS);
+        summaries
+    }
+
+    /// Compute the age distribution of open transactions and warn about any
+    /// that have been open longer than `warn_fraction` of their timeout,
+    /// once per transaction however often it is called.
+    /// Meant to be called periodically alongside `cleanup_expired_transactions`.
+    pub fn monitor_transactions(&self, warn_fraction: f64) -> TransactionAgeReport {
+        let mut report = match self.send(Command::MonitorTransactions { warn_fraction }) {
+            Ok(Reply::Ages(report)) => report,
+            reply => unreachable!("Unexpected reply to MonitorTransactions: {:?}", reply),
+        };
+
+        // Forget transactions no longer stuck (committed, aborted or expired)
+        let mut warned_stuck = self.warned_stuck.lock().unwrap();
+        warned_stuck.retain(|txn_id| report.stuck.iter().any(|stuck| &stuck.txn_id == txn_id));
+        for stuck in &report.stuck {
+            if !warned_stuck.insert(stuck.txn_id.clone()) {
+                continue;
+            }
+            report.warned += 1;
+            warn!(
+                txn_id = %stuck.txn_id,
+                age_ms = stuck.age.as_millis() as u64,
+                timeout_ms = stuck.timeout.as_millis() as u64,
+                staged_ops = stuck.staged_ops,
+                "Transaction open for most of its timeout; client may have leaked it"
+            );
+        }
+        drop(warned_stuck);
+
+        debug!(
+            open = report.open,
+            buckets = ?report.buckets,
+            oldest_ms = report.oldest.map(|age| age.as_millis() as u64),
+            "Open transaction ages"
+        );
+
+        report
+    }
+
+    fn execute_monitor(&self, state: &WriterState, warn_fraction: f64) -> TransactionAgeReport {
+        let transactions = &state.transactions;
+        let mut report = TransactionAgeReport {
+            open: transactions.len(),
+            ..Default::default()
+        };
+
+        for txn in transactions.values() {
+            let age = txn.created_at.elapsed();
+            let age_ms = age.as_millis() as u64;
+            let bucket = TXN_AGE_BUCKETS_MS
+                .iter()
+                .position(|bound| age_ms < *bound)
+                .unwrap_or(TXN_AGE_BUCKETS_MS.len());
+            report.buckets[bucket] += 1;
+            report.oldest = report.oldest.max(Some(age));
+
+            if age.as_secs_f64() >= txn.timeout.as_secs_f64() * warn_fraction {
+                report.stuck.push(StuckTransaction {
+                    txn_id: txn.txn_id.clone(),
+                    age,
+                    timeout: txn.timeout,
+                    staged_ops: txn.operations.len(),
+                });
+            }
+        }
+        report
+    }
+
+    /// Create and save a snapshot of current state. Waits for a snapshot
+    /// already in progress to finish first.
+    pub fn create_snapshot(&self) -> Result<SnapshotMetadata> {
+        let _snapshotting = self.snapshotting.lock().unwrap();
+        self.snapshot_locked()
+    }
+
+    /// Like `create_snapshot`, but returns `None` straight away if another
+    /// snapshot is in progress, for background schedulers
+    pub fn try_snapshot(&self) -> Result<Option<SnapshotMetadata>> {
+        let Ok(_snapshotting) = self.snapshotting.try_lock() else {
+            return Ok(None);
+        };
+        self.snapshot_locked().map(Some)
+    }
+
+    /// Time since the last snapshot was saved (or since startup, if none was)
+    pub fn since_last_snapshot(&self) -> Duration {
+        self.last_snapshot.lock().unwrap().elapsed()
+    }
+
+    /// Caller holds `snapshotting`
+    fn snapshot_locked(&self) -> Result<SnapshotMetadata> {
+        // The writer only answers between commits, so this pins a point with
+        // no half-applied commit. The scan itself runs outside the writer and
+        // doesn't block commits.
+        let snapshot_ts = self.committed_ts()?;
+        let snapshot = self.storage.create_snapshot_at(snapshot_ts)?;
+        self.storage.save_snapshot(&snapshot)?;
+        
+        // Reset counter after successful snapshot
+        let mut counter = self.commits_since_snapshot.write().unwrap();
+        *counter = 0;
+        *self.last_snapshot.lock().unwrap() = Instant::now();
+        
+        Ok(snapshot.metadata)
+    }
+
+    /// Snapshot current state, then drop what the snapshot makes redundant:
+    /// old versions beyond the newest `keep_versions` per key, tombstoned keys
+    /// past their delete grace period, and the event log up to the snapshot. History and replay before the
+    /// snapshot are no longer available afterwards.
+    ///
+    /// Each removal step runs as its own command in the writer, so commits
+    /// queue behind a step but not behind the snapshot scan.
+    pub fn compact(&self, keep_versions: usize) -> Result<CompactionStats> {
+        let snapshot = self.create_snapshot()?;
+        let mut stats = self.gc_versions(snapshot.snapshot_ts, keep_versions)?;
+        stats.merge(&self.gc_tombstones(snapshot.snapshot_ts)?);
+        stats.merge(&self.trim_events(snapshot.snapshot_ts)?);
+
+        info!(
+            snapshot_ts = stats.snapshot_ts,
+            versions_removed = stats.versions_removed,
+            tombstones_removed = stats.tombstones_removed,
+            events_removed = stats.events_removed,
+            bytes_reclaimed = stats.bytes_reclaimed,
+            "Compaction complete"
+        );
+
+        Ok(stats)
+    }
+
+    /// Run the maintenance steps `opts` enables, in an order that is safe to
+    /// stop after any step: snapshot, version GC, tombstone GC, log trim,
+    /// then a consistency check of the result. GC and trimming only touch
+    /// what is at or before the snapshot, so the log is never trimmed past a
+    /// snapshot that could rebuild what it held; without the snapshot step
+    /// they are anchored to the newest commit and trimming is refused.
+    /// Progress is logged as each step starts and finishes.
+    pub fn run_maintenance(&self, opts: &MaintenanceOpts) -> Result<MaintenanceReport> {
+        if opts.trim_log && !opts.snapshot {
+            return Err(anyhow!("Trimming the log needs the snapshot step, so the trimmed events are covered by a snapshot"));
+        }
+
+        let steps = opts.steps();
+        let mut report = MaintenanceReport::default();
+        let started = Instant::now();
+        let mut anchor_ts = None;
+        for (index, step) in steps.iter().enumerate() {
+            info!(step = step.name(), progress = %format!("{}/{}", index + 1, steps.len()), "Maintenance step started");
+            let step_started = Instant::now();
+            match step {
+                MaintenanceStep::Snapshot => {
+                    let metadata = self.create_snapshot()?;
+                    anchor_ts = Some(metadata.snapshot_ts);
+                    report.snapshot = Some(metadata);
+                }
+                MaintenanceStep::VersionGc => {
+                    let up_to_ts = self.maintenance_anchor(&mut anchor_ts)?;
+                    report.version_gc = Some(self.gc_versions(up_to_ts, opts.keep_versions)?);
+                }
+                MaintenanceStep::TombstoneGc => {
+                    let up_to_ts = self.maintenance_anchor(&mut anchor_ts)?;
+                    report.tombstone_gc = Some(self.gc_tombstones(up_to_ts)?);
+                }
+                MaintenanceStep::TrimLog => {
+                    let up_to_ts = self.maintenance_anchor(&mut anchor_ts)?;
+                    report.trim_log = Some(self.trim_events(up_to_ts)?);
+                }
+                MaintenanceStep::ConsistencyCheck => {
+                    report.consistency = Some(self.consistency_check()?);
+                }
+            }
+            info!(step = step.name(), elapsed_ms = step_started.elapsed().as_millis() as u64, "Maintenance step finished");
+        }
+
+        let totals = report.totals();
+        info!(
+            steps = steps.len(),
+            versions_removed = totals.versions_removed,
+            tombstones_removed = totals.tombstones_removed,
+            events_removed = totals.events_removed,
+            bytes_reclaimed = totals.bytes_reclaimed,
+            violations = report.consistency.as_ref().map_or(0, |c| c.violations.len()),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Maintenance complete"
+        );
+
+        Ok(report)
+    }
+
+    /// The commit_ts maintenance GC works up to: the snapshot's, or without
+    /// one the newest fully applied commit, fixed at first use
+    fn maintenance_anchor(&self, anchor_ts: &mut Option<CommitTs>) -> Result<CommitTs> {
+        if let Some(anchor_ts) = anchor_ts {
+            return Ok(*anchor_ts);
+        }
+        Ok(*anchor_ts.insert(self.committed_ts()?))
+    }
+
+    /// Drop versions at or before `up_to_ts` beyond the newest
+    /// `keep_versions` per key
+    fn gc_versions(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> {
+        // With a delete grace period, the version before a tombstone is the
+        // one `undelete` restores
+        let keep_versions = if self.delete_grace.is_zero() { keep_versions } else { keep_versions.max(2) };
+        match self.send(Command::CompactHistory { up_to_ts, keep_versions })? {
+            Reply::Compacted(stats) => Ok(stats),
+            reply => unreachable!("Unexpected reply to CompactHistory: {:?}", reply),
+        }
+    }
+
+    /// Drop events at or before `up_to_ts` from the log
+    fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> {
+        match self.send(Command::TrimEvents { up_to_ts })? {
+            Reply::Compacted(stats) => Ok(stats),
+            reply => unreachable!("Unexpected reply to TrimEvents: {:?}", reply),
+        }
+    }
+
+    /// Purge keys tombstoned at or before `up_to_ts` and past their delete
+    /// grace period
+    fn gc_tombstones(&self, up_to_ts: CommitTs) -> Result<CompactionStats> {
+        match self.send(Command::PurgeTombstones { up_to_ts })? {
+            Reply::Compacted(stats) => Ok(stats),
+            reply => unreachable!("Unexpected reply to PurgeTombstones: {:?}", reply),
+        }
+    }
+
+    fn execute_gc_tombstones(&self, state: &mut WriterState, up_to_ts: CommitTs) -> Result<CompactionStats> {
+        let now = unix_now_ms();
+        let mut tombstones = self.storage.list_tombstones(up_to_ts)?;
+        tombstones.retain(|tombstone| tombstone.purge_after.is_none_or(|purge_after| purge_after <= now));
+        if tombstones.is_empty() {
+            return Ok(CompactionStats { snapshot_ts: up_to_ts, ..Default::default() });
+        }
+
+        let version_counters = &mut state.version_counters;
+        let mut stats = self.storage.purge_tombstones(&tombstones)?;
+        stats.snapshot_ts = up_to_ts;
+
+        // Purged keys start again from version 1, as after a restart
+        for tombstone in &tombstones {
+            let record_id = RecordId::new(
+                tombstone.namespace.clone(),
+                tombstone.agent_id.clone(),
+                tombstone.key.clone(),
+            );
+            if version_counters.get(&record_id) == Some(&tombstone.version) {
+                version_counters.remove(&record_id);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Physically remove a key: its latest record, every version, and its
+    /// operations in the event log, leaving no tombstone. Not versioned or
+    /// replicated, and if the key is written again it starts over at version
+    /// 1. Values may survive in snapshot files saved before the purge.
+    pub fn purge_key(&self, namespace: &str, agent_id: &str, key: &str) -> Result<PurgeStats> {
+        let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+        match self.send(Command::PurgeKey { record_id })? {
+            Reply::Purged(stats) => Ok(stats),
+            reply => unreachable!("Unexpected reply to PurgeKey: {:?}", reply),
+        }
+    }
+
+    fn execute_purge_key(&self, state: &mut WriterState, record_id: &RecordId) -> Result<PurgeStats> {
+        // Run by the writer, so no commit writes the key mid-purge
+        let stats = self.storage.purge_key(record_id)?;
+        state.version_counters.remove(record_id);
+
+        info!(
+            namespace = %record_id.namespace,
+            agent_id = %record_id.agent_id,
+            key = %record_id.key,
+            versions_removed = stats.versions_removed,
+            events_scrubbed = stats.events_scrubbed,
+            "Key purged"
+        );
+
+        Ok(stats)
+    }
+
+    /// Rename namespace `from` to `to`: every key moves with its versions
+    /// and history, and events that wrote it name `to` from then on. Fails
+    /// with `NamespaceNotEmpty` if `to` already has records, unless `merge`
+    /// is set, and with `KeyExists` if a key exists in both. Not versioned
+    /// or replicated. Snapshot files saved for `from` keep its name, and
+    /// transactions open across the rename still write under it.
+    pub fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> {
+        if from == to {
+            return Err(anyhow!("Cannot rename namespace {} to itself", from));
+        }
+        match self.send(Command::RenameNamespace { from: from.to_string(), to: to.to_string(), merge })? {
+            Reply::Renamed(stats) => Ok(stats),
+            reply => unreachable!("Unexpected reply to RenameNamespace: {:?}", reply),
+        }
+    }
+
+    fn execute_rename_namespace(&self, state: &mut WriterState, from: &str, to: &str, merge: bool) -> Result<RenameStats> {
+        // Run by the writer, so no commit writes either namespace mid-rename
+        let stats = self.storage.rename_namespace(from, to, merge)?;
+        state.version_counters.retain(|record_id, _| record_id.namespace != from);
+
+        info!(
+            from = %from,
+            to = %to,
+            records_moved = stats.records_moved,
+            versions_moved = stats.versions_moved,
+            events_rewritten = stats.events_rewritten,
+            "Namespace renamed"
+        );
+
+        Ok(stats)
+    }
+
+    /// Create and save a snapshot of a single namespace
+    pub fn create_namespace_snapshot(&self, namespace: &str) -> Result<crate::storage::Snapshot> {
+        let snapshot = self.storage.create_snapshot_for_namespace(namespace)?;
+        self.storage.save_snapshot_for_namespace(&snapshot)?;
+
+        info!(
+            namespace = %namespace,
+            records = snapshot.metadata.record_count,
+            snapshot_ts = snapshot.metadata.snapshot_ts,
+            "Namespace snapshot saved"
+        );
+
+        Ok(snapshot)
+    }
+
+    /// Restore a single namespace from its saved snapshot, leaving other
+    /// namespaces untouched. History written since the snapshot is dropped,
+    /// and keys carry on from their restored versions. Returns false if no
+    /// snapshot exists for it.
+    pub fn restore_namespace(&self, namespace: &str) -> Result<bool> {
+        let snapshot = match self.storage.load_snapshot_for_namespace(namespace)? {
+            Some(snapshot) => snapshot,
+            None => return Ok(false),
+        };
+        self.send(Command::RestoreNamespace { namespace: namespace.to_string(), snapshot: Box::new(snapshot) })?;
+        Ok(true)
+    }
+
+    fn execute_restore_namespace(&self, state: &mut WriterState, namespace: &str, snapshot: &crate::storage::Snapshot) -> Result<()> {
+        self.storage.restore_namespace_snapshot(snapshot)?;
+
+        // Counters for this namespace are reseeded from storage on next use
+        state.version_counters.retain(|record_id, _| record_id.namespace != namespace);
+
+        info!(
+            namespace = %namespace,
+            records = snapshot.metadata.record_count,
+            snapshot_ts = snapshot.metadata.snapshot_ts,
+            "Namespace restored from snapshot"
+        );
+
+        Ok(())
+    }
+
+    /// Check if snapshot should be created and do it if needed
+    pub fn maybe_snapshot(&self, snapshot_interval: u64) -> Result<()> {
+        let mut counter = self.commits_since_snapshot.write().unwrap();
+        *counter += 1;
+        
+        if *counter >= snapshot_interval {
+            drop(counter); // Release lock before creating snapshot
+            self.create_snapshot()?;
+        }
+        
+        Ok(())
+    }
+
+    /// Rebuild the version counters after a restart: seed them from the
+    /// latest snapshot, then replay every event committed after it. The
+    /// replay runs in the writer, so commits wait until it's done. Progress
+    /// is logged periodically and reported by `recovery_status`.
+    pub fn recover(&self) -> Result<RecoveryStats> {
+        let started = Instant::now();
+        self.set_recovery_status(RecoveryStatus::LoadingSnapshot);
+        info!("Recovery: loading snapshot");
+        let snapshot = self.storage.load_snapshot()?.map(Box::new);
+
+        let stats = match self.send(Command::Recover { snapshot })? {
+            Reply::Recovered(stats) => stats,
+            reply => unreachable!("Unexpected reply to Recover: {:?}", reply),
+        };
+
+        self.set_recovery_status(RecoveryStatus::Ready);
+        info!(
+            snapshot_ts = ?stats.snapshot_ts,
+            events_replayed = stats.events_replayed,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Recovery complete"
+        );
+        Ok(stats)
+    }
+
+    fn execute_recover(&self, state: &mut WriterState, snapshot: Option<&crate::storage::Snapshot>) -> Result<RecoveryStats> {
+        let version_counters = &mut state.version_counters;
+        let snapshot_ts = match snapshot {
+            Some(snapshot) => {
+                self.seed_version_counters(version_counters, snapshot)?;
+                snapshot.metadata.snapshot_ts
+            }
+            None => {
+                version_counters.clear();
+                0
+            }
+        };
+
+        // Only the events there are: timestamps whose event was purged,
+        // trimmed or never written cost nothing
+        let total = self.storage.count_events(snapshot_ts + 1)?;
+        self.set_recovery_status(RecoveryStatus::ReplayingEvents { processed: 0, total });
+        info!(snapshot_ts, events = total, "Recovery: replaying events");
+
+        let mut stats = RecoveryStats { snapshot_ts: snapshot.map(|s| s.metadata.snapshot_ts), events_replayed: 0 };
+        let mut last_report = Instant::now();
+        let mut from_ts = snapshot_ts + 1;
+        loop {
+            let events = self.storage.read_events(from_ts, RECOVERY_PAGE_SIZE)?;
+            let Some(last) = events.last() else { break };
+            from_ts = last.commit_ts + 1;
+            for event in events {
+                for op in event.operations {
+                    let counter = version_counters.entry(RecordId::new(op.namespace, op.agent_id, op.key)).or_insert(0);
+                    *counter = (*counter).max(op.version);
+                }
+                stats.events_replayed += 1;
+            }
+
+            let processed = stats.events_replayed;
+            self.set_recovery_status(RecoveryStatus::ReplayingEvents { processed, total });
+            if last_report.elapsed() >= RECOVERY_PROGRESS_INTERVAL {
+                info!(processed, total, "Recovery: replaying events");
+                last_report = Instant::now();
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn recovery_status(&self) -> RecoveryStatus {
+        *self.recovery.lock().unwrap()
+    }
+
+    fn set_recovery_status(&self, status: RecoveryStatus) {
+        *self.recovery.lock().unwrap() = status;
+    }
+
+    /// Load snapshot and replay events after snapshot timestamp.
+    /// Storage may already hold commits newer than the snapshot, so a counter
+    /// never goes below the persisted version of its record.
+    pub fn recover_from_snapshot(&self, snapshot: &crate::storage::Snapshot) -> Result<()> {
+        self.send(Command::SeedVersions { snapshot: Box::new(snapshot.clone()) })?;
+        Ok(())
+    }
+
+    /// Reset the version counters to a snapshot's versions
+    fn seed_version_counters(&self, version_counters: &mut HashMap<RecordId, Version>, snapshot: &crate::storage::Snapshot) -> Result<()> {
+        version_counters.clear();
+        
+        for record in &snapshot.records {
+            let record_id = RecordId::new(
+                record.namespace.clone(),
+                record.agent_id.clone(),
+                record.key.clone(),
+            );
+            let persisted = self.storage.read_state(&record_id)?.map(|r| r.version).unwrap_or(0);
+            version_counters.insert(record_id, record.version.max(persisted));
+        }
+        
+        Ok(())
+    }
+}
+
+/// Nesting depth of a JSON value: 0 for a scalar, 1 for a flat object or
+/// array. Walks an explicit stack, so any depth is safe to measure.
+fn json_depth(value: &serde_json::Value) -> usize {
+    let mut deepest = 0;
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        match value {
+            serde_json::Value::Object(map) => {
+                deepest = deepest.max(depth + 1);
+                stack.extend(map.values().map(|child| (child, depth + 1)));
+            }
+            serde_json::Value::Array(items) => {
+                deepest = deepest.max(depth + 1);
+                stack.extend(items.iter().map(|child| (child, depth + 1)));
+            }
+            _ => {}
+        }
+    }
+    deepest
+}
+
+fn unix_now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// `current` (absent as `None`) with `delta` added to the integer at
+/// `pointer`, or why that can't be done. A missing field is added, as 0 plus
+/// `delta`, if its parent is an object; a missing value counts as `{}`, or
+/// as 0 if `pointer` is "".
+fn incremented(current: Option<serde_json::Value>, pointer: &str, delta: i64) -> std::result::Result<serde_json::Value, String> {
+    let mut value = current.unwrap_or_else(|| if pointer.is_empty() { serde_json::json!(0) } else { serde_json::json!({}) });
+    if let Some(field) = value.pointer_mut(pointer) {
+        let n = field.as_i64().ok_or_else(|| format!("{:?} is not an integer", pointer))?;
+        *field = n.checked_add(delta).ok_or_else(|| format!("{:?} would overflow", pointer))?.into();
+        return Ok(value);
+    }
+
+    let (parent, name) = pointer.rsplit_once('/').ok_or_else(|| format!("invalid JSON pointer {:?}", pointer))?;
+    match value.pointer_mut(parent) {
+        Some(serde_json::Value::Object(fields)) => {
+            fields.insert(name.replace("~1", "/").replace("~0", "~"), delta.into());
+            Ok(value)
+        }
+        _ => Err(format!("{:?} has no object to add {:?} to", parent, name)),
+    }
+}
+
+/// Client-supplied ids must be non-empty, at most `MAX_TXN_ID_LEN` bytes, and
+/// made of ASCII letters, digits, `-`, `_`, `.` or `:`
+fn validate_txn_id(txn_id: &str) -> Result<()> {
+    let reason = if txn_id.is_empty() {
+        "must not be empty".to_string()
+    } else if txn_id.len() > MAX_TXN_ID_LEN {
+        format!("must be at most {} bytes", MAX_TXN_ID_LEN)
+    } else if !txn_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')) {
+        "may only contain ASCII letters, digits, '-', '_', '.' and ':'".to_string()
+    } else {
+        return Ok(());
+    };
+    Err(StatehouseError::InvalidTxnId { txn_id: txn_id.to_string(), reason }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InMemoryStorage, Snapshot};
+
+    #[test]
+    fn test_transaction_lifecycle() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Begin transaction
+        let txn_id = sm.begin_transaction(None).unwrap();
+
+        // Write
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 42})).unwrap();
+
+        // Commit
+        let commit_ts = sm.commit(&txn_id).unwrap().commit_ts;
+        assert!(commit_ts > 0);
+
+        // Read
+        let state = sm.get_state("default", "agent-1", "key1").unwrap();
+        assert!(state.is_some());
+        let record = state.unwrap();
+        assert_eq!(record.value.unwrap()["value"], 42);
+    }
+
+    #[test]
+    fn test_delete() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Write
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 42})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Delete
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Read should show deleted
+        let state = sm.get_state("default", "agent-1", "key1").unwrap();
+        assert!(state.is_some());
+        assert!(state.unwrap().deleted);
+    }
+
+    #[test]
+    fn test_conditional_delete() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Stage a delete against version 1
+        let delete_txn = sm.begin_transaction(None).unwrap();
+        sm.delete_if_version(&delete_txn, "default".to_string(), "agent-1".to_string(), "key1".to_string(), 1).unwrap();
+
+        // A concurrent writer bumps the key to version 2
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // The conditional delete must now fail and leave the new value in place
+        let err = sm.commit(&delete_txn).unwrap_err();
+        match err.downcast_ref::<StatehouseError>() {
+            Some(StatehouseError::Conflict { key, expected, actual, .. }) => {
+                assert_eq!(key, "key1");
+                assert_eq!(*expected, 1);
+                assert_eq!(*actual, 2);
+            }
+            other => panic!("expected conflict, got {:?}", other),
+        }
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert!(!state.deleted);
+        assert_eq!(state.value.unwrap()["value"], 2);
+
+        // Against the current version it succeeds
+        let delete_txn = sm.begin_transaction(None).unwrap();
+        sm.delete_if_version(&delete_txn, "default".to_string(), "agent-1".to_string(), "key1".to_string(), 2).unwrap();
+        sm.commit(&delete_txn).unwrap();
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert!(state.deleted);
+        assert_eq!(state.version, 3);
+    }
+
+    #[test]
+    fn test_conditional_delete_counts_earlier_writes_in_the_transaction() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(1)).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // The write before it moves the key to version 2 within the transaction
+        let stale = sm.begin_transaction(None).unwrap();
+        sm.write(&stale, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(2)).unwrap();
+        sm.delete_if_version(&stale, "default".to_string(), "agent-1".to_string(), "key1".to_string(), 1).unwrap();
+        match sm.commit(&stale).unwrap_err().downcast_ref::<StatehouseError>() {
+            Some(StatehouseError::Conflict { expected: 1, actual: 2, .. }) => {}
+            other => panic!("expected conflict, got {:?}", other),
+        }
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(2)).unwrap();
+        sm.delete_if_version(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), 2).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert!(state.deleted);
+        assert_eq!(state.version, 3);
+    }
+
+    #[test]
+    fn test_write_if_match() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write_if_match = |value: i64, etag: String| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write_if_match(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(value), Labels::new(), etag).unwrap();
+            sm.commit(&txn_id)
+        };
+        let etag_of = |record: &StateRecord| etag(record.version, record.value.as_ref().filter(|_| !record.deleted).map(|value| (value, &record.labels)));
+
+        // A missing key has the ETag of version 0, so it can be created only if still missing
+        write_if_match(1, etag(0, None)).unwrap();
+        let read = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        write_if_match(2, etag_of(&read)).unwrap();
+
+        // Stale after someone else's write, so nothing is written
+        let err = write_if_match(3, etag_of(&read)).unwrap_err();
+        let current = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        match err.downcast_ref::<StatehouseError>() {
+            Some(StatehouseError::EtagMismatch { expected, actual, .. }) => assert_eq!((expected.clone(), actual.clone()), (etag_of(&read), etag_of(&current))),
+            other => panic!("expected an ETag mismatch, got {:?}", other),
+        }
+        assert_eq!(current.value, Some(serde_json::json!(2)));
+
+        // The check counts the transaction's own earlier changes
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(4)).unwrap();
+        sm.delete_if_match(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), etag(3, Some((&serde_json::json!(4), &Labels::new())))).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().deleted);
+    }
+
+    #[test]
+    fn test_etag_does_not_survive_purge() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write = |value: i64| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(value)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+        write(1);
+        let read = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        let stale = etag(read.version, read.value.as_ref().map(|value| (value, &read.labels)));
+
+        // Purged and recreated, the key is back at version 1 but with other content
+        sm.purge_key("default", "agent-1", "key1").unwrap();
+        write(2);
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().version, 1);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write_if_match(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(3), Labels::new(), stale).unwrap();
+        let err = sm.commit(&txn_id).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::EtagMismatch { .. })));
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().value, Some(serde_json::json!(2)));
+    }
+
+    #[test]
+    fn test_write_if() {
+        use crate::predicate::CompareOp;
+
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let status_is = |status: &str| ValuePredicate::new("/status", CompareOp::Eq, serde_json::json!(status)).unwrap();
+        let write_if = |key: &str, value: serde_json::Value, predicate: ValuePredicate| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write_if(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value, predicate).unwrap();
+            sm.commit(&txn_id)
+        };
+        let predicate_failed = |result: Result<CommitResult>| match result.unwrap_err().downcast_ref::<StatehouseError>() {
+            Some(StatehouseError::PredicateFailed { reason, .. }) => reason.clone(),
+            other => panic!("expected a failed predicate, got {:?}", other),
+        };
+
+        // A missing key fails the predicate
+        let reason = predicate_failed(write_if("task", serde_json::json!({"status": "running"}), status_is("pending")));
+        assert_eq!(reason, "key does not exist");
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task".to_string(), serde_json::json!({"status": "pending"})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        write_if("task", serde_json::json!({"status": "running"}), status_is("pending")).unwrap();
+        let state = sm.get_state("default", "agent-1", "task").unwrap().unwrap();
+        assert_eq!(state.value.unwrap()["status"], "running");
+        assert_eq!(state.version, 2);
+
+        // No longer pending: rejected, and the value is unchanged
+        let reason = predicate_failed(write_if("task", serde_json::json!({"status": "running"}), status_is("pending")));
+        assert!(reason.contains("\"running\""), "{}", reason);
+        let reason = predicate_failed(write_if("task", serde_json::json!({}), ValuePredicate::new("/owner", CompareOp::Ne, serde_json::json!("bob")).unwrap()));
+        assert!(reason.contains("does not exist"), "{}", reason);
+        assert_eq!(sm.get_state("default", "agent-1", "task").unwrap().unwrap().version, 2);
+
+        // The predicate sees earlier writes in the same transaction
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task".to_string(), serde_json::json!({"status": "done"})).unwrap();
+        sm.write_if(&txn_id, "default".to_string(), "agent-1".to_string(), "task".to_string(), serde_json::json!({"status": "archived"}), status_is("done")).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert_eq!(sm.get_state("default", "agent-1", "task").unwrap().unwrap().value.unwrap()["status"], "archived");
+    }
+
+    #[test]
+    fn test_namespace_quota() {
+        let quota = NamespaceQuota { max_records: Some(3), max_bytes: None };
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()))
+            .with_namespace_quotas(NamespaceQuotas::default().with_quota("tenant-a", quota));
+        let commit = |writes: &[&str], deletes: &[&str]| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            for key in writes {
+                sm.write(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({})).unwrap();
+            }
+            for key in deletes {
+                sm.delete(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), key.to_string()).unwrap();
+            }
+            sm.commit(&txn_id)
+        };
+        let records = || sm.namespace_usage("tenant-a").unwrap().0.records;
+
+        // Approaching: overwrites don't add records
+        commit(&["a", "b"], &[]).unwrap();
+        commit(&["a"], &[]).unwrap();
+        assert_eq!(records(), 2);
+        assert_eq!(sm.namespace_usage("tenant-a").unwrap().1, Some(quota));
+
+        // Hitting: the whole transaction is rejected and nothing is written
+        let err = commit(&["c", "d"], &[]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StatehouseError>(),
+            Some(StatehouseError::QuotaExceeded { usage: 2, added: 2, .. })
+        ), "{:?}", err);
+        assert_eq!(records(), 2);
+        assert!(sm.get_state("tenant-a", "agent-1", "c").unwrap().is_none());
+        commit(&["c"], &[]).unwrap();
+        assert!(commit(&["d"], &[]).is_err());
+
+        // Aborted transactions never count
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), "e".to_string(), serde_json::json!({})).unwrap();
+        sm.abort(&txn_id).unwrap();
+        assert_eq!(records(), 3);
+
+        // Recovering: a delete frees room within the same transaction or later
+        commit(&["d"], &["a"]).unwrap();
+        assert_eq!(records(), 3);
+        commit(&[], &["b", "c"]).unwrap();
+        commit(&["e", "f"], &[]).unwrap();
+        assert_eq!(records(), 3);
+
+        // Other namespaces are unaffected
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for i in 0..5 {
+            sm.write(&txn_id, "tenant-b".to_string(), "agent-1".to_string(), format!("key{}", i), serde_json::json!({})).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+        assert_eq!(sm.namespace_usage("tenant-b").unwrap(), (NamespaceUsage { records: 5, bytes: 10 }, None));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        use crate::storage::{RocksStorage, StorageConfig};
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = StorageConfig { data_dir: temp_dir.path().to_path_buf(), fsync_on_commit: false, ..StorageConfig::default() };
+        let rocks: Arc<dyn Storage> = Arc::new(RocksStorage::new(config).unwrap());
+        for storage in [rocks, Arc::new(InMemoryStorage::new()) as Arc<dyn Storage>] {
+            let policies = EvictionPolicies::default().with_policy("cache", EvictionPolicy::Lru { max_keys: 3 });
+            let sm = StateMachine::new(storage).with_eviction_policies(policies);
+            let commit = |agent_id: &str, keys: &[&str]| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                for key in keys {
+                    sm.write(&txn_id, "cache".to_string(), agent_id.to_string(), key.to_string(), serde_json::json!(key)).unwrap();
+                }
+                sm.commit(&txn_id)
+            };
+            let live = |agent_id: &str, key: &str| sm.exists("cache", agent_id, key).unwrap();
+            let records = || sm.namespace_usage("cache").unwrap().0.records;
+
+            commit("agent-1", &["a", "b"]).unwrap();
+            commit("agent-2", &["c"]).unwrap();
+            // Rewriting "a" makes "b" the oldest
+            commit("agent-1", &["a"]).unwrap();
+            assert_eq!(records(), 3);
+
+            // Writing past the cap evicts the oldest key in the same event
+            let result = commit("agent-1", &["d"]).unwrap();
+            assert_eq!(records(), 3);
+            assert!(!live("agent-1", "b"));
+            assert!(live("agent-1", "a") && live("agent-2", "c") && live("agent-1", "d"));
+            let event = sm.get_event(result.commit_ts).unwrap().unwrap();
+            let operations: Vec<(&str, bool)> = event.operations.iter().map(|op| (op.key.as_str(), op.value.is_some())).collect();
+            assert_eq!(operations, vec![("d", true), ("b", false)]);
+
+            // Across agents, and never a key the transaction writes itself
+            commit("agent-1", &["c", "e"]).unwrap();
+            assert_eq!(records(), 3);
+            assert!(!live("agent-2", "c") && !live("agent-1", "a"));
+            assert!(live("agent-1", "c") && live("agent-1", "d") && live("agent-1", "e"));
+
+            // A transaction that alone exceeds the cap is rejected
+            let err = commit("agent-1", &["f", "g", "h", "i"]).unwrap_err();
+            assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::QuotaExceeded { .. })), "{:?}", err);
+            assert_eq!(records(), 3);
+
+            // Other namespaces are unaffected
+            let txn_id = sm.begin_transaction(None).unwrap();
+            for i in 0..5 {
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), format!("key{}", i), serde_json::json!({})).unwrap();
+            }
+            sm.commit(&txn_id).unwrap();
+            assert_eq!(sm.namespace_usage("default").unwrap().0.records, 5);
+        }
+    }
+
+    #[test]
+    fn test_keys_expire_after_their_ttl() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write_with_ttl(&txn_id, "default".to_string(), "agent-1".to_string(), "short".to_string(), serde_json::json!(1), Labels::new(), Duration::from_millis(50)).unwrap();
+        sm.write_with_ttl(&txn_id, "default".to_string(), "agent-1".to_string(), "long".to_string(), serde_json::json!(2), Labels::new(), Duration::from_secs(3600)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "forever".to_string(), serde_json::json!(3)).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let expires_at = sm.get_state("default", "agent-1", "short").unwrap().unwrap().expires_at.unwrap();
+        assert!(expires_at > unix_now_ms());
+        assert_eq!(sm.get_state("default", "agent-1", "forever").unwrap().unwrap().expires_at, None);
+
+        // Nothing is due yet
+        assert_eq!(sm.expire_keys().unwrap(), 0);
+        assert!(sm.exists("default", "agent-1", "short").unwrap());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(sm.expire_keys().unwrap(), 1);
+        let state = sm.get_state("default", "agent-1", "short").unwrap().unwrap();
+        assert!(state.deleted);
+        assert_eq!(state.version, 2);
+        assert!(sm.exists("default", "agent-1", "long").unwrap());
+        assert!(sm.exists("default", "agent-1", "forever").unwrap());
+
+        // The expiry is an ordinary delete in the log
+        let event = sm.get_event(state.commit_ts).unwrap().unwrap();
+        assert_eq!(event.operations.len(), 1);
+        assert!(event.operations[0].value.is_none());
+        assert_eq!(sm.expire_keys().unwrap(), 0);
+
+        // Writing the key again without a TTL keeps it
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write_with_ttl(&txn_id, "default".to_string(), "agent-1".to_string(), "rewritten".to_string(), serde_json::json!(1), Labels::new(), Duration::from_millis(1)).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "rewritten".to_string(), serde_json::json!(2)).unwrap();
+        sm.commit(&txn_id).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(sm.expire_keys().unwrap(), 0);
+        assert!(sm.exists("default", "agent-1", "rewritten").unwrap());
+    }
+
+    #[test]
+    fn test_namespace_default_ttl() {
+        let ttls = DefaultTtls::default().with_ttl("scratch", Duration::from_millis(50));
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new())).with_default_ttls(ttls);
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "scratch".to_string(), "agent-1".to_string(), "default".to_string(), serde_json::json!(1)).unwrap();
+        sm.write_with_ttl(&txn_id, "scratch".to_string(), "agent-1".to_string(), "pinned".to_string(), serde_json::json!(2), Labels::new(), Duration::ZERO).unwrap();
+        sm.write_with_ttl(&txn_id, "scratch".to_string(), "agent-1".to_string(), "longer".to_string(), serde_json::json!(3), Labels::new(), Duration::from_secs(3600)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "default".to_string(), serde_json::json!(4)).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // A write without a TTL takes the namespace's; an explicit zero never expires
+        assert!(sm.get_state("scratch", "agent-1", "default").unwrap().unwrap().expires_at.is_some());
+        assert_eq!(sm.get_state("scratch", "agent-1", "pinned").unwrap().unwrap().expires_at, None);
+        assert_eq!(sm.get_state("default", "agent-1", "default").unwrap().unwrap().expires_at, None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(sm.expire_keys().unwrap(), 1);
+        assert!(!sm.exists("scratch", "agent-1", "default").unwrap());
+        assert!(sm.exists("scratch", "agent-1", "pinned").unwrap());
+        assert!(sm.exists("scratch", "agent-1", "longer").unwrap());
+        // A namespace without a default behaves as before
+        assert!(sm.exists("default", "agent-1", "default").unwrap());
+    }
+
+    #[test]
+    fn test_client_supplied_txn_id() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // A fresh id is accepted and usable
+        let txn_id = sm.begin_transaction_with_id(Some("order-42:attempt-1".to_string()), None).unwrap();
+        assert_eq!(txn_id, "order-42:attempt-1");
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 1})).unwrap();
+
+        // A duplicate of an open transaction is rejected
+        let err = sm.begin_transaction_with_id(Some(txn_id.clone()), None).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::TransactionExists { .. })));
+
+        // Malformed ids are rejected
+        for bad in ["", "has space", &"x".repeat(MAX_TXN_ID_LEN + 1)] {
+            let err = sm.begin_transaction_with_id(Some(bad.to_string()), None).unwrap_err();
+            assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::InvalidTxnId { .. })));
+        }
+
+        // Once committed, the id can be reused
+        sm.commit(&txn_id).unwrap();
+        sm.begin_transaction_with_id(Some(txn_id), None).unwrap();
+
+        // Without an id, a UUID is generated
+        let generated = sm.begin_transaction_with_id(None, None).unwrap();
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+    }
+
+    #[test]
+    fn test_batch_apply() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+        let value = |key: &str| sm.get_state("default", "agent-1", key).unwrap().filter(|r| !r.deleted).and_then(|r| r.value);
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "counter".to_string(), serde_json::json!({"n": 1})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task".to_string(), serde_json::json!({"done": false})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let batch = || {
+            vec![
+                BatchOp { record_id: id("note"), kind: BatchOpKind::Write { value: serde_json::json!({"text": "hi"}), labels: Labels::new() }, expected_version: Some(0) },
+                BatchOp { record_id: id("counter"), kind: BatchOpKind::Increment { pointer: "/n".to_string(), delta: 2 }, expected_version: Some(1) },
+                // Stale: task is at version 1
+                BatchOp { record_id: id("task"), kind: BatchOpKind::Delete, expected_version: Some(5) },
+            ]
+        };
+
+        // Atomic: the one conflict fails the batch and nothing is written
+        let err = sm.batch_apply(batch(), true, None).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StatehouseError::Conflict { key, expected: 5, actual: 1, .. }) if key == "task"));
+        assert_eq!(value("note"), None);
+        assert_eq!(value("counter"), Some(serde_json::json!({"n": 1})));
+        assert!(sm.list_open_transactions().is_empty());
+
+        // Not atomic: only the conflicting delete is left out
+        let result = sm.batch_apply(batch(), false, None).unwrap();
+        assert!(result.commit.commit_ts > 0);
+        assert_eq!(result.outcomes[0].as_ref().unwrap(), &1);
+        assert_eq!(result.outcomes[1].as_ref().unwrap(), &2);
+        assert!(matches!(result.outcomes[2].as_ref().unwrap_err().downcast_ref(), Some(StatehouseError::Conflict { .. })));
+        assert_eq!(value("note"), Some(serde_json::json!({"text": "hi"})));
+        assert_eq!(value("counter"), Some(serde_json::json!({"n": 3})));
+        assert_eq!(value("task"), Some(serde_json::json!({"done": false})));
+
+        // Each operation sees those before it; increments fail on non-integers
+        // and create missing fields
+        let result = sm.batch_apply(vec![
+            BatchOp { record_id: id("counter"), kind: BatchOpKind::Increment { pointer: "/n".to_string(), delta: -3 }, expected_version: None },
+            BatchOp { record_id: id("counter"), kind: BatchOpKind::Delete, expected_version: Some(2) },
+            BatchOp { record_id: id("task"), kind: BatchOpKind::Increment { pointer: "/done".to_string(), delta: 1 }, expected_version: None },
+            BatchOp { record_id: id("stats"), kind: BatchOpKind::Increment { pointer: "/hits".to_string(), delta: 1 }, expected_version: None },
+        ], false, None).unwrap();
+        assert_eq!(result.outcomes[0].as_ref().unwrap(), &3);
+        assert!(matches!(result.outcomes[1].as_ref().unwrap_err().downcast_ref(), Some(StatehouseError::Conflict { expected: 2, actual: 3, .. })));
+        assert!(matches!(result.outcomes[2].as_ref().unwrap_err().downcast_ref(), Some(StatehouseError::PredicateFailed { .. })));
+        assert_eq!(value("counter"), Some(serde_json::json!({"n": 0})));
+        assert_eq!(value("stats"), Some(serde_json::json!({"hits": 1})));
+        assert_eq!(result.commit.changed, vec![id("counter"), id("stats")]);
+    }
+
+    #[test]
+    fn test_get_or_create() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let get_or_create = |txn_id: &str, default: serde_json::Value| {
+            sm.get_or_create(txn_id, "default".to_string(), "agent-1".to_string(), "config".to_string(), default).unwrap();
+        };
+
+        // Both transactions stage before either commits; only the first creates
+        let first = sm.begin_transaction(None).unwrap();
+        let second = sm.begin_transaction(None).unwrap();
+        get_or_create(&first, serde_json::json!({"mode": "a"}));
+        get_or_create(&second, serde_json::json!({"mode": "b"}));
+
+        let created = sm.commit(&first).unwrap().get_or_create.remove(0);
+        assert!(created.created);
+        assert_eq!(created.value, serde_json::json!({"mode": "a"}));
+        assert_eq!(created.version, 1);
+
+        let existing = sm.commit(&second).unwrap();
+        assert!(existing.changed.is_empty());
+        let existing = &existing.get_or_create[0];
+        assert!(!existing.created);
+        assert_eq!(existing.value, serde_json::json!({"mode": "a"}));
+        assert_eq!(existing.version, 1);
+        assert_eq!(sm.get_state("default", "agent-1", "config").unwrap().unwrap().version, 1);
+
+        // A deleted key counts as absent
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "config".to_string()).unwrap();
+        get_or_create(&txn_id, serde_json::json!({"mode": "c"}));
+        let result = sm.commit(&txn_id).unwrap().get_or_create.remove(0);
+        assert!(result.created);
+        assert_eq!(result.version, 3);
+        let record = sm.get_state("default", "agent-1", "config").unwrap().unwrap();
+        assert_eq!((record.value, record.version), (Some(serde_json::json!({"mode": "c"})), 3));
+    }
+
+    #[test]
+    fn test_with_retry() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write = |value: i32| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "lock".to_string(), serde_json::json!(value)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+        write(1);
+
+        // The first attempt is overtaken by another writer before it commits
+        let mut attempts = 0;
+        let commit_ts = sm.with_retry(3, |txn_id| {
+            attempts += 1;
+            let version = sm.get_state("default", "agent-1", "lock")?.map_or(0, |r| r.version);
+            sm.delete_if_version(txn_id, "default".to_string(), "agent-1".to_string(), "lock".to_string(), version)?;
+            if attempts == 1 {
+                write(2);
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(attempts, 2);
+        let record = sm.get_state("default", "agent-1", "lock").unwrap().unwrap();
+        assert!(record.deleted);
+        assert_eq!(record.commit_ts, commit_ts);
+        assert!(sm.list_open_transactions().is_empty());
+
+        // Conflicts on every attempt give up after max_attempts
+        let mut attempts = 0;
+        let err = sm.with_retry(2, |txn_id| {
+            attempts += 1;
+            sm.delete_if_version(txn_id, "default".to_string(), "agent-1".to_string(), "lock".to_string(), 99)
+        }).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::Conflict { .. })));
+        assert_eq!(attempts, 2);
+
+        // Other errors are not retried
+        let mut attempts = 0;
+        sm.with_retry(5, |_| {
+            attempts += 1;
+            Err(anyhow!("bad input"))
+        }).unwrap_err();
+        assert_eq!(attempts, 1);
+        assert!(sm.list_open_transactions().is_empty());
+    }
+
+    #[test]
+    fn test_reset_agent() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "b".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-2".to_string(), "a".to_string(), serde_json::json!({"value": 3})).unwrap();
+        let written = sm.commit(&txn_id).unwrap();
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        let mut cleared = sm.reset_agent(&txn_id, "default".to_string(), "agent-1".to_string()).unwrap();
+        cleared.sort();
+        assert_eq!(cleared, vec!["a".to_string(), "b".to_string()]);
+        let reset = sm.commit(&txn_id).unwrap();
+        assert_eq!(reset.changed.len(), 2);
+
+        assert!(sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap().is_empty());
+        assert_eq!(sm.list_keys("default", "agent-2", false, Deadline::NONE).unwrap(), vec!["a".to_string()]);
+
+        // History survives: the writes and then a single reset event
+        let events = sm.replay("default", "agent-1", None, None).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].commit_ts, written.commit_ts);
+        assert_eq!(events[0].operations.len(), 3);
+        assert_eq!(events[1].commit_ts, reset.commit_ts);
+        assert!(events[1].operations.iter().all(|op| op.value.is_none()));
+        let before = sm.get_state_as_of("default", "agent-1", "b", written.commit_ts).unwrap().unwrap();
+        assert_eq!(before.value.unwrap()["value"], 2);
+
+        // Nothing left to clear; a limit breach stages none of the deletes
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert!(sm.reset_agent(&txn_id, "default".to_string(), "agent-1".to_string()).unwrap().is_empty());
+        let sm = sm.with_transaction_limits(TransactionLimits { max_ops_per_transaction: 1, ..Default::default() });
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-2".to_string(), "b".to_string(), serde_json::json!({"value": 4})).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert!(sm.reset_agent(&txn_id, "default".to_string(), "agent-2".to_string()).is_err());
+        assert_eq!(sm.list_open_transactions().iter().find(|t| t.txn_id == txn_id).unwrap().staged_ops, 0);
+    }
+
+    #[test]
+    fn test_purge_key() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 16,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        }).unwrap();
+        let storages: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in storages {
+            let sm = StateMachine::new(storage);
+            for i in 1..=3 {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "secret".to_string(), serde_json::json!({"ssn": i})).unwrap();
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "other".to_string(), serde_json::json!(i)).unwrap();
+                sm.commit(&txn_id).unwrap();
+            }
+            sm.get_state("default", "agent-1", "secret").unwrap().unwrap();
+
+            let stats = sm.purge_key("default", "agent-1", "secret").unwrap();
+            assert_eq!(stats, PurgeStats { versions_removed: 3, events_scrubbed: 3 });
+
+            assert!(sm.get_state("default", "agent-1", "secret").unwrap().is_none());
+            for version in 1..=3 {
+                assert!(sm.get_state_at_version("default", "agent-1", "secret", version).unwrap().is_none());
+            }
+            assert!(sm.get_version_history("default", "agent-1", "secret", 10).unwrap().is_empty());
+            assert_eq!(sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap(), vec!["other".to_string()]);
+
+            // Events stay, with only the other key's operations
+            let events = sm.replay("default", "agent-1", None, None).unwrap();
+            assert_eq!(events.len(), 3);
+            assert!(events.iter().all(|e| e.operations.len() == 1 && e.operations[0].key == "other"));
+
+            // Written again, the key starts over
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "secret".to_string(), serde_json::json!({})).unwrap();
+            sm.commit(&txn_id).unwrap();
+            assert_eq!(sm.get_state("default", "agent-1", "secret").unwrap().unwrap().version, 1);
+        }
+    }
+
+    #[test]
+    fn test_rename_namespace() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 16,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 32,
+        }).unwrap();
+        let storages: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in storages {
+            let sm = StateMachine::new(storage);
+            let write = |namespace: &str, agent_id: &str, key: &str, value: serde_json::Value| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                sm.write(&txn_id, namespace.to_string(), agent_id.to_string(), key.to_string(), value).unwrap();
+                sm.commit(&txn_id).unwrap()
+            };
+            let mut commits = Vec::new();
+            for step in 1..=3 {
+                // Long enough to be stored as a shared blob
+                commits.push(write("staging", "agent-1", "plan", serde_json::json!({"step": step, "text": "a plan long enough to dedup"})));
+                write("staging", "agent-2", "note", serde_json::json!(step));
+            }
+            write("prod", "agent-1", "existing", serde_json::json!(true));
+
+            let err = sm.rename_namespace("staging", "prod", false).unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(StatehouseError::NamespaceNotEmpty { .. })));
+            assert!(sm.rename_namespace("staging", "staging", true).is_err());
+
+            let stats = sm.rename_namespace("staging", "prod", true).unwrap();
+            assert_eq!(stats, RenameStats { records_moved: 2, versions_moved: 6, events_rewritten: 6 });
+
+            // Everything is reachable under the new name, versions and history included
+            let plan = sm.get_state("prod", "agent-1", "plan").unwrap().unwrap();
+            assert_eq!((plan.namespace.as_str(), plan.version), ("prod", 3));
+            assert_eq!(plan.value.unwrap()["step"], 3);
+            assert_eq!(sm.get_version_history("prod", "agent-1", "plan", 10).unwrap().len(), 3);
+            let first = sm.get_state_at_version("prod", "agent-1", "plan", 1).unwrap().unwrap();
+            assert_eq!(first.value.unwrap()["step"], 1);
+            let as_of = sm.get_state_as_of("prod", "agent-1", "plan", commits[1].commit_ts).unwrap().unwrap();
+            assert_eq!(as_of.version, 2);
+            assert_eq!(sm.list_keys("prod", "agent-1", false, Deadline::NONE).unwrap(), vec!["existing", "plan"]);
+            let events = sm.replay("prod", "agent-1", None, None).unwrap();
+            assert_eq!(events.len(), 4);
+            assert!(events.iter().flat_map(|e| &e.operations).all(|op| op.namespace == "prod"));
+            assert_eq!(sm.replay("prod", "agent-2", None, None).unwrap().len(), 3);
+            assert_eq!(sm.namespace_usage("prod").unwrap().0.records, 3);
+
+            // ...and gone under the old one
+            assert!(sm.get_state("staging", "agent-1", "plan").unwrap().is_none());
+            assert!(sm.get_version_history("staging", "agent-1", "plan", 10).unwrap().is_empty());
+            assert!(sm.list_keys("staging", "agent-2", true, Deadline::NONE).unwrap().is_empty());
+            assert!(sm.replay("staging", "agent-1", None, None).unwrap().is_empty());
+            assert_eq!(sm.namespace_usage("staging").unwrap().0.records, 0);
+
+            // Moved keys carry on from their versions
+            write("prod", "agent-1", "plan", serde_json::json!({"step": 4}));
+            assert_eq!(sm.get_state("prod", "agent-1", "plan").unwrap().unwrap().version, 4);
+
+            // A key under both names stops a merge before anything moves
+            write("staging", "agent-1", "plan", serde_json::json!({"step": 1}));
+            write("staging", "agent-1", "draft", serde_json::json!({}));
+            let err = sm.rename_namespace("staging", "prod", true).unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(StatehouseError::KeyExists { key, .. }) if key == "plan"));
+            assert_eq!(sm.list_keys("staging", "agent-1", false, Deadline::NONE).unwrap(), vec!["draft", "plan"]);
+            assert!(sm.get_state("prod", "agent-1", "draft").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_scan_prefix_consistent_never_tears_a_commit() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig { data_dir: temp_dir.path().to_path_buf(), fsync_on_commit: false, ..StorageConfig::default() };
+        let storages: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(RocksStorage::new(config).unwrap())];
+
+        for storage in storages {
+            let sm = Arc::new(StateMachine::new(storage));
+            let write_pair = |sm: &StateMachine, n: u64| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                for key in ["order:123:items", "order:123:total"] {
+                    sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(n)).unwrap();
+                }
+                sm.commit(&txn_id).unwrap();
+            };
+            write_pair(&sm, 0);
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let (sm, stop) = (sm.clone(), stop.clone());
+                thread::spawn(move || {
+                    let mut n = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        n += 1;
+                        write_pair(&sm, n);
+                    }
+                    n
+                })
+            };
+
+            let mut last = 0;
+            for _ in 0..500 {
+                let records = sm.scan_prefix_consistent("default", "agent-1", "order:123:", &Labels::new(), false, Deadline::NONE).unwrap();
+                let values: Vec<u64> = records.iter().map(|r| r.value.as_ref().unwrap().as_u64().unwrap()).collect();
+                assert_eq!(values.len(), 2);
+                assert_eq!(values[0], values[1], "torn read of {:?}", records);
+                assert!(values[0] >= last);
+                last = values[0];
+            }
+            stop.store(true, Ordering::Relaxed);
+            let written = writer.join().unwrap();
+            assert!(last <= written);
+        }
+    }
+
+    #[test]
+    fn test_scan_prefix_iter() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        }).unwrap();
+        let storages: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        let count = SCAN_PAGE_SIZE * 2 + 10;
+        for storage in storages {
+            let sm = StateMachine::new(storage);
+            let txn_id = sm.begin_transaction(None).unwrap();
+            // Written out of order, with neighbours outside the prefix
+            for i in (0..count).rev() {
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), format!("item:{:04}", i), serde_json::json!(i)).unwrap();
+            }
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "items".to_string(), serde_json::json!(0)).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-2".to_string(), "item:0000".to_string(), serde_json::json!(0)).unwrap();
+            sm.commit(&txn_id).unwrap();
+
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "item:0005".to_string()).unwrap();
+            sm.commit(&txn_id).unwrap();
+
+            let keys: Vec<String> = sm
+                .scan_prefix_iter("default", "agent-1", "item:", None)
+                .map(|record| record.unwrap().key)
+                .collect();
+            let expected: Vec<String> = (0..count).filter(|i| *i != 5).map(|i| format!("item:{:04}", i)).collect();
+            assert_eq!(keys, expected);
+
+            // Resuming from a cursor, including a deleted key and one before the prefix
+            let resumed: Vec<String> = sm
+                .scan_prefix_iter("default", "agent-1", "item:", Some("item:0005".to_string()))
+                .map(|record| record.unwrap().key)
+                .collect();
+            assert_eq!(resumed, expected[5..]);
+            assert_eq!(sm.scan_prefix_iter("default", "agent-1", "item:", Some("a".to_string())).count(), count - 1);
+            assert_eq!(sm.scan_prefix_iter("default", "agent-1", "item:", Some(expected[count - 2].clone())).count(), 0);
+        }
+    }
+
+    /// In-memory storage standing in for a disk: a simulated crash keeps
+    /// only the state as of the last flush
+    #[derive(Default)]
+    struct CrashStorage {
+        live: InMemoryStorage,
+        flushed: std::sync::Mutex<Vec<StateRecord>>,
+        flush_pending: std::sync::atomic::AtomicBool,
+    }
+
+    impl CrashStorage {
+        /// Storage as it would reopen after a crash now
+        fn crash(&self) -> InMemoryStorage {
+            let recovered = InMemoryStorage::new();
+            for record in self.flushed.lock().unwrap().iter() {
+                recovered.write_state(record.clone()).unwrap();
+            }
+            recovered
+        }
+    }
+
+    impl Storage for CrashStorage {
+        fn health_check(&self) -> Result<()> { self.live.health_check() }
+        fn write_state(&self, record: StateRecord) -> Result<()> { self.live.write_state(record) }
+        fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> { self.live.read_state(record_id) }
+        fn exists(&self, record_id: &RecordId) -> Result<bool> { self.live.exists(record_id) }
+        fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> { self.live.read_state_meta(record_id) }
+        fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> { self.live.read_states(record_ids) }
+        fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> { self.live.read_state_at_version(record_id, version) }
+        fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> { self.live.read_version_history(record_id, limit) }
+        fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> { self.live.read_state_as_of(record_id, as_of) }
+        fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> { self.live.list_keys(namespace, agent_id, include_deleted, deadline) }
+        fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> { self.live.count_keys(namespace, agent_id, prefix) }
+        fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> { self.live.scan_namespace_prefix(namespace, prefix, limit) }
+        fn append_event(&self, event: EventLogEntry) -> Result<()> { self.live.append_event(event) }
+        fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> { self.live.replay_events(namespace, agent_id, start_ts, end_ts, deadline) }
+        fn next_commit_ts(&self) -> Result<CommitTs> { self.live.next_commit_ts() }
+        fn current_commit_ts(&self) -> Result<CommitTs> { self.live.current_commit_ts() }
+        fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> { self.live.advance_commit_ts(commit_ts) }
+        fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> { self.live.reserve_commit_ts_block(n) }
+        fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.live.next_namespace_ts(namespace) }
+        fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> { self.live.advance_namespace_ts(namespace, namespace_ts) }
+        fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.live.current_namespace_ts(namespace) }
+        fn create_snapshot(&self) -> Result<Snapshot> { self.live.create_snapshot() }
+        fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> { self.live.create_snapshot_at(snapshot_ts) }
+        fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> { self.live.save_snapshot(snapshot) }
+        fn load_snapshot(&self) -> Result<Option<Snapshot>> { self.live.load_snapshot() }
+        fn get_all_state(&self) -> Result<Vec<StateRecord>> { self.live.get_all_state() }
+        fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> { self.live.create_snapshot_for_namespace(namespace) }
+        fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()> { self.live.save_snapshot_for_namespace(snapshot) }
+        fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>> { self.live.load_snapshot_for_namespace(namespace) }
+        fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> { self.live.restore_namespace_snapshot(snapshot) }
+        fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> { self.live.compact_history(up_to_ts, keep_versions) }
+        fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> { self.live.trim_events(up_to_ts) }
+        fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> { self.live.list_tombstones(up_to_ts) }
+        fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> { self.live.purge_tombstones(tombstones) }
+        fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> { self.live.list_expired(now_ms) }
+        fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> { self.live.purge_key(record_id) }
+        fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> { self.live.rename_namespace(from, to, merge) }
+        fn last_event_ts(&self) -> Result<Option<CommitTs>> { self.live.last_event_ts() }
+        fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> { self.live.read_event(commit_ts) }
+        fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> { self.live.read_events(from_ts, limit) }
+        fn count_events(&self, from_ts: CommitTs) -> Result<u64> { self.live.count_events(from_ts) }
+        fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> { self.live.version_bounds(record_id) }
+        fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> { self.live.namespace_usage(namespace) }
+        fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> { self.live.least_recent_keys(namespace, limit) }
+        fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>> { self.live.version_counts(namespace, deadline) }
+        fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> { self.live.agent_last_commit_ts(namespace, agent_id) }
+        fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> { self.live.scan_prefix(namespace, agent_id, prefix, include_deleted, deadline) }
+        fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> { self.live.scan_prefix_consistent(namespace, agent_id, prefix, include_deleted, deadline) }
+        fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
+            self.live.scan_prefix_page(namespace, agent_id, prefix, include_deleted, start_after, limit)
+        }
+        fn flush(&self) -> Result<()> {
+            *self.flushed.lock().unwrap() = self.live.get_all_state()?;
+            Ok(())
+        }
+        fn supports_durability(&self, durability: Durability) -> bool { durability != Durability::Memory }
+        fn sync_commit(&self, durability: Option<Durability>) -> Result<()> {
+            match durability.unwrap_or(Durability::Fsync) {
+                Durability::Fsync => self.flush(),
+                _ => {
+                    self.flush_pending.store(true, std::sync::atomic::Ordering::SeqCst);
+                    Ok(())
+                }
+            }
+        }
+        fn flush_deferred(&self) -> Result<()> {
+            if self.flush_pending.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                self.flush()?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commit_durability() {
+        let storage = Arc::new(CrashStorage::default());
+        let sm = StateMachine::new(storage.clone());
+        let commit = |key: &str, durability: Option<Durability>| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({})).unwrap();
+            sm.commit_with_durability(&txn_id, durability)
+        };
+        let record_id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+
+        commit("critical", Some(Durability::Fsync)).unwrap();
+        commit("scratch", Some(Durability::Async)).unwrap();
+        assert!(sm.get_state("default", "agent-1", "scratch").unwrap().is_some());
+
+        // Until the background batch flushes, a crash keeps the Fsync commit
+        // but may lose the Async one
+        let recovered = storage.crash();
+        assert!(recovered.read_state(&record_id("critical")).unwrap().is_some());
+        assert!(recovered.read_state(&record_id("scratch")).unwrap().is_none());
+
+        sm.flush_deferred().unwrap();
+        assert!(storage.crash().read_state(&record_id("scratch")).unwrap().is_some());
+
+        // Refused up front by disk-backed storage; the transaction stays open
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "ram".to_string(), serde_json::json!({})).unwrap();
+        let err = sm.commit_with_durability(&txn_id, Some(Durability::Memory)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::UnsupportedDurability { .. })));
+        assert!(sm.get_state("default", "agent-1", "ram").unwrap().is_none());
+        sm.commit(&txn_id).unwrap();
+        assert!(storage.crash().read_state(&record_id("ram")).unwrap().is_some());
+
+        // In-memory storage takes any durability
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "ram".to_string(), serde_json::json!({})).unwrap();
+        sm.commit_with_durability(&txn_id, Some(Durability::Memory)).unwrap();
+    }
+
+    #[test]
+    fn test_sync_makes_async_commits_durable() {
+        let storage = Arc::new(CrashStorage::default());
+        let sm = StateMachine::new(storage.clone());
+        assert_eq!(sm.sync().unwrap(), 0);
+        let commit = |key: &str| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({})).unwrap();
+            sm.commit_with_durability(&txn_id, Some(Durability::Async)).unwrap().commit_ts
+        };
+        let record_id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+
+        commit("draft-1");
+        let last = commit("draft-2");
+        assert!(storage.crash().read_state(&record_id("draft-1")).unwrap().is_none());
+
+        // Everything acknowledged before the barrier survives a crash after it
+        assert_eq!(sm.sync().unwrap(), last);
+        commit("draft-3");
+        let recovered = storage.crash();
+        assert!(recovered.read_state(&record_id("draft-1")).unwrap().is_some());
+        assert!(recovered.read_state(&record_id("draft-2")).unwrap().is_some());
+        assert!(recovered.read_state(&record_id("draft-3")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rename() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "draft:x".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "final:y".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Successful move: destination gets the value, source is tombstoned
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.rename(&txn_id, "default".to_string(), "agent-1".to_string(), "draft:x".to_string(), "final:x".to_string(), false).unwrap();
+        let result = sm.commit(&txn_id).unwrap();
+        assert_eq!(result.changed.len(), 2);
+        let moved = sm.get_state("default", "agent-1", "final:x").unwrap().unwrap();
+        assert_eq!(moved.value.unwrap()["value"], 1);
+        assert_eq!(moved.commit_ts, result.commit_ts);
+        assert!(sm.get_state("default", "agent-1", "draft:x").unwrap().unwrap().deleted);
+
+        // Moving onto an existing key without overwrite is rejected and changes nothing
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.rename(&txn_id, "default".to_string(), "agent-1".to_string(), "final:x".to_string(), "final:y".to_string(), false).unwrap();
+        let err = sm.commit(&txn_id).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::KeyExists { key, .. }) if key == "final:y"));
+        assert!(!sm.get_state("default", "agent-1", "final:x").unwrap().unwrap().deleted);
+        assert_eq!(sm.get_state("default", "agent-1", "final:y").unwrap().unwrap().value.unwrap()["value"], 2);
+
+        // With overwrite the destination is replaced
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.rename(&txn_id, "default".to_string(), "agent-1".to_string(), "final:x".to_string(), "final:y".to_string(), true).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let replaced = sm.get_state("default", "agent-1", "final:y").unwrap().unwrap();
+        assert_eq!(replaced.value.unwrap()["value"], 1);
+        assert_eq!(replaced.version, 2);
+        assert!(sm.get_state("default", "agent-1", "final:x").unwrap().unwrap().deleted);
+
+        // A missing source fails the commit
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.rename(&txn_id, "default".to_string(), "agent-1".to_string(), "draft:x".to_string(), "final:z".to_string(), false).unwrap();
+        let err = sm.commit(&txn_id).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::KeyNotFound { .. })));
+    }
+
+    #[test]
+    fn test_transaction_limits() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage).with_transaction_limits(TransactionLimits {
+            max_transaction_bytes: 1024,
+            max_ops_per_transaction: 3,
+            max_json_depth: 3,
+        });
+
+        // Staging past the byte limit is rejected; earlier operations stay staged
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "small".to_string(), serde_json::json!({"value": 1})).unwrap();
+        let err = sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "big".to_string(), serde_json::json!({"blob": "x".repeat(2048)})).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::TransactionTooLarge { .. })));
+        assert_eq!(sm.list_open_transactions()[0].staged_ops, 1);
+
+        // The rejected write didn't count against the budget; smaller writes still fit
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "small2".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.abort(&txn_id).unwrap();
+        assert!(sm.get_state("default", "agent-1", "small").unwrap().is_none());
+
+        // Values may nest up to max_json_depth objects and arrays
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "nested".to_string(), serde_json::json!({"a": {"b": {"c": 1}}})).unwrap();
+        let err = sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "deeper".to_string(), serde_json::json!({"a": {"b": {"c": [1]}}})).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::ValueTooDeep { limit: 3 })));
+        let err = sm.get_or_create(&txn_id, "default".to_string(), "agent-1".to_string(), "deeper".to_string(), serde_json::json!([[[[]]]])).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::ValueTooDeep { .. })));
+        sm.commit(&txn_id).unwrap();
+
+        // The op-count cap applies to every kind of operation
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for key in ["a", "b", "c"] {
+            sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string()).unwrap();
+        }
+        let err = sm.touch(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::TransactionTooLarge { .. })));
+        sm.commit(&txn_id).unwrap();
+    }
+
+    #[test]
+    fn test_replicated_events_converge() {
+        struct Recorder(std::sync::Mutex<Vec<EventLogEntry>>);
+
+        impl ReplicationSink for Recorder {
+            fn ship(&self, event: &EventLogEntry) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        let recorder = Arc::new(Recorder(std::sync::Mutex::new(Vec::new())));
+        let primary = StateMachine::new(Arc::new(InMemoryStorage::new())).with_replication_sink(recorder.clone());
+        let standby = StateMachine::new(Arc::new(InMemoryStorage::new()));
+
+        for i in 0..3 {
+            let txn_id = primary.begin_transaction(None).unwrap();
+            primary.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": i})).unwrap();
+            primary.write(&txn_id, "default".to_string(), "agent-1".to_string(), format!("key{}", i + 2), serde_json::json!(i)).unwrap();
+            primary.commit(&txn_id).unwrap();
+        }
+        let txn_id = primary.begin_transaction(None).unwrap();
+        primary.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key2".to_string()).unwrap();
+        primary.commit(&txn_id).unwrap();
+
+        // Aborted and failed transactions ship nothing
+        let txn_id = primary.begin_transaction(None).unwrap();
+        primary.touch(&txn_id, "default".to_string(), "agent-1".to_string(), "missing".to_string()).unwrap();
+        assert!(primary.commit(&txn_id).is_err());
+
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(events.len(), 4);
+        for event in events.iter().cloned() {
+            assert!(standby.apply_replicated_event(event).unwrap());
+        }
+        // Redelivery is a no-op
+        assert!(!standby.apply_replicated_event(events[0].clone()).unwrap());
+
+        for key in ["key1", "key2", "key3", "key4"] {
+            let expected = primary.get_state("default", "agent-1", key).unwrap().unwrap();
+            let actual = standby.get_state("default", "agent-1", key).unwrap().unwrap();
+            assert_eq!(actual.value, expected.value);
+            assert_eq!(actual.version, expected.version);
+            assert_eq!(actual.commit_ts, expected.commit_ts);
+            assert_eq!(actual.deleted, expected.deleted);
+        }
+        assert_eq!(standby.replay("default", "agent-1", None, None).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_touch() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 1})).unwrap();
+        let first_ts = sm.commit(&txn_id).unwrap().commit_ts;
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.touch(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string()).unwrap();
+        let touch_ts = sm.commit(&txn_id).unwrap().commit_ts;
+
+        // Same value, newer version and commit_ts
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert_eq!(state.value.unwrap(), serde_json::json!({"value": 1}));
+        assert_eq!(state.version, 2);
+        assert_eq!(state.commit_ts, touch_ts);
+        assert!(touch_ts > first_ts);
+
+        // Touching a missing key fails
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.touch(&txn_id, "default".to_string(), "agent-1".to_string(), "missing".to_string()).unwrap();
+        let err = sm.commit(&txn_id).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::KeyNotFound { .. })));
+
+        // So does touching a key deleted earlier in the same transaction
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string()).unwrap();
+        sm.touch(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string()).unwrap();
+        assert!(sm.commit(&txn_id).is_err());
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert!(!state.deleted);
+        assert_eq!(state.version, 2);
+    }
+
+    #[test]
+    fn test_undelete() {
+        let commit = |sm: &StateMachine, stage: &dyn Fn(&str) -> Result<()>| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            stage(&txn_id).unwrap();
+            sm.commit(&txn_id)
+        };
+        let write = |sm: &StateMachine, key: &str, value: serde_json::Value| {
+            commit(sm, &|txn_id| sm.write(txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value.clone())).unwrap();
+        };
+        let delete = |sm: &StateMachine, key: &str| {
+            commit(sm, &|txn_id| sm.delete(txn_id, "default".to_string(), "agent-1".to_string(), key.to_string())).unwrap();
+        };
+        let undelete = |sm: &StateMachine, key: &str| {
+            commit(sm, &|txn_id| sm.undelete(txn_id, "default".to_string(), "agent-1".to_string(), key.to_string()))
+        };
+
+        // Within the grace period the pre-delete value comes back as a new version
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new())).with_delete_grace(Duration::from_secs(3600));
+        write(&sm, "key1", serde_json::json!({"n": 1}));
+        write(&sm, "key1", serde_json::json!({"n": 2}));
+        delete(&sm, "key1");
+        let tombstone = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert!(tombstone.deleted && tombstone.purge_after.is_some());
+
+        // Compaction keeps the tombstone and the value it hides
+        let stats = sm.compact(1).unwrap();
+        assert_eq!(stats.tombstones_removed, 0);
+        undelete(&sm, "key1").unwrap();
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert_eq!(state.value, Some(serde_json::json!({"n": 2})));
+        assert_eq!(state.version, 4);
+        assert_eq!(state.purge_after, None);
+
+        // Live and missing keys can't be undeleted
+        let err = undelete(&sm, "key1").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::KeyExists { .. })));
+        let err = undelete(&sm, "missing").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::KeyNotFound { .. })));
+
+        // Nor can one the transaction already changes
+        delete(&sm, "key1");
+        let err = commit(&sm, &|txn_id| {
+            sm.write(txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"n": 3}))?;
+            sm.undelete(txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string())
+        }).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::UndeleteAfterChange { .. })));
+        assert!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().deleted);
+
+        // Once the grace period is over, undelete fails and compaction purges
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new())).with_delete_grace(Duration::from_millis(20));
+        write(&sm, "key1", serde_json::json!({"n": 1}));
+        delete(&sm, "key1");
+        std::thread::sleep(Duration::from_millis(50));
+        let err = undelete(&sm, "key1").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::GracePeriodExpired { .. })));
+        assert_eq!(sm.compact(1).unwrap().tombstones_removed, 1);
+        assert!(sm.get_state("default", "agent-1", "key1").unwrap().is_none());
+
+        // Without a grace period deletes are final
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        write(&sm, "key1", serde_json::json!({"n": 1}));
+        delete(&sm, "key1");
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().purge_after, None);
+        let err = undelete(&sm, "key1").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::GracePeriodExpired { .. })));
+    }
+
+    #[test]
+    fn test_list_keys_at() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string(), serde_json::json!(1)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "b".to_string(), serde_json::json!(2)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-2".to_string(), "other".to_string(), serde_json::json!(3)).unwrap();
+        let ts = sm.commit(&txn_id).unwrap().commit_ts;
+
+        // Delete "a" and add "c"
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string()).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "c".to_string(), serde_json::json!(4)).unwrap();
+        let now = sm.commit(&txn_id).unwrap().commit_ts;
+
+        assert_eq!(sm.list_keys_at("default", "agent-1", ts, Deadline::NONE).unwrap(), vec!["a", "b"]);
+        assert_eq!(sm.list_keys_at("default", "agent-1", ts - 1, Deadline::NONE).unwrap(), Vec::<String>::new());
+
+        // At the current watermark it agrees with list_keys
+        let mut current = sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap();
+        current.sort();
+        assert_eq!(current, vec!["b", "c"]);
+        assert_eq!(sm.list_keys_at("default", "agent-1", now, Deadline::NONE).unwrap(), current);
+    }
+
+    #[test]
+    fn test_exists() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        assert!(!sm.exists("default", "agent-1", "key1").unwrap());
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"blob": "x".repeat(1024 * 1024)})).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert!(sm.exists("default", "agent-1", "key1").unwrap());
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert!(!sm.exists("default", "agent-1", "key1").unwrap());
+    }
+
+    #[test]
+    fn test_version_history() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        for i in 1..=5 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": i})).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        let history = sm.get_version_history("default", "agent-1", "key1", 3).unwrap();
+        let versions: Vec<Version> = history.iter().map(|r| r.version).collect();
+        assert_eq!(versions, vec![5, 4, 3]);
+        assert_eq!(history[0].value.as_ref().unwrap()["value"], 5);
+        assert_eq!(history[2].value.as_ref().unwrap()["value"], 3);
+        assert!(history.windows(2).all(|w| w[0].commit_ts > w[1].commit_ts));
+
+        // Fewer versions than the limit, and a key that doesn't exist
+        assert_eq!(sm.get_version_history("default", "agent-1", "key1", 10).unwrap().len(), 5);
+        assert!(sm.get_version_history("default", "agent-1", "missing", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_commit_reports_changed_records() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "b".to_string(), serde_json::json!(1)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string(), serde_json::json!(2)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "b".to_string(), serde_json::json!(3)).unwrap();
+        sm.delete(&txn_id, "other".to_string(), "agent-2".to_string(), "c".to_string()).unwrap();
+        let result = sm.commit(&txn_id).unwrap();
+
+        let record_id = |ns: &str, agent: &str, key: &str| RecordId::new(ns.to_string(), agent.to_string(), key.to_string());
+        assert_eq!(result.changed, vec![
+            record_id("default", "agent-1", "b"),
+            record_id("default", "agent-1", "a"),
+            record_id("other", "agent-2", "c"),
+        ]);
+        // b was written twice, so it moves two versions
+        assert_eq!(result.versions, vec![(0, 2), (0, 1), (0, 1)]);
+        assert_eq!(sm.get_state("default", "agent-1", "b").unwrap().unwrap().commit_ts, result.commit_ts);
+
+        // An empty transaction changes nothing
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert!(sm.commit(&txn_id).unwrap().changed.is_empty());
+    }
+
+    #[test]
+    fn test_versioning() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Write v1
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Write v2
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Read at version 1
+        let state_v1 = sm.get_state_at_version("default", "agent-1", "key1", 1).unwrap();
+        assert_eq!(state_v1.unwrap().value.unwrap()["value"], 1);
+
+        // Read at version 2
+        let state_v2 = sm.get_state_at_version("default", "agent-1", "key1", 2).unwrap();
+        assert_eq!(state_v2.unwrap().value.unwrap()["value"], 2);
+
+        // Read latest
+        let state_latest = sm.get_state("default", "agent-1", "key1").unwrap();
+        assert_eq!(state_latest.unwrap().value.unwrap()["value"], 2);
+    }
+
+    #[test]
+    fn test_read_after_write() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Write
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "counter".to_string(), serde_json::json!(1)).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // Read should immediately see the value
+        let state = sm.get_state("default", "agent-1", "counter").unwrap();
+        assert!(state.is_some());
+        assert_eq!(state.unwrap().value.unwrap(), serde_json::json!(1));
+    }
+
+    #[test]
+    fn test_aborted_tx_has_no_effect() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Begin transaction
+        let txn_id = sm.begin_transaction(None).unwrap();
+        
+        // Write
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "temp".to_string(), serde_json::json!(42)).unwrap();
+        
+        // Abort
+        sm.abort(&txn_id).unwrap();
+
+        // Read should return None (no state)
+        let state = sm.get_state("default", "agent-1", "temp").unwrap();
+        assert!(state.is_none());
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write = |txn_id: &str, key: &str| sm.write(txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(key));
+        let not_found = |result: Result<usize>| matches!(result.unwrap_err().downcast::<StatehouseError>().unwrap(), StatehouseError::SavepointNotFound { .. });
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        write(&txn_id, "a").unwrap();
+        let first = sm.savepoint(&txn_id).unwrap();
+        write(&txn_id, "b").unwrap();
+        let second = sm.savepoint(&txn_id).unwrap();
+        write(&txn_id, "c").unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string()).unwrap();
+
+        // Rolling back to the first releases the second, but keeps the first
+        assert_eq!(sm.rollback_to(&txn_id, first).unwrap(), 3);
+        assert!(not_found(sm.rollback_to(&txn_id, second)));
+        write(&txn_id, "d").unwrap();
+        assert_eq!(sm.rollback_to(&txn_id, first).unwrap(), 1);
+        assert_eq!(sm.rollback_to(&txn_id, first).unwrap(), 0);
+
+        // A released savepoint is gone, along with later ones
+        let third = sm.savepoint(&txn_id).unwrap();
+        let fourth = sm.savepoint(&txn_id).unwrap();
+        sm.release_savepoint(&txn_id, third).unwrap();
+        assert!(not_found(sm.rollback_to(&txn_id, third)));
+        assert!(not_found(sm.rollback_to(&txn_id, fourth)));
+        write(&txn_id, "e").unwrap();
+
+        // Only what was staged before the savepoint, and after the last rollback, commits
+        let result = sm.commit(&txn_id).unwrap();
+        let keys: Vec<&str> = result.changed.iter().map(|id| id.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "e"]);
+        assert_eq!(sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap(), vec!["a", "e"]);
+        assert!(sm.savepoint(&txn_id).is_err());
+
+        // Staging limits count only what is still staged
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()))
+            .with_transaction_limits(TransactionLimits { max_ops_per_transaction: 2, ..TransactionLimits::default() });
+        let txn_id = sm.begin_transaction(None).unwrap();
+        let savepoint = sm.savepoint(&txn_id).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string(), serde_json::json!(1)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "b".to_string(), serde_json::json!(1)).unwrap();
+        sm.rollback_to(&txn_id, savepoint).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "c".to_string(), serde_json::json!(1)).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "d".to_string(), serde_json::json!(1)).unwrap();
+    }
+
+    #[test]
+    fn test_abort_idle_transactions_under_memory_pressure() {
+        use crate::admission::IdleAbort;
+
+        let abort_idle = IdleAbort { high_water_bytes: 2000, min_idle: Duration::from_millis(50) };
+        let limits = AdmissionLimits { abort_idle: Some(abort_idle), ..AdmissionLimits::default() };
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new())).with_admission_limits(limits);
+        let write = |txn_id: &str, key: &str, value: serde_json::Value| {
+            sm.write(txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value)
+        };
+
+        // A large transaction still staging is left alone
+        let large = sm.begin_transaction(None).unwrap();
+        write(&large, "large-1", serde_json::json!("x".repeat(1500))).unwrap();
+        write(&large, "large-2", serde_json::json!("x".repeat(1500))).unwrap();
+        let small_idle = sm.begin_transaction(None).unwrap();
+        write(&small_idle, "small-idle", serde_json::json!(1)).unwrap();
+        assert!(sm.backlog().staged_bytes > abort_idle.high_water_bytes);
+        assert_eq!(sm.list_open_transactions().len(), 2);
+
+        // Once it goes idle, it's the one aborted to make room
+        std::thread::sleep(Duration::from_millis(60));
+        let small_active = sm.begin_transaction(None).unwrap();
+        write(&small_active, "small-active", serde_json::json!(2)).unwrap();
+        let backlog = sm.backlog();
+        assert_eq!((backlog.open_transactions, backlog.idle_aborted), (2, 1));
+        assert!(backlog.staged_bytes <= abort_idle.high_water_bytes);
+
+        assert!(write(&large, "large-3", serde_json::json!(3)).is_err());
+        assert!(sm.commit(&large).is_err());
+        sm.commit(&small_idle).unwrap();
+        sm.commit(&small_active).unwrap();
+        assert!(sm.get_state("default", "agent-1", "large-1").unwrap().is_none());
+        assert_eq!(sm.get_state("default", "agent-1", "small-idle").unwrap().unwrap().value, Some(serde_json::json!(1)));
+    }
+
+    #[test]
+    fn test_write_with_seq_ignores_replays() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write = |txn_id: &str, key: &str, value: i64, seq: u64| {
+            sm.write_with_seq(txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(value), Labels::new(), seq)
+        };
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert!(write(&txn_id, "a", 1, 1).unwrap());
+        assert!(write(&txn_id, "b", 1, 2).unwrap());
+        // Retries of both, one with a changed value, are acked but ignored
+        assert!(!write(&txn_id, "a", 1, 1).unwrap());
+        assert!(!write(&txn_id, "b", 2, 2).unwrap());
+        // Seqs may skip ahead, and writes without one aren't checked
+        assert!(write(&txn_id, "c", 1, 5).unwrap());
+        assert!(!write(&txn_id, "a", 4, 4).unwrap());
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "d".to_string(), serde_json::json!(1)).unwrap();
+
+        let result = sm.commit(&txn_id).unwrap();
+        assert_eq!(result.changed.len(), 4);
+        for key in ["a", "b"] {
+            assert_eq!(sm.get_version_history("default", "agent-1", key, 10).unwrap().len(), 1);
+            assert_eq!(sm.get_state("default", "agent-1", key).unwrap().unwrap().value, Some(serde_json::json!(1)));
+        }
+
+        // The high-water mark is per transaction
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert!(write(&txn_id, "a", 6, 1).unwrap());
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write = |txn_id: &str, value: i64| sm.write(txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(value));
+        let error = |result: Result<CommitResult>| result.unwrap_err().downcast::<StatehouseError>().unwrap();
+
+        // Prepare, then finalize
+        let txn_id = sm.begin_transaction(None).unwrap();
+        write(&txn_id, 1).unwrap();
+        sm.prepare_commit(&txn_id, None).unwrap();
+        sm.prepare_commit(&txn_id, None).unwrap();
+        assert!(matches!(sm.commit_status(&txn_id), CommitStatus::Prepared));
+        assert!(matches!(write(&txn_id, 2).unwrap_err().downcast::<StatehouseError>().unwrap(), StatehouseError::TransactionPrepared { .. }));
+        assert!(matches!(error(sm.commit(&txn_id)), StatehouseError::TransactionPrepared { .. }));
+
+        // Its key is held until it is finalized
+        let other = sm.begin_transaction(None).unwrap();
+        write(&other, 3).unwrap();
+        assert!(matches!(error(sm.commit(&other)), StatehouseError::KeyPrepared { txn_id: holder, .. } if holder == txn_id));
+
+        let committed = sm.finalize_commit(&txn_id).unwrap();
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().value, Some(serde_json::json!(1)));
+        assert!(matches!(sm.commit_status(&txn_id), CommitStatus::Committed(result) if result.commit_ts == committed.commit_ts));
+
+        // Finalizing again returns the same commit without applying it twice
+        let retried = sm.finalize_commit(&txn_id).unwrap();
+        assert_eq!(retried.commit_ts, committed.commit_ts);
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().version, 1);
+        assert!(sm.cancel_commit(&txn_id).is_err());
+
+        // Prepare, then cancel
+        let txn_id = sm.begin_transaction(None).unwrap();
+        write(&txn_id, 4).unwrap();
+        assert!(matches!(error(sm.finalize_commit(&txn_id)), StatehouseError::TransactionNotPrepared { .. }));
+        sm.prepare_commit(&txn_id, None).unwrap();
+        sm.cancel_commit(&txn_id).unwrap();
+        sm.cancel_commit(&txn_id).unwrap();
+        assert!(matches!(sm.commit_status(&txn_id), CommitStatus::Cancelled));
+        assert!(matches!(error(sm.finalize_commit(&txn_id)), StatehouseError::TransactionNotPrepared { .. }));
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().value, Some(serde_json::json!(1)));
+
+        // A transaction that would fail to commit fails to prepare, and stays open
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete_if_version(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), 7).unwrap();
+        assert!(matches!(sm.prepare_commit(&txn_id, None).unwrap_err().downcast::<StatehouseError>().unwrap(), StatehouseError::Conflict { .. }));
+        assert!(matches!(sm.commit_status(&txn_id), CommitStatus::Open));
+    }
+
+    #[test]
+    fn test_concurrent_commits_serialize() {
+        use std::thread;
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = Arc::new(StateMachine::new(storage));
+
+        let mut handles = vec![];
+
+        // Spawn multiple threads that each commit a transaction
+        for i in 0..10 {
+            let sm_clone = sm.clone();
+            let handle = thread::spawn(move || {
+                let txn_id = sm_clone.begin_transaction(None).unwrap();
+                sm_clone.write(
+                    &txn_id,
+                    "default".to_string(),
+                    "agent-1".to_string(),
+                    format!("key{}", i),
+                    serde_json::json!(i),
+                ).unwrap();
+                sm_clone.commit(&txn_id).unwrap();
+            });
+            handles.push(handle);
+        }
+
+        // Wait for all threads to complete
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Verify all writes succeeded
+        for i in 0..10 {
+            let state = sm.get_state("default", "agent-1", &format!("key{}", i)).unwrap();
+            assert!(state.is_some());
+            assert_eq!(state.unwrap().value.unwrap(), serde_json::json!(i));
+        }
+    }
+
+    #[test]
+    fn test_fair_commit_ordering() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = Arc::new(StateMachine::new(storage.clone()).with_commit_ordering(CommitOrdering::Fifo));
+        let stop = Arc::new(AtomicBool::new(false));
+        let queue = sm.commit_queue.as_ref().unwrap();
+
+        // Hold the writer until every committer is queued
+        let held = queue.enter(String::new);
+
+        // Small committers keep the writer busy the whole time
+        let committers = 8;
+        let small: Vec<_> = (0..committers)
+            .map(|i| {
+                let (sm, stop) = (sm.clone(), stop.clone());
+                thread::spawn(move || {
+                    let mut commits = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        let txn_id = sm.begin_transaction(None).unwrap();
+                        sm.write(&txn_id, "small".to_string(), format!("agent-{}", i), "counter".to_string(), serde_json::json!(commits)).unwrap();
+                        sm.commit(&txn_id).unwrap();
+                        commits += 1;
+                    }
+                    commits
+                })
+            })
+            .collect();
+        while queue.waiting() < committers {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let large = {
+            let sm = sm.clone();
+            thread::spawn(move || {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                for i in 0..2000 {
+                    sm.write(&txn_id, "large".to_string(), "agent-1".to_string(), format!("key-{}", i), serde_json::json!({"i": i})).unwrap();
+                }
+                sm.commit(&txn_id).unwrap().commit_ts
+            })
+        };
+        while queue.waiting() < committers + 1 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let before = storage.current_commit_ts().unwrap();
+        drop(held);
+        let commit_ts = large.join().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        let small_commits: u64 = small.into_iter().map(|h| h.join().unwrap()).sum();
+
+        // Only the commits queued ahead of it went first; the committers'
+        // next commits queued behind it
+        assert_eq!(commit_ts, before + committers as u64 + 1);
+        assert!(small_commits >= committers as u64);
+        assert_eq!(sm.list_keys("large", "agent-1", false, Deadline::NONE).unwrap().len(), 2000);
+    }
+
+    #[test]
+    fn test_commands_run_in_arrival_order() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let txn_ids: Vec<_> = (0..5)
+            .map(|i| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(i)).unwrap();
+                txn_id
+            })
+            .collect();
+
+        // While another caller is acting as the writer, commands queue up
+        let writer = sm.commands.writer.lock().unwrap();
+        let replies: Vec<_> = txn_ids
+            .iter()
+            .rev()
+            .map(|txn_id| {
+                let (reply, replied) = mpsc::channel();
+                sm.commands.sender.send((Command::Commit { txn_id: txn_id.clone(), durability: None }, reply)).unwrap();
+                replied
+            })
+            .collect();
+        drop(writer);
+
+        // The next caller to become the writer runs them, in the order sent
+        sm.abort("unknown").unwrap();
+        let commit_ts: Vec<_> = replies
+            .into_iter()
+            .map(|replied| match replied.recv().unwrap().unwrap() {
+                Reply::Committed(result) => result.commit_ts,
+                reply => panic!("Unexpected reply {:?}", reply),
+            })
+            .collect();
+        assert_eq!(commit_ts, vec![1, 2, 3, 4, 5]);
+        let state = sm.get_state("default", "agent-1", "key1").unwrap().unwrap();
+        assert_eq!(state.value.unwrap(), serde_json::json!(0));
+        assert_eq!(state.version, 5);
+    }
+
+    #[test]
+    fn test_purges_and_savepoints_go_through_the_writer() {
+        use std::thread;
+
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(1)).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let open_txn = sm.begin_transaction(None).unwrap();
+
+        // Neither can run while another caller is acting as the writer
+        let writer = sm.commands.writer.lock().unwrap();
+        let purge = {
+            let sm = sm.clone();
+            thread::spawn(move || sm.purge_key("default", "agent-1", "key1").unwrap())
+        };
+        let savepoint = {
+            let sm = sm.clone();
+            thread::spawn(move || sm.savepoint(&open_txn).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!purge.is_finished());
+        assert!(!savepoint.is_finished());
+        assert!(sm.get_state("default", "agent-1", "key1").unwrap().is_some());
+        drop(writer);
+
+        assert_eq!(purge.join().unwrap().versions_removed, 1);
+        assert_eq!(savepoint.join().unwrap(), 1);
+        assert!(sm.get_state("default", "agent-1", "key1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_history_gc_and_log_trim_go_through_the_writer() {
+        use std::thread;
+
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        for i in 0..3 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(i)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        let writer = sm.commands.writer.lock().unwrap();
+        let gc = {
+            let sm = sm.clone();
+            thread::spawn(move || sm.gc_versions(3, 1).unwrap())
+        };
+        let trim = {
+            let sm = sm.clone();
+            thread::spawn(move || sm.trim_events(3).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!gc.is_finished());
+        assert!(!trim.is_finished());
+        assert_eq!(sm.get_version_history("default", "agent-1", "key1", 10).unwrap().len(), 3);
+        drop(writer);
+
+        assert_eq!(gc.join().unwrap().versions_removed, 2);
+        assert_eq!(trim.join().unwrap().events_removed, 3);
+    }
+
+    #[test]
+    fn test_snapshot_batch_get_is_consistent() {
+        use std::thread;
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = Arc::new(StateMachine::new(storage));
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for key in ["a", "b"] {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({"v": 1})).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        let (read_txn, read_ts) = sm.begin_read_transaction(None).unwrap();
+
+        // Concurrently update both keys and create a new one
+        let writer = sm.clone();
+        thread::spawn(move || {
+            let txn_id = writer.begin_transaction(None).unwrap();
+            for key in ["a", "b", "c"] {
+                writer.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({"v": 2})).unwrap();
+            }
+            writer.commit(&txn_id).unwrap();
+        }).join().unwrap();
+
+        let refs: Vec<RecordId> = ["a", "b", "c"]
+            .iter()
+            .map(|k| RecordId::new("default".to_string(), "agent-1".to_string(), k.to_string()))
+            .collect();
+
+        // Read twice so the second pass is served from the per-txn cache
+        for _ in 0..2 {
+            let records = sm.get_state_batch_in_snapshot(&read_txn, &refs).unwrap();
+            assert_eq!(records.len(), 3);
+            for record in &records[..2] {
+                let record = record.as_ref().unwrap();
+                assert_eq!(record.value.as_ref().unwrap()["v"], 1);
+                assert!(record.commit_ts <= read_ts);
+            }
+            assert!(records[2].is_none());
+        }
+
+        // Latest reads see the update
+        let latest = sm.get_state("default", "agent-1", "a").unwrap().unwrap();
+        assert_eq!(latest.value.unwrap()["v"], 2);
+
+        // Ending the read transaction makes it unusable
+        sm.abort(&read_txn).unwrap();
+        assert!(sm.get_state_batch_in_snapshot(&read_txn, &refs).is_err());
+    }
+
+    #[test]
+    fn test_transaction_timeout() {
+        use std::time::Duration;
+        use std::thread;
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Begin transaction with very short timeout
+        let txn_id = sm.begin_transaction(Some(100)).unwrap(); // 100ms timeout
+
+        // Wait for timeout
+        thread::sleep(Duration::from_millis(150));
+
+        // Try to commit - should fail
+        let result = sm.commit(&txn_id);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("expired"));
+    }
+
+    #[test]
+    fn test_monitor_flags_old_transactions() {
+        use std::thread;
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Short-lived transaction with one staged write
+        let old_txn = sm.begin_transaction(Some(100)).unwrap();
+        sm.write(&old_txn, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(1)).unwrap();
+
+        // Fresh transaction with the default timeout
+        let fresh_txn = sm.begin_transaction(None).unwrap();
+
+        thread::sleep(Duration::from_millis(60));
+
+        let report = sm.monitor_transactions(0.5);
+        assert_eq!(report.open, 2);
+        assert_eq!(report.buckets[0], 2);
+        assert!(report.oldest.unwrap() >= Duration::from_millis(60));
+
+        assert_eq!(report.stuck.len(), 1);
+        assert_eq!(report.stuck[0].txn_id, old_txn);
+        assert_eq!(report.stuck[0].staged_ops, 1);
+        assert!(report.stuck.iter().all(|t| t.txn_id != fresh_txn));
+        assert_eq!(report.warned, 1);
+
+        // Still stuck at the next tick, but already warned about
+        let report = sm.monitor_transactions(0.5);
+        assert_eq!(report.stuck.len(), 1);
+        assert_eq!(report.warned, 0);
+
+        // A new transaction sharing the id once the old one is gone is warned about afresh
+        sm.abort(&old_txn).unwrap();
+        assert_eq!(sm.monitor_transactions(0.5).stuck.len(), 0);
+        sm.begin_transaction_with_id(Some(old_txn.clone()), Some(100)).unwrap();
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(sm.monitor_transactions(0.5).warned, 1);
+    }
+
+    #[test]
+    fn test_list_open_transactions() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        let txn_a = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_a, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(1)).unwrap();
+        sm.write(&txn_a, "other".to_string(), "agent-2".to_string(), "key2".to_string(), serde_json::json!(2)).unwrap();
+        sm.delete(&txn_a, "default".to_string(), "agent-1".to_string(), "key3".to_string()).unwrap();
+
+        let txn_b = sm.begin_transaction(None).unwrap();
+
+        let listed = sm.list_open_transactions();
+        assert_eq!(listed.len(), 2);
+        let a = listed.iter().find(|t| t.txn_id == txn_a).unwrap();
+        assert_eq!(a.staged_ops, 3);
+        assert_eq!(a.agents.len(), 2);
+        assert!(a.agents.contains(&("other".to_string(), "agent-2".to_string())));
+        let b = listed.iter().find(|t| t.txn_id == txn_b).unwrap();
+        assert_eq!(b.staged_ops, 0);
+        assert!(b.agents.is_empty());
+
+        // Committed and aborted transactions drop out of the list
+        sm.commit(&txn_a).unwrap();
+        sm.abort(&txn_b).unwrap();
+        assert!(sm.list_open_transactions().is_empty());
+    }
+
+    #[test]
+    fn test_list_keys_after_operations() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage);
+
+        // Write multiple keys
+        for i in 1..=5 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(
+                &txn_id,
+                "default".to_string(),
+                "agent-1".to_string(),
+                format!("key{}", i),
+                serde_json::json!(i),
+            ).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        // Delete one key
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key3".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        // List should show 4 keys (5 - 1 deleted)
+        let keys = sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap();
+        assert_eq!(keys.len(), 4);
+        assert!(!keys.contains(&"key3".to_string()));
+    }
+
+    #[test]
+    fn test_delete_batch() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(key)).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        // Mixed with a plain write in the same transaction
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert_eq!(sm.delete_batch(&txn_id, vec![id("a"), id("b"), id("c")]).unwrap(), 3);
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "e".to_string(), serde_json::json!("e")).unwrap();
+        let result = sm.commit(&txn_id).unwrap();
+
+        for key in ["a", "b", "c"] {
+            let record = sm.get_state("default", "agent-1", key).unwrap().unwrap();
+            assert!(record.deleted, "{}", key);
+            assert_eq!(record.commit_ts, result.commit_ts);
+        }
+        let mut keys = sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["d", "e"]);
+
+        assert!(sm.delete_batch("no-such-txn", vec![id("d")]).is_err());
+    }
+
+    #[test]
+    fn test_swap() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "active".to_string(), serde_json::json!({"buffer": 1})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "standby".to_string(), serde_json::json!({"buffer": 2})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.swap(&txn_id, "default".to_string(), "agent-1".to_string(), "active".to_string(), "standby".to_string(), false).unwrap();
+        let result = sm.commit(&txn_id).unwrap();
+
+        let active = sm.get_state("default", "agent-1", "active").unwrap().unwrap();
+        let standby = sm.get_state("default", "agent-1", "standby").unwrap().unwrap();
+        assert_eq!(active.value, Some(serde_json::json!({"buffer": 2})));
+        assert_eq!(standby.value, Some(serde_json::json!({"buffer": 1})));
+        assert_eq!((active.version, standby.version), (2, 2));
+        assert_eq!((active.commit_ts, standby.commit_ts), (result.commit_ts, result.commit_ts));
+
+        // A missing key fails the commit unless it counts as null
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.swap(&txn_id, "default".to_string(), "agent-1".to_string(), "active".to_string(), "missing".to_string(), false).unwrap();
+        let err = sm.commit(&txn_id).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::KeyNotFound { key, .. }) if key == "missing"));
+        assert_eq!(sm.get_state("default", "agent-1", "active").unwrap().unwrap().version, 2);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.swap(&txn_id, "default".to_string(), "agent-1".to_string(), "active".to_string(), "missing".to_string(), true).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert_eq!(sm.get_state("default", "agent-1", "active").unwrap().unwrap().value, Some(serde_json::Value::Null));
+        assert_eq!(sm.get_state("default", "agent-1", "missing").unwrap().unwrap().value, Some(serde_json::json!({"buffer": 2})));
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        assert!(sm.swap(&txn_id, "default".to_string(), "agent-1".to_string(), "active".to_string(), "active".to_string(), false).is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let labels = |pairs: &[(&str, &str)]| -> Labels { pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() };
+        let write = |key: &str, pairs: &[(&str, &str)]| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write_with_labels(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(key), labels(pairs)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+        write("fact/1", &[("source", "llm"), ("confidence", "0.9")]);
+        write("fact/2", &[("source", "llm"), ("confidence", "0.5")]);
+        write("fact/3", &[("source", "user")]);
+        write("fact/4", &[]);
+
+        let record = sm.get_state("default", "agent-1", "fact/1").unwrap().unwrap();
+        assert_eq!(record.labels, labels(&[("source", "llm"), ("confidence", "0.9")]));
+
+        let scan = |pairs: &[(&str, &str)]| -> Vec<String> {
+            let mut keys: Vec<String> = sm.scan_prefix_with_labels("default", "agent-1", "fact/", &labels(pairs), false, Deadline::NONE).unwrap().into_iter().map(|r| r.key).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(scan(&[("source", "llm")]), vec!["fact/1", "fact/2"]);
+        assert_eq!(scan(&[("source", "llm"), ("confidence", "0.9")]), vec!["fact/1"]);
+        assert_eq!(scan(&[("source", "bot")]), Vec::<String>::new());
+        assert_eq!(scan(&[]).len(), 4);
+
+        // Labels are versioned with the value: a rewrite replaces them, and
+        // the old version keeps its own
+        write("fact/1", &[("source", "user")]);
+        assert_eq!(scan(&[("source", "llm")]), vec!["fact/2"]);
+        let old = sm.get_state_at_version("default", "agent-1", "fact/1", 1).unwrap().unwrap();
+        assert_eq!(old.labels["confidence"], "0.9");
+
+        // Carried along by operations that keep the value
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.touch(&txn_id, "default".to_string(), "agent-1".to_string(), "fact/2".to_string()).unwrap();
+        sm.rename(&txn_id, "default".to_string(), "agent-1".to_string(), "fact/3".to_string(), "fact/5".to_string(), false).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert_eq!(sm.get_state("default", "agent-1", "fact/2").unwrap().unwrap().labels["source"], "llm");
+        assert_eq!(sm.get_state("default", "agent-1", "fact/5").unwrap().unwrap().labels["source"], "user");
+        let event = sm.get_event(sm.storage.current_commit_ts().unwrap()).unwrap().unwrap();
+        assert!(event.operations.iter().any(|op| op.key == "fact/5" && op.labels["source"] == "user"));
+    }
+
+    #[test]
+    fn test_count_keys() {
+        use crate::storage::{RocksStorage, StorageConfig};
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = StorageConfig { data_dir: temp_dir.path().to_path_buf(), fsync_on_commit: false, ..StorageConfig::default() };
+        let rocks: Arc<dyn Storage> = Arc::new(RocksStorage::new(config).unwrap());
+        for storage in [rocks, Arc::new(InMemoryStorage::new()) as Arc<dyn Storage>] {
+            let sm = StateMachine::new(storage);
+            let txn_id = sm.begin_transaction(None).unwrap();
+            for key in ["task/1", "task/2", "task/3", "note/1", "tasks"] {
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(key)).unwrap();
+            }
+            sm.write(&txn_id, "default".to_string(), "agent-2".to_string(), "task/1".to_string(), serde_json::json!(1)).unwrap();
+            sm.commit(&txn_id).unwrap();
+
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "task/2".to_string()).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task/1".to_string(), serde_json::json!("again")).unwrap();
+            sm.commit(&txn_id).unwrap();
+
+            let count = |prefix| sm.count_keys("default", "agent-1", prefix).unwrap();
+            assert_eq!(count(None), sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap().len() as u64);
+            assert_eq!(count(None), 4);
+            assert_eq!(count(Some("task/")), 2);
+            assert_eq!(count(Some("task")), 3);
+            assert_eq!(count(Some("nope")), 0);
+            assert_eq!(sm.count_keys("default", "agent-2", None).unwrap(), 1);
+
+            // A tombstoned key counts again once it's rewritten
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task/2".to_string(), serde_json::json!("back")).unwrap();
+            sm.commit(&txn_id).unwrap();
+            assert_eq!(count(Some("task/")), 3);
+            assert_eq!(count(None), sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap().len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_agent_last_commit_ts() {
+        use crate::storage::{RocksStorage, StorageConfig};
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = StorageConfig { data_dir: temp_dir.path().to_path_buf(), fsync_on_commit: false, ..StorageConfig::default() };
+        let rocks: Arc<dyn Storage> = Arc::new(RocksStorage::new(config).unwrap());
+        for storage in [rocks, Arc::new(InMemoryStorage::new()) as Arc<dyn Storage>] {
+            let sm = StateMachine::new(storage);
+            let watermark = |agent_id: &str| sm.agent_last_commit_ts("default", agent_id).unwrap();
+            let commit = |agent_id: &str, delete: bool| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                if delete {
+                    sm.delete(&txn_id, "default".to_string(), agent_id.to_string(), "key".to_string()).unwrap();
+                } else {
+                    sm.write(&txn_id, "default".to_string(), agent_id.to_string(), "key".to_string(), serde_json::json!(1)).unwrap();
+                }
+                sm.commit(&txn_id).unwrap().commit_ts
+            };
+            assert_eq!(watermark("agent-1"), None);
+
+            // Advances with each commit touching the agent, deletes included
+            let ts = commit("agent-1", false);
+            assert_eq!(watermark("agent-1"), Some(ts));
+            let ts = commit("agent-1", true);
+            assert_eq!(watermark("agent-1"), Some(ts));
+
+            // Commits to other agents, or the same agent id in another namespace, leave it be
+            commit("agent-2", false);
+            commit("agent-1x", false);
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "other".to_string(), "agent-1".to_string(), "key".to_string(), serde_json::json!(1)).unwrap();
+            sm.commit(&txn_id).unwrap();
+            assert_eq!(watermark("agent-1"), Some(ts));
+            assert!(watermark("agent-2") < watermark("agent-1x"));
         }
-        
-        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage::InMemoryStorage;
+    #[test]
+    fn test_replay_limits() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let limits = ReplayLimits { max_span: Some(5), clamp_unbounded: false };
+        let sm = StateMachine::new(storage.clone()).with_replay_limits(limits);
+        for i in 0..20 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key".to_string(), serde_json::json!(i)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        // Within the span
+        assert_eq!(sm.replay("default", "agent-1", Some(11), Some(15)).unwrap().len(), 5);
+        assert_eq!(sm.replay("default", "agent-1", Some(16), None).unwrap().len(), 5);
+
+        // Too wide, bounded or not
+        for (start_ts, end_ts) in [(None, None), (Some(1), Some(10)), (Some(10), None)] {
+            let err = sm.replay("default", "agent-1", start_ts, end_ts).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<StatehouseError>(),
+                Some(StatehouseError::ReplaySpanTooLarge { limit: 5, .. })
+            ), "{:?}", (start_ts, end_ts));
+        }
+
+        // Clamping only applies to an open start
+        let sm = StateMachine::new(storage).with_replay_limits(ReplayLimits { clamp_unbounded: true, ..limits });
+        let events = sm.replay("default", "agent-1", None, None).unwrap();
+        assert_eq!(events.iter().map(|e| e.commit_ts).collect::<Vec<_>>(), vec![16, 17, 18, 19, 20]);
+        let events = sm.replay("default", "agent-1", None, Some(8)).unwrap();
+        assert_eq!(events.first().map(|e| e.commit_ts), Some(4));
+        assert!(sm.replay("default", "agent-1", Some(1), None).is_err());
+    }
 
     #[test]
-    fn test_transaction_lifecycle() {
+    fn test_replay_filtered() {
         let storage = Arc::new(InMemoryStorage::new());
         let sm = StateMachine::new(storage);
-
-        // Begin transaction
+        let agent = || ("default".to_string(), "agent-1".to_string());
+        for key in ["task/1", "task/2", "note/1"] {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            let (ns, agent_id) = agent();
+            sm.write(&txn_id, ns, agent_id, key.to_string(), serde_json::json!({"key": key})).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+        // One transaction mixing a write and two deletes
         let txn_id = sm.begin_transaction(None).unwrap();
+        let (ns, agent_id) = agent();
+        sm.write(&txn_id, ns.clone(), agent_id.clone(), "task/3".to_string(), serde_json::json!({})).unwrap();
+        sm.delete(&txn_id, ns.clone(), agent_id.clone(), "task/1".to_string()).unwrap();
+        sm.delete(&txn_id, ns, agent_id, "note/1".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
 
-        // Write
-        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 42})).unwrap();
+        let ops = |filter: ReplayFilter| -> Vec<(String, bool)> {
+            sm.replay_filtered("default", "agent-1", None, None, &filter, Deadline::NONE)
+                .unwrap()
+                .into_iter()
+                .flat_map(|event| event.operations)
+                .map(|op| (op.key, op.value.is_some()))
+                .collect()
+        };
 
-        // Commit
-        let commit_ts = sm.commit(&txn_id).unwrap();
-        assert!(commit_ts > 0);
+        let deletes = ops(ReplayFilter { include_writes: false, ..Default::default() });
+        assert_eq!(deletes, vec![("task/1".to_string(), false), ("note/1".to_string(), false)]);
 
-        // Read
-        let state = sm.get_state("default", "agent-1", "key1").unwrap();
-        assert!(state.is_some());
-        let record = state.unwrap();
-        assert_eq!(record.value.unwrap()["value"], 42);
+        let task_writes = ops(ReplayFilter { include_deletes: false, key_prefix: "task/".to_string(), ..Default::default() });
+        let keys: Vec<&str> = task_writes.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["task/1", "task/2", "task/3"]);
+
+        // Events left empty are dropped; the default filter matches replay
+        let none = ReplayFilter { include_writes: false, include_deletes: false, ..Default::default() };
+        assert!(sm.replay_filtered("default", "agent-1", None, None, &none, Deadline::NONE).unwrap().is_empty());
+        assert_eq!(ops(ReplayFilter::default()).len(), 6);
     }
 
     #[test]
-    fn test_delete() {
+    fn test_replay_determinism() {
         let storage = Arc::new(InMemoryStorage::new());
         let sm = StateMachine::new(storage);
 
-        // Write
-        let txn_id = sm.begin_transaction(None).unwrap();
-        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 42})).unwrap();
-        sm.commit(&txn_id).unwrap();
+        // Perform multiple transactions
+        for i in 1..=5 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(
+                &txn_id,
+                "default".to_string(),
+                "agent-1".to_string(),
+                format!("key{}", i),
+                serde_json::json!({"step": i}),
+            ).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
 
-        // Delete
-        let txn_id = sm.begin_transaction(None).unwrap();
-        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string()).unwrap();
-        sm.commit(&txn_id).unwrap();
+        // Replay events
+        let events = sm.replay("default", "agent-1", None, None).unwrap();
+        assert_eq!(events.len(), 5);
 
-        // Read should show deleted
-        let state = sm.get_state("default", "agent-1", "key1").unwrap();
-        assert!(state.is_some());
-        assert!(state.unwrap().deleted);
+        // Verify events are in order by commit_ts
+        for i in 1..events.len() {
+            assert!(events[i].commit_ts > events[i-1].commit_ts);
+        }
     }
 
     #[test]
-    fn test_versioning() {
+    fn test_create_snapshot() {
         let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage);
+        let sm = StateMachine::new(storage.clone());
+
+        // Write some data
+        for i in 1..=3 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(
+                &txn_id,
+                "default".to_string(),
+                "agent-1".to_string(),
+                format!("key{}", i),
+                serde_json::json!({"value": i}),
+            ).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        // Create snapshot
+        let snapshot = storage.create_snapshot().unwrap();
+        assert_eq!(snapshot.metadata.version, 1);
+        assert_eq!(snapshot.metadata.record_count, 3);
+        assert_eq!(snapshot.records.len(), 3);
+    }
+
+    #[test]
+    fn test_recover_from_stale_snapshot_keeps_versions() {
+        let storage = Arc::new(InMemoryStorage::new());
+        let sm = StateMachine::new(storage.clone());
 
-        // Write v1
         let txn_id = sm.begin_transaction(None).unwrap();
-        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(1)).unwrap();
         sm.commit(&txn_id).unwrap();
+        let stale = storage.create_snapshot().unwrap();
 
-        // Write v2
         let txn_id = sm.begin_transaction(None).unwrap();
-        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(2)).unwrap();
         sm.commit(&txn_id).unwrap();
 
-        // Read at version 1
-        let state_v1 = sm.get_state_at_version("default", "agent-1", "key1", 1).unwrap();
-        assert_eq!(state_v1.unwrap().value.unwrap()["value"], 1);
+        // Recovering from the older snapshot must not reissue version 2
+        sm.recover_from_snapshot(&stale).unwrap();
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(3)).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().version, 3);
+    }
 
-        // Read at version 2
-        let state_v2 = sm.get_state_at_version("default", "agent-1", "key1", 2).unwrap();
-        assert_eq!(state_v2.unwrap().value.unwrap()["value"], 2);
+    /// Storage that records the state machine's recovery status each time
+    /// recovery reads from it, and keeps the saved snapshot
+    #[derive(Default)]
+    struct RecoveryObserver {
+        inner: InMemoryStorage,
+        snapshot: Mutex<Option<Snapshot>>,
+        state_machine: std::sync::OnceLock<std::sync::Weak<StateMachine>>,
+        statuses: Mutex<Vec<String>>,
+    }
 
-        // Read latest
-        let state_latest = sm.get_state("default", "agent-1", "key1").unwrap();
-        assert_eq!(state_latest.unwrap().value.unwrap()["value"], 2);
+    impl RecoveryObserver {
+        fn observe(&self) {
+            if let Some(sm) = self.state_machine.get().and_then(std::sync::Weak::upgrade) {
+                self.statuses.lock().unwrap().push(sm.recovery_status().to_string());
+            }
+        }
+    }
+
+    impl Storage for RecoveryObserver {
+        fn health_check(&self) -> Result<()> { self.inner.health_check() }
+        fn write_state(&self, record: StateRecord) -> Result<()> { self.inner.write_state(record) }
+        fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> { self.inner.read_state(record_id) }
+        fn exists(&self, record_id: &RecordId) -> Result<bool> { self.inner.exists(record_id) }
+        fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> { self.inner.read_state_meta(record_id) }
+        fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> { self.inner.read_states(record_ids) }
+        fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> { self.inner.read_state_at_version(record_id, version) }
+        fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> { self.inner.read_version_history(record_id, limit) }
+        fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> { self.inner.read_state_as_of(record_id, as_of) }
+        fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> { self.inner.list_keys(namespace, agent_id, include_deleted, deadline) }
+        fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> { self.inner.count_keys(namespace, agent_id, prefix) }
+        fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> { self.inner.scan_namespace_prefix(namespace, prefix, limit) }
+        fn append_event(&self, event: EventLogEntry) -> Result<()> { self.inner.append_event(event) }
+        fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> { self.inner.replay_events(namespace, agent_id, start_ts, end_ts, deadline) }
+        fn next_commit_ts(&self) -> Result<CommitTs> { self.inner.next_commit_ts() }
+        fn current_commit_ts(&self) -> Result<CommitTs> { self.inner.current_commit_ts() }
+        fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> { self.inner.advance_commit_ts(commit_ts) }
+        fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> { self.inner.reserve_commit_ts_block(n) }
+        fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.inner.next_namespace_ts(namespace) }
+        fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> { self.inner.advance_namespace_ts(namespace, namespace_ts) }
+        fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.inner.current_namespace_ts(namespace) }
+        fn create_snapshot(&self) -> Result<Snapshot> { self.inner.create_snapshot() }
+        fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> { self.inner.create_snapshot_at(snapshot_ts) }
+        fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+            *self.snapshot.lock().unwrap() = Some(snapshot.clone());
+            Ok(())
+        }
+        fn load_snapshot(&self) -> Result<Option<Snapshot>> {
+            self.observe();
+            Ok(self.snapshot.lock().unwrap().clone())
+        }
+        fn get_all_state(&self) -> Result<Vec<StateRecord>> { self.inner.get_all_state() }
+        fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> { self.inner.create_snapshot_for_namespace(namespace) }
+        fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()> { self.inner.save_snapshot_for_namespace(snapshot) }
+        fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>> { self.inner.load_snapshot_for_namespace(namespace) }
+        fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> { self.inner.restore_namespace_snapshot(snapshot) }
+        fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> { self.inner.compact_history(up_to_ts, keep_versions) }
+        fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> { self.inner.trim_events(up_to_ts) }
+        fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> { self.inner.list_tombstones(up_to_ts) }
+        fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> { self.inner.purge_tombstones(tombstones) }
+        fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> { self.inner.list_expired(now_ms) }
+        fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> { self.inner.purge_key(record_id) }
+        fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> { self.inner.rename_namespace(from, to, merge) }
+        fn last_event_ts(&self) -> Result<Option<CommitTs>> { self.inner.last_event_ts() }
+        fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> {
+            self.observe();
+            self.inner.read_event(commit_ts)
+        }
+        fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> {
+            self.observe();
+            self.inner.read_events(from_ts, limit)
+        }
+        fn count_events(&self, from_ts: CommitTs) -> Result<u64> { self.inner.count_events(from_ts) }
+        fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> { self.inner.version_bounds(record_id) }
+        fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> { self.inner.namespace_usage(namespace) }
+        fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> { self.inner.least_recent_keys(namespace, limit) }
+        fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>> { self.inner.version_counts(namespace, deadline) }
+        fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> { self.inner.agent_last_commit_ts(namespace, agent_id) }
+        fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> { self.inner.scan_prefix(namespace, agent_id, prefix, include_deleted, deadline) }
+        fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> { self.inner.scan_prefix_consistent(namespace, agent_id, prefix, include_deleted, deadline) }
+        fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
+            self.inner.scan_prefix_page(namespace, agent_id, prefix, include_deleted, start_after, limit)
+        }
+        fn flush(&self) -> Result<()> { self.inner.flush() }
+        fn supports_durability(&self, durability: Durability) -> bool { self.inner.supports_durability(durability) }
+        fn sync_commit(&self, durability: Option<Durability>) -> Result<()> { self.inner.sync_commit(durability) }
+        fn flush_deferred(&self) -> Result<()> { self.inner.flush_deferred() }
+    }
+
+    #[test]
+    fn test_recovery_reports_progress() {
+        let storage = Arc::new(RecoveryObserver::default());
+        let sm = Arc::new(StateMachine::new(storage.clone()));
+        let write = |key: &str| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(1)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+        write("key1");
+        storage.save_snapshot(&storage.create_snapshot().unwrap()).unwrap();
+        write("key1");
+        write("key2");
+        // A gap in the commit timestamps isn't walked, or counted
+        storage.advance_commit_ts(1_000_000).unwrap();
+        write("key1");
+
+        storage.state_machine.set(Arc::downgrade(&sm)).unwrap();
+        let stats = sm.recover().unwrap();
+        assert_eq!(stats, RecoveryStats { snapshot_ts: Some(1), events_replayed: 3 });
+        assert_eq!(
+            *storage.statuses.lock().unwrap(),
+            vec!["loading snapshot", "replaying events (0/3)", "replaying events (3/3)"]
+        );
+        assert_eq!(sm.recovery_status(), RecoveryStatus::Ready);
+
+        // Replayed versions carry on from where the log left off
+        write("key1");
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().version, 4);
+    }
+
+    #[test]
+    fn test_compact() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 16,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        })
+        .unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            let write = |key: &str, value: serde_json::Value| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value).unwrap();
+                sm.commit(&txn_id).unwrap();
+            };
+            for i in 1..=3 {
+                write("a", serde_json::json!(i));
+            }
+            write("b", serde_json::json!("gone"));
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "b".to_string()).unwrap();
+            sm.commit(&txn_id).unwrap();
+            write("c", serde_json::json!(true));
+
+            let stats = sm.compact(1).unwrap();
+            assert_eq!(stats.snapshot_ts, 6);
+            // a@1, a@2, then both versions of the tombstoned b
+            assert_eq!(stats.versions_removed, 4);
+            assert_eq!(stats.tombstones_removed, 1);
+            assert_eq!(stats.events_removed, 6);
+            assert!(stats.bytes_reclaimed > 0);
+
+            let history = sm.get_version_history("default", "agent-1", "a", 10).unwrap();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].value, Some(serde_json::json!(3)));
+            assert!(sm.get_state("default", "agent-1", "b").unwrap().is_none());
+            assert_eq!(sm.get_state("default", "agent-1", "c").unwrap().unwrap().version, 1);
+            assert!(sm.replay("default", "agent-1", None, None).unwrap().is_empty());
+
+            // A purged key starts over, as it would after a restart
+            write("b", serde_json::json!("back"));
+            assert_eq!(sm.get_state("default", "agent-1", "b").unwrap().unwrap().version, 1);
+        }
+    }
+
+    #[test]
+    fn test_run_maintenance() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig { data_dir: temp_dir.path().to_path_buf(), ..StorageConfig::default() }).unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            // Three versions of ten keys for two agents, then two keys of each deleted
+            for round in 1..=3 {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                for agent_id in ["agent-1", "agent-2"] {
+                    for i in 0..10 {
+                        sm.write(&txn_id, "default".to_string(), agent_id.to_string(), format!("k{}", i), serde_json::json!(round)).unwrap();
+                    }
+                }
+                sm.commit(&txn_id).unwrap();
+            }
+            let txn_id = sm.begin_transaction(None).unwrap();
+            for agent_id in ["agent-1", "agent-2"] {
+                for i in 0..2 {
+                    sm.delete(&txn_id, "default".to_string(), agent_id.to_string(), format!("k{}", i)).unwrap();
+                }
+            }
+            sm.commit(&txn_id).unwrap();
+
+            // Trimming the log isn't safe without a snapshot covering it
+            let no_snapshot = MaintenanceOpts { snapshot: false, ..MaintenanceOpts::default() };
+            assert!(sm.run_maintenance(&no_snapshot).is_err());
+
+            let report = sm.run_maintenance(&MaintenanceOpts::default()).unwrap();
+            let snapshot = report.snapshot.as_ref().unwrap();
+            assert_eq!(snapshot.snapshot_ts, 4);
+            // 16 live keys keep 1 of 3 versions; the 4 deleted ones keep their tombstone...
+            assert_eq!(report.version_gc.as_ref().unwrap().versions_removed, 16 * 2 + 4 * 3);
+            // ...until tombstone GC removes them
+            let tombstone_gc = report.tombstone_gc.as_ref().unwrap();
+            assert_eq!((tombstone_gc.tombstones_removed, tombstone_gc.versions_removed), (4, 4));
+            assert_eq!(report.trim_log.as_ref().unwrap().events_removed, 4);
+            let consistency = report.consistency.as_ref().unwrap();
+            assert!(consistency.is_consistent(), "{:?}", consistency.violations);
+            let totals = report.totals();
+            assert_eq!((totals.snapshot_ts, totals.versions_removed, totals.tombstones_removed, totals.events_removed), (4, 48, 4, 4));
+
+            // Everything live still reads at its latest version
+            for agent_id in ["agent-1", "agent-2"] {
+                assert_eq!(sm.list_keys("default", agent_id, false, Deadline::NONE).unwrap().len(), 8);
+                for i in 2..10 {
+                    let record = sm.get_state("default", agent_id, &format!("k{}", i)).unwrap().unwrap();
+                    assert_eq!((record.version, record.value), (3, Some(serde_json::json!(3))));
+                }
+                assert!(sm.get_state("default", agent_id, "k0").unwrap().is_none());
+            }
+
+            // A second pass has nothing left to do; steps can be run alone
+            let report = sm.run_maintenance(&MaintenanceOpts::default()).unwrap();
+            assert_eq!(report.totals(), CompactionStats { snapshot_ts: 4, ..Default::default() });
+            let check_only = MaintenanceOpts {
+                snapshot: false,
+                version_gc: false,
+                tombstone_gc: false,
+                trim_log: false,
+                ..MaintenanceOpts::default()
+            };
+            let report = sm.run_maintenance(&check_only).unwrap();
+            assert!(report.snapshot.is_none() && report.version_gc.is_none() && report.trim_log.is_none());
+            assert!(report.consistency.unwrap().is_consistent());
+        }
+    }
+
+    #[test]
+    fn test_import_records() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        fn record(key: &str, version: Version, commit_ts: CommitTs, value: Option<serde_json::Value>) -> StateRecord {
+            StateRecord {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: key.to_string(),
+                deleted: value.is_none(),
+                value,
+                version,
+                commit_ts,
+                namespace_ts: None,
+                purge_after: None,
+                labels: Labels::new(),
+                value_hash: None,
+                expires_at: None,
+            }
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig { data_dir: temp_dir.path().to_path_buf(), ..StorageConfig::default() }).unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in backends {
+            let sm = StateMachine::new(storage.clone());
+            // Out of commit_ts order; "a" and "c" share commit_ts 5
+            let stats = sm.import_records(vec![
+                record("a", 2, 9, Some(serde_json::json!("a2"))),
+                record("b", 1, 2, Some(serde_json::json!("b1"))),
+                record("a", 1, 5, Some(serde_json::json!("a1"))),
+                record("b", 2, 7, None),
+                record("c", 1, 5, Some(serde_json::json!("c1"))),
+            ]).unwrap();
+            assert_eq!(stats, ImportStats { records_imported: 5, records_skipped: 0, events_written: 4, max_commit_ts: 9 });
+            assert_eq!(storage.current_commit_ts().unwrap(), 9);
+
+            let a1 = sm.get_state_at_version("default", "agent-1", "a", 1).unwrap().unwrap();
+            assert_eq!((a1.commit_ts, a1.value), (5, Some(serde_json::json!("a1"))));
+            let a = sm.get_state("default", "agent-1", "a").unwrap().unwrap();
+            assert_eq!((a.version, a.commit_ts), (2, 9));
+            assert!(sm.get_state("default", "agent-1", "b").unwrap().unwrap().deleted);
+
+            let events = sm.replay("default", "agent-1", None, None).unwrap();
+            assert_eq!(events.iter().map(|e| e.commit_ts).collect::<Vec<_>>(), vec![2, 5, 7, 9]);
+            assert_eq!(events[1].operations.len(), 2);
+            assert!(events[2].operations[0].value.is_none());
+
+            // Commits continue after the imported counters
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string(), serde_json::json!("a3")).unwrap();
+            let result = sm.commit(&txn_id).unwrap();
+            assert_eq!(result.commit_ts, 10);
+            assert_eq!(sm.get_state("default", "agent-1", "a").unwrap().unwrap().version, 3);
+
+            // Rerunning skips what's stored; anything older than a key's latest is refused
+            let stats = sm.import_records(vec![
+                record("a", 1, 5, Some(serde_json::json!("a1"))),
+                record("d", 1, 4, Some(serde_json::json!("d1"))),
+            ]).unwrap();
+            assert_eq!((stats.records_imported, stats.records_skipped), (1, 1));
+            let err = sm.import_records(vec![record("a", 3, 8, Some(serde_json::json!("x")))]).unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(StatehouseError::ImportOutOfOrder { current_version: 3, .. })));
+            assert!(sm.import_records(vec![record("e", 0, 1, None)]).is_err());
+
+            let events = sm.replay("default", "agent-1", None, None).unwrap();
+            assert_eq!(events.iter().map(|e| e.commit_ts).collect::<Vec<_>>(), vec![2, 4, 5, 7, 9, 10]);
+            assert_eq!(storage.current_commit_ts().unwrap(), 10);
+        }
+    }
+
+    #[test]
+    fn test_get_existing_batch() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig { data_dir: temp_dir.path().to_path_buf(), ..StorageConfig::default() }).unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            let txn_id = sm.begin_transaction(None).unwrap();
+            for key in ["present-1", "present-2", "deleted"] {
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({ "key": key })).unwrap();
+            }
+            sm.commit(&txn_id).unwrap();
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "present-2".to_string(), serde_json::json!({ "key": "v2" })).unwrap();
+            sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "deleted".to_string()).unwrap();
+            sm.commit(&txn_id).unwrap();
+
+            let refs: Vec<RecordId> = ["absent", "present-2", "deleted", "present-1", "absent-2"]
+                .iter()
+                .map(|key| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string()))
+                .collect();
+            let batch = sm.get_existing_batch(&refs).unwrap();
+
+            let found: Vec<_> = batch.found.iter().map(|r| (r.key.as_str(), r.version, r.value.clone().unwrap())).collect();
+            assert_eq!(found, vec![
+                ("present-2", 2, serde_json::json!({ "key": "v2" })),
+                ("present-1", 1, serde_json::json!({ "key": "present-1" })),
+            ]);
+            let missing: Vec<_> = batch.missing.iter().map(|r| r.key.as_str()).collect();
+            assert_eq!(missing, vec!["absent", "deleted", "absent-2"]);
+
+            let empty = sm.get_existing_batch(&[]).unwrap();
+            assert!(empty.found.is_empty() && empty.missing.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_transaction_log_paging() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig { data_dir: temp_dir.path().to_path_buf(), ..StorageConfig::default() }).unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
+
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            let mut committed = Vec::new();
+            for i in 0..7 {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                let namespace = if i % 2 == 0 { "tenant-a" } else { "tenant-b" };
+                sm.write(&txn_id, namespace.to_string(), format!("agent-{}", i % 3), "k".to_string(), serde_json::json!(i)).unwrap();
+                committed.push((txn_id.clone(), sm.commit(&txn_id).unwrap().commit_ts));
+            }
+            // An aborted transaction leaves nothing in the log
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.abort(&txn_id).unwrap();
+
+            let mut offset = 0;
+            let mut pages = Vec::new();
+            let mut seen = Vec::new();
+            loop {
+                let page = sm.transaction_log(offset, 3).unwrap();
+                if page.events.is_empty() {
+                    assert_eq!(page.next_offset, offset);
+                    break;
+                }
+                assert_eq!(page.next_offset, page.events.last().unwrap().commit_ts + 1);
+                pages.push(page.events.len());
+                seen.extend(page.events.into_iter().map(|event| (event.txn_id, event.commit_ts)));
+                offset = page.next_offset;
+            }
+            assert_eq!(pages, vec![3, 3, 1]);
+            assert_eq!(seen, committed);
+
+            // Resuming from a stored offset starts at the first unseen commit
+            let resumed = sm.transaction_log(committed[4].1, 0).unwrap();
+            assert_eq!(resumed.events.iter().map(|event| event.commit_ts).collect::<Vec<_>>(), committed[4..].iter().map(|(_, ts)| *ts).collect::<Vec<_>>());
+
+            // New commits show up after the offset a caught-up reader holds
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "tenant-a".to_string(), "agent-0".to_string(), "k".to_string(), serde_json::json!(7)).unwrap();
+            let commit_ts = sm.commit(&txn_id).unwrap().commit_ts;
+            let page = sm.transaction_log(offset, 3).unwrap();
+            assert_eq!((page.events.len(), page.events[0].commit_ts, page.next_offset), (1, commit_ts, commit_ts + 1));
+        }
+    }
+
+    #[test]
+    fn test_commit_ts_blocks() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig { data_dir: temp_dir.path().to_path_buf(), ..StorageConfig::default() };
+        let commit = |sm: &StateMachine, value: i64| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(value)).unwrap();
+            sm.commit(&txn_id).unwrap().commit_ts
+        };
+
+        {
+            let storage = Arc::new(RocksStorage::new(config.clone()).unwrap());
+            let sm = StateMachine::new(storage.clone()).with_commit_ts_blocks(10);
+            assert_eq!((1..=3).map(|i| commit(&sm, i)).collect::<Vec<_>>(), vec![1, 2, 3]);
+            // Storage only moved once, to the end of the block...
+            assert_eq!(storage.current_commit_ts().unwrap(), 10);
+            // ...while reads and snapshots see the last timestamp handed out
+            assert_eq!(sm.begin_read_transaction(None).unwrap().1, 3);
+            assert_eq!(sm.create_snapshot().unwrap().snapshot_ts, 3);
+        }
+
+        // A restart skips what's left of the block
+        let storage = Arc::new(RocksStorage::new(config).unwrap());
+        let sm = StateMachine::new(storage).with_commit_ts_blocks(2);
+        assert_eq!((4..=6).map(|i| commit(&sm, i)).collect::<Vec<_>>(), vec![11, 12, 13]);
+        let events = sm.replay("default", "agent-1", None, None).unwrap();
+        assert_eq!(events.iter().map(|e| e.commit_ts).collect::<Vec<_>>(), vec![1, 2, 3, 11, 12, 13]);
+    }
+
+    #[test]
+    fn test_diff_versions() {
+        use crate::diff::ChangeKind;
+
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()));
+        let write = |value: serde_json::Value| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "profile".to_string(), value).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+        write(serde_json::json!({"name": "ada", "address": {"city": "London", "zip": "N1"}, "tags": ["a"]}));
+        write(serde_json::json!({"name": "ada", "address": {"city": "Paris"}, "tags": ["a", "b"], "age": 36}));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "profile".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let changes = |from, to| -> Vec<(String, ChangeKind)> {
+            sm.diff_versions("default", "agent-1", "profile", from, to).unwrap().into_iter().map(|c| (c.path, c.kind)).collect()
+        };
+        assert_eq!(
+            changes(1, 2),
+            vec![
+                ("/address/city".to_string(), ChangeKind::Changed),
+                ("/address/zip".to_string(), ChangeKind::Removed),
+                ("/age".to_string(), ChangeKind::Added),
+                ("/tags/1".to_string(), ChangeKind::Added),
+            ]
+        );
+        let city = &sm.diff_versions("default", "agent-1", "profile", 1, 2).unwrap()[0];
+        assert_eq!((city.before.clone(), city.after.clone()), (Some(serde_json::json!("London")), Some(serde_json::json!("Paris"))));
+
+        // A tombstone, or version 0, is no value at all
+        assert_eq!(changes(2, 3), vec![(String::new(), ChangeKind::Removed)]);
+        assert_eq!(changes(0, 1), vec![(String::new(), ChangeKind::Added)]);
+        assert!(changes(2, 2).is_empty());
+
+        let err = sm.diff_versions("default", "agent-1", "profile", 1, 9).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::VersionNotFound { version: 9, .. })));
     }
 
     #[test]
-    fn test_read_after_write() {
-        let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage);
+    fn test_version_bounds() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
 
-        // Write
-        let txn_id = sm.begin_transaction(None).unwrap();
-        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "counter".to_string(), serde_json::json!(1)).unwrap();
-        sm.commit(&txn_id).unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        })
+        .unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
 
-        // Read should immediately see the value
-        let state = sm.get_state("default", "agent-1", "counter").unwrap();
-        assert!(state.is_some());
-        assert_eq!(state.unwrap().value.unwrap(), serde_json::json!(1));
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            let write = |key: &str, value: serde_json::Value| {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value).unwrap();
+                sm.commit(&txn_id).unwrap();
+            };
+            for i in 1..=5 {
+                write("a", serde_json::json!(i));
+            }
+            // Shares a's prefix, so its versions sort right after a's
+            write("ab", serde_json::json!(0));
+            assert_eq!(sm.version_bounds("default", "agent-1", "a").unwrap(), Some((1, 5)));
+            assert_eq!(sm.version_bounds("default", "agent-1", "ab").unwrap(), Some((1, 1)));
+            assert_eq!(sm.version_bounds("default", "agent-1", "missing").unwrap(), None);
+
+            sm.compact(2).unwrap();
+            write("a", serde_json::json!(6));
+            assert_eq!(sm.version_bounds("default", "agent-1", "a").unwrap(), Some((4, 6)));
+
+            // Compacted versions are an error, not a miss
+            let err = sm.get_state_at_version("default", "agent-1", "a", 3).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<StatehouseError>(),
+                Some(StatehouseError::VersionCompacted { version: 3, min_version: 4, .. })
+            ), "{:?}", err);
+            assert_eq!(sm.get_state_at_version("default", "agent-1", "a", 4).unwrap().unwrap().value, Some(serde_json::json!(4)));
+            assert!(sm.get_state_at_version("default", "agent-1", "a", 7).unwrap().is_none());
+            assert!(sm.get_state_at_version("default", "agent-1", "missing", 1).unwrap().is_none());
+        }
     }
 
     #[test]
-    fn test_aborted_tx_has_no_effect() {
-        let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage);
+    fn test_version_depth_histogram() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
 
-        // Begin transaction
-        let txn_id = sm.begin_transaction(None).unwrap();
-        
-        // Write
-        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "temp".to_string(), serde_json::json!(42)).unwrap();
-        
-        // Abort
-        sm.abort(&txn_id).unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        })
+        .unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
 
-        // Read should return None (no state)
-        let state = sm.get_state("default", "agent-1", "temp").unwrap();
-        assert!(state.is_none());
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            for (namespace, key, versions) in [("default", "once", 1), ("default", "few", 3), ("default", "several", 6), ("default", "tie", 6), ("default", "many", 21), ("default", "deep", 101), ("other", "deep", 200)] {
+                for i in 0..versions {
+                    let txn_id = sm.begin_transaction(None).unwrap();
+                    sm.write(&txn_id, namespace.to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(i)).unwrap();
+                    sm.commit(&txn_id).unwrap();
+                }
+            }
+
+            let histogram = sm.version_depth_histogram("default", 4, Deadline::NONE).unwrap();
+            assert_eq!((histogram.keys, histogram.versions), (6, 138));
+            assert_eq!(histogram.buckets, [1, 1, 2, 1, 1]);
+            let deepest: Vec<(&str, u64)> = histogram.deepest.iter().map(|(id, count)| (id.key.as_str(), *count)).collect();
+            assert_eq!(deepest, [("deep", 101), ("many", 21), ("several", 6), ("tie", 6)]);
+
+            // Compaction shows up as shallower chains
+            sm.compact(2).unwrap();
+            let histogram = sm.version_depth_histogram("default", 1, Deadline::NONE).unwrap();
+            assert_eq!(histogram.buckets, [1, 5, 0, 0, 0]);
+            assert_eq!(histogram.deepest.len(), 1);
+            assert_eq!(sm.version_depth_histogram("missing", 10, Deadline::NONE).unwrap().keys, 0);
+        }
     }
 
     #[test]
-    fn test_concurrent_commits_serialize() {
-        use std::thread;
+    fn test_get_event() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
 
-        let storage = Arc::new(InMemoryStorage::new());
-        let sm = Arc::new(StateMachine::new(storage));
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        })
+        .unwrap();
+        let backends: Vec<Arc<dyn Storage>> = vec![Arc::new(InMemoryStorage::new()), Arc::new(rocks)];
 
-        let mut handles = vec![];
+        for storage in backends {
+            let sm = StateMachine::new(storage);
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "a".to_string(), serde_json::json!(1)).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-2".to_string(), "b".to_string(), serde_json::json!(2)).unwrap();
+            sm.delete(&txn_id, "other".to_string(), "agent-3".to_string(), "c".to_string()).unwrap();
+            let commit_ts = sm.commit(&txn_id).unwrap().commit_ts;
 
-        // Spawn multiple threads that each commit a transaction
-        for i in 0..10 {
-            let sm_clone = sm.clone();
-            let handle = thread::spawn(move || {
-                let txn_id = sm_clone.begin_transaction(None).unwrap();
-                sm_clone.write(
-                    &txn_id,
-                    "default".to_string(),
-                    "agent-1".to_string(),
-                    format!("key{}", i),
-                    serde_json::json!(i),
-                ).unwrap();
-                sm_clone.commit(&txn_id).unwrap();
-            });
-            handles.push(handle);
-        }
+            let event = sm.get_event(commit_ts).unwrap().unwrap();
+            assert_eq!(event.txn_id, txn_id);
+            assert_eq!(event.commit_ts, commit_ts);
+            let operations: Vec<_> = event
+                .operations
+                .iter()
+                .map(|op| (op.namespace.as_str(), op.agent_id.as_str(), op.key.as_str(), op.value.clone()))
+                .collect();
+            assert_eq!(operations, vec![
+                ("default", "agent-1", "a", Some(serde_json::json!(1))),
+                ("default", "agent-2", "b", Some(serde_json::json!(2))),
+                ("other", "agent-3", "c", None),
+            ]);
 
-        // Wait for all threads to complete
-        for handle in handles {
-            handle.join().unwrap();
+            assert!(sm.get_event(commit_ts + 1).unwrap().is_none());
+            assert!(sm.get_event(0).unwrap().is_none());
         }
+    }
 
-        // Verify all writes succeeded
-        for i in 0..10 {
-            let state = sm.get_state("default", "agent-1", &format!("key{}", i)).unwrap();
-            assert!(state.is_some());
-            assert_eq!(state.unwrap().value.unwrap(), serde_json::json!(i));
+    #[test]
+    fn test_coalesced_writes() {
+        let rules = CoalesceRules::default().with_prefix("heartbeat", Duration::from_millis(300));
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new())).with_coalescing(rules);
+        let write = |key: &str, value: serde_json::Value| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value).unwrap();
+            sm.commit(&txn_id).unwrap()
+        };
+
+        for i in 0..100 {
+            let result = write("heartbeat", serde_json::json!({"beat": i}));
+            assert!(result.coalesced);
+            assert_eq!(result.commit_ts, 0);
         }
+        // Other keys commit as usual, and nothing is flushed before the window closes
+        assert!(!write("status", serde_json::json!("running")).coalesced);
+        assert_eq!(sm.flush_coalesced(false).unwrap(), 0);
+        assert!(sm.get_state("default", "agent-1", "heartbeat").unwrap().is_none());
+
+        // Asking for a durability commits right away
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "heartbeat-2".to_string(), serde_json::json!(1)).unwrap();
+        let result = sm.commit_with_durability(&txn_id, Some(Durability::Fsync)).unwrap();
+        assert!(!result.coalesced);
+        assert_ne!(result.commit_ts, 0);
+        assert_eq!(sm.get_state("default", "agent-1", "heartbeat-2").unwrap().unwrap().value, Some(serde_json::json!(1)));
+        assert_eq!(sm.flush_coalesced(false).unwrap(), 0);
+        assert!(sm.get_state("default", "agent-1", "heartbeat").unwrap().is_none());
+
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(sm.flush_coalesced(false).unwrap(), 1);
+        let state = sm.get_state("default", "agent-1", "heartbeat").unwrap().unwrap();
+        assert_eq!(state.value, Some(serde_json::json!({"beat": 99})));
+        assert_eq!(state.version, 1);
+        assert_eq!(sm.get_version_history("default", "agent-1", "heartbeat", 10).unwrap().len(), 1);
+        assert_eq!(sm.replay("default", "agent-1", None, None).unwrap().len(), 3);
+
+        // Anything else on the key commits the buffered write first
+        write("heartbeat", serde_json::json!({"beat": 100}));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "heartbeat".to_string()).unwrap();
+        assert!(!sm.commit(&txn_id).unwrap().coalesced);
+        let history = sm.get_version_history("default", "agent-1", "heartbeat", 10).unwrap();
+        assert_eq!(history.iter().map(|r| (r.version, r.deleted)).collect::<Vec<_>>(), vec![(3, true), (2, false), (1, false)]);
+        assert_eq!(history[1].value, Some(serde_json::json!({"beat": 100})));
+
+        // A forced flush doesn't wait for the window
+        write("heartbeat", serde_json::json!({"beat": 101}));
+        assert_eq!(sm.flush_coalesced(true).unwrap(), 1);
+        assert_eq!(sm.get_state("default", "agent-1", "heartbeat").unwrap().unwrap().version, 4);
     }
 
     #[test]
-    fn test_transaction_timeout() {
-        use std::time::Duration;
-        use std::thread;
-
-        let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage);
+    fn test_coalesced_writes_survive_a_failed_flush() {
+        let quota = NamespaceQuota { max_records: Some(1), max_bytes: None };
+        let sm = StateMachine::new(Arc::new(InMemoryStorage::new()))
+            .with_coalescing(CoalesceRules::default().with_prefix("heartbeat", Duration::from_secs(60)))
+            .with_namespace_quotas(NamespaceQuotas::default().with_quota("tenant-a", quota));
+        let commit = |key: &str, value: Option<serde_json::Value>| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            match value {
+                Some(value) => sm.write(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), key.to_string(), value).unwrap(),
+                None => sm.delete(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), key.to_string()).unwrap(),
+            }
+            sm.commit(&txn_id).unwrap()
+        };
 
-        // Begin transaction with very short timeout
-        let txn_id = sm.begin_transaction(Some(100)).unwrap(); // 100ms timeout
+        commit("status", Some(serde_json::json!("running")));
+        assert!(commit("heartbeat", Some(serde_json::json!({"beat": 1}))).coalesced);
 
-        // Wait for timeout
-        thread::sleep(Duration::from_millis(150));
+        // The buffered write would pass the quota: the flush fails but keeps it
+        for _ in 0..2 {
+            let err = sm.flush_coalesced(true).unwrap_err();
+            assert!(matches!(err.downcast_ref::<StatehouseError>(), Some(StatehouseError::QuotaExceeded { .. })));
+            assert!(sm.get_state("tenant-a", "agent-1", "heartbeat").unwrap().is_none());
+        }
 
-        // Try to commit - should fail
-        let result = sm.commit(&txn_id);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("expired"));
+        commit("status", None);
+        assert_eq!(sm.flush_coalesced(true).unwrap(), 1);
+        let state = sm.get_state("tenant-a", "agent-1", "heartbeat").unwrap().unwrap();
+        assert_eq!(state.value, Some(serde_json::json!({"beat": 1})));
     }
 
     #[test]
-    fn test_list_keys_after_operations() {
+    fn test_consistency_check() {
         let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage);
-
-        // Write multiple keys
-        for i in 1..=5 {
+        let sm = StateMachine::new(storage.clone());
+        for key in ["a", "b", "a"] {
             let txn_id = sm.begin_transaction(None).unwrap();
-            sm.write(
-                &txn_id,
-                "default".to_string(),
-                "agent-1".to_string(),
-                format!("key{}", i),
-                serde_json::json!(i),
-            ).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!({})).unwrap();
             sm.commit(&txn_id).unwrap();
         }
+        let report = sm.consistency_check().unwrap();
+        assert!(report.is_consistent(), "{:?}", report.violations);
+        assert_eq!((report.records_checked, report.counters_checked), (2, 2));
 
-        // Delete one key
-        let txn_id = sm.begin_transaction(None).unwrap();
-        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "key3".to_string()).unwrap();
-        sm.commit(&txn_id).unwrap();
+        // A counter that drifted from storage is flagged with its record
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "a".to_string());
+        sm.commands.writer.lock().unwrap().state.version_counters.insert(record_id.clone(), 7);
+        let report = sm.consistency_check().unwrap();
+        assert_eq!(report.violations, vec![ConsistencyViolation::VersionCounterMismatch {
+            record_id: record_id.clone(),
+            counter: 7,
+            stored_version: 2,
+        }]);
+        assert_eq!(report.violations[0].record_id(), Some(&record_id));
+        sm.commands.writer.lock().unwrap().state.version_counters.insert(record_id, 2);
 
-        // List should show 4 keys (5 - 1 deleted)
-        let keys = sm.list_keys("default", "agent-1").unwrap();
-        assert_eq!(keys.len(), 4);
-        assert!(!keys.contains(&"key3".to_string()));
+        // As is an event the commit timestamp counter hasn't caught up with
+        storage.append_event(EventLogEntry {
+            txn_id: "stray".to_string(),
+            commit_ts: 10,
+            operations: Vec::new(),
+            namespace_ts: BTreeMap::new(),
+        }).unwrap();
+        let report = sm.consistency_check().unwrap();
+        assert_eq!(report.violations, vec![ConsistencyViolation::CommitTsBehindEvents { commit_ts: 3, last_event_ts: 10 }]);
     }
 
     #[test]
-    fn test_replay_determinism() {
-        let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage);
+    fn test_namespace_sequences_are_contiguous() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use tempfile::TempDir;
 
-        // Perform multiple transactions
-        for i in 1..=5 {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        };
+        let open = || StateMachine::new(Arc::new(RocksStorage::new(config.clone()).unwrap())).with_namespace_sequences(true);
+        let commit = |sm: &StateMachine, namespace: &str, key: &str| {
             let txn_id = sm.begin_transaction(None).unwrap();
-            sm.write(
-                &txn_id,
-                "default".to_string(),
-                "agent-1".to_string(),
-                format!("key{}", i),
-                serde_json::json!({"step": i}),
-            ).unwrap();
-            sm.commit(&txn_id).unwrap();
+            sm.write(&txn_id, namespace.to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(key)).unwrap();
+            sm.commit(&txn_id).unwrap()
+        };
+
+        {
+            let sm = open();
+            let mut seen: BTreeMap<&str, Vec<CommitTs>> = BTreeMap::new();
+            for (i, namespace) in ["tenant-a", "tenant-b", "tenant-b", "tenant-a", "tenant-b", "tenant-a"].into_iter().enumerate() {
+                let result = commit(&sm, namespace, &format!("key-{}", i));
+                assert_eq!(result.commit_ts, i as CommitTs + 1);
+                seen.entry(namespace).or_default().push(result.namespace_ts[namespace]);
+            }
+            assert_eq!(seen["tenant-a"], vec![1, 2, 3]);
+            assert_eq!(seen["tenant-b"], vec![1, 2, 3]);
+
+            let record = sm.get_state("tenant-b", "agent-1", "key-4").unwrap().unwrap();
+            assert_eq!((record.commit_ts, record.namespace_ts), (5, Some(3)));
+
+            // Replay bounds are in the namespace's own sequence
+            let replayed = sm.replay("tenant-a", "agent-1", Some(2), Some(3)).unwrap();
+            let commit_ts: Vec<CommitTs> = replayed.iter().map(|e| e.commit_ts).collect();
+            assert_eq!(commit_ts, vec![4, 6]);
         }
 
-        // Replay events
-        let events = sm.replay("default", "agent-1", None, None).unwrap();
-        assert_eq!(events.len(), 5);
+        // Both sequences carry on after a restart
+        let sm = open();
+        let result = commit(&sm, "tenant-a", "after-restart");
+        assert_eq!(result.commit_ts, 7);
+        assert_eq!(result.namespace_ts["tenant-a"], 4);
+        assert_eq!(commit(&sm, "tenant-b", "after-restart").namespace_ts["tenant-b"], 4);
+    }
 
-        // Verify events are in order by commit_ts
-        for i in 1..events.len() {
-            assert!(events[i].commit_ts > events[i-1].commit_ts);
+    #[test]
+    fn test_snapshot_during_commits_is_consistent() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        };
+        let storage = Arc::new(RocksStorage::new(config).unwrap());
+        let sm = Arc::new(StateMachine::new(storage.clone()));
+
+        // Every commit writes the same counter to keys in two namespaces
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let sm = sm.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    i += 1;
+                    let txn_id = sm.begin_transaction(None).unwrap();
+                    sm.write(&txn_id, "ns-a".to_string(), "agent-1".to_string(), "counter".to_string(), serde_json::json!(i)).unwrap();
+                    sm.write(&txn_id, "ns-b".to_string(), "agent-1".to_string(), "counter".to_string(), serde_json::json!(i)).unwrap();
+                    sm.commit(&txn_id).unwrap();
+                }
+            })
+        };
+
+        for _ in 0..20 {
+            sm.create_snapshot().unwrap();
+            let snapshot = storage.load_snapshot().unwrap().unwrap();
+            assert!(snapshot.records.iter().all(|r| r.commit_ts <= snapshot.metadata.snapshot_ts));
+
+            // No torn commits: both keys present with the same value, or neither
+            let values: Vec<_> = snapshot.records.iter().map(|r| r.value.clone()).collect();
+            match values.as_slice() {
+                [] => {}
+                [a, b] => assert_eq!(a, b),
+                other => panic!("unexpected records: {:?}", other),
+            }
         }
+
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
     }
 
     #[test]
-    fn test_create_snapshot() {
-        let storage = Arc::new(InMemoryStorage::new());
-        let sm = StateMachine::new(storage.clone());
+    fn test_storage_snapshots_are_transaction_consistent() {
+        use crate::storage::{RocksStorage, StorageConfig};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use tempfile::TempDir;
 
-        // Write some data
-        for i in 1..=3 {
-            let txn_id = sm.begin_transaction(None).unwrap();
-            sm.write(
-                &txn_id,
-                "default".to_string(),
-                "agent-1".to_string(),
-                format!("key{}", i),
-                serde_json::json!({"value": i}),
-            ).unwrap();
-            sm.commit(&txn_id).unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        };
+        let storage = Arc::new(RocksStorage::new(config).unwrap());
+        let sm = Arc::new(StateMachine::new(storage.clone()));
+
+        // Every commit writes the same counter to three keys, two in one namespace
+        let keys = [("ns-a", "first"), ("ns-a", "second"), ("ns-b", "third")];
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let sm = sm.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) && i < 2_000 {
+                    i += 1;
+                    let txn_id = sm.begin_transaction(None).unwrap();
+                    for (namespace, key) in keys {
+                        sm.write(&txn_id, namespace.to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(i)).unwrap();
+                    }
+                    sm.commit(&txn_id).unwrap();
+                }
+            })
+        };
+
+        // Snapshots taken straight from storage, without asking the writer
+        let assert_whole = |snapshot: &crate::storage::Snapshot, expected_records: usize| {
+            assert!(snapshot.records.iter().all(|r| r.commit_ts <= snapshot.metadata.snapshot_ts));
+            if snapshot.records.is_empty() {
+                return;
+            }
+            assert_eq!(snapshot.records.len(), expected_records, "{:?}", snapshot.records);
+            let commit_ts = snapshot.records[0].commit_ts;
+            assert!(snapshot.records.iter().all(|r| r.commit_ts == commit_ts && r.value == snapshot.records[0].value));
+        };
+        for _ in 0..50 {
+            assert_whole(&storage.create_snapshot().unwrap(), 3);
+            assert_whole(&storage.create_snapshot_for_namespace("ns-a").unwrap(), 2);
         }
 
-        // Create snapshot
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+
+        // Once commits stop, the snapshot catches up to the last one
         let snapshot = storage.create_snapshot().unwrap();
-        assert_eq!(snapshot.metadata.version, 1);
-        assert_eq!(snapshot.metadata.record_count, 3);
-        assert_eq!(snapshot.records.len(), 3);
+        assert_eq!(snapshot.metadata.snapshot_ts, storage.current_commit_ts().unwrap());
     }
 
     #[test]
@@ -619,6 +7261,12 @@ mod tests {
             fsync_on_commit: true,
             snapshot_interval: 10,
             max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
         };
 
         // Write data and create snapshot
@@ -664,6 +7312,117 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_restore_single_namespace() {
+        use tempfile::TempDir;
+        use crate::storage::RocksStorage;
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = crate::storage::StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: true,
+            snapshot_interval: 10,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        };
+
+        let storage = Arc::new(RocksStorage::new(config).unwrap());
+        let sm = StateMachine::new(storage);
+
+        let put = |ns: &str, key: &str, value: serde_json::Value| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, ns.to_string(), "agent-1".to_string(), key.to_string(), value).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+
+        put("tenant-a", "k1", serde_json::json!({"v": "a1"}));
+        put("tenant-a", "k2", serde_json::json!({"v": "a2"}));
+        put("tenant-b", "k1", serde_json::json!({"v": "b1"}));
+
+        let snapshot_a = sm.create_namespace_snapshot("tenant-a").unwrap();
+        let snapshot_b = sm.create_namespace_snapshot("tenant-b").unwrap();
+        assert_eq!(snapshot_a.metadata.record_count, 2);
+        assert_eq!(snapshot_b.metadata.record_count, 1);
+        assert!(temp_dir.path().join("snapshot-tenant-a.json").exists());
+        assert!(temp_dir.path().join("snapshot-tenant-b.json").exists());
+
+        // Wipe tenant-a and keep working in tenant-b
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), "k1".to_string()).unwrap();
+        sm.delete(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), "k2".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
+        put("tenant-a", "k3", serde_json::json!({"v": "a3"}));
+        put("tenant-b", "k1", serde_json::json!({"v": "b2"}));
+        assert_eq!(sm.list_keys("tenant-a", "agent-1", false, Deadline::NONE).unwrap(), vec!["k3".to_string()]);
+
+        assert!(sm.restore_namespace("tenant-a").unwrap());
+
+        // tenant-a is back to its snapshot
+        let mut keys = sm.list_keys("tenant-a", "agent-1", false, Deadline::NONE).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["k1".to_string(), "k2".to_string()]);
+        let k1 = sm.get_state("tenant-a", "agent-1", "k1").unwrap().unwrap();
+        assert_eq!(k1.value.unwrap()["v"], "a1");
+
+        // tenant-b keeps its post-snapshot state
+        let b1 = sm.get_state("tenant-b", "agent-1", "k1").unwrap().unwrap();
+        assert_eq!(b1.value.unwrap()["v"], "b2");
+
+        // No snapshot for an unknown namespace
+        assert!(!sm.restore_namespace("tenant-c").unwrap());
+    }
+
+    #[test]
+    fn test_writes_after_restore_keep_history() {
+        use tempfile::TempDir;
+        use crate::storage::RocksStorage;
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = crate::storage::StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: true,
+            snapshot_interval: 10,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 1024 * 1024,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        };
+        let sm = StateMachine::new(Arc::new(RocksStorage::new(config).unwrap()));
+        let put = |key: &str, value: serde_json::Value| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "tenant-a".to_string(), "agent-1".to_string(), key.to_string(), value).unwrap();
+            sm.commit(&txn_id).unwrap();
+        };
+        let history = |key: &str| -> Vec<(Version, serde_json::Value)> {
+            sm.get_version_history("tenant-a", "agent-1", key, 10).unwrap().into_iter().map(|r| (r.version, r.value.unwrap())).collect()
+        };
+
+        put("k1", serde_json::json!("a"));
+        sm.create_namespace_snapshot("tenant-a").unwrap();
+        assert!(temp_dir.path().join("snapshot-tenant-a.json.zst").exists());
+        put("k1", serde_json::json!("b"));
+        put("k1", serde_json::json!("b2"));
+        put("k2", serde_json::json!("x"));
+        assert!(sm.restore_namespace("tenant-a").unwrap());
+
+        // Versions carry on from the restored ones, over history that's gone
+        put("k1", serde_json::json!("c"));
+        put("k2", serde_json::json!("y"));
+        assert_eq!(history("k1"), vec![(2, serde_json::json!("c")), (1, serde_json::json!("a"))]);
+        assert_eq!(history("k2"), vec![(1, serde_json::json!("y"))]);
+        let replayed: Vec<CommitTs> = sm.replay("tenant-a", "agent-1", None, None).unwrap().iter().map(|e| e.commit_ts).collect();
+        assert_eq!(replayed.len(), 3);
+        assert!(replayed.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
     #[test]
     fn test_log_replay_after_snapshot() {
         use tempfile::TempDir;
@@ -676,6 +7435,12 @@ mod tests {
             fsync_on_commit: true,
             snapshot_interval: 3,
             max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
         };
 
         let snapshot_ts;
@@ -762,6 +7527,12 @@ mod tests {
             fsync_on_commit: true,
             snapshot_interval: 10,
             max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
         };
 
         // Phase 1: Normal operation
@@ -2,25 +2,68 @@
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
 
+use crate::deadline::Deadline;
+use crate::error::StatehouseError;
 use crate::types::*;
 
 /// Snapshot format version for compatibility
 pub const SNAPSHOT_VERSION: u32 = 1;
 
+/// Smallest `max_log_size` accepted by `StorageConfig::validate`; anything
+/// less can't hold a single large record
+pub const MIN_LOG_SIZE: u64 = 64 * 1024;
+
 /// Storage configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
     /// Data directory for persistent storage
     pub data_dir: PathBuf,
-    /// Enable fsync on commit (slower but safer)
+    /// Enable fsync on commit (slower but safer). This is the durability of
+    /// commits that don't ask for one.
+    ///
+    /// - `true` (`Durability::Fsync`): a commit is flushed before it is
+    ///   acknowledged, so an acknowledged commit survives a crash.
+    /// - `false` (`Durability::Async`): writes sit in RocksDB's memtable/WAL
+    ///   until the next `flush_deferred`. A crash may lose recently
+    ///   acknowledged commits, including the persisted `__commit_ts__`
+    ///   counter. On open the counter is reconciled against the newest
+    ///   surviving event so timestamps are never reissued.
     pub fsync_on_commit: bool,
     /// Snapshot interval (number of commits)
     pub snapshot_interval: u64,
     /// Max log size before compaction (bytes)
     pub max_log_size: u64,
+    /// Number of records kept in the in-memory read cache (0 = disabled)
+    pub read_cache_capacity: usize,
+    /// Stored records whose JSON encoding is at least this many bytes are
+    /// zstd-compressed (0 = disabled). Records are read back either way.
+    pub value_compression_threshold: usize,
+    /// Save snapshots as zstd-compressed shards of at most this many bytes
+    /// of JSON each, listed in `snapshot.manifest.json` (0 = a single
+    /// `snapshot.json`). Either layout loads whatever this is set to.
+    /// Namespace snapshots are compressed too when set, as a single
+    /// `snapshot-<ns>.json.zst` rather than `snapshot-<ns>.json`.
+    pub max_snapshot_shard_bytes: u64,
+    /// Let RocksDB's background compactions drop a superseded version once
+    /// the newest commit is this many commits past the one that replaced it
+    /// (0 = disabled; `compact_history` still trims history). A read
+    /// transaction pinned further back may then find that version gone.
+    pub compaction_version_retention: u64,
+    /// Let RocksDB's background compactions drop tombstones whose delete
+    /// grace period (`purge_after`) has passed, along with their history.
+    /// Tombstones without a grace period are left to `purge_tombstones`.
+    pub compaction_expire_tombstones: bool,
+    /// Values whose JSON encoding is at least this many bytes are stored
+    /// once per distinct content, under a `blob` key named by its hash, and
+    /// `state` and `version` entries refer to it (0 = disabled). Costs a read
+    /// per write and per value read. Records stored either way still read.
+    pub value_dedup_threshold: usize,
 }
 
 impl Default for StorageConfig {
@@ -30,7 +73,49 @@ impl Default for StorageConfig {
             fsync_on_commit: true,
             snapshot_interval: 1000,
             max_log_size: 100 * 1024 * 1024, // 100MB
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Check the settings before anything is opened, failing with
+    /// `StatehouseError::InvalidConfig` naming the first bad field. Creates
+    /// `data_dir` if missing and writes (then removes) a probe file in it, so
+    /// a directory this user can't write is reported now rather than on the
+    /// first commit.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |field, reason: String| -> anyhow::Error { StatehouseError::InvalidConfig { field, reason }.into() };
+
+        if self.snapshot_interval == 0 {
+            return Err(invalid("snapshot_interval", "must be at least 1 commit".to_string()));
+        }
+        if self.max_log_size < MIN_LOG_SIZE {
+            return Err(invalid("max_log_size", format!("is {} bytes; it must be at least {}", self.max_log_size, MIN_LOG_SIZE)));
+        }
+        if self.max_snapshot_shard_bytes > 0 && self.max_snapshot_shard_bytes > self.max_log_size {
+            return Err(invalid("max_snapshot_shard_bytes", format!("is {} bytes, more than max_log_size ({})", self.max_snapshot_shard_bytes, self.max_log_size)));
         }
+        // The compaction filter drops entries without releasing their blobs
+        if self.value_dedup_threshold > 0 && (self.compaction_version_retention > 0 || self.compaction_expire_tombstones) {
+            return Err(invalid("value_dedup_threshold", "can't be combined with compaction_version_retention or compaction_expire_tombstones".to_string()));
+        }
+
+        let data_dir = &self.data_dir;
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| invalid("data_dir", format!("{:?} can't be created: {}", data_dir, e)))?;
+        let probe = data_dir.join(".statehouse-write-test");
+        std::fs::File::create(&probe)
+            .and_then(|mut file| file.write_all(b"ok").and_then(|_| file.sync_all()))
+            .map_err(|e| invalid("data_dir", format!("{:?} isn't writable: {}", data_dir, e)))?;
+        std::fs::remove_file(&probe)
+            .map_err(|e| invalid("data_dir", format!("{:?} doesn't allow removing files: {}", data_dir, e)))?;
+        Ok(())
     }
 }
 
@@ -44,6 +129,61 @@ pub struct StateRecord {
     pub version: Version,
     pub commit_ts: CommitTs,
     pub deleted: bool,
+    /// Commit timestamp in the namespace's own sequence, when the state
+    /// machine runs with per-namespace sequences
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_ts: Option<CommitTs>,
+    /// For a tombstone written with a delete grace period: Unix time in
+    /// milliseconds until which the key can be undeleted and compaction
+    /// leaves it in place
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_after: Option<u64>,
+    /// Labels written with the value; versioned with it
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    /// `value_hash` of the value, computed when it was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_hash: Option<String>,
+    /// For a value written with a TTL: Unix time in milliseconds from which
+    /// the expiry sweep deletes the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl StateRecord {
+    /// Whether the record has every label in `filter` with the same value
+    pub fn has_labels(&self, filter: &Labels) -> bool {
+        filter.iter().all(|(name, value)| self.labels.get(name) == Some(value))
+    }
+}
+
+/// A record's latest state without its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateMeta {
+    pub version: Version,
+    pub commit_ts: CommitTs,
+    pub deleted: bool,
+    /// Length of the value serialized as JSON (0 for tombstones)
+    pub value_bytes: u64,
+    /// See `StateRecord::expires_at`
+    pub expires_at: Option<u64>,
+}
+
+impl StateMeta {
+    pub fn of(record: &StateRecord) -> Self {
+        Self {
+            version: record.version,
+            commit_ts: record.commit_ts,
+            deleted: record.deleted,
+            value_bytes: record.value.as_ref().map_or(0, json_size) as u64,
+            expires_at: record.expires_at,
+        }
+    }
+
+    /// Whether the expiry sweep should delete the record at `now_ms`
+    pub fn expired(&self, now_ms: u64) -> bool {
+        !self.deleted && self.expires_at.is_some_and(|expires_at| expires_at <= now_ms)
+    }
 }
 
 /// Event log entry
@@ -52,6 +192,10 @@ pub struct EventLogEntry {
     pub txn_id: TxnId,
     pub commit_ts: CommitTs,
     pub operations: Vec<OperationRecord>,
+    /// Namespace-local timestamp of this commit in each namespace it touched;
+    /// empty unless per-namespace sequences are enabled
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespace_ts: BTreeMap<Namespace, CommitTs>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,6 +205,10 @@ pub struct OperationRecord {
     pub key: Key,
     pub value: Option<serde_json::Value>,
     pub version: Version,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
 }
 
 /// Snapshot metadata
@@ -74,15 +222,89 @@ pub struct SnapshotMetadata {
     pub record_count: usize,
     /// Timestamp when snapshot was created (system time)
     pub created_at: u64,
+    /// Namespace covered by this snapshot (`None` for a full snapshot)
+    #[serde(default)]
+    pub namespace: Option<Namespace>,
 }
 
-/// Complete snapshot of system state
+/// Complete snapshot of system state. Records are ordered by
+/// (namespace, agent_id, key).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
     pub metadata: SnapshotMetadata,
     pub records: Vec<StateRecord>,
 }
 
+/// What one compaction pass removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Snapshot the pass was anchored to; nothing committed after it is removed
+    pub snapshot_ts: CommitTs,
+    pub versions_removed: u64,
+    pub tombstones_removed: u64,
+    pub events_removed: u64,
+    /// Encoded size of the removed keys and values
+    pub bytes_reclaimed: u64,
+}
+
+impl CompactionStats {
+    /// Add the counts from `other`, keeping this pass's `snapshot_ts`
+    pub fn merge(&mut self, other: &CompactionStats) {
+        self.versions_removed += other.versions_removed;
+        self.tombstones_removed += other.tombstones_removed;
+        self.events_removed += other.events_removed;
+        self.bytes_reclaimed += other.bytes_reclaimed;
+    }
+}
+
+/// What `Storage::purge_key` removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeStats {
+    /// Stored versions deleted, the latest record's included
+    pub versions_removed: u64,
+    /// Events rewritten without the key's operations
+    pub events_scrubbed: u64,
+}
+
+/// What `Storage::rename_namespace` moved
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenameStats {
+    /// Keys moved, tombstones included
+    pub records_moved: u64,
+    /// Stored versions moved with them
+    pub versions_moved: u64,
+    /// Events rewritten to name the new namespace
+    pub events_rewritten: u64,
+}
+
+/// Live (non-deleted) records in a namespace and the bytes of their values
+/// serialized as JSON, as enforced by namespace quotas
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceUsage {
+    pub records: u64,
+    pub bytes: u64,
+}
+
+impl NamespaceUsage {
+    /// Usage after a record's latest state goes from `previous` to `current`
+    /// (`None` for a record that doesn't exist)
+    fn replace(self, previous: Option<&StateMeta>, current: Option<&StateMeta>) -> Self {
+        let live = |meta: Option<&StateMeta>| meta.filter(|m| !m.deleted).map_or((0, 0), |m| (1, m.value_bytes));
+        let (old_records, old_bytes) = live(previous);
+        let (new_records, new_bytes) = live(current);
+        Self {
+            records: (self.records + new_records).saturating_sub(old_records),
+            bytes: (self.bytes + new_bytes).saturating_sub(old_bytes),
+        }
+    }
+
+    fn of_records<'a>(records: impl IntoIterator<Item = &'a StateRecord>) -> Self {
+        records
+            .into_iter()
+            .fold(Self::default(), |usage, record| usage.replace(None, Some(&StateMeta::of(record))))
+    }
+}
+
 /// Storage abstraction for Statehouse
 pub trait Storage: Send + Sync {
     /// Health check
@@ -94,50 +316,538 @@ pub trait Storage: Send + Sync {
     /// Read a state record
     fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>>;
 
+    /// Whether a record exists and is not deleted, without reading its value
+    fn exists(&self, record_id: &RecordId) -> Result<bool>;
+
+    /// Metadata of a record's latest state (including tombstones), reading
+    /// as little of the value as the backend allows
+    fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>>;
+
+    /// Read the latest record (tombstones included) of each of `record_ids`,
+    /// in order, in as few round trips as the backend allows
+    fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>>;
+
     /// Read state at specific version
     fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>>;
 
-    /// List all keys for an agent
-    fn list_keys(&self, namespace: &str, agent_id: &str) -> Result<Vec<String>>;
+    /// Read up to `limit` versions of a record, newest first
+    fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>>;
 
-    /// Scan keys with prefix
-    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<Vec<StateRecord>>;
+    /// Oldest and newest version still stored for a record; `None` if it has
+    /// none. Compaction raises the oldest.
+    fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>>;
+
+    /// Read the latest version of a record with `commit_ts <= as_of`
+    fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>>;
+
+    /// List the live keys of an agent, in key order; with `include_deleted`,
+    /// keys whose latest version is a tombstone too
+    fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>>;
+
+    /// Number of an agent's live keys starting with `prefix` ("" for all),
+    /// without reading their values
+    fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64>;
+
+    /// Scan keys with prefix; tombstones are skipped unless `include_deleted`
+    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>>;
+
+    /// `scan_prefix` as of one point in time at which no commit is partly
+    /// applied, so no record reflects a commit that another record in the
+    /// result doesn't. `scan_prefix` reads each record as the scan reaches
+    /// it, so a commit landing meanwhile may show in some records only.
+    fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>>;
+
+    /// One page of a prefix scan: at most `limit` records whose key starts
+    /// with `prefix` and sorts after `start_after`, in key order. Tombstones
+    /// are skipped unless `include_deleted`.
+    fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>>;
+
+    /// Scan live keys with prefix across every agent in a namespace, ordered
+    /// by (agent_id, key), returning at most `limit` records
+    fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>>;
 
     /// Append event to log
     fn append_event(&self, event: EventLogEntry) -> Result<()>;
 
+    /// Commit timestamp of the newest event in the log, `None` if it's empty
+    fn last_event_ts(&self) -> Result<Option<CommitTs>>;
+
+    /// The event committed at `commit_ts`, if it is still in the log
+    fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>>;
+
     /// Replay events for an agent
-    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>) -> Result<Vec<EventLogEntry>>;
+    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>>;
+
+    /// Up to `limit` events of every agent with `commit_ts >= from_ts`,
+    /// oldest first
+    fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>>;
+
+    /// Number of events of every agent with `commit_ts >= from_ts`
+    fn count_events(&self, from_ts: CommitTs) -> Result<u64>;
 
     /// Get next commit timestamp
     fn next_commit_ts(&self) -> Result<CommitTs>;
 
+    /// Get the most recently issued commit timestamp
+    fn current_commit_ts(&self) -> Result<CommitTs>;
+
+    /// Raise the commit timestamp counter to at least `commit_ts` (never lowers it)
+    fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()>;
+
+    /// Reserve the next `n` commit timestamps (at least one) for the caller
+    /// to hand out itself, returning the inclusive range. The counter moves
+    /// past the whole block at once and is persisted before returning, so
+    /// after a restart timestamps resume after the block even if some of it
+    /// went unused.
+    fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)>;
+
+    /// Get the next timestamp in `namespace`'s own sequence, starting at 1
+    fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs>;
+
+    /// Raise `namespace`'s sequence to at least `namespace_ts` (never lowers it)
+    fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()>;
+
+    /// The most recently issued timestamp in `namespace`'s sequence (0 if unused)
+    fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs>;
+
     /// Flush writes to disk
     fn flush(&self) -> Result<()>;
 
+    /// Whether a commit can be acknowledged at `durability`
+    fn supports_durability(&self, durability: Durability) -> bool;
+
+    /// Called once a commit's records and event are written, before it is
+    /// acknowledged: flush now, or leave it to `flush_deferred`, as
+    /// `durability` asks. `None` uses the configured default.
+    fn sync_commit(&self, durability: Option<Durability>) -> Result<()>;
+
+    /// Flush commits acknowledged with `Durability::Async` since the last flush
+    fn flush_deferred(&self) -> Result<()>;
+
     /// Create a snapshot of current state
     fn create_snapshot(&self) -> Result<Snapshot>;
 
+    /// Create a snapshot of state as of `snapshot_ts`. Commits newer than
+    /// `snapshot_ts` may land during the scan and are excluded; the caller
+    /// must ensure every commit up to `snapshot_ts` is fully applied.
+    fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot>;
+
     /// Save snapshot to disk
     fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()>;
 
     /// Load latest snapshot from disk
     fn load_snapshot(&self) -> Result<Option<Snapshot>>;
 
-    /// Get all state records (for snapshotting)
+    /// Get all state records (for snapshotting), ordered by (namespace, agent_id, key)
     fn get_all_state(&self) -> Result<Vec<StateRecord>>;
+
+    /// Create a snapshot of one namespace's current state
+    fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot>;
+
+    /// Save a namespace snapshot to its own file
+    fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()>;
+
+    /// Load the latest snapshot of one namespace from disk
+    fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>>;
+
+    /// Replace one namespace's current state with a namespace snapshot,
+    /// leaving every other namespace untouched. The namespace's version
+    /// history newer than the snapshot is dropped with the state, so no
+    /// stored version is newer than its key's restored one.
+    fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()>;
+
+    /// Delete version history made redundant by a snapshot at `up_to_ts`.
+    /// Every version committed after `up_to_ts` is kept, plus the newest
+    /// `keep_versions` (at least one) at or before it.
+    fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats>;
+
+    /// Delete events committed at or before `up_to_ts`, which a snapshot at
+    /// `up_to_ts` makes redundant for recovery
+    fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats>;
+
+    /// Latest records that are tombstones committed at or before `up_to_ts`
+    fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>>;
+
+    /// Remove the state, header and history of each tombstone that is still
+    /// its key's latest record. Only the state machine's writer may call it.
+    fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats>;
+
+    /// Live records whose `expires_at` is at or before `now_ms`, for the
+    /// expiry sweep to delete
+    fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>>;
+
+    /// Physically remove a record: its latest state, every version, and its
+    /// operations in the event log. Events are kept, minus those operations,
+    /// so commit timestamps stay contiguous.
+    fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats>;
+
+    /// Move every key of namespace `from`, with its versions, index entries,
+    /// counters and the operations naming it in the event log, to namespace
+    /// `to`, in one write. Fails with `NamespaceNotEmpty` if `to` has records
+    /// and `merge` isn't set, and with `KeyExists` if a key exists under
+    /// both names. Only the state machine's writer may call it.
+    fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats>;
+
+    /// A namespace's usage, kept up to date by every write, purge and
+    /// restore rather than counted on each call
+    fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage>;
+
+    /// Up to `limit` live records of `namespace`, least recently written
+    /// (by latest commit_ts) first, as evicted by an LRU eviction policy
+    fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>>;
+
+    /// Number of stored versions of each key in `namespace` that has any,
+    /// in key order. Counted from the version keys alone.
+    fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>>;
+
+    /// commit_ts of the newest record written for an agent, deletes
+    /// included; `None` if it has none
+    fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>>;
+}
+
+/// Drop `record_id`'s operations from `event`; true if any were dropped
+fn scrub_event(event: &mut EventLogEntry, record_id: &RecordId) -> bool {
+    let before = event.operations.len();
+    event.operations.retain(|op| {
+        (op.namespace.as_str(), op.agent_id.as_str(), op.key.as_str())
+            != (record_id.namespace.as_str(), record_id.agent_id.as_str(), record_id.key.as_str())
+    });
+    event.operations.len() != before
+}
+
+/// Move `event`'s operations in namespace `from` to `to`, with its
+/// namespace timestamp; true if it had any
+fn rename_event(event: &mut EventLogEntry, from: &str, to: &str) -> bool {
+    let mut renamed = false;
+    for op in event.operations.iter_mut().filter(|op| op.namespace == from) {
+        op.namespace = to.to_string();
+        renamed = true;
+    }
+    if let Some(namespace_ts) = event.namespace_ts.remove(from) {
+        let merged = event.namespace_ts.entry(to.to_string()).or_default();
+        *merged = (*merged).max(namespace_ts);
+    }
+    renamed
+}
+
+/// `KeyExists` for a key a rename would move onto an existing one
+fn rename_collision(to: &str, record_id: &RecordId) -> anyhow::Error {
+    StatehouseError::KeyExists {
+        namespace: to.to_string(),
+        agent_id: record_id.agent_id.clone(),
+        key: record_id.key.clone(),
+    }
+    .into()
+}
+
+/// Namespace a snapshot was taken for, or an error for a full snapshot
+fn snapshot_namespace(snapshot: &Snapshot) -> Result<&str> {
+    snapshot
+        .metadata
+        .namespace
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Snapshot is not scoped to a namespace"))
+}
+
+/// Order records by (namespace, agent_id, key), so snapshots of the same
+/// state serialize identically whatever order the storage iterates in
+fn sort_records(records: &mut [StateRecord]) {
+    records.sort_by(|a, b| (&a.namespace, &a.agent_id, &a.key).cmp(&(&b.namespace, &b.agent_id, &b.key)));
+}
+
+/// Serialized size of a JSON value, computed without allocating the output
+pub(crate) fn json_size(value: &serde_json::Value) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    // Writing a Value to an infallible writer can't fail
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+/// Serialized size of a removed in-memory entry, for `CompactionStats`
+fn encoded_len<T: Serialize>(value: &T) -> u64 {
+    serde_json::to_vec(value).map(|bytes| bytes.len() as u64).unwrap_or(0)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn unix_now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// ============================================================================
+// JSONL Snapshot Format
+// ============================================================================
+
+impl Snapshot {
+    /// Write the snapshot as JSONL: a `SnapshotMetadata` header line followed
+    /// by one `StateRecord` per line, for tools that grep/jq or resume mid-file
+    pub fn export_jsonl<W: Write>(&self, mut writer: W) -> Result<()> {
+        serde_json::to_writer(&mut writer, &self.metadata)?;
+        writer.write_all(b"\n")?;
+        for record in &self.records {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Streaming reader for JSONL snapshots. The header is parsed up front so
+/// callers can inspect `metadata()` before consuming any records; records
+/// are then read one line at a time without loading the whole file.
+pub struct JsonlSnapshotReader<R> {
+    metadata: SnapshotMetadata,
+    lines: std::io::Lines<R>,
+    line_no: usize,
+    count: usize,
+}
+
+impl<R: BufRead> JsonlSnapshotReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut lines = reader.lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("JSONL snapshot is empty"))??;
+        let metadata: SnapshotMetadata = serde_json::from_str(&header)?;
+
+        if metadata.version != SNAPSHOT_VERSION {
+            return Err(anyhow::anyhow!(
+                "Snapshot version mismatch: expected {}, got {}",
+                SNAPSHOT_VERSION,
+                metadata.version
+            ));
+        }
+
+        Ok(Self { metadata, lines, line_no: 1, count: 0 })
+    }
+
+    pub fn metadata(&self) -> &SnapshotMetadata {
+        &self.metadata
+    }
+}
+
+impl<R: BufRead> Iterator for JsonlSnapshotReader<R> {
+    /// Yields an error for a malformed line, or at the end if the record
+    /// count doesn't match the header (e.g. a truncated file)
+    type Item = Result<StateRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for line in self.lines.by_ref() {
+            self.line_no += 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            self.count += 1;
+            return Some(serde_json::from_str(&line).map_err(|e| {
+                anyhow::anyhow!("Invalid record on line {}: {}", self.line_no, e)
+            }));
+        }
+
+        if self.count != self.metadata.record_count {
+            let err = anyhow::anyhow!(
+                "JSONL snapshot is incomplete: header declares {} records, found {}",
+                self.metadata.record_count,
+                self.count
+            );
+            // Report once, then end
+            self.count = self.metadata.record_count;
+            return Some(Err(err));
+        }
+        None
+    }
+}
+
+// ============================================================================
+// Sharded Snapshot Format
+// ============================================================================
+
+/// Name of the manifest of a sharded snapshot, in the data directory
+const SNAPSHOT_MANIFEST: &str = "snapshot.manifest.json";
+
+/// One shard of a sharded snapshot, holding records
+/// `first_record..first_record + record_count` in snapshot order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotShard {
+    /// File name, relative to the manifest
+    pub file: String,
+    pub first_record: usize,
+    pub record_count: usize,
+}
+
+/// Manifest of a sharded snapshot. Each shard is zstd-compressed JSONL, one
+/// `StateRecord` per line; `checksum` is FNV-1a over the uncompressed shards
+/// in order, so a missing, reordered or corrupted shard fails the load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub metadata: SnapshotMetadata,
+    pub shards: Vec<SnapshotShard>,
+    pub checksum: u64,
+}
+
+fn snapshot_shard_name(index: usize) -> String {
+    format!("snapshot.part{}.json.zst", index)
+}
+
+/// Write `snapshot` into `dir` as shards of at most `max_shard_bytes` of
+/// uncompressed JSONL each (a record larger than that gets a shard of its
+/// own), then the manifest, atomically, so a reader never sees a manifest
+/// naming shards that aren't written yet
+fn write_sharded_snapshot(dir: &Path, snapshot: &Snapshot, max_shard_bytes: u64) -> Result<SnapshotManifest> {
+    let mut shards = Vec::new();
+    let mut hash = crate::wal::checksum(&[]);
+    let mut records = snapshot.records.iter().peekable();
+    let mut first_record = 0;
+
+    while records.peek().is_some() {
+        let mut shard = SnapshotShard { file: snapshot_shard_name(shards.len()), first_record, record_count: 0 };
+        let file = std::fs::File::create(dir.join(&shard.file))?;
+        let mut encoder = zstd::Encoder::new(std::io::BufWriter::new(file), ZSTD_LEVEL)?;
+        let mut shard_bytes = 0;
+
+        while let Some(record) = records.peek() {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            if shard.record_count > 0 && shard_bytes + line.len() as u64 > max_shard_bytes {
+                break;
+            }
+            encoder.write_all(&line)?;
+            hash = crate::wal::extend_checksum(hash, &line);
+            shard_bytes += line.len() as u64;
+            shard.record_count += 1;
+            records.next();
+        }
+        encoder.finish()?.flush()?;
+        first_record += shard.record_count;
+        shards.push(shard);
+    }
+
+    // Shards left over from an earlier, larger snapshot
+    let mut index = shards.len();
+    while dir.join(snapshot_shard_name(index)).exists() {
+        std::fs::remove_file(dir.join(snapshot_shard_name(index)))?;
+        index += 1;
+    }
+
+    let manifest = SnapshotManifest { metadata: snapshot.metadata.clone(), shards, checksum: hash };
+    let temp_path = dir.join(format!("{}.tmp", SNAPSHOT_MANIFEST));
+    std::fs::write(&temp_path, serde_json::to_string_pretty(&manifest)?)?;
+    std::fs::rename(temp_path, dir.join(SNAPSHOT_MANIFEST))?;
+    Ok(manifest)
+}
+
+/// Read the sharded snapshot whose manifest is in `dir`, streaming the
+/// shards in order and checking record counts and the checksum
+fn read_sharded_snapshot(dir: &Path) -> Result<Snapshot> {
+    let manifest: SnapshotManifest = serde_json::from_str(&std::fs::read_to_string(dir.join(SNAPSHOT_MANIFEST))?)?;
+    if manifest.metadata.version != SNAPSHOT_VERSION {
+        return Err(anyhow::anyhow!(
+            "Snapshot version mismatch: expected {}, got {}",
+            SNAPSHOT_VERSION,
+            manifest.metadata.version
+        ));
+    }
+
+    let mut records = Vec::with_capacity(manifest.metadata.record_count);
+    let mut hash = crate::wal::checksum(&[]);
+    for shard in &manifest.shards {
+        if shard.first_record != records.len() {
+            return Err(anyhow::anyhow!(
+                "Snapshot shard {} starts at record {}, expected {}",
+                shard.file,
+                shard.first_record,
+                records.len()
+            ));
+        }
+        let file = std::fs::File::open(dir.join(&shard.file))?;
+        let mut reader = std::io::BufReader::new(zstd::Decoder::new(file)?);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            hash = crate::wal::extend_checksum(hash, &line);
+            records.push(serde_json::from_slice(&line).map_err(|e| {
+                anyhow::anyhow!("Invalid record in snapshot shard {}: {}", shard.file, e)
+            })?);
+            line.clear();
+        }
+        if records.len() != shard.first_record + shard.record_count {
+            return Err(anyhow::anyhow!(
+                "Snapshot shard {} is incomplete: manifest declares {} records, found {}",
+                shard.file,
+                shard.record_count,
+                records.len() - shard.first_record
+            ));
+        }
+    }
+
+    if records.len() != manifest.metadata.record_count {
+        return Err(anyhow::anyhow!(
+            "Sharded snapshot is incomplete: manifest declares {} records, found {}",
+            manifest.metadata.record_count,
+            records.len()
+        ));
+    }
+    if hash != manifest.checksum {
+        return Err(anyhow::anyhow!("Sharded snapshot checksum mismatch"));
+    }
+    Ok(Snapshot { metadata: manifest.metadata, records })
+}
+
+/// Refuse to restore a snapshot older than the data it would overwrite:
+/// moving `__commit_ts__` backward would reissue commit timestamps that
+/// are already in the event log.
+fn check_snapshot_regression(snapshot_ts: CommitTs, current_ts: CommitTs, allow_regression: bool) -> Result<()> {
+    if snapshot_ts < current_ts && !allow_regression {
+        return Err(StatehouseError::SnapshotRegression { snapshot_ts, current_ts }.into());
+    }
+    if snapshot_ts < current_ts {
+        warn!(snapshot_ts, current_ts, "Restoring a snapshot older than the data directory (regression allowed)");
+    }
+    Ok(())
 }
 
 // ============================================================================
 // In-Memory Storage (for tests)
 // ============================================================================
 
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
 
 pub struct InMemoryStorage {
     state: Arc<RwLock<HashMap<RecordId, Vec<StateRecord>>>>,
     events: Arc<RwLock<Vec<EventLogEntry>>>,
     commit_ts_counter: Arc<RwLock<CommitTs>>,
+    namespace_ts_counters: Arc<RwLock<HashMap<Namespace, CommitTs>>>,
+    namespace_usage: Arc<RwLock<HashMap<Namespace, NamespaceUsage>>>,
+    /// Newest commit whose records have all been written, moved once its
+    /// event is appended
+    applied_commit_ts: Arc<AtomicU64>,
+    /// Versions kept per key, oldest dropped first; unbounded when `None`
+    max_versions_per_key: Option<usize>,
 }
 
 impl InMemoryStorage {
@@ -146,8 +856,20 @@ impl InMemoryStorage {
             state: Arc::new(RwLock::new(HashMap::new())),
             events: Arc::new(RwLock::new(Vec::new())),
             commit_ts_counter: Arc::new(RwLock::new(0)),
+            namespace_ts_counters: Arc::new(RwLock::new(HashMap::new())),
+            namespace_usage: Arc::new(RwLock::new(HashMap::new())),
+            applied_commit_ts: Arc::new(AtomicU64::new(0)),
+            max_versions_per_key: None,
         }
     }
+
+    /// Keep at most `max_versions` (at least one) versions of each key,
+    /// dropping the oldest as new ones are written. Dropped versions read
+    /// like compacted ones: `version_bounds` starts after them.
+    pub fn with_max_versions_per_key(mut self, max_versions: usize) -> Self {
+        self.max_versions_per_key = Some(max_versions.max(1));
+        self
+    }
 }
 
 impl Default for InMemoryStorage {
@@ -168,7 +890,16 @@ impl Storage for InMemoryStorage {
             record.agent_id.clone(),
             record.key.clone(),
         );
-        state.entry(record_id).or_insert_with(Vec::new).push(record);
+        let versions = state.entry(record_id).or_default();
+
+        let mut usage = self.namespace_usage.write().unwrap();
+        let usage = usage.entry(record.namespace.clone()).or_default();
+        *usage = usage.replace(versions.last().map(StateMeta::of).as_ref(), Some(&StateMeta::of(&record)));
+
+        versions.push(record);
+        if let Some(excess) = self.max_versions_per_key.and_then(|max| versions.len().checked_sub(max)) {
+            versions.drain(..excess);
+        }
         Ok(())
     }
 
@@ -177,6 +908,25 @@ impl Storage for InMemoryStorage {
         Ok(state.get(record_id).and_then(|versions| versions.last().cloned()))
     }
 
+    fn exists(&self, record_id: &RecordId) -> Result<bool> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .get(record_id)
+            .and_then(|versions| versions.last())
+            .map(|r| !r.deleted)
+            .unwrap_or(false))
+    }
+
+    fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> {
+        let state = self.state.read().unwrap();
+        Ok(state.get(record_id).and_then(|versions| versions.last()).map(StateMeta::of))
+    }
+
+    fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> {
+        let state = self.state.read().unwrap();
+        Ok(record_ids.iter().map(|record_id| state.get(record_id).and_then(|versions| versions.last().cloned())).collect())
+    }
+
     fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> {
         let state = self.state.read().unwrap();
         Ok(state.get(record_id).and_then(|versions| {
@@ -184,67 +934,172 @@ impl Storage for InMemoryStorage {
         }))
     }
 
-    fn list_keys(&self, namespace: &str, agent_id: &str) -> Result<Vec<String>> {
+    fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .get(record_id)
+            .map(|versions| versions.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .get(record_id)
+            .and_then(|versions| Some((versions.first()?.version, versions.last()?.version))))
+    }
+
+    fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> {
+        let state = self.state.read().unwrap();
+        Ok(state.get(record_id).and_then(|versions| {
+            versions.iter().rev().find(|r| r.commit_ts <= as_of).cloned()
+        }))
+    }
+
+    fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> {
+        let state = self.state.read().unwrap();
+        let mut keys = Vec::new();
+        for (scanned, (id, versions)) in state.iter().enumerate() {
+            deadline.check_every(scanned)?;
+            if id.namespace == namespace
+                && id.agent_id == agent_id
+                && versions.last().map(|r| include_deleted || !r.deleted).unwrap_or(false)
+            {
+                keys.push(id.key.clone());
+            }
+        }
+        // In key order, like RocksStorage
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> {
         let state = self.state.read().unwrap();
-        let keys: Vec<String> = state
+        let count = state
             .iter()
             .filter(|(id, versions)| {
                 id.namespace == namespace
                     && id.agent_id == agent_id
-                    && versions.last().map(|r| !r.deleted).unwrap_or(false)
+                    && id.key.starts_with(prefix)
+                    && versions.last().is_some_and(|r| !r.deleted)
             })
-            .map(|(id, _)| id.key.clone())
-            .collect();
-        Ok(keys)
+            .count();
+        Ok(count as u64)
+    }
+
+    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        let state = self.state.read().unwrap();
+        let mut records = Vec::new();
+        for (scanned, (id, versions)) in state.iter().enumerate() {
+            deadline.check_every(scanned)?;
+            if id.namespace != namespace || id.agent_id != agent_id || !id.key.starts_with(prefix) {
+                continue;
+            }
+            if let Some(record) = versions.last().filter(|r| include_deleted || !r.deleted) {
+                records.push(record.clone());
+            }
+        }
+        Ok(records)
+    }
+
+    fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        // Records are written under the state lock, so with it held every
+        // commit up to the watermark is whole and later ones are read as of it
+        let state = self.state.read().unwrap();
+        let applied_ts = self.applied_commit_ts.load(Ordering::SeqCst);
+        let mut records = Vec::new();
+        for (scanned, (id, versions)) in state.iter().enumerate() {
+            deadline.check_every(scanned)?;
+            if id.namespace != namespace || id.agent_id != agent_id || !id.key.starts_with(prefix) {
+                continue;
+            }
+            let record = versions.iter().rev().find(|r| r.commit_ts <= applied_ts);
+            if let Some(record) = record.filter(|r| include_deleted || !r.deleted) {
+                records.push(record.clone());
+            }
+        }
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(records)
     }
 
-    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<Vec<StateRecord>> {
+    fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
         let state = self.state.read().unwrap();
-        let records: Vec<StateRecord> = state
+        let mut records: Vec<StateRecord> = state
             .iter()
             .filter(|(id, _)| {
                 id.namespace == namespace
                     && id.agent_id == agent_id
                     && id.key.starts_with(prefix)
+                    && start_after.is_none_or(|after| id.key.as_str() > after)
             })
             .filter_map(|(_, versions)| versions.last().cloned())
+            .filter(|r| include_deleted || !r.deleted)
+            .collect();
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        records.truncate(limit);
+        Ok(records)
+    }
+
+    fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> {
+        let state = self.state.read().unwrap();
+        let mut records: Vec<StateRecord> = state
+            .iter()
+            .filter(|(id, _)| id.namespace == namespace && id.key.starts_with(prefix))
+            .filter_map(|(_, versions)| versions.last().cloned())
             .filter(|r| !r.deleted)
             .collect();
+        records.sort_by(|a, b| (&a.agent_id, &a.key).cmp(&(&b.agent_id, &b.key)));
+        records.truncate(limit);
         Ok(records)
     }
 
     fn append_event(&self, event: EventLogEntry) -> Result<()> {
+        // Kept in commit_ts order, replacing any event at the same commit_ts
+        // like RocksDB does; only imports append out of order
+        let commit_ts = event.commit_ts;
         let mut events = self.events.write().unwrap();
-        events.push(event);
+        match events.binary_search_by_key(&event.commit_ts, |e| e.commit_ts) {
+            Ok(i) => events[i] = event,
+            Err(i) => events.insert(i, event),
+        }
+        drop(events);
+        // The event is a commit's last write
+        self.applied_commit_ts.fetch_max(commit_ts, Ordering::SeqCst);
         Ok(())
     }
 
-    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>) -> Result<Vec<EventLogEntry>> {
-        let events = self.events.read().unwrap();
-        let filtered: Vec<EventLogEntry> = events
-            .iter()
-            .filter(|e| {
-                e.operations.iter().any(|op| {
-                    op.namespace == namespace && op.agent_id == agent_id
-                })
-            })
-            .filter(|e| {
-                if let Some(start) = start_ts {
-                    e.commit_ts >= start
-                } else {
-                    true
-                }
-            })
-            .filter(|e| {
-                if let Some(end) = end_ts {
-                    e.commit_ts <= end
-                } else {
-                    true
-                }
-            })
-            .cloned()
-            .collect();
-        Ok(filtered)
+    fn last_event_ts(&self) -> Result<Option<CommitTs>> {
+        Ok(self.events.read().unwrap().iter().map(|event| event.commit_ts).max())
+    }
+
+    fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> {
+        Ok(self.events.read().unwrap().iter().find(|event| event.commit_ts == commit_ts).cloned())
+    }
+
+    fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> {
+        // Kept in commit_ts order by append_event
+        let events = self.events.read().unwrap();
+        let start = events.partition_point(|event| event.commit_ts < from_ts);
+        Ok(events[start..].iter().take(limit).cloned().collect())
+    }
+
+    fn count_events(&self, from_ts: CommitTs) -> Result<u64> {
+        let events = self.events.read().unwrap();
+        Ok((events.len() - events.partition_point(|event| event.commit_ts < from_ts)) as u64)
+    }
+
+    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> {
+        let events = self.events.read().unwrap();
+        let mut filtered = Vec::new();
+        for (scanned, e) in events.iter().enumerate() {
+            deadline.check_every(scanned)?;
+            let relevant = e.operations.iter().any(|op| op.namespace == namespace && op.agent_id == agent_id);
+            let in_range = start_ts.is_none_or(|start| e.commit_ts >= start) && end_ts.is_none_or(|end| e.commit_ts <= end);
+            if relevant && in_range {
+                filtered.push(e.clone());
+            }
+        }
+        Ok(filtered)
     }
 
     fn next_commit_ts(&self) -> Result<CommitTs> {
@@ -253,10 +1108,59 @@ impl Storage for InMemoryStorage {
         Ok(*counter)
     }
 
+    fn current_commit_ts(&self) -> Result<CommitTs> {
+        Ok(*self.commit_ts_counter.read().unwrap())
+    }
+
+    fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> {
+        let mut counter = self.commit_ts_counter.write().unwrap();
+        *counter = (*counter).max(commit_ts);
+        self.applied_commit_ts.fetch_max(commit_ts, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> {
+        let mut counter = self.commit_ts_counter.write().unwrap();
+        let start = *counter + 1;
+        *counter += n.max(1);
+        Ok((start, *counter))
+    }
+
+    fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> {
+        let mut counters = self.namespace_ts_counters.write().unwrap();
+        let counter = counters.entry(namespace.to_string()).or_default();
+        *counter += 1;
+        Ok(*counter)
+    }
+
+    fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> {
+        let mut counters = self.namespace_ts_counters.write().unwrap();
+        let counter = counters.entry(namespace.to_string()).or_default();
+        *counter = (*counter).max(namespace_ts);
+        Ok(())
+    }
+
+    fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> {
+        Ok(self.namespace_ts_counters.read().unwrap().get(namespace).copied().unwrap_or(0))
+    }
+
     fn flush(&self) -> Result<()> {
         Ok(())
     }
 
+    fn supports_durability(&self, _durability: Durability) -> bool {
+        true
+    }
+
+    /// Nothing reaches disk, whatever the durability
+    fn sync_commit(&self, _durability: Option<Durability>) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush_deferred(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn create_snapshot(&self) -> Result<Snapshot> {
         let state = self.state.read().unwrap();
         let commit_ts_counter = self.commit_ts_counter.read().unwrap();
@@ -268,6 +1172,7 @@ impl Storage for InMemoryStorage {
                 records.push(record.clone());
             }
         }
+        sort_records(&mut records);
 
         let metadata = SnapshotMetadata {
             version: SNAPSHOT_VERSION,
@@ -277,6 +1182,26 @@ impl Storage for InMemoryStorage {
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            namespace: None,
+        };
+
+        Ok(Snapshot { metadata, records })
+    }
+
+    fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> {
+        let state = self.state.read().unwrap();
+        let mut records: Vec<StateRecord> = state
+            .values()
+            .filter_map(|versions| versions.iter().rev().find(|r| r.commit_ts <= snapshot_ts).cloned())
+            .collect();
+        sort_records(&mut records);
+
+        let metadata = SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            snapshot_ts,
+            record_count: records.len(),
+            created_at: unix_now(),
+            namespace: None,
         };
 
         Ok(Snapshot { metadata, records })
@@ -300,300 +1225,3397 @@ impl Storage for InMemoryStorage {
                 records.push(record.clone());
             }
         }
+        sort_records(&mut records);
         Ok(records)
     }
-}
-
-// ============================================================================
-// RocksDB Storage
-// ============================================================================
 
-use rocksdb::{Options, DB};
-
-pub struct RocksStorage {
-    db: Arc<DB>,
-    config: StorageConfig,
-    commit_ts_counter: Arc<RwLock<CommitTs>>,
-}
+    fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> {
+        let state = self.state.read().unwrap();
+        let commit_ts_counter = self.commit_ts_counter.read().unwrap();
 
-impl RocksStorage {
-    pub fn new(config: StorageConfig) -> Result<Self> {
-        std::fs::create_dir_all(&config.data_dir)?;
+        let mut records: Vec<StateRecord> = state
+            .iter()
+            .filter(|(id, _)| id.namespace == namespace)
+            .filter_map(|(_, versions)| versions.last().cloned())
+            .collect();
+        sort_records(&mut records);
 
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.create_missing_column_families(true);
+        let metadata = SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            snapshot_ts: *commit_ts_counter,
+            record_count: records.len(),
+            created_at: unix_now(),
+            namespace: Some(namespace.to_string()),
+        };
 
-        let db_path = config.data_dir.join("rocksdb");
-        let db = DB::open(&opts, db_path)?;
+        Ok(Snapshot { metadata, records })
+    }
 
-        // Load current commit timestamp
-        let commit_ts = if let Some(value) = db.get(b"__commit_ts__")? {
-            u64::from_be_bytes(value.try_into().unwrap_or([0; 8]))
-        } else {
-            0
-        };
+    fn save_snapshot_for_namespace(&self, _snapshot: &Snapshot) -> Result<()> {
+        // In-memory storage doesn't persist snapshots
+        Ok(())
+    }
 
-        Ok(Self {
-            db: Arc::new(db),
-            config,
-            commit_ts_counter: Arc::new(RwLock::new(commit_ts)),
-        })
+    fn load_snapshot_for_namespace(&self, _namespace: &str) -> Result<Option<Snapshot>> {
+        // In-memory storage doesn't persist snapshots
+        Ok(None)
     }
 
-    /// Restore state from snapshot
-    pub fn restore_from_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
-        // Write all records from snapshot
+    fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let namespace = snapshot_namespace(snapshot)?;
+        let mut state = self.state.write().unwrap();
+
+        // In-memory history is not kept across a restore
+        state.retain(|id, _| id.namespace != namespace);
+        self.namespace_usage.write().unwrap().insert(namespace.to_string(), NamespaceUsage::of_records(&snapshot.records));
         for record in &snapshot.records {
-            self.write_state(record.clone())?;
+            let record_id = RecordId::new(
+                record.namespace.clone(),
+                record.agent_id.clone(),
+                record.key.clone(),
+            );
+            state.insert(record_id, vec![record.clone()]);
         }
-
-        // Update commit timestamp counter
-        let mut counter = self.commit_ts_counter.write().unwrap();
-        *counter = snapshot.metadata.snapshot_ts;
-        self.db.put(b"__commit_ts__", &counter.to_be_bytes())?;
-
-        self.flush()?;
         Ok(())
     }
 
-    /// Get path for snapshot file
-    fn snapshot_path(&self) -> PathBuf {
-        self.config.data_dir.join("snapshot.json")
-    }
+    fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> {
+        let keep_versions = keep_versions.max(1);
+        let mut stats = CompactionStats { snapshot_ts: up_to_ts, ..Default::default() };
 
-    fn state_key(record_id: &RecordId) -> Vec<u8> {
-        format!("state:{}:{}:{}", record_id.namespace, record_id.agent_id, record_id.key).into_bytes()
-    }
+        let mut state = self.state.write().unwrap();
+        for versions in state.values_mut() {
+            // Versions are kept oldest first, so the settled ones are a prefix
+            let settled = versions.iter().take_while(|r| r.commit_ts <= up_to_ts).count();
+            for record in versions.drain(..settled.saturating_sub(keep_versions)) {
+                stats.versions_removed += 1;
+                stats.bytes_reclaimed += encoded_len(&record);
+            }
+        }
 
-    fn version_key(record_id: &RecordId, version: Version) -> Vec<u8> {
-        format!("version:{}:{}:{}:{:020}", record_id.namespace, record_id.agent_id, record_id.key, version).into_bytes()
+        Ok(stats)
     }
 
-    fn event_key(commit_ts: CommitTs) -> Vec<u8> {
-        format!("event:{:020}", commit_ts).into_bytes()
+    fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> {
+        let mut stats = CompactionStats { snapshot_ts: up_to_ts, ..Default::default() };
+        let mut events = self.events.write().unwrap();
+        events.retain(|event| {
+            if event.commit_ts > up_to_ts {
+                return true;
+            }
+            stats.events_removed += 1;
+            stats.bytes_reclaimed += encoded_len(event);
+            false
+        });
+
+        Ok(stats)
     }
-}
 
-impl Storage for RocksStorage {
-    fn health_check(&self) -> Result<()> {
-        // Try a simple read
-        self.db.get(b"__health__")?;
-        Ok(())
+    fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .values()
+            .filter_map(|versions| versions.last())
+            .filter(|r| r.deleted && r.commit_ts <= up_to_ts)
+            .cloned()
+            .collect())
     }
 
-    fn write_state(&self, record: StateRecord) -> Result<()> {
-        let record_id = RecordId::new(
-            record.namespace.clone(),
-            record.agent_id.clone(),
-            record.key.clone(),
-        );
+    fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .iter()
+            .filter(|(_, versions)| versions.last().is_some_and(|r| StateMeta::of(r).expired(now_ms)))
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
 
-        // Write latest state
-        let state_key = Self::state_key(&record_id);
-        let state_value = serde_json::to_vec(&record)?;
-        self.db.put(&state_key, &state_value)?;
+    fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> {
+        let mut stats = CompactionStats::default();
+        let mut state = self.state.write().unwrap();
 
-        // Write versioned state
-        let version_key = Self::version_key(&record_id, record.version);
-        self.db.put(&version_key, &state_value)?;
+        for tombstone in tombstones {
+            let record_id = RecordId::new(
+                tombstone.namespace.clone(),
+                tombstone.agent_id.clone(),
+                tombstone.key.clone(),
+            );
+            let still_deleted = state
+                .get(&record_id)
+                .and_then(|versions| versions.last())
+                .is_some_and(|latest| latest.deleted && latest.version == tombstone.version);
+            if !still_deleted {
+                continue;
+            }
 
-        if self.config.fsync_on_commit {
-            self.db.flush()?;
+            let versions = state.remove(&record_id).unwrap_or_default();
+            stats.tombstones_removed += 1;
+            stats.versions_removed += versions.len() as u64;
+            stats.bytes_reclaimed += versions.iter().map(encoded_len).sum::<u64>();
         }
 
-        Ok(())
+        Ok(stats)
     }
 
-    fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> {
-        let key = Self::state_key(record_id);
-        if let Some(value) = self.db.get(&key)? {
-            let record: StateRecord = serde_json::from_slice(&value)?;
-            Ok(Some(record))
-        } else {
-            Ok(None)
+    fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> {
+        let versions = self.state.write().unwrap().remove(record_id).unwrap_or_default();
+        if let Some(latest) = versions.last() {
+            let mut usage = self.namespace_usage.write().unwrap();
+            let usage = usage.entry(record_id.namespace.clone()).or_default();
+            *usage = usage.replace(Some(&StateMeta::of(latest)), None);
         }
-    }
-
-    fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> {
-        let key = Self::version_key(record_id, version);
-        if let Some(value) = self.db.get(&key)? {
-            let record: StateRecord = serde_json::from_slice(&value)?;
-            Ok(Some(record))
-        } else {
-            Ok(None)
+        let mut events = self.events.write().unwrap();
+        let mut events_scrubbed = 0;
+        for event in events.iter_mut() {
+            if scrub_event(event, record_id) {
+                events_scrubbed += 1;
+            }
         }
+
+        Ok(PurgeStats {
+            versions_removed: versions.len() as u64,
+            events_scrubbed: events_scrubbed as u64,
+        })
     }
 
-    fn list_keys(&self, namespace: &str, agent_id: &str) -> Result<Vec<String>> {
-        let prefix = format!("state:{}:{}:", namespace, agent_id);
-        let mut keys = Vec::new();
+    fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> {
+        let mut state = self.state.write().unwrap();
+        if !merge && state.keys().any(|record_id| record_id.namespace == to) {
+            return Err(StatehouseError::NamespaceNotEmpty { namespace: to.to_string() }.into());
+        }
+        let renamed = |record_id: &RecordId| RecordId::new(to.to_string(), record_id.agent_id.clone(), record_id.key.clone());
+        let moving: Vec<RecordId> = state.keys().filter(|record_id| record_id.namespace == from).cloned().collect();
+        if let Some(record_id) = moving.iter().find(|record_id| state.contains_key(&renamed(record_id))) {
+            return Err(rename_collision(to, record_id));
+        }
 
-        let iter = self.db.prefix_iterator(prefix.as_bytes());
-        for item in iter {
-            let (key, value) = item?;
-            let key_str = String::from_utf8_lossy(&key);
-            if !key_str.starts_with(&prefix) {
-                break;
+        let mut stats = RenameStats::default();
+        for record_id in moving {
+            let mut versions = state.remove(&record_id).unwrap_or_default();
+            for version in &mut versions {
+                version.namespace = to.to_string();
             }
-
-            let record: StateRecord = serde_json::from_slice(&value)?;
-            if !record.deleted {
-                keys.push(record.key);
+            stats.records_moved += 1;
+            stats.versions_moved += versions.len() as u64;
+            state.insert(renamed(&record_id), versions);
+        }
+        for event in self.events.write().unwrap().iter_mut() {
+            if rename_event(event, from, to) {
+                stats.events_rewritten += 1;
             }
         }
 
-        Ok(keys)
+        let mut counters = self.namespace_ts_counters.write().unwrap();
+        if let Some(namespace_ts) = counters.remove(from) {
+            let counter = counters.entry(to.to_string()).or_default();
+            *counter = (*counter).max(namespace_ts);
+        }
+        let mut usage = self.namespace_usage.write().unwrap();
+        if let Some(moved) = usage.remove(from) {
+            let merged = usage.entry(to.to_string()).or_default();
+            *merged = NamespaceUsage { records: merged.records + moved.records, bytes: merged.bytes + moved.bytes };
+        }
+        Ok(stats)
     }
 
-    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<Vec<StateRecord>> {
-        let state_prefix = format!("state:{}:{}:{}", namespace, agent_id, prefix);
-        let mut records = Vec::new();
+    fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> {
+        Ok(self.namespace_usage.read().unwrap().get(namespace).copied().unwrap_or_default())
+    }
 
-        let iter = self.db.prefix_iterator(state_prefix.as_bytes());
-        for item in iter {
-            let (key, value) = item?;
-            let key_str = String::from_utf8_lossy(&key);
-            if !key_str.starts_with(&state_prefix) {
-                break;
-            }
+    fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> {
+        let state = self.state.read().unwrap();
+        let mut live: Vec<(CommitTs, &RecordId)> = state
+            .iter()
+            .filter(|(id, _)| id.namespace == namespace)
+            .filter_map(|(id, versions)| versions.last().filter(|r| !r.deleted).map(|r| (r.commit_ts, id)))
+            .collect();
+        // Ties in the order of the RocksDB index
+        live.sort_by(|a, b| (a.0, &a.1.agent_id, &a.1.key).cmp(&(b.0, &b.1.agent_id, &b.1.key)));
+        Ok(live.into_iter().take(limit).map(|(_, id)| id.clone()).collect())
+    }
 
-            let record: StateRecord = serde_json::from_slice(&value)?;
-            if !record.deleted {
-                records.push(record);
-            }
-        }
+    fn version_counts(&self, namespace: &str, _deadline: Deadline) -> Result<Vec<(RecordId, u64)>> {
+        let state = self.state.read().unwrap();
+        let mut counts: Vec<(RecordId, u64)> = state
+            .iter()
+            .filter(|(id, versions)| id.namespace == namespace && !versions.is_empty())
+            .map(|(id, versions)| (id.clone(), versions.len() as u64))
+            .collect();
+        counts.sort_by(|a, b| (&a.0.agent_id, &a.0.key).cmp(&(&b.0.agent_id, &b.0.key)));
+        Ok(counts)
+    }
 
-        Ok(records)
+    fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .iter()
+            .filter(|(id, _)| id.namespace == namespace && id.agent_id == agent_id)
+            .filter_map(|(_, versions)| versions.last().map(|r| r.commit_ts))
+            .max())
     }
+}
 
-    fn append_event(&self, event: EventLogEntry) -> Result<()> {
-        let key = Self::event_key(event.commit_ts);
-        let value = serde_json::to_vec(&event)?;
-        self.db.put(&key, &value)?;
+// ============================================================================
+// RocksDB Storage
+// ============================================================================
 
-        if self.config.fsync_on_commit {
-            self.db.flush()?;
-        }
+use rocksdb::{CompactionDecision, Direction, IteratorMode, Options, ReadOptions, WriteBatch, DB};
+use crate::cache::LruCache;
+use crate::key_codec;
 
-        Ok(())
-    }
+/// A key and value as stored
+type StoredEntry = (Box<[u8]>, Box<[u8]>);
 
-    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>) -> Result<Vec<EventLogEntry>> {
-        let start_key = if let Some(ts) = start_ts {
-            Self::event_key(ts)
-        } else {
-            b"event:".to_vec()
-        };
+/// One raw key/value from a RocksDB iterator
+type RawEntry = std::result::Result<StoredEntry, rocksdb::Error>;
 
-        let mut events = Vec::new();
-        let iter = self.db.prefix_iterator(&start_key);
+/// A `version` key, its key plus value size, the decoded record and the
+/// blob it refers to
+type VersionEntry = (Box<[u8]>, usize, StateRecord, Option<String>);
 
-        for item in iter {
-            let (key, value) = item?;
-            let key_str = String::from_utf8_lossy(&key);
-            if !key_str.starts_with("event:") {
-                break;
-            }
+/// What a blob reference is resolved through: the database, or the pinned
+/// snapshot the reference was read from
+trait BlobSource {
+    fn get_blob(&self, hash: &str) -> Result<Option<Vec<u8>>>;
+}
 
-            let event: EventLogEntry = serde_json::from_slice(&value)?;
+impl BlobSource for DB {
+    fn get_blob(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key_codec::blob_key(hash))?)
+    }
+}
 
-            // Check if event is relevant to this agent
-            let relevant = event.operations.iter().any(|op| {
-                op.namespace == namespace && op.agent_id == agent_id
-            });
+impl BlobSource for rocksdb::Snapshot<'_> {
+    fn get_blob(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key_codec::blob_key(hash))?)
+    }
+}
 
-            if relevant {
-                if let Some(end) = end_ts {
-                    if event.commit_ts > end {
-                        break;
-                    }
-                }
-                events.push(event);
-            }
-        }
+/// Blob reference counts changed by one write batch
+#[derive(Default)]
+struct BlobChanges {
+    refs: HashMap<String, i64>,
+    /// Stored blobs that written entries refer to, saved if they are new
+    contents: HashMap<String, Vec<u8>>,
+}
 
-        Ok(events)
+impl BlobChanges {
+    /// Count `entries` more entries (fewer, if negative) referring to `blob`
+    fn count(&mut self, blob: Option<String>, entries: i64) {
+        if let Some(hash) = blob {
+            *self.refs.entry(hash).or_default() += entries;
+        }
     }
+}
 
-    fn next_commit_ts(&self) -> Result<CommitTs> {
-        let mut counter = self.commit_ts_counter.write().unwrap();
-        *counter += 1;
-        let ts = *counter;
+/// Name RocksDB records for `RetentionFilter`
+const RETENTION_FILTER_NAME: &str = "statehouse_retention";
 
-        // Persist commit timestamp
-        self.db.put(b"__commit_ts__", &ts.to_be_bytes())?;
+/// Compaction filter dropping what `compaction_version_retention` and
+/// `compaction_expire_tombstones` allow while RocksDB compacts in the
+/// background. Each entry is judged against its record's header and latest
+/// state as stored before the compaction: a `version` entry goes once a newer
+/// version has superseded it for long enough, and a record's `state`, `head`
+/// and `version` entries all go once it is a tombstone past its grace period.
+/// A live record's latest state, header and version are always kept.
+struct RetentionFilter {
+    version_retention: u64,
+    expire_tombstones: bool,
+    /// Set by `RocksStorage::new` once its backfills are done; until then
+    /// headers may be missing, so every entry is kept
+    storage: OnceLock<FilterContext>,
+}
 
-        Ok(ts)
+struct FilterContext {
+    /// Weak so the filter, which the database owns, doesn't keep it open
+    db: Weak<DB>,
+    commit_ts_counter: Arc<RwLock<CommitTs>>,
+}
+
+impl RetentionFilter {
+    /// `None` if `config` enables neither rule
+    fn new(config: &StorageConfig) -> Option<Self> {
+        (config.compaction_version_retention > 0 || config.compaction_expire_tombstones).then(|| Self {
+            version_retention: config.compaction_version_retention,
+            expire_tombstones: config.compaction_expire_tombstones,
+            storage: OnceLock::new(),
+        })
     }
 
-    fn flush(&self) -> Result<()> {
-        self.db.flush()?;
-        Ok(())
+    fn attach(&self, storage: &RocksStorage) {
+        let _ = self.storage.set(FilterContext {
+            db: Arc::downgrade(&storage.db),
+            commit_ts_counter: storage.commit_ts_counter.clone(),
+        });
     }
 
-    fn create_snapshot(&self) -> Result<Snapshot> {
-        let commit_ts_counter = self.commit_ts_counter.read().unwrap();
-        let records = self.get_all_state()?;
+    fn decide(&self, key: &[u8], value: &[u8]) -> CompactionDecision {
+        match self.should_drop(key, value) {
+            Ok(true) => CompactionDecision::Remove,
+            Ok(false) => CompactionDecision::Keep,
+            Err(e) => {
+                warn!(error = %e, "Compaction filter couldn't judge an entry; keeping it");
+                CompactionDecision::Keep
+            }
+        }
+    }
 
-        let metadata = SnapshotMetadata {
-            version: SNAPSHOT_VERSION,
-            snapshot_ts: *commit_ts_counter,
-            record_count: records.len(),
-            created_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+    fn should_drop(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let is_record_key = [key_codec::STATE_TAG, key_codec::HEAD_TAG, key_codec::VERSION_TAG]
+            .iter()
+            .any(|tag| key.starts_with(tag));
+        let Some(context) = self.storage.get().filter(|_| is_record_key) else { return Ok(false) };
+        let Some(db) = context.db.upgrade() else { return Ok(false) };
+        let latest_state = |record_id: &RecordId| -> Result<Option<StateRecord>> {
+            db.get(key_codec::state_key(record_id))?.map(|value| RocksStorage::decode_record(&value)).transpose()
         };
 
-        Ok(Snapshot { metadata, records })
+        match key_codec::decode_key(key)? {
+            key_codec::DecodedKey::State(_) => Ok(self.is_expired_tombstone(&RocksStorage::decode_record(value)?)),
+            key_codec::DecodedKey::Head(record_id) => {
+                let Some(head) = RocksStorage::decode_head(value).filter(|head| head.deleted && self.expire_tombstones) else {
+                    return Ok(false);
+                };
+                // No state left means an earlier compaction dropped the tombstone
+                Ok(latest_state(&record_id)?
+                    .is_none_or(|state| state.version == head.version && self.is_expired_tombstone(&state)))
+            }
+            key_codec::DecodedKey::Version(record_id, version) => {
+                let head = db.get(key_codec::head_key(&record_id))?.and_then(|head| RocksStorage::decode_head(&head));
+                let Some(head) = head else {
+                    // Likewise for both state and header
+                    return Ok(self.expire_tombstones && latest_state(&record_id)?.is_none());
+                };
+                if head.deleted && latest_state(&record_id)?.is_some_and(|state| self.is_expired_tombstone(&state)) {
+                    return Ok(true);
+                }
+                if self.version_retention == 0 || version >= head.version {
+                    return Ok(false);
+                }
+
+                // Superseded by the next version, or if that was trimmed, no
+                // later than by the latest
+                let superseded_ts = match db.get(key_codec::version_key(&record_id, version + 1))? {
+                    Some(next) => RocksStorage::decode_record(&next)?.commit_ts,
+                    None => head.commit_ts,
+                };
+                let newest_ts = *context.commit_ts_counter.read().unwrap();
+                Ok(newest_ts.saturating_sub(superseded_ts) >= self.version_retention)
+            }
+            _ => Ok(false),
+        }
     }
 
-    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
-        let path = self.snapshot_path();
-        let json = serde_json::to_string_pretty(snapshot)?;
-        std::fs::write(path, json)?;
+    fn is_expired_tombstone(&self, record: &StateRecord) -> bool {
+        self.expire_tombstones && record.deleted && record.purge_after.is_some_and(|purge_after| purge_after <= unix_now_ms())
+    }
+}
+
+pub struct RocksStorage {
+    db: Arc<DB>,
+    config: StorageConfig,
+    commit_ts_counter: Arc<RwLock<CommitTs>>,
+    /// Newest commit whose writes have all landed. The counter is bumped
+    /// before a commit writes its records; this only moves once its event is
+    /// appended, so it never names a half-applied commit.
+    applied_commit_ts: AtomicU64,
+    /// Per-namespace sequences, loaded from their `counter` keys on first use
+    namespace_ts_counters: Mutex<HashMap<Namespace, CommitTs>>,
+    /// Per-namespace usage, loaded from their `counter` keys on first use.
+    /// Held while a write updates one, so counter and record land together.
+    namespace_usage: Mutex<HashMap<Namespace, NamespaceUsage>>,
+    /// Latest-state cache for `read_state`; `None` when disabled
+    read_cache: Option<Mutex<ReadCache>>,
+    /// Set by `Durability::Async` commits, cleared by the flush covering them
+    flush_pending: AtomicBool,
+    /// Whether entries may refer to blobs: dedup is on, or was when some
+    /// were written. Writes then check the entries they replace for
+    /// references to release.
+    stores_blobs: bool,
+    /// Held while blob reference counts are read and updated
+    blob_refs: Mutex<()>,
+}
+
+/// Decoded latest records, kept coherent with `write_state`
+struct ReadCache {
+    records: LruCache<RecordId, StateRecord>,
+    /// Bumped by every write. A reader that missed only fills the cache if no
+    /// write happened while it was reading RocksDB, so a slow reader can never
+    /// overwrite a newer cached record with the one it read.
+    write_gen: u64,
+}
+
+impl RocksStorage {
+    pub fn new(config: StorageConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.data_dir)?;
+
+        let db_path = config.data_dir.join("rocksdb");
+        let mut options = Self::open_options();
+        let retention_filter = RetentionFilter::new(&config).map(Arc::new);
+        if let Some(filter) = &retention_filter {
+            let filter = filter.clone();
+            options.set_compaction_filter(RETENTION_FILTER_NAME, move |_level, key, value| filter.decide(key, value));
+        }
+        let db = DB::open(&options, &db_path).map_err(|e| open_error(&db_path, e))?;
+        Self::migrate_legacy_keys(&db)?;
+
+        // Load current commit timestamp
+        let persisted_ts = if let Some(value) = db.get(b"__commit_ts__")? {
+            u64::from_be_bytes(value.try_into().unwrap_or([0; 8]))
+        } else {
+            0
+        };
+
+        // The counter and the events are not flushed together, so after a crash
+        // the counter may lag the newest event. Never hand out a used timestamp.
+        let last_event = Self::last_event(&db)?;
+        let last_event_ts = last_event.as_ref().map_or(0, |event| event.commit_ts);
+        let commit_ts = persisted_ts.max(last_event_ts);
+        if commit_ts != persisted_ts {
+            warn!(
+                persisted_ts = persisted_ts,
+                last_event_ts = last_event_ts,
+                "Commit timestamp counter behind event log; repairing"
+            );
+            db.put(b"__commit_ts__", commit_ts.to_be_bytes())?;
+            db.flush()?;
+        }
+
+        // Likewise for the namespace sequences the newest commit stamped
+        for (namespace, namespace_ts) in last_event.map(|event| event.namespace_ts).unwrap_or_default() {
+            let key = Self::namespace_ts_key(&namespace);
+            if Self::load_namespace_ts(&db, &key)? < namespace_ts {
+                warn!(namespace = %namespace, namespace_ts = namespace_ts, "Namespace sequence behind event log; repairing");
+                db.put(&key, namespace_ts.to_be_bytes())?;
+            }
+        }
+
+        Self::backfill_heads(&db)?;
+        Self::backfill_recency(&db)?;
+        Self::backfill_agent_events(&db)?;
+
+        let storage = Self::with_db(db, config, commit_ts);
+        if let Some(filter) = retention_filter {
+            filter.attach(&storage);
+        }
+        Ok(storage)
+    }
+
+    fn open_options() -> Options {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts
+    }
+
+    /// Rebuild the RocksDB metadata of `config.data_dir` from the table and
+    /// log files that survive, so a directory `new` reported as
+    /// `StorageCorrupt` opens again. Writes in damaged files may be lost, so
+    /// back the directory up first. The daemon must not be running.
+    pub fn repair(config: &StorageConfig) -> Result<()> {
+        let db_path = config.data_dir.join("rocksdb");
+        if !db_path.exists() {
+            return Err(StatehouseError::StorageUnavailable {
+                path: db_path.display().to_string(),
+                reason: "no such directory".to_string(),
+            }
+            .into());
+        }
+
+        error!(path = %db_path.display(), "Repairing RocksDB data; records in damaged files may be lost");
+        DB::repair(&Self::open_options(), &db_path).map_err(|e| open_error(&db_path, e))?;
+        warn!(path = %db_path.display(), "RocksDB repair finished; run `statehoused verify <data_dir>` to check the result");
         Ok(())
     }
 
-    fn load_snapshot(&self) -> Result<Option<Snapshot>> {
-        let path = self.snapshot_path();
-        
-        if !path.exists() {
-            return Ok(None);
+    /// Open the data directory of a daemon running elsewhere without writing
+    /// to it. RocksDB's plain read-only mode is frozen at open, so this opens
+    /// a secondary instance instead, which `catch_up_with_primary` moves
+    /// forward to the primary's latest writes while reads carry on.
+    /// `secondary_dir` holds the instance's own logs and must not be shared.
+    /// Every write fails.
+    pub fn open_read_only(config: StorageConfig, secondary_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(secondary_dir)?;
+        let db_path = config.data_dir.join("rocksdb");
+        let mut options = Options::default();
+        // A secondary can't reopen a table file once the primary's compactions
+        // have deleted it, so it must keep every one it opens open
+        options.set_max_open_files(-1);
+        // And it must open every column family the primary has
+        let column_families = DB::list_cf(&options, &db_path).map_err(|e| open_error(&db_path, e))?;
+        let db = DB::open_cf_as_secondary(&options, &db_path, &secondary_dir.to_path_buf(), &column_families)
+            .map_err(|e| open_error(&db_path, e))?;
+        let commit_ts = Self::last_event(&db)?.map_or(0, |event| event.commit_ts);
+        Ok(Self::with_db(db, config, commit_ts))
+    }
+
+    fn with_db(db: DB, config: StorageConfig, commit_ts: CommitTs) -> Self {
+        let read_cache = (config.read_cache_capacity > 0).then(|| {
+            Mutex::new(ReadCache {
+                records: LruCache::new(config.read_cache_capacity),
+                write_gen: 0,
+            })
+        });
+
+        let stores_blobs = config.value_dedup_threshold > 0
+            || db.prefix_iterator(key_codec::BLOB_REF_TAG).next().is_some_and(|item| {
+                item.is_ok_and(|(key, _)| key.starts_with(key_codec::BLOB_REF_TAG))
+            });
+
+        Self {
+            db: Arc::new(db),
+            config,
+            commit_ts_counter: Arc::new(RwLock::new(commit_ts)),
+            applied_commit_ts: AtomicU64::new(commit_ts),
+            namespace_ts_counters: Mutex::new(HashMap::new()),
+            namespace_usage: Mutex::new(HashMap::new()),
+            read_cache,
+            flush_pending: AtomicBool::new(false),
+            stores_blobs,
+            blob_refs: Mutex::new(()),
         }
+    }
 
-        let json = std::fs::read_to_string(path)?;
-        let snapshot: Snapshot = serde_json::from_str(&json)?;
+    /// Pick up what the primary has written since the last call, for storage
+    /// opened with `open_read_only`. Returns the newest commit now visible:
+    /// a primary appends a commit's event after its records, so everything up
+    /// to the last event is complete.
+    pub fn catch_up_with_primary(&self) -> Result<CommitTs> {
+        self.db.try_catch_up_with_primary()?;
+        let commit_ts = Self::last_event(&self.db)?.map_or(0, |event| event.commit_ts);
+        *self.commit_ts_counter.write().unwrap() = commit_ts;
+        self.applied_commit_ts.store(commit_ts, Ordering::SeqCst);
+        // Reloaded from their counter keys on next use
+        self.namespace_ts_counters.lock().unwrap().clear();
+        self.namespace_usage.lock().unwrap().clear();
+        self.invalidate_read_cache();
+        Ok(commit_ts)
+    }
 
-        // Verify snapshot version
-        if snapshot.metadata.version != SNAPSHOT_VERSION {
-            return Err(anyhow::anyhow!(
-                "Snapshot version mismatch: expected {}, got {}",
-                SNAPSHOT_VERSION,
-                snapshot.metadata.version
-            ));
+    /// Restore state from snapshot. Fails with `StatehouseError::SnapshotRegression`
+    /// if the snapshot is older than the persisted `__commit_ts__`, unless
+    /// `allow_regression` is set.
+    pub fn restore_from_snapshot(&self, snapshot: &Snapshot, allow_regression: bool) -> Result<()> {
+        check_snapshot_regression(snapshot.metadata.snapshot_ts, self.current_commit_ts()?, allow_regression)?;
+
+        // Write all records from snapshot
+        for record in &snapshot.records {
+            self.write_state(record.clone())?;
         }
 
-        Ok(Some(snapshot))
+        // Update commit timestamp counter
+        self.set_commit_ts(snapshot.metadata.snapshot_ts)?;
+
+        self.flush()?;
+        Ok(())
     }
 
-    fn get_all_state(&self) -> Result<Vec<StateRecord>> {
+    /// Restore state from a JSONL snapshot, streaming records from `reader`.
+    /// The regression check matches `restore_from_snapshot`. Records are
+    /// written as they are read, so a truncated file leaves the records
+    /// before the damage in place and returns an error.
+    pub fn import_jsonl<R: BufRead>(&self, reader: R, allow_regression: bool) -> Result<SnapshotMetadata> {
+        let mut snapshot = JsonlSnapshotReader::new(reader)?;
+        let metadata = snapshot.metadata().clone();
+        check_snapshot_regression(metadata.snapshot_ts, self.current_commit_ts()?, allow_regression)?;
+
+        for record in snapshot.by_ref() {
+            self.write_state(record?)?;
+        }
+
+        self.set_commit_ts(metadata.snapshot_ts)?;
+
+        self.flush()?;
+        Ok(metadata)
+    }
+
+    /// Drop every cached record, for writes that bypass `write_state`
+    fn invalidate_read_cache(&self) {
+        if let Some(cache) = &self.read_cache {
+            let mut cache = cache.lock().unwrap();
+            cache.records.clear();
+            cache.write_gen += 1;
+        }
+    }
+
+    /// Read and decode the latest record from RocksDB
+    fn read_state_uncached(&self, record_id: &RecordId) -> Result<Option<StateRecord>> {
+        let key = key_codec::state_key(record_id);
+        if let Some(value) = self.db.get(&key)? {
+            let record: StateRecord = Self::load_record(&*self.db, &value)?;
+            Ok(Some(record))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Overwrite the commit timestamp counter and persist it
+    fn set_commit_ts(&self, commit_ts: CommitTs) -> Result<()> {
+        let mut counter = self.commit_ts_counter.write().unwrap();
+        *counter = commit_ts;
+        self.db.put(b"__commit_ts__", counter.to_be_bytes())?;
+        self.applied_commit_ts.store(commit_ts, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Newest entry in the event log
+    fn last_event(db: &DB) -> Result<Option<EventLogEntry>> {
+        let seek_key = key_codec::prefix_end(key_codec::EVENT_TAG);
+        let mut iter = db.iterator(IteratorMode::From(&seek_key, Direction::Reverse));
+        match iter.next() {
+            Some(item) => {
+                let (key, value) = item?;
+                if !key.starts_with(key_codec::EVENT_TAG) {
+                    return Ok(None);
+                }
+                Ok(Some(serde_json::from_slice(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn namespace_ts_key(namespace: &str) -> Vec<u8> {
+        key_codec::counter_key(key_codec::NAMESPACE_TS_COUNTER, namespace)
+    }
+
+    /// Persisted value of a namespace sequence (0 if never used)
+    fn load_namespace_ts(db: &DB, key: &[u8]) -> Result<CommitTs> {
+        Ok(match db.get(key)? {
+            Some(value) => u64::from_be_bytes(value.try_into().unwrap_or([0; 8])),
+            None => 0,
+        })
+    }
+
+    /// Set a namespace sequence with `update`, loading it first if needed,
+    /// and persist it when it changes. Returns the new value.
+    fn update_namespace_ts(&self, namespace: &str, update: impl FnOnce(CommitTs) -> CommitTs) -> Result<CommitTs> {
+        let key = Self::namespace_ts_key(namespace);
+        let mut counters = self.namespace_ts_counters.lock().unwrap();
+        let current = match counters.get(namespace) {
+            Some(current) => *current,
+            None => Self::load_namespace_ts(&self.db, &key)?,
+        };
+
+        let updated = update(current);
+        if updated != current {
+            self.db.put(&key, updated.to_be_bytes())?;
+        }
+        counters.insert(namespace.to_string(), updated);
+        Ok(updated)
+    }
+
+    fn namespace_usage_key(namespace: &str) -> Vec<u8> {
+        key_codec::counter_key(key_codec::NAMESPACE_USAGE_COUNTER, namespace)
+    }
+
+    /// Usage counter value: records, then bytes, each 8-byte big-endian
+    fn encode_usage(usage: NamespaceUsage) -> [u8; 16] {
+        let mut value = [0u8; 16];
+        value[..8].copy_from_slice(&usage.records.to_be_bytes());
+        value[8..].copy_from_slice(&usage.bytes.to_be_bytes());
+        value
+    }
+
+    /// A namespace's usage from the cache or its counter. Data written before
+    /// usage was tracked has no counter; its usage is counted from the
+    /// namespace's headers and persisted with the next write.
+    fn load_namespace_usage(&self, cached: &HashMap<Namespace, NamespaceUsage>, namespace: &str) -> Result<NamespaceUsage> {
+        if let Some(usage) = cached.get(namespace) {
+            return Ok(*usage);
+        }
+        if let Some(value) = self.db.get(Self::namespace_usage_key(namespace))? {
+            if let Ok(value) = <[u8; 16]>::try_from(value.as_slice()) {
+                return Ok(NamespaceUsage {
+                    records: u64::from_be_bytes(value[..8].try_into().unwrap()),
+                    bytes: u64::from_be_bytes(value[8..].try_into().unwrap()),
+                });
+            }
+        }
+
+        let prefix = key_codec::namespace_head_prefix(namespace);
+        let mut usage = NamespaceUsage::default();
+        for item in self.db.prefix_iterator(&prefix) {
+            let (key, head) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            usage = usage.replace(None, Self::decode_head(&head).as_ref());
+        }
+        Ok(usage)
+    }
+
+    /// Get path for snapshot file
+    fn snapshot_path(&self) -> PathBuf {
+        self.config.data_dir.join("snapshot.json")
+    }
+
+    /// Get path for a namespace snapshot file (`snapshot-<ns>.json`, plus
+    /// `.zst` if `compressed`). Bytes outside `[A-Za-z0-9_.-]` are
+    /// percent-encoded to keep the name filesystem-safe.
+    fn namespace_snapshot_path(&self, namespace: &str, compressed: bool) -> PathBuf {
+        let mut name = String::from("snapshot-");
+        for byte in namespace.bytes() {
+            if byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'.' | b'-') {
+                name.push(byte as char);
+            } else {
+                name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        name.push_str(if compressed { ".json.zst" } else { ".json" });
+        self.config.data_dir.join(name)
+    }
+
+    /// Every key and value under `prefix`
+    fn entries_under(&self, prefix: &[u8]) -> Result<Vec<StoredEntry>> {
+        let mut entries = Vec::new();
+        for item in self.db.prefix_iterator(prefix) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    /// Latest state records (including tombstones) in one namespace, with
+    /// the blob each refers to; a referenced value is left unresolved
+    fn namespace_state(&self, namespace: &str) -> Result<Vec<(StateRecord, Option<String>)>> {
+        let prefix = key_codec::namespace_state_prefix(namespace);
         let mut records = Vec::new();
-        let iter = self.db.prefix_iterator(b"state:");
+
+        let iter = self.db.prefix_iterator(&prefix);
+        for item in iter {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            records.push((Self::decode_record(&value)?, Self::referenced_blob(&value)?));
+        }
+
+        Ok(records)
+    }
+
+    /// Latest version of a record with `commit_ts <= as_of`, read through
+    /// `source` and its `iterator` (the live DB or a pinned snapshot)
+    fn version_as_of<I>(source: &impl BlobSource, iterator: impl FnOnce(IteratorMode<'_>) -> I, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>>
+    where
+        I: Iterator<Item = RawEntry>,
+    {
+        // Walk this key's versions newest-first; versions are stamped in commit_ts order
+        let prefix = key_codec::version_prefix(record_id);
+        let seek_key = key_codec::version_key(record_id, Version::MAX);
+        let iter = iterator(IteratorMode::From(&seek_key, Direction::Reverse));
 
         for item in iter {
             let (key, value) = item?;
-            let key_str = String::from_utf8_lossy(&key);
-            
-            if !key_str.starts_with("state:") {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let record: StateRecord = Self::decode_record(&value)?;
+            if record.commit_ts <= as_of {
+                return Self::resolve_blob(source, &value, record).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Number of threads used to scan the `state` keyspace
+    fn scan_workers() -> usize {
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+
+    /// Split the `state` keyspace into one `[start, end)` range per namespace,
+    /// in key order
+    fn namespace_ranges(snapshot: &rocksdb::Snapshot<'_>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut ranges = Vec::new();
+        let mut seek = key_codec::STATE_TAG.to_vec();
+
+        while let Some(item) = snapshot.iterator(IteratorMode::From(&seek, Direction::Forward)).next() {
+            let key = item?.0;
+            if !key.starts_with(key_codec::STATE_TAG) {
                 break;
             }
 
-            let record: StateRecord = serde_json::from_slice(&value)?;
-            records.push(record);
+            let namespace = key_codec::decode_state_key(&key)?.namespace;
+            let start = key_codec::namespace_state_prefix(&namespace);
+            let end = key_codec::prefix_end(&start);
+
+            ranges.push((start, end.clone()));
+            seek = end;
         }
 
+        Ok(ranges)
+    }
+
+    /// A pinned RocksDB snapshot and the newest commit fully visible in it.
+    /// The watermark is read first, so every commit up to it is complete in
+    /// the snapshot; later commits may be partly visible and must be read
+    /// as of the watermark.
+    fn pin_applied(&self) -> (rocksdb::Snapshot<'_>, CommitTs) {
+        let applied_ts = self.applied_commit_ts.load(Ordering::SeqCst);
+        (self.db.snapshot(), applied_ts)
+    }
+
+    /// State records in `[start, end)` of `db_snapshot` as of `snapshot_ts`.
+    /// Records committed after `snapshot_ts` fall back to the version current
+    /// at it.
+    fn scan_state_range(db_snapshot: &rocksdb::Snapshot<'_>, start: &[u8], end: &[u8], snapshot_ts: CommitTs) -> Result<Vec<StateRecord>> {
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_upper_bound(end.to_vec());
+
+        let mut records = Vec::new();
+        for item in db_snapshot.iterator_opt(IteratorMode::From(start, Direction::Forward), readopts) {
+            let (_, value) = item?;
+            let record: StateRecord = Self::decode_record(&value)?;
+            if record.commit_ts <= snapshot_ts {
+                records.push(Self::resolve_blob(db_snapshot, &value, record)?);
+                continue;
+            }
+
+            let record_id = RecordId::new(record.namespace, record.agent_id, record.key);
+            if let Some(record) = Self::version_as_of(db_snapshot, |mode| db_snapshot.iterator(mode), &record_id, snapshot_ts)? {
+                records.push(record);
+            }
+        }
         Ok(records)
     }
+
+    /// Latest state records as of `snapshot_ts`, in key order. Reads go through
+    /// the pinned `db_snapshot` so commits can proceed concurrently. Namespace
+    /// ranges are spread across up to `workers` threads.
+    fn scan_state(db_snapshot: &rocksdb::Snapshot<'_>, snapshot_ts: CommitTs, workers: usize) -> Result<Vec<StateRecord>> {
+        let ranges = Self::namespace_ranges(db_snapshot)?;
+        let workers = workers.clamp(1, ranges.len().max(1));
+
+        let scan_range = |start: &[u8], end: &[u8]| Self::scan_state_range(db_snapshot, start, end, snapshot_ts);
+
+        let mut per_range: Vec<Vec<StateRecord>> = vec![Vec::new(); ranges.len()];
+        std::thread::scope(|scope| -> Result<()> {
+            let handles: Vec<_> = (0..workers)
+                .map(|worker| {
+                    let ranges = &ranges;
+                    let scan_range = &scan_range;
+                    scope.spawn(move || -> Result<Vec<(usize, Vec<StateRecord>)>> {
+                        ranges
+                            .iter()
+                            .enumerate()
+                            .skip(worker)
+                            .step_by(workers)
+                            .map(|(index, (start, end))| Ok((index, scan_range(start, end)?)))
+                            .collect()
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                let scanned = handle.join().map_err(|_| anyhow::anyhow!("State scan worker panicked"))??;
+                for (index, records) in scanned {
+                    per_range[index] = records;
+                }
+            }
+            Ok(())
+        })?;
+
+        // Ranges are in key order, and key order is (namespace, agent, key) order
+        Ok(per_range.into_iter().flatten().collect())
+    }
+
+    /// A record as stored under `state` and `version` keys: its JSON, or
+    /// once that reaches `value_compression_threshold`, `COMPRESSED_ZSTD`
+    /// followed by the JSON compressed. JSON always starts with `{`, so the
+    /// two can't be confused and records stored before compression existed
+    /// still read. A value reaching `value_dedup_threshold` is added to
+    /// `blobs` instead, and the record without it stored as `BLOB_REF`
+    /// followed by its JSON, whose `value_hash` names the blob.
+    fn encode_record(&self, record: &StateRecord, blobs: &mut BlobChanges) -> Result<Vec<u8>> {
+        let threshold = self.config.value_dedup_threshold;
+        if let Some(value) = record.value.as_ref().filter(|value| threshold > 0 && json_size(value) >= threshold) {
+            let json = serde_json::to_vec(value)?;
+            let hash = hash_json(&json);
+            if !blobs.contents.contains_key(&hash) {
+                blobs.contents.insert(hash.clone(), self.compress(json)?);
+            }
+            let reference = StateRecord {
+                namespace: record.namespace.clone(),
+                agent_id: record.agent_id.clone(),
+                key: record.key.clone(),
+                value: None,
+                labels: record.labels.clone(),
+                value_hash: Some(hash),
+                ..*record
+            };
+            let mut encoded = vec![BLOB_REF];
+            serde_json::to_writer(&mut encoded, &reference)?;
+            return Ok(encoded);
+        }
+        self.compress(serde_json::to_vec(record)?)
+    }
+
+    /// `json`, or `COMPRESSED_ZSTD` followed by it compressed once it
+    /// reaches `value_compression_threshold`
+    fn compress(&self, json: Vec<u8>) -> Result<Vec<u8>> {
+        let threshold = self.config.value_compression_threshold;
+        if threshold == 0 || json.len() < threshold {
+            return Ok(json);
+        }
+        let mut encoded = vec![COMPRESSED_ZSTD];
+        zstd::stream::copy_encode(json.as_slice(), &mut encoded, ZSTD_LEVEL)?;
+        Ok(encoded)
+    }
+
+    /// Inverse of `compress`
+    fn decompress(stored: &[u8]) -> Result<Cow<'_, [u8]>> {
+        match stored.split_first() {
+            Some((&COMPRESSED_ZSTD, compressed)) => Ok(Cow::Owned(zstd::stream::decode_all(compressed)?)),
+            _ => Ok(Cow::Borrowed(stored)),
+        }
+    }
+
+    /// Inverse of `encode_record`, except that a record stored by reference
+    /// comes back without its value; `load_record` resolves it
+    fn decode_record(value: &[u8]) -> Result<StateRecord> {
+        match value.split_first() {
+            Some((&BLOB_REF, json)) => Ok(serde_json::from_slice(json)?),
+            _ => Ok(serde_json::from_slice(&Self::decompress(value)?)?),
+        }
+    }
+
+    /// `decode_record`, with a blob reference resolved through `source`
+    fn load_record(source: &impl BlobSource, value: &[u8]) -> Result<StateRecord> {
+        Self::resolve_blob(source, value, Self::decode_record(value)?)
+    }
+
+    /// Fill in the value of `record`, decoded from `value`, if it was stored
+    /// by reference
+    fn resolve_blob(source: &impl BlobSource, value: &[u8], mut record: StateRecord) -> Result<StateRecord> {
+        if value.first() != Some(&BLOB_REF) {
+            return Ok(record);
+        }
+        let hash = record.value_hash.as_deref().ok_or_else(|| anyhow::anyhow!("Blob reference without a hash"))?;
+        let blob = source.get_blob(hash)?.ok_or_else(|| anyhow::anyhow!("Blob {} is missing", hash))?;
+        let json = Self::decompress(&blob)?;
+        // The hash doubles as a checksum of the shared copy
+        if hash_json(&json) != hash {
+            return Err(anyhow::anyhow!("Blob {} doesn't match its hash", hash));
+        }
+        record.value = Some(serde_json::from_slice(&json)?);
+        Ok(record)
+    }
+
+    /// A stored `state` or `version` entry with its record moved to
+    /// `namespace`, stored the same way; a blob reference still names the
+    /// same blob
+    fn renamed_entry(&self, value: &[u8], namespace: &str) -> Result<Vec<u8>> {
+        let mut record = Self::decode_record(value)?;
+        record.namespace = namespace.to_string();
+        if value.first() == Some(&BLOB_REF) {
+            let mut encoded = vec![BLOB_REF];
+            serde_json::to_writer(&mut encoded, &record)?;
+            return Ok(encoded);
+        }
+        self.compress(serde_json::to_vec(&record)?)
+    }
+
+    /// The blob a stored record refers to, if it was stored by reference
+    fn referenced_blob(value: &[u8]) -> Result<Option<String>> {
+        match value.first() {
+            Some(&BLOB_REF) => Ok(Self::decode_record(value)?.value_hash),
+            _ => Ok(None),
+        }
+    }
+
+    /// Write `batch` with the reference counts `blobs` changes, saving
+    /// blobs on their first reference and deleting them with their last
+    fn write_with_blobs(&self, mut batch: WriteBatch, blobs: BlobChanges) -> Result<()> {
+        if blobs.refs.is_empty() {
+            self.db.write(batch)?;
+            return Ok(());
+        }
+
+        let _blob_refs = self.blob_refs.lock().unwrap();
+        for (hash, entries) in blobs.refs {
+            let ref_key = key_codec::blob_ref_key(&hash);
+            let count = match self.db.get(&ref_key)? {
+                Some(value) => u64::from_be_bytes(value.as_slice().try_into()?),
+                None => 0,
+            };
+            match count.saturating_add_signed(entries) {
+                0 => {
+                    batch.delete(&ref_key);
+                    batch.delete(key_codec::blob_key(&hash));
+                }
+                updated => {
+                    if let Some(contents) = blobs.contents.get(&hash).filter(|_| count == 0) {
+                        batch.put(key_codec::blob_key(&hash), contents);
+                    }
+                    batch.put(&ref_key, updated.to_be_bytes());
+                }
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Fixed-layout summary of a record's latest state, kept under `head` keys so
+    /// existence and metadata checks don't parse the (possibly large) JSON value:
+    /// byte 0 is the deleted flag, then version, commit_ts and the value's
+    /// serialized length as big-endian u64s.
+    fn encode_head(record: &StateRecord) -> [u8; HEAD_LEN] {
+        let meta = StateMeta::of(record);
+        let mut head = [0u8; HEAD_LEN];
+        head[0] = meta.deleted as u8;
+        head[1..9].copy_from_slice(&meta.version.to_be_bytes());
+        head[9..17].copy_from_slice(&meta.commit_ts.to_be_bytes());
+        head[17..25].copy_from_slice(&meta.value_bytes.to_be_bytes());
+        head[25..33].copy_from_slice(&meta.expires_at.unwrap_or(0).to_be_bytes());
+        head
+    }
+
+    /// Inverse of `encode_head`; `None` if `head` isn't in the current layout
+    fn decode_head(head: &[u8]) -> Option<StateMeta> {
+        if head.len() != HEAD_LEN {
+            return None;
+        }
+        let u64_at = |pos: usize| u64::from_be_bytes(head[pos..pos + 8].try_into().unwrap());
+        Some(StateMeta {
+            deleted: head[0] != 0,
+            version: u64_at(1),
+            commit_ts: u64_at(9),
+            value_bytes: u64_at(17),
+            expires_at: Some(u64_at(25)).filter(|&expires_at| expires_at > 0),
+        })
+    }
+
+    /// Write `head` entries for state written before they existed, or before
+    /// the current header layout. Runs once per data directory and layout,
+    /// marked by `__heads__` holding `HEAD_FORMAT`.
+    fn backfill_heads(db: &DB) -> Result<()> {
+        if db.get(b"__heads__")?.as_deref() == Some(HEAD_FORMAT) {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+        for item in db.prefix_iterator(key_codec::STATE_TAG) {
+            let (key, value) = item?;
+            if !key.starts_with(key_codec::STATE_TAG) {
+                break;
+            }
+            let record: StateRecord = Self::load_record(db, &value)?;
+            let record_id = RecordId::new(record.namespace.clone(), record.agent_id.clone(), record.key.clone());
+            batch.put(key_codec::head_key(&record_id), Self::encode_head(&record));
+        }
+
+        if !batch.is_empty() {
+            info!(records = batch.len(), "Backfilling record headers");
+        }
+        batch.put(b"__heads__", HEAD_FORMAT);
+        db.write(batch)?;
+        Ok(())
+    }
+
+    /// Build the recency index from the headers of data written before it
+    /// existed. Runs once per data directory, marked by `__recent__`.
+    fn backfill_recency(db: &DB) -> Result<()> {
+        if db.get(b"__recent__")?.is_some() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+        for item in db.prefix_iterator(key_codec::HEAD_TAG) {
+            let (key, head) = item?;
+            if !key.starts_with(key_codec::HEAD_TAG) {
+                break;
+            }
+            if let Some(meta) = Self::decode_head(&head).filter(|meta| !meta.deleted) {
+                batch.put(key_codec::recent_key(&key_codec::decode_head_key(&key)?, meta.commit_ts), []);
+            }
+            if batch.len() >= WRITE_BATCH_SIZE {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        batch.put(b"__recent__", b"");
+        db.write(batch)?;
+        Ok(())
+    }
+
+    /// Index events written before the agent event index existed. Runs once
+    /// per data directory, marked by `__agent_events__`.
+    fn backfill_agent_events(db: &DB) -> Result<()> {
+        if db.get(b"__agent_events__")?.is_some() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut indexed = 0usize;
+        for item in db.prefix_iterator(key_codec::EVENT_TAG) {
+            let (key, value) = item?;
+            if !key.starts_with(key_codec::EVENT_TAG) {
+                break;
+            }
+            let event: EventLogEntry = serde_json::from_slice(&value)?;
+            for index_key in Self::agent_event_keys(&event) {
+                batch.put(index_key, event.commit_ts.to_be_bytes());
+            }
+            indexed += 1;
+            if batch.len() >= WRITE_BATCH_SIZE {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        if indexed > 0 {
+            info!(events = indexed, "Backfilling agent event index");
+        }
+        batch.put(b"__agent_events__", b"");
+        db.write(batch)?;
+        Ok(())
+    }
+
+    /// Agent event index entries for `event`, one per agent it touches
+    fn agent_event_keys(event: &EventLogEntry) -> BTreeSet<Vec<u8>> {
+        event
+            .operations
+            .iter()
+            .map(|op| key_codec::agent_event_key(&op.namespace, &op.agent_id, event.commit_ts))
+            .collect()
+    }
+
+    /// Raw `version` entries of one key, newest first, with their decoded
+    /// records (values stored by reference left unresolved)
+    fn version_entries(&self, record_id: &RecordId) -> Result<Vec<VersionEntry>> {
+        let prefix = key_codec::version_prefix(record_id);
+        let seek_key = key_codec::version_key(record_id, Version::MAX);
+        let mut entries = Vec::new();
+
+        for item in self.db.iterator(IteratorMode::From(&seek_key, Direction::Reverse)) {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let record: StateRecord = Self::decode_record(&value)?;
+            let size = key.len() + value.len();
+            entries.push((key, size, record, Self::referenced_blob(&value)?));
+        }
+
+        Ok(entries)
+    }
+
+    /// Rewrite keys from the original `state:{ns}:{agent}:{key}` text layout,
+    /// where ':' in a component was ambiguous, into the `key_codec` layout.
+    /// Records and events are re-keyed from their values rather than by
+    /// parsing the old keys; headers are dropped for `backfill_heads` to
+    /// rebuild. Runs once per data directory, marked by `__key_format__`.
+    fn migrate_legacy_keys(db: &DB) -> Result<()> {
+        if db.get(b"__key_format__")?.is_some() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut migrated = 0usize;
+        for legacy_prefix in [b"state:".as_slice(), b"version:", b"head:", b"event:", b"__namespace_ts__:"] {
+            for item in db.prefix_iterator(legacy_prefix) {
+                let (key, value) = item?;
+                if !key.starts_with(legacy_prefix) {
+                    break;
+                }
+
+                let new_key = match legacy_prefix {
+                    b"state:" => {
+                        let record: StateRecord = serde_json::from_slice(&value)?;
+                        Some(key_codec::state_key(&RecordId::new(record.namespace, record.agent_id, record.key)))
+                    }
+                    b"version:" => {
+                        let record: StateRecord = serde_json::from_slice(&value)?;
+                        let version = record.version;
+                        Some(key_codec::version_key(&RecordId::new(record.namespace, record.agent_id, record.key), version))
+                    }
+                    b"event:" => {
+                        let event: EventLogEntry = serde_json::from_slice(&value)?;
+                        Some(key_codec::event_key(event.commit_ts))
+                    }
+                    b"__namespace_ts__:" => {
+                        let namespace = std::str::from_utf8(&key[legacy_prefix.len()..])?;
+                        Some(Self::namespace_ts_key(namespace))
+                    }
+                    _ => None,
+                };
+                if let Some(new_key) = new_key {
+                    batch.put(new_key, &value);
+                }
+                batch.delete(&key);
+                migrated += 1;
+
+                if batch.len() >= WRITE_BATCH_SIZE {
+                    db.write(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+
+        if migrated > 0 {
+            info!(keys = migrated, "Migrated keys to the escaped key format");
+        }
+        // Also on a retry after a crash part-way through, when the old headers are already gone
+        batch.delete(b"__heads__");
+        batch.put(b"__key_format__", b"");
+        db.write(batch)?;
+        Ok(())
+    }
+}
+
+/// Tell a damaged directory, which needs a repair, from one that couldn't
+/// be reached at all
+fn open_error(path: &Path, e: rocksdb::Error) -> anyhow::Error {
+    let path = path.display().to_string();
+    let corrupt = e.kind() == rocksdb::ErrorKind::Corruption;
+    let reason = e.into_string();
+    if corrupt {
+        StatehouseError::StorageCorrupt { path, reason }.into()
+    } else {
+        StatehouseError::StorageUnavailable { path, reason }.into()
+    }
+}
+
+/// blake3 hash, in hex, of a value's JSON encoding: equal values hash alike,
+/// so it identifies a value without comparing the whole of it
+pub fn value_hash(value: &serde_json::Value) -> String {
+    hash_json(&serde_json::to_vec(value).unwrap_or_default())
+}
+
+fn hash_json(json: &[u8]) -> String {
+    blake3::hash(json).to_hex().to_string()
+}
+
+/// Length of an encoded record header
+const HEAD_LEN: usize = 33;
+
+/// Format byte of a zstd-compressed stored record
+const COMPRESSED_ZSTD: u8 = 0x01;
+
+/// Format byte of a stored record whose value is kept as a blob
+const BLOB_REF: u8 = 0x02;
+
+/// zstd's default level: most of the gain on repetitive JSON, at write speed
+const ZSTD_LEVEL: i32 = 3;
+
+/// Value of `__heads__` once every header is in the `HEAD_LEN` layout
+const HEAD_FORMAT: &[u8] = b"3";
+
+/// Operations buffered by bulk rewrites (compaction, key migration) before a batch is written
+const WRITE_BATCH_SIZE: usize = 10_000;
+
+impl Storage for RocksStorage {
+    fn health_check(&self) -> Result<()> {
+        // Try a simple read
+        self.db.get(b"__health__")?;
+        Ok(())
+    }
+
+    fn write_state(&self, record: StateRecord) -> Result<()> {
+        let record_id = RecordId::new(
+            record.namespace.clone(),
+            record.agent_id.clone(),
+            record.key.clone(),
+        );
+
+        let mut batch = WriteBatch::default();
+        let head_key = key_codec::head_key(&record_id);
+        let previous = self.db.get(&head_key)?.and_then(|head| Self::decode_head(&head));
+
+        // Write latest state
+        let mut blobs = BlobChanges::default();
+        let state_key = key_codec::state_key(&record_id);
+        let state_value = self.encode_record(&record, &mut blobs)?;
+        batch.put(&state_key, &state_value);
+
+        // Write versioned state
+        let version_key = key_codec::version_key(&record_id, record.version);
+        batch.put(&version_key, &state_value);
+
+        // Both entries hold the blob, and release any the entries they replace held
+        blobs.count(Self::referenced_blob(&state_value)?, 2);
+        if self.stores_blobs {
+            for replaced in [self.db.get(&state_key)?, self.db.get(&version_key)?].into_iter().flatten() {
+                blobs.count(Self::referenced_blob(&replaced)?, -1);
+            }
+        }
+
+        // Write the fixed-layout header used by existence checks
+        batch.put(&head_key, Self::encode_head(&record));
+
+        // Advance the agent's watermark (restores may write older records)
+        let watermark_key = key_codec::watermark_key(&record.namespace, &record.agent_id);
+        let watermark = match self.db.get(&watermark_key)? {
+            Some(value) => u64::from_be_bytes(value.as_slice().try_into()?),
+            None => 0,
+        };
+        if record.commit_ts > watermark {
+            batch.put(&watermark_key, record.commit_ts.to_be_bytes());
+        }
+
+        // Move the record in its namespace's recency index
+        if let Some(previous) = previous.filter(|meta| !meta.deleted) {
+            batch.delete(key_codec::recent_key(&record_id, previous.commit_ts));
+        }
+        if !record.deleted {
+            batch.put(key_codec::recent_key(&record_id, record.commit_ts), []);
+        }
+
+        // And the namespace's usage, in the same batch so they can't drift
+        let mut usage_counters = self.namespace_usage.lock().unwrap();
+        let usage = self
+            .load_namespace_usage(&usage_counters, &record.namespace)?
+            .replace(previous.as_ref(), Some(&StateMeta::of(&record)));
+        batch.put(Self::namespace_usage_key(&record.namespace), Self::encode_usage(usage));
+        self.write_with_blobs(batch, blobs)?;
+        usage_counters.insert(record.namespace.clone(), usage);
+        drop(usage_counters);
+
+        // Only after RocksDB has the record, so a miss never re-reads the old one
+        if let Some(cache) = &self.read_cache {
+            let mut cache = cache.lock().unwrap();
+            cache.records.put(record_id, record);
+            cache.write_gen += 1;
+        }
+
+        Ok(())
+    }
+
+    fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> {
+        let Some(cache) = &self.read_cache else {
+            return self.read_state_uncached(record_id);
+        };
+
+        let write_gen = {
+            let mut cache = cache.lock().unwrap();
+            if let Some(record) = cache.records.get(record_id) {
+                return Ok(Some(record.clone()));
+            }
+            cache.write_gen
+        };
+
+        let record = self.read_state_uncached(record_id)?;
+        if let Some(record) = &record {
+            let mut cache = cache.lock().unwrap();
+            if cache.write_gen == write_gen {
+                cache.records.put(record_id.clone(), record.clone());
+            }
+        }
+        Ok(record)
+    }
+
+    fn exists(&self, record_id: &RecordId) -> Result<bool> {
+        match self.db.get(key_codec::head_key(record_id))? {
+            Some(head) => Ok(head.first() == Some(&0)),
+            None => Ok(false),
+        }
+    }
+
+    fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> {
+        let Some(head) = self.db.get(key_codec::head_key(record_id))? else {
+            return Ok(None);
+        };
+        match Self::decode_head(&head) {
+            Some(meta) => Ok(Some(meta)),
+            // Unreadable header; the record itself is authoritative
+            None => Ok(self.read_state_uncached(record_id)?.as_ref().map(StateMeta::of)),
+        }
+    }
+
+    fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> {
+        // One multi_get rather than a lookup per key; bypasses the read cache
+        let keys: Vec<Vec<u8>> = record_ids.iter().map(key_codec::state_key).collect();
+        self.db
+            .multi_get(&keys)
+            .into_iter()
+            .map(|value| value?.map(|value| Self::load_record(&*self.db, &value)).transpose())
+            .collect()
+    }
+
+    fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> {
+        let key = key_codec::version_key(record_id, version);
+        if let Some(value) = self.db.get(&key)? {
+            let record: StateRecord = Self::load_record(&*self.db, &value)?;
+            Ok(Some(record))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> {
+        let prefix = key_codec::version_prefix(record_id);
+        let seek_key = key_codec::version_key(record_id, Version::MAX);
+        let mut history = Vec::new();
+
+        for item in self.db.iterator(IteratorMode::From(&seek_key, Direction::Reverse)) {
+            if history.len() >= limit {
+                break;
+            }
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            history.push(Self::load_record(&*self.db, &value)?);
+        }
+
+        Ok(history)
+    }
+
+    fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> {
+        // Only the first and last keys of the record's range are read
+        let prefix = key_codec::version_prefix(record_id);
+        let edge = |mode: IteratorMode<'_>| -> Result<Option<Version>> {
+            match self.db.iterator(mode).next() {
+                Some(item) => {
+                    let (key, _) = item?;
+                    if !key.starts_with(&prefix) {
+                        return Ok(None);
+                    }
+                    Ok(Some(key_codec::decode_version_key(&key)?.1))
+                }
+                None => Ok(None),
+            }
+        };
+
+        let Some(min_version) = edge(IteratorMode::From(&prefix, Direction::Forward))? else {
+            return Ok(None);
+        };
+        let seek_key = key_codec::version_key(record_id, Version::MAX);
+        let max_version = edge(IteratorMode::From(&seek_key, Direction::Reverse))?.unwrap_or(min_version);
+        Ok(Some((min_version, max_version)))
+    }
+
+    fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> {
+        Self::version_as_of(&*self.db, |mode| self.db.iterator(mode), record_id, as_of)
+    }
+
+    fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> {
+        let prefix = key_codec::agent_state_prefix(namespace, agent_id);
+        let mut keys = Vec::new();
+
+        let iter = self.db.prefix_iterator(&prefix);
+        for (scanned, item) in iter.enumerate() {
+            deadline.check_every(scanned)?;
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let record: StateRecord = Self::decode_record(&value)?;
+            if include_deleted || !record.deleted {
+                keys.push(record.key);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> {
+        // Headers are a few bytes each and carry the deleted flag, so no
+        // value is read or parsed
+        let head_prefix = key_codec::key_prefix_head_prefix(namespace, agent_id, prefix);
+        let mut count = 0;
+        for item in self.db.prefix_iterator(&head_prefix) {
+            let (key, head) = item?;
+            if !key.starts_with(&head_prefix) {
+                break;
+            }
+            if head.first() == Some(&0) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        let state_prefix = key_codec::key_prefix_state_prefix(namespace, agent_id, prefix);
+        let mut records = Vec::new();
+
+        let iter = self.db.prefix_iterator(&state_prefix);
+        for (scanned, item) in iter.enumerate() {
+            deadline.check_every(scanned)?;
+            let (key, value) = item?;
+            if !key.starts_with(&state_prefix) {
+                break;
+            }
+
+            let record: StateRecord = Self::decode_record(&value)?;
+            if include_deleted || !record.deleted {
+                records.push(Self::resolve_blob(&*self.db, &value, record)?);
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        // A commit writes its records one at a time, so even a single RocksDB
+        // snapshot may hold part of one; records newer than the watermark are
+        // read as of it instead
+        let (db_snapshot, applied_ts) = self.pin_applied();
+        let state_prefix = key_codec::key_prefix_state_prefix(namespace, agent_id, prefix);
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_upper_bound(key_codec::prefix_end(&state_prefix));
+        let mut records = Vec::new();
+
+        let iter = db_snapshot.iterator_opt(IteratorMode::From(&state_prefix, Direction::Forward), readopts);
+        for (scanned, item) in iter.enumerate() {
+            deadline.check_every(scanned)?;
+            let (_, value) = item?;
+            let record: StateRecord = Self::decode_record(&value)?;
+            let record = if record.commit_ts <= applied_ts {
+                Some(Self::resolve_blob(&db_snapshot, &value, record)?)
+            } else {
+                let record_id = RecordId::new(record.namespace, record.agent_id, record.key);
+                Self::version_as_of(&db_snapshot, |mode| db_snapshot.iterator(mode), &record_id, applied_ts)?
+            };
+            if let Some(record) = record.filter(|r| include_deleted || !r.deleted) {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
+        let state_prefix = key_codec::key_prefix_state_prefix(namespace, agent_id, prefix);
+        // Keys sort like their strings, so the cursor's own state key is
+        // where the page starts; skip it if the key is still there
+        let (seek_key, skip_key) = match start_after {
+            Some(after) if after >= prefix => {
+                let key = key_codec::state_key(&RecordId::new(namespace.to_string(), agent_id.to_string(), after.to_string()));
+                (key.clone(), Some(key))
+            }
+            _ => (state_prefix.clone(), None),
+        };
+        let mut records = Vec::new();
+
+        for item in self.db.iterator(IteratorMode::From(&seek_key, Direction::Forward)) {
+            if records.len() >= limit {
+                break;
+            }
+            let (key, value) = item?;
+            if !key.starts_with(&state_prefix) {
+                break;
+            }
+            if skip_key.as_deref() == Some(&*key) {
+                continue;
+            }
+
+            let record: StateRecord = Self::decode_record(&value)?;
+            if include_deleted || !record.deleted {
+                records.push(Self::resolve_blob(&*self.db, &value, record)?);
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> {
+        // The key prefix can't be pushed into the iterator bound because the
+        // agent id sits between the namespace and the key, so match it per record
+        let state_prefix = key_codec::namespace_state_prefix(namespace);
+        let mut records = Vec::new();
+
+        let iter = self.db.prefix_iterator(&state_prefix);
+        for item in iter {
+            if records.len() >= limit {
+                break;
+            }
+            let (key, value) = item?;
+            if !key.starts_with(&state_prefix) {
+                break;
+            }
+
+            let record: StateRecord = Self::decode_record(&value)?;
+            if record.key.starts_with(prefix) && !record.deleted {
+                records.push(Self::resolve_blob(&*self.db, &value, record)?);
+            }
+        }
+
+        // Keys sort by (agent, key) within the namespace, so this is already in order
+        Ok(records)
+    }
+
+    fn append_event(&self, event: EventLogEntry) -> Result<()> {
+        let key = key_codec::event_key(event.commit_ts);
+        let value = serde_json::to_vec(&event)?;
+        let mut batch = WriteBatch::default();
+        for index_key in Self::agent_event_keys(&event) {
+            batch.put(index_key, event.commit_ts.to_be_bytes());
+        }
+        batch.put(&key, &value);
+        self.db.write(batch)?;
+        // The event is a commit's last write
+        self.applied_commit_ts.fetch_max(event.commit_ts, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn last_event_ts(&self) -> Result<Option<CommitTs>> {
+        Ok(Self::last_event(&self.db)?.map(|event| event.commit_ts))
+    }
+
+    fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> {
+        match self.db.get(key_codec::event_key(commit_ts))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> {
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_upper_bound(key_codec::prefix_end(key_codec::EVENT_TAG));
+        let seek_key = key_codec::event_key(from_ts);
+
+        let mut events = Vec::new();
+        for item in self.db.iterator_opt(IteratorMode::From(&seek_key, Direction::Forward), readopts).take(limit) {
+            let (_, value) = item?;
+            events.push(serde_json::from_slice(&value)?);
+        }
+        Ok(events)
+    }
+
+    fn count_events(&self, from_ts: CommitTs) -> Result<u64> {
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_upper_bound(key_codec::prefix_end(key_codec::EVENT_TAG));
+        let seek_key = key_codec::event_key(from_ts);
+
+        let mut count = 0;
+        for item in self.db.iterator_opt(IteratorMode::From(&seek_key, Direction::Forward), readopts) {
+            item?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> {
+        // The agent's index lists just its events, so other agents' commits
+        // are never read
+        let seek_key = key_codec::agent_event_key(namespace, agent_id, start_ts.unwrap_or(0));
+        let upper_bound = match end_ts.and_then(|end| end.checked_add(1)) {
+            Some(after_end) => key_codec::agent_event_key(namespace, agent_id, after_end),
+            None => key_codec::prefix_end(&key_codec::agent_event_prefix(namespace, agent_id)),
+        };
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_upper_bound(upper_bound);
+        let mut events = Vec::new();
+
+        for (scanned, item) in self.db.iterator_opt(IteratorMode::From(&seek_key, Direction::Forward), readopts).enumerate() {
+            deadline.check_every(scanned)?;
+            let (key, _) = item?;
+            let (_, _, commit_ts) = key_codec::decode_agent_event_key(&key)?;
+            if let Some(value) = self.db.get(key_codec::event_key(commit_ts))? {
+                events.push(serde_json::from_slice(&value)?);
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn next_commit_ts(&self) -> Result<CommitTs> {
+        let mut counter = self.commit_ts_counter.write().unwrap();
+        *counter += 1;
+        let ts = *counter;
+
+        // Persist commit timestamp
+        self.db.put(b"__commit_ts__", ts.to_be_bytes())?;
+
+        Ok(ts)
+    }
+
+    fn current_commit_ts(&self) -> Result<CommitTs> {
+        Ok(*self.commit_ts_counter.read().unwrap())
+    }
+
+    fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> {
+        let mut counter = self.commit_ts_counter.write().unwrap();
+        if commit_ts > *counter {
+            *counter = commit_ts;
+            self.db.put(b"__commit_ts__", counter.to_be_bytes())?;
+        }
+        // Called once the records up to `commit_ts` are written
+        self.applied_commit_ts.fetch_max(commit_ts, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> {
+        let mut counter = self.commit_ts_counter.write().unwrap();
+        let start = *counter + 1;
+        let end = *counter + n.max(1);
+        self.db.put(b"__commit_ts__", end.to_be_bytes())?;
+        // Another writer may hand out the block's timestamps once this
+        // returns; a crash must not give them out again
+        self.db.flush()?;
+        *counter = end;
+        Ok((start, end))
+    }
+
+    fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> {
+        self.update_namespace_ts(namespace, |current| current + 1)
+    }
+
+    fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> {
+        self.update_namespace_ts(namespace, |current| current.max(namespace_ts))?;
+        Ok(())
+    }
+
+    fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> {
+        self.update_namespace_ts(namespace, |current| current)
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Cleared first, so an Async commit landing during the flush is
+        // flushed again by the next batch
+        self.flush_pending.store(false, Ordering::SeqCst);
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn supports_durability(&self, durability: Durability) -> bool {
+        durability != Durability::Memory
+    }
+
+    fn sync_commit(&self, durability: Option<Durability>) -> Result<()> {
+        let durability = durability.unwrap_or(if self.config.fsync_on_commit { Durability::Fsync } else { Durability::Async });
+        match durability {
+            Durability::Fsync => self.flush(),
+            Durability::Async => {
+                self.flush_pending.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            Durability::Memory => Err(StatehouseError::UnsupportedDurability { durability }.into()),
+        }
+    }
+
+    fn flush_deferred(&self) -> Result<()> {
+        if self.flush_pending.swap(false, Ordering::SeqCst) {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    fn create_snapshot(&self) -> Result<Snapshot> {
+        // The counter may already name a commit that is still writing, so
+        // snapshot as of the newest fully applied one instead
+        let (db_snapshot, snapshot_ts) = self.pin_applied();
+        let records = Self::scan_state(&db_snapshot, snapshot_ts, Self::scan_workers())?;
+
+        let metadata = SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            snapshot_ts,
+            record_count: records.len(),
+            created_at: unix_now(),
+            namespace: None,
+        };
+
+        Ok(Snapshot { metadata, records })
+    }
+
+    fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> {
+        let records = Self::scan_state(&self.db.snapshot(), snapshot_ts, Self::scan_workers())?;
+
+        let metadata = SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            snapshot_ts,
+            record_count: records.len(),
+            created_at: unix_now(),
+            namespace: None,
+        };
+
+        Ok(Snapshot { metadata, records })
+    }
+
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let dir = &self.config.data_dir;
+        let path = self.snapshot_path();
+        if self.config.max_snapshot_shard_bytes > 0 {
+            write_sharded_snapshot(dir, snapshot, self.config.max_snapshot_shard_bytes)?;
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+
+        let json = serde_json::to_string_pretty(snapshot)?;
+        std::fs::write(path, json)?;
+        // A manifest left by an earlier sharded save would otherwise win on load
+        if dir.join(SNAPSHOT_MANIFEST).exists() {
+            std::fs::remove_file(dir.join(SNAPSHOT_MANIFEST))?;
+        }
+        Ok(())
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Snapshot>> {
+        if self.config.data_dir.join(SNAPSHOT_MANIFEST).exists() {
+            return read_sharded_snapshot(&self.config.data_dir).map(Some);
+        }
+
+        let path = self.snapshot_path();
+        
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json = std::fs::read_to_string(path)?;
+        let snapshot: Snapshot = serde_json::from_str(&json)?;
+
+        // Verify snapshot version
+        if snapshot.metadata.version != SNAPSHOT_VERSION {
+            return Err(anyhow::anyhow!(
+                "Snapshot version mismatch: expected {}, got {}",
+                SNAPSHOT_VERSION,
+                snapshot.metadata.version
+            ));
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> {
+        let (db_snapshot, snapshot_ts) = self.pin_applied();
+        let start = key_codec::namespace_state_prefix(namespace);
+        let records = Self::scan_state_range(&db_snapshot, &start, &key_codec::prefix_end(&start), snapshot_ts)?;
+
+        let metadata = SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            snapshot_ts,
+            record_count: records.len(),
+            created_at: unix_now(),
+            namespace: Some(namespace.to_string()),
+        };
+
+        Ok(Snapshot { metadata, records })
+    }
+
+    fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()> {
+        let namespace = snapshot_namespace(snapshot)?;
+        let compressed = self.config.max_snapshot_shard_bytes > 0;
+        let path = self.namespace_snapshot_path(namespace, compressed);
+        if compressed {
+            let file = std::fs::File::create(path)?;
+            let mut encoder = zstd::Encoder::new(std::io::BufWriter::new(file), ZSTD_LEVEL)?;
+            serde_json::to_writer(&mut encoder, snapshot)?;
+            encoder.finish()?.flush()?;
+        } else {
+            std::fs::write(path, serde_json::to_string_pretty(snapshot)?)?;
+        }
+        // One saved in the other format would otherwise win on load
+        let other = self.namespace_snapshot_path(namespace, !compressed);
+        if other.exists() {
+            std::fs::remove_file(other)?;
+        }
+        Ok(())
+    }
+
+    fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>> {
+        let compressed = self.namespace_snapshot_path(namespace, true);
+        let snapshot: Snapshot = if compressed.exists() {
+            serde_json::from_reader(std::io::BufReader::new(zstd::Decoder::new(std::fs::File::open(compressed)?)?))?
+        } else {
+            let path = self.namespace_snapshot_path(namespace, false);
+            if !path.exists() {
+                return Ok(None);
+            }
+            serde_json::from_str(&std::fs::read_to_string(path)?)?
+        };
+
+        // Verify snapshot version
+        if snapshot.metadata.version != SNAPSHOT_VERSION {
+            return Err(anyhow::anyhow!(
+                "Snapshot version mismatch: expected {}, got {}",
+                SNAPSHOT_VERSION,
+                snapshot.metadata.version
+            ));
+        }
+
+        if snapshot.metadata.namespace.as_deref() != Some(namespace) {
+            return Err(anyhow::anyhow!(
+                "Snapshot namespace mismatch: expected {}, got {:?}",
+                namespace,
+                snapshot.metadata.namespace
+            ));
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let namespace = snapshot_namespace(snapshot)?;
+        let mut batch = WriteBatch::default();
+        let mut usage_counters = self.namespace_usage.lock().unwrap();
+
+        // Drop the namespace's current state, and the history written after
+        // the snapshot along with it: versions then resume from the restored
+        // ones rather than reusing numbers still stored. Older history stays.
+        let snapshot_ts = snapshot.metadata.snapshot_ts;
+        let mut blobs = BlobChanges::default();
+        for (key, value) in self.entries_under(&key_codec::namespace_version_prefix(namespace))? {
+            if Self::decode_record(&value)?.commit_ts > snapshot_ts {
+                blobs.count(Self::referenced_blob(&value)?, -1);
+                batch.delete(key);
+            }
+        }
+        for (key, _) in self.entries_under(&key_codec::namespace_agent_event_prefix(namespace))? {
+            if key_codec::decode_agent_event_key(&key)?.2 > snapshot_ts {
+                batch.delete(key);
+            }
+        }
+        for (key, _) in self.entries_under(&key_codec::namespace_watermark_prefix(namespace))? {
+            batch.delete(key);
+        }
+        for (record, blob) in self.namespace_state(namespace)? {
+            blobs.count(blob, -1);
+            let record_id = RecordId::new(record.namespace, record.agent_id, record.key);
+            batch.delete(key_codec::state_key(&record_id));
+            batch.delete(key_codec::head_key(&record_id));
+            if !record.deleted {
+                batch.delete(key_codec::recent_key(&record_id, record.commit_ts));
+            }
+        }
+
+        let mut watermarks: BTreeMap<&str, CommitTs> = BTreeMap::new();
+        for record in &snapshot.records {
+            let record_id = RecordId::new(
+                record.namespace.clone(),
+                record.agent_id.clone(),
+                record.key.clone(),
+            );
+            let state_value = self.encode_record(record, &mut blobs)?;
+            blobs.count(Self::referenced_blob(&state_value)?, 1);
+            batch.put(key_codec::state_key(&record_id), state_value);
+            batch.put(key_codec::head_key(&record_id), Self::encode_head(record));
+            if !record.deleted {
+                batch.put(key_codec::recent_key(&record_id, record.commit_ts), []);
+            }
+            let watermark = watermarks.entry(record.agent_id.as_str()).or_default();
+            *watermark = (*watermark).max(record.commit_ts);
+        }
+        for (agent_id, commit_ts) in watermarks {
+            batch.put(key_codec::watermark_key(namespace, agent_id), commit_ts.to_be_bytes());
+        }
+        let usage = NamespaceUsage::of_records(&snapshot.records);
+        batch.put(Self::namespace_usage_key(namespace), Self::encode_usage(usage));
+
+        self.write_with_blobs(batch, blobs)?;
+        usage_counters.insert(namespace.to_string(), usage);
+        drop(usage_counters);
+        self.invalidate_read_cache();
+        self.flush()?;
+        Ok(())
+    }
+
+    fn get_all_state(&self) -> Result<Vec<StateRecord>> {
+        Self::scan_state(&self.db.snapshot(), CommitTs::MAX, Self::scan_workers())
+    }
+
+    fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> {
+        let keep_versions = keep_versions.max(1);
+        let mut stats = CompactionStats { snapshot_ts: up_to_ts, ..Default::default() };
+        let mut batch = WriteBatch::default();
+        let mut blobs = BlobChanges::default();
+
+        for item in self.db.prefix_iterator(key_codec::STATE_TAG) {
+            let (key, value) = item?;
+            if !key.starts_with(key_codec::STATE_TAG) {
+                break;
+            }
+            let record: StateRecord = Self::decode_record(&value)?;
+            let record_id = RecordId::new(record.namespace, record.agent_id, record.key);
+
+            let settled = self
+                .version_entries(&record_id)?
+                .into_iter()
+                .filter(|(_, _, version, _)| version.commit_ts <= up_to_ts)
+                .skip(keep_versions);
+            for (key, size, _, blob) in settled {
+                batch.delete(key);
+                blobs.count(blob, -1);
+                stats.versions_removed += 1;
+                stats.bytes_reclaimed += size as u64;
+            }
+
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.write_with_blobs(std::mem::take(&mut batch), std::mem::take(&mut blobs))?;
+            }
+        }
+
+        self.write_with_blobs(batch, blobs)?;
+        self.flush()?;
+        Ok(stats)
+    }
+
+    fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> {
+        let mut stats = CompactionStats { snapshot_ts: up_to_ts, ..Default::default() };
+        let mut batch = WriteBatch::default();
+
+        let last_event_key = key_codec::event_key(up_to_ts);
+        for item in self.db.prefix_iterator(key_codec::EVENT_TAG) {
+            let (key, value) = item?;
+            if !key.starts_with(key_codec::EVENT_TAG) || key.as_ref() > last_event_key.as_slice() {
+                break;
+            }
+            stats.events_removed += 1;
+            stats.bytes_reclaimed += (key.len() + value.len()) as u64;
+            let event: EventLogEntry = serde_json::from_slice(&value)?;
+            for index_key in Self::agent_event_keys(&event) {
+                batch.delete(index_key);
+            }
+            batch.delete(key);
+
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        self.db.write(batch)?;
+        self.flush()?;
+        Ok(stats)
+    }
+
+    fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> {
+        let mut tombstones = Vec::new();
+
+        // Headers carry the deleted flag, so live values are never decoded
+        for item in self.db.prefix_iterator(key_codec::HEAD_TAG) {
+            let (key, head) = item?;
+            if !key.starts_with(key_codec::HEAD_TAG) {
+                break;
+            }
+            match Self::decode_head(&head) {
+                Some(meta) if meta.deleted && meta.commit_ts <= up_to_ts => {}
+                _ => continue,
+            }
+
+            let state_key = key_codec::state_key(&key_codec::decode_head_key(&key)?);
+            if let Some(value) = self.db.get(&state_key)? {
+                tombstones.push(Self::decode_record(&value)?);
+            }
+        }
+
+        Ok(tombstones)
+    }
+
+    fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> {
+        let mut expired = Vec::new();
+        for item in self.db.prefix_iterator(key_codec::HEAD_TAG) {
+            let (key, head) = item?;
+            if !key.starts_with(key_codec::HEAD_TAG) {
+                break;
+            }
+            if Self::decode_head(&head).is_some_and(|meta| meta.expired(now_ms)) {
+                expired.push(key_codec::decode_head_key(&key)?);
+            }
+        }
+        Ok(expired)
+    }
+
+    fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> {
+        let mut stats = CompactionStats::default();
+        let mut batch = WriteBatch::default();
+        let mut blobs = BlobChanges::default();
+
+        for tombstone in tombstones {
+            let record_id = RecordId::new(
+                tombstone.namespace.clone(),
+                tombstone.agent_id.clone(),
+                tombstone.key.clone(),
+            );
+            // Leave keys that were written again since they were listed
+            match self.read_state_uncached(&record_id)? {
+                Some(latest) if latest.deleted && latest.version == tombstone.version => {}
+                _ => continue,
+            }
+
+            let state_key = key_codec::state_key(&record_id);
+            let head_key = key_codec::head_key(&record_id);
+            stats.bytes_reclaimed += (state_key.len() + head_key.len() + HEAD_LEN) as u64;
+            batch.delete(state_key);
+            batch.delete(head_key);
+
+            for (key, size, _, blob) in self.version_entries(&record_id)? {
+                batch.delete(key);
+                blobs.count(blob, -1);
+                stats.versions_removed += 1;
+                stats.bytes_reclaimed += size as u64;
+            }
+            stats.tombstones_removed += 1;
+        }
+
+        self.write_with_blobs(batch, blobs)?;
+        self.invalidate_read_cache();
+        self.flush()?;
+        Ok(stats)
+    }
+
+    fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> {
+        let mut stats = PurgeStats::default();
+        let mut batch = WriteBatch::default();
+        let mut blobs = BlobChanges::default();
+
+        // The usage update goes in the first batch, with the record's removal
+        let mut usage_counters = self.namespace_usage.lock().unwrap();
+        let head_key = key_codec::head_key(record_id);
+        let previous = self.db.get(&head_key)?.and_then(|head| Self::decode_head(&head));
+        let usage = self
+            .load_namespace_usage(&usage_counters, &record_id.namespace)?
+            .replace(previous.as_ref(), None);
+        batch.put(Self::namespace_usage_key(&record_id.namespace), Self::encode_usage(usage));
+        let state_key = key_codec::state_key(record_id);
+        if let Some(state) = self.db.get(&state_key)? {
+            blobs.count(Self::referenced_blob(&state)?, -1);
+        }
+        batch.delete(state_key);
+        batch.delete(head_key);
+        if let Some(previous) = previous.filter(|meta| !meta.deleted) {
+            batch.delete(key_codec::recent_key(record_id, previous.commit_ts));
+        }
+        for (key, _, _, blob) in self.version_entries(record_id)? {
+            batch.delete(key);
+            blobs.count(blob, -1);
+            stats.versions_removed += 1;
+        }
+
+        // Events aren't indexed by key, so every one is checked
+        for item in self.db.prefix_iterator(key_codec::EVENT_TAG) {
+            let (key, value) = item?;
+            if !key.starts_with(key_codec::EVENT_TAG) {
+                break;
+            }
+            let mut event: EventLogEntry = serde_json::from_slice(&value)?;
+            let indexed = Self::agent_event_keys(&event);
+            if scrub_event(&mut event, record_id) {
+                // Unindex the event for the agent if that was its last operation there
+                for index_key in indexed.difference(&Self::agent_event_keys(&event)) {
+                    batch.delete(index_key);
+                }
+                batch.put(key, serde_json::to_vec(&event)?);
+                stats.events_scrubbed += 1;
+            }
+
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.write_with_blobs(std::mem::take(&mut batch), std::mem::take(&mut blobs))?;
+            }
+        }
+
+        self.write_with_blobs(batch, blobs)?;
+        usage_counters.insert(record_id.namespace.clone(), usage);
+        drop(usage_counters);
+        self.invalidate_read_cache();
+        self.flush()?;
+        Ok(stats)
+    }
+
+    fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> {
+        let mut stats = RenameStats::default();
+        let mut usage_counters = self.namespace_usage.lock().unwrap();
+        let mut ts_counters = self.namespace_ts_counters.lock().unwrap();
+
+        let target = key_codec::namespace_state_prefix(to);
+        let target_has_records = match self.db.prefix_iterator(&target).next() {
+            Some(item) => item?.0.starts_with(&target),
+            None => false,
+        };
+        if target_has_records && !merge {
+            return Err(StatehouseError::NamespaceNotEmpty { namespace: to.to_string() }.into());
+        }
+
+        // Nothing is written until every entry is queued, so a collision
+        // found partway leaves both namespaces as they were. Entries keep
+        // their blob references, so reference counts don't change.
+        let mut batch = WriteBatch::default();
+        let renamed = |record_id: &RecordId| RecordId::new(to.to_string(), record_id.agent_id.clone(), record_id.key.clone());
+        for (key, value) in self.entries_under(&key_codec::namespace_state_prefix(from))? {
+            let record_id = key_codec::decode_state_key(&key)?;
+            let state_key = key_codec::state_key(&renamed(&record_id));
+            if target_has_records && self.db.get(&state_key)?.is_some() {
+                return Err(rename_collision(to, &record_id));
+            }
+            batch.delete(key);
+            batch.put(state_key, self.renamed_entry(&value, to)?);
+            stats.records_moved += 1;
+        }
+        for (key, value) in self.entries_under(&key_codec::namespace_version_prefix(from))? {
+            let (record_id, version) = key_codec::decode_version_key(&key)?;
+            batch.delete(key);
+            batch.put(key_codec::version_key(&renamed(&record_id), version), self.renamed_entry(&value, to)?);
+            stats.versions_moved += 1;
+        }
+        for (key, head) in self.entries_under(&key_codec::namespace_head_prefix(from))? {
+            let record_id = key_codec::decode_head_key(&key)?;
+            batch.delete(key);
+            batch.put(key_codec::head_key(&renamed(&record_id)), head);
+        }
+        for (key, _) in self.entries_under(&key_codec::namespace_recent_prefix(from))? {
+            let (commit_ts, record_id) = key_codec::decode_recent_key(&key)?;
+            batch.delete(key);
+            batch.put(key_codec::recent_key(&renamed(&record_id), commit_ts), []);
+        }
+        for (key, watermark) in self.entries_under(&key_codec::namespace_watermark_prefix(from))? {
+            let (_, agent_id) = key_codec::decode_watermark_key(&key)?;
+            let watermark_key = key_codec::watermark_key(to, &agent_id);
+            // Big-endian, so the bytes compare like the timestamps
+            let merged = match self.db.get(&watermark_key)? {
+                Some(existing) => existing.max(watermark.to_vec()),
+                None => watermark.to_vec(),
+            };
+            batch.delete(key);
+            batch.put(watermark_key, merged);
+        }
+
+        // The agent event index names every event touching the namespace
+        let mut events = BTreeSet::new();
+        for (key, _) in self.entries_under(&key_codec::namespace_agent_event_prefix(from))? {
+            let (_, agent_id, commit_ts) = key_codec::decode_agent_event_key(&key)?;
+            batch.delete(key);
+            batch.put(key_codec::agent_event_key(to, &agent_id, commit_ts), []);
+            events.insert(commit_ts);
+        }
+        for commit_ts in events {
+            let event_key = key_codec::event_key(commit_ts);
+            let Some(value) = self.db.get(&event_key)? else { continue };
+            let mut event: EventLogEntry = serde_json::from_slice(&value)?;
+            if rename_event(&mut event, from, to) {
+                batch.put(event_key, serde_json::to_vec(&event)?);
+                stats.events_rewritten += 1;
+            }
+        }
+
+        let moved = self.load_namespace_usage(&usage_counters, from)?;
+        let existing = self.load_namespace_usage(&usage_counters, to)?;
+        let usage = NamespaceUsage { records: existing.records + moved.records, bytes: existing.bytes + moved.bytes };
+        batch.delete(Self::namespace_usage_key(from));
+        batch.put(Self::namespace_usage_key(to), Self::encode_usage(usage));
+        let namespace_ts = |namespace: &str| -> Result<CommitTs> {
+            match ts_counters.get(namespace) {
+                Some(current) => Ok(*current),
+                None => Self::load_namespace_ts(&self.db, &Self::namespace_ts_key(namespace)),
+            }
+        };
+        let merged_ts = namespace_ts(from)?.max(namespace_ts(to)?);
+        batch.delete(Self::namespace_ts_key(from));
+        if merged_ts > 0 {
+            batch.put(Self::namespace_ts_key(to), merged_ts.to_be_bytes());
+        }
+
+        self.db.write(batch)?;
+        usage_counters.remove(from);
+        usage_counters.insert(to.to_string(), usage);
+        ts_counters.remove(from);
+        ts_counters.insert(to.to_string(), merged_ts);
+        drop(ts_counters);
+        drop(usage_counters);
+        self.invalidate_read_cache();
+        self.flush()?;
+        Ok(stats)
+    }
+
+    fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> {
+        let usage_counters = self.namespace_usage.lock().unwrap();
+        self.load_namespace_usage(&usage_counters, namespace)
+    }
+
+    fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> {
+        let prefix = key_codec::namespace_recent_prefix(namespace);
+        let mut keys = Vec::new();
+        for item in self.db.prefix_iterator(&prefix).take(limit) {
+            let (key, _) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            keys.push(key_codec::decode_recent_key(&key)?.1);
+        }
+        Ok(keys)
+    }
+
+    fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>> {
+        // A key's versions are adjacent, so each run of one record is a count
+        let prefix = key_codec::namespace_version_prefix(namespace);
+        let mut counts: Vec<(RecordId, u64)> = Vec::new();
+        for (scanned, item) in self.db.prefix_iterator(&prefix).enumerate() {
+            deadline.check_every(scanned)?;
+            let (key, _) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (record_id, _) = key_codec::decode_version_key(&key)?;
+            match counts.last_mut() {
+                Some((last, count)) if *last == record_id => *count += 1,
+                _ => counts.push((record_id, 1)),
+            }
+        }
+        Ok(counts)
+    }
+
+    fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> {
+        if let Some(value) = self.db.get(key_codec::watermark_key(namespace, agent_id))? {
+            return Ok(Some(u64::from_be_bytes(value.as_slice().try_into()?)));
+        }
+
+        // Data written before watermarks were kept: the newest header
+        let prefix = key_codec::key_prefix_head_prefix(namespace, agent_id, "");
+        let mut last = None;
+        for item in self.db.prefix_iterator(&prefix) {
+            let (key, head) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if let Some(meta) = Self::decode_head(&head) {
+                last = last.max(Some(meta.commit_ts));
+            }
+        }
+        Ok(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn test_config(dir: &TempDir) -> StorageConfig {
+        StorageConfig {
+            data_dir: dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            snapshot_interval: 10,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        }
+    }
+
+    #[test]
+    fn test_commit_ts_repaired_from_event_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+
+        // Events land on disk but the persisted counter stays stale
+        {
+            let storage = RocksStorage::new(config.clone()).unwrap();
+            assert_eq!(storage.next_commit_ts().unwrap(), 1);
+            for commit_ts in 5..=7 {
+                storage.append_event(EventLogEntry {
+                    txn_id: format!("txn-{}", commit_ts),
+                    commit_ts,
+                    operations: Vec::new(),
+                    namespace_ts: BTreeMap::new(),
+                }).unwrap();
+            }
+        }
+
+        let storage = RocksStorage::new(config).unwrap();
+        assert_eq!(storage.current_commit_ts().unwrap(), 7);
+        assert_eq!(storage.next_commit_ts().unwrap(), 8);
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let invalid_field = |config: StorageConfig| match config.validate().unwrap_err().downcast_ref() {
+            Some(StatehouseError::InvalidConfig { field, .. }) => *field,
+            other => panic!("unexpected error {:?}", other),
+        };
+
+        // Creates a missing data directory and leaves no probe behind
+        let data_dir = temp_dir.path().join("nested").join("data");
+        let config = StorageConfig { data_dir: data_dir.clone(), ..test_config(&temp_dir) };
+        config.validate().unwrap();
+        assert_eq!(std::fs::read_dir(&data_dir).unwrap().count(), 0);
+
+        assert_eq!(invalid_field(StorageConfig { snapshot_interval: 0, ..config.clone() }), "snapshot_interval");
+        assert_eq!(invalid_field(StorageConfig { max_log_size: MIN_LOG_SIZE - 1, ..config.clone() }), "max_log_size");
+        assert_eq!(invalid_field(StorageConfig { max_snapshot_shard_bytes: config.max_log_size + 1, ..config.clone() }), "max_snapshot_shard_bytes");
+        assert_eq!(invalid_field(StorageConfig { value_dedup_threshold: 1024, compaction_expire_tombstones: true, ..config.clone() }), "value_dedup_threshold");
+
+        // A regular file where the directory should be
+        let file = temp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(invalid_field(StorageConfig { data_dir: file.clone(), ..config.clone() }), "data_dir");
+        let err = StorageConfig { data_dir: file.join("data"), ..config }.validate().unwrap_err();
+        assert!(err.to_string().starts_with("Invalid storage config: data_dir "), "{}", err);
+    }
+
+    #[test]
+    fn test_reserve_commit_ts_block() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+
+        {
+            let storage = RocksStorage::new(config.clone()).unwrap();
+            assert_eq!(storage.next_commit_ts().unwrap(), 1);
+            assert_eq!(storage.reserve_commit_ts_block(10).unwrap(), (2, 11));
+            assert_eq!(storage.reserve_commit_ts_block(5).unwrap(), (12, 16));
+            assert_eq!(storage.current_commit_ts().unwrap(), 16);
+            // Never moves the counter back
+            storage.advance_commit_ts(3).unwrap();
+            assert_eq!(storage.current_commit_ts().unwrap(), 16);
+        }
+
+        // Nothing from either block is handed out again after a restart
+        let storage = RocksStorage::new(config).unwrap();
+        assert_eq!(storage.current_commit_ts().unwrap(), 16);
+        assert_eq!(storage.reserve_commit_ts_block(0).unwrap(), (17, 17));
+        assert_eq!(storage.next_commit_ts().unwrap(), 18);
+
+        let in_memory = InMemoryStorage::new();
+        assert_eq!(in_memory.reserve_commit_ts_block(3).unwrap(), (1, 3));
+        assert_eq!(in_memory.next_commit_ts().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let source_dir = TempDir::new().unwrap();
+        let source = RocksStorage::new(test_config(&source_dir)).unwrap();
+        for i in 0..1000 {
+            source.write_state(StateRecord {
+                namespace: "default".to_string(),
+                agent_id: format!("agent-{}", i % 7),
+                key: format!("key-{:04}", i),
+                value: Some(serde_json::json!({"n": i, "text": "line\nbreak"})),
+                version: 1,
+                commit_ts: i + 1,
+                deleted: i % 10 == 0,
+                namespace_ts: None,
+                purge_after: None,
+                labels: Labels::new(),
+                value_hash: None,
+                expires_at: None,
+            }).unwrap();
+        }
+        source.set_commit_ts(1000).unwrap();
+
+        let snapshot = source.create_snapshot().unwrap();
+        let mut buf = Vec::new();
+        snapshot.export_jsonl(&mut buf).unwrap();
+        assert_eq!(buf.iter().filter(|b| **b == b'\n').count(), 1001);
+
+        let target_dir = TempDir::new().unwrap();
+        let target = RocksStorage::new(test_config(&target_dir)).unwrap();
+        let metadata = target.import_jsonl(buf.as_slice(), false).unwrap();
+        assert_eq!(metadata.record_count, 1000);
+        assert_eq!(target.current_commit_ts().unwrap(), 1000);
+
+        // Same records, same order
+        let original: Vec<serde_json::Value> = snapshot.records.iter().map(|r| serde_json::to_value(r).unwrap()).collect();
+        let restored: Vec<serde_json::Value> = target.create_snapshot().unwrap().records.iter().map(|r| serde_json::to_value(r).unwrap()).collect();
+        assert_eq!(original, restored);
+
+        // A truncated file is rejected
+        let truncated = &buf[..buf.len() / 2];
+        let cut = truncated.iter().rposition(|b| *b == b'\n').unwrap() + 1;
+        let reader = JsonlSnapshotReader::new(&truncated[..cut]).unwrap();
+        assert!(reader.collect::<Result<Vec<_>>>().is_err());
+    }
+
+    #[test]
+    fn test_stale_snapshot_restore_is_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::new(test_config(&temp_dir)).unwrap();
+        let record = |value: i32, version, commit_ts| StateRecord {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "key1".to_string(),
+            value: Some(serde_json::json!(value)),
+            version,
+            commit_ts,
+            deleted: false,
+            namespace_ts: None,
+            purge_after: None,
+            labels: Labels::new(),
+            value_hash: None,
+            expires_at: None,
+        };
+
+        // Snapshots only include commits whose event has been appended
+        let commit = |record: StateRecord| {
+            let commit_ts = storage.next_commit_ts().unwrap();
+            storage.write_state(record).unwrap();
+            storage.append_event(EventLogEntry {
+                txn_id: format!("txn-{}", commit_ts),
+                commit_ts,
+                operations: Vec::new(),
+                namespace_ts: BTreeMap::new(),
+            }).unwrap();
+        };
+
+        commit(record(1, 1, 1));
+        let stale = storage.create_snapshot().unwrap();
+        let mut stale_jsonl = Vec::new();
+        stale.export_jsonl(&mut stale_jsonl).unwrap();
+
+        commit(record(2, 2, 2));
+
+        // Refused by default, leaving newer data and the counter alone
+        let err = storage.restore_from_snapshot(&stale, false).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StatehouseError>(),
+            Some(StatehouseError::SnapshotRegression { snapshot_ts: 1, current_ts: 2 })
+        ));
+        assert!(storage.import_jsonl(stale_jsonl.as_slice(), false).is_err());
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "key1".to_string());
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 2);
+        assert_eq!(storage.current_commit_ts().unwrap(), 2);
+
+        // Explicit override goes through
+        storage.restore_from_snapshot(&stale, true).unwrap();
+        assert_eq!(storage.current_commit_ts().unwrap(), 1);
+
+        // A snapshot at the current watermark is not a regression
+        let current = storage.create_snapshot().unwrap();
+        storage.restore_from_snapshot(&current, false).unwrap();
+    }
+
+    fn record(namespace: &str, key: &str, version: Version, commit_ts: CommitTs) -> StateRecord {
+        StateRecord {
+            namespace: namespace.to_string(),
+            agent_id: "agent-1".to_string(),
+            key: key.to_string(),
+            value: Some(serde_json::json!({"version": version})),
+            version,
+            commit_ts,
+            deleted: false,
+            namespace_ts: None,
+            purge_after: None,
+            labels: Labels::new(),
+            value_hash: None,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_snapshots_serialize_identically() {
+        let mut records = Vec::new();
+        for namespace in ["tenant-b", "tenant-a"] {
+            for agent_id in ["agent-2", "agent-10", "agent-1"] {
+                for key in ["b", "a"] {
+                    let mut record = record(namespace, key, 1, 1);
+                    record.agent_id = agent_id.to_string();
+                    records.push(record);
+                }
+            }
+        }
+
+        // Same state written in opposite orders, and into RocksDB
+        let forward = InMemoryStorage::new();
+        let backward = InMemoryStorage::new();
+        let dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(test_config(&dir)).unwrap();
+        for record in &records {
+            forward.write_state(record.clone()).unwrap();
+            rocks.write_state(record.clone()).unwrap();
+        }
+        for record in records.iter().rev() {
+            backward.write_state(record.clone()).unwrap();
+        }
+        rocks.advance_commit_ts(1).unwrap();
+
+        let expected = forward.create_snapshot_at(1).unwrap();
+        let first = &expected.records[0];
+        assert_eq!((first.namespace.as_str(), first.agent_id.as_str(), first.key.as_str()), ("tenant-a", "agent-1", "a"));
+        for storage in [&backward as &dyn Storage, &rocks] {
+            let mut snapshot = storage.create_snapshot_at(1).unwrap();
+            // Only the wall-clock creation time may differ
+            snapshot.metadata.created_at = expected.metadata.created_at;
+            assert_eq!(serde_json::to_vec(&snapshot).unwrap(), serde_json::to_vec(&expected).unwrap());
+            assert_eq!(
+                serde_json::to_vec(&storage.get_all_state().unwrap()).unwrap(),
+                serde_json::to_vec(&expected.records).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_create_snapshot_at_excludes_later_commits() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::new(test_config(&temp_dir)).unwrap();
+
+        storage.write_state(record("ns1", "a", 1, 1)).unwrap();
+        storage.write_state(record("ns2", "b", 1, 2)).unwrap();
+        // Landed after the snapshot point
+        storage.write_state(record("ns1", "a", 2, 3)).unwrap();
+        storage.write_state(record("ns3", "c", 1, 3)).unwrap();
+
+        let snapshot = storage.create_snapshot_at(2).unwrap();
+        let found: Vec<(String, Version)> = snapshot.records.iter().map(|r| (r.key.clone(), r.version)).collect();
+        assert_eq!(found, vec![("a".to_string(), 1), ("b".to_string(), 1)]);
+        assert_eq!(snapshot.metadata.snapshot_ts, 2);
+
+        // Everything, in key order, when nothing is newer
+        let all = storage.get_all_state().unwrap();
+        let keys: Vec<&str> = all.iter().map(|r| r.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+    }
+
+    fn agent_event(commit_ts: CommitTs, agents: &[(&str, &str)]) -> EventLogEntry {
+        EventLogEntry {
+            txn_id: format!("txn-{}", commit_ts),
+            commit_ts,
+            operations: agents
+                .iter()
+                .map(|(namespace, agent_id)| OperationRecord {
+                    namespace: namespace.to_string(),
+                    agent_id: agent_id.to_string(),
+                    key: format!("key-{}", commit_ts % 3),
+                    value: Some(serde_json::json!(commit_ts)),
+                    version: 1,
+                    labels: Labels::new(),
+                    expires_at: None,
+                })
+                .collect(),
+            namespace_ts: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_count_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let rocks = RocksStorage::new(test_config(&temp_dir)).unwrap();
+        for storage in [&rocks as &dyn Storage, &InMemoryStorage::new()] {
+            assert_eq!(storage.count_events(0).unwrap(), 0);
+            for commit_ts in [1, 2, 5, 1_000] {
+                storage.append_event(agent_event(commit_ts, &[("default", "agent-1")])).unwrap();
+            }
+            assert_eq!(storage.count_events(0).unwrap(), 4);
+            assert_eq!(storage.count_events(3).unwrap(), 2);
+            assert_eq!(storage.count_events(1_001).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_replay_uses_agent_event_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        // InMemoryStorage filters the whole log, so it gives the expected results
+        let expected = InMemoryStorage::new();
+        let rocks = RocksStorage::new(config.clone()).unwrap();
+        for commit_ts in 1..=200u64 {
+            let agent = format!("agent-{}", commit_ts % 5);
+            let mut agents = vec![("default", agent.as_str())];
+            if commit_ts % 7 == 0 {
+                agents.push(("other", "agent-1"));
+            }
+            if commit_ts % 11 == 0 {
+                agents.push(("default", "agent:x"));
+            }
+            expected.append_event(agent_event(commit_ts, &agents)).unwrap();
+            rocks.append_event(agent_event(commit_ts, &agents)).unwrap();
+        }
+
+        let assert_same = |rocks: &RocksStorage| {
+            for (namespace, agent_id) in [("default", "agent-1"), ("default", "agent:x"), ("other", "agent-1"), ("default", "agent"), ("missing", "agent-1")] {
+                for (start_ts, end_ts) in [(None, None), (Some(40), None), (None, Some(120)), (Some(77), Some(77)), (Some(150), Some(100))] {
+                    let timestamps = |storage: &dyn Storage| -> Vec<CommitTs> {
+                        storage.replay_events(namespace, agent_id, start_ts, end_ts, Deadline::NONE).unwrap().iter().map(|e| e.commit_ts).collect()
+                    };
+                    assert_eq!(timestamps(rocks), timestamps(&expected), "{}/{} {:?}..{:?}", namespace, agent_id, start_ts, end_ts);
+                }
+            }
+        };
+        assert_same(&rocks);
+
+        // Trimmed and scrubbed events leave no index entries behind
+        for storage in [&rocks as &dyn Storage, &expected] {
+            storage.trim_events(50).unwrap();
+            storage.purge_key(&RecordId::new("other".to_string(), "agent-1".to_string(), "key-0".to_string())).unwrap();
+        }
+        assert_same(&rocks);
+        let index_entries = |rocks: &RocksStorage, namespace: &str, agent_id: &str| {
+            let prefix = key_codec::agent_event_prefix(namespace, agent_id);
+            rocks.db.prefix_iterator(&prefix).map(|item| item.unwrap().0).take_while(|key| key.starts_with(&prefix)).count()
+        };
+        assert_eq!(index_entries(&rocks, "other", "agent-1"), (51..=200).filter(|ts| ts % 7 == 0 && ts % 3 != 0).count());
+
+        // Data written before the index existed is indexed on open
+        for item in rocks.db.prefix_iterator(key_codec::AGENT_EVENT_TAG) {
+            let (key, _) = item.unwrap();
+            if !key.starts_with(key_codec::AGENT_EVENT_TAG) {
+                break;
+            }
+            rocks.db.delete(key).unwrap();
+        }
+        rocks.db.delete(b"__agent_events__").unwrap();
+        drop(rocks);
+        let rocks = RocksStorage::new(config).unwrap();
+        assert_same(&rocks);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_replay_sparse_agent`
+    #[test]
+    #[ignore]
+    fn bench_replay_sparse_agent() {
+        let temp_dir = TempDir::new().unwrap();
+
+        // The same 100 events for one agent, among more and more of another's
+        for busy_events in [0u64, 10_000, 100_000] {
+            let config = StorageConfig { data_dir: temp_dir.path().join(busy_events.to_string()), ..test_config(&temp_dir) };
+            let storage = RocksStorage::new(config).unwrap();
+            let every = busy_events / 100 + 1;
+            for commit_ts in 1..=busy_events + 100 {
+                let agent_id = if commit_ts % every == 0 { "sparse" } else { "busy" };
+                storage.append_event(agent_event(commit_ts, &[("default", agent_id)])).unwrap();
+            }
+
+            let start = std::time::Instant::now();
+            for _ in 0..100 {
+                assert_eq!(storage.replay_events("default", "sparse", None, None, Deadline::NONE).unwrap().len(), 100);
+            }
+            println!("100 replays of a 100-event agent beside {} other events: {:?}", busy_events, start.elapsed());
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_snapshot_scan`
+    #[test]
+    #[ignore]
+    fn bench_snapshot_scan() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::new(test_config(&temp_dir)).unwrap();
+
+        for i in 0..1_000_000u64 {
+            let namespace = format!("ns-{:02}", i % 16);
+            storage.write_state(record(&namespace, &format!("key-{:07}", i), 1, i + 1)).unwrap();
+        }
+
+        let start = std::time::Instant::now();
+        let single = RocksStorage::scan_state(&storage.db.snapshot(), CommitTs::MAX, 1).unwrap();
+        let single_time = start.elapsed();
+
+        let workers = RocksStorage::scan_workers();
+        let start = std::time::Instant::now();
+        let parallel = RocksStorage::scan_state(&storage.db.snapshot(), CommitTs::MAX, workers).unwrap();
+        let parallel_time = start.elapsed();
+
+        assert_eq!(single.len(), 1_000_000);
+        assert_eq!(parallel.len(), single.len());
+        println!("snapshot scan of 1M records: 1 thread {:?}, {} threads {:?}", single_time, workers, parallel_time);
+    }
+
+    #[test]
+    fn test_exists_uses_record_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "big".to_string());
+
+        {
+            let storage = RocksStorage::new(config.clone()).unwrap();
+            let mut big = record("default", "big", 1, 1);
+            big.value = Some(serde_json::json!({"blob": "x".repeat(4 * 1024 * 1024)}));
+            storage.write_state(big).unwrap();
+            assert!(storage.exists(&record_id).unwrap());
+
+            let mut tombstone = record("default", "big", 2, 2);
+            tombstone.value = None;
+            tombstone.deleted = true;
+            storage.write_state(tombstone).unwrap();
+            assert!(!storage.exists(&record_id).unwrap());
+
+            let missing = RecordId::new("default".to_string(), "agent-1".to_string(), "missing".to_string());
+            assert!(!storage.exists(&missing).unwrap());
+
+            storage.write_state(record("default", "big", 3, 3)).unwrap();
+
+            // Simulate a data directory from before headers existed
+            storage.db.delete(key_codec::head_key(&record_id)).unwrap();
+            storage.db.delete(b"__heads__").unwrap();
+            assert!(!storage.exists(&record_id).unwrap());
+        }
+
+        // Headers are backfilled on open
+        let storage = RocksStorage::new(config).unwrap();
+        assert!(storage.exists(&record_id).unwrap());
+    }
+
+    #[test]
+    fn test_state_meta_reports_value_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        let value = serde_json::json!({"name": "ünïcode \"quoted\"", "items": [1, 2.5, null], "nested": {"ok": true}});
+        let value_bytes = serde_json::to_vec(&value).unwrap().len() as u64;
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "key".to_string());
+        let mut live = record("default", "key", 1, 1);
+        live.value = Some(value);
+
+        let rocks = RocksStorage::new(config.clone()).unwrap();
+        for storage in [&rocks as &dyn Storage, &InMemoryStorage::new()] {
+            assert_eq!(storage.read_state_meta(&record_id).unwrap(), None);
+
+            storage.write_state(live.clone()).unwrap();
+            let meta = storage.read_state_meta(&record_id).unwrap().unwrap();
+            assert_eq!(meta, StateMeta { version: 1, commit_ts: 1, deleted: false, value_bytes, expires_at: None });
+
+            let mut tombstone = record("default", "key", 2, 2);
+            tombstone.value = None;
+            tombstone.deleted = true;
+            storage.write_state(tombstone).unwrap();
+            let meta = storage.read_state_meta(&record_id).unwrap().unwrap();
+            assert_eq!(meta, StateMeta { version: 2, commit_ts: 2, deleted: true, value_bytes: 0, expires_at: None });
+        }
+
+        // Headers in the original layout, without value_bytes, are rebuilt on open
+        live.version = 3;
+        live.commit_ts = 3;
+        rocks.write_state(live).unwrap();
+        rocks.db.put(key_codec::head_key(&record_id), &RocksStorage::encode_head(&record("default", "key", 3, 3))[..17]).unwrap();
+        rocks.db.put(b"__heads__", b"").unwrap();
+        drop(rocks);
+
+        let rocks = RocksStorage::new(config).unwrap();
+        let meta = rocks.read_state_meta(&record_id).unwrap().unwrap();
+        assert_eq!(meta, StateMeta { version: 3, commit_ts: 3, deleted: false, value_bytes, expires_at: None });
+    }
+
+    #[test]
+    fn test_list_expired() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        let id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+        let expiring = |key: &str, version, expires_at| StateRecord { expires_at: Some(expires_at), ..record("default", key, version, version) };
+
+        let rocks = RocksStorage::new(config.clone()).unwrap();
+        for storage in [&rocks as &dyn Storage, &InMemoryStorage::new()] {
+            storage.write_state(expiring("due", 1, 1_000)).unwrap();
+            storage.write_state(expiring("later", 2, 5_000)).unwrap();
+            storage.write_state(record("default", "forever", 3, 3)).unwrap();
+            // Written again without a TTL after it was given one
+            storage.write_state(expiring("rewritten", 4, 1_000)).unwrap();
+            storage.write_state(record("default", "rewritten", 5, 5)).unwrap();
+
+            assert_eq!(storage.list_expired(999).unwrap(), Vec::<RecordId>::new());
+            assert_eq!(storage.list_expired(1_000).unwrap(), vec![id("due")]);
+            assert_eq!(storage.read_state_meta(&id("later")).unwrap().unwrap().expires_at, Some(5_000));
+
+            // A tombstone has nothing left to expire
+            let tombstone = StateRecord { value: None, deleted: true, ..record("default", "due", 6, 6) };
+            storage.write_state(tombstone).unwrap();
+            assert_eq!(storage.list_expired(5_000).unwrap(), vec![id("later")]);
+        }
+
+        // Expiry times survive a reopen
+        drop(rocks);
+        let rocks = RocksStorage::new(config).unwrap();
+        assert_eq!(rocks.list_expired(5_000).unwrap(), vec![id("later")]);
+    }
+
+    #[test]
+    fn test_large_values_are_compressed() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig { value_compression_threshold: 4096, ..test_config(&temp_dir) };
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "big".to_string());
+        let small_id = RecordId::new("default".to_string(), "agent-1".to_string(), "small".to_string());
+        let mut big = record("default", "big", 1, 1);
+        big.value = Some(serde_json::json!({"text": "statehouse ".repeat(1024 * 1024 / 11)}));
+        let json_len = serde_json::to_vec(&big).unwrap().len();
+        assert!(json_len >= 1024 * 1024);
+
+        {
+            let storage = RocksStorage::new(config.clone()).unwrap();
+            storage.write_state(big.clone()).unwrap();
+            storage.write_state(record("default", "small", 1, 1)).unwrap();
+
+            let stored = storage.db.get(key_codec::state_key(&record_id)).unwrap().unwrap();
+            assert_eq!(stored[0], COMPRESSED_ZSTD);
+            assert!(stored.len() < json_len / 100, "{} bytes stored", stored.len());
+            let stored = storage.db.get(key_codec::version_key(&record_id, 1)).unwrap().unwrap();
+            assert!(stored.len() < json_len / 100);
+
+            // Small values stay as they were
+            let stored = storage.db.get(key_codec::state_key(&small_id)).unwrap().unwrap();
+            assert_eq!(stored, serde_json::to_vec(&record("default", "small", 1, 1)).unwrap());
+        }
+
+        // Reads return the exact original, after a reopen too and with compression off
+        let storage = RocksStorage::new(StorageConfig { value_compression_threshold: 0, ..config }).unwrap();
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().value, big.value);
+        assert_eq!(storage.read_version_history(&record_id, 1).unwrap()[0].value, big.value);
+        assert_eq!(storage.read_state_meta(&record_id).unwrap().unwrap().value_bytes, json_size(big.value.as_ref().unwrap()) as u64);
+        assert_eq!(storage.scan_prefix("default", "agent-1", "", false, Deadline::NONE).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_identical_values_share_a_blob() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig { value_dedup_threshold: 256, ..test_config(&temp_dir) };
+        let template = serde_json::json!({"prompt": "You are a careful planning agent. ".repeat(32)});
+        let hash = value_hash(&template);
+        let with_template = |key: &str, version, commit_ts| StateRecord {
+            value: Some(template.clone()),
+            value_hash: Some(hash.clone()),
+            ..record("default", key, version, commit_ts)
+        };
+        let id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+        let refs = |storage: &RocksStorage| {
+            let count = storage.db.get(key_codec::blob_ref_key(&hash)).unwrap();
+            count.map(|count| u64::from_be_bytes(count.as_slice().try_into().unwrap()))
+        };
+        let blobs = |storage: &RocksStorage| {
+            storage.db.prefix_iterator(key_codec::BLOB_TAG).filter(|item| item.as_ref().unwrap().0.starts_with(key_codec::BLOB_TAG)).count()
+        };
+
+        let storage = RocksStorage::new(config.clone()).unwrap();
+        storage.write_state(with_template("plan", 1, 1)).unwrap();
+        storage.write_state(with_template("backup", 1, 2)).unwrap();
+        storage.write_state(record("default", "small", 1, 3)).unwrap();
+
+        // One blob, held by both keys' state and version entries; small values stay inline
+        assert_eq!((blobs(&storage), refs(&storage)), (1, Some(4)));
+        let stored = storage.db.get(key_codec::state_key(&id("plan"))).unwrap().unwrap();
+        assert_eq!(stored[0], BLOB_REF);
+        assert!(stored.len() < json_size(&template));
+        assert_eq!(storage.db.get(key_codec::state_key(&id("small"))).unwrap().unwrap()[0], b'{');
+        assert_eq!(storage.read_state(&id("plan")).unwrap().unwrap().value, Some(template.clone()));
+        assert_eq!(storage.read_state_as_of(&id("backup"), 2).unwrap().unwrap().value, Some(template.clone()));
+        let scanned = storage.scan_prefix("default", "agent-1", "", false, Deadline::NONE).unwrap();
+        assert_eq!(scanned.iter().filter(|r| r.value == Some(template.clone())).count(), 2);
+        assert_eq!(storage.get_all_state().unwrap().iter().filter(|r| r.value == Some(template.clone())).count(), 2);
+
+        // Deleting and purging one key releases its references, not the blob
+        storage.write_state(StateRecord { value: None, deleted: true, ..record("default", "plan", 2, 4) }).unwrap();
+        assert_eq!(refs(&storage), Some(3));
+        storage.purge_key(&id("plan")).unwrap();
+        assert_eq!((blobs(&storage), refs(&storage)), (1, Some(2)));
+        assert_eq!(storage.read_state_at_version(&id("backup"), 1).unwrap().unwrap().value, Some(template.clone()));
+
+        // References are still released with dedup turned off, and the blob
+        // goes with the last
+        drop(storage);
+        let storage = RocksStorage::new(StorageConfig { value_dedup_threshold: 0, ..config }).unwrap();
+        assert_eq!(storage.read_state(&id("backup")).unwrap().unwrap().value, Some(template.clone()));
+        storage.write_state(record("default", "backup", 2, 5)).unwrap();
+        assert_eq!(refs(&storage), Some(1));
+        storage.compact_history(5, 1).unwrap();
+        assert_eq!((blobs(&storage), refs(&storage)), (0, None));
+        assert_eq!(storage.read_state(&id("backup")).unwrap().unwrap().value, Some(serde_json::json!({"version": 2})));
+    }
+
+    #[test]
+    fn test_sharded_snapshot_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig { max_snapshot_shard_bytes: 8 * 1024, ..test_config(&temp_dir) };
+        let storage = RocksStorage::new(config.clone()).unwrap();
+        for i in 0..500 {
+            let mut record = record("default", &format!("key-{:04}", i), 1, i + 1);
+            record.value = Some(serde_json::json!({"n": i, "text": "line\nbreak"}));
+            storage.write_state(record).unwrap();
+        }
+        storage.set_commit_ts(500).unwrap();
+        let snapshot = storage.create_snapshot().unwrap();
+        storage.save_snapshot(&snapshot).unwrap();
+        drop(storage);
+
+        let manifest: SnapshotManifest =
+            serde_json::from_str(&std::fs::read_to_string(temp_dir.path().join(SNAPSHOT_MANIFEST)).unwrap()).unwrap();
+        assert!(manifest.shards.len() > 1, "{} shards", manifest.shards.len());
+        assert_eq!(manifest.shards.iter().map(|s| s.record_count).sum::<usize>(), 500);
+        for shard in &manifest.shards {
+            let raw = zstd::stream::decode_all(std::fs::File::open(temp_dir.path().join(&shard.file)).unwrap()).unwrap();
+            assert!(raw.len() as u64 <= config.max_snapshot_shard_bytes);
+        }
+        assert!(!temp_dir.path().join("snapshot.json").exists());
+
+        // Reload reconstructs every record in order, whatever the setting now is
+        let storage = RocksStorage::new(StorageConfig { max_snapshot_shard_bytes: 0, ..config.clone() }).unwrap();
+        let loaded = storage.load_snapshot().unwrap().unwrap();
+        assert_eq!(loaded.metadata.snapshot_ts, 500);
+        assert_eq!(serde_json::to_string(&loaded.records).unwrap(), serde_json::to_string(&snapshot.records).unwrap());
+
+        // A smaller snapshot drops the shards it no longer needs
+        let small = Snapshot { metadata: SnapshotMetadata { record_count: 1, ..snapshot.metadata.clone() }, records: snapshot.records[..1].to_vec() };
+        write_sharded_snapshot(temp_dir.path(), &small, config.max_snapshot_shard_bytes).unwrap();
+        assert!(!temp_dir.path().join(snapshot_shard_name(1)).exists());
+        assert_eq!(storage.load_snapshot().unwrap().unwrap().records.len(), 1);
+
+        // A damaged shard fails the load instead of restoring partial state
+        write_sharded_snapshot(temp_dir.path(), &snapshot, config.max_snapshot_shard_bytes).unwrap();
+        std::fs::copy(temp_dir.path().join(snapshot_shard_name(0)), temp_dir.path().join(snapshot_shard_name(1))).unwrap();
+        assert!(storage.load_snapshot().is_err());
+    }
+
+    #[test]
+    fn test_namespace_usage_follows_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        let bytes = |version: Version| json_size(&serde_json::json!({"version": version})) as u64;
+        let usage = |records, bytes| NamespaceUsage { records, bytes };
+
+        let rocks = RocksStorage::new(config.clone()).unwrap();
+        for storage in [&rocks as &dyn Storage, &InMemoryStorage::new()] {
+            storage.write_state(record("tenant-a", "a", 1, 1)).unwrap();
+            storage.write_state(record("tenant-a", "b", 1, 2)).unwrap();
+            storage.write_state(record("tenant-b", "a", 1, 3)).unwrap();
+            // Overwrites replace the old value's bytes
+            storage.write_state(record("tenant-a", "a", 10, 4)).unwrap();
+            assert_eq!(storage.namespace_usage("tenant-a").unwrap(), usage(2, bytes(10) + bytes(1)));
+
+            // Tombstones free their record
+            let mut tombstone = record("tenant-a", "b", 2, 5);
+            tombstone.value = None;
+            tombstone.deleted = true;
+            storage.write_state(tombstone).unwrap();
+            assert_eq!(storage.namespace_usage("tenant-a").unwrap(), usage(1, bytes(10)));
+
+            storage.purge_key(&RecordId::new("tenant-a".to_string(), "agent-1".to_string(), "a".to_string())).unwrap();
+            assert_eq!(storage.namespace_usage("tenant-a").unwrap(), usage(0, 0));
+            assert_eq!(storage.namespace_usage("tenant-b").unwrap(), usage(1, bytes(1)));
+
+            let snapshot = Snapshot {
+                metadata: SnapshotMetadata { namespace: Some("tenant-b".to_string()), ..storage.create_snapshot().unwrap().metadata },
+                records: vec![record("tenant-b", "x", 1, 1), record("tenant-b", "y", 1, 1)],
+            };
+            storage.restore_namespace_snapshot(&snapshot).unwrap();
+            assert_eq!(storage.namespace_usage("tenant-b").unwrap(), usage(2, 2 * bytes(1)));
+        }
+
+        // Persisted with the records, and counted from them for data
+        // written before usage was tracked
+        drop(rocks);
+        let rocks = RocksStorage::new(config.clone()).unwrap();
+        assert_eq!(rocks.namespace_usage("tenant-b").unwrap(), usage(2, 2 * bytes(1)));
+        rocks.db.delete(RocksStorage::namespace_usage_key("tenant-b")).unwrap();
+        drop(rocks);
+        let rocks = RocksStorage::new(config).unwrap();
+        assert_eq!(rocks.namespace_usage("tenant-b").unwrap(), usage(2, 2 * bytes(1)));
+        rocks.write_state(record("tenant-b", "z", 1, 6)).unwrap();
+        assert_eq!(rocks.namespace_usage("tenant-b").unwrap(), usage(3, 3 * bytes(1)));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_exists`
+    #[test]
+    #[ignore]
+    fn bench_exists_vs_read_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::new(test_config(&temp_dir)).unwrap();
+        let mut big = record("default", "big", 1, 1);
+        big.value = Some(serde_json::json!({"blob": "x".repeat(1024 * 1024)}));
+        storage.write_state(big).unwrap();
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "big".to_string());
+
+        let start = std::time::Instant::now();
+        for _ in 0..200 {
+            assert!(storage.exists(&record_id).unwrap());
+        }
+        let exists_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..200 {
+            assert!(!storage.read_state(&record_id).unwrap().unwrap().deleted);
+        }
+        let read_time = start.elapsed();
+
+        println!("200 checks on a 1MB value: exists {:?}, read_state {:?}", exists_time, read_time);
+        assert!(exists_time < read_time);
+    }
+
+    #[test]
+    fn test_read_cache_sees_updates() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            read_cache_capacity: 2,
+            ..test_config(&temp_dir)
+        };
+        let storage = RocksStorage::new(config).unwrap();
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "hot".to_string());
+
+        storage.write_state(record("default", "hot", 1, 1)).unwrap();
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 1);
+
+        // A committed write replaces the cached record
+        storage.write_state(record("default", "hot", 2, 2)).unwrap();
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 2);
+
+        // Evicted records are re-read from RocksDB
+        for key in ["a", "b", "c"] {
+            storage.write_state(record("default", key, 1, 3)).unwrap();
+        }
+        assert_eq!(storage.read_cache.as_ref().unwrap().lock().unwrap().records.len(), 2);
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 2);
+
+        // Restores bypass write_state and must not leave stale entries behind
+        let snapshot = Snapshot {
+            metadata: SnapshotMetadata {
+                version: SNAPSHOT_VERSION,
+                snapshot_ts: 10,
+                record_count: 1,
+                created_at: 0,
+                namespace: Some("default".to_string()),
+            },
+            records: vec![record("default", "hot", 7, 10)],
+        };
+        storage.restore_namespace_snapshot(&snapshot).unwrap();
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 7);
+    }
+
+    #[test]
+    #[ignore] // benchmark: cargo test -p statehouse-core --release -- --ignored --nocapture bench_read_cache
+    fn bench_read_cache_hot_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut hot = record("default", "hot", 1, 1);
+        hot.value = Some(serde_json::json!({"items": (0..1000).collect::<Vec<_>>()}));
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "hot".to_string());
+
+        let mut times = Vec::new();
+        for capacity in [0, 1024] {
+            let config = StorageConfig {
+                data_dir: temp_dir.path().join(capacity.to_string()),
+                read_cache_capacity: capacity,
+                ..test_config(&temp_dir)
+            };
+            let storage = RocksStorage::new(config).unwrap();
+            storage.write_state(hot.clone()).unwrap();
+
+            let start = std::time::Instant::now();
+            for _ in 0..2000 {
+                assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 1);
+            }
+            times.push(start.elapsed());
+        }
+
+        println!("2000 hot-key reads: uncached {:?}, cached {:?}", times[0], times[1]);
+        assert!(times[1] < times[0]);
+    }
+
+    #[test]
+    fn test_scan_namespace_prefix_across_agents() {
+        let temp_dir = TempDir::new().unwrap();
+        let rocks: Box<dyn Storage> = Box::new(RocksStorage::new(test_config(&temp_dir)).unwrap());
+        let memory: Box<dyn Storage> = Box::new(InMemoryStorage::new());
+
+        for storage in [rocks, memory] {
+            let write = |namespace: &str, agent_id: &str, key: &str| {
+                let mut record = record(namespace, key, 1, 1);
+                record.agent_id = agent_id.to_string();
+                storage.write_state(record).unwrap();
+            };
+            write("ns", "agent-1", "task:a");
+            write("ns", "agent-10", "task:b");
+            write("ns", "agent-10", "note:c");
+            write("ns:x", "agent-1", "task:d");
+            write("other", "agent-1", "task:e");
+            let mut tombstone = record("ns", "task:f", 2, 2);
+            tombstone.deleted = true;
+            storage.write_state(tombstone).unwrap();
+
+            let found: Vec<(String, String)> = storage
+                .scan_namespace_prefix("ns", "task:", usize::MAX)
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.agent_id, r.key))
+                .collect();
+            assert_eq!(found, vec![
+                ("agent-1".to_string(), "task:a".to_string()),
+                ("agent-10".to_string(), "task:b".to_string()),
+            ]);
+
+            assert_eq!(storage.scan_namespace_prefix("ns", "", usize::MAX).unwrap().len(), 3);
+            assert_eq!(storage.scan_namespace_prefix("ns", "task:", 1).unwrap()[0].key, "task:a");
+            assert!(storage.scan_namespace_prefix("ns", "missing:", usize::MAX).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_scan_include_deleted() {
+        let temp_dir = TempDir::new().unwrap();
+        let rocks: Box<dyn Storage> = Box::new(RocksStorage::new(test_config(&temp_dir)).unwrap());
+        let memory: Box<dyn Storage> = Box::new(InMemoryStorage::new());
+
+        for storage in [rocks, memory] {
+            storage.write_state(record("default", "task:a", 1, 1)).unwrap();
+            storage.write_state(record("default", "task:b", 1, 2)).unwrap();
+            let mut tombstone = record("default", "task:b", 2, 3);
+            tombstone.deleted = true;
+            tombstone.value = None;
+            storage.write_state(tombstone).unwrap();
+
+            let scan = |include_deleted| -> Vec<(String, bool)> {
+                let mut found: Vec<_> = storage.scan_prefix("default", "agent-1", "task:", include_deleted, Deadline::NONE)
+                    .unwrap()
+                    .into_iter()
+                    .map(|r| (r.key, r.deleted))
+                    .collect();
+                found.sort();
+                found
+            };
+            assert_eq!(scan(false), vec![("task:a".to_string(), false)]);
+            assert_eq!(scan(true), vec![("task:a".to_string(), false), ("task:b".to_string(), true)]);
+
+            assert_eq!(storage.list_keys("default", "agent-1", false, Deadline::NONE).unwrap(), vec!["task:a"]);
+            assert_eq!(storage.list_keys("default", "agent-1", true, Deadline::NONE).unwrap(), vec!["task:a", "task:b"]);
+        }
+    }
+
+    #[test]
+    fn test_retention_filter_drops_expired_on_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            compaction_version_retention: 5,
+            compaction_expire_tombstones: true,
+            value_dedup_threshold: 0,
+            ..test_config(&temp_dir)
+        };
+        let storage = RocksStorage::new(config).unwrap();
+        let tombstone = |key: &str, version, commit_ts, purge_after| StateRecord {
+            value: None,
+            deleted: true,
+            purge_after: Some(purge_after),
+            ..record("default", key, version, commit_ts)
+        };
+
+        // history v1 was superseded at ts 2, v2 only at ts 20
+        storage.write_state(record("default", "history", 1, 1)).unwrap();
+        storage.write_state(record("default", "history", 2, 2)).unwrap();
+        storage.write_state(record("default", "history", 3, 20)).unwrap();
+        storage.write_state(record("default", "live", 1, 3)).unwrap();
+        storage.write_state(record("default", "expired", 1, 4)).unwrap();
+        storage.write_state(tombstone("expired", 2, 5, 1)).unwrap();
+        storage.write_state(record("default", "grace", 1, 6)).unwrap();
+        storage.write_state(tombstone("grace", 2, 7, u64::MAX)).unwrap();
+        storage.advance_commit_ts(22).unwrap();
+
+        storage.db.compact_range::<&[u8], &[u8]>(None, None);
+
+        let id = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+        let versions = |storage: &RocksStorage, key: &str| -> Vec<Version> {
+            storage.read_version_history(&id(key), 10).unwrap().iter().map(|r| r.version).collect()
+        };
+        assert_eq!(versions(&storage, "history"), vec![3, 2]);
+        assert_eq!(storage.read_state(&id("history")).unwrap().unwrap().version, 3);
+        assert_eq!(versions(&storage, "live"), vec![1]);
+        assert!(storage.read_state(&id("live")).unwrap().is_some());
+
+        // The expired tombstone goes whole; the one in its grace period stays
+        assert!(storage.read_state(&id("expired")).unwrap().is_none());
+        assert!(storage.db.get(key_codec::head_key(&id("expired"))).unwrap().is_none());
+        assert!(versions(&storage, "expired").is_empty());
+        assert!(storage.read_state(&id("grace")).unwrap().unwrap().deleted);
+        assert_eq!(versions(&storage, "grace"), vec![2]);
+
+        // Without either setting nothing is dropped
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::new(test_config(&temp_dir)).unwrap();
+        storage.write_state(record("default", "history", 1, 1)).unwrap();
+        storage.write_state(record("default", "history", 2, 2)).unwrap();
+        storage.write_state(tombstone("expired", 1, 3, 1)).unwrap();
+        storage.advance_commit_ts(100).unwrap();
+        storage.db.compact_range::<&[u8], &[u8]>(None, None);
+        assert_eq!(versions(&storage, "history"), vec![2, 1]);
+        assert!(storage.read_state(&id("expired")).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_version_history_skips_longer_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::new(test_config(&temp_dir)).unwrap();
+
+        for version in 1..=3 {
+            storage.write_state(record("default", "a", version, version)).unwrap();
+        }
+        storage.write_state(record("default", "a:b", 1, 4)).unwrap();
+
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "a".to_string());
+        let history = storage.read_version_history(&record_id, 10).unwrap();
+        let versions: Vec<Version> = history.iter().map(|r| r.version).collect();
+        assert_eq!(versions, vec![3, 2, 1]);
+        assert!(history.iter().all(|r| r.key == "a"));
+    }
+
+    #[test]
+    fn test_in_memory_version_cap() {
+        let storage = InMemoryStorage::new().with_max_versions_per_key(10);
+        for version in 1..=100 {
+            storage.write_state(record("default", "a", version, version)).unwrap();
+        }
+
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "a".to_string());
+        assert_eq!(storage.version_bounds(&record_id).unwrap(), Some((91, 100)));
+        let versions: Vec<Version> = storage.read_version_history(&record_id, 100).unwrap().iter().map(|r| r.version).collect();
+        assert_eq!(versions, (91..=100).rev().collect::<Vec<_>>());
+        assert!(storage.read_state_at_version(&record_id, 90).unwrap().is_none());
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 100);
+    }
+
+    #[test]
+    fn test_corrupt_directory_is_reported_and_repaired() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "k".to_string());
+        {
+            let storage = RocksStorage::new(config.clone()).unwrap();
+            storage.write_state(record("default", "k", 1, 1)).unwrap();
+            storage.db.flush().unwrap();
+
+            // Held by another open instance: not corruption
+            let err = RocksStorage::new(config.clone()).err().unwrap();
+            assert!(matches!(err.downcast_ref(), Some(StatehouseError::StorageUnavailable { .. })), "{}", err);
+        }
+
+        std::fs::write(temp_dir.path().join("rocksdb").join("CURRENT"), b"garbage").unwrap();
+        let err = RocksStorage::new(config.clone()).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(StatehouseError::StorageCorrupt { .. })), "{}", err);
+        assert!(err.to_string().contains("statehoused repair"), "{}", err);
+
+        RocksStorage::repair(&config).unwrap();
+        let storage = RocksStorage::new(config).unwrap();
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 1);
+
+        let missing = StorageConfig { data_dir: temp_dir.path().join("missing"), ..test_config(&temp_dir) };
+        let err = RocksStorage::repair(&missing).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StatehouseError::StorageUnavailable { .. })), "{}", err);
+    }
+
+    #[test]
+    fn test_legacy_keys_are_migrated() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(&temp_dir);
+        // A ':' in the key made the old layout ambiguous
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "a:b".to_string());
+
+        // Write a data directory in the original text layout
+        {
+            let storage = RocksStorage::new(config.clone()).unwrap();
+            storage.db.delete(b"__key_format__").unwrap();
+            storage.db.delete(b"__heads__").unwrap();
+            for version in 1..=2 {
+                let value = serde_json::to_vec(&record("default", "a:b", version, version)).unwrap();
+                storage.db.put(format!("version:default:agent-1:a:b:{:020}", version), &value).unwrap();
+                storage.db.put("state:default:agent-1:a:b", &value).unwrap();
+                let event = EventLogEntry {
+                    txn_id: format!("txn-{}", version),
+                    commit_ts: version,
+                    operations: Vec::new(),
+                    namespace_ts: BTreeMap::from([("default".to_string(), version)]),
+                };
+                storage.db.put(format!("event:{:020}", version), serde_json::to_vec(&event).unwrap()).unwrap();
+            }
+            storage.db.put("head:default:agent-1:a:b", [0u8; 17]).unwrap();
+            storage.db.put("__namespace_ts__:default", 2u64.to_be_bytes()).unwrap();
+            storage.db.put(b"__commit_ts__", 2u64.to_be_bytes()).unwrap();
+        }
+
+        let storage = RocksStorage::new(config).unwrap();
+        assert_eq!(storage.read_state(&record_id).unwrap().unwrap().version, 2);
+        assert!(storage.exists(&record_id).unwrap());
+        let versions: Vec<Version> = storage
+            .read_version_history(&record_id, 10)
+            .unwrap()
+            .iter()
+            .map(|r| r.version)
+            .collect();
+        assert_eq!(versions, vec![2, 1]);
+        assert_eq!(storage.list_keys("default", "agent-1", false, Deadline::NONE).unwrap(), vec!["a:b".to_string()]);
+        assert_eq!(storage.next_namespace_ts("default").unwrap(), 3);
+        assert_eq!(storage.next_commit_ts().unwrap(), 3);
+
+        // Every remaining key decodes under the new scheme
+        for item in storage.db.iterator(IteratorMode::Start) {
+            let (key, _) = item.unwrap();
+            key_codec::decode_key(&key).unwrap();
+        }
+    }
 }
@@ -0,0 +1,265 @@
+// Storage backend conformance
+//
+// Runs the same seeded random workload (writes, deletes, commits, aborts and
+// crashes) against every storage backend through the state machine, and after
+// each step compares what each one reports, via `get_state`, `list_keys` and
+// `replay`, with a model that keeps committed state in a plain `HashMap`. A
+// failing seed reproduces exactly.
+//
+// Agents and keys are picked from small sets of names that are prefixes of
+// one another, so range scans that run past their own record are caught.
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+use crate::deadline::Deadline;
+use crate::state_machine::StateMachine;
+use crate::storage::{EventLogEntry, InMemoryStorage, RocksStorage, StateRecord, Storage, StorageConfig};
+use crate::types::*;
+
+const NAMESPACES: &[&str] = &["ns", "ns2"];
+const AGENTS: &[&str] = &["a", "ab", "a:b"];
+const KEYS: &[&str] = &["k", "k1", "k:1", "kk", "k\0"];
+
+/// SplitMix64; small, seedable, and the same on every platform
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn pick<'a>(&mut self, names: &[&'a str]) -> &'a str {
+        names[self.below(names.len())]
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Begin,
+    Write(RecordId, Value),
+    Delete(RecordId),
+    Commit,
+    Abort,
+    /// Drop the state machine and reopen storage, losing open transactions
+    Crash,
+}
+
+fn random_step(rng: &mut Rng, open: bool) -> Step {
+    if !open {
+        return if rng.below(10) == 0 { Step::Crash } else { Step::Begin };
+    }
+    let record_id = RecordId::new(rng.pick(NAMESPACES).to_string(), rng.pick(AGENTS).to_string(), rng.pick(KEYS).to_string());
+    match rng.below(20) {
+        0..=8 => {
+            let value = match rng.below(3) {
+                0 => json!(rng.below(100)),
+                1 => json!({"n": rng.below(100), "tags": ["x", rng.below(10)]}),
+                _ => json!(format!("v{}", rng.below(1000))),
+            };
+            Step::Write(record_id, value)
+        }
+        9..=12 => Step::Delete(record_id),
+        13..=16 => Step::Commit,
+        17..=18 => Step::Abort,
+        _ => Step::Crash,
+    }
+}
+
+/// A staged operation: key and value, `None` for a delete
+type StagedOperation = (RecordId, Option<Value>);
+
+/// A committed operation: key, value and new version
+type ModelOperation = (RecordId, Option<Value>, Version);
+
+/// Committed state and history as the backends should report it
+#[derive(Default)]
+struct Model {
+    /// Latest value (`None` once deleted) and version of every key ever written
+    records: HashMap<RecordId, (Option<Value>, Version)>,
+    /// Committed events: commit_ts, txn_id and operations
+    events: Vec<(CommitTs, TxnId, Vec<ModelOperation>)>,
+    commit_ts: CommitTs,
+    /// The open transaction's id and staged operations
+    open: Option<(TxnId, Vec<StagedOperation>)>,
+}
+
+impl Model {
+    fn commit(&mut self) {
+        let (txn_id, staged) = self.open.take().expect("commit without a transaction");
+        self.commit_ts += 1;
+        let mut operations = Vec::new();
+        for (record_id, value) in staged {
+            let entry = self.records.entry(record_id.clone()).or_insert((None, 0));
+            *entry = (value.clone(), entry.1 + 1);
+            operations.push((record_id, value, entry.1));
+        }
+        self.events.push((self.commit_ts, txn_id, operations));
+    }
+
+    fn get_state(&self, record_id: &RecordId) -> Option<(Option<Value>, Version, bool)> {
+        self.records.get(record_id).map(|(value, version)| (value.clone(), *version, value.is_none()))
+    }
+
+    fn list_keys(&self, namespace: &str, agent_id: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .records
+            .iter()
+            .filter(|(id, (value, _))| id.namespace == namespace && id.agent_id == agent_id && value.is_some())
+            .map(|(id, _)| id.key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn replay(&self, namespace: &str, agent_id: &str) -> Vec<Value> {
+        self.events
+            .iter()
+            .filter(|(_, _, operations)| operations.iter().any(|(id, _, _)| id.namespace == namespace && id.agent_id == agent_id))
+            .map(|(commit_ts, txn_id, operations)| {
+                let operations: Vec<Value> = operations
+                    .iter()
+                    .map(|(id, value, version)| json!([id.namespace, id.agent_id, id.key, value, version]))
+                    .collect();
+                json!([commit_ts, txn_id, operations])
+            })
+            .collect()
+    }
+}
+
+/// Comparable form of a replayed event, without fields the model doesn't track
+fn event_summary(event: &EventLogEntry) -> Value {
+    let operations: Vec<Value> = event
+        .operations
+        .iter()
+        .map(|op| json!([op.namespace, op.agent_id, op.key, op.value, op.version]))
+        .collect();
+    json!([event.commit_ts, event.txn_id, operations])
+}
+
+fn record_summary(record: Option<StateRecord>) -> Option<(Option<Value>, Version, bool)> {
+    record.map(|record| (record.value, record.version, record.deleted))
+}
+
+/// A backend under test and how to reopen it after a crash
+struct Backend {
+    name: &'static str,
+    sm: Option<StateMachine>,
+    reopen: Box<dyn Fn() -> Result<Arc<dyn Storage>>>,
+}
+
+impl Backend {
+    fn in_memory() -> Self {
+        // Nothing survives a real crash of in-memory storage, so a crash here
+        // only loses the state machine and its open transactions
+        let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+        let reopen = Box::new(move || Ok(storage.clone()));
+        Self::open("in-memory", reopen)
+    }
+
+    fn rocks(dir: &TempDir) -> Self {
+        let config = StorageConfig { data_dir: dir.path().to_path_buf(), fsync_on_commit: true, ..StorageConfig::default() };
+        let reopen = Box::new(move || Ok(Arc::new(RocksStorage::new(config.clone())?) as Arc<dyn Storage>));
+        Self::open("rocksdb", reopen)
+    }
+
+    fn open(name: &'static str, reopen: Box<dyn Fn() -> Result<Arc<dyn Storage>>>) -> Self {
+        let sm = StateMachine::new(reopen().unwrap());
+        Self { name, sm: Some(sm), reopen }
+    }
+
+    fn sm(&self) -> &StateMachine {
+        self.sm.as_ref().unwrap()
+    }
+
+    fn crash(&mut self) {
+        // RocksDB can't be reopened until the old handle is closed
+        self.sm = None;
+        let sm = StateMachine::new((self.reopen)().unwrap());
+        sm.recover().unwrap();
+        self.sm = Some(sm);
+    }
+
+    fn apply(&mut self, step: &Step, txn_id: &str) -> Result<()> {
+        let sm = self.sm();
+        match step.clone() {
+            Step::Begin => sm.begin_transaction_with_id(Some(txn_id.to_string()), None).map(drop),
+            Step::Write(RecordId { namespace, agent_id, key }, value) => sm.write(txn_id, namespace, agent_id, key, value),
+            Step::Delete(RecordId { namespace, agent_id, key }) => sm.delete(txn_id, namespace, agent_id, key),
+            Step::Commit => sm.commit(txn_id).map(drop),
+            Step::Abort => sm.abort(txn_id),
+            Step::Crash => {
+                self.crash();
+                Ok(())
+            }
+        }
+    }
+
+    /// Panic with the seed and step if anything differs from `model`
+    fn check(&self, model: &Model, context: &str) {
+        let sm = self.sm();
+        for namespace in NAMESPACES {
+            for agent_id in AGENTS {
+                for key in KEYS {
+                    let record_id = RecordId::new(namespace.to_string(), agent_id.to_string(), key.to_string());
+                    let actual = record_summary(sm.get_state(namespace, agent_id, key).unwrap());
+                    assert_eq!(actual, model.get_state(&record_id), "{}: {} get_state {:?}", context, self.name, record_id);
+                }
+                let keys = sm.list_keys(namespace, agent_id, false, Deadline::NONE).unwrap();
+                assert_eq!(keys, model.list_keys(namespace, agent_id), "{}: {} list_keys {}/{}", context, self.name, namespace, agent_id);
+                let events: Vec<Value> = sm.replay(namespace, agent_id, None, None).unwrap().iter().map(event_summary).collect();
+                assert_eq!(events, model.replay(namespace, agent_id), "{}: {} replay {}/{}", context, self.name, namespace, agent_id);
+            }
+        }
+    }
+}
+
+fn run(seed: u64, steps: usize) {
+    let dir = TempDir::new().unwrap();
+    let mut backends = [Backend::in_memory(), Backend::rocks(&dir)];
+    let mut model = Model::default();
+    let mut rng = Rng(seed);
+    let mut txn_count = 0;
+
+    for step_index in 0..steps {
+        let step = random_step(&mut rng, model.open.is_some());
+        let context = format!("seed {} step {} {:?}", seed, step_index, step);
+        if matches!(step, Step::Begin) {
+            txn_count += 1;
+        }
+        let txn_id = format!("txn-{}", txn_count);
+
+        for backend in &mut backends {
+            backend.apply(&step, &txn_id).unwrap_or_else(|e| panic!("{}: {} failed: {}", context, backend.name, e));
+        }
+        match step {
+            Step::Begin => model.open = Some((txn_id, Vec::new())),
+            Step::Write(record_id, value) => model.open.as_mut().unwrap().1.push((record_id, Some(value))),
+            Step::Delete(record_id) => model.open.as_mut().unwrap().1.push((record_id, None)),
+            Step::Commit => model.commit(),
+            Step::Abort | Step::Crash => model.open = None,
+        }
+
+        for backend in &backends {
+            backend.check(&model, &context);
+        }
+    }
+}
+
+#[test]
+fn test_backends_match_model() {
+    for seed in 0..8 {
+        run(seed, 150);
+    }
+}
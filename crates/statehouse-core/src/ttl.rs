@@ -0,0 +1,86 @@
+// Per-namespace default TTLs
+//
+// A namespace used for ephemeral memory can give every write a time to live
+// rather than each client asking for one. A write that sets its own TTL keeps
+// it, 0 meaning the key never expires; one that doesn't takes its namespace's
+// default when the transaction commits. The expiry sweep
+// (`StateMachine::expire_keys`) deletes keys whose time has passed, as an
+// ordinary commit, so replay and replicas see the expiries like any other
+// delete.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::types::Namespace;
+
+/// The default TTL of every namespace: a per-namespace one if set, else the
+/// default
+#[derive(Debug, Clone, Default)]
+pub struct DefaultTtls {
+    default: Option<Duration>,
+    namespaces: HashMap<Namespace, Duration>,
+}
+
+impl DefaultTtls {
+    /// Parse `namespace=ms;...`, where the namespace `*` sets the default,
+    /// e.g. `*=3600000;scratch=60000`. A TTL of 0 means no expiry, to exempt
+    /// a namespace from the default.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut ttls = Self::default();
+        for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (namespace, ttl_ms) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid default TTL {:?}: expected namespace=ms", entry))?;
+            let ttl_ms = ttl_ms.trim().parse().map_err(|_| anyhow!("Invalid default TTL {:?}: not a number", entry))?;
+            let ttl = Duration::from_millis(ttl_ms);
+            ttls = match namespace.trim() {
+                "*" => ttls.with_default(ttl),
+                namespace => ttls.with_ttl(namespace, ttl),
+            };
+        }
+        Ok(ttls)
+    }
+
+    /// TTL for namespaces without their own
+    pub fn with_default(mut self, ttl: Duration) -> Self {
+        self.default = Some(ttl);
+        self
+    }
+
+    pub fn with_ttl(mut self, namespace: &str, ttl: Duration) -> Self {
+        self.namespaces.insert(namespace.to_string(), ttl);
+        self
+    }
+
+    /// TTL of a write to `namespace` that doesn't set one, `None` if it
+    /// doesn't expire
+    pub fn get(&self, namespace: &str) -> Option<Duration> {
+        self.namespaces.get(namespace).copied().or(self.default).filter(|ttl| !ttl.is_zero())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.namespaces.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let ttls = DefaultTtls::parse("*=60000; scratch=1000; durable=0").unwrap();
+        assert_eq!(ttls.get("scratch"), Some(Duration::from_secs(1)));
+        assert_eq!(ttls.get("other"), Some(Duration::from_secs(60)));
+        assert_eq!(ttls.get("durable"), None);
+
+        let ttls = DefaultTtls::parse("scratch=1000").unwrap();
+        assert_eq!(ttls.get("other"), None);
+        assert!(DefaultTtls::parse("").unwrap().is_empty());
+
+        for bad in ["scratch", "scratch=soon", "scratch=-1"] {
+            assert!(DefaultTtls::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
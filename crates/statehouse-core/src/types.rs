@@ -1,6 +1,7 @@
 // Core types for Statehouse
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Namespace for logical isolation
 pub type Namespace = String;
@@ -14,12 +15,33 @@ pub type Key = String;
 /// Transaction ID
 pub type TxnId = String;
 
+/// Savepoint within a transaction, unique to it
+pub type SavepointId = u64;
+
 /// Version counter
 pub type Version = u64;
 
 /// Commit timestamp (logical)
 pub type CommitTs = u64;
 
+/// Opaque entity tag for a key at `version` with `content` (its value and
+/// labels, `None` if missing or deleted), for clients that validate with
+/// ETags rather than version numbers. Quoted, as in an HTTP `ETag` header.
+/// Versions start over when a key is purged, so the content is hashed in
+/// too: a key recreated after a purge doesn't take the old key's ETags.
+pub fn etag(version: Version, content: Option<(&serde_json::Value, &Labels)>) -> String {
+    match content {
+        Some(content) => {
+            let hash = blake3::hash(&serde_json::to_vec(&content).unwrap_or_default());
+            format!("\"{}-{}\"", version, &hash.to_hex()[..16])
+        }
+        None => format!("\"{}\"", version),
+    }
+}
+
+/// Small name/value labels attached to a key's value, e.g. `source=llm`
+pub type Labels = BTreeMap<String, String>;
+
 /// Record identity tuple
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct RecordId {
@@ -37,3 +59,15 @@ impl RecordId {
         }
     }
 }
+
+/// How durable a commit must be before it is acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Durability {
+    /// Flushed to disk before the commit returns
+    Fsync,
+    /// Written before the commit returns, flushed by a later background
+    /// batch. A crash before that flush may lose it.
+    Async,
+    /// Never written to disk; only in-memory storage supports it
+    Memory,
+}
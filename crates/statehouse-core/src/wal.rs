@@ -0,0 +1,956 @@
+// Write-ahead log decorator for Storage
+//
+// A commit is durable once its frame is appended to the WAL with a single
+// sequential fsync. A background thread then applies it to the wrapped
+// storage; reads wait for that to catch up so callers still read their writes.
+// The applied position is checkpointed every `checkpoint_interval`, so a
+// restart only replays the frames applied since then, plus any never applied.
+// Maintenance that changes the inner storage directly (purges, renames,
+// compaction, restores) first checkpoints everything, so no replayed frame
+// can undo it.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::deadline::Deadline;
+use crate::storage::{CompactionStats, EventLogEntry, NamespaceUsage, PurgeStats, RenameStats, Snapshot, StateMeta, StateRecord, Storage};
+use crate::types::*;
+
+/// File holding the newest commit_ts known to be applied and flushed to the inner storage
+const CHECKPOINT_FILE: &str = "applied";
+
+/// Frame header: payload length (u32 LE) followed by its checksum (u64 LE)
+const FRAME_HEADER_LEN: usize = 12;
+
+/// WAL configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalConfig {
+    /// Directory holding WAL segments and the apply checkpoint
+    pub dir: PathBuf,
+    /// Size at which the current segment is closed and a new one started (bytes)
+    pub segment_bytes: u64,
+    /// Longest an applied frame goes without being checkpointed. Each
+    /// checkpoint flushes the inner storage.
+    pub checkpoint_interval: Duration,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./data/wal"),
+            segment_bytes: 64 * 1024 * 1024, // 64MB
+            checkpoint_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// One committed transaction as written to the WAL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WalFrame {
+    records: Vec<StateRecord>,
+    event: EventLogEntry,
+}
+
+/// Segment files: the open one being appended to, plus closed ones not yet pruned
+struct WalLog {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    seq: u64,
+    /// Newest commit_ts in the open segment
+    max_ts: CommitTs,
+    /// Closed segments and the newest commit_ts each contains
+    closed: Vec<(PathBuf, CommitTs)>,
+    /// Newest commit_ts checkpointed, and when
+    checkpoint_ts: CommitTs,
+    checkpointed_at: Instant,
+    /// A failed append left bytes past `size` that couldn't be truncated;
+    /// appends are refused until they are
+    torn: bool,
+    /// Write only this many bytes of the next frame, then fail
+    #[cfg(test)]
+    fail_after: Option<usize>,
+}
+
+/// Progress of the background applier
+#[derive(Default)]
+struct ApplyState {
+    pending: VecDeque<WalFrame>,
+    /// Frames appended to the WAL since open
+    durable: u64,
+    /// Frames applied to the inner storage since open
+    applied: u64,
+    applied_ts: CommitTs,
+    shutdown: bool,
+    failed: Option<String>,
+}
+
+struct Shared {
+    inner: Arc<dyn Storage>,
+    config: WalConfig,
+    log: Mutex<WalLog>,
+    apply: Mutex<ApplyState>,
+    cond: Condvar,
+}
+
+/// `Storage` decorator that makes commits durable in a segmented WAL and
+/// applies them to the inner storage in the background.
+///
+/// `write_state` calls are staged and written out together with the next
+/// `append_event`, which is how `StateMachine::commit` drives the trait.
+/// The inner storage must be persistent: on open, WAL frames newer than the
+/// apply checkpoint are replayed into it.
+pub struct WalStorage {
+    shared: Arc<Shared>,
+    staged: Mutex<Vec<StateRecord>>,
+    /// Held shared by appends and exclusively by `bypass`, so no frame lands
+    /// while the inner storage is changed directly
+    appends: RwLock<()>,
+    applier: Option<JoinHandle<()>>,
+}
+
+impl WalStorage {
+    /// Open the WAL in `config.dir`, replay any un-applied tail into `inner`,
+    /// and start the background applier
+    pub fn open(inner: Arc<dyn Storage>, config: WalConfig) -> Result<Self> {
+        let mut wal = Self::recover(inner, config)?;
+        let shared = wal.shared.clone();
+        wal.applier = Some(std::thread::spawn(move || run_applier(shared)));
+        Ok(wal)
+    }
+
+    /// Replay un-applied frames and start a fresh segment, without an applier
+    fn recover(inner: Arc<dyn Storage>, config: WalConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let checkpoint = read_checkpoint(&config.dir)?;
+        let segments = list_segments(&config.dir)?;
+
+        let mut replayed = 0;
+        let mut max_ts = checkpoint;
+        for (_, path) in &segments {
+            for frame in read_frames(path)? {
+                let commit_ts = frame.event.commit_ts;
+                for (namespace, namespace_ts) in &frame.event.namespace_ts {
+                    inner.advance_namespace_ts(namespace, *namespace_ts)?;
+                }
+                if commit_ts > checkpoint {
+                    apply_frame(&*inner, frame)?;
+                    replayed += 1;
+                }
+                max_ts = max_ts.max(commit_ts);
+            }
+        }
+
+        // Everything in the old segments is now in the inner storage
+        inner.advance_commit_ts(max_ts)?;
+        inner.flush()?;
+        write_checkpoint(&config.dir, max_ts)?;
+        for (_, path) in &segments {
+            fs::remove_file(path)?;
+        }
+        if replayed > 0 {
+            info!(replayed = replayed, applied_ts = max_ts, "Replayed WAL tail into storage");
+        }
+
+        let seq = segments.last().map(|(seq, _)| seq + 1).unwrap_or(1);
+        let mut log = WalLog::create(&config.dir, seq, Vec::new())?;
+        log.checkpoint_ts = max_ts;
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                inner,
+                config,
+                log: Mutex::new(log),
+                apply: Mutex::new(ApplyState {
+                    applied_ts: max_ts,
+                    ..Default::default()
+                }),
+                cond: Condvar::new(),
+            }),
+            staged: Mutex::new(Vec::new()),
+            appends: RwLock::new(()),
+            applier: None,
+        })
+    }
+
+    /// Block until every durable commit has been applied to the inner storage
+    fn wait_applied(&self) -> Result<()> {
+        let mut apply = self.shared.apply.lock().unwrap();
+        while apply.applied < apply.durable && apply.failed.is_none() {
+            apply = self.shared.cond.wait(apply).unwrap();
+        }
+        match &apply.failed {
+            Some(e) => Err(anyhow!("WAL apply failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Run `op` directly against the inner storage, outside the WAL. Every
+    /// frame is applied and checkpointed first, so a restart never replays
+    /// one over what `op` did.
+    fn bypass<T>(&self, op: impl FnOnce(&dyn Storage) -> Result<T>) -> Result<T> {
+        let _appends = self.appends.write().unwrap();
+        self.wait_applied()?;
+        let applied_ts = self.shared.apply.lock().unwrap().applied_ts;
+        self.shared.prune(applied_ts, true)?;
+        op(&*self.shared.inner)
+    }
+}
+
+#[cfg(test)]
+impl WalStorage {
+    /// Stop the applier without the shutdown checkpoint, as if the process
+    /// died once every frame was applied
+    fn crash(mut self) {
+        self.shared.apply.lock().unwrap().shutdown = true;
+        self.shared.cond.notify_all();
+        if let Some(applier) = self.applier.take() {
+            let _ = applier.join();
+        }
+    }
+}
+
+impl Drop for WalStorage {
+    fn drop(&mut self) {
+        self.shared.apply.lock().unwrap().shutdown = true;
+        self.shared.cond.notify_all();
+
+        // The applier drains pending frames before exiting
+        if let Some(applier) = self.applier.take() {
+            let _ = applier.join();
+            let applied_ts = self.shared.apply.lock().unwrap().applied_ts;
+            if let Err(e) = self.shared.prune(applied_ts, true) {
+                warn!(error = %e, "Failed to checkpoint WAL on shutdown");
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Checkpoint `applied_ts` and delete closed segments it covers, if
+    /// any can be deleted or the last checkpoint is `checkpoint_interval`
+    /// old. With `force`, the checkpoint is written regardless.
+    fn prune(&self, applied_ts: CommitTs, force: bool) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let prunable = log.closed.iter().take_while(|(_, max_ts)| *max_ts <= applied_ts).count();
+        let due = applied_ts > log.checkpoint_ts && log.checkpointed_at.elapsed() >= self.config.checkpoint_interval;
+        if prunable == 0 && !force && !due {
+            return Ok(());
+        }
+
+        // The checkpoint must not claim more than the inner storage has persisted
+        self.inner.flush()?;
+        write_checkpoint(&self.config.dir, applied_ts)?;
+        log.checkpoint_ts = applied_ts;
+        log.checkpointed_at = Instant::now();
+        for (path, _) in log.closed.drain(..prunable) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn run_applier(shared: Arc<Shared>) {
+    loop {
+        let frame = {
+            let mut apply = shared.apply.lock().unwrap();
+            while apply.pending.is_empty() && !apply.shutdown {
+                let (guard, wait) = shared.cond.wait_timeout(apply, shared.config.checkpoint_interval).unwrap();
+                apply = guard;
+                if wait.timed_out() && apply.pending.is_empty() {
+                    // Idle: checkpoint the last frames applied, if not yet done.
+                    // `prune` takes the log lock, which appends take before this one.
+                    let applied_ts = apply.applied_ts;
+                    drop(apply);
+                    if let Err(e) = shared.prune(applied_ts, false) {
+                        warn!(error = %e, "Failed to checkpoint WAL");
+                    }
+                    apply = shared.apply.lock().unwrap();
+                }
+            }
+            match apply.pending.pop_front() {
+                Some(frame) => frame,
+                None => return,
+            }
+        };
+
+        let commit_ts = frame.event.commit_ts;
+        let result = apply_frame(&*shared.inner, frame);
+
+        {
+            let mut apply = shared.apply.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    apply.applied += 1;
+                    apply.applied_ts = commit_ts;
+                }
+                Err(e) => {
+                    // The frame stays in the WAL and is replayed on restart
+                    error!(commit_ts = commit_ts, error = %e, "Failed to apply WAL frame");
+                    apply.failed = Some(e.to_string());
+                }
+            }
+        }
+        shared.cond.notify_all();
+
+        if let Err(e) = shared.prune(commit_ts, false) {
+            warn!(error = %e, "Failed to prune WAL segments");
+        }
+        if shared.apply.lock().unwrap().failed.is_some() {
+            return;
+        }
+    }
+}
+
+fn apply_frame(inner: &dyn Storage, frame: WalFrame) -> Result<()> {
+    for record in frame.records {
+        inner.write_state(record)?;
+    }
+    inner.append_event(frame.event)
+}
+
+impl WalLog {
+    fn create(dir: &Path, seq: u64, closed: Vec<(PathBuf, CommitTs)>) -> Result<Self> {
+        let path = dir.join(format!("{:020}.wal", seq));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            file,
+            path,
+            size: 0,
+            seq,
+            max_ts: 0,
+            closed,
+            checkpoint_ts: 0,
+            checkpointed_at: Instant::now(),
+            torn: false,
+            #[cfg(test)]
+            fail_after: None,
+        })
+    }
+
+    /// Append one encoded frame and fsync, rotating if the segment is full.
+    /// A failed append is cut back off the segment: `read_frames` stops at
+    /// a torn frame, so any frame appended after one would be lost on restart.
+    fn append(&mut self, bytes: &[u8], commit_ts: CommitTs, segment_bytes: u64) -> Result<()> {
+        if self.torn {
+            self.truncate().map_err(|e| anyhow!("WAL has a torn frame that can't be removed: {}", e))?;
+        }
+        if let Err(e) = self.write_frame(bytes) {
+            if let Err(truncate) = self.truncate() {
+                error!(path = ?self.path, error = %truncate, "Failed to remove torn WAL frame; refusing appends");
+            }
+            return Err(e);
+        }
+        self.size += bytes.len() as u64;
+        self.max_ts = self.max_ts.max(commit_ts);
+
+        if self.size >= segment_bytes {
+            let mut closed = std::mem::take(&mut self.closed);
+            closed.push((self.path.clone(), self.max_ts));
+            let dir = self.path.parent().map(Path::to_path_buf).unwrap_or_default();
+            let (checkpoint_ts, checkpointed_at) = (self.checkpoint_ts, self.checkpointed_at);
+            *self = Self::create(&dir, self.seq + 1, closed)?;
+            self.checkpoint_ts = checkpoint_ts;
+            self.checkpointed_at = checkpointed_at;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<()> {
+        #[cfg(test)]
+        if let Some(written) = self.fail_after.take() {
+            self.file.write_all(&bytes[..written])?;
+            return Err(anyhow!("Injected WAL write failure"));
+        }
+        self.file.write_all(bytes)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Cut the segment back to the frames appended whole
+    fn truncate(&mut self) -> Result<()> {
+        self.torn = true;
+        self.file.set_len(self.size)?;
+        self.file.sync_data()?;
+        self.torn = false;
+        Ok(())
+    }
+}
+
+/// FNV-1a, enough to detect a torn or corrupted frame
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    extend_checksum(0xcbf29ce484222325, bytes)
+}
+
+/// Continue a `checksum` over more bytes, for data checked in pieces
+pub(crate) fn extend_checksum(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn encode_frame(frame: &WalFrame) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(frame)?;
+    let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Read every intact frame in a segment. A torn frame at the end (a crash
+/// mid-append, never acknowledged) ends the segment.
+fn read_frames(path: &Path) -> Result<Vec<WalFrame>> {
+    let bytes = fs::read(path)?;
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let header = match bytes.get(offset..offset + FRAME_HEADER_LEN) {
+            Some(header) => header,
+            None => break,
+        };
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let expected = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let start = offset + FRAME_HEADER_LEN;
+        let payload = match bytes.get(start..start + len) {
+            Some(payload) if checksum(payload) == expected => payload,
+            _ => break,
+        };
+        frames.push(serde_json::from_slice(payload)?);
+        offset = start + len;
+    }
+
+    if offset < bytes.len() {
+        warn!(path = ?path, offset = offset, "Ignoring torn WAL frame");
+    }
+    Ok(frames)
+}
+
+/// Segment files in `dir`, oldest first
+fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("wal") {
+            continue;
+        }
+        if let Some(seq) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            segments.push((seq, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn read_checkpoint(dir: &Path) -> Result<CommitTs> {
+    match fs::read_to_string(dir.join(CHECKPOINT_FILE)) {
+        Ok(contents) => Ok(contents.trim().parse()?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_checkpoint(dir: &Path, applied_ts: CommitTs) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(applied_ts.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join(CHECKPOINT_FILE))?;
+    Ok(())
+}
+
+impl Storage for WalStorage {
+    fn health_check(&self) -> Result<()> {
+        if let Some(e) = &self.shared.apply.lock().unwrap().failed {
+            return Err(anyhow!("WAL apply failed: {}", e));
+        }
+        self.shared.inner.health_check()
+    }
+
+    fn write_state(&self, record: StateRecord) -> Result<()> {
+        self.staged.lock().unwrap().push(record);
+        Ok(())
+    }
+
+    fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.read_state(record_id)
+    }
+
+    fn exists(&self, record_id: &RecordId) -> Result<bool> {
+        self.wait_applied()?;
+        self.shared.inner.exists(record_id)
+    }
+
+    fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> {
+        self.wait_applied()?;
+        self.shared.inner.read_state_meta(record_id)
+    }
+
+    fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> {
+        self.wait_applied()?;
+        self.shared.inner.read_states(record_ids)
+    }
+
+    fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> {
+        self.wait_applied()?;
+        self.shared.inner.version_bounds(record_id)
+    }
+
+    fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.read_state_at_version(record_id, version)
+    }
+
+    fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.read_version_history(record_id, limit)
+    }
+
+    fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.read_state_as_of(record_id, as_of)
+    }
+
+    fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> {
+        self.wait_applied()?;
+        self.shared.inner.list_keys(namespace, agent_id, include_deleted, deadline)
+    }
+
+    fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> {
+        self.wait_applied()?;
+        self.shared.inner.count_keys(namespace, agent_id, prefix)
+    }
+
+    fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.scan_prefix(namespace, agent_id, prefix, include_deleted, deadline)
+    }
+
+    fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.scan_prefix_consistent(namespace, agent_id, prefix, include_deleted, deadline)
+    }
+
+    fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.scan_prefix_page(namespace, agent_id, prefix, include_deleted, start_after, limit)
+    }
+
+    fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.scan_namespace_prefix(namespace, prefix, limit)
+    }
+
+    /// The commit point: the staged records and the event are written as one
+    /// frame and fsynced before returning
+    fn append_event(&self, event: EventLogEntry) -> Result<()> {
+        let records = std::mem::take(&mut *self.staged.lock().unwrap());
+        let commit_ts = event.commit_ts;
+        let frame = WalFrame { records, event };
+        let bytes = encode_frame(&frame)?;
+
+        // Holding the log lock while queueing keeps apply order equal to WAL order
+        let _appends = self.appends.read().unwrap();
+        let mut log = self.shared.log.lock().unwrap();
+        log.append(&bytes, commit_ts, self.shared.config.segment_bytes)?;
+        {
+            let mut apply = self.shared.apply.lock().unwrap();
+            apply.pending.push_back(frame);
+            apply.durable += 1;
+        }
+        drop(log);
+        self.shared.cond.notify_all();
+        Ok(())
+    }
+
+    fn last_event_ts(&self) -> Result<Option<CommitTs>> {
+        self.wait_applied()?;
+        self.shared.inner.last_event_ts()
+    }
+
+    fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> {
+        self.wait_applied()?;
+        self.shared.inner.read_event(commit_ts)
+    }
+
+    fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> {
+        self.wait_applied()?;
+        self.shared.inner.read_events(from_ts, limit)
+    }
+
+    fn count_events(&self, from_ts: CommitTs) -> Result<u64> {
+        self.wait_applied()?;
+        self.shared.inner.count_events(from_ts)
+    }
+
+    fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> {
+        self.wait_applied()?;
+        self.shared.inner.replay_events(namespace, agent_id, start_ts, end_ts, deadline)
+    }
+
+    fn next_commit_ts(&self) -> Result<CommitTs> {
+        self.shared.inner.next_commit_ts()
+    }
+
+    fn current_commit_ts(&self) -> Result<CommitTs> {
+        self.shared.inner.current_commit_ts()
+    }
+
+    fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> {
+        self.shared.inner.advance_commit_ts(commit_ts)
+    }
+
+    fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> {
+        self.shared.inner.reserve_commit_ts_block(n)
+    }
+
+    fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> {
+        self.shared.inner.next_namespace_ts(namespace)
+    }
+
+    fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> {
+        self.shared.inner.advance_namespace_ts(namespace, namespace_ts)
+    }
+
+    fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> {
+        self.shared.inner.current_namespace_ts(namespace)
+    }
+
+    /// Commits are already durable in the WAL; the inner storage is flushed
+    /// by the applier when segments are checkpointed
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_durability(&self, durability: Durability) -> bool {
+        durability != Durability::Memory
+    }
+
+    /// Every frame is fsynced by `append_event`, so `Async` commits get
+    /// `Fsync` durability too
+    fn sync_commit(&self, _durability: Option<Durability>) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush_deferred(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_snapshot(&self) -> Result<Snapshot> {
+        self.wait_applied()?;
+        self.shared.inner.create_snapshot()
+    }
+
+    fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> {
+        self.wait_applied()?;
+        self.shared.inner.create_snapshot_at(snapshot_ts)
+    }
+
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        self.shared.inner.save_snapshot(snapshot)
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Snapshot>> {
+        self.shared.inner.load_snapshot()
+    }
+
+    fn get_all_state(&self) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.get_all_state()
+    }
+
+    fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> {
+        self.wait_applied()?;
+        self.shared.inner.create_snapshot_for_namespace(namespace)
+    }
+
+    fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()> {
+        self.shared.inner.save_snapshot_for_namespace(snapshot)
+    }
+
+    fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>> {
+        self.shared.inner.load_snapshot_for_namespace(namespace)
+    }
+
+    fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        self.bypass(|inner| inner.restore_namespace_snapshot(snapshot))
+    }
+
+    fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> {
+        self.bypass(|inner| inner.compact_history(up_to_ts, keep_versions))
+    }
+
+    fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> {
+        self.bypass(|inner| inner.trim_events(up_to_ts))
+    }
+
+    fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> {
+        self.wait_applied()?;
+        self.shared.inner.list_tombstones(up_to_ts)
+    }
+
+    fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> {
+        self.bypass(|inner| inner.purge_tombstones(tombstones))
+    }
+
+    fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> {
+        self.wait_applied()?;
+        self.shared.inner.list_expired(now_ms)
+    }
+
+    /// Segments not yet pruned still hold the key's values on disk until
+    /// they are deleted, but the checkpoint keeps them from being replayed
+    fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> {
+        self.bypass(|inner| inner.purge_key(record_id))
+    }
+
+    fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> {
+        self.bypass(|inner| inner.rename_namespace(from, to, merge))
+    }
+
+    fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> {
+        self.wait_applied()?;
+        self.shared.inner.namespace_usage(namespace)
+    }
+
+    fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> {
+        self.wait_applied()?;
+        self.shared.inner.least_recent_keys(namespace, limit)
+    }
+
+    fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>> {
+        self.wait_applied()?;
+        self.shared.inner.version_counts(namespace, deadline)
+    }
+
+    fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> {
+        self.wait_applied()?;
+        self.shared.inner.agent_last_commit_ts(namespace, agent_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_machine::StateMachine;
+    use crate::storage::{RocksStorage, StorageConfig};
+    use tempfile::TempDir;
+
+    fn storage_config(dir: &TempDir) -> StorageConfig {
+        StorageConfig {
+            data_dir: dir.path().join("data"),
+            fsync_on_commit: false,
+            snapshot_interval: 1000,
+            max_log_size: 1024 * 1024,
+            read_cache_capacity: 0,
+            value_compression_threshold: 0,
+            max_snapshot_shard_bytes: 0,
+            compaction_version_retention: 0,
+            compaction_expire_tombstones: false,
+            value_dedup_threshold: 0,
+        }
+    }
+
+    fn wal_config(dir: &TempDir, segment_bytes: u64) -> WalConfig {
+        WalConfig {
+            dir: dir.path().join("wal"),
+            segment_bytes,
+            ..Default::default()
+        }
+    }
+
+    fn write(sm: &StateMachine, key: &str, value: serde_json::Value) -> CommitTs {
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), value).unwrap();
+        sm.commit(&txn_id).unwrap().commit_ts
+    }
+
+    #[test]
+    fn test_crash_before_apply_recovers_from_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let record_id = RecordId::new("default".to_string(), "agent-1".to_string(), "key1".to_string());
+
+        // Commit with no applier running: durable in the WAL, never applied
+        let commit_ts = {
+            let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+            let wal = WalStorage::recover(inner, wal_config(&temp_dir, 1024 * 1024)).unwrap();
+            let sm = StateMachine::new(Arc::new(wal));
+            write(&sm, "key1", serde_json::json!({"value": 42}))
+        };
+
+        {
+            let inner = RocksStorage::new(storage_config(&temp_dir)).unwrap();
+            assert!(inner.read_state(&record_id).unwrap().is_none());
+        }
+
+        // Reopening replays the WAL tail into RocksDB
+        let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+        let wal = Arc::new(WalStorage::open(inner.clone(), wal_config(&temp_dir, 1024 * 1024)).unwrap());
+        let record = inner.read_state(&record_id).unwrap().unwrap();
+        assert_eq!(record.value.unwrap()["value"], 42);
+        assert_eq!(record.commit_ts, commit_ts);
+        assert_eq!(inner.replay_events("default", "agent-1", None, None, Deadline::NONE).unwrap().len(), 1);
+
+        // New commits continue after the replayed one
+        let sm = StateMachine::new(wal);
+        assert!(write(&sm, "key2", serde_json::json!(1)) > commit_ts);
+        assert_eq!(sm.get_state("default", "agent-1", "key1").unwrap().unwrap().version, 1);
+    }
+
+    #[test]
+    fn test_segments_rotate_and_are_pruned() {
+        let temp_dir = TempDir::new().unwrap();
+
+        {
+            let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+            let wal = WalStorage::open(inner, wal_config(&temp_dir, 512)).unwrap();
+            let sm = StateMachine::new(Arc::new(wal));
+
+            for i in 0..50 {
+                write(&sm, &format!("key{}", i), serde_json::json!({"value": i}));
+            }
+            // Reads see every acknowledged commit
+            for i in 0..50 {
+                let state = sm.get_state("default", "agent-1", &format!("key{}", i)).unwrap().unwrap();
+                assert_eq!(state.value.unwrap()["value"], i);
+            }
+        }
+
+        // Closed segments were pruned once applied; only a later open segment remains
+        let segments = list_segments(&temp_dir.path().join("wal")).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].0 > 1);
+        assert_eq!(read_checkpoint(&temp_dir.path().join("wal")).unwrap(), 50);
+
+        // Nothing left to replay
+        let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+        let sm = StateMachine::new(Arc::new(WalStorage::open(inner, wal_config(&temp_dir, 512)).unwrap()));
+        assert_eq!(sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap().len(), 50);
+    }
+
+    #[test]
+    fn test_applied_frames_are_checkpointed_without_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+        let config = WalConfig { checkpoint_interval: Duration::from_millis(20), ..wal_config(&temp_dir, 1024 * 1024) };
+        let sm = StateMachine::new(Arc::new(WalStorage::open(inner, config).unwrap()));
+
+        let mut last = 0;
+        for i in 0..5 {
+            last = write(&sm, &format!("key{}", i), serde_json::json!(i));
+        }
+        // Once idle, the applier checkpoints what it applied; the segment never filled
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read_checkpoint(&temp_dir.path().join("wal")).unwrap() < last {
+            assert!(Instant::now() < deadline, "applied frames were never checkpointed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(list_segments(&temp_dir.path().join("wal")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_purge_and_rename_survive_a_crash() {
+        let temp_dir = TempDir::new().unwrap();
+        // Only the maintenance itself checkpoints
+        let config = WalConfig { checkpoint_interval: Duration::from_secs(3600), ..wal_config(&temp_dir, 1024 * 1024) };
+
+        {
+            let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+            let wal = Arc::new(WalStorage::open(inner, config.clone()).unwrap());
+            let sm = StateMachine::new(wal.clone());
+            write(&sm, "secret", serde_json::json!("hunter2"));
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "old".to_string(), "agent-1".to_string(), "key1".to_string(), serde_json::json!(1)).unwrap();
+            sm.commit(&txn_id).unwrap();
+
+            sm.purge_key("default", "agent-1", "secret").unwrap();
+            sm.rename_namespace("old", "new", false).unwrap();
+            write(&sm, "after", serde_json::json!(2));
+            drop(sm);
+            Arc::into_inner(wal).unwrap().crash();
+        }
+
+        // The frames from before the purge and rename aren't replayed over them
+        let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+        let sm = StateMachine::new(Arc::new(WalStorage::open(inner, config).unwrap()));
+        assert!(sm.get_state("default", "agent-1", "secret").unwrap().is_none());
+        assert!(sm.get_version_history("default", "agent-1", "secret", 10).unwrap().is_empty());
+        assert!(sm.get_state("old", "agent-1", "key1").unwrap().is_none());
+        assert_eq!(sm.get_state("new", "agent-1", "key1").unwrap().unwrap().value, Some(serde_json::json!(1)));
+        assert_eq!(sm.get_state("default", "agent-1", "after").unwrap().unwrap().value, Some(serde_json::json!(2)));
+    }
+
+    #[test]
+    fn test_failed_append_does_not_hide_later_frames() {
+        let temp_dir = TempDir::new().unwrap();
+        let commit = |wal: &WalStorage, commit_ts: CommitTs| {
+            wal.write_state(StateRecord {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: format!("key{}", commit_ts),
+                value: Some(serde_json::json!(commit_ts)),
+                version: 1,
+                commit_ts,
+                deleted: false,
+                namespace_ts: None,
+                purge_after: None,
+                labels: Default::default(),
+                value_hash: None,
+                expires_at: None,
+            })?;
+            wal.append_event(EventLogEntry {
+                txn_id: format!("txn-{}", commit_ts),
+                commit_ts,
+                operations: Vec::new(),
+                namespace_ts: Default::default(),
+            })
+        };
+
+        // Never applied, so only the WAL holds these
+        {
+            let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+            let wal = WalStorage::recover(inner, wal_config(&temp_dir, 1024 * 1024)).unwrap();
+            commit(&wal, 1).unwrap();
+            wal.shared.log.lock().unwrap().fail_after = Some(20);
+            assert!(commit(&wal, 2).is_err());
+            commit(&wal, 3).unwrap();
+        }
+
+        let inner = Arc::new(RocksStorage::new(storage_config(&temp_dir)).unwrap());
+        let _wal = WalStorage::open(inner.clone(), wal_config(&temp_dir, 1024 * 1024)).unwrap();
+        let key = |key: &str| RecordId::new("default".to_string(), "agent-1".to_string(), key.to_string());
+        assert!(inner.read_state(&key("key1")).unwrap().is_some());
+        assert!(inner.read_state(&key("key2")).unwrap().is_none());
+        assert!(inner.read_state(&key("key3")).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_torn_frame_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("00000000000000000001.wal");
+
+        let frame = WalFrame {
+            records: Vec::new(),
+            event: EventLogEntry {
+                txn_id: "txn-1".to_string(),
+                commit_ts: 1,
+                operations: Vec::new(),
+                namespace_ts: Default::default(),
+            },
+        };
+        let mut bytes = encode_frame(&frame).unwrap();
+        let second = encode_frame(&frame).unwrap();
+        bytes.extend_from_slice(&second[..second.len() - 3]);
+        fs::write(&path, bytes).unwrap();
+
+        assert_eq!(read_frames(&path).unwrap().len(), 1);
+    }
+}
@@ -3,7 +3,7 @@ use std::process::Command;
 fn main() {
     // Get git SHA at build time
     let git_sha = Command::new("git")
-        .args(&["rev-parse", "--short", "HEAD"])
+        .args(["rev-parse", "--short", "HEAD"])
         .output()
         .ok()
         .and_then(|output| {
@@ -0,0 +1,135 @@
+// Audit trail of mutating RPCs
+//
+// Separate from the event log: it records who asked for each change and when,
+// including attempts that were rejected, rather than the resulting values.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::{Request, Status};
+use tracing::warn;
+
+use statehouse_core::{AgentId, CommitTs, Key, Namespace, TxnId, Version};
+
+use crate::service::ADMIN_TOKEN_HEADER;
+
+/// Caller identity, inserted into request extensions by an authenticating
+/// interceptor and copied into audit entries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthIdentity(pub String);
+
+/// Identifies callers presenting the admin token as "admin". Everyone else
+/// stays anonymous; the request is never rejected here.
+#[derive(Clone)]
+pub struct AdminIdentityInterceptor {
+    pub admin_token: Option<String>,
+}
+
+impl tonic::service::Interceptor for AdminIdentityInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(expected) = &self.admin_token {
+            let provided = request.metadata().get(ADMIN_TOKEN_HEADER).and_then(|v| v.to_str().ok());
+            if provided == Some(expected.as_str()) {
+                request.extensions_mut().insert(AuthIdentity("admin".to_string()));
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// One audited RPC
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    /// Wall-clock time, milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Authenticated caller, if an interceptor identified one
+    pub identity: Option<String>,
+    /// RPC name, e.g. "Write" or "Commit"
+    pub operation: &'static str,
+    pub txn_id: Option<TxnId>,
+    /// Records involved, with versions where known
+    pub records: Vec<AuditRecord>,
+    /// Set once the change is committed
+    pub commit_ts: Option<CommitTs>,
+    /// Why the request was rejected; `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// A record touched by an audited RPC. Staged writes only know the version
+/// they were staged against; commits know both.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub namespace: Namespace,
+    pub agent_id: AgentId,
+    pub key: Key,
+    /// Version before the change (0 = absent)
+    pub before_version: Option<Version>,
+    pub after_version: Option<Version>,
+}
+
+impl AuditEntry {
+    pub fn new(identity: Option<String>, operation: &'static str) -> Self {
+        Self {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            identity,
+            operation,
+            txn_id: None,
+            records: Vec::new(),
+            commit_ts: None,
+            error: None,
+        }
+    }
+}
+
+/// Destination for audit entries. Called on the request path, so
+/// implementations should be quick and must not fail the request.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: &AuditEntry);
+}
+
+/// Appends audit entries to a file, one JSON object per line
+pub struct JsonlAuditSink {
+    file: Mutex<File>,
+    /// fsync after every entry, independent of the storage fsync setting
+    sync_each: bool,
+}
+
+impl JsonlAuditSink {
+    pub fn open(path: impl AsRef<Path>, sync_each: bool) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file), sync_each })
+    }
+}
+
+impl AuditSink for JsonlAuditSink {
+    fn record(&self, entry: &AuditEntry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "Failed to encode audit entry");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // One write per entry, so concurrent entries never interleave
+        let mut file = self.file.lock().unwrap();
+        let result = file.write_all(&line).and_then(|_| {
+            if self.sync_each {
+                file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            warn!(error = %e, operation = entry.operation, "Failed to write audit entry");
+        }
+    }
+}
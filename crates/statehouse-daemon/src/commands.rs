@@ -0,0 +1,253 @@
+// Offline maintenance subcommands
+// These open the data directory directly, so the daemon must not be running.
+
+use anyhow::{anyhow, bail, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::Arc;
+use tracing::{error, info};
+
+use statehouse_core::state_machine::{MaintenanceOpts, StateMachine};
+use statehouse_core::storage::{JsonlSnapshotReader, RocksStorage, Snapshot, Storage, StorageConfig, SNAPSHOT_VERSION};
+use statehouse_core::wal::{WalConfig, WalStorage};
+
+const USAGE: &str = "Usage: statehoused <export|import> [--format json|jsonl] [--allow-regression] [--import-commit-ts] <data_dir> <path>\n       statehoused verify <data_dir>\n       statehoused repair <data_dir>\n       statehoused maintain <data_dir> [--keep-versions N] [--no-snapshot] [--no-version-gc] [--no-tombstone-gc] [--no-trim-log] [--no-verify]";
+
+/// On-disk snapshot format for export/import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Single pretty-printed JSON object (same as `snapshot.json`)
+    Json,
+    /// `SnapshotMetadata` header line followed by one record per line
+    Jsonl,
+}
+
+/// Run a subcommand given the command-line arguments after the program name
+pub fn run(args: &[String]) -> Result<()> {
+    let (command, rest) = args.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    if command == "verify" {
+        let data_dir = match rest {
+            [data_dir] => data_dir,
+            _ => bail!("Expected one data directory\n{}", USAGE),
+        };
+        return verify(&StateMachine::new(open(data_dir)?));
+    }
+    if command == "repair" {
+        let data_dir = match rest {
+            [data_dir] => data_dir,
+            _ => bail!("Expected one data directory\n{}", USAGE),
+        };
+        let config = StorageConfig { data_dir: data_dir.into(), ..StorageConfig::default() };
+        RocksStorage::repair(&config)?;
+        // Open once to check the repaired directory is usable
+        return verify(&StateMachine::new(open(data_dir)?));
+    }
+    if command == "maintain" {
+        return maintain(rest);
+    }
+    if command != "export" && command != "import" {
+        bail!("Unknown command: {}\n{}", command, USAGE);
+    }
+
+    let mut format = Format::Json;
+    let mut allow_regression = false;
+    let mut import_commit_ts = false;
+    let mut data_dir = None;
+    let mut path = None;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match rest.next().map(String::as_str) {
+                    Some("json") => Format::Json,
+                    Some("jsonl") => Format::Jsonl,
+                    other => bail!("Unknown format: {:?}\n{}", other, USAGE),
+                }
+            }
+            "--allow-regression" => allow_regression = true,
+            "--import-commit-ts" if command == "import" => import_commit_ts = true,
+            _ if data_dir.is_none() => data_dir = Some(arg.clone()),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => bail!("Unexpected argument: {}\n{}", arg, USAGE),
+        }
+    }
+    let (Some(data_dir), Some(path)) = (data_dir, path) else {
+        bail!("Expected a data directory and a path\n{}", USAGE);
+    };
+
+    let storage = open(&data_dir)?;
+    if import_commit_ts {
+        return import_with_commit_ts(storage, &path, format);
+    }
+    if command == "export" {
+        export(&storage, &path, format)
+    } else {
+        import(&storage, &path, format, allow_regression)
+    }
+}
+
+/// Open the RocksDB in `data_dir`. With STATEHOUSE_WAL set, as for the
+/// daemon, the write-ahead log is replayed into it and checkpointed first,
+/// so the command sees every acknowledged commit and what it writes isn't
+/// overwritten by frames applied at the next start.
+fn open(data_dir: &str) -> Result<Arc<RocksStorage>> {
+    let config = StorageConfig { data_dir: data_dir.into(), ..StorageConfig::default() };
+    let wal_dir = config.data_dir.join("wal");
+    let rocks = Arc::new(RocksStorage::new(config)?);
+    if std::env::var("STATEHOUSE_WAL").is_ok() {
+        // Closing the log applies every frame and checkpoints it
+        drop(WalStorage::open(rocks.clone(), WalConfig { dir: wal_dir, ..Default::default() })?);
+    }
+    Ok(rocks)
+}
+
+fn export(storage: &RocksStorage, path: &str, format: Format) -> Result<()> {
+    let snapshot = storage.create_snapshot()?;
+    let writer = BufWriter::new(File::create(path)?);
+
+    match format {
+        Format::Json => serde_json::to_writer_pretty(writer, &snapshot)?,
+        Format::Jsonl => snapshot.export_jsonl(writer)?,
+    }
+
+    info!(
+        path = %path,
+        records = snapshot.metadata.record_count,
+        snapshot_ts = snapshot.metadata.snapshot_ts,
+        "Export complete"
+    );
+    Ok(())
+}
+
+/// Restore a snapshot file. Refuses a snapshot older than the data directory
+/// unless `allow_regression` is set.
+fn import(storage: &RocksStorage, path: &str, format: Format, allow_regression: bool) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let metadata = match format {
+        Format::Json => {
+            let snapshot: Snapshot = serde_json::from_reader(reader)?;
+            if snapshot.metadata.version != SNAPSHOT_VERSION {
+                bail!(
+                    "Snapshot version mismatch: expected {}, got {}",
+                    SNAPSHOT_VERSION,
+                    snapshot.metadata.version
+                );
+            }
+            storage.restore_from_snapshot(&snapshot, allow_regression)?;
+            snapshot.metadata
+        }
+        Format::Jsonl => storage.import_jsonl(reader, allow_regression)?,
+    };
+
+    info!(
+        path = %path,
+        records = metadata.record_count,
+        snapshot_ts = metadata.snapshot_ts,
+        "Import complete"
+    );
+    Ok(())
+}
+
+/// Import a snapshot file's records with their own commit_ts and version
+/// (see `StateMachine::import_records`) rather than restoring it as of its
+/// snapshot_ts, adding them to the event log and leaving the commit counter
+/// at the highest commit_ts imported
+fn import_with_commit_ts(storage: Arc<RocksStorage>, path: &str, format: Format) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    let records = match format {
+        Format::Json => {
+            let snapshot: Snapshot = serde_json::from_reader(reader)?;
+            if snapshot.metadata.version != SNAPSHOT_VERSION {
+                bail!(
+                    "Snapshot version mismatch: expected {}, got {}",
+                    SNAPSHOT_VERSION,
+                    snapshot.metadata.version
+                );
+            }
+            snapshot.records
+        }
+        Format::Jsonl => JsonlSnapshotReader::new(reader)?.collect::<Result<_>>()?,
+    };
+
+    let stats = StateMachine::new(storage).import_records(records)?;
+
+    info!(
+        path = %path,
+        imported = stats.records_imported,
+        skipped = stats.records_skipped,
+        max_commit_ts = stats.max_commit_ts,
+        "Import complete"
+    );
+    Ok(())
+}
+
+/// Run the maintenance pipeline on a data directory, failing if the
+/// consistency check finds violations
+fn maintain(args: &[String]) -> Result<()> {
+    let mut opts = MaintenanceOpts::default();
+    let mut data_dir = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keep-versions" => {
+                opts.keep_versions = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => n,
+                    _ => bail!("--keep-versions expects a number\n{}", USAGE),
+                }
+            }
+            "--no-snapshot" => opts.snapshot = false,
+            "--no-version-gc" => opts.version_gc = false,
+            "--no-tombstone-gc" => opts.tombstone_gc = false,
+            "--no-trim-log" => opts.trim_log = false,
+            "--no-verify" => opts.consistency_check = false,
+            _ if data_dir.is_none() => data_dir = Some(arg.clone()),
+            _ => bail!("Unexpected argument: {}\n{}", arg, USAGE),
+        }
+    }
+    let data_dir = data_dir.ok_or_else(|| anyhow!("Expected a data directory\n{}", USAGE))?;
+    if opts.trim_log && !opts.snapshot {
+        bail!("--no-snapshot requires --no-trim-log: the log is only trimmed up to a fresh snapshot");
+    }
+
+    let state_machine = StateMachine::new(open(&data_dir)?);
+    let report = state_machine.run_maintenance(&opts)?;
+
+    let totals = report.totals();
+    info!(
+        snapshot_ts = report.snapshot.as_ref().map(|m| m.snapshot_ts),
+        versions_removed = totals.versions_removed,
+        tombstones_removed = totals.tombstones_removed,
+        events_removed = totals.events_removed,
+        bytes_reclaimed = totals.bytes_reclaimed,
+        "Maintenance complete"
+    );
+    if let Some(consistency) = &report.consistency {
+        for violation in &consistency.violations {
+            error!("Consistency violation: {}", violation);
+        }
+        if !consistency.is_consistent() {
+            bail!("Consistency check found {} violation(s)", consistency.violations.len());
+        }
+    }
+    Ok(())
+}
+
+/// Run the state machine's consistency check, logging each violation and
+/// failing if there are any
+pub fn verify(state_machine: &StateMachine) -> Result<()> {
+    let report = state_machine.consistency_check()?;
+    for violation in &report.violations {
+        error!("Consistency violation: {}", violation);
+    }
+    if !report.is_consistent() {
+        bail!("Consistency check found {} violation(s)", report.violations.len());
+    }
+
+    info!(
+        records = report.records_checked,
+        counters = report.counters_checked,
+        "Data directory is consistent"
+    );
+    Ok(())
+}
@@ -0,0 +1,138 @@
+// Read-only mode while the data disk is low on space
+//
+// RocksDB fails writes part-way through a commit once the disk is full, with
+// errors that say little about the cause. Checking free space periodically and
+// refusing writes below a threshold fails them up front, with a clear status,
+// while reads keep working.
+
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{error, info, warn};
+
+/// Source of the free space figure, injectable for tests
+pub trait FreeSpaceProvider: Send + Sync {
+    /// Bytes available to the daemon on the data filesystem
+    fn free_bytes(&self) -> Result<u64>;
+}
+
+/// Free space of the filesystem holding `path`, as reported by `statvfs`
+pub struct FilesystemFreeSpace {
+    pub path: PathBuf,
+}
+
+impl FreeSpaceProvider for FilesystemFreeSpace {
+    #[cfg(unix)]
+    fn free_bytes(&self) -> Result<u64> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(self.path.as_os_str().as_bytes())?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `path` is NUL-terminated and `stats` is a valid out-pointer
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Blocks available to unprivileged users, not the root reserve
+        #[allow(clippy::unnecessary_cast)] // field widths vary by platform
+        Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+
+    #[cfg(not(unix))]
+    fn free_bytes(&self) -> Result<u64> {
+        anyhow::bail!("Free space checks are only supported on Unix")
+    }
+}
+
+/// Tracks whether the daemon is read-only because free space fell below
+/// `min_free_bytes`. `check` is called periodically; the mode flips back
+/// as soon as a check sees enough space again.
+pub struct DiskGuard {
+    provider: Box<dyn FreeSpaceProvider>,
+    min_free_bytes: u64,
+    read_only: AtomicBool,
+}
+
+impl DiskGuard {
+    pub fn new(provider: Box<dyn FreeSpaceProvider>, min_free_bytes: u64) -> Self {
+        Self {
+            provider,
+            min_free_bytes,
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Sample free space and enter or leave read-only mode. If free space
+    /// can't be read, the current mode is kept. Returns whether the daemon
+    /// is read-only.
+    pub fn check(&self) -> bool {
+        let free_bytes = match self.provider.free_bytes() {
+            Ok(free_bytes) => free_bytes,
+            Err(e) => {
+                warn!(error = %e, "Failed to read free disk space");
+                return self.is_read_only();
+            }
+        };
+
+        let low = free_bytes < self.min_free_bytes;
+        let was_read_only = self.read_only.swap(low, Ordering::Relaxed);
+        if low {
+            // Every check, so the condition stays visible in the logs
+            error!(
+                free_bytes = free_bytes,
+                min_free_bytes = self.min_free_bytes,
+                "Disk space low; rejecting writes until space is freed"
+            );
+        } else if was_read_only {
+            info!(
+                free_bytes = free_bytes,
+                min_free_bytes = self.min_free_bytes,
+                "Disk space recovered; accepting writes again"
+            );
+        }
+        low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    /// Free space set by the test
+    struct FixedFreeSpace(Arc<AtomicU64>);
+
+    impl FreeSpaceProvider for FixedFreeSpace {
+        fn free_bytes(&self) -> Result<u64> {
+            Ok(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn test_read_only_follows_free_space() {
+        let free = Arc::new(AtomicU64::new(10_000));
+        let guard = DiskGuard::new(Box::new(FixedFreeSpace(free.clone())), 1_000);
+        assert!(!guard.check());
+
+        free.store(999, Ordering::Relaxed);
+        assert!(guard.check());
+        assert!(guard.is_read_only());
+
+        // Stays read-only until a check sees space again
+        free.store(5_000, Ordering::Relaxed);
+        assert!(guard.is_read_only());
+        assert!(!guard.check());
+        assert!(!guard.is_read_only());
+
+        #[cfg(unix)]
+        {
+            let filesystem = FilesystemFreeSpace { path: std::env::temp_dir() };
+            filesystem.free_bytes().unwrap();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// In-process latency percentiles
+//
+// Commit and read latencies are recorded into HDR histograms so operators get
+// quick p50/p90/p99 figures from GetLatencyStats without running Prometheus.
+// Each recorder keeps the current window and the one before it and reports
+// over both, so stats always cover at least one full window and old spikes
+// age out.
+
+use hdrhistogram::Histogram;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Length of one latency window
+pub const LATENCY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Latencies above this are recorded as this (one minute, in microseconds)
+const MAX_TRACKED_MICROS: u64 = 60_000_000;
+
+/// Percentiles of the latencies recorded in the last one to two windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Thread-safe latency recorder with a rolling window
+pub struct LatencyRecorder {
+    window: Duration,
+    windows: Mutex<Windows>,
+}
+
+struct Windows {
+    started: Instant,
+    current: Histogram<u64>,
+    previous: Histogram<u64>,
+}
+
+impl LatencyRecorder {
+    pub fn new(window: Duration) -> Self {
+        // Three significant digits: values are reported to within 0.1%
+        let histogram = || Histogram::new_with_bounds(1, MAX_TRACKED_MICROS, 3).expect("valid histogram bounds");
+        Self {
+            window,
+            windows: Mutex::new(Windows {
+                started: Instant::now(),
+                current: histogram(),
+                previous: histogram(),
+            }),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let micros = (latency.as_micros() as u64).clamp(1, MAX_TRACKED_MICROS);
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.window);
+        windows.current.saturating_record(micros);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.window);
+
+        let mut merged = windows.previous.clone();
+        merged.add(&windows.current).expect("histograms share bounds");
+        if merged.is_empty() {
+            return LatencySummary::default();
+        }
+        let at = |quantile: f64| Duration::from_micros(merged.value_at_quantile(quantile));
+        LatencySummary {
+            count: merged.len(),
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: Duration::from_micros(merged.max()),
+        }
+    }
+}
+
+impl Windows {
+    /// Start a new window if the current one has run its length, dropping
+    /// the previous one; if a whole window passed idle, drop both
+    fn rotate(&mut self, window: Duration) {
+        let elapsed = self.started.elapsed();
+        if elapsed < window {
+            return;
+        }
+        if elapsed < window * 2 {
+            std::mem::swap(&mut self.previous, &mut self.current);
+        } else {
+            self.previous.reset();
+        }
+        self.current.reset();
+        self.started = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let recorder = LatencyRecorder::new(LATENCY_WINDOW);
+        assert_eq!(recorder.summary(), LatencySummary::default());
+
+        // 1ms..=100ms, one of each
+        for ms in 1..=100 {
+            recorder.record(Duration::from_millis(ms));
+        }
+        let summary = recorder.summary();
+        assert_eq!(summary.count, 100);
+        let near = |actual: Duration, expected_ms: u64| {
+            let expected = Duration::from_millis(expected_ms);
+            let tolerance = expected / 100;
+            assert!(actual.abs_diff(expected) <= tolerance, "{:?} vs {:?}", actual, expected);
+        };
+        near(summary.p50, 50);
+        near(summary.p90, 90);
+        near(summary.p99, 99);
+        near(summary.max, 100);
+
+        // Oversized latencies are capped rather than dropped
+        recorder.record(Duration::from_secs(3600));
+        assert_eq!(recorder.summary().count, 101);
+        near(recorder.summary().max, 60_000);
+    }
+
+    #[test]
+    fn test_old_windows_age_out() {
+        let recorder = LatencyRecorder::new(Duration::from_millis(100));
+        recorder.record(Duration::from_millis(10));
+
+        // Still reported through the next window
+        std::thread::sleep(Duration::from_millis(120));
+        recorder.record(Duration::from_millis(20));
+        let summary = recorder.summary();
+        assert_eq!(summary.count, 2);
+
+        // Gone once a whole window passes without it
+        std::thread::sleep(Duration::from_millis(220));
+        assert_eq!(recorder.summary().count, 0);
+    }
+}
@@ -0,0 +1,208 @@
+// Listen addresses
+//
+// STATEHOUSE_ADDR is either a TCP `host:port` or `unix:/path/to/socket`. A Unix
+// domain socket suits sidecar deployments: no TCP stack, and access is gated by
+// the socket file's permissions, which are set to owner-only. The socket file
+// is removed again when the server shuts down.
+
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where the gRPC server listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(addr: &str) -> Result<Self> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some("") => bail!("Invalid listen address {:?}: missing socket path", addr),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(addr.parse().with_context(|| format!("Invalid listen address {:?}", addr))?)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::bind_unix;
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{bail, Context, Result};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use tokio::net::UnixListener;
+    use tokio_stream::wrappers::UnixListenerStream;
+    use tracing::{info, warn};
+
+    /// Socket file of a bound listener, removed on drop
+    pub struct SocketFile(PathBuf);
+
+    impl Drop for SocketFile {
+        fn drop(&mut self) {
+            if let Err(e) = std::fs::remove_file(&self.0) {
+                warn!(path = ?self.0, error = %e, "Failed to remove socket file");
+            }
+        }
+    }
+
+    /// Bind a Unix domain socket at `path`, replacing a stale socket file
+    /// left by a previous run. Fails if another server is still listening
+    /// there, or if the path is some other kind of file.
+    ///
+    /// The socket is bound inside an owner-only directory next to `path`
+    /// and only moved into place once it is owner-only itself, so no other
+    /// user can connect in between. (Binding under a tighter umask would do
+    /// too, but the umask is process-wide and would apply to every file the
+    /// server's other threads create meanwhile.)
+    pub fn bind_unix(path: &Path) -> Result<(UnixListenerStream, SocketFile)> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                bail!("{:?} exists and is not a socket", path);
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                bail!("{:?} is in use by another server", path);
+            }
+            info!(path = ?path, "Removing stale socket file");
+            std::fs::remove_file(path).with_context(|| format!("Failed to remove stale socket {:?}", path))?;
+        }
+
+        let file_name = path.file_name().with_context(|| format!("Invalid socket path {:?}", path))?;
+        let staging = path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+        // Left by a run that crashed mid-bind, under the same pid
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging).with_context(|| format!("Failed to remove stale {:?}", staging))?;
+        }
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&staging)
+            .with_context(|| format!("Failed to create {:?}", staging))?;
+        let bound = bind_staged(&staging.join(file_name), path);
+        if let Err(e) = std::fs::remove_dir_all(&staging) {
+            warn!(path = ?staging, error = %e, "Failed to remove socket staging directory");
+        }
+        let listener = bound?;
+        Ok((UnixListenerStream::new(listener), SocketFile(path.to_path_buf())))
+    }
+
+    /// Bind at `staged`, make it owner-only and move it to `path`
+    fn bind_staged(staged: &Path, path: &Path) -> Result<UnixListener> {
+        let listener = UnixListener::bind(staged).with_context(|| format!("Failed to bind {:?}", path))?;
+        std::fs::set_permissions(staged, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set permissions on {:?}", path))?;
+        std::fs::rename(staged, path).with_context(|| format!("Failed to move socket to {:?}", path))?;
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ListenAddr::parse("0.0.0.0:50051").unwrap(), ListenAddr::Tcp("0.0.0.0:50051".parse().unwrap()));
+        assert_eq!(ListenAddr::parse("unix:/run/statehouse.sock").unwrap(), ListenAddr::Unix(PathBuf::from("/run/statehouse.sock")));
+        assert_eq!(ListenAddr::parse("unix:/run/statehouse.sock").unwrap().to_string(), "unix:/run/statehouse.sock");
+        assert!(ListenAddr::parse("unix:").is_err());
+        assert!(ListenAddr::parse("localhost").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_round_trip() {
+        use crate::service::StatehouseServiceImpl;
+        use hyper_util::rt::TokioIo;
+        use statehouse_core::{state_machine::StateMachine, storage::InMemoryStorage};
+        use statehouse_proto::statehouse_service_client::StatehouseServiceClient;
+        use statehouse_proto::statehouse_service_server::StatehouseServiceServer;
+        use statehouse_proto::*;
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::Arc;
+        use tokio::net::UnixStream;
+        use tonic::transport::{Endpoint, Server};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("statehouse.sock");
+
+        // A stale socket file from a crashed run doesn't block startup
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (incoming, socket) = bind_unix(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(bind_unix(&path).is_err(), "a live socket must not be replaced");
+        // Nothing is left of the directory the socket was bound in
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, ["statehouse.sock"]);
+
+        let service = StatehouseServiceImpl::new(Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new()))));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            Server::builder()
+                .add_service(StatehouseServiceServer::new(service))
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = stopped.await;
+                })
+                .await
+                .unwrap();
+            drop(socket);
+        });
+
+        // The URI is required but unused: every connection goes to the socket
+        let connect_path = path.clone();
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(tower::service_fn(move |_| {
+                let path = connect_path.clone();
+                async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?)) }
+            }))
+            .await
+            .unwrap();
+        let mut client = StatehouseServiceClient::new(channel);
+
+        let txn_id = client.begin_transaction(BeginTransactionRequest::default()).await.unwrap().into_inner().txn_id;
+        let mut fields = std::collections::BTreeMap::new();
+        fields.insert("n".to_string(), prost_types::Value { kind: Some(prost_types::value::Kind::NumberValue(1.0)) });
+        client.write(WriteRequest {
+            txn_id: txn_id.clone(),
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "key".to_string(),
+            value: Some(prost_types::Struct { fields }),
+            labels: Default::default(),
+            client_seq: None,
+            if_match_etag: None,
+            ttl_ms: None,
+        }).await.unwrap();
+        client.commit(CommitRequest { txn_id, ..Default::default() }).await.unwrap();
+
+        let state = client.get_state(GetStateRequest {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "key".to_string(),
+            ..Default::default()
+        }).await.unwrap().into_inner();
+        assert!(state.exists);
+        assert_eq!(state.version, 1);
+
+        // Shutting down removes the socket file
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+}
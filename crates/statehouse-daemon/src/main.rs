@@ -1,54 +1,416 @@
 // Statehouse Daemon
 // gRPC server implementation
 
+mod audit;
+mod commands;
+mod disk_guard;
+mod latency;
+mod listener;
+mod logging;
+mod replication;
 mod service;
+mod snapshot_schedule;
+mod streams;
+mod watch;
 
 use anyhow::Result;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 use tonic::transport::Server;
-use tracing::info;
+use tracing::{error, info, warn};
+use tracing_subscriber::util::SubscriberInitExt;
 
 use statehouse_core::{
-    state_machine::StateMachine,
+    admission::{AdmissionLimits, IdleAbort},
+    coalesce::CoalesceRules,
+    commit_queue::CommitOrdering,
+    error::StatehouseError,
+    eviction::EvictionPolicies,
+    quota::NamespaceQuotas,
+    replication::ReplicationSink,
+    state_machine::{ReplayLimits, StateMachine, TransactionLimits},
     storage::{InMemoryStorage, RocksStorage, StorageConfig},
+    ttl::DefaultTtls,
+    wal::{WalConfig, WalStorage},
 };
 use statehouse_proto::statehouse_service_server::StatehouseServiceServer;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+    let log_format = match std::env::var("STATEHOUSE_LOG_FORMAT") {
+        Ok(name) => logging::LogFormat::parse(&name)?,
+        Err(_) => logging::LogFormat::default(),
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    logging::subscriber(log_format, filter, std::io::stdout).init();
+
+    // Offline subcommands (export/import/verify/repair) run against the data directory and exit
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return commands::run(&args);
+    }
 
     // Startup banner
     print_startup_banner();
 
     // Initialize storage
     let use_memory = std::env::var("STATEHOUSE_USE_MEMORY").is_ok();
+    let read_only_replica = std::env::var("STATEHOUSE_READ_ONLY").is_ok();
+    if read_only_replica && use_memory {
+        anyhow::bail!("STATEHOUSE_READ_ONLY needs a RocksDB data directory to follow");
+    }
+    let mut data_dir = None;
+    let mut replica = None;
     let storage: Arc<dyn statehouse_core::storage::Storage> = if use_memory {
         info!("📦 Storage: In-memory (ephemeral)");
         Arc::new(InMemoryStorage::new())
     } else {
-        let config = StorageConfig::default();
+        let mut config = StorageConfig::default();
+        if let Some(capacity) = std::env::var("STATEHOUSE_READ_CACHE_CAPACITY").ok().and_then(|v| v.parse().ok()) {
+            config.read_cache_capacity = capacity;
+        }
+        if let Some(threshold) = std::env::var("STATEHOUSE_VALUE_COMPRESSION_THRESHOLD").ok().and_then(|v| v.parse().ok()) {
+            config.value_compression_threshold = threshold;
+        }
+        if let Some(bytes) = std::env::var("STATEHOUSE_MAX_SNAPSHOT_SHARD_BYTES").ok().and_then(|v| v.parse().ok()) {
+            config.max_snapshot_shard_bytes = bytes;
+        }
+        if let Some(commits) = std::env::var("STATEHOUSE_COMPACTION_VERSION_RETENTION").ok().and_then(|v| v.parse().ok()) {
+            config.compaction_version_retention = commits;
+        }
+        config.compaction_expire_tombstones = std::env::var("STATEHOUSE_COMPACTION_EXPIRE_TOMBSTONES").is_ok();
+        if let Some(threshold) = std::env::var("STATEHOUSE_VALUE_DEDUP_THRESHOLD").ok().and_then(|v| v.parse().ok()) {
+            config.value_dedup_threshold = threshold;
+        }
+        // Fail before opening anything; a replica only reads the primary's directory
+        if !read_only_replica {
+            config.validate()?;
+        }
         info!("📦 Storage: RocksDB");
         info!("📁 Data directory: {:?}", config.data_dir);
-        Arc::new(RocksStorage::new(config)?)
+        let wal_dir = config.data_dir.join("wal");
+        data_dir = Some(config.data_dir.clone());
+        if read_only_replica {
+            // The primary owns the data directory; this process only reads it
+            let secondary_dir = std::env::temp_dir().join(format!("statehouse-replica-{}", std::process::id()));
+            info!("👀 Read-only replica (secondary files in {:?})", secondary_dir);
+            let rocks = Arc::new(RocksStorage::open_read_only(config, &secondary_dir)?);
+            replica = Some(rocks.clone());
+            rocks
+        } else {
+            let rocks = match RocksStorage::new(config.clone()) {
+                Err(e) if std::env::var("STATEHOUSE_AUTO_REPAIR").is_ok() && matches!(e.downcast_ref(), Some(StatehouseError::StorageCorrupt { .. })) => {
+                    error!("🩹 {}", e);
+                    error!("🩹 STATEHOUSE_AUTO_REPAIR is set; repairing RocksDB before starting");
+                    RocksStorage::repair(&config)?;
+                    RocksStorage::new(config)?
+                }
+                result => result?,
+            };
+            let rocks = Arc::new(rocks);
+            if std::env::var("STATEHOUSE_WAL").is_ok() {
+                info!("📝 Write-ahead log: {:?}", wal_dir);
+                Arc::new(WalStorage::open(rocks, WalConfig { dir: wal_dir, ..Default::default() })?)
+            } else {
+                rocks
+            }
+        }
     };
 
     // Initialize state machine
-    let state_machine = Arc::new(StateMachine::new(storage));
+    let mut limits = TransactionLimits::default();
+    if let Some(bytes) = std::env::var("STATEHOUSE_MAX_TXN_BYTES").ok().and_then(|v| v.parse().ok()) {
+        limits.max_transaction_bytes = bytes;
+    }
+    if let Some(ops) = std::env::var("STATEHOUSE_MAX_TXN_OPS").ok().and_then(|v| v.parse().ok()) {
+        limits.max_ops_per_transaction = ops;
+    }
+    if let Some(depth) = std::env::var("STATEHOUSE_MAX_JSON_DEPTH").ok().and_then(|v| v.parse().ok()) {
+        limits.max_json_depth = depth;
+    }
+    let replay_limits = ReplayLimits {
+        max_span: std::env::var("STATEHOUSE_MAX_REPLAY_SPAN").ok().and_then(|v| v.parse().ok()).filter(|&span| span > 0),
+        clamp_unbounded: std::env::var("STATEHOUSE_REPLAY_CLAMP").is_ok(),
+    };
+    let namespace_sequences = std::env::var("STATEHOUSE_NAMESPACE_SEQUENCES").is_ok();
+    if namespace_sequences {
+        info!("🔢 Per-namespace commit sequences enabled");
+    }
+    let quotas = match std::env::var("STATEHOUSE_NAMESPACE_QUOTAS") {
+        Ok(spec) => NamespaceQuotas::parse(&spec)?,
+        Err(_) => NamespaceQuotas::default(),
+    };
+    if !quotas.is_empty() {
+        info!("📏 Namespace quotas enabled");
+    }
+    let eviction = match std::env::var("STATEHOUSE_NAMESPACE_EVICTION") {
+        Ok(spec) => EvictionPolicies::parse(&spec)?,
+        Err(_) => EvictionPolicies::default(),
+    };
+    if !eviction.is_empty() {
+        info!("♻️  Namespace eviction enabled");
+    }
+    let default_ttls = match std::env::var("STATEHOUSE_NAMESPACE_TTL_MS") {
+        Ok(spec) => DefaultTtls::parse(&spec)?,
+        Err(_) => DefaultTtls::default(),
+    };
+    if !default_ttls.is_empty() {
+        info!("⏳ Namespace default TTLs enabled");
+    }
+    let delete_grace = std::env::var("STATEHOUSE_DELETE_GRACE_SECS").ok().and_then(|v| v.parse().ok()).map_or(Duration::ZERO, Duration::from_secs);
+    if !delete_grace.is_zero() {
+        info!("🗑️  Deleted keys recoverable for {:?}", delete_grace);
+    }
+    let commit_ordering = match std::env::var("STATEHOUSE_COMMIT_ORDERING") {
+        Ok(name) => CommitOrdering::parse(&name)?,
+        Err(_) => CommitOrdering::default(),
+    };
+    if commit_ordering != CommitOrdering::Unordered {
+        info!("⚖️  Commit ordering: {:?}", commit_ordering);
+    }
+    let coalesce = match std::env::var("STATEHOUSE_COALESCE_KEYS") {
+        Ok(spec) => CoalesceRules::parse(&spec)?,
+        Err(_) => CoalesceRules::default(),
+    };
+    if !coalesce.is_empty() {
+        info!("🫧 Write coalescing enabled");
+    }
+    let admission = AdmissionLimits {
+        max_staged_bytes: std::env::var("STATEHOUSE_MAX_STAGED_BYTES").ok().and_then(|v| v.parse().ok()).filter(|&bytes| bytes > 0),
+        max_backlog: std::env::var("STATEHOUSE_MAX_COMMIT_BACKLOG_MS").ok().and_then(|v| v.parse().ok()).filter(|&ms| ms > 0).map(Duration::from_millis),
+        abort_idle: std::env::var("STATEHOUSE_ABORT_IDLE_ABOVE_BYTES").ok().and_then(|v| v.parse().ok()).filter(|&bytes| bytes > 0).map(|high_water_bytes| IdleAbort {
+            high_water_bytes,
+            min_idle: Duration::from_millis(std::env::var("STATEHOUSE_ABORT_IDLE_AFTER_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(5000)),
+        }),
+    };
+    if admission.is_enabled() {
+        info!("🚦 Write admission control: {:?}", admission);
+    }
+    let commit_ts_block = std::env::var("STATEHOUSE_COMMIT_TS_BLOCK").ok().and_then(|v| v.parse().ok()).unwrap_or(0);
+    if commit_ts_block > 0 {
+        info!("🎟️  Commit timestamps reserved in blocks of {}", commit_ts_block);
+    }
+    let mut state_machine = StateMachine::new(storage)
+        .with_transaction_limits(limits)
+        .with_replay_limits(replay_limits)
+        .with_namespace_sequences(namespace_sequences)
+        .with_namespace_quotas(quotas)
+        .with_eviction_policies(eviction)
+        .with_default_ttls(default_ttls)
+        .with_delete_grace(delete_grace)
+        .with_commit_ordering(commit_ordering)
+        .with_coalescing(coalesce)
+        .with_admission_limits(admission)
+        .with_commit_ts_blocks(commit_ts_block);
+
+    // Refuse to serve from a data directory whose invariants don't hold
+    if std::env::var("STATEHOUSE_VERIFY_ON_STARTUP").is_ok() {
+        info!("🩺 Verifying data consistency");
+        commands::verify(&state_machine)?;
+    }
+
+    // The admin token also authenticates this node to its standby
+    let admin_token = std::env::var("STATEHOUSE_ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+
+    // Ship committed events to a warm standby
+    let mut replication_sink = None;
+    if let Some(peer) = std::env::var("STATEHOUSE_REPLICA_ADDR").ok().filter(|a| !a.is_empty()) {
+        let queue_capacity = std::env::var("STATEHOUSE_REPLICATION_QUEUE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(10_000);
+        let sink = Arc::new(replication::GrpcReplicationSink::spawn(&peer, admin_token.clone(), queue_capacity)?);
+
+        let metrics = sink.metrics();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                info!(
+                    backlog = metrics.backlog(),
+                    shipped = metrics.shipped.load(Ordering::Relaxed),
+                    dropped = metrics.dropped.load(Ordering::Relaxed),
+                    "Replication status"
+                );
+            }
+        });
+        replication_sink = Some(sink);
+    }
+
+    // Committed events go to Watch streams, and on to the standby if there is one
+    let watch_hub = Arc::new(watch::WatchHub::new(replication_sink.clone().map(|sink| sink as Arc<dyn ReplicationSink>)));
+    state_machine = state_machine.with_replication_sink(watch_hub.clone());
+    let state_machine = Arc::new(state_machine);
+
+    // Rebuild version counters from the snapshot and event log; Health
+    // reports progress and writes are refused until it's done
+    let recovering = state_machine.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = recovering.recover() {
+            error!(error = %e, "Startup recovery failed");
+            std::process::exit(1);
+        }
+    });
+
+    // Reap expired transactions and warn about ones that look leaked
+    let txn_warn_fraction = std::env::var("STATEHOUSE_TXN_WARN_FRACTION")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(0.8);
+    let reaper = state_machine.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            reaper.monitor_transactions(txn_warn_fraction);
+            reaper.cleanup_expired_transactions();
+        }
+    });
+
+    // Delete keys whose TTL has run out. A warm standby should turn this off
+    // (0): the primary's expiries reach it as replicated deletes.
+    let expiry_sweep_ms = std::env::var("STATEHOUSE_EXPIRY_SWEEP_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(1000);
+    if expiry_sweep_ms > 0 && !read_only_replica {
+        let sweeper = state_machine.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(expiry_sweep_ms));
+            loop {
+                interval.tick().await;
+                if !sweeper.recovery_status().is_ready() {
+                    continue;
+                }
+                let sweeper = sweeper.clone();
+                match tokio::task::spawn_blocking(move || sweeper.expire_keys()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => warn!(error = %e, "Failed to delete expired keys"),
+                    Err(e) => warn!(error = %e, "Expiry sweep task failed"),
+                }
+            }
+        });
+    }
+
+    // Flush commits acknowledged with async durability in batches, and
+    // coalesced writes whose window has closed
+    let async_flush_ms = std::env::var("STATEHOUSE_ASYNC_FLUSH_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(100)
+        .max(1);
+    let flusher = state_machine.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(async_flush_ms));
+        loop {
+            interval.tick().await;
+            if let Err(e) = flusher.flush_deferred() {
+                warn!(error = %e, "Failed to flush async commits");
+            }
+            if let Err(e) = flusher.flush_coalesced(false) {
+                warn!(error = %e, "Failed to commit coalesced writes");
+            }
+        }
+    });
+
+    // Follow the primary's commits; reads keep being served from the last
+    // caught-up state meanwhile
+    if let Some(rocks) = replica {
+        let catch_up_ms = std::env::var("STATEHOUSE_CATCH_UP_MS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(1000)
+            .max(1);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(catch_up_ms));
+            loop {
+                interval.tick().await;
+                let rocks = rocks.clone();
+                match tokio::task::spawn_blocking(move || rocks.catch_up_with_primary()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => warn!(error = %e, "Failed to catch up with primary"),
+                    Err(e) => warn!(error = %e, "Catch-up task panicked"),
+                }
+            }
+        });
+    }
+
+    // Snapshot at least every so often, even when idle
+    let snapshot_max_interval_secs = std::env::var("STATEHOUSE_SNAPSHOT_MAX_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    if snapshot_max_interval_secs > 0 && !use_memory && !read_only_replica {
+        let max_interval = Duration::from_secs(snapshot_max_interval_secs);
+        let jitter = std::env::var("STATEHOUSE_SNAPSHOT_JITTER_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map_or(max_interval / 10, Duration::from_secs);
+        info!("📸 Snapshot at least every {:?} (+ up to {:?} jitter)", max_interval, jitter);
+        snapshot_schedule::SnapshotSchedule { max_interval, jitter }.spawn(state_machine.clone());
+    }
 
     // Create gRPC service
-    let service = service::StatehouseServiceImpl::new(state_machine.clone());
+    if admin_token.is_none() {
+        info!("🔒 Admin RPCs disabled (STATEHOUSE_ADMIN_TOKEN not set)");
+    }
+    let slow_op_threshold = std::env::var("STATEHOUSE_SLOW_OP_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(service::DEFAULT_SLOW_OP_THRESHOLD);
+    let max_page_size = std::env::var("STATEHOUSE_MAX_PAGE_SIZE").ok().and_then(|v| v.parse::<usize>().ok()).filter(|&size| size > 0);
+    if let Some(size) = max_page_size {
+        info!("📄 ListKeys/ScanPrefix responses capped at {} entries", size);
+    }
+    let stream_limits = streams::StreamLimits {
+        max_streams: std::env::var("STATEHOUSE_MAX_STREAMS").ok().and_then(|v| v.parse::<usize>().ok()).filter(|&max| max > 0),
+        max_streams_per_client: std::env::var("STATEHOUSE_MAX_STREAMS_PER_CLIENT").ok().and_then(|v| v.parse::<usize>().ok()).filter(|&max| max > 0),
+    };
+    if stream_limits != streams::StreamLimits::default() {
+        info!("🌊 Watch/Replay stream limits: {:?}", stream_limits);
+    }
+    let mut service = service::StatehouseServiceImpl::new(state_machine.clone())
+        .with_admin_token(admin_token.clone())
+        .with_slow_op_threshold(slow_op_threshold)
+        .with_max_page_size(max_page_size)
+        .with_stream_limits(stream_limits)
+        .with_watch_hub(watch_hub)
+        .with_read_only_replica(read_only_replica);
+
+    // Refuse writes while the data disk is nearly full
+    let min_free_disk_mb = std::env::var("STATEHOUSE_MIN_FREE_DISK_MB")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(512);
+    if let Some(path) = data_dir.filter(|_| min_free_disk_mb > 0) {
+        info!("💽 Minimum free disk space: {} MB", min_free_disk_mb);
+        let guard = Arc::new(disk_guard::DiskGuard::new(
+            Box::new(disk_guard::FilesystemFreeSpace { path }),
+            min_free_disk_mb * 1024 * 1024,
+        ));
+        service = service.with_disk_guard(guard.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                guard.check();
+            }
+        });
+    }
+
+    // Append-only audit trail of mutating RPCs
+    if let Some(path) = std::env::var("STATEHOUSE_AUDIT_LOG").ok().filter(|p| !p.is_empty()) {
+        let sync_each = std::env::var("STATEHOUSE_AUDIT_FSYNC").is_ok();
+        info!("🧾 Audit log: {} (fsync: {})", path, sync_each);
+        service = service.with_audit_sink(Arc::new(audit::JsonlAuditSink::open(&path, sync_each)?));
+    }
 
     // Server address
-    let addr = std::env::var("STATEHOUSE_ADDR")
-        .unwrap_or_else(|_| "0.0.0.0:50051".to_string())
-        .parse()?;
+    let addr = listener::ListenAddr::parse(&std::env::var("STATEHOUSE_ADDR").unwrap_or_else(|_| "0.0.0.0:50051".to_string()))?;
 
     info!("✅ Statehouse daemon ready");
     info!("📡 Listening on {}", addr);
@@ -57,10 +419,36 @@ async fn main() -> Result<()> {
     info!("");
 
     // Start gRPC server
-    Server::builder()
-        .add_service(StatehouseServiceServer::new(service))
-        .serve(addr)
-        .await?;
+    let server = Server::builder()
+        .add_service(StatehouseServiceServer::with_interceptor(service, audit::AdminIdentityInterceptor { admin_token }));
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Shutting down");
+    };
+    match addr {
+        listener::ListenAddr::Tcp(addr) => server.serve_with_shutdown(addr, shutdown).await?,
+        #[cfg(unix)]
+        listener::ListenAddr::Unix(path) => {
+            let (incoming, _socket) = listener::bind_unix(&path)?;
+            server.serve_with_incoming_shutdown(incoming, shutdown).await?;
+        }
+        #[cfg(not(unix))]
+        listener::ListenAddr::Unix(_) => anyhow::bail!("Unix domain sockets are not supported on this platform"),
+    }
+
+    // Buffered coalesced writes would otherwise be lost
+    match state_machine.flush_coalesced(true) {
+        Ok(0) => {}
+        Ok(keys) => info!(keys, "Committed coalesced writes"),
+        Err(e) => warn!(error = %e, "Failed to commit coalesced writes"),
+    }
+
+    // Give the standby a chance to catch up before exiting
+    if let Some(sink) = replication_sink {
+        if tokio::time::timeout(Duration::from_secs(5), sink.flush()).await.is_err() {
+            warn!(backlog = sink.metrics().backlog(), "Exiting with unshipped replication events");
+        }
+    }
 
     Ok(())
 }
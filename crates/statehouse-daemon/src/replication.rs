@@ -0,0 +1,307 @@
+// Log shipping to a warm standby over gRPC
+//
+// Committed events are queued on the primary and sent in batches to the
+// standby's ApplyReplicatedEvents RPC. Delivery is asynchronous and lossy
+// under overload; see `statehouse_core::replication`.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Request;
+use tracing::{error, info, warn};
+
+use statehouse_core::replication::ReplicationSink;
+use statehouse_core::storage::{EventLogEntry, OperationRecord};
+use statehouse_proto::statehouse_service_client::StatehouseServiceClient;
+use statehouse_proto::*;
+
+use crate::service::ADMIN_TOKEN_HEADER;
+
+/// Maximum events sent in one ApplyReplicatedEvents call
+const MAX_BATCH_EVENTS: usize = 256;
+
+/// Longest wait between retries while the standby is unreachable
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Shipping counters, readable while the sink runs
+#[derive(Debug, Default)]
+pub struct ReplicationMetrics {
+    /// Events accepted into the queue
+    pub enqueued: AtomicU64,
+    /// Events acknowledged by the standby
+    pub shipped: AtomicU64,
+    /// Events dropped because the queue was full. Any drop means the standby
+    /// has diverged and must be reseeded.
+    pub dropped: AtomicU64,
+}
+
+impl ReplicationMetrics {
+    /// Events queued or in flight but not yet acknowledged
+    pub fn backlog(&self) -> u64 {
+        self.enqueued.load(Ordering::Relaxed).saturating_sub(self.shipped.load(Ordering::Relaxed))
+    }
+}
+
+/// `ReplicationSink` that ships events to a standby daemon
+pub struct GrpcReplicationSink {
+    queue: mpsc::Sender<EventLogEntry>,
+    metrics: Arc<ReplicationMetrics>,
+    /// Count of acknowledged events, for `flush`
+    shipped: watch::Receiver<u64>,
+}
+
+impl GrpcReplicationSink {
+    /// Start shipping to `peer` (e.g. `http://standby:50051`), buffering up to
+    /// `queue_capacity` events. `admin_token` must match the standby's
+    /// STATEHOUSE_ADMIN_TOKEN. Must be called inside a Tokio runtime.
+    pub fn spawn(peer: &str, admin_token: Option<String>, queue_capacity: usize) -> Result<Self> {
+        let channel = Endpoint::from_shared(peer.to_string())?.connect_lazy();
+        let admin_token = admin_token.map(|t| t.parse::<AsciiMetadataValue>()).transpose()?;
+        let (queue, receiver) = mpsc::channel(queue_capacity);
+        let (shipped_tx, shipped) = watch::channel(0);
+        let metrics = Arc::new(ReplicationMetrics::default());
+
+        tokio::spawn(run_shipper(
+            StatehouseServiceClient::new(channel),
+            admin_token,
+            receiver,
+            metrics.clone(),
+            shipped_tx,
+        ));
+
+        info!(peer = %peer, queue_capacity = queue_capacity, "Replication to standby enabled");
+        Ok(Self { queue, metrics, shipped })
+    }
+
+    pub fn metrics(&self) -> Arc<ReplicationMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Wait until every event queued so far has been acknowledged by the standby
+    pub async fn flush(&self) {
+        let target = self.metrics.enqueued.load(Ordering::SeqCst);
+        let mut shipped = self.shipped.clone();
+        let _ = shipped.wait_for(|shipped| *shipped >= target).await;
+    }
+}
+
+impl ReplicationSink for GrpcReplicationSink {
+    fn ship(&self, event: &EventLogEntry) {
+        match self.queue.try_send(event.clone()) {
+            Ok(()) => {
+                self.metrics.enqueued.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                let dropped = self.metrics.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // Log at 1, 2, 4, 8, ... so a sustained overload doesn't flood the log
+                if dropped.is_power_of_two() {
+                    error!(
+                        commit_ts = event.commit_ts,
+                        dropped = dropped,
+                        "Replication queue full; dropping event. The standby must be reseeded"
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn run_shipper(
+    mut client: StatehouseServiceClient<Channel>,
+    admin_token: Option<AsciiMetadataValue>,
+    mut receiver: mpsc::Receiver<EventLogEntry>,
+    metrics: Arc<ReplicationMetrics>,
+    shipped_tx: watch::Sender<u64>,
+) {
+    while let Some(event) = receiver.recv().await {
+        let mut batch = vec![event];
+        while batch.len() < MAX_BATCH_EVENTS {
+            match receiver.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        let events: Vec<ReplicatedEvent> = batch.iter().map(event_to_proto).collect();
+
+        // Retry until the standby takes the batch; the bounded queue turns a
+        // long outage into dropped events rather than unbounded memory
+        let mut backoff = Duration::from_millis(100);
+        loop {
+            let mut request = Request::new(ApplyReplicatedEventsRequest { events: events.clone() });
+            if let Some(token) = &admin_token {
+                request.metadata_mut().insert(ADMIN_TOKEN_HEADER, token.clone());
+            }
+            match client.apply_replicated_events(request).await {
+                Ok(_) => break,
+                Err(status) => {
+                    warn!(error = %status, backlog = metrics.backlog(), "Replication to standby failed; retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+            }
+        }
+
+        let shipped = metrics.shipped.fetch_add(batch.len() as u64, Ordering::SeqCst) + batch.len() as u64;
+        let _ = shipped_tx.send(shipped);
+    }
+}
+
+fn event_to_proto(event: &EventLogEntry) -> ReplicatedEvent {
+    ReplicatedEvent {
+        txn_id: event.txn_id.clone(),
+        commit_ts: event.commit_ts,
+        operations: event.operations.iter().map(|op| ReplicatedOperation {
+            namespace: op.namespace.clone(),
+            agent_id: op.agent_id.clone(),
+            key: op.key.clone(),
+            // Serializing a Value can't fail
+            value_json: op.value.as_ref().map(|v| serde_json::to_vec(v).unwrap_or_default()),
+            version: op.version,
+            labels: op.labels.clone().into_iter().collect(),
+            expires_at_ms: op.expires_at,
+        }).collect(),
+        namespace_ts: event.namespace_ts.clone().into_iter().collect(),
+    }
+}
+
+/// Decode an event received by a standby
+pub fn event_from_proto(event: ReplicatedEvent) -> Result<EventLogEntry> {
+    let operations = event.operations.into_iter().map(|op| {
+        Ok(OperationRecord {
+            namespace: op.namespace,
+            agent_id: op.agent_id,
+            key: op.key,
+            value: op.value_json.map(|v| serde_json::from_slice(&v)).transpose()?,
+            version: op.version,
+            labels: op.labels.into_iter().collect(),
+            expires_at: op.expires_at_ms,
+        })
+    }).collect::<Result<Vec<_>>>()?;
+
+    Ok(EventLogEntry {
+        txn_id: event.txn_id,
+        commit_ts: event.commit_ts,
+        operations,
+        namespace_ts: event.namespace_ts.into_iter().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::StatehouseServiceImpl;
+    use prost_types::value::Kind;
+    use statehouse_core::state_machine::StateMachine;
+    use statehouse_core::storage::InMemoryStorage;
+    use statehouse_proto::statehouse_service_server::StatehouseServiceServer;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::Server;
+
+    /// Serve a daemon on an ephemeral local port and return its URL
+    async fn serve(service: StatehouseServiceImpl) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let accepted = listener.accept().await.map(|(stream, _)| stream);
+                if tx.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(StatehouseServiceServer::new(service))
+                .serve_with_incoming(ReceiverStream::new(rx)),
+        );
+        url
+    }
+
+    #[tokio::test]
+    async fn test_standby_converges_after_flush() {
+        let standby = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let standby_url = serve(
+            StatehouseServiceImpl::new(standby.clone()).with_admin_token(Some("secret".to_string())),
+        ).await;
+
+        let sink = Arc::new(GrpcReplicationSink::spawn(&standby_url, Some("secret".to_string()), 100).unwrap());
+        let primary = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())).with_replication_sink(sink.clone()));
+        let primary_url = serve(StatehouseServiceImpl::new(primary.clone())).await;
+
+        // Commit through the primary's gRPC API
+        let mut client = StatehouseServiceClient::connect(primary_url).await.unwrap();
+        for i in 0..5 {
+            let txn_id = client.begin_transaction(BeginTransactionRequest::default()).await.unwrap().into_inner().txn_id;
+            let value = prost_types::Struct {
+                fields: [("n".to_string(), prost_types::Value { kind: Some(Kind::NumberValue(i as f64)) })].into(),
+            };
+            client.write(WriteRequest {
+                txn_id: txn_id.clone(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: format!("key{}", i % 3),
+                value: Some(value),
+                labels: Default::default(),
+                client_seq: None,
+                if_match_etag: None,
+                ttl_ms: None,
+            }).await.unwrap();
+            client.commit(CommitRequest { txn_id, ..Default::default() }).await.unwrap();
+        }
+        let txn_id = client.begin_transaction(BeginTransactionRequest::default()).await.unwrap().into_inner().txn_id;
+        client.delete(DeleteRequest {
+            txn_id: txn_id.clone(),
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "key0".to_string(),
+            expected_version: None,
+            if_match_etag: None,
+        }).await.unwrap();
+        client.commit(CommitRequest { txn_id, ..Default::default() }).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), sink.flush()).await.unwrap();
+        let metrics = sink.metrics();
+        assert_eq!(metrics.shipped.load(Ordering::SeqCst), 6);
+        assert_eq!(metrics.dropped.load(Ordering::SeqCst), 0);
+        assert_eq!(metrics.backlog(), 0);
+
+        for key in ["key0", "key1", "key2"] {
+            let expected = primary.get_state("default", "agent-1", key).unwrap().unwrap();
+            let actual = standby.get_state("default", "agent-1", key).unwrap().unwrap();
+            assert_eq!(actual.value, expected.value);
+            assert_eq!(actual.version, expected.version);
+            assert_eq!(actual.commit_ts, expected.commit_ts);
+            assert_eq!(actual.deleted, expected.deleted);
+        }
+        assert!(standby.get_state("default", "agent-1", "key0").unwrap().unwrap().deleted);
+        assert_eq!(standby.replay("default", "agent-1", None, None).unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_events() {
+        // Nothing listens here, so the shipper holds its first batch and the queue fills
+        let sink = GrpcReplicationSink::spawn("http://127.0.0.1:1", None, 2).unwrap();
+        let event = EventLogEntry {
+            txn_id: "txn-1".to_string(),
+            commit_ts: 1,
+            operations: Vec::new(),
+            namespace_ts: Default::default(),
+        };
+        for _ in 0..10 {
+            sink.ship(&event);
+        }
+
+        let metrics = sink.metrics();
+        let accepted = metrics.enqueued.load(Ordering::SeqCst);
+        assert!((2..=3).contains(&accepted));
+        assert_eq!(metrics.dropped.load(Ordering::SeqCst), 10 - accepted);
+        assert_eq!(metrics.shipped.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,28 +1,464 @@
 // gRPC service implementation
 
 use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
-use tonic::{Request, Response, Status};
-use tokio_stream::wrappers::ReceiverStream;
+use std::time::{Duration, Instant};
+use tonic::{Request, Response, Status, Streaming};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::{debug, warn};
 
 use statehouse_proto::*;
-use statehouse_core::state_machine::StateMachine;
+use statehouse_proto::stream_transaction_request::Command;
+use statehouse_core::{deadline::Deadline, predicate::{self, ValuePredicate}, projection, schema, state_machine::{self, BatchOp, BatchOpKind, CommitResult, MaintenanceOpts, ReplayFilter, ScanPage, StateMachine}, storage::{OperationRecord, StateMeta, StateRecord}, etag, Labels, RecordId, StatehouseError, TxnId, Version};
 
+use crate::audit::{AuditEntry, AuditRecord, AuditSink, AuthIdentity};
+use crate::disk_guard::DiskGuard;
+use crate::latency::{LatencyRecorder, LatencySummary, LATENCY_WINDOW};
+use crate::streams::{StreamLimiter, StreamLimits};
+use crate::watch::WatchHub;
+
+/// Metadata header carrying the admin token for admin RPCs
+pub(crate) const ADMIN_TOKEN_HEADER: &str = "x-statehouse-admin-token";
+
+/// Trailing metadata on an UNAVAILABLE status from admission control: how
+/// long to wait before retrying, in milliseconds
+pub const RETRY_AFTER_HEADER: &str = "retry-after-ms";
+
+/// Reads slower than this are logged unless overridden with `with_slow_op_threshold`
+pub const DEFAULT_SLOW_OP_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Entries a ScanPrefixStream reads ahead of the client before waiting
+const SCAN_STREAM_BUFFER: usize = 64;
+
+/// Watch events buffered for a client before the stream stops reading commits
+const WATCH_STREAM_BUFFER: usize = 64;
+
+/// Deepest keys GetVersionDepthHistogram lists when the request doesn't say
+const DEFAULT_DEEPEST_KEYS: usize = 10;
+
+/// Keys InferSchema samples when the request doesn't say
+const DEFAULT_SCHEMA_SAMPLE: usize = 100;
+
+#[derive(Clone)]
 pub struct StatehouseServiceImpl {
     state_machine: Arc<StateMachine>,
+    /// Token required by admin RPCs; admin RPCs are disabled when unset
+    admin_token: Option<String>,
+    /// Reads taking longer than this are logged as slow
+    slow_op_threshold: Duration,
+    /// Receives an entry for every mutating RPC, if auditing is enabled
+    audit: Option<Arc<dyn AuditSink>>,
+    /// Puts the server in read-only mode when the data disk is low
+    disk_guard: Option<Arc<DiskGuard>>,
+    /// Source of committed events for Watch; Watch is unavailable when unset
+    watch: Option<Arc<WatchHub>>,
+    /// Serving another daemon's data directory; every write is refused
+    read_only_replica: bool,
+    /// Most keys or entries in one ListKeys or ScanPrefix response; larger
+    /// requests get a cursor to continue from
+    max_page_size: Option<usize>,
+    /// Caps open Watch and Replay streams
+    streams: Arc<StreamLimiter>,
+    commit_latency: Arc<LatencyRecorder>,
+    read_latency: Arc<LatencyRecorder>,
 }
 
 impl StatehouseServiceImpl {
     pub fn new(state_machine: Arc<StateMachine>) -> Self {
-        Self { state_machine }
+        Self {
+            state_machine,
+            admin_token: None,
+            slow_op_threshold: DEFAULT_SLOW_OP_THRESHOLD,
+            audit: None,
+            disk_guard: None,
+            watch: None,
+            read_only_replica: false,
+            max_page_size: None,
+            streams: Arc::new(StreamLimiter::default()),
+            commit_latency: Arc::new(LatencyRecorder::new(LATENCY_WINDOW)),
+            read_latency: Arc::new(LatencyRecorder::new(LATENCY_WINDOW)),
+        }
+    }
+
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    pub fn with_slow_op_threshold(mut self, slow_op_threshold: Duration) -> Self {
+        self.slow_op_threshold = slow_op_threshold;
+        self
+    }
+
+    pub fn with_audit_sink(mut self, audit: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn with_disk_guard(mut self, disk_guard: Arc<DiskGuard>) -> Self {
+        self.disk_guard = Some(disk_guard);
+        self
+    }
+
+    pub fn with_watch_hub(mut self, watch: Arc<WatchHub>) -> Self {
+        self.watch = Some(watch);
+        self
+    }
+
+    pub fn with_read_only_replica(mut self, read_only_replica: bool) -> Self {
+        self.read_only_replica = read_only_replica;
+        self
+    }
+
+    pub fn with_stream_limits(mut self, limits: StreamLimits) -> Self {
+        self.streams = Arc::new(StreamLimiter::new(limits));
+        self
+    }
+
+    pub fn with_max_page_size(mut self, max_page_size: Option<usize>) -> Self {
+        self.max_page_size = max_page_size;
+        self
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk_guard.as_ref().is_some_and(|guard| guard.is_read_only())
+    }
+
+    /// Reject anything that would modify a read-only replica's data
+    #[allow(clippy::result_large_err)]
+    fn check_not_replica(&self) -> Result<(), Status> {
+        if self.read_only_replica {
+            return Err(Status::failed_precondition("Read-only replica: send writes to the primary"));
+        }
+        Ok(())
+    }
+
+    /// Reject writes and commits on a replica, or while the disk guard has
+    /// the server read-only
+    #[allow(clippy::result_large_err)]
+    fn check_writable(&self) -> Result<(), Status> {
+        self.check_not_replica()?;
+        if self.is_read_only() {
+            return Err(Status::resource_exhausted("Disk low: writes are refused until space is freed"));
+        }
+        let recovery = self.state_machine.recovery_status();
+        if !recovery.is_ready() {
+            return Err(Status::unavailable(format!("Recovering ({}): writes are refused until recovery finishes", recovery)));
+        }
+        Ok(())
+    }
+
+    /// Record an audit entry filled in by `fill`, which only runs when
+    /// auditing is enabled
+    fn audit(&self, identity: Option<String>, operation: &'static str, fill: impl FnOnce(&mut AuditEntry)) {
+        if let Some(sink) = &self.audit {
+            let mut entry = AuditEntry::new(identity, operation);
+            fill(&mut entry);
+            sink.record(&entry);
+        }
+    }
+
+    /// Audit a commit attempt with the before/after version of each changed record
+    fn audit_commit(&self, identity: Option<String>, operation: &'static str, txn_id: &str, result: &anyhow::Result<CommitResult>) {
+        self.audit(identity, operation, |entry| {
+            entry.txn_id = Some(txn_id.to_string());
+            match result {
+                Ok(result) => {
+                    entry.commit_ts = Some(result.commit_ts);
+                    entry.records = committed_records(result);
+                }
+                Err(e) => entry.error = Some(e.to_string()),
+            }
+        });
+    }
+
+    /// Convert a value from a request, enforcing the state machine's `max_json_depth`
+    fn request_value(&self, value: Option<prost_types::Struct>) -> Result<serde_json::Value> {
+        let value = value.unwrap_or_default();
+        let converted = prost_types_to_json(&value, self.state_machine.transaction_limits().max_json_depth);
+        if converted.is_err() {
+            drop_flat(value);
+        }
+        converted
+    }
+
+    /// Convert a single request value of any JSON type, enforcing
+    /// `max_json_depth` like `request_value` (the wrapping struct counts as a level)
+    fn request_operand(&self, value: Option<prost_types::Value>) -> Result<serde_json::Value> {
+        let wrapped = prost_types::Struct { fields: value.map(|v| (String::new(), v)).into_iter().collect() };
+        match prost_types_to_json(&wrapped, self.state_machine.transaction_limits().max_json_depth) {
+            Ok(mut converted) => Ok(converted[""].take()),
+            Err(e) => {
+                drop_flat(wrapped);
+                Err(e)
+            }
+        }
+    }
+
+    /// Stage a write, conditional on `if_match_etag` if set and expiring
+    /// after `ttl` if set, and audit it as `operation`. Returns false if
+    /// `client_seq` marked it a retry, which is ignored.
+    #[allow(clippy::too_many_arguments)]
+    fn stage_write(
+        &self,
+        identity: Option<String>,
+        operation: &'static str,
+        txn_id: &str,
+        record_id: RecordId,
+        value: serde_json::Value,
+        labels: Labels,
+        client_seq: Option<u64>,
+        if_match_etag: Option<String>,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        let (namespace, agent_id, key) = (record_id.namespace.clone(), record_id.agent_id.clone(), record_id.key.clone());
+        let result = match (client_seq, if_match_etag, ttl) {
+            (Some(client_seq), _, _) => self.state_machine.write_with_seq(txn_id, namespace, agent_id, key, value, labels, client_seq),
+            (None, Some(etag), _) => self.state_machine.write_if_match(txn_id, namespace, agent_id, key, value, labels, etag).map(|()| true),
+            (None, None, Some(ttl)) => self.state_machine.write_with_ttl(txn_id, namespace, agent_id, key, value, labels, ttl).map(|()| true),
+            (None, None, None) => self.state_machine.write_with_labels(txn_id, namespace, agent_id, key, value, labels).map(|()| true),
+        };
+        self.audit(identity, operation, |entry| {
+            entry.txn_id = Some(txn_id.to_string());
+            entry.records.push(self.staged_record(&record_id.namespace, &record_id.agent_id, &record_id.key));
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        result
+    }
+
+    /// Stage a delete, conditional on `expected_version` or `if_match_etag`
+    /// if set, and audit it as `operation`
+    #[allow(clippy::too_many_arguments)]
+    fn stage_delete(
+        &self,
+        identity: Option<String>,
+        operation: &'static str,
+        txn_id: &str,
+        record_id: RecordId,
+        expected_version: Option<Version>,
+        if_match_etag: Option<String>,
+    ) -> anyhow::Result<()> {
+        let RecordId { namespace, agent_id, key } = record_id;
+        let result = match (expected_version, if_match_etag) {
+            (Some(expected_version), _) => self.state_machine.delete_if_version(
+                txn_id,
+                namespace.clone(),
+                agent_id.clone(),
+                key.clone(),
+                expected_version,
+            ),
+            (None, Some(etag)) => self.state_machine.delete_if_match(txn_id, namespace.clone(), agent_id.clone(), key.clone(), etag),
+            (None, None) => self.state_machine.delete(txn_id, namespace.clone(), agent_id.clone(), key.clone()),
+        };
+        self.audit(identity, operation, |entry| {
+            entry.txn_id = Some(txn_id.to_string());
+            entry.records.push(self.staged_record(&namespace, &agent_id, &key));
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        result
+    }
+
+    /// Audit record for a staged change, with the version it was staged against
+    fn staged_record(&self, namespace: &str, agent_id: &str, key: &str) -> AuditRecord {
+        let before_version = self.state_machine.get_state(namespace, agent_id, key).ok().map(|r| r.map_or(0, |r| r.version));
+        AuditRecord {
+            namespace: namespace.to_string(),
+            agent_id: agent_id.to_string(),
+            key: key.to_string(),
+            before_version,
+            after_version: None,
+        }
+    }
+
+    /// Record the latency of a read that began at `started`, and warn if it
+    /// exceeded the slow-op threshold. `key_or_prefix` is empty for
+    /// whole-agent reads.
+    fn observe_read(&self, op: &str, namespace: &str, agent_id: &str, key_or_prefix: &str, results: usize, started: Instant) {
+        let elapsed = started.elapsed();
+        self.read_latency.record(elapsed);
+        if elapsed > self.slow_op_threshold {
+            warn!(
+                op = op,
+                namespace = %namespace,
+                agent_id = %agent_id,
+                key_or_prefix = %key_or_prefix,
+                results = results,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow read"
+            );
+        }
+    }
+
+    /// Entries to return for a request asking for `limit` (0 for all),
+    /// capped at `max_page_size`; `None` for no limit. Paging clients hit the
+    /// cap on every page, so it is only logged, at debug, when the client
+    /// asked for more than it.
+    fn page_limit(&self, op: &str, limit: u32) -> Option<usize> {
+        let requested = (limit > 0).then_some(limit as usize);
+        match self.max_page_size {
+            Some(max) if requested.is_none_or(|requested| requested > max) => {
+                if requested.is_some() {
+                    debug!(op = op, requested = limit, max_page_size = max, "Clamped page size");
+                }
+                Some(max)
+            }
+            _ => requested,
+        }
+    }
+
+    /// Drive one StreamTransaction stream: apply `inbound` commands in order,
+    /// sending an ack for each to `outbound`. If the stream ends any other way
+    /// than by a commit or abort, including the client disconnecting or a
+    /// command failing, the open transaction is aborted, unless the client
+    /// can pick it up again: it was resumed, or a write in it carried a
+    /// `client_seq`. That one is left open for a resume on a new stream, and
+    /// to expire on its timeout if none comes.
+    async fn run_stream_transaction<S>(self, identity: Option<String>, mut inbound: S, outbound: mpsc::Sender<Result<StreamTransactionResponse, Status>>)
+    where
+        S: Stream<Item = Result<StreamTransactionRequest, Status>> + Unpin,
+    {
+        let mut txn_id: Option<TxnId> = None;
+        let mut seq = 0;
+        let mut resumable = false;
+
+        while let Some(Ok(message)) = inbound.next().await {
+            seq += 1;
+            let resumes = match &message.command {
+                Some(Command::Resume(_)) => true,
+                Some(Command::Write(req)) => req.client_seq.is_some(),
+                _ => false,
+            };
+            match self.stream_command(identity.clone(), &mut txn_id, message.command) {
+                Ok(mut ack) => {
+                    resumable |= resumes;
+                    ack.seq = seq;
+                    if outbound.send(Ok(ack)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(status) => {
+                    let _ = outbound.send(Err(status)).await;
+                    break;
+                }
+            }
+            // Committed or aborted
+            if txn_id.is_none() {
+                break;
+            }
+        }
+
+        match txn_id {
+            Some(txn_id) if resumable => {
+                debug!(txn_id = %txn_id, "Stream ended with its transaction open; leaving it to be resumed");
+            }
+            Some(txn_id) => {
+                debug!(txn_id = %txn_id, "Stream ended with its transaction open; aborting");
+                let _ = self.state_machine.abort(&txn_id);
+            }
+            None => {}
+        }
+    }
+
+    /// Apply one StreamTransaction command. `txn_id` is the stream's open
+    /// transaction: set by begin or resume, cleared by commit or abort.
+    #[allow(clippy::result_large_err)]
+    fn stream_command(&self, identity: Option<String>, txn_id: &mut Option<TxnId>, command: Option<Command>) -> Result<StreamTransactionResponse, Status> {
+        let command = command.ok_or_else(|| Status::invalid_argument("StreamTransaction message has no command"))?;
+        let mut ack = StreamTransactionResponse::default();
+
+        if let Command::Begin(_) | Command::Resume(_) = command {
+            if txn_id.is_some() {
+                return Err(Status::failed_precondition("This stream already has a transaction"));
+            }
+        } else if txn_id.is_none() {
+            return Err(Status::failed_precondition("The first StreamTransaction message must begin or resume a transaction"));
+        }
+
+        match command {
+            Command::Begin(req) => {
+                self.check_writable()?;
+                let begun = self.state_machine.begin_transaction_with_id(req.txn_id, req.timeout_ms)
+                    .map_err(|e| error_to_status("Failed to begin transaction", e))?;
+                *txn_id = Some(begun);
+            }
+            Command::Resume(resumed) => *txn_id = Some(resumed),
+            Command::Write(req) => {
+                self.check_writable()?;
+                let value = self.request_value(req.value).map_err(|e| error_to_status("Write failed", e))?;
+                let record_id = RecordId::new(req.namespace, req.agent_id, req.key);
+                check_etag_alone(&req.if_match_etag, req.client_seq.map(|_| "client_seq"))?;
+                let labels = req.labels.into_iter().collect();
+                let applied = self.stage_write(identity, "StreamWrite", txn_id.as_deref().unwrap_or_default(), record_id, value, labels, req.client_seq, req.if_match_etag, None)
+                    .map_err(|e| error_to_status("Write failed", e))?;
+                ack.duplicate = !applied;
+            }
+            Command::Delete(req) => {
+                self.check_writable()?;
+                check_etag_alone(&req.if_match_etag, req.expected_version.map(|_| "expected_version"))?;
+                let record_id = RecordId::new(req.namespace, req.agent_id, req.key);
+                self.stage_delete(identity, "StreamDelete", txn_id.as_deref().unwrap_or_default(), record_id, req.expected_version, req.if_match_etag)
+                    .map_err(|e| error_to_status("Delete failed", e))?;
+            }
+            Command::Commit(_) => {
+                self.check_writable()?;
+                // A failed commit discards the transaction too
+                let committing = txn_id.take().unwrap_or_default();
+                let started = Instant::now();
+                let result = self.state_machine.commit(&committing);
+                self.commit_latency.record(started.elapsed());
+                self.audit_commit(identity, "StreamCommit", &committing, &result);
+                let result = result.map_err(|e| error_to_status("Commit failed", e))?;
+                ack.txn_id = committing;
+                ack.commit_ts = Some(result.commit_ts);
+                ack.namespace_ts = result.namespace_ts.into_iter().collect();
+                return Ok(ack);
+            }
+            Command::Abort(_) => {
+                let aborting = txn_id.take().unwrap_or_default();
+                self.state_machine.abort(&aborting)
+                    .map_err(|e| Status::internal(format!("Abort failed: {}", e)))?;
+                ack.txn_id = aborting;
+                return Ok(ack);
+            }
+        }
+
+        ack.txn_id = txn_id.clone().unwrap_or_default();
+        Ok(ack)
+    }
+
+    /// Reject the request unless it carries the configured admin token
+    #[allow(clippy::result_large_err)]
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(expected) = &self.admin_token else {
+            return Err(Status::permission_denied("Admin RPCs are disabled; set STATEHOUSE_ADMIN_TOKEN to enable them"));
+        };
+        let provided = request.metadata().get(ADMIN_TOKEN_HEADER).and_then(|v| v.to_str().ok());
+        if provided != Some(expected.as_str()) {
+            return Err(Status::unauthenticated("Missing or invalid admin token"));
+        }
+        Ok(())
     }
 }
 
 #[tonic::async_trait]
 impl statehouse_service_server::StatehouseService for StatehouseServiceImpl {
     async fn health(&self, _request: Request<HealthRequest>) -> Result<Response<HealthResponse>, Status> {
+        let read_only = self.is_read_only();
+        let recovery = self.state_machine.recovery_status();
+        let status = if !recovery.is_ready() {
+            "recovering"
+        } else if read_only {
+            "read_only"
+        } else {
+            "ok"
+        };
         Ok(Response::new(HealthResponse {
-            status: "ok".to_string(),
+            status: status.to_string(),
+            read_only,
+            recovery_status: recovery.to_string(),
         }))
     }
 
@@ -33,51 +469,288 @@ impl statehouse_service_server::StatehouseService for StatehouseServiceImpl {
         }))
     }
 
+    async fn get_latency_stats(&self, _request: Request<GetLatencyStatsRequest>) -> Result<Response<GetLatencyStatsResponse>, Status> {
+        Ok(Response::new(GetLatencyStatsResponse {
+            commit: Some(latency_stats(self.commit_latency.summary())),
+            read: Some(latency_stats(self.read_latency.summary())),
+            window_ms: LATENCY_WINDOW.as_millis() as u64,
+        }))
+    }
+
+    async fn get_backlog(&self, _request: Request<GetBacklogRequest>) -> Result<Response<GetBacklogResponse>, Status> {
+        let backlog = self.state_machine.backlog();
+        let limits = self.state_machine.admission_limits();
+        Ok(Response::new(GetBacklogResponse {
+            open_transactions: backlog.open_transactions as u64,
+            staged_bytes: backlog.staged_bytes,
+            pending_commits: backlog.pending_commits as u64,
+            avg_commit_us: backlog.avg_commit_time.as_micros() as u64,
+            estimated_drain_ms: backlog.estimated_drain.as_millis() as u64,
+            rejected: backlog.rejected,
+            idle_aborted: backlog.idle_aborted,
+            max_staged_bytes: limits.max_staged_bytes,
+            max_backlog_ms: limits.max_backlog.map(|max| max.as_millis() as u64),
+        }))
+    }
+
+    async fn get_stream_stats(&self, _request: Request<GetStreamStatsRequest>) -> Result<Response<GetStreamStatsResponse>, Status> {
+        let stats = self.streams.stats();
+        let limits = self.streams.limits();
+        Ok(Response::new(GetStreamStatsResponse {
+            open: stats.open as u64,
+            clients: stats.clients as u64,
+            max_client_open: stats.max_client_open as u64,
+            rejected: stats.rejected,
+            max_streams: limits.max_streams.map(|max| max as u64),
+            max_streams_per_client: limits.max_streams_per_client.map(|max| max as u64),
+        }))
+    }
+
+    async fn get_namespace_stats(&self, request: Request<GetNamespaceStatsRequest>) -> Result<Response<GetNamespaceStatsResponse>, Status> {
+        let req = request.into_inner();
+        let (usage, quota) = self.state_machine.namespace_usage(&req.namespace)
+            .map_err(|e| Status::internal(format!("GetNamespaceStats failed: {}", e)))?;
+
+        Ok(Response::new(GetNamespaceStatsResponse {
+            records: usage.records,
+            bytes: usage.bytes,
+            max_records: quota.and_then(|q| q.max_records),
+            max_bytes: quota.and_then(|q| q.max_bytes),
+        }))
+    }
+
     async fn begin_transaction(&self, request: Request<BeginTransactionRequest>) -> Result<Response<BeginTransactionResponse>, Status> {
+        self.check_writable()?;
         let req = request.into_inner();
-        let txn_id = self.state_machine.begin_transaction(req.timeout_ms)
-            .map_err(|e| Status::internal(format!("Failed to begin transaction: {}", e)))?;
+        let txn_id = self.state_machine.begin_transaction_with_id(req.txn_id, req.timeout_ms)
+            .map_err(|e| error_to_status("Failed to begin transaction", e))?;
 
         Ok(Response::new(BeginTransactionResponse { txn_id }))
     }
 
     async fn write(&self, request: Request<WriteRequest>) -> Result<Response<WriteResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
         let req = request.into_inner();
         
         // Convert protobuf Struct to serde_json::Value
-        let value = prost_types_to_json(&req.value.unwrap_or_default());
+        let value = self.request_value(req.value).map_err(|e| error_to_status("Write failed", e))?;
 
-        self.state_machine.write(
-            &req.txn_id,
-            req.namespace,
-            req.agent_id,
-            req.key,
-            value,
-        ).map_err(|e| Status::internal(format!("Write failed: {}", e)))?;
+        check_etag_alone(&req.if_match_etag, req.client_seq.map(|_| "client_seq"))?;
+        let other = req.client_seq.map(|_| "client_seq").or(req.if_match_etag.as_ref().map(|_| "if_match_etag"));
+        if let (Some(_), Some(other)) = (req.ttl_ms, other) {
+            return Err(Status::invalid_argument(format!("ttl_ms can't be combined with {}", other)));
+        }
+        let ttl = req.ttl_ms.map(Duration::from_millis);
+        let record_id = RecordId::new(req.namespace, req.agent_id, req.key);
+        let applied = self.stage_write(identity, "Write", &req.txn_id, record_id, value, req.labels.into_iter().collect(), req.client_seq, req.if_match_etag, ttl)
+            .map_err(|e| error_to_status("Write failed", e))?;
 
-        Ok(Response::new(WriteResponse {}))
+        Ok(Response::new(WriteResponse { duplicate: !applied }))
     }
 
     async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
         let req = request.into_inner();
 
-        self.state_machine.delete(
-            &req.txn_id,
-            req.namespace,
-            req.agent_id,
-            req.key,
-        ).map_err(|e| Status::internal(format!("Delete failed: {}", e)))?;
+        check_etag_alone(&req.if_match_etag, req.expected_version.map(|_| "expected_version"))?;
+        let record_id = RecordId::new(req.namespace, req.agent_id, req.key);
+        self.stage_delete(identity, "Delete", &req.txn_id, record_id, req.expected_version, req.if_match_etag)
+            .map_err(|e| error_to_status("Delete failed", e))?;
 
         Ok(Response::new(DeleteResponse {}))
     }
 
+    async fn compare_and_set(&self, request: Request<CompareAndSetRequest>) -> Result<Response<CompareAndSetResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let op = match req.op() {
+            CompareOp::Eq => predicate::CompareOp::Eq,
+            CompareOp::Ne => predicate::CompareOp::Ne,
+            CompareOp::Lt => predicate::CompareOp::Lt,
+            CompareOp::Le => predicate::CompareOp::Le,
+            CompareOp::Gt => predicate::CompareOp::Gt,
+            CompareOp::Ge => predicate::CompareOp::Ge,
+        };
+        let expected = self.request_operand(req.expected).map_err(|e| error_to_status("CompareAndSet failed", e))?;
+        let predicate = ValuePredicate::new(&req.pointer, op, expected)
+            .map_err(|e| Status::invalid_argument(format!("CompareAndSet failed: {}", e)))?;
+        let value = self.request_value(req.value).map_err(|e| error_to_status("CompareAndSet failed", e))?;
+
+        let result = self.state_machine.write_if(&req.txn_id, req.namespace.clone(), req.agent_id.clone(), req.key.clone(), value, predicate);
+        self.audit(identity, "CompareAndSet", |entry| {
+            entry.txn_id = Some(req.txn_id.clone());
+            entry.records.push(self.staged_record(&req.namespace, &req.agent_id, &req.key));
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        result.map_err(|e| error_to_status("CompareAndSet failed", e))?;
+
+        Ok(Response::new(CompareAndSetResponse {}))
+    }
+
+    async fn rename(&self, request: Request<RenameRequest>) -> Result<Response<RenameResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let result = self.state_machine.rename(
+            &req.txn_id,
+            req.namespace.clone(),
+            req.agent_id.clone(),
+            req.from_key.clone(),
+            req.to_key.clone(),
+            req.overwrite,
+        );
+        self.audit(identity, "Rename", |entry| {
+            entry.txn_id = Some(req.txn_id.clone());
+            entry.records.push(self.staged_record(&req.namespace, &req.agent_id, &req.from_key));
+            entry.records.push(self.staged_record(&req.namespace, &req.agent_id, &req.to_key));
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        result.map_err(|e| error_to_status("Rename failed", e))?;
+
+        Ok(Response::new(RenameResponse {}))
+    }
+
+    async fn batch_delete(&self, request: Request<BatchDeleteRequest>) -> Result<Response<BatchDeleteResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let refs: Vec<RecordId> = req.keys.into_iter()
+            .map(|k| RecordId::new(k.namespace, k.agent_id, k.key))
+            .collect();
+        let result = self.state_machine.delete_batch(&req.txn_id, refs.clone());
+        self.audit(identity, "BatchDelete", |entry| {
+            entry.txn_id = Some(req.txn_id.clone());
+            entry.records = refs.iter().map(|id| self.staged_record(&id.namespace, &id.agent_id, &id.key)).collect();
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        let staged = result.map_err(|e| error_to_status("BatchDelete failed", e))?;
+
+        Ok(Response::new(BatchDeleteResponse { staged: staged as u64 }))
+    }
+
+    async fn batch_apply(&self, request: Request<BatchApplyRequest>) -> Result<Response<BatchApplyResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+        let durability = durability(req.durability());
+
+        let mut ops = Vec::with_capacity(req.operations.len());
+        for operation in req.operations {
+            let kind = match operation.op {
+                Some(batch_operation::Op::Write(write)) => BatchOpKind::Write {
+                    value: self.request_value(write.value).map_err(|e| error_to_status("BatchApply failed", e))?,
+                    labels: write.labels.into_iter().collect(),
+                },
+                Some(batch_operation::Op::Delete(BatchDeleteOp {})) => BatchOpKind::Delete,
+                Some(batch_operation::Op::Increment(increment)) => {
+                    if !increment.pointer.is_empty() && !increment.pointer.starts_with('/') {
+                        return Err(Status::invalid_argument(format!("BatchApply failed: invalid JSON pointer {:?}", increment.pointer)));
+                    }
+                    BatchOpKind::Increment { pointer: increment.pointer, delta: increment.delta }
+                }
+                None => return Err(Status::invalid_argument("BatchApply failed: operation has no write, delete or increment")),
+            };
+            ops.push(BatchOp {
+                record_id: RecordId::new(operation.namespace, operation.agent_id, operation.key),
+                kind,
+                expected_version: operation.expected_version,
+            });
+        }
+
+        let started = Instant::now();
+        let result = self.state_machine.batch_apply(ops, req.atomic, durability);
+        self.commit_latency.record(started.elapsed());
+        self.audit(identity, "BatchApply", |entry| match &result {
+            Ok(result) => {
+                entry.txn_id = Some(result.txn_id.clone());
+                entry.commit_ts = Some(result.commit.commit_ts);
+                entry.records = committed_records(&result.commit);
+            }
+            Err(e) => entry.error = Some(e.to_string()),
+        });
+        let result = result.map_err(|e| error_to_status("BatchApply failed", e))?;
+
+        let results = result.outcomes.into_iter().map(|outcome| match outcome {
+            Ok(version) => BatchOperationResult { status: BatchOperationStatus::Applied as i32, version, error: String::new() },
+            Err(e) => {
+                let status = match e.downcast_ref() {
+                    Some(StatehouseError::Conflict { .. }) => BatchOperationStatus::Conflict,
+                    _ => BatchOperationStatus::Failed,
+                };
+                BatchOperationResult { status: status as i32, version: 0, error: e.to_string() }
+            }
+        }).collect();
+
+        Ok(Response::new(BatchApplyResponse {
+            commit_ts: result.commit.commit_ts,
+            namespace_ts: result.commit.namespace_ts.into_iter().collect(),
+            results,
+        }))
+    }
+
+    async fn swap(&self, request: Request<SwapRequest>) -> Result<Response<SwapResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let result = self.state_machine.swap(
+            &req.txn_id,
+            req.namespace.clone(),
+            req.agent_id.clone(),
+            req.key_a.clone(),
+            req.key_b.clone(),
+            req.missing_as_null,
+        );
+        self.audit(identity, "Swap", |entry| {
+            entry.txn_id = Some(req.txn_id.clone());
+            entry.records.push(self.staged_record(&req.namespace, &req.agent_id, &req.key_a));
+            entry.records.push(self.staged_record(&req.namespace, &req.agent_id, &req.key_b));
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        result.map_err(|e| error_to_status("Swap failed", e))?;
+
+        Ok(Response::new(SwapResponse {}))
+    }
+
+    async fn reset_agent(&self, request: Request<ResetAgentRequest>) -> Result<Response<ResetAgentResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let result = self.state_machine.reset_agent(&req.txn_id, req.namespace.clone(), req.agent_id.clone());
+        self.audit(identity, "ResetAgent", |entry| {
+            entry.txn_id = Some(req.txn_id.clone());
+            if let Ok(keys) = &result {
+                entry.records = keys.iter().map(|key| self.staged_record(&req.namespace, &req.agent_id, key)).collect();
+            }
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        let keys = result.map_err(|e| error_to_status("ResetAgent failed", e))?;
+
+        Ok(Response::new(ResetAgentResponse { keys_cleared: keys.len() as u64 }))
+    }
+
     async fn commit(&self, request: Request<CommitRequest>) -> Result<Response<CommitResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
         let req = request.into_inner();
 
-        let commit_ts = self.state_machine.commit(&req.txn_id)
-            .map_err(|e| Status::internal(format!("Commit failed: {}", e)))?;
+        let started = Instant::now();
+        let result = self.state_machine.commit_with_durability(&req.txn_id, durability(req.durability()));
+        self.commit_latency.record(started.elapsed());
+        self.audit_commit(identity, "Commit", &req.txn_id, &result);
+        let result = result.map_err(|e| error_to_status("Commit failed", e))?;
 
-        Ok(Response::new(CommitResponse { commit_ts }))
+        Ok(Response::new(CommitResponse {
+            commit_ts: result.commit_ts,
+            namespace_ts: result.namespace_ts.into_iter().collect(),
+        }))
     }
 
     async fn abort(&self, request: Request<AbortRequest>) -> Result<Response<AbortResponse>, Status> {
@@ -89,177 +762,2741 @@ impl statehouse_service_server::StatehouseService for StatehouseServiceImpl {
         Ok(Response::new(AbortResponse {}))
     }
 
-    async fn get_state(&self, request: Request<GetStateRequest>) -> Result<Response<GetStateResponse>, Status> {
+    async fn sync(&self, _request: Request<SyncRequest>) -> Result<Response<SyncResponse>, Status> {
+        // A replica's storage belongs to the primary
+        self.check_not_replica()?;
+
+        let state_machine = self.state_machine.clone();
+        let durable_commit_ts = tokio::task::spawn_blocking(move || state_machine.sync())
+            .await
+            .map_err(|e| Status::internal(format!("Sync task failed: {}", e)))?
+            .map_err(|e| error_to_status("Sync failed", e))?;
+
+        Ok(Response::new(SyncResponse { durable_commit_ts }))
+    }
+
+    async fn savepoint(&self, request: Request<SavepointRequest>) -> Result<Response<SavepointResponse>, Status> {
         let req = request.into_inner();
 
-        let state = self.state_machine.get_state(&req.namespace, &req.agent_id, &req.key)
-            .map_err(|e| Status::internal(format!("GetState failed: {}", e)))?;
+        let savepoint_id = self.state_machine.savepoint(&req.txn_id)
+            .map_err(|e| error_to_status("Savepoint failed", e))?;
 
-        if let Some(record) = state {
-            let value = record.value.map(|v| json_to_prost_types(&v));
-            Ok(Response::new(GetStateResponse {
-                value,
-                version: record.version,
-                commit_ts: record.commit_ts,
-                exists: !record.deleted,
-            }))
-        } else {
-            Ok(Response::new(GetStateResponse {
-                value: None,
-                version: 0,
-                commit_ts: 0,
-                exists: false,
-            }))
-        }
+        Ok(Response::new(SavepointResponse { savepoint_id }))
     }
 
-    async fn get_state_at_version(&self, request: Request<GetStateAtVersionRequest>) -> Result<Response<GetStateAtVersionResponse>, Status> {
+    async fn rollback_to(&self, request: Request<RollbackToRequest>) -> Result<Response<RollbackToResponse>, Status> {
         let req = request.into_inner();
 
-        let state = self.state_machine.get_state_at_version(&req.namespace, &req.agent_id, &req.key, req.version)
-            .map_err(|e| Status::internal(format!("GetStateAtVersion failed: {}", e)))?;
+        let discarded = self.state_machine.rollback_to(&req.txn_id, req.savepoint_id)
+            .map_err(|e| error_to_status("RollbackTo failed", e))?;
 
-        if let Some(record) = state {
-            let value = record.value.map(|v| json_to_prost_types(&v));
-            Ok(Response::new(GetStateAtVersionResponse {
-                value,
-                version: record.version,
-                commit_ts: record.commit_ts,
-                exists: !record.deleted,
-            }))
-        } else {
-            Ok(Response::new(GetStateAtVersionResponse {
-                value: None,
-                version: 0,
-                commit_ts: 0,
-                exists: false,
-            }))
-        }
+        Ok(Response::new(RollbackToResponse { discarded: discarded as u64 }))
     }
 
-    async fn list_keys(&self, request: Request<ListKeysRequest>) -> Result<Response<ListKeysResponse>, Status> {
+    async fn release_savepoint(&self, request: Request<ReleaseSavepointRequest>) -> Result<Response<ReleaseSavepointResponse>, Status> {
         let req = request.into_inner();
 
-        let keys = self.state_machine.list_keys(&req.namespace, &req.agent_id)
-            .map_err(|e| Status::internal(format!("ListKeys failed: {}", e)))?;
+        self.state_machine.release_savepoint(&req.txn_id, req.savepoint_id)
+            .map_err(|e| error_to_status("ReleaseSavepoint failed", e))?;
 
-        Ok(Response::new(ListKeysResponse { keys }))
+        Ok(Response::new(ReleaseSavepointResponse {}))
     }
 
-    async fn scan_prefix(&self, request: Request<ScanPrefixRequest>) -> Result<Response<ScanPrefixResponse>, Status> {
+    async fn prepare_commit(&self, request: Request<PrepareCommitRequest>) -> Result<Response<PrepareCommitResponse>, Status> {
+        self.check_writable()?;
         let req = request.into_inner();
 
-        let records = self.state_machine.scan_prefix(&req.namespace, &req.agent_id, &req.prefix)
-            .map_err(|e| Status::internal(format!("ScanPrefix failed: {}", e)))?;
+        self.state_machine.prepare_commit(&req.txn_id, durability(req.durability()))
+            .map_err(|e| error_to_status("PrepareCommit failed", e))?;
 
-        let entries = records.into_iter().map(|r| StateEntry {
-            key: r.key,
-            value: Some(json_to_prost_types(&r.value.unwrap_or_default())),
-            version: r.version,
-            commit_ts: r.commit_ts,
-        }).collect();
+        Ok(Response::new(PrepareCommitResponse {}))
+    }
 
-        Ok(Response::new(ScanPrefixResponse { entries }))
+    async fn finalize_commit(&self, request: Request<FinalizeCommitRequest>) -> Result<Response<CommitResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let result = self.state_machine.finalize_commit(&req.txn_id);
+        self.commit_latency.record(started.elapsed());
+        self.audit_commit(identity, "FinalizeCommit", &req.txn_id, &result);
+        let result = result.map_err(|e| error_to_status("FinalizeCommit failed", e))?;
+
+        Ok(Response::new(CommitResponse {
+            commit_ts: result.commit_ts,
+            namespace_ts: result.namespace_ts.into_iter().collect(),
+        }))
     }
 
-    type ReplayStream = ReceiverStream<Result<ReplayEvent, Status>>;
+    async fn cancel_commit(&self, request: Request<CancelCommitRequest>) -> Result<Response<CancelCommitResponse>, Status> {
+        let req = request.into_inner();
 
-    async fn replay(&self, request: Request<ReplayRequest>) -> Result<Response<Self::ReplayStream>, Status> {
+        self.state_machine.cancel_commit(&req.txn_id)
+            .map_err(|e| error_to_status("CancelCommit failed", e))?;
+
+        Ok(Response::new(CancelCommitResponse {}))
+    }
+
+    async fn get_commit_status(&self, request: Request<GetCommitStatusRequest>) -> Result<Response<GetCommitStatusResponse>, Status> {
         let req = request.into_inner();
 
-        let events = self.state_machine.replay(&req.namespace, &req.agent_id, req.start_ts, req.end_ts)
-            .map_err(|e| Status::internal(format!("Replay failed: {}", e)))?;
+        let (status, commit_ts) = match self.state_machine.commit_status(&req.txn_id) {
+            state_machine::CommitStatus::Unknown => (CommitStatus::Unknown, 0),
+            state_machine::CommitStatus::Open => (CommitStatus::Open, 0),
+            state_machine::CommitStatus::Prepared => (CommitStatus::Prepared, 0),
+            state_machine::CommitStatus::Committed(result) => (CommitStatus::Committed, result.commit_ts),
+            state_machine::CommitStatus::Cancelled => (CommitStatus::Cancelled, 0),
+        };
 
-        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        Ok(Response::new(GetCommitStatusResponse { status: status as i32, commit_ts }))
+    }
 
-        tokio::spawn(async move {
-            for event in events {
-                let operations = event.operations.into_iter().map(|op| Operation {
-                    key: op.key,
-                    value: op.value.map(|v| json_to_prost_types(&v)),
-                    version: op.version,
-                }).collect();
+    type StreamTransactionStream = ReceiverStream<Result<StreamTransactionResponse, Status>>;
 
-                let replay_event = ReplayEvent {
-                    txn_id: event.txn_id,
-                    commit_ts: event.commit_ts,
-                    operations,
-                };
+    async fn stream_transaction(&self, request: Request<Streaming<StreamTransactionRequest>>) -> Result<Response<Self::StreamTransactionStream>, Status> {
+        let identity = identity(&request);
+        let (tx, rx) = mpsc::channel(128);
 
-                if tx.send(Ok(replay_event)).await.is_err() {
-                    break;
-                }
-            }
-        });
+        tokio::spawn(self.clone().run_stream_transaction(identity, request.into_inner(), tx));
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
-}
 
-// Helper functions to convert between prost_types::Struct and serde_json::Value
+    async fn touch(&self, request: Request<TouchRequest>) -> Result<Response<TouchResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
 
-fn prost_types_to_json(value: &prost_types::Struct) -> serde_json::Value {
-    use prost_types::value::Kind;
+        let txn_id = self.state_machine.begin_transaction(None)
+            .map_err(|e| Status::internal(format!("Touch failed: {}", e)))?;
+        self.state_machine.touch(&txn_id, req.namespace.clone(), req.agent_id.clone(), req.key.clone())
+            .map_err(|e| Status::internal(format!("Touch failed: {}", e)))?;
+        let result = self.state_machine.commit(&txn_id);
+        self.audit_commit(identity, "Touch", &txn_id, &result);
+        let commit_ts = result
+            .map_err(|e| error_to_status("Touch failed", e))?
+            .commit_ts;
 
-    let map: serde_json::Map<String, serde_json::Value> = value.fields.iter().map(|(k, v)| {
-        let json_val = match &v.kind {
-            Some(Kind::NullValue(_)) => serde_json::Value::Null,
-            Some(Kind::NumberValue(n)) => serde_json::json!(n),
-            Some(Kind::StringValue(s)) => serde_json::json!(s),
-            Some(Kind::BoolValue(b)) => serde_json::json!(b),
-            Some(Kind::StructValue(s)) => prost_types_to_json(s),
-            Some(Kind::ListValue(l)) => {
-                let vals: Vec<serde_json::Value> = l.values.iter().map(|v| {
-                    match &v.kind {
-                        Some(Kind::NullValue(_)) => serde_json::Value::Null,
-                        Some(Kind::NumberValue(n)) => serde_json::json!(n),
-                        Some(Kind::StringValue(s)) => serde_json::json!(s),
-                        Some(Kind::BoolValue(b)) => serde_json::json!(b),
-                        _ => serde_json::Value::Null,
-                    }
-                }).collect();
-                serde_json::Value::Array(vals)
-            }
-            None => serde_json::Value::Null,
-        };
-        (k.clone(), json_val)
-    }).collect();
+        let record = self.state_machine.get_state_as_of(&req.namespace, &req.agent_id, &req.key, commit_ts)
+            .map_err(|e| Status::internal(format!("Touch failed: {}", e)))?
+            .ok_or_else(|| Status::internal("Touch failed: record missing after commit"))?;
 
-    serde_json::Value::Object(map)
-}
+        Ok(Response::new(TouchResponse { version: record.version, commit_ts }))
+    }
 
-fn json_to_prost_types(value: &serde_json::Value) -> prost_types::Struct {
-    use prost_types::value::Kind;
-    use std::collections::BTreeMap;
-
-    let fields: BTreeMap<String, prost_types::Value> = match value {
-        serde_json::Value::Object(map) => {
-            map.iter().map(|(k, v)| {
-                let kind = match v {
-                    serde_json::Value::Null => Kind::NullValue(0),
-                    serde_json::Value::Bool(b) => Kind::BoolValue(*b),
-                    serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or(0.0)),
-                    serde_json::Value::String(s) => Kind::StringValue(s.clone()),
-                    serde_json::Value::Array(arr) => {
-                        let values = arr.iter().map(|v| {
-                            let kind = match v {
-                                serde_json::Value::Null => Kind::NullValue(0),
-                                serde_json::Value::Bool(b) => Kind::BoolValue(*b),
-                                serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or(0.0)),
-                                serde_json::Value::String(s) => Kind::StringValue(s.clone()),
-                                _ => Kind::NullValue(0),
-                            };
-                            prost_types::Value { kind: Some(kind) }
-                        }).collect();
-                        Kind::ListValue(prost_types::ListValue { values })
-                    }
-                    serde_json::Value::Object(_) => Kind::StructValue(json_to_prost_types(v)),
-                };
-                (k.clone(), prost_types::Value { kind: Some(kind) })
-            }).collect()
-        }
-        _ => BTreeMap::new(),
-    };
+    async fn undelete(&self, request: Request<UndeleteRequest>) -> Result<Response<UndeleteResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
 
-    prost_types::Struct { fields }
+        let txn_id = self.state_machine.begin_transaction(None)
+            .map_err(|e| Status::internal(format!("Undelete failed: {}", e)))?;
+        self.state_machine.undelete(&txn_id, req.namespace.clone(), req.agent_id.clone(), req.key.clone())
+            .map_err(|e| Status::internal(format!("Undelete failed: {}", e)))?;
+        let result = self.state_machine.commit(&txn_id);
+        self.audit_commit(identity, "Undelete", &txn_id, &result);
+        let commit_ts = result
+            .map_err(|e| error_to_status("Undelete failed", e))?
+            .commit_ts;
+
+        let record = self.state_machine.get_state_as_of(&req.namespace, &req.agent_id, &req.key, commit_ts)
+            .map_err(|e| Status::internal(format!("Undelete failed: {}", e)))?
+            .ok_or_else(|| Status::internal("Undelete failed: record missing after commit"))?;
+
+        Ok(Response::new(UndeleteResponse {
+            value: Some(json_to_prost_types(&record.value.unwrap_or_default())),
+            version: record.version,
+            commit_ts,
+        }))
+    }
+
+    async fn get_or_create(&self, request: Request<GetOrCreateRequest>) -> Result<Response<GetOrCreateResponse>, Status> {
+        self.check_writable()?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+        let default = self.request_value(req.default_value).map_err(|e| error_to_status("GetOrCreate failed", e))?;
+
+        let txn_id = self.state_machine.begin_transaction(None)
+            .map_err(|e| Status::internal(format!("GetOrCreate failed: {}", e)))?;
+        self.state_machine.get_or_create(&txn_id, req.namespace, req.agent_id, req.key, default)
+            .map_err(|e| error_to_status("GetOrCreate failed", e))?;
+        let result = self.state_machine.commit(&txn_id);
+        self.audit_commit(identity, "GetOrCreate", &txn_id, &result);
+        let result = result.map_err(|e| error_to_status("GetOrCreate failed", e))?;
+        let outcome = result.get_or_create.into_iter().next()
+            .ok_or_else(|| Status::internal("GetOrCreate failed: no outcome after commit"))?;
+
+        Ok(Response::new(GetOrCreateResponse {
+            created: outcome.created,
+            value: Some(json_to_prost_types(&outcome.value)),
+            version: outcome.version,
+            commit_ts: result.commit_ts,
+        }))
+    }
+
+    async fn begin_read_transaction(&self, request: Request<BeginReadTransactionRequest>) -> Result<Response<BeginReadTransactionResponse>, Status> {
+        let req = request.into_inner();
+        let (txn_id, read_ts) = self.state_machine.begin_read_transaction(req.timeout_ms)
+            .map_err(|e| Status::internal(format!("Failed to begin read transaction: {}", e)))?;
+
+        Ok(Response::new(BeginReadTransactionResponse { txn_id, read_ts }))
+    }
+
+    async fn snapshot_batch_get(&self, request: Request<SnapshotBatchGetRequest>) -> Result<Response<SnapshotBatchGetResponse>, Status> {
+        let req = request.into_inner();
+
+        let refs: Vec<RecordId> = req.keys.into_iter()
+            .map(|k| RecordId::new(k.namespace, k.agent_id, k.key))
+            .collect();
+
+        let records = self.state_machine.get_state_batch_in_snapshot(&req.txn_id, &refs)
+            .map_err(|e| Status::internal(format!("SnapshotBatchGet failed: {}", e)))?;
+
+        let entries = refs.into_iter().zip(records).map(|(record_id, record)| match record {
+            Some(record) => SnapshotEntry {
+                namespace: record_id.namespace,
+                agent_id: record_id.agent_id,
+                key: record_id.key,
+                value: record.value.map(|v| json_to_prost_types(&v)),
+                version: record.version,
+                commit_ts: record.commit_ts,
+                exists: !record.deleted,
+            },
+            None => SnapshotEntry {
+                namespace: record_id.namespace,
+                agent_id: record_id.agent_id,
+                key: record_id.key,
+                value: None,
+                version: 0,
+                commit_ts: 0,
+                exists: false,
+            },
+        }).collect();
+
+        Ok(Response::new(SnapshotBatchGetResponse { entries }))
+    }
+
+    async fn batch_get_existing(&self, request: Request<BatchGetExistingRequest>) -> Result<Response<BatchGetExistingResponse>, Status> {
+        let req = request.into_inner();
+
+        let refs: Vec<RecordId> = req.keys.into_iter()
+            .map(|k| RecordId::new(k.namespace, k.agent_id, k.key))
+            .collect();
+
+        let started = Instant::now();
+        let batch = self.state_machine.get_existing_batch(&refs)
+            .map_err(|e| error_to_status("BatchGetExisting failed", e))?;
+        let (namespace, agent_id) = refs.first().map_or(("", ""), |r| (r.namespace.as_str(), r.agent_id.as_str()));
+        self.observe_read("BatchGetExisting", namespace, agent_id, "", batch.found.len(), started);
+
+        let found = batch.found.into_iter().map(|record| ExistingEntry {
+            value: record.value.map(|v| json_to_prost_types(&v)),
+            namespace: record.namespace,
+            agent_id: record.agent_id,
+            key: record.key,
+            version: record.version,
+            commit_ts: record.commit_ts,
+            labels: record.labels.into_iter().collect(),
+        }).collect();
+        let missing = batch.missing.into_iter().map(|record_id| KeyRef {
+            namespace: record_id.namespace,
+            agent_id: record_id.agent_id,
+            key: record_id.key,
+        }).collect();
+
+        Ok(Response::new(BatchGetExistingResponse { found, missing }))
+    }
+
+    async fn list_transactions(&self, request: Request<ListTransactionsRequest>) -> Result<Response<ListTransactionsResponse>, Status> {
+        self.check_admin(&request)?;
+
+        let transactions = self.state_machine.list_open_transactions().into_iter().map(|txn| TransactionSummary {
+            txn_id: txn.txn_id,
+            age_ms: txn.age.as_millis() as u64,
+            timeout_ms: txn.timeout.as_millis() as u64,
+            staged_ops: txn.staged_ops as u64,
+            agents: txn.agents.into_iter().map(|(namespace, agent_id)| AgentRef { namespace, agent_id }).collect(),
+        }).collect();
+
+        Ok(Response::new(ListTransactionsResponse { transactions }))
+    }
+
+    async fn consistency_check(&self, request: Request<ConsistencyCheckRequest>) -> Result<Response<ConsistencyCheckResponse>, Status> {
+        self.check_admin(&request)?;
+
+        let state_machine = self.state_machine.clone();
+        let report = tokio::task::spawn_blocking(move || state_machine.consistency_check())
+            .await
+            .map_err(|e| Status::internal(format!("Consistency check task failed: {}", e)))?
+            .map_err(|e| error_to_status("Consistency check failed", e))?;
+
+        let violations = report.violations.iter().map(consistency_violation).collect();
+
+        Ok(Response::new(ConsistencyCheckResponse {
+            consistent: report.is_consistent(),
+            records_checked: report.records_checked,
+            counters_checked: report.counters_checked,
+            violations,
+        }))
+    }
+
+    async fn get_version_depth_histogram(&self, request: Request<GetVersionDepthHistogramRequest>) -> Result<Response<GetVersionDepthHistogramResponse>, Status> {
+        self.check_admin(&request)?;
+        let deadline = request_deadline(&request);
+        let req = request.into_inner();
+
+        let state_machine = self.state_machine.clone();
+        let top_n = req.top_n.map_or(DEFAULT_DEEPEST_KEYS, |n| n as usize);
+        let histogram = tokio::task::spawn_blocking(move || state_machine.version_depth_histogram(&req.namespace, top_n, deadline))
+            .await
+            .map_err(|e| Status::internal(format!("GetVersionDepthHistogram task failed: {}", e)))?
+            .map_err(|e| error_to_status("GetVersionDepthHistogram failed", e))?;
+
+        // Bucket i holds the keys above bound i-1, up to bound i
+        let buckets = histogram.buckets.iter().enumerate().map(|(i, &keys)| VersionDepthBucket {
+            min_versions: i.checked_sub(1).map_or(1, |prev| state_machine::VERSION_DEPTH_BUCKETS[prev] + 1),
+            max_versions: state_machine::VERSION_DEPTH_BUCKETS.get(i).copied(),
+            keys,
+        }).collect();
+        let deepest = histogram.deepest.into_iter().map(|(record_id, versions)| KeyVersionDepth {
+            agent_id: record_id.agent_id,
+            key: record_id.key,
+            versions,
+        }).collect();
+
+        Ok(Response::new(GetVersionDepthHistogramResponse {
+            keys: histogram.keys,
+            versions: histogram.versions,
+            buckets,
+            deepest,
+        }))
+    }
+
+    async fn snapshot(&self, request: Request<SnapshotRequest>) -> Result<Response<SnapshotResponse>, Status> {
+        self.check_admin(&request)?;
+        self.check_not_replica()?;
+
+        // The scan and file write are blocking; keep them off the async workers
+        let state_machine = self.state_machine.clone();
+        let metadata = tokio::task::spawn_blocking(move || state_machine.create_snapshot())
+            .await
+            .map_err(|e| Status::internal(format!("Snapshot task failed: {}", e)))?
+            .map_err(|e| error_to_status("Snapshot failed", e))?;
+
+        Ok(Response::new(SnapshotResponse {
+            snapshot_ts: metadata.snapshot_ts,
+            record_count: metadata.record_count as u64,
+        }))
+    }
+
+    async fn compact(&self, request: Request<CompactRequest>) -> Result<Response<CompactResponse>, Status> {
+        self.check_admin(&request)?;
+        self.check_not_replica()?;
+        let req = request.into_inner();
+
+        let state_machine = self.state_machine.clone();
+        let stats = tokio::task::spawn_blocking(move || state_machine.compact(req.keep_versions as usize))
+            .await
+            .map_err(|e| Status::internal(format!("Compact task failed: {}", e)))?
+            .map_err(|e| error_to_status("Compact failed", e))?;
+
+        Ok(Response::new(CompactResponse {
+            snapshot_ts: stats.snapshot_ts,
+            versions_removed: stats.versions_removed,
+            tombstones_removed: stats.tombstones_removed,
+            events_removed: stats.events_removed,
+            bytes_reclaimed: stats.bytes_reclaimed,
+        }))
+    }
+
+    async fn maintain(&self, request: Request<MaintainRequest>) -> Result<Response<MaintainResponse>, Status> {
+        self.check_admin(&request)?;
+        self.check_not_replica()?;
+        let req = request.into_inner();
+
+        let opts = MaintenanceOpts {
+            snapshot: !req.skip_snapshot,
+            version_gc: !req.skip_version_gc,
+            keep_versions: req.keep_versions as usize,
+            tombstone_gc: !req.skip_tombstone_gc,
+            trim_log: !req.skip_trim_log,
+            consistency_check: !req.skip_consistency_check,
+        };
+        if opts.trim_log && !opts.snapshot {
+            return Err(Status::invalid_argument("skip_trim_log is required with skip_snapshot"));
+        }
+        let steps = opts.steps().iter().map(|step| step.name().to_string()).collect();
+        let state_machine = self.state_machine.clone();
+        let report = tokio::task::spawn_blocking(move || state_machine.run_maintenance(&opts))
+            .await
+            .map_err(|e| Status::internal(format!("Maintain task failed: {}", e)))?
+            .map_err(|e| error_to_status("Maintain failed", e))?;
+
+        let totals = report.totals();
+        let violations: Vec<_> = report.consistency.iter().flat_map(|c| c.violations.iter()).map(consistency_violation).collect();
+        Ok(Response::new(MaintainResponse {
+            steps,
+            snapshot_ts: report.snapshot.as_ref().map_or(0, |m| m.snapshot_ts),
+            record_count: report.snapshot.as_ref().map_or(0, |m| m.record_count as u64),
+            versions_removed: totals.versions_removed,
+            tombstones_removed: totals.tombstones_removed,
+            events_removed: totals.events_removed,
+            bytes_reclaimed: totals.bytes_reclaimed,
+            consistent: violations.is_empty(),
+            violations,
+        }))
+    }
+
+    async fn purge_key(&self, request: Request<PurgeKeyRequest>) -> Result<Response<PurgeKeyResponse>, Status> {
+        self.check_writable()?;
+        self.check_admin(&request)?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+
+        let record = self.staged_record(&req.namespace, &req.agent_id, &req.key);
+        let state_machine = self.state_machine.clone();
+        let (namespace, agent_id, key) = (req.namespace, req.agent_id, req.key);
+        let result = tokio::task::spawn_blocking(move || state_machine.purge_key(&namespace, &agent_id, &key))
+            .await
+            .map_err(|e| Status::internal(format!("PurgeKey task failed: {}", e)))?;
+        self.audit(identity, "PurgeKey", |entry| {
+            entry.records.push(record);
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        let stats = result.map_err(|e| error_to_status("PurgeKey failed", e))?;
+
+        Ok(Response::new(PurgeKeyResponse {
+            versions_removed: stats.versions_removed,
+            events_scrubbed: stats.events_scrubbed,
+        }))
+    }
+
+    async fn rename_namespace(&self, request: Request<RenameNamespaceRequest>) -> Result<Response<RenameNamespaceResponse>, Status> {
+        self.check_writable()?;
+        self.check_admin(&request)?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+        if req.from.is_empty() || req.to.is_empty() {
+            return Err(Status::invalid_argument("from and to are required"));
+        }
+
+        let state_machine = self.state_machine.clone();
+        let result = tokio::task::spawn_blocking(move || state_machine.rename_namespace(&req.from, &req.to, req.merge))
+            .await
+            .map_err(|e| Status::internal(format!("RenameNamespace task failed: {}", e)))?;
+        self.audit(identity, "RenameNamespace", |entry| {
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        let stats = result.map_err(|e| error_to_status("RenameNamespace failed", e))?;
+
+        Ok(Response::new(RenameNamespaceResponse {
+            records_moved: stats.records_moved,
+            versions_moved: stats.versions_moved,
+            events_rewritten: stats.events_rewritten,
+        }))
+    }
+
+    async fn import_record(&self, request: Request<ImportRecordRequest>) -> Result<Response<ImportRecordResponse>, Status> {
+        self.check_writable()?;
+        self.check_admin(&request)?;
+        let identity = identity(&request);
+        let req = request.into_inner();
+        if req.version == 0 || req.commit_ts == 0 {
+            return Err(Status::invalid_argument("version and commit_ts must be at least 1"));
+        }
+
+        let value = match req.deleted {
+            true => None,
+            false => Some(self.request_value(req.value).map_err(|e| error_to_status("ImportRecord failed", e))?),
+        };
+        let mut audit_record = self.staged_record(&req.namespace, &req.agent_id, &req.key);
+        audit_record.after_version = Some(req.version);
+        let record = StateRecord {
+            namespace: req.namespace,
+            agent_id: req.agent_id,
+            key: req.key,
+            value,
+            version: req.version,
+            commit_ts: req.commit_ts,
+            deleted: req.deleted,
+            namespace_ts: None,
+            purge_after: None,
+            labels: req.labels.into_iter().collect(),
+            value_hash: None,
+            expires_at: req.expires_at_ms.filter(|_| !req.deleted),
+        };
+        let state_machine = self.state_machine.clone();
+        let result = tokio::task::spawn_blocking(move || state_machine.import_records(vec![record]))
+            .await
+            .map_err(|e| Status::internal(format!("ImportRecord task failed: {}", e)))?;
+        self.audit(identity, "ImportRecord", |entry| {
+            entry.commit_ts = Some(req.commit_ts);
+            entry.records.push(audit_record);
+            entry.error = result.as_ref().err().map(|e| e.to_string());
+        });
+        let stats = result.map_err(|e| error_to_status("ImportRecord failed", e))?;
+
+        Ok(Response::new(ImportRecordResponse { imported: stats.records_imported > 0 }))
+    }
+
+    async fn apply_replicated_events(&self, request: Request<ApplyReplicatedEventsRequest>) -> Result<Response<ApplyReplicatedEventsResponse>, Status> {
+        self.check_writable()?;
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+
+        let mut applied = 0;
+        for event in req.events {
+            let event = crate::replication::event_from_proto(event)
+                .map_err(|e| Status::invalid_argument(format!("Invalid replicated event: {}", e)))?;
+            if self.state_machine.apply_replicated_event(event).map_err(|e| error_to_status("ApplyReplicatedEvents failed", e))? {
+                applied += 1;
+            }
+        }
+
+        Ok(Response::new(ApplyReplicatedEventsResponse { applied }))
+    }
+
+    async fn get_state(&self, request: Request<GetStateRequest>) -> Result<Response<GetStateResponse>, Status> {
+        let req = request.into_inner();
+        check_projection(&req.projection)?;
+
+        let started = Instant::now();
+        let state = self.state_machine.get_state(&req.namespace, &req.agent_id, &req.key)
+            .map_err(|e| Status::internal(format!("GetState failed: {}", e)))?;
+        self.observe_read("GetState", &req.namespace, &req.agent_id, &req.key, state.is_some() as usize, started);
+
+        if let Some(record) = state {
+            let value_bytes = StateMeta::of(&record).value_bytes;
+            let etag = etag(record.version, record.value.as_ref().filter(|_| !record.deleted).map(|value| (value, &record.labels)));
+            let value = record.value.map(|v| json_to_prost_types(&project_value(v, &req.projection)));
+            Ok(Response::new(GetStateResponse {
+                value,
+                version: record.version,
+                commit_ts: record.commit_ts,
+                exists: !record.deleted,
+                tombstoned: record.deleted,
+                namespace_ts: record.namespace_ts,
+                value_bytes,
+                labels: record.labels.into_iter().collect(),
+                etag,
+                expires_at_ms: record.expires_at,
+            }))
+        } else {
+            Ok(Response::new(GetStateResponse {
+                value: None,
+                version: 0,
+                commit_ts: 0,
+                exists: false,
+                tombstoned: false,
+                namespace_ts: None,
+                value_bytes: 0,
+                labels: HashMap::new(),
+                etag: etag(0, None),
+                expires_at_ms: None,
+            }))
+        }
+    }
+
+    async fn get_state_meta(&self, request: Request<GetStateMetaRequest>) -> Result<Response<GetStateMetaResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let meta = self.state_machine.get_state_meta(&req.namespace, &req.agent_id, &req.key)
+            .map_err(|e| Status::internal(format!("GetStateMeta failed: {}", e)))?;
+        self.observe_read("GetStateMeta", &req.namespace, &req.agent_id, &req.key, meta.is_some() as usize, started);
+
+        let response = match meta {
+            Some(meta) => GetStateMetaResponse {
+                version: meta.version,
+                commit_ts: meta.commit_ts,
+                exists: !meta.deleted,
+                tombstoned: meta.deleted,
+                value_bytes: meta.value_bytes,
+            },
+            None => GetStateMetaResponse::default(),
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn exists(&self, request: Request<ExistsRequest>) -> Result<Response<ExistsResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let exists = self.state_machine.exists(&req.namespace, &req.agent_id, &req.key)
+            .map_err(|e| Status::internal(format!("Exists failed: {}", e)))?;
+        self.observe_read("Exists", &req.namespace, &req.agent_id, &req.key, exists as usize, started);
+
+        Ok(Response::new(ExistsResponse { exists }))
+    }
+
+    async fn get_state_at_version(&self, request: Request<GetStateAtVersionRequest>) -> Result<Response<GetStateAtVersionResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let state = self.state_machine.get_state_at_version(&req.namespace, &req.agent_id, &req.key, req.version)
+            .map_err(|e| error_to_status("GetStateAtVersion failed", e))?;
+        self.observe_read("GetStateAtVersion", &req.namespace, &req.agent_id, &req.key, state.is_some() as usize, started);
+
+        if let Some(record) = state {
+            let value = record.value.map(|v| json_to_prost_types(&v));
+            Ok(Response::new(GetStateAtVersionResponse {
+                value,
+                version: record.version,
+                commit_ts: record.commit_ts,
+                exists: !record.deleted,
+            }))
+        } else {
+            Ok(Response::new(GetStateAtVersionResponse {
+                value: None,
+                version: 0,
+                commit_ts: 0,
+                exists: false,
+            }))
+        }
+    }
+
+    async fn get_version_bounds(&self, request: Request<GetVersionBoundsRequest>) -> Result<Response<GetVersionBoundsResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let bounds = self.state_machine.version_bounds(&req.namespace, &req.agent_id, &req.key)
+            .map_err(|e| Status::internal(format!("GetVersionBounds failed: {}", e)))?;
+        self.observe_read("GetVersionBounds", &req.namespace, &req.agent_id, &req.key, bounds.is_some() as usize, started);
+
+        let (min_version, max_version) = bounds.unwrap_or_default();
+        Ok(Response::new(GetVersionBoundsResponse { min_version, max_version }))
+    }
+
+    async fn get_version_history(&self, request: Request<GetVersionHistoryRequest>) -> Result<Response<GetVersionHistoryResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let history = self.state_machine.get_version_history(&req.namespace, &req.agent_id, &req.key, req.limit as usize)
+            .map_err(|e| Status::internal(format!("GetVersionHistory failed: {}", e)))?;
+        self.observe_read("GetVersionHistory", &req.namespace, &req.agent_id, &req.key, history.len(), started);
+
+        let versions = history.into_iter().map(|record| VersionEntry {
+            value: record.value.map(|v| json_to_prost_types(&v)),
+            version: record.version,
+            commit_ts: record.commit_ts,
+            deleted: record.deleted,
+        }).collect();
+
+        Ok(Response::new(GetVersionHistoryResponse { versions }))
+    }
+
+    async fn diff_versions(&self, request: Request<DiffVersionsRequest>) -> Result<Response<DiffVersionsResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let changes = self.state_machine.diff_versions(&req.namespace, &req.agent_id, &req.key, req.from_version, req.to_version)
+            .map_err(|e| error_to_status("DiffVersions failed", e))?;
+        self.observe_read("DiffVersions", &req.namespace, &req.agent_id, &req.key, changes.len(), started);
+
+        let changes = changes.into_iter().map(|change| ValueChange {
+            path: change.path,
+            kind: change.kind.as_str().to_string(),
+            before: change.before.as_ref().map(json_to_prost_value),
+            after: change.after.as_ref().map(json_to_prost_value),
+        }).collect();
+
+        Ok(Response::new(DiffVersionsResponse { changes }))
+    }
+
+    async fn infer_schema(&self, request: Request<InferSchemaRequest>) -> Result<Response<InferSchemaResponse>, Status> {
+        let req = request.into_inner();
+        let sample_size = req.sample_size.map_or(DEFAULT_SCHEMA_SAMPLE, |n| n as usize);
+
+        let started = Instant::now();
+        let inferred = self.state_machine.infer_schema(&req.namespace, &req.agent_id, sample_size)
+            .map_err(|e| error_to_status("InferSchema failed", e))?;
+        self.observe_read("InferSchema", &req.namespace, &req.agent_id, "", inferred.sampled as usize, started);
+
+        let schema = (inferred.sampled > 0).then(|| schema_node(inferred.root));
+        Ok(Response::new(InferSchemaResponse { sampled: inferred.sampled, schema }))
+    }
+
+    async fn list_keys(&self, request: Request<ListKeysRequest>) -> Result<Response<ListKeysResponse>, Status> {
+        let deadline = request_deadline(&request);
+        let req = request.into_inner();
+
+        let limit = self.page_limit("ListKeys", req.limit);
+
+        let started = Instant::now();
+        let (keys, next_cursor) = match (limit, req.cursor) {
+            (None, None) => {
+                let keys = self.state_machine.list_keys(&req.namespace, &req.agent_id, req.include_deleted, deadline)
+                    .map_err(|e| error_to_status("ListKeys failed", e))?;
+                (keys, None)
+            }
+            (limit, cursor) => {
+                let page = self.state_machine.scan_prefix_page(&req.namespace, &req.agent_id, "", &Labels::new(), req.include_deleted, cursor.as_deref(), limit.unwrap_or(usize::MAX), deadline)
+                    .map_err(|e| error_to_status("ListKeys failed", e))?;
+                (page.records.into_iter().map(|record| record.key).collect(), page.next_cursor)
+            }
+        };
+        self.observe_read("ListKeys", &req.namespace, &req.agent_id, "", keys.len(), started);
+
+        Ok(Response::new(ListKeysResponse { keys, next_cursor }))
+    }
+
+    async fn count_keys(&self, request: Request<CountKeysRequest>) -> Result<Response<CountKeysResponse>, Status> {
+        let req = request.into_inner();
+        let prefix = req.prefix.as_deref();
+
+        let started = Instant::now();
+        let count = self.state_machine.count_keys(&req.namespace, &req.agent_id, prefix)
+            .map_err(|e| Status::internal(format!("CountKeys failed: {}", e)))?;
+        self.observe_read("CountKeys", &req.namespace, &req.agent_id, prefix.unwrap_or(""), count as usize, started);
+
+        Ok(Response::new(CountKeysResponse { count }))
+    }
+
+    async fn get_agent_watermark(&self, request: Request<GetAgentWatermarkRequest>) -> Result<Response<GetAgentWatermarkResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let last_commit_ts = self.state_machine.agent_last_commit_ts(&req.namespace, &req.agent_id)
+            .map_err(|e| Status::internal(format!("GetAgentWatermark failed: {}", e)))?;
+        self.observe_read("GetAgentWatermark", &req.namespace, &req.agent_id, "", last_commit_ts.is_some() as usize, started);
+
+        Ok(Response::new(GetAgentWatermarkResponse { last_commit_ts: last_commit_ts.unwrap_or(0) }))
+    }
+
+    async fn list_keys_at(&self, request: Request<ListKeysAtRequest>) -> Result<Response<ListKeysResponse>, Status> {
+        let deadline = request_deadline(&request);
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let keys = self.state_machine.list_keys_at(&req.namespace, &req.agent_id, req.as_of_ts, deadline)
+            .map_err(|e| error_to_status("ListKeysAt failed", e))?;
+        self.observe_read("ListKeysAt", &req.namespace, &req.agent_id, "", keys.len(), started);
+
+        Ok(Response::new(ListKeysResponse { keys, next_cursor: None }))
+    }
+
+    async fn scan_prefix(&self, request: Request<ScanPrefixRequest>) -> Result<Response<ScanPrefixResponse>, Status> {
+        let deadline = request_deadline(&request);
+        let req = request.into_inner();
+        check_projection(&req.projection)?;
+
+        // A consistent scan can't continue with a cursor, so one asking for
+        // everything isn't held to the page cap
+        let limit = if req.consistent && req.limit == 0 { None } else { self.page_limit("ScanPrefix", req.limit) };
+
+        let started = Instant::now();
+        let label_filter: Labels = req.label_filter.into_iter().collect();
+        let ScanPage { records, next_cursor } = match (limit, req.cursor) {
+            (_, Some(_)) if req.consistent => return Err(Status::invalid_argument("A consistent ScanPrefix is returned whole and takes no cursor")),
+            (limit, None) if req.consistent => {
+                let records = self.state_machine.scan_prefix_consistent(&req.namespace, &req.agent_id, &req.prefix, &label_filter, req.include_deleted, deadline)
+                    .map_err(|e| error_to_status("ScanPrefix failed", e))?;
+                if let Some(limit) = limit.filter(|limit| records.len() > *limit) {
+                    return Err(Status::failed_precondition(format!("Consistent ScanPrefix matched {} entries, more than the limit of {}", records.len(), limit)));
+                }
+                ScanPage { records, next_cursor: None }
+            }
+            (None, None) => {
+                let records = self.state_machine.scan_prefix_with_labels(&req.namespace, &req.agent_id, &req.prefix, &label_filter, req.include_deleted, deadline)
+                    .map_err(|e| error_to_status("ScanPrefix failed", e))?;
+                ScanPage { records, next_cursor: None }
+            }
+            (limit, cursor) => self.state_machine.scan_prefix_page(&req.namespace, &req.agent_id, &req.prefix, &label_filter, req.include_deleted, cursor.as_deref(), limit.unwrap_or(usize::MAX), deadline)
+                .map_err(|e| error_to_status("ScanPrefix failed", e))?,
+        };
+        self.observe_read("ScanPrefix", &req.namespace, &req.agent_id, &req.prefix, records.len(), started);
+
+        let entries = records
+            .into_iter()
+            .map(|mut record| {
+                record.value = record.value.map(|v| project_value(v, &req.projection));
+                state_entry(record)
+            })
+            .collect();
+
+        Ok(Response::new(ScanPrefixResponse { entries, next_cursor }))
+    }
+
+    async fn scan_namespace_prefix(&self, request: Request<ScanNamespacePrefixRequest>) -> Result<Response<ScanPrefixResponse>, Status> {
+        let req = request.into_inner();
+        let limit = if req.limit == 0 { usize::MAX } else { req.limit as usize };
+
+        let started = Instant::now();
+        let records = self.state_machine.scan_namespace_prefix(&req.namespace, &req.prefix, limit)
+            .map_err(|e| Status::internal(format!("ScanNamespacePrefix failed: {}", e)))?;
+        self.observe_read("ScanNamespacePrefix", &req.namespace, "", &req.prefix, records.len(), started);
+
+        let entries = records.into_iter().map(state_entry).collect();
+
+        Ok(Response::new(ScanPrefixResponse { entries, next_cursor: None }))
+    }
+
+    type ScanPrefixStreamStream = ReceiverStream<Result<StateEntry, Status>>;
+
+    async fn scan_prefix_stream(&self, request: Request<ScanPrefixStreamRequest>) -> Result<Response<Self::ScanPrefixStreamStream>, Status> {
+        let req = request.into_inner();
+        check_projection(&req.projection)?;
+
+        // Not timed as a read: how long the stream runs is up to the client
+        let records = self.state_machine.scan_prefix_iter(&req.namespace, &req.agent_id, &req.prefix, req.start_after);
+        let (tx, rx) = mpsc::channel(SCAN_STREAM_BUFFER);
+
+        let projection = req.projection;
+        tokio::task::spawn_blocking(move || {
+            for record in records {
+                let entry = record
+                    .map(|mut record| {
+                        record.value = record.value.map(|v| project_value(v, &projection));
+                        state_entry(record)
+                    })
+                    .map_err(|e| Status::internal(format!("ScanPrefixStream failed: {}", e)));
+                let failed = entry.is_err();
+                // Waits while the buffer is full; fails once the client is gone
+                if tx.blocking_send(entry).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ReplayStream = ReceiverStream<Result<ReplayEvent, Status>>;
+
+    async fn replay(&self, request: Request<ReplayRequest>) -> Result<Response<Self::ReplayStream>, Status> {
+        let slot = self.streams.acquire(&stream_client(&request))?;
+        let deadline = request_deadline(&request);
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let filter = ReplayFilter {
+            include_writes: req.include_writes.unwrap_or(true),
+            include_deletes: req.include_deletes.unwrap_or(true),
+            key_prefix: req.key_prefix,
+            resume_after: req.resume_token,
+        };
+        let events = self.state_machine.replay_filtered(&req.namespace, &req.agent_id, req.start_ts, req.end_ts, &filter, deadline)
+            .map_err(|e| error_to_status("Replay failed", e))?;
+        self.observe_read("Replay", &req.namespace, &req.agent_id, "", events.len(), started);
+
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+
+        let namespace = req.namespace;
+        tokio::spawn(async move {
+            let _slot = slot;
+            for event in events {
+                let namespace_ts = event.namespace_ts.get(&namespace).copied();
+                let operations = event.operations.into_iter().map(operation).collect();
+
+                let replay_event = ReplayEvent {
+                    txn_id: event.txn_id,
+                    commit_ts: event.commit_ts,
+                    operations,
+                    namespace_ts,
+                    resume_token: event.commit_ts,
+                };
+
+                if tx.send(Ok(replay_event)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_event(&self, request: Request<GetEventRequest>) -> Result<Response<GetEventResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let event = self.state_machine.get_event(req.commit_ts)
+            .map_err(|e| Status::internal(format!("GetEvent failed: {}", e)))?;
+        self.observe_read("GetEvent", "", "", "", event.is_some() as usize, started);
+
+        let Some(event) = event else {
+            return Ok(Response::new(GetEventResponse { commit_ts: req.commit_ts, ..Default::default() }));
+        };
+        Ok(Response::new(GetEventResponse {
+            found: true,
+            txn_id: event.txn_id,
+            commit_ts: event.commit_ts,
+            operations: event.operations.into_iter().map(operation).collect(),
+            namespace_ts: event.namespace_ts.into_iter().collect(),
+        }))
+    }
+
+    async fn get_transaction_log(&self, request: Request<GetTransactionLogRequest>) -> Result<Response<GetTransactionLogResponse>, Status> {
+        let req = request.into_inner();
+
+        let started = Instant::now();
+        let page = self.state_machine.transaction_log(req.from_offset, req.limit as usize)
+            .map_err(|e| error_to_status("GetTransactionLog failed", e))?;
+        self.observe_read("GetTransactionLog", "", "", "", page.events.len(), started);
+
+        let entries = page.events.into_iter().map(|event| TransactionLogEntry {
+            txn_id: event.txn_id,
+            commit_ts: event.commit_ts,
+            operations: event.operations.into_iter().map(operation).collect(),
+            namespace_ts: event.namespace_ts.into_iter().collect(),
+        }).collect();
+        Ok(Response::new(GetTransactionLogResponse { entries, next_offset: page.next_offset }))
+    }
+
+    type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
+
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let client = stream_client(&request);
+        let req = request.into_inner();
+        let hub = self.watch.as_ref().ok_or_else(|| Status::unimplemented("Watch is not enabled on this server"))?;
+        if req.keys.is_empty() {
+            return Err(Status::invalid_argument("Watch needs at least one key"));
+        }
+        let slot = self.streams.acquire(&client)?;
+
+        // Subscribe before reading the current states: a commit landing in
+        // between is then in both, and dropped from the live events below
+        let mut events = hub.subscribe();
+        let (tx, rx) = mpsc::channel(WATCH_STREAM_BUFFER);
+
+        let state_machine = self.state_machine.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            let keys: BTreeSet<String> = req.keys.into_iter().collect();
+            // commit_ts of the state sent for each key; changes up to it are already in it
+            let mut sent_ts = HashMap::new();
+            if req.subscribe_with_snapshot {
+                for key in &keys {
+                    let record = match state_machine.get_state(&req.namespace, &req.agent_id, key) {
+                        Ok(Some(record)) => record,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            let _ = tx.send(Err(Status::internal(format!("Watch failed: {}", e)))).await;
+                            return;
+                        }
+                    };
+                    sent_ts.insert(key.clone(), record.commit_ts);
+                    let event = WatchEvent {
+                        commit_ts: record.commit_ts,
+                        operation: Some(operation(OperationRecord {
+                            namespace: record.namespace,
+                            agent_id: record.agent_id,
+                            key: record.key,
+                            value: record.value,
+                            version: record.version,
+                            labels: record.labels,
+                            expires_at: record.expires_at,
+                        })),
+                        snapshot: true,
+                    };
+                    if tx.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+
+            loop {
+                let event = tokio::select! {
+                    _ = tx.closed() => return,
+                    event = events.recv() => event,
+                };
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        let _ = tx.send(Err(Status::data_loss(format!("Watch fell {} events behind; resubscribe", missed)))).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                for op in &event.operations {
+                    if op.namespace != req.namespace || op.agent_id != req.agent_id || !keys.contains(&op.key) {
+                        continue;
+                    }
+                    if sent_ts.get(&op.key).is_some_and(|ts| event.commit_ts <= *ts) {
+                        continue;
+                    }
+                    let watch_event = WatchEvent { commit_ts: event.commit_ts, operation: Some(operation(op.clone())), snapshot: false };
+                    if tx.send(Ok(watch_event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Identity an authenticating interceptor attached to the request, if any
+fn identity<T>(request: &Request<T>) -> Option<String> {
+    request.extensions().get::<AuthIdentity>().map(|identity| identity.0.clone())
+}
+
+/// Refuse `if_match_etag` alongside `other`, the name of another condition
+/// or retry marker set on the same request
+#[allow(clippy::result_large_err)]
+fn check_etag_alone(if_match_etag: &Option<String>, other: Option<&str>) -> Result<(), Status> {
+    match (if_match_etag, other) {
+        (Some(_), Some(other)) => Err(Status::invalid_argument(format!("if_match_etag can't be combined with {}", other))),
+        _ => Ok(()),
+    }
+}
+
+/// Who a stream counts against for the per-client stream limit: the
+/// authenticated identity, else the peer's IP address
+fn stream_client<T>(request: &Request<T>) -> String {
+    identity(request).or_else(|| request.remote_addr().map(|addr| addr.ip().to_string())).unwrap_or_default()
+}
+
+/// Map a state machine error to a gRPC status, using a specific code for
+/// errors clients can act on and `INTERNAL` for everything else
+fn error_to_status(context: &str, e: anyhow::Error) -> Status {
+    match e.downcast_ref::<StatehouseError>() {
+        Some(StatehouseError::Conflict { .. }) => Status::aborted(format!("{}: {}", context, e)),
+        Some(StatehouseError::KeyNotFound { .. }) => Status::not_found(format!("{}: {}", context, e)),
+        Some(StatehouseError::KeyExists { .. }) => Status::already_exists(format!("{}: {}", context, e)),
+        Some(StatehouseError::SnapshotRegression { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::TransactionExists { .. }) => Status::already_exists(format!("{}: {}", context, e)),
+        Some(StatehouseError::TransactionTooLarge { .. }) => Status::resource_exhausted(format!("{}: {}", context, e)),
+        Some(StatehouseError::InvalidTxnId { .. }) => Status::invalid_argument(format!("{}: {}", context, e)),
+        Some(StatehouseError::ImportOutOfOrder { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::SavepointNotFound { .. }) => Status::not_found(format!("{}: {}", context, e)),
+        Some(StatehouseError::ValueTooDeep { .. }) => Status::invalid_argument(format!("{}: {}", context, e)),
+        Some(StatehouseError::ReplaySpanTooLarge { .. }) => Status::invalid_argument(format!("{}: {}", context, e)),
+        Some(StatehouseError::UnsupportedDurability { .. }) => Status::invalid_argument(format!("{}: {}", context, e)),
+        Some(StatehouseError::PredicateFailed { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::EtagMismatch { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::QuotaExceeded { .. }) => Status::resource_exhausted(format!("{}: {}", context, e)),
+        Some(StatehouseError::VersionCompacted { .. }) => Status::out_of_range(format!("{}: {}", context, e)),
+        Some(StatehouseError::VersionNotFound { .. }) => Status::not_found(format!("{}: {}", context, e)),
+        Some(StatehouseError::GracePeriodExpired { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::UndeleteAfterChange { .. }) => Status::invalid_argument(format!("{}: {}", context, e)),
+        Some(StatehouseError::KeyPrepared { .. }) => Status::aborted(format!("{}: {}", context, e)),
+        Some(StatehouseError::TransactionPrepared { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::TransactionNotPrepared { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::DeadlineExceeded) => Status::deadline_exceeded(format!("{}: {}", context, e)),
+        Some(StatehouseError::Overloaded { retry_after, .. }) => {
+            let mut status = Status::unavailable(format!("{}: {}", context, e));
+            status.metadata_mut().insert(RETRY_AFTER_HEADER, (retry_after.as_millis() as u64).into());
+            status
+        }
+        Some(StatehouseError::StorageCorrupt { .. }) => Status::data_loss(format!("{}: {}", context, e)),
+        Some(StatehouseError::StorageUnavailable { .. }) => Status::unavailable(format!("{}: {}", context, e)),
+        Some(StatehouseError::InvalidConfig { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        Some(StatehouseError::NamespaceNotEmpty { .. }) => Status::failed_precondition(format!("{}: {}", context, e)),
+        None => Status::internal(format!("{}: {}", context, e)),
+    }
+}
+
+/// Audit records for the records a commit changed, with their before and after versions
+fn committed_records(result: &CommitResult) -> Vec<AuditRecord> {
+    result.changed.iter().zip(&result.versions).map(|(record_id, (before, after))| AuditRecord {
+        namespace: record_id.namespace.clone(),
+        agent_id: record_id.agent_id.clone(),
+        key: record_id.key.clone(),
+        before_version: Some(*before),
+        after_version: Some(*after),
+    }).collect()
+}
+
+fn durability(durability: Durability) -> Option<statehouse_core::Durability> {
+    match durability {
+        Durability::Default => None,
+        Durability::Fsync => Some(statehouse_core::Durability::Fsync),
+        Durability::Async => Some(statehouse_core::Durability::Async),
+        Durability::Memory => Some(statehouse_core::Durability::Memory),
+    }
+}
+
+fn latency_stats(summary: LatencySummary) -> LatencyStats {
+    LatencyStats {
+        count: summary.count,
+        p50_us: summary.p50.as_micros() as u64,
+        p90_us: summary.p90.as_micros() as u64,
+        p99_us: summary.p99.as_micros() as u64,
+        max_us: summary.max.as_micros() as u64,
+    }
+}
+
+fn consistency_violation(violation: &state_machine::ConsistencyViolation) -> ConsistencyViolation {
+    let kind = match violation {
+        state_machine::ConsistencyViolation::StateVersionMismatch { .. } => "state_version_mismatch",
+        state_machine::ConsistencyViolation::CommitTsBehindEvents { .. } => "commit_ts_behind_events",
+        state_machine::ConsistencyViolation::VersionCounterMismatch { .. } => "version_counter_mismatch",
+    };
+    let (namespace, agent_id, key) = match violation.record_id() {
+        Some(record_id) => (record_id.namespace.clone(), record_id.agent_id.clone(), record_id.key.clone()),
+        None => Default::default(),
+    };
+    ConsistencyViolation {
+        kind: kind.to_string(),
+        namespace,
+        agent_id,
+        key,
+        description: violation.to_string(),
+    }
+}
+
+fn operation(op: OperationRecord) -> Operation {
+    Operation {
+        key: op.key,
+        value: op.value.map(|v| json_to_prost_types(&v)),
+        version: op.version,
+        namespace: op.namespace,
+        agent_id: op.agent_id,
+        labels: op.labels.into_iter().collect(),
+    }
+}
+
+fn schema_node(node: schema::SchemaNode) -> SchemaNode {
+    let objects = node.objects;
+    SchemaNode {
+        count: node.count,
+        types: node.types.iter().map(|t| t.as_str().to_string()).collect(),
+        // A field is optional if fewer objects had it than were seen
+        fields: node.fields.into_iter().map(|(name, field)| SchemaField {
+            name,
+            optional: field.count < objects,
+            schema: Some(schema_node(field)),
+        }).collect(),
+        items: node.items.map(|items| Box::new(schema_node(*items))),
+    }
+}
+
+fn state_entry(record: StateRecord) -> StateEntry {
+    StateEntry {
+        key: record.key,
+        value: (!record.deleted).then(|| json_to_prost_types(&record.value.unwrap_or_default())),
+        version: record.version,
+        commit_ts: record.commit_ts,
+        agent_id: record.agent_id,
+        labels: record.labels.into_iter().collect(),
+        deleted: record.deleted,
+    }
+}
+
+/// The deadline a client set on `request`, from its `grpc-timeout` header:
+/// up to 8 digits and a unit (H, M, S, m, u or n). `NONE` if there is none
+/// or it is malformed.
+fn request_deadline<T>(request: &Request<T>) -> Deadline {
+    let Some(timeout) = request.metadata().get("grpc-timeout").and_then(|value| value.to_str().ok()) else {
+        return Deadline::NONE;
+    };
+    let (digits, unit) = timeout.split_at(timeout.len().saturating_sub(1));
+    let Some(amount) = digits.parse::<u64>().ok().filter(|_| (1..=8).contains(&digits.len())) else {
+        return Deadline::NONE;
+    };
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return Deadline::NONE,
+    };
+    Deadline::after(timeout)
+}
+
+/// Reject malformed projection pointers before reading anything
+#[allow(clippy::result_large_err)]
+fn check_projection(projection: &[String]) -> Result<(), Status> {
+    for pointer in projection {
+        projection::parse_pointer(pointer).map_err(|e| Status::invalid_argument(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Apply a request's projection to a value read from storage. An empty
+/// projection returns the value whole.
+fn project_value(value: serde_json::Value, projection: &[String]) -> serde_json::Value {
+    if projection.is_empty() {
+        return value;
+    }
+    // Pointers were validated by check_projection
+    projection::project(&value, projection).unwrap_or_default()
+}
+
+// Helper functions to convert between prost_types::Struct and serde_json::Value.
+// Both walk nested structs and lists with an explicit stack instead of
+// recursing, so a deeply nested value can't overflow the call stack.
+//
+// `json -> prost -> json` gives back the same value, except where
+// google.protobuf.Value can't represent it: numbers are doubles, so
+// - integers beyond +/-2^53 are rounded to the nearest double,
+// - integral floats come back as integers (1.0 reads as 1, -0.0 as 0),
+// - NaN and infinities, which JSON can't hold, read as null.
+
+/// Largest magnitude up to which every integer is exactly a double (2^53)
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Convert a request value, rejecting it with `StatehouseError::ValueTooDeep`
+/// if structs and lists nest deeper than `max_depth`
+fn prost_types_to_json(value: &prost_types::Struct, max_depth: usize) -> Result<serde_json::Value> {
+    use prost_types::value::Kind;
+    use serde_json::Value;
+
+    type Children<'a> = Box<dyn Iterator<Item = (Option<&'a String>, &'a prost_types::Value)> + 'a>;
+
+    let too_deep = || StatehouseError::ValueTooDeep { limit: max_depth };
+    if max_depth == 0 {
+        return Err(too_deep().into());
+    }
+    fn struct_children(s: &prost_types::Struct) -> Children<'_> {
+        Box::new(s.fields.iter().map(|(k, v)| (Some(k), v)))
+    }
+
+    // One entry per struct or list being converted: its remaining children
+    // (keyed in a struct), the value built so far and the key it goes under
+    // in its parent
+    let mut stack = vec![(struct_children(value), Value::Object(serde_json::Map::new()), None)];
+    loop {
+        let depth = stack.len();
+        let (children, built, _) = stack.last_mut().unwrap();
+        let Some((key, child)) = children.next() else {
+            let (_, done, key) = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some((_, parent, _)) => insert_json(parent, key, done),
+                None => return Ok(done),
+            }
+            continue;
+        };
+
+        let (children, empty): (Children, Value) = match &child.kind {
+            Some(Kind::StructValue(s)) => (struct_children(s), Value::Object(serde_json::Map::new())),
+            Some(Kind::ListValue(l)) => (Box::new(l.values.iter().map(|v| (None, v))), Value::Array(Vec::new())),
+            kind => {
+                insert_json(built, key.cloned(), prost_scalar_to_json(kind));
+                continue;
+            }
+        };
+        if depth >= max_depth {
+            return Err(too_deep().into());
+        }
+        stack.push((children, empty, key.cloned()));
+    }
+}
+
+/// Add a converted child to the object (under `key`) or array being built
+fn insert_json(parent: &mut serde_json::Value, key: Option<String>, value: serde_json::Value) {
+    match (parent, key) {
+        (serde_json::Value::Object(map), Some(key)) => {
+            map.insert(key, value);
+        }
+        (serde_json::Value::Array(items), None) => items.push(value),
+        _ => unreachable!("struct children are keyed, list children are not"),
+    }
+}
+
+/// Drop a struct of any depth without recursing. The default drop recurses
+/// once per level, so a rejected over-deep value could still overflow the stack.
+fn drop_flat(value: prost_types::Struct) {
+    use prost_types::value::Kind;
+
+    let mut pending = vec![Kind::StructValue(value)];
+    while let Some(kind) = pending.pop() {
+        // Children are moved out first, so each node drops shallowly
+        match kind {
+            Kind::StructValue(s) => pending.extend(s.fields.into_values().filter_map(|v| v.kind)),
+            Kind::ListValue(l) => pending.extend(l.values.into_iter().filter_map(|v| v.kind)),
+            _ => {}
+        }
+    }
+}
+
+fn prost_scalar_to_json(kind: &Option<prost_types::value::Kind>) -> serde_json::Value {
+    use prost_types::value::Kind;
+
+    match kind {
+        // Integral doubles in the exact range were integers when written
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() <= MAX_EXACT_INTEGER => serde_json::json!(*n as i64),
+        Some(Kind::NumberValue(n)) => serde_json::Number::from_f64(*n).map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Some(Kind::StringValue(s)) => serde_json::json!(s),
+        Some(Kind::BoolValue(b)) => serde_json::json!(b),
+        _ => serde_json::Value::Null,
+    }
+}
+
+/// Convert a stored value. Stored values passed the depth check when they
+/// were written, so no limit is applied here.
+fn json_to_prost_types(value: &serde_json::Value) -> prost_types::Struct {
+    use prost_types::value::Kind;
+
+    type Children<'a> = Box<dyn Iterator<Item = (Option<&'a String>, &'a serde_json::Value)> + 'a>;
+
+    let serde_json::Value::Object(map) = value else {
+        return prost_types::Struct::default();
+    };
+    fn object_children(map: &serde_json::Map<String, serde_json::Value>) -> Children<'_> {
+        Box::new(map.iter().map(|(k, v)| (Some(k), v)))
+    }
+
+    // Same shape as in `prost_types_to_json`
+    let mut stack = vec![(object_children(map), Kind::StructValue(prost_types::Struct::default()), None)];
+    loop {
+        let (children, built, _) = stack.last_mut().unwrap();
+        let Some((key, child)) = children.next() else {
+            let (_, done, key) = stack.pop().unwrap();
+            match (stack.last_mut(), done) {
+                (Some((_, parent, _)), done) => insert_prost(parent, key, done),
+                (None, Kind::StructValue(converted)) => return converted,
+                (None, _) => unreachable!("the root is a struct"),
+            }
+            continue;
+        };
+
+        let (children, empty): (Children, Kind) = match child {
+            serde_json::Value::Object(child) => (object_children(child), Kind::StructValue(prost_types::Struct::default())),
+            serde_json::Value::Array(items) => (Box::new(items.iter().map(|v| (None, v))), Kind::ListValue(prost_types::ListValue::default())),
+            scalar => {
+                insert_prost(built, key.cloned(), json_scalar_to_prost(scalar));
+                continue;
+            }
+        };
+        stack.push((children, empty, key.cloned()));
+    }
+}
+
+/// Convert any JSON value, where `json_to_prost_types` only takes objects
+fn json_to_prost_value(value: &serde_json::Value) -> prost_types::Value {
+    let mut wrapped = json_to_prost_types(&serde_json::json!({ "value": value }));
+    wrapped.fields.remove("value").unwrap_or_default()
+}
+
+/// Add a converted child to the struct (under `key`) or list being built
+fn insert_prost(parent: &mut prost_types::value::Kind, key: Option<String>, kind: prost_types::value::Kind) {
+    use prost_types::value::Kind;
+
+    let value = prost_types::Value { kind: Some(kind) };
+    match (parent, key) {
+        (Kind::StructValue(s), Some(key)) => {
+            s.fields.insert(key, value);
+        }
+        (Kind::ListValue(l), None) => l.values.push(value),
+        _ => unreachable!("struct children are keyed, list children are not"),
+    }
+}
+
+fn json_scalar_to_prost(value: &serde_json::Value) -> prost_types::value::Kind {
+    use prost_types::value::Kind;
+
+    match value {
+        serde_json::Value::Bool(b) => Kind::BoolValue(*b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or(0.0)),
+        serde_json::Value::String(s) => Kind::StringValue(s.clone()),
+        _ => Kind::NullValue(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use statehouse_core::admission::{AdmissionLimits, MIN_RETRY_AFTER};
+    use statehouse_core::storage::{CompactionStats, EventLogEntry, InMemoryStorage, NamespaceUsage, PurgeStats, RenameStats, RocksStorage, Snapshot, Storage, StorageConfig};
+    use statehouse_core::{AgentId, CommitTs, Durability, Version};
+    use statehouse_proto::statehouse_service_server::StatehouseService;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// In-memory storage whose prefix scans take at least `delay` and
+    /// commits at least `commit_delay`, counting the records read by paged
+    /// scans, and that runs `before_read` ahead of the next `read_state`
+    #[derive(Default)]
+    struct SlowStorage {
+        inner: InMemoryStorage,
+        delay: Duration,
+        commit_delay: Duration,
+        scanned: AtomicUsize,
+        before_read: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    }
+
+    impl Storage for SlowStorage {
+        fn health_check(&self) -> Result<()> { self.inner.health_check() }
+        fn write_state(&self, record: StateRecord) -> Result<()> { self.inner.write_state(record) }
+        fn read_state(&self, record_id: &RecordId) -> Result<Option<StateRecord>> {
+            let before_read = self.before_read.lock().unwrap().take();
+            if let Some(before_read) = before_read {
+                before_read();
+            }
+            self.inner.read_state(record_id)
+        }
+        fn exists(&self, record_id: &RecordId) -> Result<bool> { self.inner.exists(record_id) }
+        fn read_state_meta(&self, record_id: &RecordId) -> Result<Option<StateMeta>> { self.inner.read_state_meta(record_id) }
+        fn read_states(&self, record_ids: &[RecordId]) -> Result<Vec<Option<StateRecord>>> { self.inner.read_states(record_ids) }
+        fn read_state_at_version(&self, record_id: &RecordId, version: Version) -> Result<Option<StateRecord>> { self.inner.read_state_at_version(record_id, version) }
+        fn read_version_history(&self, record_id: &RecordId, limit: usize) -> Result<Vec<StateRecord>> { self.inner.read_version_history(record_id, limit) }
+        fn read_state_as_of(&self, record_id: &RecordId, as_of: CommitTs) -> Result<Option<StateRecord>> { self.inner.read_state_as_of(record_id, as_of) }
+        fn list_keys(&self, namespace: &str, agent_id: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<String>> { self.inner.list_keys(namespace, agent_id, include_deleted, deadline) }
+        fn count_keys(&self, namespace: &str, agent_id: &str, prefix: &str) -> Result<u64> { self.inner.count_keys(namespace, agent_id, prefix) }
+        fn scan_prefix(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+            std::thread::sleep(self.delay);
+            self.inner.scan_prefix(namespace, agent_id, prefix, include_deleted, deadline)
+        }
+        fn scan_prefix_consistent(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, deadline: Deadline) -> Result<Vec<StateRecord>> {
+            std::thread::sleep(self.delay);
+            self.inner.scan_prefix_consistent(namespace, agent_id, prefix, include_deleted, deadline)
+        }
+        fn scan_prefix_page(&self, namespace: &str, agent_id: &str, prefix: &str, include_deleted: bool, start_after: Option<&str>, limit: usize) -> Result<Vec<StateRecord>> {
+            let records = self.inner.scan_prefix_page(namespace, agent_id, prefix, include_deleted, start_after, limit)?;
+            self.scanned.fetch_add(records.len(), Ordering::Relaxed);
+            Ok(records)
+        }
+        fn scan_namespace_prefix(&self, namespace: &str, prefix: &str, limit: usize) -> Result<Vec<StateRecord>> { self.inner.scan_namespace_prefix(namespace, prefix, limit) }
+        fn append_event(&self, event: EventLogEntry) -> Result<()> { self.inner.append_event(event) }
+        fn replay_events(&self, namespace: &str, agent_id: &str, start_ts: Option<CommitTs>, end_ts: Option<CommitTs>, deadline: Deadline) -> Result<Vec<EventLogEntry>> { self.inner.replay_events(namespace, agent_id, start_ts, end_ts, deadline) }
+        fn next_commit_ts(&self) -> Result<CommitTs> { self.inner.next_commit_ts() }
+        fn current_commit_ts(&self) -> Result<CommitTs> { self.inner.current_commit_ts() }
+        fn advance_commit_ts(&self, commit_ts: CommitTs) -> Result<()> { self.inner.advance_commit_ts(commit_ts) }
+        fn reserve_commit_ts_block(&self, n: u64) -> Result<(CommitTs, CommitTs)> { self.inner.reserve_commit_ts_block(n) }
+        fn next_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.inner.next_namespace_ts(namespace) }
+        fn advance_namespace_ts(&self, namespace: &str, namespace_ts: CommitTs) -> Result<()> { self.inner.advance_namespace_ts(namespace, namespace_ts) }
+        fn current_namespace_ts(&self, namespace: &str) -> Result<CommitTs> { self.inner.current_namespace_ts(namespace) }
+        fn flush(&self) -> Result<()> { self.inner.flush() }
+        fn supports_durability(&self, durability: Durability) -> bool { self.inner.supports_durability(durability) }
+        fn sync_commit(&self, durability: Option<Durability>) -> Result<()> {
+            std::thread::sleep(self.commit_delay);
+            self.inner.sync_commit(durability)
+        }
+        fn flush_deferred(&self) -> Result<()> { self.inner.flush_deferred() }
+        fn create_snapshot(&self) -> Result<Snapshot> { self.inner.create_snapshot() }
+        fn create_snapshot_at(&self, snapshot_ts: CommitTs) -> Result<Snapshot> { self.inner.create_snapshot_at(snapshot_ts) }
+        fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> { self.inner.save_snapshot(snapshot) }
+        fn load_snapshot(&self) -> Result<Option<Snapshot>> { self.inner.load_snapshot() }
+        fn get_all_state(&self) -> Result<Vec<StateRecord>> { self.inner.get_all_state() }
+        fn create_snapshot_for_namespace(&self, namespace: &str) -> Result<Snapshot> { self.inner.create_snapshot_for_namespace(namespace) }
+        fn save_snapshot_for_namespace(&self, snapshot: &Snapshot) -> Result<()> { self.inner.save_snapshot_for_namespace(snapshot) }
+        fn load_snapshot_for_namespace(&self, namespace: &str) -> Result<Option<Snapshot>> { self.inner.load_snapshot_for_namespace(namespace) }
+        fn restore_namespace_snapshot(&self, snapshot: &Snapshot) -> Result<()> { self.inner.restore_namespace_snapshot(snapshot) }
+        fn compact_history(&self, up_to_ts: CommitTs, keep_versions: usize) -> Result<CompactionStats> { self.inner.compact_history(up_to_ts, keep_versions) }
+        fn trim_events(&self, up_to_ts: CommitTs) -> Result<CompactionStats> { self.inner.trim_events(up_to_ts) }
+        fn list_tombstones(&self, up_to_ts: CommitTs) -> Result<Vec<StateRecord>> { self.inner.list_tombstones(up_to_ts) }
+        fn purge_tombstones(&self, tombstones: &[StateRecord]) -> Result<CompactionStats> { self.inner.purge_tombstones(tombstones) }
+        fn list_expired(&self, now_ms: u64) -> Result<Vec<RecordId>> { self.inner.list_expired(now_ms) }
+        fn purge_key(&self, record_id: &RecordId) -> Result<PurgeStats> { self.inner.purge_key(record_id) }
+        fn rename_namespace(&self, from: &str, to: &str, merge: bool) -> Result<RenameStats> { self.inner.rename_namespace(from, to, merge) }
+        fn last_event_ts(&self) -> Result<Option<CommitTs>> { self.inner.last_event_ts() }
+        fn read_event(&self, commit_ts: CommitTs) -> Result<Option<EventLogEntry>> { self.inner.read_event(commit_ts) }
+        fn read_events(&self, from_ts: CommitTs, limit: usize) -> Result<Vec<EventLogEntry>> { self.inner.read_events(from_ts, limit) }
+        fn count_events(&self, from_ts: CommitTs) -> Result<u64> { self.inner.count_events(from_ts) }
+        fn version_bounds(&self, record_id: &RecordId) -> Result<Option<(Version, Version)>> { self.inner.version_bounds(record_id) }
+        fn namespace_usage(&self, namespace: &str) -> Result<NamespaceUsage> { self.inner.namespace_usage(namespace) }
+        fn least_recent_keys(&self, namespace: &str, limit: usize) -> Result<Vec<RecordId>> { self.inner.least_recent_keys(namespace, limit) }
+        fn version_counts(&self, namespace: &str, deadline: Deadline) -> Result<Vec<(RecordId, u64)>> { self.inner.version_counts(namespace, deadline) }
+        fn agent_last_commit_ts(&self, namespace: &str, agent_id: &str) -> Result<Option<CommitTs>> { self.inner.agent_last_commit_ts(namespace, agent_id) }
+    }
+
+    /// Collects formatted log output
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run one ScanPrefix against storage delayed by `delay` and return the logs
+    fn scan_prefix_logs(delay: Duration, threshold: Duration) -> String {
+        let storage = Arc::new(SlowStorage { delay, ..Default::default() });
+        let service = StatehouseServiceImpl::new(Arc::new(StateMachine::new(storage)))
+            .with_slow_op_threshold(threshold);
+
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let agent_id: AgentId = "agent-1".to_string();
+        let request = Request::new(ScanPrefixRequest {
+            namespace: "default".to_string(),
+            agent_id,
+            prefix: "draft:".to_string(),
+            projection: Vec::new(),
+            label_filter: Default::default(),
+            include_deleted: false,
+            limit: 0,
+            cursor: None,
+            consistent: false,
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(service.scan_prefix(request)).unwrap();
+
+        let bytes = logs.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_deadline_aborts_slow_scan() {
+        let storage = Arc::new(SlowStorage { delay: Duration::from_millis(200), ..Default::default() });
+        let sm = Arc::new(StateMachine::new(storage));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for i in 0..1000 {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), format!("item:{:04}", i), serde_json::json!(i)).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let scan = |timeout: Option<&str>| {
+            let mut request = Request::new(ScanPrefixRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                prefix: "item:".to_string(),
+                projection: Vec::new(),
+                label_filter: Default::default(),
+                include_deleted: false,
+                limit: 0,
+                cursor: None,
+                consistent: false,
+            });
+            if let Some(timeout) = timeout {
+                request.metadata_mut().insert("grpc-timeout", timeout.parse().unwrap());
+            }
+            runtime
+                .block_on(service.scan_prefix(request))
+                .map(|response| response.into_inner().entries.len())
+                .map_err(|status| status.code())
+        };
+
+        // The client gave up while storage was still busy: the scan stops at
+        // its first check instead of reading every record
+        assert_eq!(scan(Some("50m")), Err(tonic::Code::DeadlineExceeded));
+
+        assert_eq!(scan(Some("10S")), Ok(1000));
+        assert_eq!(scan(None), Ok(1000));
+        // A malformed header is ignored rather than failing the read
+        assert_eq!(scan(Some("soon")), Ok(1000));
+    }
+
+    #[test]
+    fn test_admission_control_sheds_load_and_recovers() {
+        let storage = Arc::new(SlowStorage { commit_delay: Duration::from_millis(50), ..Default::default() });
+        let limits = AdmissionLimits { max_backlog: Some(Duration::from_millis(100)), ..AdmissionLimits::default() };
+        let sm = Arc::new(StateMachine::new(storage).with_admission_limits(limits));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let begin = || {
+            let request = Request::new(BeginTransactionRequest { txn_id: None, timeout_ms: None });
+            runtime.block_on(service.begin_transaction(request)).map(|response| response.into_inner().txn_id).map_err(Box::new)
+        };
+        let write = |txn_id: &str| {
+            let request = Request::new(WriteRequest {
+                txn_id: txn_id.to_string(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: format!("key-{}", txn_id),
+                value: Some(json_to_prost_types(&serde_json::json!({ "n": 1 }))),
+                labels: Default::default(),
+                client_seq: None,
+                if_match_etag: None,
+                ttl_ms: None,
+            });
+            runtime.block_on(service.write(request)).map(|_| ()).map_err(Box::new)
+        };
+
+        // One commit teaches admission control how slow storage is
+        let txn_id = begin().unwrap();
+        write(&txn_id).unwrap();
+        sm.commit(&txn_id).unwrap();
+        assert!(sm.backlog().avg_commit_time >= Duration::from_millis(50));
+
+        // Open transactions pile up until committing them would take too long
+        let mut open = Vec::new();
+        let status = loop {
+            match begin().and_then(|txn_id| write(&txn_id).map(|()| txn_id)) {
+                Ok(txn_id) => open.push(txn_id),
+                Err(status) => break status,
+            }
+            assert!(open.len() < 10, "admission control never refused new work");
+        };
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let retry_after: u64 = status.metadata().get(RETRY_AFTER_HEADER).unwrap().to_str().unwrap().parse().unwrap();
+        assert!(retry_after >= MIN_RETRY_AFTER.as_millis() as u64);
+        assert_eq!(begin().unwrap_err().code(), tonic::Code::Unavailable);
+
+        let backlog = runtime.block_on(service.get_backlog(Request::new(GetBacklogRequest {}))).unwrap().into_inner();
+        assert!(backlog.open_transactions >= open.len() as u64);
+        assert!(backlog.estimated_drain_ms >= 100);
+        assert_eq!((backlog.rejected, backlog.max_backlog_ms), (2, Some(100)));
+
+        // Commits are always admitted, and draining the backlog lets new work in
+        for txn_id in sm.list_open_transactions().into_iter().map(|txn| txn.txn_id) {
+            sm.commit(&txn_id).unwrap();
+        }
+        assert_eq!(sm.backlog().open_transactions, 0);
+        let txn_id = begin().unwrap();
+        write(&txn_id).unwrap();
+    }
+
+    #[test]
+    fn test_request_deadline_parses_grpc_timeout() {
+        let deadline = |timeout: &str| {
+            let mut request = Request::new(());
+            request.metadata_mut().insert("grpc-timeout", timeout.parse().unwrap());
+            request_deadline(&request)
+        };
+        assert_eq!(request_deadline(&Request::new(())), Deadline::NONE);
+        for timeout in ["", "S", "5", "5s", "123456789S", "-5S"] {
+            assert_eq!(deadline(timeout), Deadline::NONE, "{:?}", timeout);
+        }
+        for timeout in ["1H", "30M", "5S"] {
+            assert!(!deadline(timeout).is_expired(), "{:?}", timeout);
+        }
+        let short = deadline("1n");
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(short.is_expired());
+    }
+
+    #[test]
+    fn test_slow_reads_are_logged() {
+        let logs = scan_prefix_logs(Duration::from_millis(50), Duration::from_millis(10));
+        assert!(logs.contains("Slow read"), "{}", logs);
+        assert!(logs.contains("op=\"ScanPrefix\""));
+        assert!(logs.contains("key_or_prefix=draft:"));
+        assert!(logs.contains("results=0"));
+
+        let logs = scan_prefix_logs(Duration::ZERO, Duration::from_millis(500));
+        assert!(!logs.contains("Slow read"), "{}", logs);
+    }
+
+    #[test]
+    fn test_get_state_distinguishes_tombstones() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "live".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "gone".to_string(), serde_json::json!({"value": 2})).unwrap();
+        sm.commit(&txn_id).unwrap();
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.delete(&txn_id, "default".to_string(), "agent-1".to_string(), "gone".to_string()).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let get = |key: &str| {
+            let request = Request::new(GetStateRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: key.to_string(),
+                projection: Vec::new(),
+            });
+            runtime.block_on(service.get_state(request)).unwrap().into_inner()
+        };
+
+        let live = get("live");
+        assert!(live.exists && !live.tombstoned);
+        assert_eq!(live.version, 1);
+
+        let gone = get("gone");
+        assert!(!gone.exists && gone.tombstoned);
+        assert_eq!(gone.version, 2);
+
+        let never = get("never");
+        assert!(!never.exists && !never.tombstoned);
+        assert_eq!(never.version, 0);
+
+        let value_bytes = serde_json::to_vec(&serde_json::json!({"value": 1})).unwrap().len() as u64;
+        assert_eq!(live.value_bytes, value_bytes);
+        assert_eq!(gone.value_bytes, 0);
+
+        // The metadata RPC agrees with GetState without returning the value
+        let get_meta = |key: &str| {
+            let request = Request::new(GetStateMetaRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: key.to_string(),
+            });
+            runtime.block_on(service.get_state_meta(request)).unwrap().into_inner()
+        };
+        let meta = get_meta("live");
+        assert!(meta.exists && !meta.tombstoned);
+        assert_eq!((meta.version, meta.commit_ts, meta.value_bytes), (live.version, live.commit_ts, value_bytes));
+        let meta = get_meta("gone");
+        assert!(!meta.exists && meta.tombstoned);
+        assert_eq!(meta.version, 2);
+        assert_eq!(get_meta("never"), GetStateMetaResponse::default());
+    }
+
+    #[test]
+    fn test_snapshot_now_writes_snapshot_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            read_cache_capacity: 0,
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        let sm = Arc::new(StateMachine::new(Arc::new(storage)));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for key in ["a", "b", "c"] {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(key)).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm).with_admin_token(Some("secret".to_string()));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let status = runtime.block_on(service.snapshot(Request::new(SnapshotRequest {}))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let mut request = Request::new(SnapshotRequest {});
+        request.metadata_mut().insert(ADMIN_TOKEN_HEADER, "secret".parse().unwrap());
+        let response = runtime.block_on(service.snapshot(request)).unwrap().into_inner();
+        assert_eq!(response.snapshot_ts, 1);
+        assert_eq!(response.record_count, 3);
+
+        let json = std::fs::read_to_string(temp_dir.path().join("snapshot.json")).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.metadata.snapshot_ts, 1);
+        assert_eq!(snapshot.metadata.record_count, 3);
+        assert_eq!(snapshot.records.len(), 3);
+    }
+
+    #[test]
+    fn test_committed_and_rejected_writes_are_audited() {
+        use crate::audit::JsonlAuditSink;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("audit.jsonl");
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone())
+            .with_audit_sink(Arc::new(JsonlAuditSink::open(&path, true).unwrap()));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        fn as_alice<T>(mut request: Request<T>) -> Request<T> {
+            request.extensions_mut().insert(AuthIdentity("alice".to_string()));
+            request
+        }
+        let write = |txn_id: &str| {
+            let request = as_alice(Request::new(WriteRequest {
+                txn_id: txn_id.to_string(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: "key".to_string(),
+                value: Some(prost_types::Struct::default()),
+                labels: Default::default(),
+                client_seq: None,
+                if_match_etag: None,
+                ttl_ms: None,
+            }));
+            runtime.block_on(service.write(request)).map(|_| ()).map_err(|status| status.code())
+        };
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        write(&txn_id).unwrap();
+        let commit = as_alice(Request::new(CommitRequest { txn_id: txn_id.clone(), ..Default::default() }));
+        runtime.block_on(service.commit(commit)).unwrap();
+        write("no-such-txn").unwrap_err();
+
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+
+        let staged = &entries[0];
+        assert_eq!(staged["operation"], "Write");
+        assert_eq!(staged["identity"], "alice");
+        assert_eq!(staged["txn_id"], txn_id.as_str());
+        assert_eq!(staged["records"][0]["before_version"], 0);
+        assert!(staged["error"].is_null());
+        assert!(staged["timestamp_ms"].as_u64().unwrap() > 0);
+
+        let committed = &entries[1];
+        assert_eq!(committed["operation"], "Commit");
+        assert_eq!(committed["commit_ts"], 1);
+        assert_eq!(committed["records"][0]["key"], "key");
+        assert_eq!(committed["records"][0]["before_version"], 0);
+        assert_eq!(committed["records"][0]["after_version"], 1);
+
+        let rejected = &entries[2];
+        assert_eq!(rejected["operation"], "Write");
+        assert_eq!(rejected["identity"], "alice");
+        assert!(rejected["error"].as_str().unwrap().contains("not found"), "{}", rejected);
+    }
+
+    #[test]
+    fn test_stream_transaction() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let stream = |commands: Vec<Command>| {
+            let inbound = tokio_stream::iter(commands.into_iter().map(|command| StreamTransactionRequest { command: Some(command) }).map(Ok));
+            let (tx, rx) = mpsc::channel(16);
+            runtime.block_on(async {
+                tokio::spawn(service.clone().run_stream_transaction(None, inbound, tx));
+                ReceiverStream::new(rx).collect::<Vec<_>>().await
+            })
+        };
+        let write = |key: String, i: u64| Command::Write(StreamWrite {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key,
+            value: Some(json_to_prost_types(&serde_json::json!({"i": i}))),
+            labels: Default::default(),
+            client_seq: None,
+            if_match_etag: None,
+        });
+
+        let mut commands = vec![Command::Begin(BeginTransactionRequest::default())];
+        commands.extend((0..1000).map(|i| write(format!("key-{:04}", i), i)));
+        commands.push(Command::Commit(StreamCommit {}));
+        let acks: Vec<StreamTransactionResponse> = stream(commands).into_iter().map(|ack| ack.unwrap()).collect();
+
+        assert_eq!(acks.len(), 1002);
+        assert!(acks.iter().zip(1..).all(|(ack, seq)| ack.seq == seq && ack.txn_id == acks[0].txn_id));
+        let commit_ts = acks[1001].commit_ts.unwrap();
+        assert!(acks[..1001].iter().all(|ack| ack.commit_ts.is_none()));
+        assert_eq!(sm.list_keys("default", "agent-1", false, Deadline::NONE).unwrap().len(), 1000);
+        let last = sm.get_state("default", "agent-1", "key-0999").unwrap().unwrap();
+        assert_eq!(last.value, Some(serde_json::json!({"i": 999})));
+        assert_eq!(last.commit_ts, commit_ts);
+
+        // A stream that ends without committing aborts its transaction
+        let acks = stream(vec![Command::Begin(BeginTransactionRequest::default()), write("orphan".to_string(), 0)]);
+        assert_eq!(acks.len(), 2);
+        assert!(sm.list_open_transactions().is_empty());
+        assert!(sm.get_state("default", "agent-1", "orphan").unwrap().is_none());
+
+        // Staging before begin fails the stream
+        let acks = stream(vec![write("early".to_string(), 0), Command::Commit(StreamCommit {})]);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].as_ref().unwrap_err().code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn test_stream_transaction_ignores_replayed_client_seq() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let write = |key: &str, i: u64, client_seq: u64| Command::Write(StreamWrite {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: key.to_string(),
+            value: Some(json_to_prost_types(&serde_json::json!({"i": i}))),
+            labels: Default::default(),
+            client_seq: Some(client_seq),
+            if_match_etag: None,
+        });
+
+        // The client resends writes 1 and 2 after a dropped ack; the retry of
+        // 2 carries a value that must not replace the first
+        let commands = vec![
+            Command::Begin(BeginTransactionRequest::default()),
+            write("a", 1, 1),
+            write("b", 1, 2),
+            write("a", 1, 1),
+            write("b", 2, 2),
+            write("c", 1, 3),
+            Command::Commit(StreamCommit {}),
+        ];
+        let inbound = tokio_stream::iter(commands.into_iter().map(|command| StreamTransactionRequest { command: Some(command) }).map(Ok));
+        let (tx, rx) = mpsc::channel(16);
+        let acks: Vec<StreamTransactionResponse> = runtime.block_on(async {
+            tokio::spawn(service.clone().run_stream_transaction(None, inbound, tx));
+            ReceiverStream::new(rx).collect::<Vec<_>>().await
+        }).into_iter().map(|ack| ack.unwrap()).collect();
+
+        let duplicates: Vec<bool> = acks.iter().map(|ack| ack.duplicate).collect();
+        assert_eq!(duplicates, vec![false, false, false, true, true, false, false]);
+        for key in ["a", "b", "c"] {
+            assert_eq!(sm.get_version_history("default", "agent-1", key, 10).unwrap().len(), 1);
+            assert_eq!(sm.get_state("default", "agent-1", key).unwrap().unwrap().value, Some(serde_json::json!({"i": 1})));
+        }
+    }
+
+    #[test]
+    fn test_stream_transaction_resumes_after_a_broken_stream() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        // Sends `commands`, then `broken` as a transport error if set
+        let stream = |commands: Vec<Command>, broken: Option<Status>| {
+            let requests = commands
+                .into_iter()
+                .map(|command| StreamTransactionRequest { command: Some(command) })
+                .map(Ok)
+                .chain(broken.map(Err));
+            let (tx, rx) = mpsc::channel(16);
+            runtime.block_on(async {
+                tokio::spawn(service.clone().run_stream_transaction(None, tokio_stream::iter(requests), tx));
+                ReceiverStream::new(rx).collect::<Vec<_>>().await
+            })
+        };
+        let write = |key: &str, client_seq: u64| Command::Write(StreamWrite {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: key.to_string(),
+            value: Some(json_to_prost_types(&serde_json::json!({"seq": client_seq}))),
+            labels: Default::default(),
+            client_seq: Some(client_seq),
+            if_match_etag: None,
+        });
+
+        // The connection drops after two writes
+        let acks = stream(
+            vec![Command::Begin(BeginTransactionRequest::default()), write("a", 1), write("b", 2)],
+            Some(Status::unavailable("connection reset")),
+        );
+        assert_eq!(acks.len(), 3);
+        let txn_id = acks[0].as_ref().unwrap().txn_id.clone();
+        assert_eq!(sm.list_open_transactions().len(), 1);
+
+        // The client never saw the ack for b, so resends it on a new stream
+        let acks: Vec<StreamTransactionResponse> = stream(
+            vec![Command::Resume(txn_id), write("b", 2), write("c", 3), Command::Commit(StreamCommit {})],
+            None,
+        ).into_iter().map(|ack| ack.unwrap()).collect();
+        let duplicates: Vec<bool> = acks.iter().map(|ack| ack.duplicate).collect();
+        assert_eq!(duplicates, vec![false, true, false, false]);
+        assert!(acks[3].commit_ts.is_some());
+        for key in ["a", "b", "c"] {
+            assert_eq!(sm.get_version_history("default", "agent-1", key, 10).unwrap().len(), 1);
+        }
+        assert!(sm.list_open_transactions().is_empty());
+    }
+
+    #[test]
+    fn test_deeply_nested_value_is_rejected() {
+        use prost_types::value::Kind;
+
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let mut nested = prost_types::Struct::default();
+        for _ in 0..10_000 {
+            let child = prost_types::Value { kind: Some(Kind::StructValue(nested)) };
+            nested = prost_types::Struct { fields: [("child".to_string(), child)].into() };
+        }
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        let request = Request::new(WriteRequest {
+            txn_id: txn_id.clone(),
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "deep".to_string(),
+            value: Some(nested),
+            labels: Default::default(),
+            client_seq: None,
+            if_match_etag: None,
+            ttl_ms: None,
+        });
+        let status = runtime.block_on(service.write(request)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("max_json_depth"), "{}", status.message());
+        assert_eq!(sm.list_open_transactions()[0].staged_ops, 0);
+
+        // Values within the limit convert both ways unchanged
+        let value = serde_json::json!({"a": {"b": {"c": [1, 2.5, "x", true, null]}}, "d": {}});
+        assert_eq!(prost_types_to_json(&json_to_prost_types(&value), 4).unwrap(), value);
+        assert!(prost_types_to_json(&json_to_prost_types(&value), 3).is_err());
+    }
+
+    /// Any JSON value the conversion must round-trip exactly: integers within
+    /// +/-2^53, non-integral finite floats, and nested arrays and objects
+    fn arb_json() -> impl proptest::strategy::Strategy<Value = serde_json::Value> {
+        use proptest::prelude::*;
+
+        let exact = MAX_EXACT_INTEGER as i64;
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            (-exact..=exact).prop_map(serde_json::Value::from),
+            (-1e12f64..1e12).prop_filter("integral floats read back as integers", |f| f.fract() != 0.0).prop_map(serde_json::Value::from),
+            ".{0,8}".prop_map(serde_json::Value::from),
+        ];
+        leaf.prop_recursive(8, 128, 6, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..6).prop_map(serde_json::Value::Array),
+                prop::collection::btree_map(".{0,8}", inner, 0..6).prop_map(|m| serde_json::Value::Object(m.into_iter().collect())),
+            ]
+        })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_json_prost_round_trip(fields in proptest::collection::btree_map(".{0,8}", arb_json(), 0..6)) {
+            let value = serde_json::Value::Object(fields.into_iter().collect());
+            let converted = prost_types_to_json(&json_to_prost_types(&value), usize::MAX).unwrap();
+            proptest::prop_assert_eq!(converted, value);
+        }
+    }
+
+    #[test]
+    fn test_json_prost_lossy_cases() {
+        use prost_types::value::Kind;
+
+        let number = |n: f64| prost_scalar_to_json(&Some(Kind::NumberValue(n)));
+        assert_eq!(number(f64::NAN), serde_json::Value::Null);
+        assert_eq!(number(f64::INFINITY), serde_json::Value::Null);
+        assert_eq!(number(1.0), serde_json::json!(1));
+        assert_eq!(number(-0.0), serde_json::json!(0));
+        // Past 2^53 a double no longer holds every integer
+        assert_eq!(number(MAX_EXACT_INTEGER), serde_json::json!(9_007_199_254_740_992u64));
+        assert_eq!(number(1e20), serde_json::json!(1e20));
+
+        let value = serde_json::json!({"big": u64::MAX});
+        assert_ne!(prost_types_to_json(&json_to_prost_types(&value), usize::MAX).unwrap(), value);
+    }
+
+    #[test]
+    fn test_low_disk_space_makes_server_read_only() {
+        use crate::disk_guard::FreeSpaceProvider;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        struct FixedFreeSpace(Arc<AtomicU64>);
+        impl FreeSpaceProvider for FixedFreeSpace {
+            fn free_bytes(&self) -> Result<u64> {
+                Ok(self.0.load(Ordering::Relaxed))
+            }
+        }
+
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key".to_string(), serde_json::json!({"value": 1})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let free = Arc::new(AtomicU64::new(0));
+        let guard = Arc::new(DiskGuard::new(Box::new(FixedFreeSpace(free.clone())), 1_000));
+        let service = StatehouseServiceImpl::new(sm.clone()).with_disk_guard(guard.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        assert!(guard.check());
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        let write = || {
+            let request = Request::new(WriteRequest {
+                txn_id: txn_id.clone(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: "key".to_string(),
+                value: Some(prost_types::Struct::default()),
+                labels: Default::default(),
+                client_seq: None,
+                if_match_etag: None,
+                ttl_ms: None,
+            });
+            runtime.block_on(service.write(request)).map(|_| ()).map_err(|status| status.code())
+        };
+        let commit = || {
+            let request = Request::new(CommitRequest { txn_id: txn_id.clone(), ..Default::default() });
+            runtime.block_on(service.commit(request)).map(|_| ()).map_err(|status| status.code())
+        };
+        let health = || runtime.block_on(service.health(Request::new(HealthRequest {}))).unwrap().into_inner();
+
+        assert_eq!(write(), Err(tonic::Code::ResourceExhausted));
+        assert_eq!(commit(), Err(tonic::Code::ResourceExhausted));
+        assert_eq!(health().status, "read_only");
+        assert!(health().read_only);
+
+        // Reads are still served
+        let request = Request::new(GetStateRequest {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "key".to_string(),
+            projection: Vec::new(),
+        });
+        assert!(runtime.block_on(service.get_state(request)).unwrap().into_inner().exists);
+
+        // Writes resume once space is freed
+        free.store(1_000, Ordering::Relaxed);
+        assert!(!guard.check());
+        assert_eq!(write(), Ok(()));
+        assert_eq!(commit(), Ok(()));
+        assert_eq!(health().status, "ok");
+        assert!(!health().read_only);
+    }
+
+    #[test]
+    fn test_read_only_replica_follows_primary() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            read_cache_capacity: 16,
+            ..StorageConfig::default()
+        };
+        let primary = StateMachine::new(Arc::new(RocksStorage::new(config.clone()).unwrap()));
+        let put = |value: serde_json::Value| {
+            let txn_id = primary.begin_transaction(None).unwrap();
+            primary.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key".to_string(), value).unwrap();
+            primary.commit(&txn_id).unwrap();
+        };
+        put(serde_json::json!({"value": 1}));
+
+        let secondary_dir = tempfile::TempDir::new().unwrap();
+        let replica = Arc::new(RocksStorage::open_read_only(config, secondary_dir.path()).unwrap());
+        let service = StatehouseServiceImpl::new(Arc::new(StateMachine::new(replica.clone()))).with_read_only_replica(true);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let get = || {
+            let request = Request::new(GetStateRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: "key".to_string(),
+                projection: Vec::new(),
+            });
+            let value = runtime.block_on(service.get_state(request)).unwrap().into_inner().value.unwrap();
+            prost_types_to_json(&value, usize::MAX).unwrap()
+        };
+        assert_eq!(get(), serde_json::json!({"value": 1}));
+
+        let status = runtime.block_on(service.begin_transaction(Request::new(BeginTransactionRequest::default()))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        let request = Request::new(WriteRequest {
+            txn_id: "txn".to_string(),
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "key".to_string(),
+            value: Some(prost_types::Struct::default()),
+            labels: Default::default(),
+            client_seq: None,
+            if_match_etag: None,
+            ttl_ms: None,
+        });
+        assert_eq!(runtime.block_on(service.write(request)).unwrap_err().code(), tonic::Code::FailedPrecondition);
+        let request = Request::new(CommitRequest { txn_id: "txn".to_string(), ..Default::default() });
+        assert_eq!(runtime.block_on(service.commit(request)).unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        // The primary's later commits show up once the replica catches up
+        put(serde_json::json!({"value": 2}));
+        assert_eq!(get(), serde_json::json!({"value": 1}));
+        assert_eq!(replica.catch_up_with_primary().unwrap(), 2);
+        assert_eq!(get(), serde_json::json!({"value": 2}));
+    }
+
+    #[test]
+    fn test_max_page_size_clamps_with_resumable_cursor() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone()).with_max_page_size(Some(3));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for i in 0..8 {
+            let labels: Labels = [("parity".to_string(), (i % 2).to_string())].into_iter().collect();
+            sm.write_with_labels(&txn_id, "default".to_string(), "agent-1".to_string(), format!("k{}", i), serde_json::json!(i), labels).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        let scan = |label_filter: HashMap<String, String>| -> (Vec<usize>, Vec<String>) {
+            let mut pages = Vec::new();
+            let mut keys = Vec::new();
+            let mut cursor = None;
+            loop {
+                let request = Request::new(ScanPrefixRequest {
+                    namespace: "default".to_string(),
+                    agent_id: "agent-1".to_string(),
+                    prefix: "k".to_string(),
+                    projection: Vec::new(),
+                    label_filter: label_filter.clone(),
+                    include_deleted: false,
+                    limit: 100,
+                    cursor,
+                    consistent: false,
+                });
+                let response = runtime.block_on(service.scan_prefix(request)).unwrap().into_inner();
+                pages.push(response.entries.len());
+                keys.extend(response.entries.into_iter().map(|entry| entry.key));
+                match response.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => return (pages, keys),
+                }
+            }
+        };
+        let all: Vec<String> = (0..8).map(|i| format!("k{}", i)).collect();
+        assert_eq!(scan(HashMap::new()), (vec![3, 3, 2], all.clone()));
+        let odd = [("parity".to_string(), "1".to_string())].into_iter().collect();
+        assert_eq!(scan(odd), (vec![3, 1], vec!["k1".to_string(), "k3".to_string(), "k5".to_string(), "k7".to_string()]));
+
+        // A limit under the cap is kept, and an unset one is capped too
+        let mut cursor = None;
+        let mut keys = Vec::new();
+        for expected in [2, 2, 2, 2] {
+            let request = Request::new(ListKeysRequest { namespace: "default".to_string(), agent_id: "agent-1".to_string(), include_deleted: false, limit: 2, cursor: cursor.take() });
+            let response = runtime.block_on(service.list_keys(request)).unwrap().into_inner();
+            assert_eq!(response.keys.len(), expected);
+            keys.extend(response.keys);
+            cursor = response.next_cursor;
+        }
+        assert_eq!((keys, cursor), (all, None));
+        let request = Request::new(ListKeysRequest { namespace: "default".to_string(), agent_id: "agent-1".to_string(), include_deleted: false, limit: 0, cursor: None });
+        let response = runtime.block_on(service.list_keys(request)).unwrap().into_inner();
+        assert_eq!((response.keys.len(), response.next_cursor.as_deref()), (3, Some("k2")));
+    }
+
+    #[test]
+    fn test_labels_round_trip_and_filter_scans() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let labels = |pairs: &[(&str, &str)]| -> HashMap<String, String> { pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() };
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for (key, source) in [("note/1", "llm"), ("note/2", "user"), ("note/3", "llm")] {
+            let request = Request::new(WriteRequest {
+                txn_id: txn_id.clone(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: key.to_string(),
+                value: Some(prost_types::Struct::default()),
+                labels: labels(&[("source", source)]),
+                client_seq: None,
+                if_match_etag: None,
+                ttl_ms: None,
+            });
+            runtime.block_on(service.write(request)).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        let request = Request::new(GetStateRequest {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "note/2".to_string(),
+            projection: Vec::new(),
+        });
+        let response = runtime.block_on(service.get_state(request)).unwrap().into_inner();
+        assert_eq!(response.labels, labels(&[("source", "user")]));
+
+        let request = Request::new(ScanPrefixRequest {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            prefix: "note/".to_string(),
+            projection: Vec::new(),
+            label_filter: labels(&[("source", "llm")]),
+            include_deleted: false,
+            limit: 0,
+            cursor: None,
+            consistent: false,
+        });
+        let entries = runtime.block_on(service.scan_prefix(request)).unwrap().into_inner().entries;
+        let mut keys: Vec<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["note/1", "note/3"]);
+        assert!(entries.iter().all(|entry| entry.labels == labels(&[("source", "llm")])));
+    }
+
+    #[test]
+    fn test_replay_resumes_after_token() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        for namespace_sequences in [false, true] {
+            let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())).with_namespace_sequences(namespace_sequences));
+            for i in 0..10 {
+                // Commits to another namespace put the two sequences apart
+                for namespace in ["other", "default"] {
+                    let txn_id = sm.begin_transaction(None).unwrap();
+                    sm.write(&txn_id, namespace.to_string(), "agent-1".to_string(), format!("key{}", i), serde_json::json!(i)).unwrap();
+                    sm.commit(&txn_id).unwrap();
+                }
+            }
+            let service = StatehouseServiceImpl::new(sm);
+            let replay = |start_ts: Option<u64>, resume_token: Option<u64>| -> Vec<ReplayEvent> {
+                let request = Request::new(ReplayRequest {
+                    namespace: "default".to_string(),
+                    agent_id: "agent-1".to_string(),
+                    start_ts,
+                    resume_token,
+                    ..Default::default()
+                });
+                runtime.block_on(async {
+                    let stream = service.replay(request).await.unwrap().into_inner();
+                    stream.map(Result::unwrap).collect().await
+                })
+            };
+
+            // start_ts counts in the namespace's own sequence when there is one
+            let all = replay(Some(3), None);
+            assert_eq!(all.len(), if namespace_sequences { 8 } else { 9 });
+            assert!(all.windows(2).all(|pair| pair[0].resume_token < pair[1].resume_token));
+
+            // Stop after K events, then resume with the last token
+            let first: Vec<ReplayEvent> = all.iter().take(3).cloned().collect();
+            let rest = replay(Some(3), Some(first.last().unwrap().resume_token));
+            let mut resumed = first;
+            resumed.extend(rest);
+            assert_eq!(resumed, all);
+
+            assert!(replay(Some(3), Some(all.last().unwrap().resume_token)).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_projection_returns_selected_fields() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let stored = serde_json::json!({
+            "profile": {"name": "ada", "address": {"city": "London", "zip": "N1"}},
+            "history": [1.0, 2.0, 3.0],
+        });
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "user:1".to_string(), stored.clone()).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let get = |projection: &[&str]| {
+            let request = Request::new(GetStateRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: "user:1".to_string(),
+                projection: projection.iter().map(|p| p.to_string()).collect(),
+            });
+            runtime.block_on(service.get_state(request)).map(Response::into_inner).map_err(|status| status.code())
+        };
+
+        let response = get(&["/profile/address/city"]).unwrap();
+        let value = prost_types_to_json(&response.value.unwrap(), usize::MAX).unwrap();
+        assert_eq!(value, serde_json::json!({"profile": {"address": {"city": "London"}}}));
+
+        let response = get(&["/nope"]).unwrap();
+        assert_eq!(prost_types_to_json(&response.value.unwrap(), usize::MAX).unwrap(), serde_json::json!({}));
+
+        assert_eq!(get(&["profile"]).unwrap_err(), tonic::Code::InvalidArgument);
+
+        let request = Request::new(ScanPrefixRequest {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            prefix: "user:".to_string(),
+            projection: vec!["/profile/name".to_string()],
+            label_filter: Default::default(),
+            include_deleted: false,
+            limit: 0,
+            cursor: None,
+            consistent: false,
+        });
+        let entries = runtime.block_on(service.scan_prefix(request)).unwrap().into_inner().entries;
+        let value = prost_types_to_json(entries[0].value.as_ref().unwrap(), usize::MAX).unwrap();
+        assert_eq!(value, serde_json::json!({"profile": {"name": "ada"}}));
+
+        // The stored value is untouched
+        let record = sm.get_state("default", "agent-1", "user:1").unwrap().unwrap();
+        assert_eq!(record.value, Some(stored));
+    }
+
+    #[test]
+    fn test_sync_reports_newest_durable_commit() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let sync = || runtime.block_on(service.sync(Request::new(SyncRequest {}))).unwrap().into_inner().durable_commit_ts;
+        assert_eq!(sync(), 0);
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "draft".to_string(), serde_json::json!(1)).unwrap();
+        let result = sm.commit_with_durability(&txn_id, Some(statehouse_core::Durability::Async)).unwrap();
+        assert_eq!(sync(), result.commit_ts);
+    }
+
+    #[test]
+    fn test_infer_schema() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        let values = [
+            serde_json::json!({"status": "open", "items": [{"sku": "a", "qty": 1}], "note": "gift"}),
+            serde_json::json!({"status": "paid", "items": [{"sku": "b", "qty": 2}, {"sku": "c"}]}),
+            serde_json::json!({"status": 3, "items": []}),
+        ];
+        for (i, value) in values.into_iter().enumerate() {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), format!("order:{}", i), value).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+        let service = StatehouseServiceImpl::new(sm);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let infer = |sample_size: Option<u32>| {
+            let request = Request::new(InferSchemaRequest { namespace: "default".to_string(), agent_id: "agent-1".to_string(), sample_size });
+            runtime.block_on(service.infer_schema(request)).unwrap().into_inner()
+        };
+
+        let response = infer(None);
+        assert_eq!(response.sampled, 3);
+        let root = response.schema.unwrap();
+        assert_eq!(root.types, ["object"]);
+        let fields: Vec<_> = root.fields.iter().map(|field| (field.name.as_str(), field.optional, field.schema.as_ref().unwrap().types.clone())).collect();
+        assert_eq!(fields, [
+            ("items", false, vec!["array".to_string()]),
+            ("note", true, vec!["string".to_string()]),
+            ("status", false, vec!["number".to_string(), "string".to_string()]),
+        ]);
+        let item = root.fields[0].schema.as_ref().unwrap().items.as_ref().unwrap();
+        assert_eq!(item.count, 3);
+        let item_fields: Vec<_> = item.fields.iter().map(|field| (field.name.as_str(), field.optional)).collect();
+        assert_eq!(item_fields, [("qty", true), ("sku", false)]);
+
+        // Sampling stops after the first keys
+        let response = infer(Some(2));
+        assert_eq!(response.sampled, 2);
+        assert_eq!(response.schema.unwrap().fields[2].schema.as_ref().unwrap().types, ["string"]);
+        let response = runtime.block_on(service.infer_schema(Request::new(InferSchemaRequest { namespace: "default".to_string(), agent_id: "nobody".to_string(), sample_size: None }))).unwrap().into_inner();
+        assert_eq!((response.sampled, response.schema), (0, None));
+    }
+
+    #[test]
+    fn test_consistent_scan_prefix_is_returned_whole() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for key in ["order:123:items", "order:123:total"] {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(2)).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+        let service = StatehouseServiceImpl::new(sm).with_max_page_size(Some(1));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let scan = |limit: u32, cursor: Option<&str>| {
+            let request = Request::new(ScanPrefixRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                prefix: "order:123:".to_string(),
+                projection: Vec::new(),
+                label_filter: Default::default(),
+                include_deleted: false,
+                limit,
+                cursor: cursor.map(str::to_string),
+                consistent: true,
+            });
+            runtime.block_on(service.scan_prefix(request)).map(Response::into_inner).map_err(|status| status.code())
+        };
+
+        // Asking for everything isn't held to the page cap
+        let response = scan(0, None).unwrap();
+        let keys: Vec<_> = response.entries.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, ["order:123:items", "order:123:total"]);
+        assert_eq!(response.next_cursor, None);
+
+        // An explicit limit is, and the scan fails rather than return part of the prefix
+        assert_eq!(scan(1, None).unwrap_err(), tonic::Code::FailedPrecondition);
+        assert_eq!(scan(2, None).unwrap_err(), tonic::Code::FailedPrecondition);
+        assert_eq!(scan(0, Some("order:123:items")).unwrap_err(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_watch_with_snapshot_delivers_each_commit_once() {
+        fn set_status(sm: &StateMachine, status: u64) {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "status".to_string(), serde_json::json!(status)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        let storage = Arc::new(SlowStorage::default());
+        let hub = Arc::new(WatchHub::new(None));
+        let sm = Arc::new(StateMachine::new(storage.clone()).with_replication_sink(hub.clone()));
+        set_status(&sm, 1);
+
+        // Lands after the watch subscribes but before it reads the current state
+        let racing = sm.clone();
+        *storage.before_read.lock().unwrap() = Some(Box::new(move || {
+            std::thread::spawn(move || set_status(&racing, 2)).join().unwrap();
+        }));
+
+        let service = StatehouseServiceImpl::new(sm.clone()).with_watch_hub(hub);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let events: Vec<WatchEvent> = runtime.block_on(async {
+            let request = Request::new(WatchRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                keys: vec!["status".to_string(), "missing".to_string()],
+                subscribe_with_snapshot: true,
+            });
+            let mut stream = service.watch(request).await.unwrap().into_inner();
+            let mut events = vec![stream.next().await.unwrap().unwrap()];
+            set_status(&sm, 3);
+            events.push(stream.next().await.unwrap().unwrap());
+            events
+        });
+
+        let seen: Vec<(bool, u64, Option<prost_types::Struct>)> = events
+            .into_iter()
+            .map(|event| {
+                let op = event.operation.unwrap();
+                (event.snapshot, op.version, op.value)
+            })
+            .collect();
+        let value = |status: u64| Some(json_to_prost_types(&serde_json::json!(status)));
+        // Version 2 arrives once, as the current state, not again as a change
+        assert_eq!(seen, vec![(true, 2, value(2)), (false, 3, value(3))]);
+
+        let request = Request::new(WatchRequest { keys: Vec::new(), ..Default::default() });
+        assert_eq!(runtime.block_on(service.watch(request)).unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_stream_limits_refuse_past_the_cap() {
+        let hub = Arc::new(WatchHub::new(None));
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())).with_replication_sink(hub.clone()));
+        let limits = StreamLimits { max_streams: Some(2), ..Default::default() };
+        let service = StatehouseServiceImpl::new(sm).with_watch_hub(hub).with_stream_limits(limits);
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            let watch = || {
+                service.watch(Request::new(WatchRequest {
+                    namespace: "default".to_string(),
+                    agent_id: "agent-1".to_string(),
+                    keys: vec!["status".to_string()],
+                    subscribe_with_snapshot: false,
+                }))
+            };
+            let stats = || async { service.get_stream_stats(Request::new(GetStreamStatsRequest {})).await.unwrap().into_inner() };
+
+            let first = watch().await.unwrap();
+            let _second = watch().await.unwrap();
+            assert_eq!(watch().await.unwrap_err().code(), tonic::Code::ResourceExhausted);
+            let full = stats().await;
+            assert_eq!((full.open, full.clients, full.rejected, full.max_streams), (2, 1, 1, Some(2)));
+
+            // A client going away frees its stream's slot
+            drop(first);
+            while stats().await.open == 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            assert!(watch().await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_scan_prefix_stream_reads_lazily_in_key_order() {
+        use statehouse_core::state_machine::SCAN_PAGE_SIZE;
+
+        let storage = Arc::new(SlowStorage::default());
+        let sm = Arc::new(StateMachine::new(storage.clone()));
+        let count = 5_000;
+        let payload = "x".repeat(1024);
+        let txn_id = sm.begin_transaction(None).unwrap();
+        for i in (0..count).rev() {
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), format!("blob:{:05}", i), serde_json::json!({"data": payload})).unwrap();
+        }
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let open = |start_after: Option<String>| {
+            let request = Request::new(ScanPrefixStreamRequest {
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                prefix: "blob:".to_string(),
+                projection: Vec::new(),
+                start_after,
+            });
+            runtime.block_on(service.scan_prefix_stream(request)).unwrap().into_inner()
+        };
+        // Records read from storage but not yet taken by the client
+        let read_ahead_limit = SCAN_PAGE_SIZE + SCAN_STREAM_BUFFER;
+
+        let mut stream = open(None);
+        // An idle client stops the scan once the buffer fills
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(storage.scanned.load(Ordering::Relaxed) <= read_ahead_limit);
+
+        let mut keys = Vec::new();
+        while let Some(entry) = runtime.block_on(stream.next()) {
+            keys.push(entry.unwrap().key);
+            assert!(storage.scanned.load(Ordering::Relaxed) - keys.len() <= read_ahead_limit);
+        }
+        let expected: Vec<String> = (0..count).map(|i| format!("blob:{:05}", i)).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(storage.scanned.load(Ordering::Relaxed), count);
+
+        // Resuming after the last key received
+        let resumed: Vec<String> = runtime
+            .block_on(open(Some(expected[2_499].clone())).collect::<Vec<_>>())
+            .into_iter()
+            .map(|entry| entry.unwrap().key)
+            .collect();
+        assert_eq!(resumed, expected[2_500..]);
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        use prost_types::value::Kind;
+
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task".to_string(), serde_json::json!({"status": "pending"})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let compare_and_set = |pointer: &str, expected: &str| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            let request = Request::new(CompareAndSetRequest {
+                txn_id: txn_id.clone(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: "task".to_string(),
+                pointer: pointer.to_string(),
+                expected: Some(prost_types::Value { kind: Some(Kind::StringValue(expected.to_string())) }),
+                value: Some(json_to_prost_types(&serde_json::json!({"status": "running"}))),
+                op: CompareOp::Eq.into(),
+            });
+            runtime.block_on(service.compare_and_set(request)).map_err(|status| status.code())?;
+            let request = Request::new(CommitRequest { txn_id, ..Default::default() });
+            runtime.block_on(service.commit(request)).map(|_| ()).map_err(|status| status.code())
+        };
+
+        assert_eq!(compare_and_set("status", "pending"), Err(tonic::Code::InvalidArgument));
+        compare_and_set("/status", "pending").unwrap();
+        assert_eq!(sm.get_state("default", "agent-1", "task").unwrap().unwrap().value.unwrap()["status"], "running");
+        assert_eq!(compare_and_set("/status", "pending"), Err(tonic::Code::FailedPrecondition));
+    }
+
+    #[test]
+    fn test_write_if_match_etag() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "task".to_string(), serde_json::json!({"status": "pending"})).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let get_etag = || {
+            let request = Request::new(GetStateRequest { namespace: "default".to_string(), agent_id: "agent-1".to_string(), key: "task".to_string(), ..Default::default() });
+            runtime.block_on(service.get_state(request)).unwrap().into_inner().etag
+        };
+        let write = |status: &str, if_match_etag: &str, client_seq: Option<u64>| {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            let request = Request::new(WriteRequest {
+                txn_id: txn_id.clone(),
+                namespace: "default".to_string(),
+                agent_id: "agent-1".to_string(),
+                key: "task".to_string(),
+                value: Some(json_to_prost_types(&serde_json::json!({"status": status}))),
+                labels: Default::default(),
+                client_seq,
+                if_match_etag: Some(if_match_etag.to_string()),
+                ttl_ms: None,
+            });
+            runtime.block_on(service.write(request)).map_err(|status| status.code())?;
+            let request = Request::new(CommitRequest { txn_id, ..Default::default() });
+            runtime.block_on(service.commit(request)).map(|_| ()).map_err(|status| status.code())
+        };
+
+        let read = get_etag();
+        write("running", &read, None).unwrap();
+        assert_ne!(get_etag(), read);
+
+        // Another client updated the task since `read`
+        assert_eq!(write("done", &read, None), Err(tonic::Code::FailedPrecondition));
+        assert_eq!(sm.get_state("default", "agent-1", "task").unwrap().unwrap().value.unwrap()["status"], "running");
+        assert_eq!(write("done", &get_etag(), Some(1)), Err(tonic::Code::InvalidArgument));
+
+        let txn_id = sm.begin_transaction(None).unwrap();
+        let request = Request::new(DeleteRequest {
+            txn_id: txn_id.clone(),
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: "task".to_string(),
+            expected_version: None,
+            if_match_etag: Some(read),
+        });
+        runtime.block_on(service.delete(request)).unwrap();
+        let status = runtime.block_on(service.commit(Request::new(CommitRequest { txn_id, ..Default::default() }))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn test_batch_apply_reports_each_operation() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let operation = |key: &str, op, expected_version| BatchOperation {
+            namespace: "default".to_string(),
+            agent_id: "agent-1".to_string(),
+            key: key.to_string(),
+            op: Some(op),
+            expected_version,
+        };
+        let batch = |atomic| BatchApplyRequest {
+            operations: vec![
+                operation("counter", batch_operation::Op::Increment(BatchIncrementOp { pointer: "/n".to_string(), delta: 5 }), None),
+                operation("task", batch_operation::Op::Write(BatchWriteOp { value: Some(json_to_prost_types(&serde_json::json!({"done": true}))), labels: HashMap::new() }), Some(3)),
+            ],
+            atomic,
+            ..Default::default()
+        };
+
+        let status = runtime.block_on(service.batch_apply(Request::new(batch(true)))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        assert!(sm.get_state("default", "agent-1", "counter").unwrap().is_none());
+
+        let response = runtime.block_on(service.batch_apply(Request::new(batch(false)))).unwrap().into_inner();
+        assert!(response.commit_ts > 0);
+        let statuses: Vec<_> = response.results.iter().map(|r| (r.status(), r.version)).collect();
+        assert_eq!(statuses, vec![(BatchOperationStatus::Applied, 1), (BatchOperationStatus::Conflict, 0)]);
+        assert!(response.results[1].error.contains("expected version 3"));
+        assert_eq!(sm.get_state("default", "agent-1", "counter").unwrap().unwrap().value, Some(serde_json::json!({"n": 5})));
+        assert!(sm.get_state("default", "agent-1", "task").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_version_depth_histogram_buckets() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        for (key, versions) in [("a", 1), ("b", 7)] {
+            for i in 0..versions {
+                let txn_id = sm.begin_transaction(None).unwrap();
+                sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), key.to_string(), serde_json::json!(i)).unwrap();
+                sm.commit(&txn_id).unwrap();
+            }
+        }
+
+        let service = StatehouseServiceImpl::new(sm).with_admin_token(Some("secret".to_string()));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut request = Request::new(GetVersionDepthHistogramRequest { namespace: "default".to_string(), top_n: Some(1) });
+        request.metadata_mut().insert(ADMIN_TOKEN_HEADER, "secret".parse().unwrap());
+        let response = runtime.block_on(service.get_version_depth_histogram(request)).unwrap().into_inner();
+        assert_eq!((response.keys, response.versions), (2, 8));
+        let buckets: Vec<_> = response.buckets.iter().map(|b| (b.min_versions, b.max_versions, b.keys)).collect();
+        assert_eq!(buckets, vec![(1, Some(1), 1), (2, Some(5), 0), (6, Some(20), 1), (21, Some(100), 0), (101, None, 0)]);
+        let deepest: Vec<_> = response.deepest.iter().map(|k| (k.key.as_str(), k.versions)).collect();
+        assert_eq!(deepest, vec![("b", 7)]);
+    }
+
+    #[test]
+    fn test_rename_namespace_requires_merge_into_populated_namespace() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        for namespace in ["staging", "prod"] {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, namespace.to_string(), "agent-1".to_string(), namespace.to_string(), serde_json::json!(1)).unwrap();
+            sm.commit(&txn_id).unwrap();
+        }
+
+        let service = StatehouseServiceImpl::new(sm.clone()).with_admin_token(Some("secret".to_string()));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let rename = |merge: bool| {
+            let mut request = Request::new(RenameNamespaceRequest { from: "staging".to_string(), to: "prod".to_string(), merge });
+            request.metadata_mut().insert(ADMIN_TOKEN_HEADER, "secret".parse().unwrap());
+            request
+        };
+
+        let err = runtime.block_on(service.rename_namespace(rename(false))).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        let response = runtime.block_on(service.rename_namespace(rename(true))).unwrap().into_inner();
+        assert_eq!((response.records_moved, response.versions_moved, response.events_rewritten), (1, 1, 1));
+        assert_eq!(sm.list_keys("prod", "agent-1", false, Deadline::NONE).unwrap(), vec!["prod", "staging"]);
+    }
+
+    #[test]
+    fn test_latency_stats_count_commits_and_reads() {
+        let sm = Arc::new(StateMachine::new(Arc::new(InMemoryStorage::new())));
+        let service = StatehouseServiceImpl::new(sm.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        for i in 0..3 {
+            let txn_id = sm.begin_transaction(None).unwrap();
+            sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key".to_string(), serde_json::json!(i)).unwrap();
+            runtime.block_on(service.commit(Request::new(CommitRequest { txn_id, ..Default::default() }))).unwrap();
+        }
+        let request = Request::new(ListKeysRequest { namespace: "default".to_string(), agent_id: "agent-1".to_string(), include_deleted: false, limit: 0, cursor: None });
+        runtime.block_on(service.list_keys(request)).unwrap();
+
+        let stats = runtime.block_on(service.get_latency_stats(Request::new(GetLatencyStatsRequest {}))).unwrap().into_inner();
+        let commit = stats.commit.unwrap();
+        assert_eq!(commit.count, 3);
+        assert!(commit.p50_us <= commit.p99_us && commit.p99_us <= commit.max_us);
+        assert_eq!(stats.read.unwrap().count, 1);
+        assert_eq!(stats.window_ms, 60_000);
+    }
 }
@@ -0,0 +1,112 @@
+// Time-based background snapshots
+//
+// Snapshotting only after so many commits leaves an idle daemon with an
+// ever-older snapshot, and daemons started together on the same interval hit
+// shared storage at the same moment. The scheduler snapshots once
+// `max_interval` has passed since the last snapshot, whatever took it, with a
+// random delay of up to `jitter` on top so a fleet drifts apart.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use statehouse_core::state_machine::StateMachine;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotSchedule {
+    /// Longest time between snapshots, before jitter
+    pub max_interval: Duration,
+    /// Upper bound of the random delay added to each interval
+    pub jitter: Duration,
+}
+
+impl SnapshotSchedule {
+    /// `max_interval` plus a fresh random share of `jitter`
+    fn next_interval(&self) -> Duration {
+        let jitter_nanos = self.jitter.as_nanos() as u64;
+        if jitter_nanos == 0 {
+            return self.max_interval;
+        }
+        // Every RandomState is seeded differently; no need for a rand dependency
+        let random = RandomState::new().build_hasher().finish();
+        self.max_interval + Duration::from_nanos(random % jitter_nanos)
+    }
+
+    /// Snapshot in the background on this schedule. Snapshots run on the
+    /// blocking pool and don't hold up commits; one that's due while another
+    /// is still in progress is skipped.
+    pub fn spawn(self, state_machine: Arc<StateMachine>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let interval = self.next_interval();
+                // A snapshot taken some other way meanwhile restarts the wait
+                let since_last = state_machine.since_last_snapshot();
+                if since_last < interval {
+                    tokio::time::sleep(interval - since_last).await;
+                    continue;
+                }
+
+                let snapshotting = state_machine.clone();
+                match tokio::task::spawn_blocking(move || snapshotting.try_snapshot()).await {
+                    Ok(Ok(Some(metadata))) => info!(
+                        snapshot_ts = metadata.snapshot_ts,
+                        records = metadata.record_count,
+                        "Scheduled snapshot saved"
+                    ),
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => warn!(error = %e, "Scheduled snapshot failed"),
+                    Err(e) => warn!(error = %e, "Scheduled snapshot panicked"),
+                }
+                // Don't retry a failed or skipped snapshot straight away
+                tokio::time::sleep(self.max_interval.min(state_machine.since_last_snapshot())).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use statehouse_core::storage::{RocksStorage, Snapshot, StorageConfig};
+
+    #[test]
+    fn test_idle_daemon_still_snapshots() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = RocksStorage::new(StorageConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            fsync_on_commit: false,
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        let sm = Arc::new(StateMachine::new(Arc::new(storage)));
+        let txn_id = sm.begin_transaction(None).unwrap();
+        sm.write(&txn_id, "default".to_string(), "agent-1".to_string(), "key".to_string(), serde_json::json!(1)).unwrap();
+        sm.commit(&txn_id).unwrap();
+
+        let schedule = SnapshotSchedule { max_interval: Duration::from_millis(20), jitter: Duration::from_millis(10) };
+        for _ in 0..100 {
+            let interval = schedule.next_interval();
+            assert!(interval >= schedule.max_interval && interval < schedule.max_interval + schedule.jitter);
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let snapshot_path = temp_dir.path().join("snapshot.json");
+        runtime.block_on(async {
+            let scheduler = schedule.spawn(sm.clone());
+            // Nothing is committed meanwhile, yet snapshots keep coming
+            for _ in 0..2 {
+                let before = sm.since_last_snapshot();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert!(sm.since_last_snapshot() < before + Duration::from_millis(200));
+            }
+            scheduler.abort();
+        });
+
+        let snapshot: Snapshot = serde_json::from_str(&std::fs::read_to_string(snapshot_path).unwrap()).unwrap();
+        assert_eq!(snapshot.metadata.snapshot_ts, 1);
+        assert_eq!(snapshot.metadata.record_count, 1);
+    }
+}
@@ -0,0 +1,123 @@
+// Limits on concurrent streaming subscriptions
+//
+// Every open Watch or Replay stream holds a task, and a Watch also holds a
+// broadcast receiver, for as long as the client keeps it open. The limiter
+// caps how many are open at once, in total and per client, so a client
+// opening thousands can neither exhaust the server nor take every slot from
+// the others. Past either cap a new stream is refused with
+// RESOURCE_EXHAUSTED; open ones are never cut off. A stream's task owns its
+// `StreamSlot`, so the slot is freed when the task ends, however the stream
+// does: finished, failed, or dropped by a disconnecting client.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tonic::Status;
+
+/// Caps on open streams; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamLimits {
+    pub max_streams: Option<usize>,
+    pub max_streams_per_client: Option<usize>,
+}
+
+/// Streams open now, and refused since startup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub open: usize,
+    /// Clients with at least one stream open
+    pub clients: usize,
+    /// Most streams any one client has open
+    pub max_client_open: usize,
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+pub struct StreamLimiter {
+    limits: StreamLimits,
+    /// Open streams per client; clients with none are removed
+    open: Mutex<HashMap<String, usize>>,
+    rejected: AtomicU64,
+}
+
+/// One open stream's place under the limits, given back when dropped
+pub struct StreamSlot {
+    limiter: Arc<StreamLimiter>,
+    client: String,
+}
+
+impl StreamLimiter {
+    pub fn new(limits: StreamLimits) -> Self {
+        Self { limits, ..Default::default() }
+    }
+
+    pub fn limits(&self) -> StreamLimits {
+        self.limits
+    }
+
+    /// Take a slot for a new stream of `client`'s, or refuse it with
+    /// RESOURCE_EXHAUSTED if that would pass a cap
+    #[allow(clippy::result_large_err)]
+    pub fn acquire(self: &Arc<Self>, client: &str) -> Result<StreamSlot, Status> {
+        let mut open = self.open.lock().unwrap();
+        let total: usize = open.values().sum();
+        let client_open = open.get(client).copied().unwrap_or(0);
+        let refusal = match (self.limits.max_streams, self.limits.max_streams_per_client) {
+            (Some(max), _) if total >= max => Some(format!("{} streams open, the server's limit", max)),
+            (_, Some(max)) if client_open >= max => Some(format!("{} streams open by this client, the per-client limit", max)),
+            _ => None,
+        };
+        if let Some(refusal) = refusal {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+            return Err(Status::resource_exhausted(format!("Too many streams: {}; close one and retry", refusal)));
+        }
+        *open.entry(client.to_string()).or_default() += 1;
+        Ok(StreamSlot { limiter: self.clone(), client: client.to_string() })
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        let open = self.open.lock().unwrap();
+        StreamStats {
+            open: open.values().sum(),
+            clients: open.len(),
+            max_client_open: open.values().copied().max().unwrap_or(0),
+            rejected: self.rejected.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let limiter = Arc::new(StreamLimiter::new(StreamLimits { max_streams: Some(3), max_streams_per_client: Some(2) }));
+
+        // One client can't take every slot
+        let a1 = limiter.acquire("a").unwrap();
+        let a2 = limiter.acquire("a").unwrap();
+        assert_eq!(limiter.acquire("a").err().unwrap().code(), tonic::Code::ResourceExhausted);
+        let b1 = limiter.acquire("b").unwrap();
+        assert_eq!(limiter.acquire("c").err().unwrap().code(), tonic::Code::ResourceExhausted);
+        assert_eq!(limiter.stats(), StreamStats { open: 3, clients: 2, max_client_open: 2, rejected: 2 });
+
+        drop(a1);
+        let c1 = limiter.acquire("c").unwrap();
+        drop((a2, b1, c1));
+        assert_eq!(limiter.stats(), StreamStats { open: 0, clients: 0, max_client_open: 0, rejected: 2 });
+        assert!(Arc::new(StreamLimiter::default()).acquire("a").is_ok());
+    }
+}
@@ -0,0 +1,46 @@
+// Fan-out of committed events to Watch streams
+//
+// The hub sits in the state machine's replication sink slot, so it sees every
+// committed event in commit order, and passes each one on to the standby sink
+// when replication is configured. Watchers subscribe to a bounded broadcast
+// channel; one that falls more than `WATCH_BUFFER` events behind is told so
+// and has to resubscribe.
+
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use statehouse_core::replication::ReplicationSink;
+use statehouse_core::storage::EventLogEntry;
+
+/// Events buffered for the slowest watcher before it lags
+const WATCH_BUFFER: usize = 1024;
+
+pub struct WatchHub {
+    events: broadcast::Sender<Arc<EventLogEntry>>,
+    /// Standby sink, if replication is configured
+    forward: Option<Arc<dyn ReplicationSink>>,
+}
+
+impl WatchHub {
+    pub fn new(forward: Option<Arc<dyn ReplicationSink>>) -> Self {
+        let (events, _) = broadcast::channel(WATCH_BUFFER);
+        Self { events, forward }
+    }
+
+    /// Receive every event committed from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<EventLogEntry>> {
+        self.events.subscribe()
+    }
+}
+
+impl ReplicationSink for WatchHub {
+    fn ship(&self, event: &EventLogEntry) {
+        if let Some(forward) = &self.forward {
+            forward.ship(event);
+        }
+        // Only fails when nobody is watching
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(Arc::new(event.clone()));
+        }
+    }
+}